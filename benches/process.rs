@@ -0,0 +1,202 @@
+use std::fmt::Write;
+use std::io::{self, Read};
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+
+use te::transaction::TransactionEngine;
+
+const ROWS: u64 = 1_000_000;
+const CLIENTS: u64 = 1_000;
+// Input size for the bounded history benchmark, which is generated while it's being read.
+const BIG_ROWS: u64 = 5_000_000;
+const HISTORY_LIMIT: usize = 100_000;
+
+const HEADER: &str = "type,client,tx,amount\n";
+
+// Generates a synthetic input with a mix of all transaction types: mostly deposits and
+// withdrawals, with every tenth deposit getting disputed, and the disputes alternately
+// getting resolved or charged back.
+fn synthetic_input(rows: u64) -> String {
+    let mut input = String::from(HEADER);
+    for tx in 1..=rows {
+        write_row(&mut input, tx);
+    }
+    input
+}
+
+fn write_row(input: &mut String, tx: u64) {
+    let client = tx % CLIENTS;
+    match tx % 10 {
+        0..=5 => writeln!(
+            input,
+            "deposit,{},{},{}.{:04}",
+            client,
+            tx,
+            tx % 100,
+            tx % 7
+        ),
+        6..=7 => writeln!(input, "withdrawal,{},{},{}.5", client, tx, tx % 10),
+        8 => writeln!(input, "dispute,{},{},", client, tx - 8),
+        _ if tx % 20 == 9 => writeln!(input, "resolve,{},{},", client, tx - 9),
+        _ => writeln!(input, "chargeback,{},{},", client, tx - 9),
+    }
+    .unwrap();
+}
+
+// Generates an input of deposits only, spread evenly across the clients.
+fn deposit_input(rows: u64) -> String {
+    let mut input = String::from(HEADER);
+    for tx in 1..=rows {
+        writeln!(
+            input,
+            "deposit,{},{},{}.{:04}",
+            tx % CLIENTS,
+            tx,
+            tx % 100,
+            tx % 7
+        )
+        .unwrap();
+    }
+    input
+}
+
+// Generates an input where every deposit gets disputed right away, and the disputes
+// alternately get resolved and charged back (only a third of the rows move funds in or out).
+// Charged back accounts get frozen, which the following deposits to them are rejected for.
+fn dispute_input(rows: u64) -> String {
+    let mut input = String::from(HEADER);
+    for tx in 1..=rows {
+        let deposit = tx - tx % 3;
+        let client = deposit % CLIENTS;
+        match tx % 3 {
+            0 => writeln!(input, "deposit,{},{},{}.5", client, tx, tx % 100),
+            1 => writeln!(input, "dispute,{},{},", client, deposit),
+            _ if tx % 6 == 2 => writeln!(input, "resolve,{},{},", client, deposit),
+            _ => writeln!(input, "chargeback,{},{},", client, deposit),
+        }
+        .unwrap();
+    }
+    input
+}
+
+// Produces the same input as `synthetic_input`, one row at a time, so the size of the input
+// isn't bound by the available memory.
+struct SyntheticReader {
+    rows: u64,
+    next_tx: u64,
+    pending: String,
+    offset: usize,
+}
+
+impl SyntheticReader {
+    fn new(rows: u64) -> Self {
+        SyntheticReader {
+            rows,
+            next_tx: 1,
+            pending: String::from(HEADER),
+            offset: 0,
+        }
+    }
+}
+
+impl Read for SyntheticReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.offset == self.pending.len() {
+            if self.next_tx > self.rows {
+                return Ok(0);
+            }
+            self.pending.clear();
+            self.offset = 0;
+            write_row(&mut self.pending, self.next_tx);
+            self.next_tx += 1;
+        }
+
+        let pending = &self.pending.as_bytes()[self.offset..];
+        let n = pending.len().min(buf.len());
+        buf[..n].copy_from_slice(&pending[..n]);
+        self.offset += n;
+        Ok(n)
+    }
+}
+
+fn process(c: &mut Criterion) {
+    let input = synthetic_input(ROWS);
+
+    let mut group = c.benchmark_group("process_from_reader");
+    group.throughput(Throughput::Elements(ROWS));
+    group.sample_size(10);
+    group.bench_function("mixed_1m", |b| {
+        b.iter_batched(
+            TransactionEngine::new,
+            |mut engine| {
+                engine
+                    .process_from_reader(input.as_bytes())
+                    .expect("Reading from memory cannot fail")
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+// The three workloads, with engines that start out empty and ones with room for all the
+// accounts and transactions up front.
+fn workloads(c: &mut Criterion) {
+    let inputs = [
+        ("deposits", deposit_input(ROWS)),
+        ("disputes", dispute_input(ROWS)),
+        ("mixed", synthetic_input(ROWS)),
+    ];
+
+    let mut group = c.benchmark_group("workloads");
+    group.throughput(Throughput::Elements(ROWS));
+    group.sample_size(10);
+    for (name, input) in &inputs {
+        for preallocated in [false, true] {
+            let id = if preallocated {
+                format!("{}_preallocated", name)
+            } else {
+                name.to_string()
+            };
+            group.bench_function(id, |b| {
+                b.iter_batched(
+                    || {
+                        if preallocated {
+                            TransactionEngine::with_capacity(CLIENTS as usize, ROWS as usize)
+                        } else {
+                            TransactionEngine::new()
+                        }
+                    },
+                    |mut engine| {
+                        engine
+                            .process_from_reader(input.as_bytes())
+                            .expect("Reading from memory cannot fail")
+                    },
+                    BatchSize::LargeInput,
+                )
+            });
+        }
+    }
+    group.finish();
+}
+
+fn process_bounded(c: &mut Criterion) {
+    let mut group = c.benchmark_group("process_from_reader");
+    group.throughput(Throughput::Elements(BIG_ROWS));
+    group.sample_size(10);
+    group.bench_function("bounded_history_5m", |b| {
+        b.iter_batched(
+            || TransactionEngine::new().with_history_limit(HISTORY_LIMIT),
+            |mut engine| {
+                engine
+                    .process_from_reader(SyntheticReader::new(BIG_ROWS))
+                    .expect("Generating the input cannot fail")
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, process, workloads, process_bounded);
+criterion_main!(benches);
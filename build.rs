@@ -0,0 +1,40 @@
+// Only needed for the `grpc` and `protobuf` features, which generate the types of the `rpc`
+// and `protobuf` modules, and the `capi` feature, which generates the C header of the `ffi`
+// module.
+fn main() {
+    #[cfg(feature = "grpc")]
+    {
+        println!("cargo:rerun-if-changed=proto/engine.proto");
+        let descriptors = protox::compile(["proto/engine.proto"], ["proto"])
+            .expect("Unable to compile the protobuf definitions");
+        tonic_build::configure()
+            .build_client(false)
+            .compile_fds(descriptors)
+            .expect("Unable to generate the gRPC types");
+    }
+
+    #[cfg(feature = "protobuf")]
+    {
+        println!("cargo:rerun-if-changed=proto/record.proto");
+        let descriptors = protox::compile(["proto/record.proto"], ["proto"])
+            .expect("Unable to compile the protobuf definitions");
+        prost_build::Config::new()
+            .compile_fds(descriptors)
+            .expect("Unable to generate the protobuf types");
+    }
+
+    #[cfg(feature = "capi")]
+    {
+        println!("cargo:rerun-if-changed=src/ffi.rs");
+        println!("cargo:rerun-if-changed=cbindgen.toml");
+        let crate_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+        let config = cbindgen::Config::from_file(format!("{}/cbindgen.toml", crate_dir))
+            .expect("Unable to read cbindgen.toml");
+        cbindgen::Builder::new()
+            .with_crate(&crate_dir)
+            .with_config(config)
+            .generate()
+            .expect("Unable to generate the C header")
+            .write_to_file(format!("{}/include/te.h", crate_dir));
+    }
+}
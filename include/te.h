@@ -0,0 +1,97 @@
+#ifndef TE_H
+#define TE_H
+
+/* Generated by cbindgen from src/ffi.rs, don't edit by hand. */
+
+#include <stddef.h>
+
+/**
+ * The outcome of `engine_process_csv_line`.
+ */
+typedef enum EngineStatus {
+  /**
+   * The transaction was applied.
+   */
+  EngineStatus_Ok = 0,
+  /**
+   * The engine or the line was a null pointer, or the line wasn't valid UTF-8.
+   */
+  EngineStatus_InvalidArgument = 1,
+  /**
+   * The line isn't a valid transaction record.
+   */
+  EngineStatus_InvalidRecord = 2,
+  /**
+   * The transaction was rejected by the engine (which leaves the state unchanged).
+   */
+  EngineStatus_Rejected = 3,
+} EngineStatus;
+
+/**
+ * A non-negative amount with at most four decimal places, i.e. one the engine accepts for
+ * deposits and withdrawals under the default settings (which may still reject zero, or
+ * amounts above a maximum). Parses and (de)serializes the same way as `Decimal`, with the
+ * same checks as `Amount::new`.
+ */
+typedef struct Amount Amount;
+
+/**
+ * The id of a client account. Wide enough for feeds with client ids beyond `u16`, which
+ * would otherwise fail to deserialize.
+ */
+typedef struct ClientId ClientId;
+
+/**
+ * Implements transaction processing logic.
+ */
+typedef struct TransactionEngine TransactionEngine;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Creates an engine with the default configuration.
+ */
+struct TransactionEngine *engine_new(void);
+
+/**
+ * Processes a single header-less CSV row (in the `type,client,tx,amount,...` column order of
+ * the CLI input without a header), given as a NUL terminated string.
+ *
+ * # Safety
+ *
+ * `engine` has to be null or returned by `engine_new` (and not freed yet), and `line` has to
+ * be null or point to a NUL terminated string.
+ */
+enum EngineStatus engine_process_csv_line(struct TransactionEngine *engine, const char *line);
+
+/**
+ * Writes the account report (the same CSV as the output of the CLI) to `buffer`, as a NUL
+ * terminated string, and returns its length (not counting the NUL). If `capacity` is too
+ * small, the report is truncated, so callers can pass a null `buffer` to find out how much
+ * space is needed first (like `snprintf`). Returns 0 if `engine` is null.
+ *
+ * # Safety
+ *
+ * `engine` has to be null or returned by `engine_new` (and not freed yet), and `buffer` has
+ * to be null or point to at least `capacity` writable bytes.
+ */
+size_t engine_export_accounts(const struct TransactionEngine *engine,
+                              char *buffer,
+                              size_t capacity);
+
+/**
+ * Releases an engine. Does nothing if `engine` is null.
+ *
+ * # Safety
+ *
+ * `engine` has to be null or returned by `engine_new`, and must not be used afterwards.
+ */
+void engine_free(struct TransactionEngine *engine);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* TE_H */
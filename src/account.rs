@@ -1,65 +1,738 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::io::Read;
+use std::mem;
+use std::ops::{Deref, Not};
 use std::result;
+use std::str::FromStr;
 
 use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
 
-use crate::transaction::Error;
+use crate::events::AccountEvent;
+use crate::input::reader_builder;
+use crate::risk::RiskFlag;
+use crate::transaction::{
+    ClientId, Currency, Error, ResidualBalancePolicy, TransactionId, Type, MAX_AMOUNT_SCALE,
+};
 
 type Result<T> = result::Result<T, Error>;
 
-// Contains data associated with a client account, and implements helper methods.
-#[derive(Default)]
-pub struct Account {
+// Used to report zero balances for accounts that don't hold funds in any currency yet.
+static ZERO_BALANCE: Balance = Balance {
+    available: Decimal::ZERO,
+    held: Decimal::ZERO,
+    total: Decimal::ZERO,
+};
+
+/// The funds of a client account in a single currency. The total is tracked along with the
+/// available and held funds (rather than derived from them), so inconsistencies between them
+/// show up in audits (see `TransactionEngine::audit`).
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(from = "StoredBalance")]
+pub struct Balance {
     available: Decimal,
     held: Decimal,
-    frozen: bool,
+    total: Decimal,
 }
 
-impl Account {
+// A balance as stored in checkpoints. Older ones don't have the total, which is derived from
+// the other funds for them.
+#[derive(Deserialize)]
+struct StoredBalance {
+    available: Decimal,
+    held: Decimal,
+    #[serde(default)]
+    total: Option<Decimal>,
+}
+
+impl From<StoredBalance> for Balance {
+    fn from(stored: StoredBalance) -> Self {
+        Balance {
+            available: stored.available,
+            held: stored.held,
+            total: stored.total.unwrap_or(stored.available + stored.held),
+        }
+    }
+}
+
+impl Balance {
     // The methods below return a `&mut Self` so they can be chained when appropriate/useful.
     // Also we assume the input has been validated beforehand (i.e. then `amount` is a
     // positive value).
 
     pub fn increase_available(&mut self, amount: Decimal) -> &mut Self {
         self.available += amount;
+        self.total += amount;
         self
     }
 
+    /// Same as `increase_available`, but returns an error (for the account of `client`) if the
+    /// funds would exceed the range of `Decimal`, in which case the balance stays the same.
+    pub fn try_increase_available(
+        &mut self,
+        client: ClientId,
+        amount: Decimal,
+    ) -> Result<&mut Self> {
+        self.check_increase_available(client, amount)?;
+        Ok(self.increase_available(amount))
+    }
+
     pub fn decrease_available(&mut self, amount: Decimal) -> &mut Self {
         self.available -= amount;
+        self.total -= amount;
         self
     }
 
+    /// Same as `decrease_available`, but returns an error (for the account of `client`) if the
+    /// available funds don't cover `amount`, in which case the balance stays the same.
+    pub fn try_decrease_available(
+        &mut self,
+        client: ClientId,
+        amount: Decimal,
+    ) -> Result<&mut Self> {
+        self.try_decrease_available_to(client, amount, Decimal::ZERO)
+    }
+
+    /// Same as `try_decrease_available`, but lets the available funds go down to `floor`
+    /// (i.e. below zero, for an overdraft line; see `BalanceLimits`). The error reports the
+    /// funds available above the floor.
+    pub fn try_decrease_available_to(
+        &mut self,
+        client: ClientId,
+        amount: Decimal,
+        floor: Decimal,
+    ) -> Result<&mut Self> {
+        self.check_decrease_available_to(client, amount, floor)?;
+        Ok(self.decrease_available(amount))
+    }
+
     pub fn increase_held(&mut self, amount: Decimal) -> &mut Self {
         self.held += amount;
+        self.total += amount;
         self
     }
 
+    /// Same as `increase_held`, but returns an error (for the account of `client`) if the funds
+    /// would exceed the range of `Decimal`, in which case the balance stays the same.
+    pub fn try_increase_held(&mut self, client: ClientId, amount: Decimal) -> Result<&mut Self> {
+        self.check_increase_held(client, amount)?;
+        Ok(self.increase_held(amount))
+    }
+
     pub fn decrease_held(&mut self, amount: Decimal) -> &mut Self {
         self.held -= amount;
+        self.total -= amount;
         self
     }
 
-    pub fn withdraw(&mut self, amount: Decimal) -> Result<&mut Self> {
-        // A withdrawal cannot take place if the specified `amount` is greater than
-        // the currently available funds.
-        if self.available >= amount {
-            self.available -= amount;
-            Ok(self)
+    /// Same as `decrease_held`, but returns an error (for the account of `client`) instead of
+    /// letting the held funds go negative, which can only happen if the engine state is
+    /// inconsistent (i.e. a loaded checkpoint holding less than its open disputes).
+    pub fn try_decrease_held(&mut self, client: ClientId, amount: Decimal) -> Result<&mut Self> {
+        self.check_decrease_held(client, amount)?;
+        Ok(self.decrease_held(amount))
+    }
+
+    // The checks of the `try_*` methods above, which `BalanceMut` shares.
+
+    fn check_increase_available(&self, client: ClientId, amount: Decimal) -> Result<()> {
+        match (
+            exact_add(self.available, amount),
+            exact_add(self.total, amount),
+        ) {
+            (Some(_), Some(_)) => Ok(()),
+            _ => Err(Error::Overflow { client, amount }),
+        }
+    }
+
+    fn check_decrease_available_to(
+        &self,
+        client: ClientId,
+        amount: Decimal,
+        floor: Decimal,
+    ) -> Result<()> {
+        let available = match (
+            exact_sub(self.available, amount),
+            exact_sub(self.total, amount),
+        ) {
+            (Some(available), Some(_)) => available,
+            _ => return Err(Error::Overflow { client, amount }),
+        };
+        if available < floor {
+            return Err(Error::InsufficientFunds {
+                client,
+                requested: amount,
+                available: self.available - floor,
+            });
+        }
+        Ok(())
+    }
+
+    fn check_increase_held(&self, client: ClientId, amount: Decimal) -> Result<()> {
+        match (exact_add(self.held, amount), exact_add(self.total, amount)) {
+            (Some(_), Some(_)) => Ok(()),
+            _ => Err(Error::Overflow { client, amount }),
+        }
+    }
+
+    fn check_decrease_held(&self, client: ClientId, amount: Decimal) -> Result<()> {
+        if self.held < amount {
+            return Err(Error::InconsistentState {
+                client,
+                requested: amount,
+                held: self.held,
+            });
+        }
+        Ok(())
+    }
+
+    // Applies one of the events which move funds (see `Account::apply_event`), and ignores
+    // any other.
+    fn apply(&mut self, event: &AccountEvent) {
+        match *event {
+            AccountEvent::FundsDeposited { amount, .. } => {
+                self.increase_available(amount);
+            }
+            AccountEvent::FundsWithdrawn { amount, .. } => {
+                self.decrease_available(amount);
+            }
+            AccountEvent::FundsHeld { amount, .. } => {
+                self.available -= amount;
+                self.held += amount;
+            }
+            AccountEvent::FundsReleased { amount, .. } => {
+                self.held -= amount;
+                self.available += amount;
+            }
+            _ => {}
+        }
+    }
+
+    pub fn available(&self) -> Decimal {
+        self.available
+    }
+
+    pub fn held(&self) -> Decimal {
+        self.held
+    }
+
+    pub fn total(&self) -> Decimal {
+        self.total
+    }
+
+    /// Whether the total is the sum of the available and held funds, which only fails to hold
+    /// because of engine bugs or corrupted state.
+    pub fn is_consistent(&self) -> bool {
+        self.total == self.available + self.held
+    }
+
+    // Sets the total to the sum of the available and held funds (see `repair::Correction`).
+    fn recompute_total(&mut self) {
+        self.total = self.available + self.held;
+    }
+}
+
+// `a + b`, unless it overflows or has to be rounded (for funds with more significant digits
+// than a `Decimal` holds), which would leave the total out of step with the available and
+// held funds. Sums that fit keep the larger scale of the two.
+fn exact_add(a: Decimal, b: Decimal) -> Option<Decimal> {
+    a.checked_add(b)
+        .filter(|sum| sum.scale() >= a.scale().max(b.scale()))
+}
+
+// Same as `exact_add`, for `a - b`.
+fn exact_sub(a: Decimal, b: Decimal) -> Option<Decimal> {
+    exact_add(a, -b)
+}
+
+/// A mutable handle to the balance of an account in one currency (see `Account::balance_mut`),
+/// which derefs to the `Balance`. Every change to the funds is made as one of the events of
+/// the event log (see `Account::apply_event`), which the account records when its engine
+/// keeps the log. Same as for `Balance`, amounts are assumed to be positive, and the methods
+/// can be chained.
+pub struct BalanceMut<'a> {
+    balance: &'a mut Balance,
+    // The currency of the balance, along with the events of the account, when recording them.
+    recorder: Option<(Currency, &'a mut Vec<AccountEvent>)>,
+}
+
+impl BalanceMut<'_> {
+    pub fn increase_available(&mut self, amount: Decimal) -> &mut Self {
+        self.apply(amount, |currency, amount| AccountEvent::FundsDeposited {
+            currency,
+            amount,
+        })
+    }
+
+    /// Same as `Balance::try_increase_available`.
+    pub fn try_increase_available(
+        &mut self,
+        client: ClientId,
+        amount: Decimal,
+    ) -> Result<&mut Self> {
+        self.balance.check_increase_available(client, amount)?;
+        Ok(self.increase_available(amount))
+    }
+
+    pub fn decrease_available(&mut self, amount: Decimal) -> &mut Self {
+        self.apply(amount, |currency, amount| AccountEvent::FundsWithdrawn {
+            currency,
+            amount,
+        })
+    }
+
+    /// Same as `Balance::try_decrease_available`.
+    pub fn try_decrease_available(
+        &mut self,
+        client: ClientId,
+        amount: Decimal,
+    ) -> Result<&mut Self> {
+        self.try_decrease_available_to(client, amount, Decimal::ZERO)
+    }
+
+    /// Same as `Balance::try_decrease_available_to`.
+    pub fn try_decrease_available_to(
+        &mut self,
+        client: ClientId,
+        amount: Decimal,
+        floor: Decimal,
+    ) -> Result<&mut Self> {
+        self.balance
+            .check_decrease_available_to(client, amount, floor)?;
+        Ok(self.decrease_available(amount))
+    }
+
+    /// Credits `amount` straight to the held funds, i.e. for a disputed withdrawal, whose
+    /// funds have left the account already.
+    pub fn increase_held(&mut self, amount: Decimal) -> &mut Self {
+        self.increase_available(amount).hold(amount)
+    }
+
+    /// Same as `Balance::try_increase_held`.
+    pub fn try_increase_held(&mut self, client: ClientId, amount: Decimal) -> Result<&mut Self> {
+        self.balance.check_increase_held(client, amount)?;
+        Ok(self.increase_held(amount))
+    }
+
+    /// Moves `amount` from the available to the held funds, which may leave the available
+    /// funds negative.
+    pub fn hold(&mut self, amount: Decimal) -> &mut Self {
+        self.apply(amount, |currency, amount| AccountEvent::FundsHeld {
+            currency,
+            amount,
+        })
+    }
+
+    /// Same as `hold`, but returns an error (for the account of `client`) if the available
+    /// funds would go below `floor` (see `Balance::try_decrease_available_to`).
+    pub fn try_hold_to(
+        &mut self,
+        client: ClientId,
+        amount: Decimal,
+        floor: Decimal,
+    ) -> Result<&mut Self> {
+        self.balance
+            .check_decrease_available_to(client, amount, floor)?;
+        Ok(self.hold(amount))
+    }
+
+    /// Moves `amount` from the held to the available funds.
+    pub fn release(&mut self, amount: Decimal) -> &mut Self {
+        self.apply(amount, |currency, amount| AccountEvent::FundsReleased {
+            currency,
+            amount,
+        })
+    }
+
+    /// Takes `amount` off the held funds (i.e. to settle a dispute), of which `released` goes
+    /// back to the available funds, and the rest leaves the account.
+    pub fn settle_held(&mut self, amount: Decimal, released: Decimal) -> &mut Self {
+        self.release(amount).decrease_available(amount - released)
+    }
+
+    /// Same as `settle_held`, but returns an error (for the account of `client`) instead of
+    /// letting the held funds go negative (see `Balance::try_decrease_held`).
+    pub fn try_settle_held(
+        &mut self,
+        client: ClientId,
+        amount: Decimal,
+        released: Decimal,
+    ) -> Result<&mut Self> {
+        self.balance.check_decrease_held(client, amount)?;
+        Ok(self.settle_held(amount, released))
+    }
+
+    // Credits `amount` to the available funds, or debits it for negative amounts.
+    pub(crate) fn adjust_available(&mut self, amount: Decimal) -> &mut Self {
+        if amount.is_sign_negative() {
+            self.decrease_available(-amount)
         } else {
-            Err(Error::InsufficientFunds)
+            self.increase_available(amount)
         }
     }
 
-    pub fn freeze(&mut self) -> &mut Self {
-        self.frozen = true;
+    // Applies the event `event` makes of `amount` (for the currency of the balance), and
+    // records it. Zero amounts don't change the funds, so they don't make for an event.
+    fn apply(
+        &mut self,
+        amount: Decimal,
+        event: fn(Currency, Decimal) -> AccountEvent,
+    ) -> &mut Self {
+        if !amount.is_zero() {
+            // The currency only matters for the recorded events.
+            let currency = self.recorder.as_ref().map(|(c, _)| c.clone());
+            let event = event(currency.unwrap_or_default(), amount);
+            self.balance.apply(&event);
+            if let Some((_, events)) = &mut self.recorder {
+                events.push(event);
+            }
+        }
         self
     }
+}
+
+impl Deref for BalanceMut<'_> {
+    type Target = Balance;
+
+    fn deref(&self) -> &Balance {
+        self.balance
+    }
+}
+
+/// A balance whose total isn't the sum of its available and held funds, as reported by
+/// `TransactionEngine::audit`.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct TotalMismatch {
+    pub client: ClientId,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub currency: Currency,
+    pub available: Decimal,
+    pub held: Decimal,
+    pub total: Decimal,
+}
 
-    pub fn check_frozen_mut(&mut self) -> Result<&mut Self> {
-        if self.frozen {
-            return Err(Error::AccountFrozen);
+impl fmt::Display for TotalMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "total funds of client {}", self.client)?;
+        if !self.currency.is_empty() {
+            write!(f, " in {}", self.currency)?;
         }
-        Ok(self)
+        write!(
+            f,
+            " are {}, but available ({}) and held ({}) add up to {}",
+            self.total,
+            self.available,
+            self.held,
+            self.available + self.held
+        )
+    }
+}
+
+/// The status of an account, which decides the transactions it accepts. Accounts move
+/// between them via chargebacks (which freeze the account by default) and the administrative
+/// transaction types (see `TransactionEngine::with_admin_ops`).
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    /// Accepts every transaction.
+    #[default]
+    Active,
+    /// Frozen by a chargeback, so only the transactions allowed by the `FrozenPolicy` go
+    /// through, until it's unfrozen via `unlock` (or a chargeback reversal).
+    Frozen,
+    /// Closed via `close`, which blocks all transactions until it's reopened via `reopen`.
+    Closed,
+    /// Put under review via `review`, i.e. by a risk team. Incoming funds are still accepted,
+    /// while withdrawals and outgoing transfers are rejected with `Error::AccountUnderReview`
+    /// until the account is approved via `approve`.
+    UnderReview,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Status::Active => "active",
+            Status::Frozen => "frozen",
+            Status::Closed => "closed",
+            Status::UnderReview => "under_review",
+        })
+    }
+}
+
+/// Reported for clients that don't have an account (yet).
+pub static EMPTY_ACCOUNT: Account = Account {
+    balances: BTreeMap::new(),
+    status: Status::Active,
+    under_dispute: false,
+    charged_back: false,
+    overdrawn: false,
+    latest_timestamp: None,
+    risk_flags: BTreeSet::new(),
+    metadata: None,
+    carried_held: BTreeMap::new(),
+    dispute_stats: BTreeMap::new(),
+    open_disputes: 0,
+    history: Vec::new(),
+    events: None,
+};
+
+// Separates the tags of accounts in seed files and reports.
+const TAG_SEPARATOR: char = ';';
+
+/// Descriptive details of an account, which don't affect processing, but make reports
+/// readable without joining them against another source (see `read_metadata`).
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct AccountMetadata {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// An identifier of the account in another system (i.e. a CRM).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reference: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+}
+
+impl AccountMetadata {
+    // The tags as reported, separated by semicolons.
+    fn joined_tags(&self) -> String {
+        self.tags.join(&TAG_SEPARATOR.to_string())
+    }
+}
+
+// A row of an account seed file, where every column but `client` is optional.
+#[derive(Deserialize)]
+struct SeedRow {
+    client: ClientId,
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    reference: Option<String>,
+    #[serde(default)]
+    tags: Option<String>,
+}
+
+/// Reads the metadata of accounts from a CSV seed file, with a `client` column and any of the
+/// `name`, `reference`, and `tags` columns (the tags separated by semicolons, i.e.
+/// `vip;eu`). Empty fields are left unset.
+pub fn read_metadata<R: Read>(r: R) -> csv::Result<Vec<(ClientId, AccountMetadata)>> {
+    let non_empty = |field: Option<String>| field.filter(|s| !s.trim().is_empty());
+    let mut metadata = Vec::new();
+    for row in reader_builder(true).from_reader(r).deserialize() {
+        let row: SeedRow = row?;
+        let tags = row
+            .tags
+            .iter()
+            .flat_map(|tags| tags.split(TAG_SEPARATOR))
+            .map(str::trim)
+            .filter(|tag| !tag.is_empty())
+            .map(str::to_owned)
+            .collect();
+        let entry = AccountMetadata {
+            name: non_empty(row.name),
+            reference: non_empty(row.reference),
+            tags,
+        };
+        metadata.push((row.client, entry));
+    }
+    Ok(metadata)
+}
+
+/// The balance of an account to start from (see `read_opening_balances`), in the implicit
+/// currency unless `currency` is set.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct OpeningBalance {
+    pub client: ClientId,
+    #[serde(default)]
+    pub currency: Currency,
+    pub available: Decimal,
+    #[serde(default)]
+    pub held: Decimal,
+    /// Whether the account is frozen.
+    #[serde(default)]
+    pub locked: bool,
+}
+
+/// Reads the opening balances of accounts from CSV, with `client` and `available` columns,
+/// and optionally `currency`, `held`, and `locked` columns. Other columns are ignored, so the
+/// account report of an earlier run can be read as well (its `total` is implied by the
+/// available and held funds).
+pub fn read_opening_balances<R: Read>(r: R) -> csv::Result<Vec<OpeningBalance>> {
+    reader_builder(true).from_reader(r).deserialize().collect()
+}
+
+/// An operation that was successfully applied to an account, together with the resulting
+/// balance (in the currency of the operation). For dispute-related operations, the amount
+/// and currency are those of the disputed transaction. Withdrawal fees (see `FeeSchedule`)
+/// are operations of their own, with `fee` set and the type and id of their withdrawal.
+#[derive(Clone, Debug, Serialize)]
+pub struct AppliedOp {
+    #[serde(rename = "type")]
+    type_: Type,
+    tx: TransactionId,
+    amount: Decimal,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    currency: Currency,
+    #[serde(skip_serializing_if = "Not::not")]
+    fee: bool,
+    available: Decimal,
+    held: Decimal,
+}
+
+/// A serializable view of a client account as a whole (see `Account::view`), with its funds
+/// in every currency (keyed by currency code, which is empty for the implicit currency).
+/// Amounts are reported with at most four decimal places, same as in the account report.
+#[derive(Clone, Debug, Serialize)]
+pub struct AccountView<'a> {
+    pub client: ClientId,
+    pub balances: BTreeMap<&'a str, Balance>,
+    pub status: Status,
+    pub locked: bool,
+    /// The number of disputes which haven't been resolved or charged back yet.
+    pub open_disputes: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<&'a AccountMetadata>,
+}
+
+/// The settled disputes of an account in one currency (see `Account::dispute_stats`). Partial
+/// resolves and chargebacks count as one each, with the amount they settled.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct DisputeStats {
+    pub resolved: u64,
+    pub resolved_amount: Decimal,
+    pub chargebacks: u64,
+    pub chargeback_amount: Decimal,
+}
+
+/// A row of the account report, i.e. the funds of a client account in a single currency.
+/// The `currency` column is only present in multi-asset mode. Amounts are reported with at
+/// most four decimal places.
+#[derive(Debug, Serialize)]
+pub struct AccountRecord<'a> {
+    client: ClientId,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    currency: Option<&'a str>,
+    available: Decimal,
+    held: Decimal,
+    total: Decimal,
+    locked: bool,
+    // Only reported when the administrative transaction types are enabled, since accounts
+    // can't be closed or put under review otherwise.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<Status>,
+    // Only reported when risk rules are configured.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    risk_score: Option<u32>,
+    // Only reported when overdraft lines are configured.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    overdraft: Option<Decimal>,
+    // Only reported when enabled via `TransactionEngine::with_metadata_columns` (and empty for
+    // accounts without metadata, so the rows have the same shape throughout).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reference: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tags: Option<String>,
+    // Only reported when enabled via `TransactionEngine::with_dispute_columns`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    open_disputes: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    disputed_amount: Option<Decimal>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    resolved_disputes: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    resolved_amount: Option<Decimal>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    chargebacks: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    chargeback_amount: Option<Decimal>,
+}
+
+impl<'a> AccountRecord<'a> {
+    pub fn new(
+        client: ClientId,
+        currency: Option<&'a str>,
+        balance: &Balance,
+        account: &Account,
+    ) -> Self {
+        AccountRecord {
+            client,
+            currency,
+            available: rounded(balance.available()),
+            held: rounded(balance.held()),
+            total: rounded(balance.total()),
+            locked: account.frozen(),
+            status: None,
+            risk_score: None,
+            overdraft: None,
+            name: None,
+            reference: None,
+            tags: None,
+            open_disputes: None,
+            disputed_amount: None,
+            resolved_disputes: None,
+            resolved_amount: None,
+            chargebacks: None,
+            chargeback_amount: None,
+        }
+    }
+
+    /// Adds the status of the account to the row.
+    pub fn with_status(mut self, status: Status) -> Self {
+        self.status = Some(status);
+        self
+    }
+
+    /// Adds the risk score of the account to the row (see `RiskRules::score`).
+    pub fn with_risk_score(mut self, score: u32) -> Self {
+        self.risk_score = Some(score);
+        self
+    }
+
+    /// Adds how much of the overdraft line is in use to the row, i.e. how far the available
+    /// funds are below zero.
+    pub fn with_overdraft(mut self) -> Self {
+        self.overdraft = Some((-self.available).max(Decimal::ZERO));
+        self
+    }
+
+    /// Adds the metadata of the account to the row, with empty fields if it has none.
+    pub fn with_metadata(mut self, metadata: Option<&'a AccountMetadata>) -> Self {
+        self.name = Some(metadata.and_then(|m| m.name.as_deref()).unwrap_or(""));
+        self.reference = Some(metadata.and_then(|m| m.reference.as_deref()).unwrap_or(""));
+        self.tags = Some(
+            metadata
+                .map(AccountMetadata::joined_tags)
+                .unwrap_or_default(),
+        );
+        self
+    }
+
+    /// Adds the dispute statistics of the balance to the row: the disputes which are still
+    /// open and the funds they hold (`open` and `disputed`), and the settled ones.
+    pub fn with_dispute_stats(
+        mut self,
+        open: usize,
+        disputed: Decimal,
+        stats: &DisputeStats,
+    ) -> Self {
+        self.open_disputes = Some(open);
+        self.disputed_amount = Some(rounded(disputed));
+        self.resolved_disputes = Some(stats.resolved);
+        self.resolved_amount = Some(rounded(stats.resolved_amount));
+        self.chargebacks = Some(stats.chargebacks);
+        self.chargeback_amount = Some(rounded(stats.chargeback_amount));
+        self
+    }
+
+    pub fn client(&self) -> ClientId {
+        self.client
+    }
+
+    /// The currency of the row, which is only set in multi-asset mode.
+    pub fn currency(&self) -> Option<&'a str> {
+        self.currency
     }
 
     pub fn available(&self) -> Decimal {
@@ -70,7 +743,991 @@ impl Account {
         self.held
     }
 
+    pub fn total(&self) -> Decimal {
+        self.total
+    }
+
+    pub fn locked(&self) -> bool {
+        self.locked
+    }
+
+    /// The status of the account, which is only set when the administrative transaction
+    /// types are enabled.
+    pub fn status(&self) -> Option<Status> {
+        self.status
+    }
+
+    /// The risk score of the account, which is only set when risk rules are configured.
+    pub fn risk_score(&self) -> Option<u32> {
+        self.risk_score
+    }
+
+    /// The overdraft in use, which is only set when overdraft lines are configured.
+    pub fn overdraft(&self) -> Option<Decimal> {
+        self.overdraft
+    }
+
+    /// The name of the account, which is only set when the metadata is reported.
+    pub fn name(&self) -> Option<&'a str> {
+        self.name
+    }
+
+    /// The external reference of the account, which is only set when the metadata is
+    /// reported.
+    pub fn reference(&self) -> Option<&'a str> {
+        self.reference
+    }
+
+    /// The tags of the account (separated by semicolons), which are only set when the
+    /// metadata is reported.
+    pub fn tags(&self) -> Option<&str> {
+        self.tags.as_deref()
+    }
+
+    /// The number of open disputes in the currency of the row, which is only set when the
+    /// dispute statistics are reported (same as for the other `dispute` getters).
+    pub fn open_disputes(&self) -> Option<usize> {
+        self.open_disputes
+    }
+
+    /// The funds held under the open disputes.
+    pub fn disputed_amount(&self) -> Option<Decimal> {
+        self.disputed_amount
+    }
+
+    /// The number of resolves.
+    pub fn resolved_disputes(&self) -> Option<u64> {
+        self.resolved_disputes
+    }
+
+    /// The funds released by resolves.
+    pub fn resolved_amount(&self) -> Option<Decimal> {
+        self.resolved_amount
+    }
+
+    /// The number of chargebacks.
+    pub fn chargebacks(&self) -> Option<u64> {
+        self.chargebacks
+    }
+
+    /// The funds charged back.
+    pub fn chargeback_amount(&self) -> Option<Decimal> {
+        self.chargeback_amount
+    }
+
+    /// The value of `column` as it's reported in CSV, which is unset for the columns the
+    /// report doesn't have (i.e. `currency` outside of multi-asset mode).
+    pub fn field(&self, column: AccountColumn) -> Option<String> {
+        match column {
+            AccountColumn::Client => Some(self.client.to_string()),
+            AccountColumn::Currency => self.currency.map(str::to_owned),
+            AccountColumn::Available => Some(self.available.to_string()),
+            AccountColumn::Held => Some(self.held.to_string()),
+            AccountColumn::Total => Some(self.total.to_string()),
+            AccountColumn::Locked => Some(self.locked.to_string()),
+            AccountColumn::Status => self.status.map(|status| status.to_string()),
+            AccountColumn::RiskScore => self.risk_score.map(|score| score.to_string()),
+            AccountColumn::Overdraft => self.overdraft.map(|overdraft| overdraft.to_string()),
+            AccountColumn::Name => self.name.map(str::to_owned),
+            AccountColumn::Reference => self.reference.map(str::to_owned),
+            AccountColumn::Tags => self.tags.clone(),
+            AccountColumn::OpenDisputes => self.open_disputes.map(|n| n.to_string()),
+            AccountColumn::DisputedAmount => self.disputed_amount.map(|a| a.to_string()),
+            AccountColumn::ResolvedDisputes => self.resolved_disputes.map(|n| n.to_string()),
+            AccountColumn::ResolvedAmount => self.resolved_amount.map(|a| a.to_string()),
+            AccountColumn::Chargebacks => self.chargebacks.map(|n| n.to_string()),
+            AccountColumn::ChargebackAmount => self.chargeback_amount.map(|a| a.to_string()),
+        }
+    }
+}
+
+/// The columns of the account report, i.e. to select the ones to write (see
+/// `TransactionEngine::write_accounts_with`).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AccountColumn {
+    Client,
+    Currency,
+    Available,
+    Held,
+    Total,
+    Locked,
+    Status,
+    RiskScore,
+    Overdraft,
+    Name,
+    Reference,
+    Tags,
+    OpenDisputes,
+    DisputedAmount,
+    ResolvedDisputes,
+    ResolvedAmount,
+    Chargebacks,
+    ChargebackAmount,
+}
+
+impl AccountColumn {
+    const ALL: [AccountColumn; 18] = [
+        AccountColumn::Client,
+        AccountColumn::Currency,
+        AccountColumn::Available,
+        AccountColumn::Held,
+        AccountColumn::Total,
+        AccountColumn::Locked,
+        AccountColumn::Status,
+        AccountColumn::RiskScore,
+        AccountColumn::Overdraft,
+        AccountColumn::Name,
+        AccountColumn::Reference,
+        AccountColumn::Tags,
+        AccountColumn::OpenDisputes,
+        AccountColumn::DisputedAmount,
+        AccountColumn::ResolvedDisputes,
+        AccountColumn::ResolvedAmount,
+        AccountColumn::Chargebacks,
+        AccountColumn::ChargebackAmount,
+    ];
+
+    /// The name of the column in the header row (and the key in JSON).
+    pub fn name(self) -> &'static str {
+        match self {
+            AccountColumn::Client => "client",
+            AccountColumn::Currency => "currency",
+            AccountColumn::Available => "available",
+            AccountColumn::Held => "held",
+            AccountColumn::Total => "total",
+            AccountColumn::Locked => "locked",
+            AccountColumn::Status => "status",
+            AccountColumn::RiskScore => "risk_score",
+            AccountColumn::Overdraft => "overdraft",
+            AccountColumn::Name => "name",
+            AccountColumn::Reference => "reference",
+            AccountColumn::Tags => "tags",
+            AccountColumn::OpenDisputes => "open_disputes",
+            AccountColumn::DisputedAmount => "disputed_amount",
+            AccountColumn::ResolvedDisputes => "resolved_disputes",
+            AccountColumn::ResolvedAmount => "resolved_amount",
+            AccountColumn::Chargebacks => "chargebacks",
+            AccountColumn::ChargebackAmount => "chargeback_amount",
+        }
+    }
+}
+
+impl FromStr for AccountColumn {
+    type Err = String;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        let name = s.trim();
+        AccountColumn::ALL
+            .iter()
+            .copied()
+            .find(|column| column.name() == name)
+            .ok_or_else(|| format!("unknown column `{}`", name))
+    }
+}
+
+// Rounds `amount` to the reported precision, but leaves amounts that are precise enough as
+// they are (`round_dp` would pad zero to the maximum scale otherwise).
+pub(crate) fn rounded(amount: Decimal) -> Decimal {
+    if amount.scale() > MAX_AMOUNT_SCALE {
+        amount.round_dp(MAX_AMOUNT_SCALE)
+    } else {
+        amount
+    }
+}
+
+/// Contains data associated with a client account, and implements helper methods. Funds are
+/// tracked separately for each currency, while the status applies to the account as a
+/// whole.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(from = "StoredAccount")]
+pub struct Account {
+    // Keyed by currency code. Inputs without a currency column use the implicit currency,
+    // which is represented by an empty code.
+    balances: BTreeMap<Currency, Balance>,
+    status: Status,
+    // Withdrawals are blocked until the open disputes are settled, since one of them took the
+    // available funds below zero (see `OverdraftPolicy::Restrict`). Omitted from checkpoints
+    // unless set.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    under_dispute: bool,
+    // Whether there's been a chargeback on the account. Only needs to be tracked for accounts
+    // that didn't get frozen as a result, so it's omitted from checkpoints unless set.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    charged_back: bool,
+    // Whether a withdrawal or transfer has used the overdraft line of the account (see
+    // `BalanceLimits`), which can take the total below zero as well. Omitted from
+    // checkpoints unless set.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    overdrawn: bool,
+    // The timestamp of the latest transaction of the client, only tracked to check the order
+    // of the input (see `TimeOrdering::PerClient`), and omitted from checkpoints unless set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    latest_timestamp: Option<u64>,
+    // What the risk rules flagged the account for (see `risk`), omitted from checkpoints
+    // unless there are any.
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    risk_flags: BTreeSet<RiskFlag>,
+    // Descriptive details from a seed file (see `read_metadata`), omitted from checkpoints
+    // unless there are any. Boxed, since most accounts don't have any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    metadata: Option<Box<AccountMetadata>>,
+    // The held funds of the opening balances (see `TransactionEngine::open_balance`) by
+    // currency, which none of the disputes and holds the engine knows about account for.
+    // Omitted from checkpoints unless there are any.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    carried_held: BTreeMap<Currency, Decimal>,
+    // The resolved and charged back disputes by currency, omitted from checkpoints unless
+    // there are any.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    dispute_stats: BTreeMap<Currency, DisputeStats>,
+    // Number of disputes which have not been settled yet. Not part of checkpoints, since it
+    // can be derived from the disputed transactions when loading them.
+    #[serde(skip)]
+    open_disputes: usize,
+    // Only recorded when enabled for the engine, and not part of checkpoints.
+    #[serde(skip)]
+    history: Vec<AppliedOp>,
+    // The events of the changes to the account which haven't made it into the event log of
+    // the engine yet, only recorded while it keeps one (see `set_recording`).
+    #[serde(skip)]
+    events: Option<Vec<AccountEvent>>,
+}
+
+// An account as stored in checkpoints. Older ones have `frozen` and `closed` flags instead of
+// the status (closed accounts could be frozen as well, in which case they stay closed).
+#[derive(Deserialize)]
+struct StoredAccount {
+    balances: BTreeMap<Currency, Balance>,
+    #[serde(default)]
+    status: Option<Status>,
+    #[serde(default)]
+    frozen: bool,
+    #[serde(default)]
+    closed: bool,
+    #[serde(default)]
+    under_dispute: bool,
+    #[serde(default)]
+    charged_back: bool,
+    #[serde(default)]
+    overdrawn: bool,
+    #[serde(default)]
+    latest_timestamp: Option<u64>,
+    #[serde(default)]
+    risk_flags: BTreeSet<RiskFlag>,
+    #[serde(default)]
+    metadata: Option<Box<AccountMetadata>>,
+    #[serde(default)]
+    carried_held: BTreeMap<Currency, Decimal>,
+    #[serde(default)]
+    dispute_stats: BTreeMap<Currency, DisputeStats>,
+}
+
+impl From<StoredAccount> for Account {
+    fn from(stored: StoredAccount) -> Self {
+        let status = stored.status.unwrap_or(if stored.closed {
+            Status::Closed
+        } else if stored.frozen {
+            Status::Frozen
+        } else {
+            Status::Active
+        });
+        Account {
+            balances: stored.balances,
+            status,
+            under_dispute: stored.under_dispute,
+            charged_back: stored.charged_back,
+            overdrawn: stored.overdrawn,
+            latest_timestamp: stored.latest_timestamp,
+            risk_flags: stored.risk_flags,
+            metadata: stored.metadata,
+            carried_held: stored.carried_held,
+            dispute_stats: stored.dispute_stats,
+            open_disputes: 0,
+            history: Vec::new(),
+            events: None,
+        }
+    }
+}
+
+impl Account {
+    /// Builds an account with the given funds in the implicit currency, so that focused tests
+    /// can start from arbitrary states (i.e. close to `Decimal::MAX`) without having to replay
+    /// transactions. No validation is performed.
+    #[cfg(any(test, feature = "testing"))]
+    pub fn from_parts(available: Decimal, held: Decimal, frozen: bool) -> Self {
+        let mut balances = BTreeMap::new();
+        let total = available + held;
+        balances.insert(
+            Currency::new(),
+            Balance {
+                available,
+                held,
+                total,
+            },
+        );
+        Account {
+            balances,
+            status: if frozen {
+                Status::Frozen
+            } else {
+                Status::Active
+            },
+            under_dispute: false,
+            charged_back: false,
+            overdrawn: false,
+            latest_timestamp: None,
+            risk_flags: BTreeSet::new(),
+            metadata: None,
+            carried_held: BTreeMap::new(),
+            dispute_stats: BTreeMap::new(),
+            open_disputes: 0,
+            history: Vec::new(),
+            events: None,
+        }
+    }
+
+    /// Returns a mutable handle to the balance for `currency` (and creates a new entry first
+    /// if one does not already exist).
+    pub fn balance_mut(&mut self, currency: &str) -> BalanceMut<'_> {
+        // Not using the `entry` API here, so we only allocate a key for new currencies.
+        if !self.balances.contains_key(currency) {
+            self.apply(AccountEvent::BalanceOpened {
+                currency: currency.to_owned(),
+            });
+        }
+        BalanceMut {
+            balance: self.balances.get_mut(currency).unwrap(),
+            recorder: self
+                .events
+                .as_mut()
+                .map(|events| (currency.to_owned(), events)),
+        }
+    }
+
+    /// Returns the balance for `currency`, which is all zeros if the account never held any
+    /// funds in that currency.
+    pub fn balance(&self, currency: &str) -> &Balance {
+        self.balances.get(currency).unwrap_or(&ZERO_BALANCE)
+    }
+
+    /// Iterates over the balances of the account, ordered by currency code. Accounts which
+    /// don't hold funds in any currency yet report a zero balance in the implicit currency.
+    pub fn balances(&self) -> impl Iterator<Item = (&str, &Balance)> {
+        let empty = if self.balances.is_empty() {
+            Some(("", &ZERO_BALANCE))
+        } else {
+            None
+        };
+
+        self.balances
+            .iter()
+            .map(|(currency, balance)| (currency.as_str(), balance))
+            .chain(empty)
+    }
+
+    /// Sets the total of every balance to the sum of its available and held funds (see
+    /// `repair::Correction::RecomputeTotal`), and returns the currencies of the balances it
+    /// changed, along with how much their total changed by.
+    pub(crate) fn recompute_totals(&mut self) -> Vec<(Currency, Decimal)> {
+        let mut changed = Vec::new();
+        for (currency, balance) in self.balances.iter_mut() {
+            if !balance.is_consistent() {
+                let total = balance.total;
+                balance.recompute_total();
+                changed.push((currency.clone(), balance.total - total));
+            }
+        }
+        changed
+    }
+
+    // The methods below only change the status if the account is in the status they
+    // transition from, and leave it as it is otherwise. Which transitions are allowed in the
+    // first place is up to the engine.
+
+    /// Freezes the account, unless it's closed. This supersedes a review.
+    pub fn freeze(&mut self) -> &mut Self {
+        if !matches!(self.status, Status::Closed | Status::Frozen) {
+            self.apply(AccountEvent::AccountFrozen);
+        }
+        self
+    }
+
+    /// Accounts only get frozen by chargebacks, so unfreezing keeps track of the chargeback
+    /// instead (which older checkpoints don't record for frozen accounts).
+    pub fn unfreeze(&mut self) -> &mut Self {
+        if self.status == Status::Frozen {
+            self.apply(AccountEvent::AccountUnfrozen);
+            self.mark_charged_back();
+        }
+        self
+    }
+
+    pub fn close(&mut self) -> &mut Self {
+        if self.status != Status::Closed {
+            self.apply(AccountEvent::AccountClosed);
+        }
+        self
+    }
+
+    pub fn reopen(&mut self) -> &mut Self {
+        if self.status == Status::Closed {
+            self.apply(AccountEvent::AccountReopened);
+        }
+        self
+    }
+
+    /// Puts an active account under review.
+    pub fn review(&mut self) -> &mut Self {
+        if self.status == Status::Active {
+            self.apply(AccountEvent::ReviewStarted);
+        }
+        self
+    }
+
+    /// Ends the review of the account.
+    pub fn approve(&mut self) -> &mut Self {
+        if self.status == Status::UnderReview {
+            self.apply(AccountEvent::ReviewApproved);
+        }
+        self
+    }
+
+    // Same as for the status, the flags below only make for an event when they get set.
+
+    pub fn mark_under_dispute(&mut self) -> &mut Self {
+        if !self.under_dispute {
+            self.apply(AccountEvent::WithdrawalsBlocked);
+        }
+        self
+    }
+
+    pub fn mark_charged_back(&mut self) -> &mut Self {
+        if !self.charged_back {
+            self.apply(AccountEvent::ChargebackRecorded);
+        }
+        self
+    }
+
+    pub fn mark_overdrawn(&mut self) -> &mut Self {
+        if !self.overdrawn {
+            self.apply(AccountEvent::OverdraftUsed);
+        }
+        self
+    }
+
+    /// Returns an error if the account (of `client`) is closed, or frozen unless `allow_frozen`
+    /// is set.
+    pub fn check_frozen_mut(&mut self, client: ClientId, allow_frozen: bool) -> Result<&mut Self> {
+        match self.status {
+            Status::Closed => Err(Error::AccountClosed { client }),
+            Status::Frozen if !allow_frozen => Err(Error::AccountFrozen { client }),
+            _ => Ok(self),
+        }
+    }
+
+    /// The funds (by currency) to pay out for closing the account (of `client`) under
+    /// `policy`, which are none unless paying out. Returns an error if the account can't be
+    /// closed with the funds it holds.
+    pub fn residual_payouts(
+        &self,
+        client: ClientId,
+        policy: ResidualBalancePolicy,
+    ) -> Result<Vec<(Currency, Decimal)>> {
+        let mut payouts = Vec::new();
+        for (currency, balance) in self.balances() {
+            let (available, held) = (balance.available(), balance.held());
+            let settled = match policy {
+                ResidualBalancePolicy::Retain => true,
+                ResidualBalancePolicy::Reject => available.is_zero() && held.is_zero(),
+                ResidualBalancePolicy::PayOut => held.is_zero() && !available.is_sign_negative(),
+            };
+            if !settled {
+                return Err(Error::ResidualBalance { client });
+            }
+            if policy == ResidualBalancePolicy::PayOut && !available.is_zero() {
+                payouts.push((currency.to_owned(), available));
+            }
+        }
+        Ok(payouts)
+    }
+
+    /// Returns an error if withdrawals from the account (of `client`) are blocked, because
+    /// it's under review, or until its disputes have been settled.
+    pub fn check_withdrawable_mut(&mut self, client: ClientId) -> Result<&mut Self> {
+        if self.status == Status::UnderReview {
+            return Err(Error::AccountUnderReview { client });
+        }
+        if self.under_dispute {
+            return Err(Error::AccountUnderDispute { client });
+        }
+        Ok(self)
+    }
+
+    /// Checks whether the account is in a state that can actually be reached by processing
+    /// some sequence of transactions, which helps catch corrupted inputs when loading
+    /// existing state. The rules (which apply to the balance of every currency) are:
+    /// - The total is the sum of `available` and `held`, since every change of either of them
+    ///   is applied to the total as well.
+    /// - `held` is never negative, since it only ever contains amounts of disputed
+    ///   transactions.
+    /// - The total funds of an account are never negative, unless there's been a chargeback.
+    ///   Withdrawals require sufficient available funds, and disputes/resolves either move
+    ///   funds between `available` and `held`, or hold and release the amount of a withdrawal,
+    ///   so only a chargeback can bring the total below zero. Frozen accounts are exempt as
+    ///   well, since chargebacks freeze accounts by default (and older checkpoints don't
+    ///   record chargebacks otherwise), and so are accounts which used an overdraft line.
+    pub fn is_valid(&self) -> bool {
+        let negative_allowed = self.frozen() || self.charged_back || self.overdrawn;
+        self.balances.values().all(|b| {
+            b.is_consistent()
+                && b.held >= Decimal::ZERO
+                && (negative_allowed || b.total() >= Decimal::ZERO)
+        })
+    }
+
+    /// A serializable view of the account (of `client`), i.e. for embedders to hand out the
+    /// results without going through the account report.
+    pub fn view(&self, client: ClientId) -> AccountView<'_> {
+        let balances = self
+            .balances()
+            .map(|(currency, balance)| {
+                let rounded = Balance {
+                    available: rounded(balance.available),
+                    held: rounded(balance.held),
+                    total: rounded(balance.total),
+                };
+                (currency, rounded)
+            })
+            .collect();
+        AccountView {
+            client,
+            balances,
+            status: self.status,
+            locked: self.frozen(),
+            open_disputes: self.open_disputes,
+            metadata: self.metadata(),
+        }
+    }
+
+    /// Returns whether any of the funds are held in an explicit currency.
+    pub fn is_multi_asset(&self) -> bool {
+        self.balances.keys().any(|currency| !currency.is_empty())
+    }
+
+    pub fn status(&self) -> Status {
+        self.status
+    }
+
     pub fn frozen(&self) -> bool {
-        self.frozen
+        self.status == Status::Frozen
+    }
+
+    pub fn closed(&self) -> bool {
+        self.status == Status::Closed
+    }
+
+    /// Whether the account has been subject to a chargeback (even if unfrozen since).
+    pub fn charged_back(&self) -> bool {
+        self.charged_back
+    }
+
+    /// Whether the overdraft line of the account has been used (even if repaid since).
+    pub fn overdrawn(&self) -> bool {
+        self.overdrawn
+    }
+
+    /// The timestamp of the latest transaction of the client, when checking the order of
+    /// timestamps per client.
+    pub fn latest_timestamp(&self) -> Option<u64> {
+        self.latest_timestamp
+    }
+
+    /// Moves the latest timestamp of the account forward to `timestamp` (if it's later).
+    pub fn record_timestamp(&mut self, timestamp: u64) -> &mut Self {
+        if self.latest_timestamp < Some(timestamp) {
+            self.apply(AccountEvent::TimestampRecorded { timestamp });
+        }
+        self
+    }
+
+    /// What the risk rules flagged the account for, in a fixed order.
+    pub fn risk_flags(&self) -> impl Iterator<Item = RiskFlag> + '_ {
+        self.risk_flags.iter().copied()
+    }
+
+    /// Returns whether the account hadn't been flagged for `flag` yet.
+    pub fn flag(&mut self, flag: RiskFlag) -> bool {
+        let new = !self.risk_flags.contains(&flag);
+        if new {
+            self.apply(AccountEvent::RiskFlagged { flag });
+        }
+        new
+    }
+
+    /// The descriptive details of the account, if any have been attached.
+    pub fn metadata(&self) -> Option<&AccountMetadata> {
+        self.metadata.as_deref()
+    }
+
+    /// Attaches descriptive details to the account, replacing any previous ones.
+    pub fn set_metadata(&mut self, metadata: AccountMetadata) -> &mut Self {
+        self.metadata = Some(Box::new(metadata));
+        self
+    }
+
+    /// The held funds in `currency` carried over from the opening balance of the account,
+    /// which stay held, since no dispute or hold known to the engine accounts for them.
+    pub fn carried_held(&self, currency: &str) -> Decimal {
+        self.carried_held.get(currency).copied().unwrap_or_default()
+    }
+
+    // All the carried over held funds, by currency.
+    pub(crate) fn carried_held_all(&self) -> impl Iterator<Item = (&str, Decimal)> {
+        self.carried_held
+            .iter()
+            .map(|(currency, &held)| (currency.as_str(), held))
+    }
+
+    // Adds `held` to the held funds in `currency` which are carried over (see
+    // `carried_held`). The funds themselves have to be held separately.
+    pub(crate) fn carry_held(&mut self, currency: &str, held: Decimal) -> &mut Self {
+        *self.carried_held.entry(currency.to_owned()).or_default() += held;
+        self
+    }
+
+    // Adds the funds and open disputes of `other`, the account of the same client in another
+    // engine (see `TransactionEngine::merge`), in every currency. The account ends up with
+    // the more restrictive of the two statuses (closed, then frozen, then under review), and
+    // with the flags of either; the metadata of `other` only applies if it has none itself.
+    // Absorbing into a new account makes for the events which open `other` as it is.
+    pub(crate) fn absorb(&mut self, other: Account) {
+        for (currency, balance) in other.balances {
+            let mut merged = self.balance_mut(&currency);
+            merged
+                .adjust_available(balance.available)
+                .increase_held(balance.held);
+            // Totals which don't add up (see `TotalMismatch`) stay that way.
+            merged.balance.total += balance.total - balance.available - balance.held;
+        }
+        for (currency, held) in other.carried_held {
+            self.carry_held(&currency, held);
+        }
+
+        let restriction = |status: Status| match status {
+            Status::Active => 0,
+            Status::UnderReview => 1,
+            Status::Frozen => 2,
+            Status::Closed => 3,
+        };
+        if restriction(other.status) > restriction(self.status) {
+            match other.status {
+                Status::UnderReview => self.apply(AccountEvent::ReviewStarted),
+                Status::Frozen => self.apply(AccountEvent::AccountFrozen),
+                Status::Closed => self.apply(AccountEvent::AccountClosed),
+                Status::Active => {}
+            }
+        }
+        if other.under_dispute {
+            self.mark_under_dispute();
+        }
+        if other.charged_back {
+            self.mark_charged_back();
+        }
+        if other.overdrawn {
+            self.mark_overdrawn();
+        }
+        if let Some(timestamp) = other.latest_timestamp {
+            self.record_timestamp(timestamp);
+        }
+        for flag in other.risk_flags {
+            self.flag(flag);
+        }
+        if self.metadata.is_none() {
+            self.metadata = other.metadata;
+        }
+        for (currency, stats) in other.dispute_stats {
+            let merged = self.dispute_stats.entry(currency).or_default();
+            merged.resolved += stats.resolved;
+            merged.resolved_amount += stats.resolved_amount;
+            merged.chargebacks += stats.chargebacks;
+            merged.chargeback_amount += stats.chargeback_amount;
+        }
+        self.open_disputes += other.open_disputes;
+        self.history.extend(other.history);
+    }
+
+    /// Whether withdrawals are blocked until the open disputes have been settled.
+    pub fn under_dispute(&self) -> bool {
+        self.under_dispute
+    }
+
+    pub fn open_dispute(&mut self) -> &mut Self {
+        self.open_disputes += 1;
+        self
+    }
+
+    /// Lifts the withdrawal block once the last open dispute has been settled. The engine
+    /// recounts the open disputes of the accounts it loads, so there's always one to settle,
+    /// but the count doesn't wrap around regardless.
+    pub fn settle_dispute(&mut self) -> &mut Self {
+        self.open_disputes = self.open_disputes.saturating_sub(1);
+        if self.open_disputes == 0 && self.under_dispute {
+            self.apply(AccountEvent::WithdrawalsUnblocked);
+        }
+        self
+    }
+
+    // Forgets about the open disputes before recounting them, without lifting the withdrawal
+    // block (which is part of checkpoints).
+    pub(crate) fn clear_open_disputes(&mut self) {
+        self.open_disputes = 0;
+    }
+
+    /// The number of disputes which have been opened, but not resolved or charged back yet.
+    pub fn open_disputes(&self) -> usize {
+        self.open_disputes
+    }
+
+    /// How many disputes of funds in `currency` have been resolved and charged back, and for
+    /// how much.
+    pub fn dispute_stats(&self, currency: &str) -> DisputeStats {
+        self.dispute_stats
+            .get(currency)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    // Counts a resolve of `amount` in `currency`, which may settle the dispute in part.
+    pub(crate) fn record_resolved(&mut self, currency: &str, amount: Decimal) {
+        let stats = self.dispute_stats_mut(currency);
+        stats.resolved += 1;
+        stats.resolved_amount += amount;
+    }
+
+    // Counts a chargeback of `amount` in `currency`.
+    pub(crate) fn record_chargeback(&mut self, currency: &str, amount: Decimal) {
+        let stats = self.dispute_stats_mut(currency);
+        stats.chargebacks += 1;
+        stats.chargeback_amount += amount;
+    }
+
+    fn dispute_stats_mut(&mut self, currency: &str) -> &mut DisputeStats {
+        // Same as for `balance_mut`, only allocating a key for new currencies.
+        if !self.dispute_stats.contains_key(currency) {
+            self.dispute_stats
+                .insert(currency.to_owned(), DisputeStats::default());
+        }
+        self.dispute_stats.get_mut(currency).unwrap()
+    }
+
+    /// Appends an operation that has just been applied to the history of the account, along
+    /// with the current balance for `currency`.
+    pub fn record_op(&mut self, type_: Type, tx: TransactionId, amount: Decimal, currency: &str) {
+        let balance = self.balance(currency);
+        let op = AppliedOp {
+            type_,
+            tx,
+            amount,
+            currency: currency.to_owned(),
+            fee: false,
+            available: balance.available(),
+            held: balance.held(),
+        };
+        self.history.push(op);
+    }
+
+    /// Appends the fee charged for withdrawal `tx` to the history, right after the withdrawal
+    /// itself (with the balance after deducting both).
+    pub fn record_fee(&mut self, tx: TransactionId, fee: Decimal, currency: &str) {
+        self.record_op(Type::Withdrawal, tx, fee, currency);
+        if let Some(op) = self.history.last_mut() {
+            op.fee = true;
+        }
+    }
+
+    /// The operations applied to the account so far, in order.
+    pub fn history(&self) -> &[AppliedOp] {
+        &self.history
+    }
+
+    /// Applies `event` to the account, i.e. to derive it from the event log (see
+    /// `events::fold`). Every change the engine makes to an account goes through here as
+    /// well. The event is assumed to be valid for the account.
+    pub fn apply_event(&mut self, event: &AccountEvent) {
+        match event {
+            AccountEvent::AccountOpened => {}
+            AccountEvent::BalanceOpened { currency } => {
+                self.balances.entry(currency.clone()).or_default();
+            }
+            AccountEvent::FundsDeposited { currency, .. }
+            | AccountEvent::FundsWithdrawn { currency, .. }
+            | AccountEvent::FundsHeld { currency, .. }
+            | AccountEvent::FundsReleased { currency, .. } => {
+                self.balances
+                    .entry(currency.clone())
+                    .or_default()
+                    .apply(event);
+            }
+            AccountEvent::AccountFrozen => self.status = Status::Frozen,
+            AccountEvent::AccountClosed => self.status = Status::Closed,
+            AccountEvent::ReviewStarted => self.status = Status::UnderReview,
+            AccountEvent::AccountUnfrozen
+            | AccountEvent::AccountReopened
+            | AccountEvent::ReviewApproved => self.status = Status::Active,
+            AccountEvent::WithdrawalsBlocked => self.under_dispute = true,
+            AccountEvent::WithdrawalsUnblocked => self.under_dispute = false,
+            AccountEvent::ChargebackRecorded => self.charged_back = true,
+            AccountEvent::OverdraftUsed => self.overdrawn = true,
+            AccountEvent::RiskFlagged { flag } => {
+                self.risk_flags.insert(*flag);
+            }
+            AccountEvent::TimestampRecorded { timestamp } => {
+                self.latest_timestamp = Some(*timestamp);
+            }
+        }
+    }
+
+    // Applies `event`, and records it when recording events.
+    fn apply(&mut self, event: AccountEvent) {
+        self.apply_event(&event);
+        if let Some(events) = &mut self.events {
+            events.push(event);
+        }
+    }
+
+    // Starts or stops recording the events of the changes to the account, for the event log
+    // of the engine to take (see `take_events`).
+    pub(crate) fn set_recording(&mut self, enabled: bool) {
+        self.events = enabled.then(Vec::new);
+    }
+
+    // The events recorded since the last call, in order.
+    pub(crate) fn take_events(&mut self) -> Vec<AccountEvent> {
+        self.events.as_mut().map(mem::take).unwrap_or_default()
+    }
+
+    // The events which open an account like this one from scratch, i.e. for the accounts an
+    // engine already has when its event log gets enabled.
+    pub(crate) fn opening_events(&self) -> Vec<AccountEvent> {
+        let mut opened = Account::default();
+        opened.set_recording(true);
+        opened.absorb(self.clone());
+        opened.take_events()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal::Decimal;
+
+    use super::*;
+
+    fn client() -> ClientId {
+        ClientId::from(1)
+    }
+
+    #[test]
+    fn withdrawing_exactly_the_available_funds() {
+        let mut account = Account::from_parts(Decimal::new(15, 1), Decimal::ONE, false);
+        account
+            .balance_mut("")
+            .try_decrease_available(client(), Decimal::new(15, 1))
+            .unwrap();
+        let balance = account.balance("");
+        assert_eq!(balance.available(), Decimal::ZERO);
+        assert_eq!(balance.held(), Decimal::ONE);
+        assert_eq!(balance.total(), Decimal::ONE);
+
+        // The held funds can't be withdrawn, and a failed withdrawal leaves the funds as
+        // they were.
+        let result = account
+            .balance_mut("")
+            .try_decrease_available(client(), Decimal::new(1, 4))
+            .map(|_| ());
+        assert!(matches!(
+            result,
+            Err(Error::InsufficientFunds { available, .. }) if available.is_zero()
+        ));
+        assert!(account.balance("").is_consistent());
+        assert_eq!(account.balance("").total(), Decimal::ONE);
+        assert!(account.is_valid());
+    }
+
+    #[test]
+    fn funds_near_the_decimal_range() {
+        let mut account = Account::from_parts(Decimal::MAX, Decimal::ZERO, false);
+        let result = account
+            .balance_mut("")
+            .try_increase_available(client(), Decimal::ONE)
+            .map(|_| ());
+        assert!(matches!(result, Err(Error::Overflow { .. })));
+        let result = account
+            .balance_mut("")
+            .try_increase_held(client(), Decimal::ONE)
+            .map(|_| ());
+        assert!(matches!(result, Err(Error::Overflow { .. })));
+        assert_eq!(account.balance("").available(), Decimal::MAX);
+        assert_eq!(account.balance("").total(), Decimal::MAX);
+
+        account
+            .balance_mut("")
+            .try_decrease_available(client(), Decimal::MAX)
+            .unwrap();
+        assert_eq!(account.balance("").total(), Decimal::ZERO);
+
+        let mut account = Account::from_parts(Decimal::MAX - Decimal::ONE, Decimal::ZERO, true);
+        account
+            .balance_mut("")
+            .try_increase_available(client(), Decimal::ONE)
+            .unwrap();
+        assert_eq!(account.balance("").available(), Decimal::MAX);
+        assert!(account.frozen());
+        assert!(matches!(
+            account.check_frozen_mut(client(), false).map(|_| ()),
+            Err(Error::AccountFrozen { .. })
+        ));
+
+        // Holding a fraction of such funds would round the available ones.
+        let mut account = Account::from_parts(Decimal::MAX, Decimal::ZERO, false);
+        let result = account
+            .balance_mut("")
+            .try_hold_to(client(), Decimal::new(5_463_845, 1), Decimal::ZERO)
+            .map(|_| ());
+        assert!(matches!(result, Err(Error::Overflow { .. })));
+        assert_eq!(account.balance("").available(), Decimal::MAX);
+        account
+            .balance_mut("")
+            .try_hold_to(client(), Decimal::new(546_384, 0), Decimal::ZERO)
+            .unwrap();
+        assert!(account.balance("").is_consistent());
+    }
+
+    #[test]
+    fn recorded_events_fold_into_the_account() {
+        let mut account = Account::default();
+        account.set_recording(true);
+        account
+            .balance_mut("")
+            .increase_available(Decimal::new(10, 0))
+            .hold(Decimal::ONE)
+            .settle_held(Decimal::ONE, Decimal::ZERO);
+        account.balance_mut("EUR").increase_held(Decimal::ONE);
+        account
+            .mark_overdrawn()
+            .freeze()
+            .unfreeze()
+            .record_timestamp(7);
+
+        let mut folded = Account::default();
+        for event in account.take_events() {
+            folded.apply_event(&event);
+        }
+        assert_eq!(folded.balance("").available(), Decimal::new(9, 0));
+        assert_eq!(folded.balance("").total(), Decimal::new(9, 0));
+        assert_eq!(folded.balance("EUR").held(), Decimal::ONE);
+        assert!(folded.overdrawn() && folded.charged_back() && !folded.frozen());
+        assert_eq!(folded.latest_timestamp(), Some(7));
+        assert!(account.take_events().is_empty());
+        assert_eq!(account.opening_events().len(), 8);
     }
 }
@@ -0,0 +1,289 @@
+use std::convert::TryFrom;
+use std::fmt;
+use std::iter;
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+use rust_decimal::Decimal;
+use serde::de::{self, IntoDeserializer, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::transaction::{Error, MAX_AMOUNT_SCALE};
+
+// The number of minor units per unit, i.e. `10^MAX_AMOUNT_SCALE`.
+const MINOR_UNITS_PER_UNIT: i64 = 10_i64.pow(MAX_AMOUNT_SCALE);
+
+// Formatting characters (i.e. currency symbols or thousands separators) which are stripped
+// from amounts before parsing them. Set at most once, before any input gets deserialized;
+// nothing is stripped by default, since i.e. `,` is a decimal separator in some locales.
+static FORMATTING_CHARS: OnceLock<Vec<char>> = OnceLock::new();
+
+/// Compares two amounts, treating them as equal if they differ by no more than `epsilon`
+/// (which should be non-negative). Values are normalized before the comparison, so
+/// differences in scale alone (i.e. `50.0` vs `50.0000`) never cause mismatches.
+pub fn amounts_equal(a: Decimal, b: Decimal, epsilon: Decimal) -> bool {
+    (a.normalize() - b.normalize()).abs() <= epsilon
+}
+
+/// Configures the characters which `deserialize_formatted` strips from amounts. Only the
+/// first call has any effect.
+pub fn set_formatting_chars(chars: &str) {
+    let _ = FORMATTING_CHARS.set(chars.chars().collect());
+}
+
+/// Deserializes an optional amount, after stripping any of the configured formatting
+/// characters (so `$1,234.5678` parses as `1234.5678` when `$` and `,` are configured).
+/// The remaining string goes through the regular `Decimal` parsing logic, so plain numeric
+/// strings are handled exactly the same either way. Amounts are parsed from the borrowed
+/// field contents, which avoids allocating unless there's something to strip.
+pub fn deserialize_formatted<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Decimal>, D::Error> {
+    deserializer.deserialize_option(FormattedAmountVisitor)
+}
+
+struct FormattedAmountVisitor;
+
+impl<'de> Visitor<'de> for FormattedAmountVisitor {
+    type Value = Option<Decimal>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "an optional amount")
+    }
+
+    fn visit_none<E: de::Error>(self) -> Result<Self::Value, E> {
+        Ok(None)
+    }
+
+    fn visit_unit<E: de::Error>(self) -> Result<Self::Value, E> {
+        Ok(None)
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_str(self)
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<Self::Value, E> {
+        let parse = |value: &str| {
+            // Amounts which fit into minor units don't need the general parser, which is
+            // still used for the others (i.e. ones with more decimal places, to be rounded
+            // or rejected later on), so both yield the same results.
+            // The amount keeps the scale it was written with, same as for the latter. Zero is
+            // left to the latter as well, which keeps the sign of `-0`.
+            #[cfg(feature = "minor-units")]
+            match value.parse::<MinorUnits>() {
+                Ok(amount) if amount != MinorUnits::ZERO => {
+                    let scale = value.find('.').map_or(0, |index| value.len() - index - 1);
+                    let scale = scale as u32;
+                    let mantissa = amount.get() / 10_i64.pow(MAX_AMOUNT_SCALE - scale);
+                    return Ok(Some(Decimal::new(mantissa, scale)));
+                }
+                _ => {}
+            }
+            <Decimal as Deserialize>::deserialize(value.into_deserializer())
+                .map(Some)
+                .map_err(|e: de::value::Error| E::custom(e))
+        };
+
+        match FORMATTING_CHARS.get() {
+            Some(chars) if value.contains(chars.as_slice()) => {
+                parse(&value.replace(chars.as_slice(), ""))
+            }
+            _ => parse(value),
+        }
+    }
+}
+
+/// A non-negative amount with at most four decimal places, i.e. one the engine accepts for
+/// deposits and withdrawals under the default settings (which may still reject zero, or
+/// amounts above a maximum). Parses and (de)serializes the same way as `Decimal`, with the
+/// same checks as `Amount::new`.
+#[derive(
+    Clone, Copy, Debug, Default, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize,
+)]
+#[serde(try_from = "Decimal", into = "Decimal")]
+pub struct Amount(Decimal);
+
+impl Amount {
+    pub const ZERO: Amount = Amount(Decimal::ZERO);
+
+    /// Fails with the same errors as processing a transaction with `amount` would, that is
+    /// `Error::InvalidAmount` for negative amounts, and `Error::ExcessivePrecision` for ones
+    /// with more than four decimal places (which would have to be rounded first, see
+    /// `Decimal::round_dp`).
+    pub fn new(amount: Decimal) -> Result<Self, Error> {
+        if amount.is_sign_negative() {
+            return Err(Error::InvalidAmount { amount });
+        }
+        if amount.normalize().scale() > MAX_AMOUNT_SCALE {
+            return Err(Error::ExcessivePrecision { amount });
+        }
+        Ok(Amount(amount))
+    }
+
+    pub fn get(self) -> Decimal {
+        self.0
+    }
+}
+
+impl TryFrom<Decimal> for Amount {
+    type Error = Error;
+
+    fn try_from(amount: Decimal) -> Result<Self, Self::Error> {
+        Amount::new(amount)
+    }
+}
+
+impl From<Amount> for Decimal {
+    fn from(amount: Amount) -> Self {
+        amount.0
+    }
+}
+
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+impl FromStr for Amount {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let amount = s
+            .parse::<Decimal>()
+            .map_err(|_| format!("invalid amount `{}`", s))?;
+        Amount::new(amount).map_err(|e| e.to_string())
+    }
+}
+
+/// An amount as a whole number of minor units, i.e. ten-thousandths (the precision of input
+/// amounts and reported balances), for embedders which keep amounts as plain integers rather
+/// than `Decimal`s. Parses from and formats to the same decimal strings as the CSV input
+/// and output (without an exponent, and with at most four decimal places), using integer
+/// arithmetic only, and converts to and from `Decimal` losslessly.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct MinorUnits(i64);
+
+impl MinorUnits {
+    pub const ZERO: MinorUnits = MinorUnits(0);
+
+    pub fn new(minor_units: i64) -> Self {
+        MinorUnits(minor_units)
+    }
+
+    /// The amount in minor units.
+    pub fn get(self) -> i64 {
+        self.0
+    }
+
+    pub fn checked_add(self, other: MinorUnits) -> Option<MinorUnits> {
+        self.0.checked_add(other.0).map(MinorUnits)
+    }
+
+    pub fn checked_sub(self, other: MinorUnits) -> Option<MinorUnits> {
+        self.0.checked_sub(other.0).map(MinorUnits)
+    }
+}
+
+impl FromStr for MinorUnits {
+    type Err = String;
+
+    /// Parses amounts such as `-12.5` or `3`, but rejects ones with more than four decimal
+    /// places, or beyond the range of `i64` minor units.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid amount `{}`", s);
+        let (negative, digits) = match s.as_bytes().first() {
+            Some(b'-') => (true, &s[1..]),
+            Some(b'+') => (false, &s[1..]),
+            _ => (false, s),
+        };
+        let (whole, fraction) = match digits.find('.') {
+            Some(index) => (&digits[..index], &digits[index + 1..]),
+            None => (digits, ""),
+        };
+        if whole.is_empty() && fraction.is_empty()
+            || fraction.len() > MAX_AMOUNT_SCALE as usize
+            || !whole
+                .bytes()
+                .chain(fraction.bytes())
+                .all(|b| b.is_ascii_digit())
+        {
+            return Err(invalid());
+        }
+
+        // Accumulating negatively, so `i64::MIN` minor units can be parsed as well.
+        let mut value: i64 = 0;
+        let padding = MAX_AMOUNT_SCALE as usize - fraction.len();
+        for digit in whole
+            .bytes()
+            .chain(fraction.bytes())
+            .chain(iter::repeat_n(b'0', padding))
+        {
+            value = value
+                .checked_mul(10)
+                .and_then(|value| value.checked_sub(i64::from(digit - b'0')))
+                .ok_or_else(invalid)?;
+        }
+        if !negative {
+            value = value.checked_neg().ok_or_else(invalid)?;
+        }
+        Ok(MinorUnits(value))
+    }
+}
+
+impl fmt::Display for MinorUnits {
+    /// Formats the amount without trailing zeros (i.e. `1.5` rather than `1.5000`).
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let sign = if self.0 < 0 { "-" } else { "" };
+        let value = self.0.unsigned_abs();
+        let units = value / MINOR_UNITS_PER_UNIT as u64;
+        let fraction = value % MINOR_UNITS_PER_UNIT as u64;
+        if fraction == 0 {
+            return write!(f, "{}{}", sign, units);
+        }
+        let fraction = format!("{:0width$}", fraction, width = MAX_AMOUNT_SCALE as usize);
+        write!(f, "{}{}.{}", sign, units, fraction.trim_end_matches('0'))
+    }
+}
+
+impl From<MinorUnits> for Decimal {
+    fn from(amount: MinorUnits) -> Self {
+        Decimal::new(amount.0, MAX_AMOUNT_SCALE).normalize()
+    }
+}
+
+impl TryFrom<Decimal> for MinorUnits {
+    type Error = String;
+
+    /// Fails for amounts with more than four decimal places (which would have to be rounded
+    /// first, see `Decimal::round_dp`), or beyond the range of `i64` minor units.
+    fn try_from(amount: Decimal) -> Result<Self, Self::Error> {
+        let mut scaled = amount.normalize();
+        if scaled.scale() > MAX_AMOUNT_SCALE {
+            return Err(format!(
+                "amount {} has more than {} decimal places",
+                amount, MAX_AMOUNT_SCALE
+            ));
+        }
+        scaled = scaled
+            .checked_mul(Decimal::from(MINOR_UNITS_PER_UNIT))
+            .ok_or_else(|| format!("amount {} is out of range", amount))?;
+        i64::try_from(scaled.mantissa() / 10_i128.pow(scaled.scale()))
+            .map(MinorUnits)
+            .map_err(|_| format!("amount {} is out of range", amount))
+    }
+}
+
+impl Serialize for MinorUnits {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for MinorUnits {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.trim().parse().map_err(de::Error::custom)
+    }
+}
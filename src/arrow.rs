@@ -0,0 +1,191 @@
+//! Columnar export of the account report, for analytics stacks that ingest Apache Arrow or
+//! Parquet. The table has the same columns as the CSV report (`client`, `available`, `held`,
+//! `total`, and `locked`, plus `currency` in multi-asset mode, `status` with the
+//! administrative transaction types, `risk_score` with risk rules, and so on), with the
+//! amounts as `Decimal128` values with four decimal places.
+
+use std::io::Write;
+use std::sync::Arc;
+
+use arrow_array::{
+    ArrayRef, BooleanArray, Decimal128Array, RecordBatch, StringArray, UInt32Array, UInt64Array,
+};
+use arrow_schema::{ArrowError, DataType, Field, Schema};
+use parquet::arrow::ArrowWriter;
+use parquet::basic::{Compression, ZstdLevel};
+use parquet::errors::ParquetError;
+use parquet::file::properties::WriterProperties;
+use rust_decimal::Decimal;
+
+use crate::account::AccountRecord;
+use crate::transaction::{TransactionEngine, MAX_AMOUNT_SCALE};
+
+// The largest precision `Decimal128` supports, which covers every `Decimal` at four decimal
+// places.
+const PRECISION: u8 = 38;
+
+/// Returns the rows of `TransactionEngine::account_records` as a single record batch.
+pub fn account_batch(engine: &TransactionEngine) -> Result<RecordBatch, ArrowError> {
+    let records = engine.account_records();
+    let multi_asset = records.iter().any(|r| r.currency().is_some());
+
+    let mut fields = vec![Field::new("client", DataType::UInt32, false)];
+    let mut columns: Vec<ArrayRef> = vec![Arc::new(
+        records
+            .iter()
+            .map(|r| r.client().get())
+            .collect::<UInt32Array>(),
+    )];
+
+    if multi_asset {
+        fields.push(Field::new("currency", DataType::Utf8, false));
+        columns.push(Arc::new(
+            records
+                .iter()
+                .map(AccountRecord::currency)
+                .collect::<StringArray>(),
+        ));
+    }
+
+    for name in ["available", "held", "total"] {
+        let data_type = DataType::Decimal128(PRECISION, MAX_AMOUNT_SCALE as i8);
+        fields.push(Field::new(name, data_type, false));
+    }
+    columns.push(amount_column(records.iter().map(AccountRecord::available))?);
+    columns.push(amount_column(records.iter().map(AccountRecord::held))?);
+    columns.push(amount_column(records.iter().map(AccountRecord::total))?);
+
+    fields.push(Field::new("locked", DataType::Boolean, false));
+    columns.push(Arc::new(
+        records
+            .iter()
+            .map(|r| Some(r.locked()))
+            .collect::<BooleanArray>(),
+    ));
+
+    if records.iter().any(|r| r.status().is_some()) {
+        fields.push(Field::new("status", DataType::Utf8, false));
+        columns.push(Arc::new(
+            records
+                .iter()
+                .map(|r| r.status().map(|status| status.to_string()))
+                .collect::<StringArray>(),
+        ));
+    }
+
+    if records.iter().any(|r| r.risk_score().is_some()) {
+        fields.push(Field::new("risk_score", DataType::UInt32, false));
+        columns.push(Arc::new(
+            records
+                .iter()
+                .map(AccountRecord::risk_score)
+                .collect::<UInt32Array>(),
+        ));
+    }
+
+    if records.iter().any(|r| r.overdraft().is_some()) {
+        let data_type = DataType::Decimal128(PRECISION, MAX_AMOUNT_SCALE as i8);
+        fields.push(Field::new("overdraft", data_type, false));
+        columns.push(amount_column(
+            records.iter().map(|r| r.overdraft().unwrap_or_default()),
+        )?);
+    }
+
+    if records.iter().any(|r| r.name().is_some()) {
+        for name in ["name", "reference", "tags"] {
+            fields.push(Field::new(name, DataType::Utf8, false));
+        }
+        columns.push(Arc::new(
+            records
+                .iter()
+                .map(AccountRecord::name)
+                .collect::<StringArray>(),
+        ));
+        columns.push(Arc::new(
+            records
+                .iter()
+                .map(AccountRecord::reference)
+                .collect::<StringArray>(),
+        ));
+        columns.push(Arc::new(
+            records.iter().map(|r| r.tags()).collect::<StringArray>(),
+        ));
+    }
+
+    if records.iter().any(|r| r.open_disputes().is_some()) {
+        let counts = |name| Field::new(name, DataType::UInt64, false);
+        let amounts = |name| {
+            let data_type = DataType::Decimal128(PRECISION, MAX_AMOUNT_SCALE as i8);
+            Field::new(name, data_type, false)
+        };
+        fields.extend([
+            counts("open_disputes"),
+            amounts("disputed_amount"),
+            counts("resolved_disputes"),
+            amounts("resolved_amount"),
+            counts("chargebacks"),
+            amounts("chargeback_amount"),
+        ]);
+        columns.push(Arc::new(
+            records
+                .iter()
+                .map(|r| r.open_disputes().map(|n| n as u64))
+                .collect::<UInt64Array>(),
+        ));
+        columns.push(amount_column(
+            records
+                .iter()
+                .map(|r| r.disputed_amount().unwrap_or_default()),
+        )?);
+        columns.push(Arc::new(
+            records
+                .iter()
+                .map(AccountRecord::resolved_disputes)
+                .collect::<UInt64Array>(),
+        ));
+        columns.push(amount_column(
+            records
+                .iter()
+                .map(|r| r.resolved_amount().unwrap_or_default()),
+        )?);
+        columns.push(Arc::new(
+            records
+                .iter()
+                .map(AccountRecord::chargebacks)
+                .collect::<UInt64Array>(),
+        ));
+        columns.push(amount_column(
+            records
+                .iter()
+                .map(|r| r.chargeback_amount().unwrap_or_default()),
+        )?);
+    }
+
+    RecordBatch::try_new(Arc::new(Schema::new(fields)), columns)
+}
+
+/// Writes the account report to `w` as a (zstd compressed) Parquet file.
+pub fn write_parquet<W: Write + Send>(
+    engine: &TransactionEngine,
+    w: W,
+) -> Result<(), ParquetError> {
+    let batch = account_batch(engine)?;
+    let properties = WriterProperties::builder()
+        .set_compression(Compression::ZSTD(ZstdLevel::default()))
+        .build();
+
+    let mut writer = ArrowWriter::try_new(w, batch.schema(), Some(properties))?;
+    writer.write(&batch)?;
+    writer.close()?;
+    Ok(())
+}
+
+// The reported amounts have at most four decimal places, so they can be represented exactly at
+// that scale.
+fn amount_column<I: Iterator<Item = Decimal>>(amounts: I) -> Result<ArrayRef, ArrowError> {
+    let values = amounts
+        .map(|amount| amount.mantissa() * 10_i128.pow(MAX_AMOUNT_SCALE - amount.scale()))
+        .collect::<Decimal128Array>()
+        .with_precision_and_scale(PRECISION, MAX_AMOUNT_SCALE as i8)?;
+    Ok(Arc::new(values))
+}
@@ -0,0 +1,148 @@
+use std::pin::pin;
+use std::thread;
+
+use tokio::sync::{mpsc, oneshot};
+use tokio_stream::{Stream, StreamExt};
+
+use crate::summary::ProcessSummary;
+use crate::transaction::{Error, Outcome, Transaction, TransactionEngine};
+
+// Upper bound for the number of requests waiting for the engine thread, so producers get
+// slowed down instead of piling up requests when the engine can't keep up.
+const QUEUED_REQUESTS: usize = 1024;
+
+type Job = Box<dyn FnOnce(&mut TransactionEngine) + Send>;
+
+enum Request {
+    // Boxed, since transactions are much larger than the other requests.
+    Process(Box<Transaction>, oneshot::Sender<Outcome>),
+    Run(Job),
+}
+
+/// A handle to a `TransactionEngine` running on a dedicated thread, which can be shared across
+/// async tasks (i.e. one per connection of a server fed by many concurrent sockets). Cloning
+/// the handle is cheap, and all clones refer to the same engine. Requests from all handles
+/// are processed one at a time, in the order they arrive, so there's no need for any locking
+/// on top. The engine thread exits once every handle has been dropped.
+///
+/// Warnings are discarded, same as for `TransactionEngine::process_from_reader`.
+#[derive(Clone)]
+pub struct AsyncTransactionEngine {
+    sender: mpsc::Sender<Request>,
+}
+
+impl AsyncTransactionEngine {
+    /// Starts the engine thread, which sets up the engine via `make_engine` (so the engine
+    /// itself doesn't need to be `Send`, i.e. when it has a transaction callback).
+    pub fn spawn<F>(make_engine: F) -> Self
+    where
+        F: FnOnce() -> TransactionEngine + Send + 'static,
+    {
+        let (handle, requests) = Self::detached();
+
+        thread::spawn(move || {
+            let mut engine = make_engine();
+            for request in requests {
+                request.run(&mut engine);
+            }
+        });
+
+        handle
+    }
+
+    /// Creates a handle without starting the engine thread, for callers that drive an engine
+    /// themselves (i.e. interleaving requests with transactions from another source). The
+    /// requests have to be taken from the returned `EngineRequests`, and run on the engine.
+    pub fn detached() -> (Self, EngineRequests) {
+        let (sender, receiver) = mpsc::channel(QUEUED_REQUESTS);
+        (
+            AsyncTransactionEngine { sender },
+            EngineRequests { receiver },
+        )
+    }
+
+    /// Same as `TransactionEngine::process_transaction`.
+    pub async fn process(&self, transaction: Transaction) -> Result<(), Error> {
+        self.process_detailed(transaction).await.result()
+    }
+
+    /// Same as `TransactionEngine::process_transaction_detailed`.
+    pub async fn process_detailed(&self, transaction: Transaction) -> Outcome {
+        let (reply, outcome) = oneshot::channel();
+        self.send(Request::Process(Box::new(transaction), reply))
+            .await;
+        outcome.await.expect("The engine thread has terminated")
+    }
+
+    /// Processes every transaction from `stream` in order, and returns what happened. The
+    /// transactions of other handles may get processed in between.
+    pub async fn process_stream<S>(&self, stream: S) -> ProcessSummary
+    where
+        S: Stream<Item = Transaction>,
+    {
+        let mut stream = pin!(stream);
+        let mut summary = ProcessSummary::default();
+
+        while let Some(transaction) = stream.next().await {
+            summary.record_processed(&self.process_detailed(transaction).await);
+        }
+
+        summary
+    }
+
+    /// Runs `f` on the engine thread, once all previously submitted requests have been
+    /// processed, and returns its result. This gives access to everything else the engine
+    /// offers, i.e. `engine.write_accounts(..)` or `engine.aggregate()` for reporting.
+    pub async fn with_engine<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut TransactionEngine) -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let (reply, result) = oneshot::channel();
+        let job: Job = Box::new(move |engine| {
+            let _ = reply.send(f(engine));
+        });
+        self.send(Request::Run(job)).await;
+        result.await.expect("The engine thread has terminated")
+    }
+
+    async fn send(&self, request: Request) {
+        // The receiver only goes away if the engine thread panicked.
+        if self.sender.send(request).await.is_err() {
+            panic!("The engine thread has terminated");
+        }
+    }
+}
+
+/// The requests submitted via the handles returned by `AsyncTransactionEngine::detached`.
+/// Iterating blocks until the next request arrives, and ends once every handle has been
+/// dropped (so it must not be used from async code).
+pub struct EngineRequests {
+    receiver: mpsc::Receiver<Request>,
+}
+
+impl Iterator for EngineRequests {
+    type Item = EngineRequest;
+
+    fn next(&mut self) -> Option<EngineRequest> {
+        self.receiver.blocking_recv().map(EngineRequest)
+    }
+}
+
+/// A single request submitted via an `AsyncTransactionEngine` handle.
+pub struct EngineRequest(Request);
+
+impl EngineRequest {
+    /// Runs the request on `engine`, and replies to the handle it came from.
+    pub fn run(self, engine: &mut TransactionEngine) {
+        match self.0 {
+            Request::Process(transaction, reply) => {
+                let outcome = engine.process_transaction_detailed(*transaction);
+                engine.drain_warnings();
+                // The caller may have given up on the outcome in the meantime.
+                let _ = reply.send(outcome);
+            }
+            Request::Run(job) => job(engine),
+        }
+    }
+}
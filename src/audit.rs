@@ -0,0 +1,211 @@
+use std::io::{self, Write};
+
+use serde::Serialize;
+
+use crate::account::Account;
+use crate::input::RecordError;
+use crate::repair::Adjustment;
+use crate::sequence::SequenceAnomaly;
+use crate::transaction::{ClientId, Error, Transaction, TransactionId, Type};
+
+#[cfg(feature = "signed-audit")]
+pub use self::signed::{AuditChain, SignedAuditLog};
+
+#[cfg(feature = "signed-audit")]
+mod signed;
+
+// A single entry of the audit stream. Each processed input record results in exactly one
+// event, which gets written out as a JSON object on its own line (i.e. JSON Lines).
+#[derive(Serialize)]
+#[serde(tag = "status", rename_all = "lowercase")]
+enum Event<'a> {
+    Applied {
+        #[serde(flatten)]
+        transaction: &'a Transaction,
+    },
+    Rejected {
+        #[serde(flatten)]
+        transaction: &'a Transaction,
+        error: String,
+    },
+    // The record could not be parsed, so there's no transaction to report on.
+    Invalid {
+        line: Option<u64>,
+        error: String,
+    },
+    // A corrective adjustment of a loaded state, outside of any transaction (see `repair`).
+    Repaired {
+        #[serde(flatten)]
+        adjustment: &'a Adjustment,
+    },
+}
+
+// The state of an account right after processing a transaction that affected it.
+#[derive(Serialize)]
+struct AccountUpdate<'a> {
+    tx: TransactionId,
+    // Either `applied` or `rejected`, same as for audit events.
+    status: &'static str,
+    account: &'a Account,
+}
+
+/// Writes audit events to the underlying writer as they happen. The writer is flushed after
+/// every event, so consumers on the other side of a pipe can follow along in real time.
+pub struct AuditLog<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> AuditLog<W> {
+    pub fn new(writer: W) -> Self {
+        AuditLog { writer }
+    }
+
+    pub fn record_processed(
+        &mut self,
+        transaction: &Transaction,
+        result: &Result<(), Error>,
+    ) -> io::Result<()> {
+        let event = match result {
+            Ok(()) => Event::Applied { transaction },
+            Err(e) => Event::Rejected {
+                transaction,
+                error: e.name().to_owned(),
+            },
+        };
+        self.write_event(&event)
+    }
+
+    pub fn record_invalid(&mut self, error: &RecordError) -> io::Result<()> {
+        let event = Event::Invalid {
+            line: error.line(),
+            error: error.to_string(),
+        };
+        self.write_event(&event)
+    }
+
+    pub fn record_repair(&mut self, adjustment: &Adjustment) -> io::Result<()> {
+        self.write_event(&Event::Repaired { adjustment })
+    }
+
+    fn write_event(&mut self, event: &Event) -> io::Result<()> {
+        serde_json::to_writer(&mut self.writer, event)?;
+        self.writer.write_all(b"\n")?;
+        self.writer.flush()
+    }
+}
+
+/// Streams the post-state of the affected account after every processed transaction, as one
+/// JSON object per line. Flushed after every update, same as `AuditLog`.
+pub struct AccountStream<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> AccountStream<W> {
+    pub fn new(writer: W) -> Self {
+        AccountStream { writer }
+    }
+
+    pub fn record(
+        &mut self,
+        transaction: &Transaction,
+        result: Result<(), &Error>,
+        account: &Account,
+    ) -> io::Result<()> {
+        let update = AccountUpdate {
+            tx: transaction.tx(),
+            status: if result.is_ok() {
+                "applied"
+            } else {
+                "rejected"
+            },
+            account,
+        };
+        serde_json::to_writer(&mut self.writer, &update)?;
+        self.writer.write_all(b"\n")?;
+        self.writer.flush()
+    }
+}
+
+// A row of the rejection report. Invalid records have no transaction to report on, so only
+// the line and the reason why parsing failed are known.
+#[derive(Serialize)]
+struct Rejection<'a> {
+    line: u64,
+    #[serde(rename = "type")]
+    type_: Option<Type>,
+    client: Option<ClientId>,
+    tx: Option<TransactionId>,
+    error: &'a str,
+}
+
+/// Writes a CSV report of the input records that didn't go through, for reconciliation. Each
+/// row has the line the record starts on, and the `Error` variant for rejected transactions,
+/// or the parse error for invalid records. Records with a sequence anomaly get a row with the
+/// kind of anomaly (`SequenceGap` or `SequenceRegression`) as well, on top of any for their
+/// outcome.
+pub struct RejectionLog<W: Write> {
+    writer: csv::Writer<W>,
+}
+
+impl<W: Write> RejectionLog<W> {
+    /// Writes the header row right away, so the report is well-formed even if nothing gets
+    /// rejected.
+    pub fn new(writer: W) -> io::Result<Self> {
+        let mut writer = csv::WriterBuilder::new()
+            .has_headers(false)
+            .from_writer(writer);
+        writer.write_record(["line", "type", "client", "tx", "error"])?;
+        Ok(RejectionLog { writer })
+    }
+
+    pub fn record_rejected(
+        &mut self,
+        line: u64,
+        transaction: &Transaction,
+        error: &Error,
+    ) -> io::Result<()> {
+        self.write_row(Rejection {
+            line,
+            type_: Some(transaction.type_()),
+            client: Some(transaction.client()),
+            tx: Some(transaction.tx()),
+            error: error.name(),
+        })
+    }
+
+    pub fn record_invalid(&mut self, line: u64, error: &RecordError) -> io::Result<()> {
+        let error = error.to_string();
+        self.write_row(Rejection {
+            line,
+            type_: None,
+            client: None,
+            tx: None,
+            error: &error,
+        })
+    }
+
+    pub fn record_sequence_anomaly(
+        &mut self,
+        line: u64,
+        transaction: &Transaction,
+        anomaly: &SequenceAnomaly,
+    ) -> io::Result<()> {
+        self.write_row(Rejection {
+            line,
+            type_: Some(transaction.type_()),
+            client: Some(transaction.client()),
+            tx: Some(transaction.tx()),
+            error: anomaly.name(),
+        })
+    }
+
+    /// Flushes the report, which would otherwise happen on drop (ignoring any errors).
+    pub fn finish(mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    fn write_row(&mut self, rejection: Rejection) -> io::Result<()> {
+        self.writer.serialize(rejection)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,184 @@
+//! The signed audit log (see `SignedAuditLog`), only built with the `signed-audit` feature.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::digest;
+use crate::transaction::Transaction;
+
+// The hash of the (non-existent) entry before the first one of a signed audit log.
+const GENESIS_HASH: [u8; 32] = [0; 32];
+// What every entry of a signed audit log ends with, around the hex digits of its hash.
+const HASH_PREFIX: &str = ",\"hash\":\"";
+const HASH_SUFFIX: &str = "\"}";
+
+// An entry of a signed audit log, without its hash.
+#[derive(Deserialize, Serialize)]
+struct ChainEntry<T> {
+    seq: u64,
+    #[serde(flatten)]
+    transaction: T,
+}
+
+/// Appends every applied transaction to a file, as a JSON object per line with a sequence
+/// number and a hash (see `AuditChain`), so tampering with the file is detectable. The hash of
+/// every entry covers its contents and the hash of the entry before it, so changing,
+/// removing, or reordering entries breaks the chain from there on. Without a key, the hash is
+/// a plain SHA-256, which only catches accidental changes; with a key, it's an HMAC-SHA256,
+/// which can't be forged without knowing the key. Flushed after every entry, same as
+/// `AuditLog`.
+pub struct SignedAuditLog {
+    writer: BufWriter<File>,
+    key: Option<Vec<u8>>,
+    seq: u64,
+    previous: [u8; 32],
+}
+
+impl SignedAuditLog {
+    /// Opens the log at `path`, which gets created if it doesn't exist yet. The entries of an
+    /// existing log are verified first, so new ones are only ever appended to an intact chain.
+    pub fn open<P: AsRef<Path>>(path: P, key: Option<Vec<u8>>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let (seq, previous) = if path.exists() {
+            let mut chain = AuditChain::new(BufReader::new(File::open(path)?), key.clone());
+            chain.by_ref().try_for_each(|entry| entry.map(drop))?;
+            (chain.seq, chain.previous)
+        } else {
+            (0, GENESIS_HASH)
+        };
+
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(SignedAuditLog {
+            writer: BufWriter::new(file),
+            key,
+            seq,
+            previous,
+        })
+    }
+
+    pub fn record_applied(&mut self, transaction: &Transaction) -> io::Result<()> {
+        let entry = ChainEntry {
+            seq: self.seq,
+            transaction,
+        };
+        let body = serde_json::to_string(&entry)?;
+        let hash = chain_hash(self.key.as_deref(), &self.previous, &body);
+
+        // Splicing the hash into the object, so the hashed body can be recovered verbatim.
+        let line = format!(
+            "{}{}{}{}",
+            &body[..body.len() - 1],
+            HASH_PREFIX,
+            digest::to_hex(&hash),
+            HASH_SUFFIX
+        );
+        writeln!(self.writer, "{}", line)?;
+        self.writer.flush()?;
+
+        self.seq += 1;
+        self.previous = hash;
+        Ok(())
+    }
+}
+
+/// Reads the entries of a signed audit log (see `SignedAuditLog`), and yields the transactions
+/// they hold in order, i.e. to replay them. Every entry is checked against the chain, and an
+/// `InvalidData` error names the first one that doesn't match (with the wrong key, that's the
+/// first one), after which nothing else is read. Note that the chain can't tell whether
+/// entries are missing at the end, which takes comparing the replayed state with the latest
+/// one (see `te verify-audit`).
+pub struct AuditChain<R> {
+    lines: io::Lines<R>,
+    key: Option<Vec<u8>>,
+    seq: u64,
+    previous: [u8; 32],
+    failed: bool,
+}
+
+impl<R: BufRead> AuditChain<R> {
+    pub fn new(r: R, key: Option<Vec<u8>>) -> Self {
+        AuditChain {
+            lines: r.lines(),
+            key,
+            seq: 0,
+            previous: GENESIS_HASH,
+            failed: false,
+        }
+    }
+
+    /// The number of entries verified so far.
+    pub fn verified(&self) -> u64 {
+        self.seq
+    }
+
+    fn verify(&mut self, line: &str) -> io::Result<Transaction> {
+        let invalid = |reason: &str| {
+            let message = format!("entry {} of the audit log {}", self.seq, reason);
+            io::Error::new(io::ErrorKind::InvalidData, message)
+        };
+
+        let hex_start = line.len().saturating_sub(64 + HASH_SUFFIX.len());
+        if !line.ends_with(HASH_SUFFIX)
+            || hex_start < HASH_PREFIX.len()
+            || !line.is_char_boundary(hex_start)
+            || !line[..hex_start].ends_with(HASH_PREFIX)
+        {
+            return Err(invalid("has no hash"));
+        }
+        let body = format!("{}}}", &line[..hex_start - HASH_PREFIX.len()]);
+        let hash = chain_hash(self.key.as_deref(), &self.previous, &body);
+        if digest::to_hex(&hash) != line[hex_start..line.len() - HASH_SUFFIX.len()] {
+            return Err(invalid("doesn't match its hash"));
+        }
+
+        let entry: ChainEntry<Transaction> =
+            serde_json::from_str(&body).map_err(|e| invalid(&format!("is malformed: {}", e)))?;
+        if entry.seq != self.seq {
+            return Err(invalid("is out of sequence"));
+        }
+
+        self.seq += 1;
+        self.previous = hash;
+        Ok(entry.transaction)
+    }
+}
+
+impl<R: BufRead> Iterator for AuditChain<R> {
+    type Item = io::Result<Transaction>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        let result = match self.lines.next()? {
+            Ok(line) => self.verify(&line),
+            Err(e) => Err(e),
+        };
+        self.failed = result.is_err();
+        Some(result)
+    }
+}
+
+// Hashes `body` chained to the `previous` hash, with HMAC-SHA256 given a key and SHA-256
+// otherwise.
+fn chain_hash(key: Option<&[u8]>, previous: &[u8; 32], body: &str) -> [u8; 32] {
+    match key {
+        Some(key) => {
+            let mut mac =
+                Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any length");
+            mac.update(previous);
+            mac.update(body.as_bytes());
+            mac.finalize().into_bytes().into()
+        }
+        None => Sha256::new()
+            .chain_update(previous)
+            .chain_update(body.as_bytes())
+            .finalize()
+            .into(),
+    }
+}
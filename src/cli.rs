@@ -0,0 +1,1101 @@
+use std::fmt;
+use std::io::{self, Write};
+use std::ops::RangeInclusive;
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use rust_decimal::{Decimal, RoundingStrategy};
+use tracing::level_filters::LevelFilter;
+
+use te::input::{ColumnOrder, Delimiter};
+use te::limits::WindowLimit;
+use te::scheduler::Period;
+use te::transaction::{
+    AsOf, ClientId, FrozenDisputes, FrozenPolicy, ResidualBalancePolicy, TimeOrdering,
+    TransactionId, ZeroAmountPolicy, DEFAULT_WAL_SNAPSHOT_INTERVAL,
+};
+use te::{AccountColumn, Amount, OverdraftPolicy};
+
+// Where `serve` listens for gRPC requests unless given `--listen`.
+const DEFAULT_LISTEN_ADDRESS: &str = "127.0.0.1:50051";
+
+// The exit codes of the binary, listed at the end of `--help` (see the `EXIT_*` constants of
+// `main.rs`).
+const EXIT_STATUS_HELP: &str = "\
+Exit status:
+  0  Success. Rejected transactions and invalid records are reported, but don't fail the
+     run unless given --strict
+  1  Failure, i.e. an unwritable output file or an unreadable checkpoint
+  2  A transaction was rejected (with --strict)
+  3  A record was invalid (with --strict)
+  4  The input could not be read
+  5  The accounts differ (diff), or there is something to repair (repair, without --fix)
+  6  Invalid command line arguments
+  7  An engine state is inconsistent, or an invariant check failed
+  8  A sequence number was out of order (with --strict and --check-sequence)";
+
+// Command line interface of the binary. Running without a subcommand is the same as running
+// `process`, so `te <input.csv>` keeps working.
+#[derive(Parser)]
+#[command(
+    name = "te",
+    version,
+    about = "Processes payment transactions into client accounts",
+    after_help = EXIT_STATUS_HELP
+)]
+#[command(args_conflicts_with_subcommands = true)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    #[command(flatten)]
+    pub process: ProcessArgs,
+    #[command(flatten)]
+    pub logging: LoggingArgs,
+    #[command(flatten)]
+    pub state: StateArgs,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Process an input file (or stdin), and write the resulting accounts (the default)
+    #[command(after_help = EXIT_STATUS_HELP)]
+    Process(Box<ProcessArgs>),
+    /// Serve the gRPC API (requires building with `--features grpc`)
+    Serve(ServeArgs),
+    /// Report the accounts, totals, and consistency of a saved checkpoint
+    Inspect(InspectArgs),
+    /// Write the statement of a client from a saved checkpoint
+    Statement(StatementArgs),
+    /// Write the deposits and withdrawals retained for a client in a saved checkpoint
+    History(HistoryArgs),
+    /// Compare the accounts of two saved checkpoints or account reports
+    Diff(DiffArgs),
+    /// Combine saved checkpoints (i.e. of different regions) into one
+    Merge(MergeArgs),
+    /// Recover from a write-ahead log, compact it, and optionally export the state
+    #[command(mut_arg("wal", |arg| arg.required(true)))]
+    Snapshot(SnapshotArgs),
+    /// Consume transactions from a Kafka topic (requires building with `--features kafka`)
+    Consume(ConsumeArgs),
+    /// Verify the chain of a signed audit log, and that replaying it yields a saved state
+    VerifyAudit(VerifyAuditArgs),
+    /// Report the impossible values of a saved checkpoint, and optionally correct them
+    Repair(RepairArgs),
+}
+
+// Logging options, which apply to every subcommand.
+#[derive(Args)]
+pub struct LoggingArgs {
+    /// Overrides the filter given via the `RUST_LOG` environment variable
+    #[arg(long, global = true, value_name = "LEVEL")]
+    pub log_level: Option<LevelFilter>,
+    /// Log output format, on top of the default one line per event
+    #[arg(long, global = true, value_name = "FORMAT")]
+    pub log_format: Option<LogFormat>,
+    /// Report the error that terminates the process as a JSON object on stderr (with the
+    /// kind of error and the exit code), instead of as text
+    #[arg(long, global = true)]
+    pub json_errors: bool,
+}
+
+// Encryption of the saved states, which applies to every subcommand that loads or saves one.
+#[derive(Args)]
+pub struct StateArgs {
+    /// File with the key to encrypt saved checkpoints with, and to decrypt loaded ones with
+    /// (32 bytes, or 64 hex digits). The `TE_STATE_KEY` environment variable can hold the hex
+    /// digits instead
+    #[arg(long, global = true, value_name = "PATH")]
+    pub state_key_file: Option<String>,
+}
+
+// How the engine gets set up, which is shared by all the subcommands that run one.
+#[derive(Args)]
+pub struct EngineArgs {
+    /// TOML file with the engine settings (dispute policy, amounts, limits, fees, history,
+    /// and output columns), which the other options take precedence over
+    #[arg(long, value_name = "PATH")]
+    pub config: Option<String>,
+    /// Engine state to start from (JSON, or TOML for `.toml` paths)
+    #[arg(long, alias = "load-state", value_name = "PATH")]
+    pub load_checkpoint: Option<String>,
+    /// Stream the affected account after every transaction
+    #[arg(long, value_name = "TARGET")]
+    pub stream_accounts: Option<StreamTarget>,
+    /// Write-ahead log to recover from, and to journal accepted transactions to
+    #[arg(long, value_name = "PATH")]
+    pub wal: Option<String>,
+    /// Number of journaled transactions between snapshots of the write-ahead log (0 disables
+    /// snapshots)
+    #[arg(long, value_name = "N", default_value_t = DEFAULT_WAL_SNAPSHOT_INTERVAL)]
+    pub wal_snapshot_interval: u64,
+    /// Sync every write-ahead log entry to disk
+    #[arg(long)]
+    pub wal_sync: bool,
+    /// Comma-separated transaction types still allowed for frozen accounts (an empty list
+    /// blocks everything)
+    #[arg(
+        long = "allow-frozen",
+        value_name = "TYPES",
+        value_parser = parse_frozen_policy
+    )]
+    pub frozen_policy: Option<FrozenPolicy>,
+    /// Which disputes opened before an account got frozen can still be settled: none
+    /// (`block`), by resolving them (`resolve-only`), or by resolving or charging them back
+    /// (`settle`)
+    #[arg(long, value_name = "POLICY", conflicts_with = "frozen_policy")]
+    pub frozen_disputes: Option<FrozenDisputeRule>,
+    /// Let the sign of deposit and withdrawal amounts decide their type
+    #[arg(long)]
+    pub signed_amounts: bool,
+    /// Tolerance for comparing amounts for equality (zero by default)
+    #[arg(long, value_name = "VALUE")]
+    pub amount_epsilon: Option<Decimal>,
+    /// Round amounts with too many decimal places, instead of rejecting them
+    #[arg(long = "round-amounts", value_name = "STRATEGY")]
+    pub amount_rounding: Option<Rounding>,
+    /// Characters to strip from amounts before parsing them (i.e. `$,`)
+    #[arg(long, value_name = "CHARS")]
+    pub amount_format_chars: Option<String>,
+    /// Report accounts of clients whose transactions all got rejected
+    #[arg(long)]
+    pub track_all_clients: bool,
+    /// Don't retain deposits, and reject dispute-related transactions
+    #[arg(long)]
+    pub no_disputes: bool,
+    /// Accept zero-amount deposits and withdrawals (as no-ops)
+    #[arg(long)]
+    pub allow_zero_amounts: bool,
+    /// What to do about zero-amount deposits and withdrawals
+    #[arg(long, value_name = "POLICY", conflicts_with = "allow_zero_amounts")]
+    pub zero_amounts: Option<ZeroAmounts>,
+    /// Record the operations applied to every account
+    #[arg(long)]
+    pub account_history: bool,
+    /// Leave accounts unfrozen after a chargeback
+    #[arg(long)]
+    pub no_chargeback_freeze: bool,
+    /// Unfreeze accounts once their chargebacks have been reversed
+    #[arg(long)]
+    pub unlock_on_reversal: bool,
+    /// Allow disputing withdrawals as well
+    #[arg(long)]
+    pub withdrawal_disputes: bool,
+    /// Allow disputing transactions again once their dispute has been resolved
+    #[arg(long)]
+    pub allow_redispute: bool,
+    /// Allow disputing transactions up to this many times (implies --allow-redispute)
+    #[arg(
+        long,
+        value_name = "N",
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub max_disputes: Option<u32>,
+    /// What to do about disputes which would leave the available funds negative (`allow` by
+    /// default)
+    #[arg(long, value_name = "POLICY")]
+    pub overdraft_policy: Option<Overdraft>,
+    /// Reject disputes which come more than this long after the disputed transaction (i.e.
+    /// `90d`, `12h`, or `3600s`), by the `timestamp` column
+    #[arg(long, value_name = "DURATION", value_parser = parse_dispute_window)]
+    pub dispute_window: Option<u64>,
+    /// Evict transactions from the history once their dispute window has ended, to bound
+    /// memory
+    #[arg(long, requires = "dispute_window")]
+    pub evict_expired: bool,
+    /// Process rows without a timestamp as of the latest timestamp before them, as a live run
+    /// stamping them on arrival would (for holds, dispute windows, and scheduled rules)
+    #[arg(long)]
+    pub simulated_time_from_timestamps: bool,
+    /// Reject transactions whose timestamps are out of order
+    #[arg(long, value_name = "ORDER", default_value = "unchecked")]
+    pub time_ordering: TimeOrder,
+    /// Reject deposits, withdrawals, and transfers of more than this amount
+    #[arg(long, value_name = "AMOUNT")]
+    pub max_amount: Option<Amount>,
+    /// Reject withdrawals and transfers beyond this total per client and day
+    #[arg(long, value_name = "AMOUNT")]
+    pub max_daily_withdrawal: Option<Amount>,
+    /// Reject timestamped transactions beyond this many per client within a number of seconds
+    #[arg(long, value_name = "COUNT/SECONDS", value_parser = parse_velocity)]
+    pub velocity: Option<(usize, u64)>,
+    /// Reject deposits or withdrawals beyond a number or amount per client within a sliding
+    /// window, i.e. `withdrawal-amount:1000/24h` or `deposit-count:10/1h` (repeatable)
+    #[arg(long, value_name = "KIND:MAX/WINDOW")]
+    pub window_limit: Vec<WindowLimit>,
+    /// TOML file with the risk rules to flag accounts by (reported as `risk_score`)
+    #[arg(long, value_name = "PATH")]
+    pub risk_rules: Option<String>,
+    /// TOML file with the fees to charge on every withdrawal (flat and/or percentage)
+    #[arg(long, value_name = "PATH")]
+    pub fees: Option<String>,
+    /// Keep at least this much available when withdrawing or transferring funds away
+    #[arg(long, value_name = "AMOUNT", value_parser = parse_limit)]
+    pub minimum_balance: Option<Decimal>,
+    /// Let withdrawals and outgoing transfers take the available funds this far below the
+    /// minimum balance (i.e. below zero)
+    #[arg(long, value_name = "AMOUNT", value_parser = parse_limit)]
+    pub overdraft_limit: Option<Decimal>,
+    /// TOML file with the minimum balances and overdraft limits of individual clients
+    #[arg(long, value_name = "PATH")]
+    pub balance_limits: Option<String>,
+    /// CSV file with the names, external references, and tags of accounts (`client`, `name`,
+    /// `reference`, and `tags` columns, the tags separated by semicolons)
+    #[arg(long, value_name = "PATH")]
+    pub accounts: Option<String>,
+    /// Add the `name`, `reference`, and `tags` columns to the account report
+    #[arg(long)]
+    pub with_metadata: bool,
+    /// Add the counts and amounts of the open, resolved, and charged back disputes to the
+    /// account report
+    #[arg(long)]
+    pub with_dispute_stats: bool,
+    /// CSV file with the balances to start the accounts off with (`client`, `available`, and
+    /// optionally `currency`, `held`, and `locked` columns), i.e. the report of an earlier run
+    #[arg(long, value_name = "PATH", conflicts_with = "load_checkpoint")]
+    pub opening_balances: Option<String>,
+    /// Accept the `unlock`, `close`, and `reopen` transaction types
+    #[arg(long)]
+    pub allow_admin_ops: bool,
+    /// What to do about the funds of accounts that get closed
+    #[arg(long, value_name = "POLICY", default_value = "retain")]
+    pub residual_balance: ResidualBalance,
+    /// Only process transactions for these clients: comma-separated ids and ranges of ids
+    /// (i.e. `1,2,5-10`)
+    #[arg(
+        long,
+        value_name = "LIST",
+        value_delimiter = ',',
+        value_parser = parse_client_range
+    )]
+    pub clients: Vec<RangeInclusive<ClientId>>,
+    /// Don't process transactions for these clients, even if they're in `--clients`
+    #[arg(
+        long,
+        value_name = "LIST",
+        value_delimiter = ',',
+        value_parser = parse_client_range
+    )]
+    pub exclude_clients: Vec<RangeInclusive<ClientId>>,
+    /// Maximum number of disputes held back until their transaction shows up (none by
+    /// default)
+    #[arg(long, value_name = "N")]
+    pub defer_disputes: Option<usize>,
+    /// Maximum number of dispute-related transactions referencing unknown transactions or
+    /// accounts which are quarantined and retried after the following transactions
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    pub quarantine: usize,
+    /// How many times each quarantined transaction is retried at most
+    #[arg(long, value_name = "N", default_value_t = 100)]
+    pub quarantine_retries: u32,
+    /// Maximum number of transactions retained for disputes
+    #[arg(long, value_name = "N")]
+    pub history_limit: Option<usize>,
+    /// Make room for this many accounts and transactions up front (`<clients>,<transactions>`),
+    /// so the engine doesn't have to grow its maps while processing
+    #[arg(long, value_name = "CLIENTS,TRANSACTIONS", value_parser = parse_capacity)]
+    pub capacity: Option<(usize, usize)>,
+    /// Directory to spill the transactions retained for disputes to, except for the ones
+    /// added most recently (see `--hot-transactions`), so long histories don't have to fit in
+    /// memory (ignored when resuming from a checkpoint)
+    #[arg(long, value_name = "DIR", conflicts_with = "load_checkpoint")]
+    pub spill_dir: Option<String>,
+    /// Number of transactions kept in memory with `--spill-dir`
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 100_000,
+        requires = "spill_dir"
+    )]
+    pub hot_transactions: usize,
+    /// Skip transactions which have been applied already, so replays are idempotent
+    #[arg(long)]
+    pub dedup: bool,
+    /// Check the affected accounts after every transaction, and the whole state at the end,
+    /// aborting on the first inconsistency (to catch engine bugs early)
+    #[arg(long)]
+    pub check_invariants: bool,
+}
+
+#[derive(Args)]
+pub struct ProcessArgs {
+    /// Input files, directories, or wildcard patterns, processed as one stream (read from
+    /// stdin when missing or `-`, and not used with `--repl`)
+    #[arg(value_name = "INPUT")]
+    pub input_paths: Vec<String>,
+    /// Process several input files by modification time, instead of by name
+    #[arg(long)]
+    pub by_mtime: bool,
+    /// Where to write the accounts (or the statement), instead of stdout
+    #[arg(long = "output", short = 'o', value_name = "PATH")]
+    pub output_path: Option<String>,
+    /// Output format of the accounts (or the statement)
+    #[arg(
+        long,
+        aliases = ["statement-format", "output-format"],
+        value_name = "FORMAT",
+        default_value = "csv"
+    )]
+    pub format: OutputFormat,
+    /// Comma-separated columns of the accounts to write, in order (i.e.
+    /// `client,available,locked`)
+    #[arg(
+        long,
+        value_name = "LIST",
+        value_delimiter = ',',
+        conflicts_with_all = ["statement", "dry_run"]
+    )]
+    pub output_columns: Option<Vec<AccountColumn>>,
+    /// Emit a JSON Lines audit event for every input record
+    #[arg(long, value_name = "TARGET")]
+    pub audit: Option<StreamTarget>,
+    /// Append every applied transaction to this hash-chained audit log
+    #[arg(long, value_name = "PATH")]
+    pub signed_audit: Option<String>,
+    /// Sign the entries of the signed audit log with the key in this file (HMAC-SHA256)
+    #[arg(long, value_name = "PATH", requires = "signed_audit")]
+    pub audit_key_file: Option<String>,
+    /// Where to write the CSV report of rejected transactions and invalid records (`-` for
+    /// stdout, ahead of the account report)
+    #[arg(long = "errors", value_name = "PATH")]
+    pub errors_path: Option<String>,
+    /// Report the number of records processed, the throughput, and the estimated time left
+    /// on stderr while processing
+    #[arg(long, conflicts_with_all = ["http", "repl"])]
+    pub progress: bool,
+    /// Only validate the input: write how many records would be applied or rejected (by
+    /// reason) instead of the accounts, without persisting anything
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "statement", "save_checkpoint", "export_sqlite", "events_path", "checkpoint_every",
+            "resume", "http", "repl", "wal",
+        ]
+    )]
+    pub dry_run: bool,
+    /// Where to persist the engine state once the input is done
+    #[arg(long, alias = "save-state", value_name = "PATH")]
+    pub save_checkpoint: Option<String>,
+    /// Where to export the accounts and the retained transactions to as an SQLite database,
+    /// once the input is done (requires building with `--features sqlite`)
+    #[arg(long, value_name = "PATH")]
+    pub export_sqlite: Option<String>,
+    /// Where to write the events of every change to an account to (as JSON Lines), once the
+    /// input is done
+    #[arg(long = "events", value_name = "PATH")]
+    pub events_path: Option<String>,
+    /// Where to write the funds paid out when closing accounts to (as CSV), once the input is
+    /// done
+    #[arg(long = "payouts", value_name = "PATH")]
+    pub payouts_path: Option<String>,
+    /// Export the lineage of disputed and voided transactions and transfers as a graph, to
+    /// `--lineage-output` once the input is done
+    #[arg(long, value_name = "FORMAT", requires = "lineage_output")]
+    pub export_lineage: Option<LineageFormat>,
+    /// Where to write the lineage to
+    #[arg(long, value_name = "PATH", requires = "export_lineage")]
+    pub lineage_output: Option<String>,
+    /// Number of worker threads to process the input with (sharded by client)
+    #[arg(
+        long,
+        value_name = "N",
+        conflicts_with_all = [
+            "audit", "signed_audit", "errors_path", "stream_accounts", "warnings", "strict",
+            "resume_after", "max_transactions", "wal", "max_amount", "max_daily_withdrawal",
+            "velocity", "window_limit", "risk_rules", "export_lineage", "check_sequence",
+        ]
+    )]
+    pub threads: Option<usize>,
+    /// Number of threads to deserialize CSV input on (0 for one per CPU), while the
+    /// transactions get processed in input order (requires building with `--features rayon`)
+    #[arg(long, value_name = "N", conflicts_with_all = ["http", "repl"])]
+    pub parse_threads: Option<usize>,
+    /// Write the statement of this client instead of the accounts
+    #[arg(long, value_name = "CLIENT")]
+    pub statement: Option<ClientId>,
+    /// Abort on the first invalid record or rejected transaction, with a non-zero exit code
+    /// (and on the first sequence anomaly, with `--check-sequence`). Without it, those only
+    /// get reported, and the exit code stays 0
+    #[arg(long)]
+    pub strict: bool,
+    /// Check the `seq` column for gaps and regressions (per tenant), and report them in the
+    /// summary and the errors report
+    #[arg(long)]
+    pub check_sequence: bool,
+    /// Detected from the input file extension by default
+    #[arg(long, value_name = "FORMAT")]
+    pub input_format: Option<InputFormat>,
+    /// Field delimiter of CSV input, as a single character, `tab`, or `auto` to detect it
+    /// from the first row (tabs for `.tsv` files, and commas otherwise)
+    #[arg(long, value_name = "CHAR", value_parser = parse_delimiter)]
+    pub delimiter: Option<Delimiter>,
+    /// The input has no header row, so the `type`, `client`, `tx`, and `amount` columns are
+    /// mapped by position
+    #[arg(long)]
+    pub no_headers: bool,
+    /// Comma-separated columns of header-less input, in order (implies `--no-headers`)
+    #[arg(long, value_name = "LIST", value_parser = parse_columns)]
+    pub columns: Option<ColumnOrder>,
+    /// Skip the records up to and including the one with this transaction id, or (given as
+    /// `time:<timestamp>`) the ones up to the first with a later timestamp
+    #[arg(long, value_name = "TX|time:TIMESTAMP", value_parser = parse_resume_point)]
+    pub resume_after: Option<ResumeAfter>,
+    /// Save a checkpoint of the state and the input position every N records
+    #[arg(
+        long,
+        value_name = "N",
+        value_parser = clap::value_parser!(u64).range(1..),
+        requires = "checkpoint_dir",
+        conflicts_with_all = ["threads", "http", "repl", "rules"]
+    )]
+    pub checkpoint_every: Option<u64>,
+    /// Directory to save the checkpoints of `--checkpoint-every` in
+    #[arg(long, value_name = "DIR")]
+    pub checkpoint_dir: Option<String>,
+    /// Write the accounts every N records while processing, to `--snapshot-dir` or
+    /// `--snapshot-file` (in the output format and columns)
+    #[arg(
+        long,
+        value_name = "N",
+        value_parser = clap::value_parser!(u64).range(1..),
+        requires = "snapshot_target",
+        conflicts_with_all = ["threads", "repl", "dry_run"]
+    )]
+    pub emit_snapshots_every: Option<u64>,
+    /// Directory to write the accounts of `--emit-snapshots-every` to, in a new file named
+    /// after the time and the number of records each time
+    #[arg(
+        long,
+        value_name = "DIR",
+        group = "snapshot_target",
+        requires = "emit_snapshots_every"
+    )]
+    pub snapshot_dir: Option<String>,
+    /// File to write the accounts of `--emit-snapshots-every` to, which gets replaced each
+    /// time
+    #[arg(
+        long,
+        value_name = "PATH",
+        group = "snapshot_target",
+        requires = "emit_snapshots_every"
+    )]
+    pub snapshot_file: Option<String>,
+    /// Continue from the last checkpoint in `--checkpoint-dir` (if any), instead of from the
+    /// start of the input
+    #[arg(
+        long,
+        requires = "checkpoint_dir",
+        conflicts_with_all = ["load_checkpoint", "resume_after", "wal", "http", "repl", "rules"]
+    )]
+    pub resume: bool,
+    /// Print advisories about processed transactions to stderr
+    #[arg(long)]
+    pub warnings: bool,
+    /// Read transactions interactively from stdin instead of an input file
+    #[arg(long)]
+    pub repl: bool,
+    /// Also serve the HTTP API on this address while processing the input (requires building
+    /// with `--features http`)
+    #[arg(long, value_name = "ADDRESS", conflicts_with_all = ["threads", "repl"])]
+    pub http: Option<String>,
+    /// Maximum size of the input, in bytes
+    #[arg(long, value_name = "N")]
+    pub max_input_bytes: Option<u64>,
+    /// Maximum number of records (accepted, rejected, or invalid) to consider
+    #[arg(long, value_name = "N")]
+    pub max_transactions: Option<u64>,
+    /// Print the processing summary to stderr at the end
+    #[arg(long)]
+    pub summary: bool,
+    /// Print the totals across all accounts to stderr at the end
+    #[arg(long)]
+    pub aggregate: bool,
+    /// Print the metrics (counts by type and error, and the throughput) to stderr at the end
+    #[arg(long)]
+    pub stats: bool,
+    /// Write a JSON report of the run (counts by type and error, accounts, volumes, and the
+    /// wall-clock throughput) to this file at the end (`-` for stderr)
+    #[arg(long, value_name = "PATH")]
+    pub report: Option<String>,
+    /// Route transactions by their `tenant` column to separate engines, and write the
+    /// accounts of each tenant to a file of its own in this directory (i.e. `acme.csv`)
+    #[arg(
+        long,
+        value_name = "DIR",
+        conflicts_with_all = [
+            "output_path", "statement", "dry_run", "audit", "signed_audit", "save_checkpoint",
+            "export_sqlite", "events_path", "payouts_path", "export_lineage", "threads",
+            "checkpoint_every", "emit_snapshots_every", "resume", "resume_after", "repl", "http",
+            "aggregate", "stats", "report", "rules", "load_checkpoint", "wal", "stream_accounts",
+        ]
+    )]
+    pub tenant_output: Option<String>,
+    /// TOML file with rules for periodic adjustments (interest or fees), which are applied
+    /// as the `timestamp` column of the input moves past the end of each period
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["threads", "repl", "wal"])]
+    pub rules: Option<String>,
+    #[command(flatten)]
+    pub engine: EngineArgs,
+}
+
+impl ProcessArgs {
+    // The format given via `--input-format`, or detected from the extension of the input file
+    // at `path`.
+    pub fn input_format(&self, path: &str) -> InputFormat {
+        if let Some(format) = self.input_format {
+            return format;
+        }
+
+        let path = uncompressed_name(path);
+        if path.ends_with(".jsonl") || path.ends_with(".ndjson") {
+            return InputFormat::JsonLines;
+        }
+        #[cfg(feature = "protobuf")]
+        if path.ends_with(".pb") || path.ends_with(".binpb") {
+            return InputFormat::Protobuf;
+        }
+        InputFormat::Csv
+    }
+
+    // The delimiter given via `--delimiter`, or the one of the format of the input file at
+    // `path` (tabs for TSV files).
+    pub fn delimiter(&self, path: &str) -> Delimiter {
+        match self.delimiter {
+            Some(delimiter) => delimiter,
+            None if uncompressed_name(path).ends_with(".tsv") => Delimiter::Byte(b'\t'),
+            None => Delimiter::Byte(b','),
+        }
+    }
+
+    pub fn has_headers(&self) -> bool {
+        !self.no_headers && self.columns.is_none()
+    }
+
+    // The order of the columns of header-less input, which is `type,client,tx,amount` unless
+    // given via `--columns`.
+    pub fn column_order(&self) -> Option<ColumnOrder> {
+        match &self.columns {
+            Some(columns) => Some(columns.clone()),
+            None if self.no_headers => Some(ColumnOrder::headerless()),
+            None => None,
+        }
+    }
+
+    // The streamed events and the account data would end up interleaved otherwise.
+    pub fn streams_to_stdout(&self) -> bool {
+        [self.audit, self.engine.stream_accounts].contains(&Some(StreamTarget::Stdout))
+    }
+}
+
+// Compressed input is named after the format it decompresses to.
+fn uncompressed_name(path: &str) -> &str {
+    path.strip_suffix(".gz")
+        .or_else(|| path.strip_suffix(".zst"))
+        .unwrap_or(path)
+}
+
+#[derive(Args)]
+pub struct ServeArgs {
+    /// Address to serve the gRPC API on
+    #[arg(long, value_name = "ADDRESS", default_value = DEFAULT_LISTEN_ADDRESS)]
+    pub listen: String,
+    /// Also serve the metrics on `/metrics` of this (HTTP) address, for Prometheus
+    #[arg(long, value_name = "ADDRESS")]
+    pub metrics: Option<String>,
+    /// Seconds between releasing the holds which have expired by the system clock (0
+    /// releases them only when a later timestamped transaction arrives)
+    #[arg(long, value_name = "SECONDS", default_value_t = 60)]
+    pub hold_sweep_interval: u64,
+    #[command(flatten)]
+    pub engine: EngineArgs,
+}
+
+#[derive(Args)]
+pub struct InspectArgs {
+    /// Checkpoint to inspect (JSON, or TOML for `.toml` paths), or transactions to process
+    /// first (for `.csv` paths)
+    #[arg(value_name = "STATE")]
+    pub state: String,
+    /// Explore the state at an interactive prompt, instead of writing the accounts
+    #[arg(
+        long,
+        short = 'i',
+        conflicts_with_all = ["output_path", "output_columns", "as_of"]
+    )]
+    pub interactive: bool,
+    /// Where to write the accounts, instead of stdout
+    #[arg(long = "output", short = 'o', value_name = "PATH")]
+    pub output_path: Option<String>,
+    /// Output format of the accounts
+    #[arg(
+        long,
+        alias = "output-format",
+        value_name = "FORMAT",
+        default_value = "csv"
+    )]
+    pub format: OutputFormat,
+    /// Comma-separated columns of the accounts to write, in order (i.e.
+    /// `client,available,locked`)
+    #[arg(long, value_name = "LIST", value_delimiter = ',')]
+    pub output_columns: Option<Vec<AccountColumn>>,
+    /// Whether the checkpoint was saved with disputable withdrawals
+    #[arg(long)]
+    pub withdrawal_disputes: bool,
+    /// Add the `name`, `reference`, and `tags` columns to the account report
+    #[arg(long)]
+    pub with_metadata: bool,
+    /// Add the counts and amounts of the open, resolved, and charged back disputes to the
+    /// account report
+    #[arg(long)]
+    pub with_dispute_stats: bool,
+    /// Report the accounts as of a point in the retained history instead: `before:<tx>`, or a
+    /// timestamp. Only deposits, withdrawals, and transfers are replayed, so fees, holds, and
+    /// disputes are left out
+    #[arg(long, value_name = "POINT")]
+    pub as_of: Option<AsOf>,
+}
+
+#[derive(Args)]
+pub struct DiffArgs {
+    /// Checkpoint or account report (for `.csv` paths) to compare
+    #[arg(value_name = "LEFT")]
+    pub left: String,
+    /// Checkpoint or account report to compare against
+    #[arg(value_name = "RIGHT")]
+    pub right: String,
+    /// Where to write the discrepancies, instead of stdout
+    #[arg(long = "output", short = 'o', value_name = "PATH")]
+    pub output_path: Option<String>,
+}
+
+#[derive(Args)]
+pub struct MergeArgs {
+    /// Checkpoints to combine, in order (JSON, or TOML for `.toml` paths)
+    #[arg(value_name = "STATE", num_args = 2.., required = true)]
+    pub states: Vec<String>,
+    /// Where to save the combined checkpoint
+    #[arg(long = "output", short = 'o', value_name = "PATH")]
+    pub output_path: String,
+}
+
+#[derive(Args)]
+pub struct RepairArgs {
+    /// Checkpoint to scan (JSON, or TOML for `.toml` paths)
+    #[arg(value_name = "STATE")]
+    pub state: String,
+    /// Apply the corrective adjustments, and save the repaired state to `--output`
+    #[arg(long, requires = "output_path")]
+    pub fix: bool,
+    /// Where to save the repaired checkpoint (which may be the scanned one)
+    #[arg(long = "output", short = 'o', value_name = "PATH", requires = "fix")]
+    pub output_path: Option<String>,
+    /// Emit a JSON Lines audit event for every corrective adjustment
+    #[arg(long, value_name = "TARGET", requires = "fix")]
+    pub audit: Option<StreamTarget>,
+}
+
+#[derive(Args)]
+pub struct StatementArgs {
+    /// Client to write the statement of
+    #[arg(value_name = "CLIENT")]
+    pub client: ClientId,
+    /// Checkpoint to read the account from (JSON, or TOML for `.toml` paths)
+    #[arg(value_name = "STATE")]
+    pub state: String,
+    /// Where to write the statement, instead of stdout
+    #[arg(long = "output", short = 'o', value_name = "PATH")]
+    pub output_path: Option<String>,
+    /// Output format of the statement
+    #[arg(long, value_name = "FORMAT", default_value = "table")]
+    pub format: StatementFormat,
+}
+
+#[derive(Args)]
+pub struct HistoryArgs {
+    /// Client to write the history of
+    #[arg(value_name = "CLIENT")]
+    pub client: ClientId,
+    /// Checkpoint to read the transactions from (JSON, or TOML for `.toml` paths)
+    #[arg(value_name = "STATE")]
+    pub state: String,
+    /// Where to write the history, instead of stdout
+    #[arg(long = "output", short = 'o', value_name = "PATH")]
+    pub output_path: Option<String>,
+}
+
+#[derive(Args)]
+pub struct SnapshotArgs {
+    /// Where to export the recovered state as a checkpoint (JSON, or TOML for `.toml` paths)
+    #[arg(long = "output", short = 'o', value_name = "PATH")]
+    pub output_path: Option<String>,
+    #[command(flatten)]
+    pub engine: EngineArgs,
+}
+
+#[derive(Args)]
+pub struct VerifyAuditArgs {
+    /// The signed audit log (see `--signed-audit`)
+    pub log: String,
+    /// The checkpoint the replayed transactions have to add up to
+    #[arg(long, value_name = "PATH")]
+    pub state: String,
+    /// The key the entries were signed with
+    #[arg(long, value_name = "PATH")]
+    pub audit_key_file: Option<String>,
+    #[command(flatten)]
+    pub engine: EngineArgs,
+}
+
+#[derive(Args)]
+pub struct ConsumeArgs {
+    /// Kafka brokers to bootstrap from, as a comma-separated list of `host:port` pairs
+    #[arg(
+        long,
+        value_name = "BROKERS",
+        value_delimiter = ',',
+        default_value = "localhost:9092"
+    )]
+    pub brokers: Vec<String>,
+    /// Topic to consume the transactions from
+    #[arg(long, value_name = "TOPIC")]
+    pub topic: String,
+    /// Consumer group to commit the offsets for
+    #[arg(long, value_name = "GROUP", default_value = "te")]
+    pub group: String,
+    /// Encoding of the messages: JSON objects, or header-less CSV rows
+    #[arg(long, value_name = "FORMAT", default_value = "json")]
+    pub payload: Payload,
+    /// Checkpoint to flush the state to periodically (JSON, or TOML for `.toml` paths)
+    #[arg(long, value_name = "PATH")]
+    pub snapshot: Option<String>,
+    /// Where to flush the accounts to along with the snapshot
+    #[arg(long = "output", short = 'o', value_name = "PATH")]
+    pub output_path: Option<String>,
+    /// Output format of the accounts
+    #[arg(
+        long,
+        alias = "output-format",
+        value_name = "FORMAT",
+        default_value = "csv"
+    )]
+    pub format: OutputFormat,
+    /// Comma-separated columns of the accounts to write, in order (i.e.
+    /// `client,available,locked`)
+    #[arg(long, value_name = "LIST", value_delimiter = ',')]
+    pub output_columns: Option<Vec<AccountColumn>>,
+    /// Seconds between flushes of the snapshot and the accounts
+    #[arg(long, value_name = "SECONDS", default_value_t = 60)]
+    pub snapshot_interval: u64,
+    #[command(flatten)]
+    pub engine: EngineArgs,
+}
+
+// The formats of `--export-lineage`.
+#[derive(Clone, Copy, ValueEnum)]
+pub enum LineageFormat {
+    // A Graphviz `digraph`, i.e. for `dot -Tsvg`.
+    Graphviz,
+    // The nodes and edges of the graph, as a JSON object.
+    Json,
+}
+
+// Where a JSON Lines stream (audit events or account updates) should be written to.
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+pub enum StreamTarget {
+    Stdout,
+    Stderr,
+}
+
+impl StreamTarget {
+    // Keeping things simple by always going through a trait object here; the overhead is
+    // negligible compared to serializing the events themselves.
+    pub fn writer(&self) -> Box<dyn Write> {
+        match self {
+            StreamTarget::Stdout => Box::new(io::stdout()),
+            StreamTarget::Stderr => Box::new(io::stderr()),
+        }
+    }
+}
+
+// Supported formats for transaction input.
+#[derive(Clone, Copy, ValueEnum)]
+pub enum InputFormat {
+    Csv,
+    #[value(name = "jsonl")]
+    JsonLines,
+    // Length-delimited `TransactionRecord` messages (see `proto/record.proto`).
+    #[cfg(feature = "protobuf")]
+    Protobuf,
+}
+
+// Supported formats for log output, on top of the default one line per event.
+#[derive(Clone, Copy, ValueEnum)]
+pub enum LogFormat {
+    Json,
+    Pretty,
+}
+
+// Supported formats for the `statement` subcommand, which defaults to a table.
+#[derive(Clone, Copy, ValueEnum)]
+pub enum StatementFormat {
+    Csv,
+    Json,
+    Table,
+}
+
+// Supported formats for the accounts and client statements (only the accounts for Parquet).
+#[derive(Clone, Copy, ValueEnum)]
+pub enum OutputFormat {
+    Csv,
+    Json,
+    Parquet,
+    // Aligned columns, for reading the output on a terminal.
+    Table,
+}
+
+// How `consume` decodes the messages of the topic.
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Payload {
+    Json,
+    Csv,
+}
+
+// The rounding strategies offered by `--round-amounts`.
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Rounding {
+    HalfEven,
+    HalfUp,
+    HalfDown,
+    Up,
+    Down,
+}
+
+impl From<Rounding> for RoundingStrategy {
+    fn from(rounding: Rounding) -> Self {
+        match rounding {
+            Rounding::HalfEven => RoundingStrategy::MidpointNearestEven,
+            Rounding::HalfUp => RoundingStrategy::MidpointAwayFromZero,
+            Rounding::HalfDown => RoundingStrategy::MidpointTowardZero,
+            Rounding::Up => RoundingStrategy::AwayFromZero,
+            Rounding::Down => RoundingStrategy::ToZero,
+        }
+    }
+}
+
+// Mirrors `OverdraftPolicy`, which doesn't depend on `clap`.
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Overdraft {
+    Allow,
+    Reject,
+    Restrict,
+}
+
+impl From<Overdraft> for OverdraftPolicy {
+    fn from(overdraft: Overdraft) -> Self {
+        match overdraft {
+            Overdraft::Allow => OverdraftPolicy::Allow,
+            Overdraft::Reject => OverdraftPolicy::Reject,
+            Overdraft::Restrict => OverdraftPolicy::Restrict,
+        }
+    }
+}
+
+// Mirrors `ZeroAmountPolicy`, which doesn't depend on `clap` either.
+#[derive(Clone, Copy, ValueEnum)]
+pub enum ZeroAmounts {
+    Reject,
+    Accept,
+    Skip,
+}
+
+impl From<ZeroAmounts> for ZeroAmountPolicy {
+    fn from(zero_amounts: ZeroAmounts) -> Self {
+        match zero_amounts {
+            ZeroAmounts::Reject => ZeroAmountPolicy::Reject,
+            ZeroAmounts::Accept => ZeroAmountPolicy::Accept,
+            ZeroAmounts::Skip => ZeroAmountPolicy::Skip,
+        }
+    }
+}
+
+// Mirrors `FrozenDisputes`, which doesn't depend on `clap` either.
+#[derive(Clone, Copy, ValueEnum)]
+pub enum FrozenDisputeRule {
+    Block,
+    ResolveOnly,
+    Settle,
+}
+
+impl From<FrozenDisputeRule> for FrozenDisputes {
+    fn from(rule: FrozenDisputeRule) -> Self {
+        match rule {
+            FrozenDisputeRule::Block => FrozenDisputes::Block,
+            FrozenDisputeRule::ResolveOnly => FrozenDisputes::ResolveOnly,
+            FrozenDisputeRule::Settle => FrozenDisputes::Settle,
+        }
+    }
+}
+
+// Mirrors `ResidualBalancePolicy`, which doesn't depend on `clap` either.
+#[derive(Clone, Copy, ValueEnum)]
+pub enum ResidualBalance {
+    Retain,
+    Reject,
+    PayOut,
+}
+
+impl From<ResidualBalance> for ResidualBalancePolicy {
+    fn from(residual: ResidualBalance) -> Self {
+        match residual {
+            ResidualBalance::Retain => ResidualBalancePolicy::Retain,
+            ResidualBalance::Reject => ResidualBalancePolicy::Reject,
+            ResidualBalance::PayOut => ResidualBalancePolicy::PayOut,
+        }
+    }
+}
+
+// Mirrors `TimeOrdering`, which doesn't depend on `clap` either.
+#[derive(Clone, Copy, ValueEnum)]
+pub enum TimeOrder {
+    Unchecked,
+    PerTransaction,
+    PerClient,
+}
+
+impl From<TimeOrder> for TimeOrdering {
+    fn from(order: TimeOrder) -> Self {
+        match order {
+            TimeOrder::Unchecked => TimeOrdering::Unchecked,
+            TimeOrder::PerTransaction => TimeOrdering::PerTransaction,
+            TimeOrder::PerClient => TimeOrdering::PerClient,
+        }
+    }
+}
+
+/// Where `--resume-after` picks up the input.
+#[derive(Clone, Copy, Debug)]
+pub enum ResumeAfter {
+    // Right after the record with this transaction id.
+    Transaction(TransactionId),
+    // At the first record with a later timestamp (in seconds since the Unix epoch).
+    Time(u64),
+}
+
+impl fmt::Display for ResumeAfter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ResumeAfter::Transaction(tx) => write!(f, "transaction {}", tx),
+            ResumeAfter::Time(time) => write!(f, "timestamp {}", time),
+        }
+    }
+}
+
+// Parses the value of `--allow-frozen`, which replaces the default policy.
+fn parse_frozen_policy(types: &str) -> Result<FrozenPolicy, String> {
+    types
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .try_fold(FrozenPolicy::block_all(), |policy, s| {
+            let type_ = s
+                .parse()
+                .map_err(|e| format!("invalid transaction type `{}`: {}", s, e))?;
+            Ok(policy.allow(type_, true))
+        })
+}
+
+// Parses an inclusive range of client ids, given as `<first>-<last>`, or a single client id.
+fn parse_client_range(range: &str) -> Result<RangeInclusive<ClientId>, String> {
+    let (first, last) = range.split_once('-').unwrap_or((range, range));
+    let first = first
+        .trim()
+        .parse()
+        .map_err(|e| format!("invalid client id: {}", e))?;
+    let last = last
+        .trim()
+        .parse()
+        .map_err(|e| format!("invalid client id: {}", e))?;
+    Ok(first..=last)
+}
+
+// Parses the value of `--capacity`, i.e. `1000,500000`.
+fn parse_capacity(capacity: &str) -> Result<(usize, usize), String> {
+    let (clients, transactions) = capacity
+        .split_once(',')
+        .ok_or_else(|| "expected `<clients>,<transactions>`".to_owned())?;
+    let parse = |n: &str| {
+        n.trim()
+            .parse()
+            .map_err(|e| format!("invalid capacity: {}", e))
+    };
+    Ok((parse(clients)?, parse(transactions)?))
+}
+
+// Parses a minimum balance or overdraft limit, which can't be negative.
+fn parse_limit(limit: &str) -> Result<Decimal, String> {
+    let limit: Decimal = limit
+        .trim()
+        .parse()
+        .map_err(|e| format!("invalid amount: {}", e))?;
+    if limit.is_sign_negative() {
+        return Err("limits can't be negative".to_owned());
+    }
+    Ok(limit)
+}
+
+// Parses the value of `--velocity`, i.e. `5/60` for at most five transactions a minute.
+fn parse_velocity(limit: &str) -> Result<(usize, u64), String> {
+    let (count, seconds) = limit
+        .split_once('/')
+        .ok_or("expected a count and a number of seconds, i.e. `5/60`")?;
+    let count = count
+        .trim()
+        .parse()
+        .map_err(|e| format!("invalid count `{}`: {}", count, e))?;
+    let seconds = seconds
+        .trim()
+        .parse()
+        .map_err(|e| format!("invalid number of seconds `{}`: {}", seconds, e))?;
+    Ok((count, seconds))
+}
+
+// Parses the value of `--dispute-window` into a number of seconds, same as the periods of the
+// scheduler rules (apart from `month`, which doesn't have a fixed length).
+fn parse_dispute_window(window: &str) -> Result<u64, String> {
+    match window.parse()? {
+        Period::Seconds(seconds) => Ok(seconds),
+        Period::Month => Err("expected a number of days, hours, minutes, or seconds".to_owned()),
+    }
+}
+
+// Parses the value of `--delimiter`, which is a single (ASCII) character, `tab`, or `auto`.
+fn parse_delimiter(delimiter: &str) -> Result<Delimiter, String> {
+    match delimiter {
+        "auto" => Ok(Delimiter::Detect),
+        "tab" | "\\t" => Ok(Delimiter::Byte(b'\t')),
+        _ => match delimiter.as_bytes() {
+            &[byte] if byte.is_ascii() && byte != b'\n' && byte != b'"' => {
+                Ok(Delimiter::Byte(byte))
+            }
+            _ => Err("expected a single ASCII character, `tab`, or `auto`".to_owned()),
+        },
+    }
+}
+
+// Parses the value of `--resume-after`, which is a transaction id, or `time:<timestamp>`.
+fn parse_resume_point(point: &str) -> Result<ResumeAfter, String> {
+    match point.strip_prefix("time:") {
+        Some(time) => time
+            .trim()
+            .parse()
+            .map(ResumeAfter::Time)
+            .map_err(|e| format!("invalid timestamp `{}`: {}", time, e)),
+        None => point
+            .trim()
+            .parse()
+            .map(ResumeAfter::Transaction)
+            .map_err(|e| format!("invalid transaction id `{}`: {}", point, e)),
+    }
+}
+
+fn parse_columns(list: &str) -> Result<ColumnOrder, String> {
+    ColumnOrder::new(list.split(',').map(str::trim))
+}
@@ -0,0 +1,109 @@
+//! Sources of the current time for the time-dependent features (the expiry of holds, dispute
+//! windows, and scheduled adjustments), which otherwise only go by the timestamps of the
+//! transactions. An engine with a clock (see `TransactionEngine::with_clock`) processes the
+//! transactions without a timestamp as of the time of the clock, and `SimulatedClock` lets
+//! tests and replays decide what that time is.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+/// The current time, in seconds since the Unix epoch, or `None` if the clock doesn't know
+/// it yet (i.e. a `SimulatedClock::from_timestamps` before the first timestamp). Clocks are
+/// shared with the worker threads of `TransactionEngine::process_parallel`, hence the
+/// `Send + Sync` bound.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Option<u64>;
+
+    /// Called for every transaction with a timestamp before it gets processed, so clocks can
+    /// follow the time of the input. Does nothing by default.
+    fn observe(&self, _timestamp: u64) {}
+}
+
+// So a clock can be shared, i.e. by the engines of a `TransactionEngineBuilder`.
+impl<C: Clock + ?Sized> Clock for Arc<C> {
+    fn now(&self) -> Option<u64> {
+        (**self).now()
+    }
+
+    fn observe(&self, timestamp: u64) {
+        (**self).observe(timestamp)
+    }
+}
+
+/// The system clock, i.e. for live runs.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Option<u64> {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs());
+        Some(now)
+    }
+}
+
+// The time of a `SimulatedClock` which doesn't know the time yet.
+const UNKNOWN: u64 = u64::MAX;
+
+/// A clock which only moves when told to. Clones share the same time, so a test can keep one
+/// to move the time of the engine it handed the other to.
+#[derive(Clone, Debug)]
+pub struct SimulatedClock {
+    now: Arc<AtomicU64>,
+    follows_timestamps: bool,
+}
+
+impl SimulatedClock {
+    /// A clock standing still at `now`.
+    pub fn new(now: u64) -> Self {
+        SimulatedClock {
+            now: Arc::new(AtomicU64::new(now.min(UNKNOWN - 1))),
+            follows_timestamps: false,
+        }
+    }
+
+    /// A clock which moves forward to the timestamp of every transaction (but never back), so
+    /// the transactions without one are processed as of the latest timestamp before them.
+    /// The time is unknown until the first timestamp.
+    pub fn from_timestamps() -> Self {
+        SimulatedClock {
+            now: Arc::new(AtomicU64::new(UNKNOWN)),
+            follows_timestamps: true,
+        }
+    }
+
+    /// Moves the clock to `now`, which may be in the past.
+    pub fn set(&self, now: u64) {
+        self.now.store(now.min(UNKNOWN - 1), Ordering::Relaxed);
+    }
+
+    /// Moves the clock forward by `seconds` (starting from zero if it doesn't know the time
+    /// yet).
+    pub fn advance(&self, seconds: u64) {
+        let _ = self
+            .now
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |now| {
+                let now = if now == UNKNOWN { 0 } else { now };
+                Some(now.saturating_add(seconds).min(UNKNOWN - 1))
+            });
+    }
+}
+
+impl Clock for SimulatedClock {
+    fn now(&self) -> Option<u64> {
+        Some(self.now.load(Ordering::Relaxed)).filter(|now| *now != UNKNOWN)
+    }
+
+    fn observe(&self, timestamp: u64) {
+        if !self.follows_timestamps {
+            return;
+        }
+        let _ = self
+            .now
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |now| {
+                (now == UNKNOWN || now < timestamp).then_some(timestamp.min(UNKNOWN - 1))
+            });
+    }
+}
@@ -0,0 +1,306 @@
+//! Engine configuration files, for deployments which would otherwise need a dozen options
+//! (see `EngineConfig::load`). Every table and setting is optional, and the ones left out
+//! keep the defaults of the engine (or whatever the engine was set up with before), i.e.:
+//!
+//! ```toml
+//! [disputes]
+//! withdrawal_disputes = true
+//! max_disputes = 3
+//! window = "90d"             # same as for `--dispute-window`
+//! overdraft = "restrict"     # `allow`, `reject`, or `restrict`
+//! frozen = "settle"          # `block`, `resolve-only`, or `settle`
+//!
+//! [amounts]
+//! rounding = "half-even"     # same as for `--round-amounts`
+//! zero = "skip"              # `reject`, `accept`, or `skip`
+//!
+//! [limits]
+//! max_amount = "10000"
+//! max_daily_withdrawal = "2500"
+//! minimum_balance = "10"
+//! window_limits = ["withdrawal-amount:5000/24h", "deposit-count:10/1h"]
+//!
+//! [fees]                     # same as a fee schedule of its own (see `FeeSchedule`)
+//! flat = "0.25"
+//!
+//! [history]
+//! limit = 1000000
+//!
+//! [output]
+//! dispute_columns = true
+//! ```
+
+use std::io::{self, Read};
+
+use rust_decimal::{Decimal, RoundingStrategy};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::amount::Amount;
+use crate::dispute::OverdraftPolicy;
+use crate::fees::FeeSchedule;
+use crate::limits::{BalanceLimits, WindowLimit, WindowLimits};
+use crate::scheduler::Period;
+use crate::transaction::{
+    FrozenDisputes, NegativeAmountPolicy, TransactionEngine, ZeroAmountPolicy,
+};
+use crate::validator::{MaxAmount, MaxDailyWithdrawal};
+
+/// The settings of an engine configuration file, one table per area. Apply them to an engine
+/// via `EngineConfig::apply`, or to the engines of a builder via
+/// `TransactionEngineBuilder::with_config`.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct EngineConfig {
+    pub disputes: DisputeSettings,
+    pub amounts: AmountSettings,
+    pub limits: LimitSettings,
+    pub fees: Option<FeeSchedule>,
+    pub history: HistorySettings,
+    pub output: OutputSettings,
+}
+
+/// Which transactions can be disputed, and what disputes do to accounts.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct DisputeSettings {
+    /// See `TransactionEngine::with_withdrawal_disputes`.
+    pub withdrawal_disputes: Option<bool>,
+    /// See `TransactionEngine::with_redisputes`.
+    pub redisputes: Option<bool>,
+    /// See `TransactionEngine::with_max_disputes`.
+    pub max_disputes: Option<u32>,
+    /// The dispute window in seconds (see `TransactionEngine::with_dispute_window`), written
+    /// as a duration, i.e. `"90d"`, `"12h"`, or `"3600s"`.
+    #[serde(deserialize_with = "duration")]
+    pub window: Option<u64>,
+    /// See `TransactionEngine::with_expired_eviction`.
+    pub evict_expired: Option<bool>,
+    /// See `TransactionEngine::with_deferred_disputes`.
+    pub deferred: Option<usize>,
+    pub overdraft: Option<OverdraftPolicy>,
+    /// See `TransactionEngine::with_frozen_disputes`.
+    pub frozen: Option<FrozenDisputes>,
+    /// See `TransactionEngine::with_freeze_on_chargeback`.
+    pub freeze_on_chargeback: Option<bool>,
+    /// See `TransactionEngine::with_unlock_on_reversal`.
+    pub unlock_on_reversal: Option<bool>,
+}
+
+/// Which amounts are accepted, and at what precision.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct AmountSettings {
+    /// How to round amounts with more than four decimal places (see
+    /// `TransactionEngine::with_amount_rounding`): `half-even`, `half-up`, `half-down`,
+    /// `up`, or `down`.
+    #[serde(deserialize_with = "rounding")]
+    pub rounding: Option<RoundingStrategy>,
+    pub zero: Option<ZeroAmountPolicy>,
+    pub negative: Option<NegativeAmountPolicy>,
+    /// See `TransactionEngine::with_amount_epsilon`.
+    pub epsilon: Option<Decimal>,
+}
+
+/// Upper bounds for transactions, and lower bounds for balances.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitSettings {
+    /// Registers a `MaxAmount` validator.
+    pub max_amount: Option<Amount>,
+    /// Registers a `MaxDailyWithdrawal` validator.
+    pub max_daily_withdrawal: Option<Amount>,
+    /// The limits of every account (see `TransactionEngine::with_balance_limits`), which are
+    /// zero if only the other one is set.
+    pub minimum_balance: Option<Decimal>,
+    pub overdraft_limit: Option<Decimal>,
+    /// Registers a `WindowLimits` validator with these limits, written the same way as for
+    /// `--window-limit`.
+    pub window_limits: Vec<WindowLimit>,
+}
+
+/// What the engine keeps track of.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct HistorySettings {
+    /// See `TransactionEngine::with_history_limit`.
+    pub limit: Option<usize>,
+    /// See `TransactionEngine::with_account_history`.
+    pub account_history: Option<bool>,
+}
+
+/// The columns of the account report.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutputSettings {
+    /// See `TransactionEngine::with_metadata_columns`.
+    pub metadata_columns: Option<bool>,
+    /// See `TransactionEngine::with_dispute_columns`.
+    pub dispute_columns: Option<bool>,
+}
+
+impl EngineConfig {
+    /// Reads the configuration from a TOML document. Unknown tables and settings are errors,
+    /// so typos don't go unnoticed, and so are negative fees and limits.
+    pub fn load<R: Read>(r: R) -> io::Result<Self> {
+        let config: EngineConfig = read_toml(r)?;
+        if let Some(fees) = &config.fees {
+            fees.validated()?;
+        }
+        if let Some(limits) = config.limits.balance_limits() {
+            limits.validated()?;
+        }
+        Ok(config)
+    }
+
+    /// Applies the settings which are set to `engine`, and leaves the others as they are.
+    pub fn apply(&self, mut engine: TransactionEngine) -> TransactionEngine {
+        let disputes = &self.disputes;
+        if let Some(enabled) = disputes.withdrawal_disputes {
+            engine = engine.with_withdrawal_disputes(enabled);
+        }
+        if let Some(enabled) = disputes.redisputes {
+            engine = engine.with_redisputes(enabled);
+        }
+        if let Some(max) = disputes.max_disputes {
+            engine = engine.with_max_disputes(max);
+        }
+        if let Some(window) = disputes.window {
+            engine = engine.with_dispute_window(window);
+        }
+        if let Some(enabled) = disputes.evict_expired {
+            engine = engine.with_expired_eviction(enabled);
+        }
+        if let Some(max) = disputes.deferred {
+            engine = engine.with_deferred_disputes(max);
+        }
+        if let Some(policy) = disputes.overdraft {
+            engine = engine.with_overdraft_policy(policy);
+        }
+        if let Some(frozen) = disputes.frozen {
+            engine = engine.with_frozen_disputes(frozen);
+        }
+        if let Some(enabled) = disputes.freeze_on_chargeback {
+            engine = engine.with_freeze_on_chargeback(enabled);
+        }
+        if let Some(enabled) = disputes.unlock_on_reversal {
+            engine = engine.with_unlock_on_reversal(enabled);
+        }
+
+        let amounts = &self.amounts;
+        if let Some(strategy) = amounts.rounding {
+            engine = engine.with_amount_rounding(strategy);
+        }
+        if let Some(policy) = amounts.zero {
+            engine = engine.with_zero_amount_policy(policy);
+        }
+        if let Some(policy) = amounts.negative {
+            engine = engine.with_negative_amount_policy(policy);
+        }
+        if let Some(epsilon) = amounts.epsilon {
+            engine = engine.with_amount_epsilon(epsilon);
+        }
+
+        if let Some(max) = self.limits.max_amount {
+            engine = engine.with_validator(MaxAmount(max.get()));
+        }
+        if let Some(limit) = self.limits.max_daily_withdrawal {
+            engine = engine.with_validator(MaxDailyWithdrawal::new(limit));
+        }
+        if let Some(limits) = self.limits.balance_limits() {
+            engine = engine.with_balance_limits(limits);
+        }
+        if !self.limits.window_limits.is_empty() {
+            engine = engine.with_validator(WindowLimits::new(self.limits.window_limits.clone()));
+        }
+        if let Some(schedule) = self.fees {
+            engine = engine.with_fee_schedule(schedule);
+        }
+
+        if let Some(limit) = self.history.limit {
+            engine = engine.with_history_limit(limit);
+        }
+        if let Some(enabled) = self.history.account_history {
+            engine = engine.with_account_history(enabled);
+        }
+
+        if let Some(enabled) = self.output.metadata_columns {
+            engine = engine.with_metadata_columns(enabled);
+        }
+        if let Some(enabled) = self.output.dispute_columns {
+            engine = engine.with_dispute_columns(enabled);
+        }
+        engine
+    }
+}
+
+impl LimitSettings {
+    fn balance_limits(&self) -> Option<BalanceLimits> {
+        (self.minimum_balance.is_some() || self.overdraft_limit.is_some()).then(|| BalanceLimits {
+            minimum_balance: self.minimum_balance.unwrap_or_default(),
+            overdraft_limit: self.overdraft_limit.unwrap_or_default(),
+        })
+    }
+}
+
+fn duration<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+    let duration = String::deserialize(deserializer)?;
+    match duration.parse().map_err(serde::de::Error::custom)? {
+        Period::Seconds(seconds) => Ok(Some(seconds)),
+        Period::Month => Err(serde::de::Error::custom(
+            "expected a number of days, hours, minutes, or seconds",
+        )),
+    }
+}
+
+fn rounding<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<RoundingStrategy>, D::Error> {
+    let strategy = match String::deserialize(deserializer)?.as_str() {
+        "half-even" => RoundingStrategy::MidpointNearestEven,
+        "half-up" => RoundingStrategy::MidpointAwayFromZero,
+        "half-down" => RoundingStrategy::MidpointTowardZero,
+        "up" => RoundingStrategy::AwayFromZero,
+        "down" => RoundingStrategy::ToZero,
+        other => {
+            return Err(serde::de::Error::custom(format!(
+                "invalid rounding strategy `{}`",
+                other
+            )))
+        }
+    };
+    Ok(Some(strategy))
+}
+
+// Reads a TOML document from `r`, for the settings files and the TOML states (see
+// `TransactionEngine::load_toml`).
+#[cfg(feature = "toml")]
+pub(crate) fn read_toml<T: DeserializeOwned, R: Read>(mut r: R) -> io::Result<T> {
+    let mut toml = String::new();
+    r.read_to_string(&mut toml)?;
+    toml::from_str(&toml).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+#[cfg(not(feature = "toml"))]
+pub(crate) fn read_toml<T: DeserializeOwned, R: Read>(_r: R) -> io::Result<T> {
+    Err(toml_unsupported())
+}
+
+// Formats `value` as a TOML document.
+#[cfg(feature = "toml")]
+pub(crate) fn to_toml<T: Serialize>(value: &T) -> io::Result<String> {
+    toml::to_string(value).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+#[cfg(not(feature = "toml"))]
+pub(crate) fn to_toml<T: Serialize>(_value: &T) -> io::Result<String> {
+    Err(toml_unsupported())
+}
+
+#[cfg(not(feature = "toml"))]
+fn toml_unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "TOML documents take the `toml` feature",
+    )
+}
@@ -0,0 +1,130 @@
+//! Comparison of the accounts of two engine states (or account reports), i.e. to validate an
+//! upgraded engine against the results of the previous version on the same input. Accounts
+//! are compared per client and currency, on their available and held funds, and on whether
+//! they're locked.
+
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+
+use rust_decimal::Decimal;
+use serde::Deserialize;
+
+use crate::input::reader_builder;
+use crate::transaction::{ClientId, Currency, TransactionEngine};
+
+/// The funds of a client in a single currency, as far as comparisons go.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Funds {
+    pub available: Decimal,
+    pub held: Decimal,
+    pub locked: bool,
+}
+
+/// The funds to compare, keyed by client and currency (which is empty for the implicit
+/// currency).
+pub type Accounts = BTreeMap<(ClientId, Currency), Funds>;
+
+/// A client whose funds in `currency` differ between the two sides, with the funds on either
+/// side (unset for the side without any).
+#[derive(Clone, Debug, PartialEq)]
+pub struct Discrepancy {
+    pub client: ClientId,
+    pub currency: Currency,
+    pub left: Option<Funds>,
+    pub right: Option<Funds>,
+}
+
+// A row of an account report, of which only the compared columns are needed.
+#[derive(Deserialize)]
+struct ReportRow {
+    client: ClientId,
+    #[serde(default)]
+    currency: Currency,
+    available: Decimal,
+    held: Decimal,
+    locked: bool,
+}
+
+/// The funds of the accounts of `engine`, as reported (i.e. rounded to four decimal places).
+pub fn accounts_of(engine: &TransactionEngine) -> Accounts {
+    engine
+        .account_records()
+        .into_iter()
+        .map(|record| {
+            let key = (record.client(), record.currency().unwrap_or("").to_owned());
+            let funds = Funds {
+                available: record.available(),
+                held: record.held(),
+                locked: record.locked(),
+            };
+            (key, funds)
+        })
+        .collect()
+}
+
+/// Reads the funds of the accounts from a CSV account report, as written by `te` (with or
+/// without the `currency` column, and ignoring any columns that aren't compared).
+pub fn read_report<R: Read>(r: R) -> csv::Result<Accounts> {
+    let mut accounts = Accounts::new();
+    for row in reader_builder(true).from_reader(r).deserialize() {
+        let row: ReportRow = row?;
+        let funds = Funds {
+            available: row.available,
+            held: row.held,
+            locked: row.locked,
+        };
+        accounts.insert((row.client, row.currency), funds);
+    }
+    Ok(accounts)
+}
+
+/// Lists the discrepancies between `left` and `right`, ordered by client and currency.
+pub fn compare(left: &Accounts, right: &Accounts) -> Vec<Discrepancy> {
+    let mut keys: Vec<_> = left.keys().chain(right.keys()).collect();
+    keys.sort_unstable();
+    keys.dedup();
+
+    keys.into_iter()
+        .filter_map(|key| {
+            let (left, right) = (left.get(key).copied(), right.get(key).copied());
+            (left != right).then(|| Discrepancy {
+                client: key.0,
+                currency: key.1.clone(),
+                left,
+                right,
+            })
+        })
+        .collect()
+}
+
+/// Writes `discrepancies` to `w` as CSV, with the funds of either side next to each other
+/// (and empty for the side without any).
+pub fn write_csv<W: Write>(w: W, discrepancies: &[Discrepancy]) -> csv::Result<()> {
+    let mut writer = csv::Writer::from_writer(w);
+    writer.write_record([
+        "client",
+        "currency",
+        "left_available",
+        "right_available",
+        "left_held",
+        "right_held",
+        "left_locked",
+        "right_locked",
+    ])?;
+
+    let field = |funds: Option<Funds>, f: fn(Funds) -> String| funds.map(f).unwrap_or_default();
+    for d in discrepancies {
+        writer.write_record([
+            d.client.to_string(),
+            d.currency.clone(),
+            field(d.left, |f| f.available.to_string()),
+            field(d.right, |f| f.available.to_string()),
+            field(d.left, |f| f.held.to_string()),
+            field(d.right, |f| f.held.to_string()),
+            field(d.left, |f| f.locked.to_string()),
+            field(d.right, |f| f.locked.to_string()),
+        ])?;
+    }
+    writer.flush()?;
+    Ok(())
+}
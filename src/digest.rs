@@ -0,0 +1,6 @@
+// Hex formatting of the hashes of the signed audit log (see `audit::SignedAuditLog`).
+
+// Formats `digest` as lowercase hex.
+pub fn to_hex(digest: &[u8]) -> String {
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
@@ -0,0 +1,135 @@
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use crate::transaction::{ClientId, Currency, FrozenPolicy, Transaction, TransactionId, Type};
+
+/// The rules for disputes, which differ between jurisdictions. The engine follows
+/// `StandardDisputePolicy` unless a different policy gets injected via
+/// `TransactionEngine::with_dispute_policy`. Policies are shared with the worker threads of
+/// `TransactionEngine::process_parallel`, hence the `Send + Sync` bound.
+pub trait DisputePolicy: Send + Sync {
+    /// Whether `transaction` (a deposit or withdrawal from the history) can be disputed.
+    fn is_disputable(&self, transaction: &Transaction) -> bool;
+
+    /// Whether `transaction` can be disputed again once its dispute has been resolved.
+    /// Otherwise, resolved transactions are removed from the history, which also frees up
+    /// their memory. Charged back transactions can never be disputed again.
+    fn allows_redispute(&self, _transaction: &Transaction) -> bool {
+        false
+    }
+
+    /// Whether a `dispute`, `resolve`, or `chargeback` (as given by `type_`) can go through
+    /// for a frozen account.
+    fn allowed_when_frozen(&self, type_: Type) -> bool;
+}
+
+/// A dispute which hasn't been resolved or charged back yet, as reported by
+/// `TransactionEngine::open_disputes`.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct DisputeRecord {
+    pub tx: TransactionId,
+    /// The client whose funds are held, which is the receiving end for transfers.
+    pub client: ClientId,
+    #[serde(rename = "type")]
+    pub type_: Type,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub currency: Currency,
+    /// The portion of the transaction held under dispute.
+    pub amount: Decimal,
+}
+
+impl DisputeRecord {
+    pub(crate) fn of(transaction: &Transaction) -> Self {
+        DisputeRecord {
+            tx: transaction.tx(),
+            client: transaction.holder(),
+            type_: transaction.type_(),
+            currency: transaction.currency().to_owned(),
+            amount: transaction.disputed_amount(),
+        }
+    }
+}
+
+/// What happens when disputing a deposit (or an incoming transfer) would take the available
+/// funds of the account below zero, because some of the funds have been withdrawn already.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum OverdraftPolicy {
+    /// The dispute goes through, and the available funds become negative.
+    #[default]
+    Allow,
+    /// The dispute is rejected with `Error::InsufficientFunds`.
+    Reject,
+    /// The dispute goes through, but the account is put under dispute (see
+    /// `Account::under_dispute`), which blocks withdrawals and outgoing transfers with
+    /// `Error::AccountUnderDispute` until all of its disputes have been settled.
+    Restrict,
+}
+
+/// The rules the engine uses by default: only deposits and transfers can be disputed (and
+/// withdrawals, if enabled), every transaction can only be disputed once (unless re-disputes
+/// are enabled, possibly up to a number of disputes), and dispute-related transactions for
+/// frozen accounts are subject to the `FrozenPolicy`. These are the rules configured via
+/// `TransactionEngine::with_withdrawal_disputes`, `TransactionEngine::with_redisputes`,
+/// `TransactionEngine::with_max_disputes`, and `TransactionEngine::with_frozen_policy`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StandardDisputePolicy {
+    withdrawal_disputes: bool,
+    frozen_policy: FrozenPolicy,
+    redisputes: bool,
+    max_disputes: Option<u32>,
+}
+
+impl StandardDisputePolicy {
+    pub fn new(withdrawal_disputes: bool, frozen_policy: FrozenPolicy) -> Self {
+        StandardDisputePolicy {
+            withdrawal_disputes,
+            frozen_policy,
+            redisputes: false,
+            max_disputes: None,
+        }
+    }
+
+    /// Lets transactions be disputed again once their dispute has been resolved.
+    pub fn with_redisputes(mut self, enabled: bool) -> Self {
+        self.redisputes = enabled;
+        self
+    }
+
+    /// Limits how many times a transaction can be disputed, with re-disputes allowed until
+    /// then (regardless of `with_redisputes`). `None` leaves it up to `with_redisputes`.
+    pub fn with_max_disputes(mut self, max: Option<u32>) -> Self {
+        self.max_disputes = max;
+        self
+    }
+
+    fn limit_reached(&self, transaction: &Transaction) -> bool {
+        self.max_disputes
+            .is_some_and(|max| transaction.dispute_count() >= max)
+    }
+}
+
+impl DisputePolicy for StandardDisputePolicy {
+    fn is_disputable(&self, transaction: &Transaction) -> bool {
+        // Adding to an open dispute doesn't count as another one.
+        if !transaction.disputed() && self.limit_reached(transaction) {
+            return false;
+        }
+        match transaction.type_() {
+            Type::Deposit | Type::Transfer => true,
+            Type::Withdrawal => self.withdrawal_disputes,
+            _ => false,
+        }
+    }
+
+    fn allows_redispute(&self, transaction: &Transaction) -> bool {
+        match self.max_disputes {
+            Some(_) => !self.limit_reached(transaction),
+            None => self.redisputes,
+        }
+    }
+
+    fn allowed_when_frozen(&self, type_: Type) -> bool {
+        self.frozen_policy.allows(type_)
+    }
+}
@@ -0,0 +1,227 @@
+//! Encryption of saved engine states, which hold regulated financial data that can't sit on
+//! disk in plaintext. States are encrypted as a whole with AES-256-GCM, under a random nonce
+//! for every file, so tampering with an encrypted state (or decrypting it with the wrong key)
+//! is detected rather than yielding garbage. Encrypted files start with `TEENC1`, followed by
+//! the nonce, the encrypted state, and the authentication tag:
+//!
+//! ```
+//! use te::encryption::{self, StateKey};
+//! use te::TransactionEngine;
+//!
+//! let key = StateKey::from_bytes(&[7; 32]).unwrap();
+//! let mut state = Vec::new();
+//! TransactionEngine::new().save_checkpoint(&mut state).unwrap();
+//!
+//! let encrypted = encryption::encrypt(&key, &state).unwrap();
+//! assert!(encryption::is_encrypted(&encrypted));
+//! assert_eq!(encryption::decrypt(&key, &encrypted).unwrap(), state);
+//! ```
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::ptr;
+
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+// What encrypted states start with, which is also authenticated along with them.
+const MAGIC: &[u8] = b"TEENC1";
+
+/// A key for encrypting states. Zeroed when dropped, and left out of `Debug` output.
+#[derive(Clone)]
+pub struct StateKey([u8; KEY_LEN]);
+
+impl StateKey {
+    fn cipher(&self) -> Aes256Gcm {
+        Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&self.0))
+    }
+
+    /// Takes a key of 32 raw bytes as it is, or parses one written as 64 hex digits (see
+    /// `from_hex`).
+    pub fn from_bytes(key: &[u8]) -> io::Result<Self> {
+        match <[u8; KEY_LEN]>::try_from(key) {
+            Ok(raw) => Ok(StateKey(raw)),
+            Err(_) => StateKey::from_hex(key),
+        }
+    }
+
+    /// Parses a key written as 64 hex digits, with any surrounding whitespace (i.e. a trailing
+    /// line break).
+    pub fn from_hex<H: AsRef<[u8]>>(hex: H) -> io::Result<Self> {
+        let hex = hex.as_ref().trim_ascii();
+        if hex.len() != 2 * KEY_LEN {
+            return Err(invalid_data(
+                "the key must be 32 bytes, or 64 hexadecimal digits",
+            ));
+        }
+        let mut raw = [0; KEY_LEN];
+        for (byte, digits) in raw.iter_mut().zip(hex.chunks(2)) {
+            *byte = std::str::from_utf8(digits)
+                .ok()
+                .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+                .ok_or_else(|| invalid_data("the key has a character which isn't a hex digit"))?;
+        }
+        Ok(StateKey(raw))
+    }
+
+    /// Reads the key from the file at `path` (see `from_bytes`).
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let mut key = fs::read(path)?;
+        let result = StateKey::from_bytes(&key);
+        key.fill(0);
+        result
+    }
+}
+
+impl fmt::Debug for StateKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("StateKey(..)")
+    }
+}
+
+impl Drop for StateKey {
+    fn drop(&mut self) {
+        // Volatile, so the writes don't get optimized away as dead stores.
+        for byte in self.0.iter_mut() {
+            unsafe { ptr::write_volatile(byte, 0) };
+        }
+    }
+}
+
+/// Whether `data` is an encrypted state, as opposed to a plaintext one.
+pub fn is_encrypted(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+/// Encrypts the state in `plaintext` (i.e. as written by `save_checkpoint`) with `key`. Only
+/// fails if the operating system doesn't provide the randomness for the nonce.
+pub fn encrypt(key: &StateKey, plaintext: &[u8]) -> io::Result<Vec<u8>> {
+    let mut nonce = [0; NONCE_LEN];
+    getrandom::getrandom(&mut nonce)?;
+
+    let payload = Payload {
+        msg: plaintext,
+        aad: MAGIC,
+    };
+    // The ciphertext comes with the tag appended.
+    let sealed = key
+        .cipher()
+        .encrypt(Nonce::from_slice(&nonce), payload)
+        .map_err(|_| invalid_data("the state is too large to encrypt"))?;
+
+    let mut encrypted = Vec::with_capacity(MAGIC.len() + NONCE_LEN + sealed.len());
+    encrypted.extend_from_slice(MAGIC);
+    encrypted.extend_from_slice(&nonce);
+    encrypted.extend_from_slice(&sealed);
+    Ok(encrypted)
+}
+
+/// Decrypts a state encrypted by `encrypt`. Fails with `io::ErrorKind::InvalidData` if
+/// `data` isn't an encrypted state, or if it was encrypted with another key or has been
+/// tampered with.
+pub fn decrypt(key: &StateKey, data: &[u8]) -> io::Result<Vec<u8>> {
+    if !is_encrypted(data) || data.len() < MAGIC.len() + NONCE_LEN + TAG_LEN {
+        return Err(invalid_data("not an encrypted state"));
+    }
+    let (nonce, sealed) = data[MAGIC.len()..].split_at(NONCE_LEN);
+    let payload = Payload {
+        msg: sealed,
+        aad: MAGIC,
+    };
+    key.cipher()
+        .decrypt(Nonce::from_slice(nonce), payload)
+        .map_err(|_| {
+            invalid_data(
+                "unable to decrypt the state: wrong key, or the file has been tampered with",
+            )
+        })
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn matches_the_gcm_test_vectors() {
+        // Test case 14 of the GCM specification (AES-256, zero key and IV, one zero block).
+        let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&[0; KEY_LEN]));
+        let sealed = cipher
+            .encrypt(Nonce::from_slice(&[0; NONCE_LEN]), &[0; 16][..])
+            .unwrap();
+        assert_eq!(
+            sealed,
+            hex("cea7403d4d606b6e074ec5d3baf39d18d0d1c8a799996bf0265b98b5d48ab919")
+        );
+    }
+
+    #[test]
+    fn decrypts_states_encrypted_elsewhere() {
+        // Encrypted with Python's `cryptography` package, under the nonce 0, 1, ..., 11.
+        let key = StateKey::from_bytes(&[7; KEY_LEN]).unwrap();
+        let encrypted = hex(
+            "5445454e4331000102030405060708090a0b63a388137e66ac2703c1c7a6b90511bc6277108349c4f59ec5e1372cebb908",
+        );
+        assert_eq!(decrypt(&key, &encrypted).unwrap(), br#"{"accounts":[]}"#);
+    }
+
+    #[test]
+    fn round_trips_under_a_fresh_nonce() {
+        let key = StateKey::from_hex("07".repeat(KEY_LEN)).unwrap();
+        let first = encrypt(&key, b"state").unwrap();
+        let second = encrypt(&key, b"state").unwrap();
+        assert_ne!(first, second);
+        assert_eq!(decrypt(&key, &first).unwrap(), b"state");
+        assert_eq!(decrypt(&key, &second).unwrap(), b"state");
+    }
+
+    #[test]
+    fn rejects_the_wrong_key() {
+        let key = StateKey::from_bytes(&[7; KEY_LEN]).unwrap();
+        let other = StateKey::from_bytes(&[8; KEY_LEN]).unwrap();
+        let encrypted = encrypt(&key, b"state").unwrap();
+        let e = decrypt(&other, &encrypted).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn rejects_tampered_states() {
+        let key = StateKey::from_bytes(&[7; KEY_LEN]).unwrap();
+        let encrypted = encrypt(&key, b"state").unwrap();
+
+        // The nonce, the ciphertext, and the tag are all covered.
+        for i in MAGIC.len()..encrypted.len() {
+            let mut tampered = encrypted.clone();
+            tampered[i] ^= 1;
+            assert!(decrypt(&key, &tampered).is_err(), "byte {} not covered", i);
+        }
+        assert!(decrypt(&key, &encrypted[..encrypted.len() - 1]).is_err());
+        assert!(decrypt(&key, &encrypted[..MAGIC.len() + NONCE_LEN]).is_err());
+        assert!(decrypt(&key, b"state").is_err());
+    }
+
+    #[test]
+    fn parses_keys() {
+        assert!(StateKey::from_hex(format!("{}\n", "ab".repeat(KEY_LEN))).is_ok());
+        assert!(StateKey::from_hex("ab".repeat(KEY_LEN - 1)).is_err());
+        assert!(StateKey::from_hex("zz".repeat(KEY_LEN)).is_err());
+        assert!(StateKey::from_bytes(&[0; 16]).is_err());
+    }
+}
@@ -0,0 +1,152 @@
+//! The event log of the engine (see `TransactionEngine::with_event_log`), which records every
+//! change to an account as a sequence of `AccountEvent`s. Folding the events (see `fold`)
+//! derives the accounts again, so the log doubles as a complete audit trail, and as a way of
+//! reconstructing the accounts as of any point in it.
+
+use std::collections::{BTreeMap, HashSet};
+use std::io::{self, Write};
+
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use crate::account::Account;
+use crate::risk::RiskFlag;
+use crate::transaction::{ClientId, Currency, TransactionId};
+
+/// A single change to an account. Balance changes are broken down into funds entering or
+/// leaving the account, and funds moving between its available and held funds (i.e. a
+/// chargeback of a deposit releases the held funds, which then leave the account).
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum AccountEvent {
+    AccountOpened,
+    // A balance in a new currency, before any funds have been credited to it.
+    BalanceOpened { currency: Currency },
+    FundsDeposited { currency: Currency, amount: Decimal },
+    FundsWithdrawn { currency: Currency, amount: Decimal },
+    // Moved from the available to the held funds.
+    FundsHeld { currency: Currency, amount: Decimal },
+    // Moved from the held to the available funds.
+    FundsReleased { currency: Currency, amount: Decimal },
+    AccountFrozen,
+    AccountUnfrozen,
+    AccountClosed,
+    AccountReopened,
+    ReviewStarted,
+    ReviewApproved,
+    // Withdrawals are blocked until the open disputes have been settled (see
+    // `OverdraftPolicy::Restrict`), or not anymore.
+    WithdrawalsBlocked,
+    WithdrawalsUnblocked,
+    ChargebackRecorded,
+    // The overdraft line of the account has been used (see `BalanceLimits`).
+    OverdraftUsed,
+    RiskFlagged { flag: RiskFlag },
+    TimestampRecorded { timestamp: u64 },
+}
+
+/// An event together with where it happened: the account of `client`, as part of processing
+/// transaction `tx` (which is unset for changes outside of any transaction, i.e. adjustments,
+/// or the accounts an engine already had when the log got enabled), with the timestamp of the
+/// transaction if it has one. Sequence numbers count the events of the log, starting from
+/// zero.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct EventRecord {
+    pub seq: u64,
+    pub client: ClientId,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tx: Option<TransactionId>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<u64>,
+    #[serde(flatten)]
+    pub event: AccountEvent,
+}
+
+/// Derives the accounts from `records`, i.e. from a prefix of the event log to get the
+/// accounts as of that point. Open disputes are not counted, and account histories (see
+/// `TransactionEngine::with_account_history`) are not part of the log, so both stay empty.
+pub fn fold<'a, I>(records: I) -> BTreeMap<ClientId, Account>
+where
+    I: IntoIterator<Item = &'a EventRecord>,
+{
+    let mut accounts = BTreeMap::new();
+    for record in records {
+        accounts
+            .entry(record.client)
+            .or_insert_with(Account::default)
+            .apply_event(&record.event);
+    }
+    accounts
+}
+
+/// Writes `records` to `w` as JSON Lines, one event per line.
+pub fn write_jsonl<'a, W, I>(mut w: W, records: I) -> io::Result<()>
+where
+    W: Write,
+    I: IntoIterator<Item = &'a EventRecord>,
+{
+    for record in records {
+        serde_json::to_writer(&mut w, record)?;
+        w.write_all(b"\n")?;
+    }
+    w.flush()
+}
+
+// The events recorded so far, which the accounts record themselves as they change (see
+// `Account::take_events`).
+#[derive(Default)]
+pub(crate) struct EventLog {
+    records: Vec<EventRecord>,
+    // The clients whose accounts have been opened in the log.
+    opened: HashSet<ClientId>,
+}
+
+impl EventLog {
+    // Starts a log on top of the accounts of `clients` (i.e. those handed to a shard), without
+    // recording any events for them.
+    pub(crate) fn on_top_of<I: IntoIterator<Item = ClientId>>(clients: I) -> Self {
+        EventLog {
+            records: Vec::new(),
+            opened: clients.into_iter().collect(),
+        }
+    }
+
+    // Records `events` for the account of `client`, as part of processing transaction `tx`
+    // (at `timestamp`), after opening the account if it's new to the log.
+    pub(crate) fn record(
+        &mut self,
+        client: ClientId,
+        (tx, timestamp): (Option<TransactionId>, Option<u64>),
+        events: Vec<AccountEvent>,
+    ) {
+        let opened = self
+            .opened
+            .insert(client)
+            .then_some(AccountEvent::AccountOpened);
+        for event in opened.into_iter().chain(events) {
+            self.records.push(EventRecord {
+                seq: self.records.len() as u64,
+                client,
+                tx,
+                timestamp,
+                event,
+            });
+        }
+    }
+
+    // Appends the events of `other`, which covers a disjoint set of clients (i.e. those of a
+    // shard), renumbering them to follow the events of this log.
+    pub(crate) fn merge(&mut self, other: EventLog) {
+        let offset = self.records.len() as u64;
+        self.records
+            .extend(other.records.into_iter().map(|record| EventRecord {
+                seq: record.seq + offset,
+                ..record
+            }));
+        self.opened.extend(other.opened);
+    }
+
+    pub(crate) fn records(&self) -> &[EventRecord] {
+        &self.records
+    }
+}
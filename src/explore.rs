@@ -0,0 +1,153 @@
+use std::cmp::Reverse;
+use std::io::{self, BufRead, Write};
+
+use rust_decimal::Decimal;
+use te::account::AccountRecord;
+use te::transaction::{self, ClientId, Error, TransactionEngine, TransactionId};
+
+const HELP: &str = "\
+account <client>           the statement of a client
+tx <id>                    a retained transaction
+reference <reference>      the retained transactions with an external reference
+disputes                   the open disputes
+top <n> by <funds>         the accounts with the most available, held, or total funds
+summary                    the totals across all accounts
+quit                       ends the session";
+
+// Runs an interactive prompt for exploring the state of `engine` (see `inspect
+// --interactive`), which answers one query per line until `quit` or the end of the input.
+// Unlike the REPL, the state is read-only, so analysts can't change it by accident.
+pub fn run(engine: &TransactionEngine) -> io::Result<()> {
+    let stdin = io::stdin();
+    let mut stdout = io::stdout();
+
+    prompt(&mut stdout)?;
+
+    for line in stdin.lock().lines() {
+        let line = line?;
+        let words: Vec<_> = line.split_whitespace().collect();
+
+        match words.as_slice() {
+            [] => {}
+            ["quit"] => break,
+            ["help"] => writeln!(stdout, "{}", HELP)?,
+            ["account", client] => match client.parse() {
+                Ok(client) => write_account(engine, client, &mut stdout)?,
+                Err(e) => writeln!(stdout, "invalid client id: {}", e)?,
+            },
+            ["tx", tx] => match tx.parse() {
+                Ok(tx) => write_transaction(engine, tx, &mut stdout)?,
+                Err(e) => writeln!(stdout, "invalid transaction id: {}", e)?,
+            },
+            ["reference", reference] => write_references(engine, reference, &mut stdout)?,
+            ["disputes"] => write_disputes(engine, &mut stdout)?,
+            ["top", n, "by", funds] => match (n.parse(), funds_of(funds)) {
+                (Ok(n), Some(funds)) => write_top(engine, n, funds, &mut stdout)?,
+                (Err(e), _) => writeln!(stdout, "invalid number of accounts: {}", e)?,
+                (_, None) => writeln!(stdout, "expected `available`, `held`, or `total`")?,
+            },
+            ["summary"] => writeln!(stdout, "{}", engine.aggregate())?,
+            _ => writeln!(stdout, "unknown command (`help` lists them)")?,
+        }
+
+        prompt(&mut stdout)?;
+    }
+
+    Ok(())
+}
+
+fn write_account<W: Write>(
+    engine: &TransactionEngine,
+    client: ClientId,
+    w: &mut W,
+) -> io::Result<()> {
+    match engine.statement(client) {
+        Some(statement) => statement.write_table(w),
+        None => writeln!(w, "error: {}", Error::AccountNotFound { client }),
+    }
+}
+
+// Writes the transaction as a CSV row (with a header), along with the funds held under
+// dispute if it's disputed.
+fn write_transaction<W: Write>(
+    engine: &TransactionEngine,
+    tx: TransactionId,
+    w: &mut W,
+) -> io::Result<()> {
+    let transaction = match engine.transaction(tx) {
+        Some(transaction) => transaction,
+        None => return writeln!(w, "transaction {} is not retained", tx),
+    };
+
+    let mut writer = csv::Writer::from_writer(&mut *w);
+    writer.serialize(&transaction)?;
+    writer.flush()?;
+    drop(writer);
+    if transaction.disputed() {
+        writeln!(w, "disputed: {} held", transaction.disputed_amount())?;
+    }
+    Ok(())
+}
+
+// Writes the retained transactions with the external reference as CSV rows (with a header).
+fn write_references<W: Write>(
+    engine: &TransactionEngine,
+    reference: &str,
+    w: &mut W,
+) -> io::Result<()> {
+    let transactions = engine.find_by_reference(reference);
+    if transactions.is_empty() {
+        return writeln!(w, "no transactions with reference {}", reference);
+    }
+
+    transaction::write_transactions(transactions, w)?;
+    Ok(())
+}
+
+fn write_disputes<W: Write>(engine: &TransactionEngine, w: &mut W) -> io::Result<()> {
+    let disputes = engine.open_disputes();
+    if disputes.is_empty() {
+        return writeln!(w, "no open disputes");
+    }
+
+    // Only multi-asset disputes have a `currency` column.
+    let mut writer = csv::WriterBuilder::new().flexible(true).from_writer(w);
+    for dispute in disputes {
+        writer.serialize(dispute)?;
+    }
+    writer.flush()
+}
+
+fn funds_of(name: &str) -> Option<fn(&AccountRecord) -> Decimal> {
+    match name {
+        "available" => Some(|record| record.available()),
+        "held" => Some(|record| record.held()),
+        "total" => Some(|record| record.total()),
+        _ => None,
+    }
+}
+
+// Writes the `n` account rows with the most `funds` as CSV (ties are ordered by client id).
+fn write_top<W: Write>(
+    engine: &TransactionEngine,
+    n: usize,
+    funds: fn(&AccountRecord) -> Decimal,
+    w: &mut W,
+) -> io::Result<()> {
+    let mut records = engine.account_records();
+    if records.is_empty() {
+        return writeln!(w, "no accounts");
+    }
+    records.sort_by_key(|record| Reverse(funds(record)));
+
+    let mut writer = csv::Writer::from_writer(w);
+    for record in records.into_iter().take(n) {
+        writer.serialize(record)?;
+    }
+    writer.flush()
+}
+
+fn prompt<W: Write>(w: &mut W) -> io::Result<()> {
+    write!(w, "> ")?;
+    w.flush()
+}
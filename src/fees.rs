@@ -0,0 +1,57 @@
+//! Fees charged on withdrawals (see `TransactionEngine::with_fee_schedule`), which are
+//! deducted from the available funds together with the withdrawn amount, so a withdrawal
+//! only goes through if the account can cover both. The schedule is usually loaded from a
+//! TOML file (see `FeeSchedule::load`), with either or both kinds of fees:
+//!
+//! ```toml
+//! flat = "0.25"     # per withdrawal, in the currency of the withdrawal
+//! percent = "1.5"   # of the withdrawn amount
+//! ```
+
+use std::io::{self, Read};
+
+use rust_decimal::Decimal;
+use serde::Deserialize;
+
+use crate::config;
+use crate::transaction::MAX_AMOUNT_SCALE;
+
+/// The fees charged on every withdrawal: a flat fee, plus a percentage of the withdrawn
+/// amount (both zero unless configured).
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct FeeSchedule {
+    #[serde(default)]
+    pub flat: Decimal,
+    #[serde(default)]
+    pub percent: Decimal,
+}
+
+impl FeeSchedule {
+    /// Reads the schedule from a TOML document.
+    pub fn load<R: Read>(r: R) -> io::Result<Self> {
+        let schedule: FeeSchedule = config::read_toml(r)?;
+        schedule.validated()
+    }
+
+    pub(crate) fn validated(self) -> io::Result<Self> {
+        if self.flat.is_sign_negative() || self.percent.is_sign_negative() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "fees can't be negative",
+            ));
+        }
+        Ok(self)
+    }
+
+    /// The fee for withdrawing `amount`, rounded to the precision of amounts. Zero-amount
+    /// withdrawals (see `TransactionEngine::with_zero_amounts_allowed`) are free, since they
+    /// have no effect.
+    pub fn fee(&self, amount: Decimal) -> Decimal {
+        if amount.is_zero() {
+            return Decimal::ZERO;
+        }
+        let percentage = amount * self.percent / Decimal::new(100, 0);
+        (self.flat + percentage).round_dp(MAX_AMOUNT_SCALE)
+    }
+}
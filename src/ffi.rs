@@ -0,0 +1,119 @@
+//! C API for embedding the engine in native code (i.e. C or C++ services), built with the
+//! `capi` feature, which also generates the matching header (`include/te.h`). The engine is
+//! an opaque pointer owned by the caller, which has to release it with `engine_free`:
+//!
+//! ```c
+//! TransactionEngine *engine = engine_new();
+//! engine_process_csv_line(engine, "deposit,1,1,2.5");
+//!
+//! size_t length = engine_export_accounts(engine, NULL, 0);
+//! char *accounts = malloc(length + 1);
+//! engine_export_accounts(engine, accounts, length + 1);
+//! engine_free(engine);
+//! ```
+//!
+//! None of the functions are thread-safe, so an engine must not be used from several threads
+//! at once.
+
+use std::ffi::CStr;
+use std::os::raw::c_char;
+use std::ptr;
+
+use crate::input::reader_builder;
+use crate::transaction::{Transaction, TransactionEngine};
+
+/// The outcome of `engine_process_csv_line`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum EngineStatus {
+    /// The transaction was applied.
+    Ok = 0,
+    /// The engine or the line was a null pointer, or the line wasn't valid UTF-8.
+    InvalidArgument = 1,
+    /// The line isn't a valid transaction record.
+    InvalidRecord = 2,
+    /// The transaction was rejected by the engine (which leaves the state unchanged).
+    Rejected = 3,
+}
+
+/// Creates an engine with the default configuration.
+#[no_mangle]
+pub extern "C" fn engine_new() -> *mut TransactionEngine {
+    Box::into_raw(Box::new(TransactionEngine::new()))
+}
+
+/// Processes a single header-less CSV row (in the `type,client,tx,amount,...` column order of
+/// the CLI input without a header), given as a NUL terminated string.
+///
+/// # Safety
+///
+/// `engine` has to be null or returned by `engine_new` (and not freed yet), and `line` has to
+/// be null or point to a NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn engine_process_csv_line(
+    engine: *mut TransactionEngine,
+    line: *const c_char,
+) -> EngineStatus {
+    if engine.is_null() || line.is_null() {
+        return EngineStatus::InvalidArgument;
+    }
+    let line = match CStr::from_ptr(line).to_str() {
+        Ok(line) => line,
+        Err(_) => return EngineStatus::InvalidArgument,
+    };
+
+    let transaction = reader_builder(false)
+        .from_reader(line.as_bytes())
+        .into_deserialize::<Transaction>()
+        .next();
+    match transaction {
+        Some(Ok(transaction)) => match (*engine).process_transaction(transaction) {
+            Ok(()) => EngineStatus::Ok,
+            Err(_) => EngineStatus::Rejected,
+        },
+        _ => EngineStatus::InvalidRecord,
+    }
+}
+
+/// Writes the account report (the same CSV as the output of the CLI) to `buffer`, as a NUL
+/// terminated string, and returns its length (not counting the NUL). If `capacity` is too
+/// small, the report is truncated, so callers can pass a null `buffer` to find out how much
+/// space is needed first (like `snprintf`). Returns 0 if `engine` is null.
+///
+/// # Safety
+///
+/// `engine` has to be null or returned by `engine_new` (and not freed yet), and `buffer` has
+/// to be null or point to at least `capacity` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn engine_export_accounts(
+    engine: *const TransactionEngine,
+    buffer: *mut c_char,
+    capacity: usize,
+) -> usize {
+    if engine.is_null() {
+        return 0;
+    }
+    let mut report = Vec::new();
+    (*engine)
+        .write_accounts(&mut report)
+        .expect("Writing to a vector can't fail");
+
+    if !buffer.is_null() && capacity > 0 {
+        let length = report.len().min(capacity - 1);
+        ptr::copy_nonoverlapping(report.as_ptr(), buffer as *mut u8, length);
+        *buffer.add(length) = 0;
+    }
+    report.len()
+}
+
+/// Releases an engine. Does nothing if `engine` is null.
+///
+/// # Safety
+///
+/// `engine` has to be null or returned by `engine_new`, and must not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn engine_free(engine: *mut TransactionEngine) {
+    if !engine.is_null() {
+        drop(Box::from_raw(engine));
+    }
+}
@@ -0,0 +1,133 @@
+//! Entry points for fuzzing the engine (see the `fuzz/` directory, which has a `cargo fuzz`
+//! target on top of `process_bytes`). The invariant checks (see
+//! `TransactionEngine::with_invariant_checks`) are enabled for every engine set up here, and
+//! any `Error::InvariantViolated` they return makes the fuzz target panic.
+
+use std::io::{Cursor, Read};
+
+use arbitrary::{Arbitrary, Unstructured};
+use rust_decimal::Decimal;
+
+use crate::dispute::OverdraftPolicy;
+use crate::transaction::{Error, Transaction, TransactionEngine, Type};
+
+// The clients and transaction ids are drawn from small ranges, so the dispute-related
+// transactions and transfers of random sequences mostly refer to existing ones.
+const CLIENTS: u32 = 8;
+const TRANSACTIONS: u64 = 32;
+
+const TYPES: [Type; 14] = [
+    Type::Approve,
+    Type::Chargeback,
+    Type::ChargebackReversal,
+    Type::Close,
+    Type::Deposit,
+    Type::Dispute,
+    Type::Hold,
+    Type::Reopen,
+    Type::Resolve,
+    Type::Review,
+    Type::Transfer,
+    Type::Unlock,
+    Type::Void,
+    Type::Withdrawal,
+];
+
+/// Feeds `data` into the engine in two ways: as CSV input (after a header row with all the
+/// columns), and as a sequence of transactions decoded from it (via `Arbitrary`). The first
+/// byte picks the configuration of the engines, so the optional behaviors get their share of
+/// the runs.
+pub fn process_bytes(data: &[u8]) {
+    let (flags, data) = match data.split_first() {
+        Some((&flags, data)) => (flags, data),
+        None => return,
+    };
+
+    let header: &[u8] = b"type,client,tx,amount,currency,to,timestamp,expires\n";
+    let mut engine = fuzzed_engine(flags);
+    // Invalid records are skipped, and reading from memory can't fail.
+    if let Ok(summary) = engine.process_from_reader(header.chain(Cursor::new(data))) {
+        let violations = summary.errors.get("InvariantViolated");
+        assert!(violations.is_none(), "Invariant violated by the CSV input");
+    }
+
+    let mut engine = fuzzed_engine(flags);
+    let mut u = Unstructured::new(data);
+    while !u.is_empty() {
+        let transaction = match Transaction::arbitrary(&mut u) {
+            Ok(transaction) => transaction,
+            Err(_) => break,
+        };
+        if let Err(e @ Error::InvariantViolated { .. }) = engine.process_transaction(transaction) {
+            panic!("Invariant violated: {}", e);
+        }
+        engine.drain_warnings();
+    }
+}
+
+// An engine with invariant checks, and the behaviors enabled by the bits of `flags`.
+fn fuzzed_engine(flags: u8) -> TransactionEngine {
+    let bit = |n: u8| flags & (1 << n) != 0;
+    let overdraft_policy = match flags >> 6 {
+        0 => OverdraftPolicy::Allow,
+        1 => OverdraftPolicy::Reject,
+        _ => OverdraftPolicy::Restrict,
+    };
+
+    let mut engine = TransactionEngine::new()
+        .with_invariant_checks(true)
+        .with_withdrawal_disputes(bit(0))
+        .with_redisputes(bit(1))
+        .with_admin_ops(bit(2))
+        .with_zero_amounts_allowed(bit(3))
+        .with_signed_amounts(bit(4))
+        .with_unlock_on_reversal(bit(5))
+        .with_overdraft_policy(overdraft_policy);
+    if bit(1) && bit(3) {
+        engine = engine.with_max_disputes(2);
+    }
+    engine
+}
+
+// Decodes transactions from the raw bytes of a fuzzer: of any type, with an amount for the
+// types that need one (and sometimes for the others), in one of two currencies.
+impl<'a> Arbitrary<'a> for Transaction {
+    fn arbitrary(u: &mut Unstructured<'a>) -> arbitrary::Result<Self> {
+        let type_ = *u.choose(&TYPES)?;
+        let client = u.int_in_range(0..=CLIENTS - 1)?;
+        let tx = u.int_in_range(0..=TRANSACTIONS - 1)?;
+
+        let amount = match type_ {
+            Type::Deposit | Type::Withdrawal | Type::Transfer | Type::Hold => Some(amount(u)?),
+            _ if u.arbitrary()? => Some(amount(u)?),
+            _ => None,
+        };
+        let mut transaction = Transaction::new(type_, client, tx, amount);
+        if u.arbitrary()? {
+            transaction = transaction.with_currency("EUR".to_owned());
+        }
+        if type_ == Type::Transfer || u.arbitrary()? {
+            transaction = transaction.with_destination(u.int_in_range(0..=CLIENTS - 1)?);
+        }
+        // Timestamps and expiries from a small range as well, so holds expire every so often.
+        if u.arbitrary()? {
+            transaction = transaction.with_timestamp(u64::from(u.arbitrary::<u8>()?));
+        }
+        if type_ == Type::Hold || u.arbitrary()? {
+            transaction = transaction.with_expiry(u64::from(u.arbitrary::<u8>()?));
+        }
+        Ok(transaction)
+    }
+}
+
+// Mostly small amounts with up to six decimal places (more than amounts may have), which may
+// be negative, and the occasional extreme one.
+fn amount(u: &mut Unstructured<'_>) -> arbitrary::Result<Decimal> {
+    let mantissa: i32 = u.arbitrary()?;
+    let amount = match u.arbitrary::<u8>()? {
+        0xff => Decimal::MAX,
+        0xfe => Decimal::MIN,
+        scale => Decimal::new(i64::from(mantissa), u32::from(scale % 7)),
+    };
+    Ok(amount)
+}
@@ -0,0 +1,106 @@
+//! The ids of client accounts and transactions, as types of their own, so one can't be
+//! passed where the other is expected. Both convert from and to the raw integers (i.e.
+//! `ClientId::from(7)`, or `7.into()` for the arguments of `Transaction::new`), and are
+//! written and parsed the same way as those, in the input and in checkpoints alike.
+
+use std::fmt;
+use std::num::ParseIntError;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+/// The id of a client account. Wide enough for feeds with client ids beyond `u16`, which
+/// would otherwise fail to deserialize.
+#[derive(
+    Clone, Copy, Debug, Default, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize,
+)]
+#[serde(transparent)]
+pub struct ClientId(u32);
+
+impl ClientId {
+    pub const MIN: ClientId = ClientId(u32::MIN);
+    pub const MAX: ClientId = ClientId(u32::MAX);
+
+    pub const fn new(id: u32) -> Self {
+        ClientId(id)
+    }
+
+    /// The raw id.
+    pub const fn get(self) -> u32 {
+        self.0
+    }
+}
+
+impl From<u32> for ClientId {
+    fn from(id: u32) -> Self {
+        ClientId(id)
+    }
+}
+
+impl From<ClientId> for u32 {
+    fn from(id: ClientId) -> Self {
+        id.0
+    }
+}
+
+impl fmt::Display for ClientId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+impl FromStr for ClientId {
+    type Err = ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse().map(ClientId)
+    }
+}
+
+/// The id of a transaction, which is supposed to be unique across all clients. Wide enough
+/// for feeds with transaction ids beyond `u32`.
+#[derive(
+    Clone, Copy, Debug, Default, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize,
+)]
+#[serde(transparent)]
+pub struct TransactionId(u64);
+
+impl TransactionId {
+    pub const MIN: TransactionId = TransactionId(u64::MIN);
+    pub const MAX: TransactionId = TransactionId(u64::MAX);
+
+    pub const fn new(id: u64) -> Self {
+        TransactionId(id)
+    }
+
+    /// The raw id.
+    pub const fn get(self) -> u64 {
+        self.0
+    }
+}
+
+impl From<u64> for TransactionId {
+    fn from(id: u64) -> Self {
+        TransactionId(id)
+    }
+}
+
+impl From<TransactionId> for u64 {
+    fn from(id: TransactionId) -> Self {
+        id.0
+    }
+}
+
+impl fmt::Display for TransactionId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+impl FromStr for TransactionId {
+    type Err = ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse().map(TransactionId)
+    }
+}
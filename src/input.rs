@@ -0,0 +1,487 @@
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read};
+use std::iter;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use csv::{ReaderBuilder, StringRecord, Trim};
+
+use crate::transaction::Transaction;
+
+// The columns of transaction input, in the canonical order which header-less input gets
+// normalized to (see `ColumnOrder`) before mapping its columns positionally.
+pub(crate) const COLUMNS: [&str; 12] = [
+    "type",
+    "client",
+    "tx",
+    "amount",
+    "currency",
+    "to",
+    "timestamp",
+    "expires",
+    "tenant",
+    "memo",
+    "reference",
+    "seq",
+];
+// Columns which are needed for every kind of transaction.
+const REQUIRED_COLUMNS: [&str; 3] = ["type", "client", "tx"];
+// The number of leading canonical columns (`type,client,tx,amount`) which header-less input
+// has without an explicit column order.
+const HEADERLESS_COLUMNS: usize = 4;
+
+/// Returns the CSV reader configuration used for transaction input.
+pub fn reader_builder(has_headers: bool) -> ReaderBuilder {
+    let mut builder = ReaderBuilder::new();
+    builder
+        // Required for the csv logic to ignore whitespaces; otherwise, the presence of
+        // any whitespace seems to cause errors.
+        .trim(Trim::All)
+        // Setting this so we can have rows where the amount is not explicitly specified
+        // (i.e. dispute-related transactions). This also means rows may have extra trailing
+        // fields (like a stray comma at the end), which are deliberately ignored: fields
+        // are only ever mapped onto the known columns (by name, or by their position in a
+        // `ColumnOrder`), so anything beyond them is never looked at.
+        .flexible(true)
+        // Without a header row, columns map onto the fields of `Transaction` positionally,
+        // in the canonical order (see `ColumnOrder::normalize`).
+        .has_headers(has_headers);
+    builder
+}
+
+/// The field delimiter of CSV input.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Delimiter {
+    Byte(u8),
+    /// Detected from the first row (see `detect_delimiter`).
+    Detect,
+}
+
+// The delimiters `Delimiter::Detect` chooses from, in order of preference.
+const DELIMITERS: [u8; 4] = [b',', b'\t', b';', b'|'];
+
+/// Picks the delimiter which occurs most often in the first line of `input` out of commas,
+/// tabs, semicolons, and pipes, preferring them in that order on ties. Falls back to commas
+/// when there are none of them (i.e. for a single column).
+pub fn detect_delimiter(input: &[u8]) -> u8 {
+    let line = input.split(|&b| b == b'\n').next().unwrap_or_default();
+    let count = |delimiter: u8| line.iter().filter(|&&b| b == delimiter).count();
+    // `max_by_key` returns the last of several maximums, hence going through them backwards.
+    DELIMITERS
+        .iter()
+        .rev()
+        .copied()
+        .max_by_key(|&d| count(d))
+        .filter(|&d| count(d) > 0)
+        .unwrap_or(b',')
+}
+
+/// Wraps a reader, and fails with an error once more than `limit` bytes have been read from
+/// it. This bounds the amount of input consumed even when the size is not known upfront.
+pub struct LimitedReader<R> {
+    inner: R,
+    limit: u64,
+    // `None` once the limit has been exceeded, so that every subsequent read fails as well.
+    remaining: Option<u64>,
+}
+
+impl<R: Read> LimitedReader<R> {
+    pub fn new(inner: R, limit: u64) -> Self {
+        LimitedReader {
+            inner,
+            limit,
+            remaining: Some(limit),
+        }
+    }
+}
+
+impl<R: Read> Read for LimitedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.remaining.ok_or_else(|| too_large(self.limit))?;
+        let n = self.inner.read(buf)? as u64;
+
+        if n > remaining {
+            self.remaining = None;
+            return Err(too_large(self.limit));
+        }
+
+        self.remaining = Some(remaining - n);
+        Ok(n as usize)
+    }
+}
+
+/// Wraps a reader, and adds the number of bytes read from it to a shared counter (i.e. to
+/// report the progress of reading a file that gets consumed elsewhere).
+pub struct CountingReader<R> {
+    inner: R,
+    count: Arc<AtomicU64>,
+}
+
+impl<R: Read> CountingReader<R> {
+    pub fn new(inner: R, count: Arc<AtomicU64>) -> Self {
+        CountingReader { inner, count }
+    }
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+}
+
+// Magic numbers at the start of gzip and zstd streams.
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// Opens the input file at `path`, or stdin when there's no path (or it's `-`). Compressed
+/// input is decompressed transparently (see `open_reader`). When `max_bytes` is set, files
+/// which are already known to be larger are rejected before reading anything, and the
+/// number of (decompressed) bytes read is capped regardless (i.e. for pipes, files which are
+/// still growing, or compressed files).
+pub fn open(
+    path: Option<&str>,
+    max_bytes: Option<u64>,
+) -> io::Result<LimitedReader<Box<dyn Read>>> {
+    open_counted(path, max_bytes, None)
+}
+
+/// Same as `open`, but adds the number of bytes read from the file as it is (i.e. before
+/// decompressing it) to `count`, if given.
+pub fn open_counted(
+    path: Option<&str>,
+    max_bytes: Option<u64>,
+    count: Option<Arc<AtomicU64>>,
+) -> io::Result<LimitedReader<Box<dyn Read>>> {
+    let limit = max_bytes.unwrap_or(u64::MAX);
+
+    let input: Box<dyn Read> = match path {
+        // Locked once up front, instead of on every read.
+        None | Some("-") => Box::new(io::stdin().lock()),
+        Some(path) => {
+            let file = File::open(path)?;
+            if file.metadata()?.len() > limit {
+                return Err(too_large(limit));
+            }
+            Box::new(file)
+        }
+    };
+    let input = match count {
+        Some(count) => Box::new(CountingReader::new(input, count)),
+        None => input,
+    };
+
+    Ok(LimitedReader::new(open_reader(input)?, limit))
+}
+
+/// The order several input files are processed in (see `input_files`).
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum FileOrder {
+    /// Lexicographic order of the paths (i.e. `day1.csv`, `day2.csv`, ...).
+    #[default]
+    Name,
+    /// Oldest modification time first, and by path for files modified at the same time.
+    ModificationTime,
+}
+
+/// Expands input paths into the files to read, in `order`: directories stand for the files
+/// directly inside them (except for hidden ones), and paths with wildcards (i.e.
+/// `data/*.csv`) for the files matching them, while other paths have to be files. `-` stands
+/// for stdin, which can't be combined with other inputs.
+pub fn input_files<S: AsRef<str>>(paths: &[S], order: FileOrder) -> io::Result<Vec<String>> {
+    if let [path] = paths {
+        if path.as_ref() == "-" {
+            return Ok(vec!["-".to_owned()]);
+        }
+    }
+
+    let mut files = Vec::new();
+    for path in paths.iter().map(AsRef::as_ref) {
+        let with_path = |e: io::Error| io::Error::new(e.kind(), format!("{}: {}", path, e));
+        if path == "-" {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "stdin (`-`) can't be combined with other inputs",
+            ));
+        } else if path.contains(['*', '?', '[']) {
+            expand_glob(path, &mut files)?;
+        } else if fs::metadata(path).map_err(with_path)?.is_dir() {
+            for entry in fs::read_dir(path).map_err(with_path)? {
+                let entry = entry.map_err(with_path)?;
+                let hidden = entry.file_name().to_string_lossy().starts_with('.');
+                if !hidden && entry.file_type().map_err(with_path)?.is_file() {
+                    files.push(entry.path().to_string_lossy().into_owned());
+                }
+            }
+        } else {
+            files.push(path.to_owned());
+        }
+    }
+
+    match order {
+        FileOrder::Name => files.sort(),
+        FileOrder::ModificationTime => {
+            let mut timed = files
+                .into_iter()
+                .map(|file| Ok((fs::metadata(&file)?.modified()?, file)))
+                .collect::<io::Result<Vec<_>>>()?;
+            timed.sort();
+            files = timed.into_iter().map(|(_, file)| file).collect();
+        }
+    }
+    files.dedup();
+    Ok(files)
+}
+
+// Adds the files matching the wildcards in `path` to `files`.
+#[cfg(feature = "glob")]
+fn expand_glob(path: &str, files: &mut Vec<String>) -> io::Result<()> {
+    let matches = glob::glob(path).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    for entry in matches {
+        let entry = entry.map_err(|e| {
+            let e = io::Error::from(e);
+            io::Error::new(e.kind(), format!("{}: {}", path, e))
+        })?;
+        if entry.is_file() {
+            files.push(entry.to_string_lossy().into_owned());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "glob"))]
+fn expand_glob(path: &str, _files: &mut Vec<String>) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("{}: wildcards in input paths take the `glob` feature", path),
+    ))
+}
+
+/// Wraps `input` so that gzip (`.gz`) and zstd (`.zst`) compressed data gets decompressed on
+/// the fly, while anything else is passed through as is. The format is detected based on
+/// the magic bytes at the start, so this works for piped input (and regardless of the file
+/// extension) as well.
+pub fn open_reader<R: Read + 'static>(mut input: R) -> io::Result<Box<dyn Read>> {
+    // Reads from pipes may return fewer bytes than requested, even before the end.
+    let mut magic = [0; ZSTD_MAGIC.len()];
+    let mut len = 0;
+    while len < magic.len() {
+        match input.read(&mut magic[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    // Putting the bytes we've looked at back in front of the remaining input.
+    let start = &magic[..len];
+    let input = io::Cursor::new(magic).take(len as u64).chain(input);
+
+    Ok(if start.starts_with(&GZIP_MAGIC) {
+        gzip_decoder(input)?
+    } else if start.starts_with(&ZSTD_MAGIC) {
+        zstd_decoder(input)?
+    } else {
+        Box::new(input)
+    })
+}
+
+// Concatenated gzip members (i.e. from appending to an archive) are read in full.
+#[cfg(feature = "gzip")]
+fn gzip_decoder<R: Read + 'static>(input: R) -> io::Result<Box<dyn Read>> {
+    Ok(Box::new(flate2::read::MultiGzDecoder::new(input)))
+}
+
+#[cfg(not(feature = "gzip"))]
+fn gzip_decoder<R: Read + 'static>(_input: R) -> io::Result<Box<dyn Read>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "gzip compressed input takes the `gzip` feature",
+    ))
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn zstd_decoder<R: Read + 'static>(input: R) -> io::Result<Box<dyn Read>> {
+    Ok(Box::new(zstd::stream::read::Decoder::new(input)?))
+}
+
+// The zstd library is written in C, so it's left out of WebAssembly builds.
+#[cfg(target_arch = "wasm32")]
+fn zstd_decoder<R: Read + 'static>(_input: R) -> io::Result<Box<dyn Read>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "zstd compressed input is not supported in WebAssembly builds",
+    ))
+}
+
+fn too_large(limit: u64) -> io::Error {
+    io::Error::other(format!(
+        "The input exceeds the maximum size of {} bytes",
+        limit
+    ))
+}
+
+/// Maps the columns of an input onto the canonical order. Named-header input doesn't need any
+/// reordering (columns are matched by name there), but validating the header row still helps
+/// reject inputs that cannot possibly be processed upfront.
+#[derive(Clone)]
+pub struct ColumnOrder {
+    // The position of every canonical column within the input, if present.
+    positions: [Option<usize>; COLUMNS.len()],
+}
+
+impl ColumnOrder {
+    /// Checks that `columns` contain every required column, and no column more than once.
+    /// Unknown columns are ignored.
+    pub fn new<'a, I: IntoIterator<Item = &'a str>>(columns: I) -> Result<Self, String> {
+        let mut positions = [None; COLUMNS.len()];
+
+        for (position, column) in columns.into_iter().enumerate() {
+            if let Some(index) = COLUMNS.iter().position(|c| *c == column) {
+                if positions[index].replace(position).is_some() {
+                    return Err(format!("Duplicate `{}` column", column));
+                }
+            }
+        }
+
+        for (column, position) in COLUMNS.iter().zip(positions.iter()) {
+            if REQUIRED_COLUMNS.contains(column) && position.is_none() {
+                return Err(format!("Missing `{}` column", column));
+            }
+        }
+
+        Ok(ColumnOrder { positions })
+    }
+
+    /// The column order of header-less input without an explicit one: `type,client,tx,amount`.
+    /// The optional columns need a header row or an explicit order, so that stray trailing
+    /// fields are ignored instead of being taken for them.
+    pub fn headerless() -> Self {
+        let mut positions = [None; COLUMNS.len()];
+        for (index, position) in positions.iter_mut().take(HEADERLESS_COLUMNS).enumerate() {
+            *position = Some(index);
+        }
+        ColumnOrder { positions }
+    }
+
+    /// Returns a copy of `record` with the fields in the canonical order, so that it can be
+    /// mapped onto a `Transaction` positionally. Missing columns result in empty fields.
+    pub fn normalize(&self, record: &StringRecord) -> StringRecord {
+        let mut normalized: StringRecord = self
+            .positions
+            .iter()
+            .map(|p| p.and_then(|p| record.get(p)).unwrap_or(""))
+            .collect();
+        normalized.set_position(record.position().cloned());
+        normalized
+    }
+}
+
+/// A record of the input which could not be turned into a transaction, or a failure to read
+/// the input in the first place.
+#[derive(Debug)]
+pub enum RecordError {
+    Csv(csv::Error),
+    Json { line: u64, error: serde_json::Error },
+    // An undecodable message of binary input (see `protobuf`), by its position in the stream.
+    Protobuf { record: u64, reason: String },
+    Io(io::Error),
+}
+
+impl RecordError {
+    /// Whether reading the input failed, as opposed to an invalid record.
+    pub fn is_io_error(&self) -> bool {
+        match self {
+            RecordError::Csv(e) => e.is_io_error(),
+            RecordError::Json { .. } | RecordError::Protobuf { .. } => false,
+            RecordError::Io(_) => true,
+        }
+    }
+
+    /// The line of the input where the record starts, if known.
+    pub fn line(&self) -> Option<u64> {
+        match self {
+            RecordError::Csv(e) => e.position().map(|p| p.line()),
+            RecordError::Json { line, .. } => Some(*line),
+            RecordError::Protobuf { .. } | RecordError::Io(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for RecordError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RecordError::Csv(e) => write!(f, "{}", e),
+            RecordError::Json { line, error } => {
+                write!(f, "JSON deserialize error: line {}: {}", line, error)
+            }
+            RecordError::Protobuf { record, reason } => {
+                write!(f, "protobuf decode error: record {}: {}", record, reason)
+            }
+            RecordError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<csv::Error> for RecordError {
+    fn from(e: csv::Error) -> Self {
+        RecordError::Csv(e)
+    }
+}
+
+/// A record of the input, as the line it starts on, and the transaction it contains (unless
+/// the record is invalid).
+pub type Record = (u64, Result<Transaction, RecordError>);
+
+/// Deserializes transactions from newline-delimited JSON objects (JSON Lines), with the same
+/// fields as the CSV columns. Amounts can be either strings or numbers. Blank lines are
+/// skipped.
+pub fn json_lines<R: Read>(input: R) -> impl Iterator<Item = Record> {
+    json_lines_with_offsets(input).map(|(record, _)| record)
+}
+
+/// Same as `json_lines`, but also yields the byte offset (relative to the start of `input`)
+/// the next line starts at along with every record, i.e. to resume reading from there later.
+pub fn json_lines_with_offsets<R: Read>(input: R) -> impl Iterator<Item = (Record, u64)> {
+    let mut input = BufReader::new(input);
+    let mut line = String::new();
+    let mut number = 0;
+    let mut offset = 0;
+    iter::from_fn(move || loop {
+        line.clear();
+        number += 1;
+        let result = match input.read_line(&mut line) {
+            Ok(0) => return None,
+            Ok(n) => {
+                offset += n as u64;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                parse_json_transaction(&line).map_err(|error| RecordError::Json {
+                    line: number,
+                    error,
+                })
+            }
+            Err(e) => Err(RecordError::Io(e)),
+        };
+        return Some(((number, result), offset));
+    })
+}
+
+// Amounts are parsed from their textual representation, so numeric JSON amounts (i.e.
+// `10.50` instead of `"10.50"`) are turned into strings first. This also preserves the
+// number of decimal places, same as for CSV input.
+pub(crate) fn parse_json_transaction(line: &str) -> serde_json::Result<Transaction> {
+    let mut value: serde_json::Value = serde_json::from_str(line)?;
+
+    if let Some(amount) = value.get_mut("amount") {
+        if amount.is_number() {
+            *amount = serde_json::Value::String(amount.to_string());
+        }
+    }
+
+    serde_json::from_value(value)
+}
@@ -0,0 +1,158 @@
+//! Consistency checks of the accounts affected by a transaction, which the engine runs right
+//! after processing it when enabled via `TransactionEngine::with_invariant_checks` (or by
+//! default, with the `invariants` feature). Unlike `TransactionEngine::is_valid`, these only
+//! look at the affected accounts, so they're cheap enough to run for every transaction, and
+//! catch engine bugs close to the transaction that caused them.
+
+use std::fmt;
+
+use rust_decimal::Decimal;
+
+use crate::account::Account;
+use crate::transaction::{ClientId, Currency};
+
+/// A broken invariant.
+#[derive(Debug)]
+pub(crate) enum Violation {
+    // The total funds aren't the sum of the available and held funds.
+    TotalMismatch {
+        client: ClientId,
+        currency: Currency,
+    },
+    // Held funds only ever contain the amounts of disputed transactions.
+    NegativeHeld {
+        client: ClientId,
+        currency: Currency,
+        held: Decimal,
+    },
+    // Only chargebacks (and overdraft lines) can take the total below zero.
+    NegativeTotal {
+        client: ClientId,
+        currency: Currency,
+        total: Decimal,
+    },
+    // Only disputes can overdraw an account (besides overdraft lines), and only if the
+    // overdraft policy allows it.
+    NegativeAvailable {
+        client: ClientId,
+        currency: Currency,
+        available: Decimal,
+    },
+    // A transaction went through for a frozen account, although the frozen policy blocks it.
+    FrozenAccountChanged {
+        client: ClientId,
+    },
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Violation::TotalMismatch { client, currency } => write!(
+                f,
+                "total funds of client {} in {:?} are not the sum of available and held",
+                client, currency
+            ),
+            Violation::NegativeHeld {
+                client,
+                currency,
+                held,
+            } => write!(
+                f,
+                "held funds of client {} in {:?} are negative ({})",
+                client, currency, held
+            ),
+            Violation::NegativeTotal {
+                client,
+                currency,
+                total,
+            } => write!(
+                f,
+                "total funds of client {} in {:?} are negative ({}) without a chargeback",
+                client, currency, total
+            ),
+            Violation::NegativeAvailable {
+                client,
+                currency,
+                available,
+            } => write!(
+                f,
+                "available funds of client {} in {:?} are negative ({}) without an overdraft",
+                client, currency, available
+            ),
+            Violation::FrozenAccountChanged { client } => write!(
+                f,
+                "the frozen account of client {} was changed against the frozen policy",
+                client
+            ),
+        }
+    }
+}
+
+/// What the checks need to know about an affected account from before the transaction.
+pub(crate) struct Before {
+    pub client: ClientId,
+    pub frozen: bool,
+}
+
+/// The rules that decide which states are legitimate.
+pub(crate) struct Rules {
+    // Whether disputes can take the available funds below zero.
+    pub overdraft_allowed: bool,
+    // Whether the transaction may go through for frozen accounts.
+    pub allowed_when_frozen: bool,
+}
+
+/// Checks the state of `account` after a transaction, which was applied if `applied` is set.
+pub(crate) fn check(
+    before: &Before,
+    account: &Account,
+    applied: bool,
+    rules: &Rules,
+) -> Result<(), Violation> {
+    let client = before.client;
+
+    if applied && before.frozen && !rules.allowed_when_frozen {
+        return Err(Violation::FrozenAccountChanged { client });
+    }
+
+    // Frozen accounts are exempt from the total, same as for `Account::is_valid`, since older
+    // checkpoints don't record chargebacks otherwise. So are overdrawn accounts, whose limits
+    // aren't known here.
+    let negative_total_allowed = account.charged_back() || account.frozen() || account.overdrawn();
+
+    for (currency, balance) in account.balances() {
+        let currency = || currency.to_owned();
+        let (available, held, total) = (balance.available(), balance.held(), balance.total());
+
+        // `total` is tracked separately, and has to move along with the other two.
+        if !balance.is_consistent() {
+            return Err(Violation::TotalMismatch {
+                client,
+                currency: currency(),
+            });
+        }
+        if held < Decimal::ZERO {
+            return Err(Violation::NegativeHeld {
+                client,
+                currency: currency(),
+                held,
+            });
+        }
+        if total < Decimal::ZERO && !negative_total_allowed {
+            return Err(Violation::NegativeTotal {
+                client,
+                currency: currency(),
+                total,
+            });
+        }
+        if available < Decimal::ZERO && !rules.overdraft_allowed && !negative_total_allowed {
+            return Err(Violation::NegativeAvailable {
+                client,
+                currency: currency(),
+                available,
+            });
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,152 @@
+//! Kafka input source, which turns the engine into a streaming settlement service: transaction
+//! events are consumed from a topic (as part of a consumer group), and processed as they
+//! arrive. Offsets are only committed once the transactions of a message have been processed,
+//! so a crash leads to redelivery rather than lost transactions (which
+//! `TransactionEngine::with_replay_dedup` can make idempotent).
+
+use std::error;
+use std::fmt;
+use std::io;
+
+use kafka::client::{FetchOffset, GroupOffsetStorage};
+use kafka::consumer::Consumer;
+use tracing::{info_span, warn};
+
+use crate::input::{self, reader_builder, RecordError};
+use crate::transaction::{Transaction, TransactionEngine};
+
+/// How the transactions are encoded in the messages of the topic.
+#[derive(Clone, Copy, Debug)]
+pub enum Payload {
+    /// JSON objects with the same fields as the JSON Lines input, one per line.
+    Json,
+    /// Header-less CSV rows, with the columns in the canonical `type,client,tx,amount,...`
+    /// order.
+    Csv,
+}
+
+/// Errors which stop consuming.
+#[derive(Debug)]
+pub enum SourceError {
+    Kafka(kafka::Error),
+    Io(io::Error),
+}
+
+impl fmt::Display for SourceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SourceError::Kafka(e) => write!(f, "Kafka error: {}", e),
+            SourceError::Io(e) => e.fmt(f),
+        }
+    }
+}
+
+impl error::Error for SourceError {}
+
+impl From<kafka::Error> for SourceError {
+    fn from(e: kafka::Error) -> Self {
+        SourceError::Kafka(e)
+    }
+}
+
+impl From<io::Error> for SourceError {
+    fn from(e: io::Error) -> Self {
+        SourceError::Io(e)
+    }
+}
+
+/// Consumes the transactions of a single topic.
+pub struct KafkaSource {
+    consumer: Consumer,
+    payload: Payload,
+}
+
+impl KafkaSource {
+    /// Connects to `brokers` (`host:port` pairs) as a member of the consumer `group`, which
+    /// picks up from the offsets committed for the group (or from the earliest message of
+    /// `topic`, for new groups).
+    pub fn connect(
+        brokers: Vec<String>,
+        topic: &str,
+        group: &str,
+        payload: Payload,
+    ) -> Result<Self, SourceError> {
+        let consumer = Consumer::from_hosts(brokers)
+            .with_topic(topic.to_owned())
+            .with_group(group.to_owned())
+            .with_fallback_offset(FetchOffset::Earliest)
+            .with_offset_storage(Some(GroupOffsetStorage::Kafka))
+            .create()?;
+        Ok(KafkaSource { consumer, payload })
+    }
+
+    /// Processes messages until consuming fails, which is the only way this returns.
+    /// Messages which don't describe transactions are logged and skipped, same as invalid
+    /// input records, and so are rejected transactions. After every poll of the topic (even
+    /// one without any messages), `after_poll` gets called with the engine (i.e. to flush a
+    /// snapshot of the state every so often), and the offsets are committed once it returns.
+    pub fn run<F>(&mut self, engine: &mut TransactionEngine, mut after_poll: F) -> SourceError
+    where
+        F: FnMut(&mut TransactionEngine) -> io::Result<()>,
+    {
+        loop {
+            if let Err(e) = self.poll(engine, &mut after_poll) {
+                return e;
+            }
+        }
+    }
+
+    fn poll<F>(
+        &mut self,
+        engine: &mut TransactionEngine,
+        after_poll: &mut F,
+    ) -> Result<(), SourceError>
+    where
+        F: FnMut(&mut TransactionEngine) -> io::Result<()>,
+    {
+        let message_sets = self.consumer.poll()?;
+
+        for set in message_sets.iter() {
+            for message in set.messages() {
+                let offset = message.offset;
+                let _span = info_span!(
+                    "message",
+                    topic = set.topic(),
+                    partition = set.partition(),
+                    offset
+                )
+                .entered();
+
+                for result in parse(self.payload, message.value) {
+                    match result {
+                        Ok(t) => {
+                            // Rejections are logged by the engine.
+                            let _ = engine.process_transaction(t);
+                            for warning in engine.drain_warnings() {
+                                warn!("{}", warning);
+                            }
+                        }
+                        Err(e) => warn!("Skipping invalid message: {}", e),
+                    }
+                }
+            }
+            self.consumer.consume_messageset(set)?;
+        }
+
+        after_poll(engine)?;
+        self.consumer.commit_consumed()?;
+        Ok(())
+    }
+}
+
+// Parses the transactions of a single message.
+fn parse(payload: Payload, value: &[u8]) -> Vec<Result<Transaction, RecordError>> {
+    match payload {
+        Payload::Json => input::json_lines(value).map(|(_, result)| result).collect(),
+        Payload::Csv => reader_builder(false)
+            .from_reader(value)
+            .into_deserialize()
+            .map(|result| result.map_err(RecordError::Csv))
+            .collect(),
+    }
+}
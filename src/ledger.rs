@@ -0,0 +1,974 @@
+use std::io;
+
+use crate::account::Account;
+use crate::transaction::{ClientId, IdMap, Transaction, TransactionId};
+
+/// Storage for the state of a `TransactionEngine` that may outgrow memory, or has to survive
+/// restarts. The history of past transactions is kept entirely in the ledger, while client
+/// accounts (of which there are comparatively few) are kept in memory by the engine, and only
+/// written to the ledger whenever they change.
+///
+/// Transactions are handed out as copies, so implementations are free to store them in
+/// serialized form. Storage failures (including corrupted entries) are returned as errors,
+/// which the engine reports via `Error::StorageFailed` (see
+/// `TransactionEngine::storage_failure`).
+pub trait Ledger {
+    /// Returns a copy of transaction `tx`, if it's part of the history.
+    fn transaction(&self, tx: TransactionId) -> io::Result<Option<Transaction>>;
+
+    fn contains_transaction(&self, tx: TransactionId) -> io::Result<bool> {
+        Ok(self.transaction(tx)?.is_some())
+    }
+
+    /// Adds `transaction` to the history, or replaces the entry with the same id (i.e. after
+    /// the transaction has been disputed).
+    fn insert_transaction(&mut self, transaction: Transaction) -> io::Result<()>;
+
+    fn remove_transaction(&mut self, tx: TransactionId) -> io::Result<()>;
+
+    /// Iterates over all transactions in the history, in no particular order.
+    fn transactions(&self) -> Box<dyn Iterator<Item = io::Result<Transaction>> + '_>;
+
+    /// Records the current state of the account of `client`. Only needed for ledgers which
+    /// persist the engine state, so this does nothing by default.
+    fn store_account(&mut self, _client: ClientId, _account: &Account) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Returns the accounts previously written via `store_account`.
+    fn accounts(&self) -> io::Result<Vec<(ClientId, Account)>> {
+        Ok(Vec::new())
+    }
+
+    /// Discards all transactions and accounts.
+    fn clear(&mut self) -> io::Result<()>;
+
+    /// Makes room for at least `additional` more transactions, as far as the ledger can tell
+    /// ahead of time. Does nothing by default.
+    fn reserve(&mut self, _additional: usize) {}
+}
+
+/// Keeps the transaction history in memory, which is what the engine uses by default.
+#[derive(Default)]
+pub struct MemoryLedger {
+    transactions: IdMap<TransactionId, Transaction>,
+}
+
+impl Ledger for MemoryLedger {
+    fn transaction(&self, tx: TransactionId) -> io::Result<Option<Transaction>> {
+        Ok(self.transactions.get(&tx).cloned())
+    }
+
+    fn contains_transaction(&self, tx: TransactionId) -> io::Result<bool> {
+        Ok(self.transactions.contains_key(&tx))
+    }
+
+    fn insert_transaction(&mut self, transaction: Transaction) -> io::Result<()> {
+        self.transactions.insert(transaction.tx(), transaction);
+        Ok(())
+    }
+
+    fn remove_transaction(&mut self, tx: TransactionId) -> io::Result<()> {
+        self.transactions.remove(&tx);
+        Ok(())
+    }
+
+    fn transactions(&self) -> Box<dyn Iterator<Item = io::Result<Transaction>> + '_> {
+        Box::new(self.transactions.values().cloned().map(Ok))
+    }
+
+    // Retains the allocated capacity, so the engine can be reused for another batch.
+    fn clear(&mut self) -> io::Result<()> {
+        self.transactions.clear();
+        Ok(())
+    }
+
+    fn reserve(&mut self, additional: usize) {
+        self.transactions.reserve(additional);
+    }
+}
+
+impl Extend<Transaction> for MemoryLedger {
+    fn extend<I: IntoIterator<Item = Transaction>>(&mut self, transactions: I) {
+        for transaction in transactions {
+            self.transactions.insert(transaction.tx(), transaction);
+        }
+    }
+}
+
+#[cfg(feature = "spill")]
+pub use self::spill_ledger::SpillLedger;
+
+#[cfg(feature = "sled")]
+pub use self::sled_ledger::SledLedger;
+
+#[cfg(feature = "redis")]
+pub use self::redis_ledger::RedisLedger;
+
+// The transactions of the ledgers which store them in serialized form.
+#[cfg(any(feature = "spill", feature = "sled", feature = "redis"))]
+mod stored {
+    use std::io;
+
+    use rust_decimal::Decimal;
+    use serde::{Deserialize, Serialize};
+
+    use crate::transaction::Transaction;
+
+    // The bookkeeping fields of a transaction are not serialized as part of it (since the
+    // struct doubles as the input record type), so they're stored alongside.
+    #[derive(Deserialize, Serialize)]
+    struct StoredTransaction<T> {
+        transaction: T,
+        disputed: bool,
+        // Missing for databases written before partial disputes, in which case disputed
+        // transactions are disputed for their whole amount.
+        #[serde(default)]
+        held: Option<Decimal>,
+        #[serde(default)]
+        settled: Decimal,
+        #[serde(default)]
+        disputes: u32,
+        seq: u64,
+    }
+
+    pub(super) fn encode(transaction: &Transaction) -> io::Result<Vec<u8>> {
+        let stored = StoredTransaction {
+            disputed: transaction.disputed,
+            held: Some(transaction.held),
+            settled: transaction.settled,
+            disputes: transaction.disputes,
+            seq: transaction.seq,
+            transaction,
+        };
+        Ok(serde_json::to_vec(&stored)?)
+    }
+
+    // Corrupted entries are reported as `io::ErrorKind::InvalidData`.
+    pub(super) fn decode(value: &[u8]) -> io::Result<Transaction> {
+        let stored: StoredTransaction<Transaction> = serde_json::from_slice(value)?;
+        let mut transaction = stored.transaction;
+        transaction.disputed = stored.disputed;
+        transaction.held = match stored.held {
+            Some(held) => held,
+            None if stored.disputed => transaction.amount(),
+            None => Decimal::ZERO,
+        };
+        transaction.settled = stored.settled;
+        transaction.disputes = stored.disputes;
+        transaction.seq = stored.seq;
+        Ok(transaction)
+    }
+}
+
+#[cfg(feature = "spill")]
+mod spill_ledger {
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::convert::TryFrom;
+    use std::fs::{self, File, OpenOptions};
+    use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+    use std::path::{Path, PathBuf};
+
+    use memmap2::Mmap;
+
+    use super::stored::{decode, encode};
+    use super::Ledger;
+    use crate::transaction::{IdMap, Transaction, TransactionId};
+
+    // The number of files the spilled transactions are spread across (by transaction id), so
+    // compacting one only rewrites a fraction of them.
+    const SHARDS: u64 = 16;
+
+    // Every spilled transaction is preceded by its id and the length of its encoding, so
+    // compaction can tell which entries of a shard are still live.
+    const HEADER_LEN: u64 = 12;
+
+    // Shards below this size are never compacted, since it wouldn't save much.
+    const MIN_COMPACTION_LEN: u64 = 1 << 20;
+
+    /// Keeps the most recently added transactions in memory, and spills the other ones to
+    /// files on disk (spread across shards by transaction id), for histories that would
+    /// outgrow memory otherwise. Only an index of where every spilled transaction is (about
+    /// 40 bytes per transaction) stays in memory. The shards are memory-mapped for reading, so
+    /// looking one up doesn't take a system call, and the OS keeps the entries read most often
+    /// cached. Transactions that get disputed (or otherwise updated) move back into memory,
+    /// and shards in which most entries have been superseded or removed get compacted.
+    ///
+    /// Unlike `SledLedger`, the files are scratch space that doesn't survive restarts: they
+    /// get truncated when the ledger is created, and removed once it's dropped. They must not
+    /// be modified by anything else in the meantime, since they're mapped into memory.
+    pub struct SpillLedger {
+        dir: PathBuf,
+        // At most `hot_capacity` transactions, along with the order they were added in (which
+        // may list transactions that have been removed or spilled since).
+        hot: IdMap<TransactionId, Transaction>,
+        hot_order: VecDeque<TransactionId>,
+        hot_capacity: usize,
+        cold: IdMap<TransactionId, Location>,
+        // Reading may need to map the entries appended since the last read, while lookups
+        // only borrow the ledger.
+        shards: Vec<RefCell<Shard>>,
+    }
+
+    // Where a spilled transaction is: the shard, and the offset and length of its entry.
+    #[derive(Clone, Copy, PartialEq)]
+    struct Location {
+        shard: usize,
+        offset: u64,
+        len: u32,
+    }
+
+    struct Shard {
+        file: File,
+        // The length of the file, and how much of it is taken by entries which aren't live
+        // anymore.
+        len: u64,
+        garbage: u64,
+        // The file as of the last time it was mapped, which gets mapped again whenever an
+        // entry beyond the end of the mapping is read. Unmapped before the file gets
+        // truncated or replaced.
+        map: Option<Mmap>,
+    }
+
+    impl Shard {
+        // The entry at `offset` (without its header) of `len` bytes.
+        fn entry(&mut self, offset: u64, len: u32) -> io::Result<&[u8]> {
+            let start = offset + HEADER_LEN;
+            let end = start + u64::from(len);
+            if end > self.len {
+                let message = "Entry beyond the end of the spilled transactions";
+                return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+            }
+            if self.map.as_ref().is_none_or(|map| (map.len() as u64) < end) {
+                // Safety: the file is private to the ledger (see `SpillLedger`), and only
+                // truncated or replaced once the mapping is gone.
+                self.map = Some(unsafe { Mmap::map(&self.file)? });
+            }
+            let map = self.map.as_ref().unwrap();
+            Ok(&map[start as usize..end as usize])
+        }
+    }
+
+    impl SpillLedger {
+        /// Creates the ledger with its shards in `dir` (which gets created if it doesn't exist
+        /// yet), keeping up to `hot_capacity` transactions in memory.
+        pub fn open<P: AsRef<Path>>(dir: P, hot_capacity: usize) -> io::Result<Self> {
+            let dir = dir.as_ref().to_owned();
+            fs::create_dir_all(&dir)?;
+            let shards = (0..SHARDS)
+                .map(|shard| {
+                    let file = OpenOptions::new()
+                        .read(true)
+                        .write(true)
+                        .create(true)
+                        .truncate(true)
+                        .open(shard_path(&dir, shard as usize))?;
+                    Ok(RefCell::new(Shard {
+                        file,
+                        len: 0,
+                        garbage: 0,
+                        map: None,
+                    }))
+                })
+                .collect::<io::Result<_>>()?;
+            Ok(SpillLedger {
+                dir,
+                hot: IdMap::default(),
+                hot_order: VecDeque::new(),
+                hot_capacity,
+                cold: IdMap::default(),
+                shards,
+            })
+        }
+
+        /// The number of transactions which have been spilled to disk.
+        pub fn spilled(&self) -> usize {
+            self.cold.len()
+        }
+
+        // Moves the transactions added the longest time ago to disk, until the ones in
+        // memory are within the capacity again. A transaction which can't be spilled stays in
+        // memory.
+        fn spill(&mut self) -> io::Result<()> {
+            while self.hot.len() > self.hot_capacity {
+                let tx = match self.hot_order.front() {
+                    Some(&tx) => tx,
+                    None => break,
+                };
+                // Ids of transactions which are gone from memory already are skipped.
+                if let Some(transaction) = self.hot.remove(&tx) {
+                    let location = encode(&transaction).and_then(|value| self.append(tx, &value));
+                    match location {
+                        Ok(location) => {
+                            self.cold.insert(tx, location);
+                        }
+                        Err(e) => {
+                            self.hot.insert(tx, transaction);
+                            return Err(e);
+                        }
+                    }
+                }
+                self.hot_order.pop_front();
+            }
+            // Stale ids would pile up otherwise, when transactions keep getting replaced.
+            if self.hot_order.len() > 2 * self.hot_capacity.max(1) {
+                let hot = &self.hot;
+                let mut seen = IdMap::default();
+                self.hot_order
+                    .retain(|tx| hot.contains_key(tx) && seen.insert(*tx, ()).is_none());
+            }
+            Ok(())
+        }
+
+        fn append(&mut self, tx: TransactionId, value: &[u8]) -> io::Result<Location> {
+            let shard_index = (tx.get() % SHARDS) as usize;
+            let len = u32::try_from(value.len()).map_err(|_| {
+                let message = format!("Transaction {} is too large to spill", tx);
+                io::Error::new(io::ErrorKind::InvalidInput, message)
+            })?;
+            let mut shard = self.shards[shard_index].borrow_mut();
+            let offset = shard.len;
+            shard.file.seek(SeekFrom::Start(offset))?;
+            let mut w = BufWriter::new(&shard.file);
+            w.write_all(&tx.get().to_be_bytes())?;
+            w.write_all(&len.to_be_bytes())?;
+            w.write_all(value)?;
+            w.flush()?;
+            drop(w);
+            shard.len += HEADER_LEN + u64::from(len);
+            Ok(Location {
+                shard: shard_index,
+                offset,
+                len,
+            })
+        }
+
+        fn read(&self, location: Location) -> io::Result<Transaction> {
+            let mut shard = self.shards[location.shard].borrow_mut();
+            decode(shard.entry(location.offset, location.len)?)
+        }
+
+        // Marks the entry at `location` as superseded, and compacts its shard once most of it
+        // is.
+        fn discard(&mut self, location: Location) -> io::Result<()> {
+            let compact = {
+                let mut shard = self.shards[location.shard].borrow_mut();
+                shard.garbage += HEADER_LEN + u64::from(location.len);
+                shard.len >= MIN_COMPACTION_LEN && 2 * shard.garbage > shard.len
+            };
+            if compact {
+                self.compact(location.shard)?;
+            }
+            Ok(())
+        }
+
+        // Rewrites the shard with only its live entries (the ones the index still points at),
+        // and moves the index along.
+        fn compact(&mut self, shard_index: usize) -> io::Result<()> {
+            let path = shard_path(&self.dir, shard_index);
+            let compacted_path = path.with_extension("compacting");
+            let mut compacted = BufWriter::new(File::create(&compacted_path)?);
+            let mut live: Vec<_> = self
+                .cold
+                .iter()
+                .filter(|(_, location)| location.shard == shard_index)
+                .map(|(&tx, &location)| (location.offset, tx, location.len))
+                .collect();
+            live.sort_unstable();
+            let mut moved = Vec::with_capacity(live.len());
+            let mut len = 0;
+
+            {
+                let mut shard = self.shards[shard_index].borrow_mut();
+                for (offset, tx, entry_len) in live {
+                    compacted.write_all(&tx.get().to_be_bytes())?;
+                    compacted.write_all(&entry_len.to_be_bytes())?;
+                    compacted.write_all(shard.entry(offset, entry_len)?)?;
+                    moved.push((tx, len));
+                    len += HEADER_LEN + u64::from(entry_len);
+                }
+                shard.map = None;
+            }
+            compacted.flush()?;
+            drop(compacted);
+
+            fs::rename(&compacted_path, &path)?;
+            let file = OpenOptions::new().read(true).write(true).open(&path)?;
+            *self.shards[shard_index].borrow_mut() = Shard {
+                file,
+                len,
+                garbage: 0,
+                map: None,
+            };
+            for (tx, offset) in moved {
+                if let Some(location) = self.cold.get_mut(&tx) {
+                    location.offset = offset;
+                }
+            }
+            Ok(())
+        }
+    }
+
+    fn shard_path(dir: &Path, shard: usize) -> PathBuf {
+        dir.join(format!("transactions-{:02}.spill", shard))
+    }
+
+    impl Ledger for SpillLedger {
+        fn transaction(&self, tx: TransactionId) -> io::Result<Option<Transaction>> {
+            match self.hot.get(&tx) {
+                Some(transaction) => Ok(Some(transaction.clone())),
+                None => self
+                    .cold
+                    .get(&tx)
+                    .map(|&location| self.read(location))
+                    .transpose(),
+            }
+        }
+
+        fn contains_transaction(&self, tx: TransactionId) -> io::Result<bool> {
+            Ok(self.hot.contains_key(&tx) || self.cold.contains_key(&tx))
+        }
+
+        fn insert_transaction(&mut self, transaction: Transaction) -> io::Result<()> {
+            let tx = transaction.tx();
+            if self.hot.insert(tx, transaction).is_none() {
+                self.hot_order.push_back(tx);
+            }
+            // Only superseded once the new version is in place.
+            if let Some(location) = self.cold.remove(&tx) {
+                self.discard(location)?;
+            }
+            self.spill()
+        }
+
+        fn remove_transaction(&mut self, tx: TransactionId) -> io::Result<()> {
+            if self.hot.remove(&tx).is_none() {
+                if let Some(location) = self.cold.remove(&tx) {
+                    self.discard(location)?;
+                }
+            }
+            Ok(())
+        }
+
+        fn transactions(&self) -> Box<dyn Iterator<Item = io::Result<Transaction>> + '_> {
+            let cold = self.cold.values().map(move |&location| self.read(location));
+            Box::new(self.hot.values().cloned().map(Ok).chain(cold))
+        }
+
+        fn clear(&mut self) -> io::Result<()> {
+            self.hot.clear();
+            self.hot_order.clear();
+            self.cold.clear();
+            for shard in &self.shards {
+                let mut shard = shard.borrow_mut();
+                shard.map = None;
+                shard.file.set_len(0)?;
+                shard.len = 0;
+                shard.garbage = 0;
+            }
+            Ok(())
+        }
+
+        fn reserve(&mut self, additional: usize) {
+            self.hot.reserve(additional.min(self.hot_capacity));
+            self.cold
+                .reserve(additional.saturating_sub(self.hot_capacity));
+        }
+    }
+
+    impl Drop for SpillLedger {
+        fn drop(&mut self) {
+            for shard in &self.shards {
+                shard.borrow_mut().map = None;
+            }
+            for shard in 0..self.shards.len() {
+                let _ = fs::remove_file(shard_path(&self.dir, shard));
+            }
+        }
+    }
+}
+
+#[cfg(feature = "sled")]
+mod sled_ledger {
+    use std::convert::TryFrom;
+    use std::io;
+    use std::path::Path;
+
+    use super::stored::{decode, encode};
+    use super::Ledger;
+    use crate::account::Account;
+    use crate::transaction::{ClientId, Transaction, TransactionId};
+
+    /// Keeps the engine state in a `sled` database on disk, so it survives restarts, and the
+    /// transaction history is no longer bound by the available memory. Every change is
+    /// written through to the database right away.
+    pub struct SledLedger {
+        transactions: sled::Tree,
+        accounts: sled::Tree,
+    }
+
+    impl SledLedger {
+        /// Opens the database at `path` (which gets created if it doesn't exist yet).
+        pub fn open<P: AsRef<Path>>(path: P) -> sled::Result<Self> {
+            let db = sled::open(path)?;
+            Ok(SledLedger {
+                transactions: db.open_tree("transactions")?,
+                accounts: db.open_tree("accounts")?,
+            })
+        }
+
+        /// Blocks until all changes have been written to disk.
+        pub fn flush(&self) -> sled::Result<()> {
+            self.transactions.flush()?;
+            self.accounts.flush()?;
+            Ok(())
+        }
+    }
+
+    // Keys are encoded as big-endian bytes, so the database keeps entries sorted by id.
+    impl Ledger for SledLedger {
+        fn transaction(&self, tx: TransactionId) -> io::Result<Option<Transaction>> {
+            self.transactions
+                .get(tx.get().to_be_bytes())?
+                .map(|value| decode(&value))
+                .transpose()
+        }
+
+        fn contains_transaction(&self, tx: TransactionId) -> io::Result<bool> {
+            Ok(self.transactions.contains_key(tx.get().to_be_bytes())?)
+        }
+
+        fn insert_transaction(&mut self, transaction: Transaction) -> io::Result<()> {
+            let key = transaction.tx().get().to_be_bytes();
+            self.transactions.insert(key, encode(&transaction)?)?;
+            Ok(())
+        }
+
+        fn remove_transaction(&mut self, tx: TransactionId) -> io::Result<()> {
+            self.transactions.remove(tx.get().to_be_bytes())?;
+            Ok(())
+        }
+
+        fn transactions(&self) -> Box<dyn Iterator<Item = io::Result<Transaction>> + '_> {
+            Box::new(
+                self.transactions
+                    .iter()
+                    .values()
+                    .map(|value| decode(&value?)),
+            )
+        }
+
+        fn store_account(&mut self, client: ClientId, account: &Account) -> io::Result<()> {
+            let value = serde_json::to_vec(account)?;
+            self.accounts.insert(client.get().to_be_bytes(), value)?;
+            Ok(())
+        }
+
+        fn accounts(&self) -> io::Result<Vec<(ClientId, Account)>> {
+            self.accounts
+                .iter()
+                .map(|entry| {
+                    let (key, value) = entry?;
+                    let client = <[u8; 4]>::try_from(&key[..]).map_err(|_| {
+                        io::Error::new(io::ErrorKind::InvalidData, "Corrupted account key")
+                    })?;
+                    let account = serde_json::from_slice(&value)?;
+                    Ok((ClientId::new(u32::from_be_bytes(client)), account))
+                })
+                .collect()
+        }
+
+        fn clear(&mut self) -> io::Result<()> {
+            self.transactions.clear()?;
+            self.accounts.clear()?;
+            Ok(())
+        }
+    }
+}
+
+#[cfg(feature = "redis")]
+mod redis_ledger {
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::convert::TryFrom;
+    use std::io::{self, BufRead, BufReader, Write};
+    use std::net::{TcpStream, ToSocketAddrs};
+
+    use super::stored::{decode, encode};
+    use super::Ledger;
+    use crate::account::Account;
+    use crate::transaction::{ClientId, Transaction, TransactionId};
+
+    // Upper bound for the number of keys fetched by a single `MGET`.
+    const FETCH_BATCH: usize = 512;
+
+    /// Keeps the engine state in Redis, so several engine instances (i.e. behind a load
+    /// balancer) can process a stream partitioned by client against shared state: the
+    /// transaction history (which duplicate checks and disputes consult, across every
+    /// instance), an index of the transactions under dispute, and the client accounts, under
+    /// keys starting with a prefix of its own. Every change is written through to Redis right
+    /// away.
+    ///
+    /// Accounts are written with optimistic locking: each one carries a version, and writing
+    /// it only goes through if the version is still the one this instance read or wrote last
+    /// (checked atomically via `WATCH`). An account changed by another instance in the
+    /// meantime means the stream isn't partitioned by client after all, which the engine
+    /// can't recover from, so this is reported as a storage failure (same as a lost
+    /// connection). The accounts of other partitions are only read when the engine starts, so
+    /// its reports show them as of then.
+    pub struct RedisLedger {
+        connection: RefCell<Connection>,
+        prefix: String,
+        // The version of every account as last read or written by this instance.
+        versions: RefCell<HashMap<ClientId, u64>>,
+    }
+
+    impl RedisLedger {
+        /// Connects to the Redis server at `address` (i.e. `127.0.0.1:6379`), with the state
+        /// under keys starting with `prefix` (i.e. `te`), so several deployments can share a
+        /// server.
+        pub fn connect<A: ToSocketAddrs>(address: A, prefix: &str) -> io::Result<Self> {
+            let stream = TcpStream::connect(address)?;
+            stream.set_nodelay(true)?;
+            let mut connection = Connection {
+                reader: BufReader::new(stream.try_clone()?),
+                writer: stream,
+            };
+            connection.command(&[b"PING"])?;
+            Ok(RedisLedger {
+                connection: RefCell::new(connection),
+                prefix: prefix.to_owned(),
+                versions: RefCell::new(HashMap::new()),
+            })
+        }
+
+        /// The transactions currently under dispute, as recorded by every instance, ordered
+        /// by transaction id.
+        pub fn disputed(&self) -> io::Result<Vec<TransactionId>> {
+            let key = self.key("disputed");
+            let mut disputed = self
+                .run(&[b"SMEMBERS", key.as_bytes()])?
+                .ids()?
+                .into_iter()
+                .map(TransactionId::new)
+                .collect::<Vec<_>>();
+            disputed.sort_unstable();
+            Ok(disputed)
+        }
+
+        fn key(&self, name: &str) -> String {
+            format!("{}:{}", self.prefix, name)
+        }
+
+        fn transaction_key(&self, tx: TransactionId) -> String {
+            format!("{}:tx:{}", self.prefix, tx)
+        }
+
+        fn account_key(&self, client: ClientId) -> String {
+            format!("{}:account:{}", self.prefix, client)
+        }
+
+        fn run(&self, args: &[&[u8]]) -> io::Result<Reply> {
+            self.connection.borrow_mut().command(args)
+        }
+
+        // Runs `commands` as a transaction (between `MULTI` and `EXEC`), which doesn't go
+        // through if a watched key has changed, in which case this returns `None`.
+        fn atomically(&self, commands: &[Vec<&[u8]>]) -> io::Result<Option<Vec<Reply>>> {
+            self.run(&[b"MULTI"])?;
+            for command in commands {
+                self.run(command)?;
+            }
+            match self.run(&[b"EXEC"])? {
+                Reply::Array(replies) => Ok(replies),
+                reply => Err(invalid_data(&format!("unexpected reply {:?}", reply))),
+            }
+        }
+
+        // Same as `atomically`, for writes which are not expected to be contended.
+        fn write(&self, commands: &[Vec<&[u8]>]) -> io::Result<()> {
+            match self.atomically(commands)? {
+                Some(_) => Ok(()),
+                None => Err(io::Error::other("Unable to write to the ledger")),
+            }
+        }
+
+        // The values of `keys`, fetched in batches.
+        fn fetch(&self, keys: &[String]) -> io::Result<Vec<Vec<u8>>> {
+            let mut values = Vec::with_capacity(keys.len());
+            for batch in keys.chunks(FETCH_BATCH) {
+                let mut command: Vec<&[u8]> = vec![b"MGET"];
+                command.extend(batch.iter().map(|key| key.as_bytes()));
+                if let Reply::Array(Some(replies)) = self.run(&command)? {
+                    values.extend(replies.into_iter().filter_map(Reply::into_bulk));
+                }
+            }
+            Ok(values)
+        }
+
+        // The keys of the transactions in the history.
+        fn transaction_keys(&self) -> io::Result<Vec<String>> {
+            let key = self.key("transactions");
+            Ok(self
+                .run(&[b"SMEMBERS", key.as_bytes()])?
+                .ids()?
+                .into_iter()
+                .map(|tx| self.transaction_key(TransactionId::new(tx)))
+                .collect())
+        }
+
+        fn changed_by_another_instance(client: ClientId) -> io::Error {
+            io::Error::other(format!(
+                "The account of client {} was changed by another instance",
+                client
+            ))
+        }
+    }
+
+    impl Ledger for RedisLedger {
+        fn transaction(&self, tx: TransactionId) -> io::Result<Option<Transaction>> {
+            let key = self.transaction_key(tx);
+            self.run(&[b"GET", key.as_bytes()])?
+                .into_bulk()
+                .map(|value| decode(&value))
+                .transpose()
+        }
+
+        fn contains_transaction(&self, tx: TransactionId) -> io::Result<bool> {
+            let key = self.transaction_key(tx);
+            Ok(matches!(
+                self.run(&[b"EXISTS", key.as_bytes()])?,
+                Reply::Integer(1)
+            ))
+        }
+
+        fn insert_transaction(&mut self, transaction: Transaction) -> io::Result<()> {
+            let (tx, disputed) = (transaction.tx().to_string(), transaction.disputed());
+            let key = self.transaction_key(transaction.tx());
+            let value = encode(&transaction)?;
+            let (transactions, index) = (self.key("transactions"), self.key("disputed"));
+            let update: &[u8] = if disputed { b"SADD" } else { b"SREM" };
+            self.write(&[
+                vec![b"SET", key.as_bytes(), &value],
+                vec![b"SADD", transactions.as_bytes(), tx.as_bytes()],
+                vec![update, index.as_bytes(), tx.as_bytes()],
+            ])
+        }
+
+        fn remove_transaction(&mut self, tx: TransactionId) -> io::Result<()> {
+            let key = self.transaction_key(tx);
+            let tx = tx.to_string();
+            let (transactions, index) = (self.key("transactions"), self.key("disputed"));
+            self.write(&[
+                vec![b"DEL", key.as_bytes()],
+                vec![b"SREM", transactions.as_bytes(), tx.as_bytes()],
+                vec![b"SREM", index.as_bytes(), tx.as_bytes()],
+            ])
+        }
+
+        fn transactions(&self) -> Box<dyn Iterator<Item = io::Result<Transaction>> + '_> {
+            let values = match self.transaction_keys().and_then(|keys| self.fetch(&keys)) {
+                Ok(values) => values,
+                Err(e) => return Box::new(std::iter::once(Err(e))),
+            };
+            Box::new(values.into_iter().map(|value| decode(&value)))
+        }
+
+        fn store_account(&mut self, client: ClientId, account: &Account) -> io::Result<()> {
+            let key = self.account_key(client);
+            self.run(&[b"WATCH", key.as_bytes()])?;
+            let stored = self
+                .run(&[b"HGET", key.as_bytes(), b"version"])?
+                .into_bulk()
+                .map(|version| parse_id(&version))
+                .transpose()?;
+            let expected = self.versions.borrow().get(&client).copied();
+            if stored.is_some() && stored != expected {
+                self.run(&[b"UNWATCH"])?;
+                return Err(RedisLedger::changed_by_another_instance(client));
+            }
+
+            let version = stored.map_or(1, |version| version + 1);
+            let value = serde_json::to_vec(account)?;
+            let (client_id, version_id) = (client.to_string(), version.to_string());
+            let accounts = self.key("accounts");
+            let written = self.atomically(&[
+                vec![
+                    b"HSET",
+                    key.as_bytes(),
+                    b"data",
+                    &value,
+                    b"version",
+                    version_id.as_bytes(),
+                ],
+                vec![b"SADD", accounts.as_bytes(), client_id.as_bytes()],
+            ])?;
+            if written.is_none() {
+                return Err(RedisLedger::changed_by_another_instance(client));
+            }
+            self.versions.borrow_mut().insert(client, version);
+            Ok(())
+        }
+
+        fn accounts(&self) -> io::Result<Vec<(ClientId, Account)>> {
+            let key = self.key("accounts");
+            let clients: Vec<ClientId> = self
+                .run(&[b"SMEMBERS", key.as_bytes()])?
+                .ids()?
+                .into_iter()
+                .map(|client| ClientId::new(client as u32))
+                .collect();
+
+            let mut accounts = Vec::with_capacity(clients.len());
+            for client in clients {
+                let key = self.account_key(client);
+                let fields = match self.run(&[b"HMGET", key.as_bytes(), b"data", b"version"])? {
+                    Reply::Array(Some(fields)) => fields,
+                    _ => continue,
+                };
+                let mut fields = fields.into_iter().map(Reply::into_bulk);
+                if let (Some(Some(data)), Some(Some(version))) = (fields.next(), fields.next()) {
+                    let account = serde_json::from_slice(&data)?;
+                    self.versions
+                        .borrow_mut()
+                        .insert(client, parse_id(&version)?);
+                    accounts.push((client, account));
+                }
+            }
+            Ok(accounts)
+        }
+
+        fn clear(&mut self) -> io::Result<()> {
+            let accounts = self.key("accounts");
+            let mut keys = self.transaction_keys()?;
+            keys.extend(
+                self.run(&[b"SMEMBERS", accounts.as_bytes()])?
+                    .ids()?
+                    .into_iter()
+                    .map(|client| self.account_key(ClientId::new(client as u32))),
+            );
+            keys.extend([self.key("transactions"), accounts, self.key("disputed")]);
+            for batch in keys.chunks(FETCH_BATCH) {
+                let mut command: Vec<&[u8]> = vec![b"DEL"];
+                command.extend(batch.iter().map(|key| key.as_bytes()));
+                self.run(&command)?;
+            }
+            self.versions.borrow_mut().clear();
+            Ok(())
+        }
+    }
+
+    // Parses an id (or version) as stored by the ledger.
+    fn parse_id(value: &[u8]) -> io::Result<u64> {
+        std::str::from_utf8(value)
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .ok_or_else(|| invalid_data("corrupted id"))
+    }
+
+    // A connection speaking the Redis protocol (RESP), which is all the ledger needs of a
+    // Redis client.
+    struct Connection {
+        reader: BufReader<TcpStream>,
+        writer: TcpStream,
+    }
+
+    #[derive(Debug)]
+    enum Reply {
+        Status,
+        Integer(i64),
+        Bulk(Option<Vec<u8>>),
+        Array(Option<Vec<Reply>>),
+    }
+
+    impl Reply {
+        fn into_bulk(self) -> Option<Vec<u8>> {
+            match self {
+                Reply::Bulk(value) => value,
+                _ => None,
+            }
+        }
+
+        // The ids listed by a reply to `SMEMBERS`.
+        fn ids(self) -> io::Result<Vec<u64>> {
+            let members = match self {
+                Reply::Array(Some(members)) => members,
+                _ => Vec::new(),
+            };
+            members
+                .into_iter()
+                .filter_map(Reply::into_bulk)
+                .map(|member| parse_id(&member))
+                .collect()
+        }
+    }
+
+    impl Connection {
+        // Sends a command (as an array of bulk strings), and reads the reply. Error replies
+        // are turned into errors.
+        fn command(&mut self, args: &[&[u8]]) -> io::Result<Reply> {
+            let mut request = format!("*{}\r\n", args.len()).into_bytes();
+            for arg in args {
+                request.extend(format!("${}\r\n", arg.len()).into_bytes());
+                request.extend_from_slice(arg);
+                request.extend_from_slice(b"\r\n");
+            }
+            self.writer.write_all(&request)?;
+            self.read_reply()
+        }
+
+        fn read_reply(&mut self) -> io::Result<Reply> {
+            let line = self.read_line()?;
+            let (kind, rest) = match line.split_first() {
+                Some((&kind, rest)) => (kind, rest),
+                None => return Err(invalid_data("empty reply")),
+            };
+            let rest = String::from_utf8_lossy(rest).into_owned();
+            let length = || {
+                rest.parse::<i64>()
+                    .map_err(|_| invalid_data("invalid length"))
+            };
+            match kind {
+                b'+' => Ok(Reply::Status),
+                b'-' => Err(io::Error::other(rest)),
+                b':' => Ok(Reply::Integer(length()?)),
+                b'$' => match usize::try_from(length()?) {
+                    Ok(len) => {
+                        let mut value = vec![0; len + 2];
+                        io::Read::read_exact(&mut self.reader, &mut value)?;
+                        value.truncate(len);
+                        Ok(Reply::Bulk(Some(value)))
+                    }
+                    Err(_) => Ok(Reply::Bulk(None)),
+                },
+                b'*' => match usize::try_from(length()?) {
+                    Ok(len) => {
+                        let replies = (0..len)
+                            .map(|_| self.read_reply())
+                            .collect::<io::Result<_>>()?;
+                        Ok(Reply::Array(Some(replies)))
+                    }
+                    Err(_) => Ok(Reply::Array(None)),
+                },
+                _ => Err(invalid_data("unknown reply type")),
+            }
+        }
+
+        // Reads a line of the reply, without the trailing `\r\n`.
+        fn read_line(&mut self) -> io::Result<Vec<u8>> {
+            let mut line = Vec::new();
+            if self.reader.read_until(b'\n', &mut line)? == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            if line.ends_with(b"\r\n") {
+                line.truncate(line.len() - 2);
+            }
+            Ok(line)
+        }
+    }
+
+    fn invalid_data(message: &str) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, message)
+    }
+}
@@ -0,0 +1,89 @@
+//! A simple transaction engine, which processes deposits, withdrawals, and disputes for
+//! client accounts. The `te` binary is a CLI on top of this library, which other services
+//! can use to embed the engine directly:
+//!
+//! ```
+//! use rust_decimal::Decimal;
+//! use te::{ClientId, Transaction, TransactionEngine, Type};
+//!
+//! let mut engine = TransactionEngine::new();
+//! engine
+//!     .process_transaction(Transaction::new(Type::Deposit, 1, 1, Some(Decimal::new(25, 1))))
+//!     .unwrap();
+//!
+//! let account = engine.account(ClientId::new(1)).unwrap();
+//! assert_eq!(account.balance("").available(), Decimal::new(25, 1));
+//! ```
+//!
+//! Accounts track funds per currency, with the empty currency code standing for the
+//! implicit currency of transactions that don't specify any.
+//!
+//! Besides `TransactionEngine::write_accounts`, the results can be read via
+//! `TransactionEngine::accounts` (in no particular order) and `TransactionEngine::account`, or
+//! serialized as a whole via `TransactionEngine::account_views`.
+
+pub mod account;
+pub mod amount;
+#[cfg(feature = "arrow")]
+pub mod arrow;
+#[cfg(feature = "tokio")]
+pub mod async_engine;
+pub mod audit;
+pub mod clock;
+pub mod config;
+pub mod diff;
+#[cfg(feature = "signed-audit")]
+mod digest;
+pub mod dispute;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod encryption;
+pub mod events;
+pub mod fees;
+#[cfg(feature = "capi")]
+pub mod ffi;
+#[cfg(feature = "fuzzing")]
+pub mod fuzz;
+pub mod id;
+pub mod input;
+mod invariants;
+#[cfg(feature = "kafka")]
+pub mod kafka;
+pub mod ledger;
+pub mod limits;
+pub mod lineage;
+pub mod metrics;
+pub mod observer;
+#[cfg(feature = "rayon")]
+pub mod pipeline;
+#[cfg(feature = "protobuf")]
+pub mod protobuf;
+#[cfg(feature = "pyo3")]
+pub mod python;
+pub mod repair;
+#[cfg(any(feature = "http", feature = "grpc"))]
+pub mod rest;
+pub mod risk;
+#[cfg(feature = "grpc")]
+pub mod rpc;
+pub mod scenario;
+pub mod scheduler;
+pub mod sequence;
+pub mod shared;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+pub mod statement;
+pub mod summary;
+pub mod tenant;
+pub mod transaction;
+pub mod validator;
+pub mod wal;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+
+pub use account::{Account, AccountColumn, AccountMetadata, AccountView, Balance, Status};
+pub use amount::Amount;
+pub use dispute::{DisputePolicy, DisputeRecord, OverdraftPolicy};
+pub use transaction::{
+    ClientId, Error, Snapshot, Transaction, TransactionEngine, TransactionEngineBuilder,
+    TransactionId, Type,
+};
@@ -0,0 +1,483 @@
+//! Limits on how far withdrawals and outgoing transfers can take the available funds of an
+//! account (see `TransactionEngine::with_balance_limits`): a minimum balance to keep, and an
+//! overdraft line to go below zero by. Limits can be set for every account, and overridden
+//! per client, i.e. from a TOML file (see `load_client_limits`) with a table per client:
+//!
+//! ```toml
+//! [7]
+//! overdraft_limit = "500"
+//!
+//! [12]
+//! minimum_balance = "100"
+//! ```
+//!
+//! Velocity limits bound the deposits and withdrawals of every client within a sliding window
+//! instead (see `WindowLimits`, which enforces them as a validator), by number or by amount,
+//! and are written as `<kind>:<max>/<window>`, i.e. `withdrawal-amount:1000/24h` or
+//! `deposit-count:10/1h`.
+
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::io::{self, Read};
+use std::str::FromStr;
+
+use rust_decimal::Decimal;
+use serde::{Deserialize, Deserializer};
+use tracing::warn;
+
+use crate::config;
+use crate::scheduler::Period;
+use crate::transaction::{ClientId, Error, Transaction, Type, Warning};
+use crate::validator::Validator;
+
+/// The limits of an account (in every currency it holds): withdrawals and outgoing transfers
+/// are rejected with `Error::InsufficientFunds` if they'd take the available funds below
+/// `minimum_balance - overdraft_limit`. Both are zero by default, so the available funds have
+/// to cover the whole amount.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct BalanceLimits {
+    #[serde(default)]
+    pub minimum_balance: Decimal,
+    #[serde(default)]
+    pub overdraft_limit: Decimal,
+}
+
+impl BalanceLimits {
+    /// The lowest the available funds may go by withdrawing (which is below zero with an
+    /// overdraft line that exceeds the minimum balance).
+    pub fn floor(&self) -> Decimal {
+        self.minimum_balance - self.overdraft_limit
+    }
+
+    pub(crate) fn validated(self) -> io::Result<Self> {
+        if self.minimum_balance.is_sign_negative() || self.overdraft_limit.is_sign_negative() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "limits can't be negative",
+            ));
+        }
+        Ok(self)
+    }
+}
+
+/// Reads the limits of individual clients from a TOML document, with a table for every
+/// client (named after its id).
+pub fn load_client_limits<R: Read>(r: R) -> io::Result<HashMap<ClientId, BalanceLimits>> {
+    let tables: BTreeMap<String, BalanceLimits> = config::read_toml(r)?;
+
+    tables
+        .into_iter()
+        .map(|(client, limits)| {
+            let client = client.parse().map_err(|e| {
+                let message = format!("invalid client id `{}`: {}", client, e);
+                io::Error::new(io::ErrorKind::InvalidData, message)
+            })?;
+            Ok((client, limits.validated()?))
+        })
+        .collect()
+}
+
+/// What a `WindowLimit` bounds: the number or the total amount of either the deposits, or the
+/// withdrawals (including outgoing transfers) of a client.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum LimitKind {
+    DepositCount,
+    DepositAmount,
+    WithdrawalCount,
+    WithdrawalAmount,
+}
+
+impl LimitKind {
+    /// The name of the kind, as written in limits (i.e. `withdrawal-amount`).
+    pub fn name(self) -> &'static str {
+        match self {
+            LimitKind::DepositCount => "deposit-count",
+            LimitKind::DepositAmount => "deposit-amount",
+            LimitKind::WithdrawalCount => "withdrawal-count",
+            LimitKind::WithdrawalAmount => "withdrawal-amount",
+        }
+    }
+
+    fn is_count(self) -> bool {
+        matches!(self, LimitKind::DepositCount | LimitKind::WithdrawalCount)
+    }
+
+    // Whether transactions of type `type_` count towards limits of this kind.
+    fn applies_to(self, type_: Type) -> bool {
+        match self {
+            LimitKind::DepositCount | LimitKind::DepositAmount => type_ == Type::Deposit,
+            LimitKind::WithdrawalCount | LimitKind::WithdrawalAmount => {
+                matches!(type_, Type::Withdrawal | Type::Transfer)
+            }
+        }
+    }
+}
+
+/// A velocity limit: at most `max` deposits or withdrawals (or that much of their amounts) per
+/// client within any `window` seconds, by the timestamps of the transactions. Transactions
+/// beyond it are rejected with `Error::LimitExceeded`.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct WindowLimit {
+    pub kind: LimitKind,
+    pub max: Decimal,
+    pub window: u64,
+}
+
+impl WindowLimit {
+    // Whether transactions of type `type_` count towards the limit, as long as they have a
+    // timestamp.
+    fn covers(&self, type_: Type) -> bool {
+        self.kind.applies_to(type_)
+    }
+
+    // Whether `transaction` counts towards the limit, which takes a timestamp.
+    fn applies_to(&self, transaction: &Transaction) -> bool {
+        transaction.timestamp().is_some() && self.covers(transaction.type_())
+    }
+
+    // What `transaction` adds to a window of the limit.
+    fn weight(&self, transaction: &Transaction) -> Decimal {
+        if self.kind.is_count() {
+            Decimal::ONE
+        } else {
+            transaction.amount()
+        }
+    }
+}
+
+/// Writes the limit the same way it's parsed, with the window in seconds.
+impl fmt::Display for WindowLimit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}/{}s", self.kind.name(), self.max, self.window)
+    }
+}
+
+/// Parses `<kind>:<max>/<window>`, where the kind is `deposit-count`, `deposit-amount`,
+/// `withdrawal-count`, or `withdrawal-amount`, and the window is a number of seconds,
+/// minutes, hours, or days (same as for the periods of the scheduler, i.e. `24h`).
+impl FromStr for WindowLimit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = |reason: &str| format!("invalid limit `{}`: {}", s, reason);
+        let (kind, rest) = s.split_once(':').ok_or_else(|| {
+            invalid("expected `<kind>:<max>/<window>`, i.e. `deposit-count:10/1h`")
+        })?;
+        let (max, window) = rest
+            .split_once('/')
+            .ok_or_else(|| invalid("expected a maximum and a window, i.e. `1000/24h`"))?;
+
+        let kind = match kind.trim() {
+            "deposit-count" => LimitKind::DepositCount,
+            "deposit-amount" => LimitKind::DepositAmount,
+            "withdrawal-count" => LimitKind::WithdrawalCount,
+            "withdrawal-amount" => LimitKind::WithdrawalAmount,
+            other => return Err(invalid(&format!("unknown kind `{}`", other))),
+        };
+        let max = if kind.is_count() {
+            max.trim()
+                .parse::<u64>()
+                .map(Decimal::from)
+                .map_err(|e| invalid(&e.to_string()))?
+        } else {
+            let max: Decimal = max.trim().parse().map_err(|e| invalid(&format!("{}", e)))?;
+            if max.is_sign_negative() {
+                return Err(invalid("limits can't be negative"));
+            }
+            max
+        };
+        let window = match window.trim().parse()? {
+            Period::Seconds(seconds) => seconds,
+            Period::Month => return Err(invalid("the window can't be a month")),
+        };
+        Ok(WindowLimit { kind, max, window })
+    }
+}
+
+impl<'de> Deserialize<'de> for WindowLimit {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+// The transactions of a client which count towards a `WindowLimit`, as their timestamps and
+// what they added (one, or their amount), oldest first, together with the total. Entries
+// drop out as the timestamps of the client move on, so checking a transaction against the
+// window only takes time for the ones which expired since.
+#[derive(Clone, Debug, Default)]
+struct Window {
+    entries: VecDeque<(u64, Decimal)>,
+    total: Decimal,
+}
+
+impl Window {
+    // The total of the entries less than `limit.window` seconds before `timestamp`, or after
+    // it (for transactions which arrive out of order).
+    fn total_at(&self, limit: &WindowLimit, timestamp: u64) -> Decimal {
+        let expired: Decimal = self
+            .entries
+            .iter()
+            .take_while(|&&(t, _)| timestamp.saturating_sub(t) >= limit.window)
+            .map(|&(_, weight)| weight)
+            .sum();
+        self.total - expired
+    }
+
+    // Adds the applied `transaction` to the window, in timestamp order, and
+    // drops the entries which fell out of the window as of its timestamp.
+    fn record(&mut self, limit: &WindowLimit, transaction: &Transaction) {
+        let timestamp = transaction.timestamp().unwrap_or_default();
+        while let Some(&(t, weight)) = self.entries.front() {
+            if timestamp.saturating_sub(t) < limit.window {
+                break;
+            }
+            self.entries.pop_front();
+            self.total -= weight;
+        }
+        let weight = limit.weight(transaction);
+        let i = self.entries.partition_point(|&(t, _)| t <= timestamp);
+        self.entries.insert(i, (timestamp, weight));
+        self.total += weight;
+    }
+}
+
+/// Enforces velocity limits (see `WindowLimit`) as a validator: deposits, or withdrawals and
+/// outgoing transfers, which would take the number or the total amount of those a client made
+/// within the window beyond the maximum are rejected with `Error::LimitExceeded`, naming the
+/// first limit they exceed. Windows slide along the timestamps of the transactions, so only
+/// ones with a timestamp count, and the first one without of every client gets a
+/// `Warning::LimitBypassed`.
+#[derive(Clone, Debug, Default)]
+pub struct WindowLimits {
+    limits: Vec<WindowLimit>,
+    client_limits: HashMap<ClientId, Vec<WindowLimit>>,
+    // The recent deposits and withdrawals of every client, with a window for each of the
+    // limits of the client (in the same order).
+    windows: HashMap<ClientId, Vec<Window>>,
+    // The clients which have been warned about bypassing their limits already.
+    bypassed: HashSet<ClientId>,
+}
+
+impl WindowLimits {
+    /// Enforces `limits` for every client (unless overridden via `with_client_limits`).
+    pub fn new(limits: Vec<WindowLimit>) -> Self {
+        WindowLimits {
+            limits,
+            ..WindowLimits::default()
+        }
+    }
+
+    /// Sets all the limits of `client` at once, which take precedence over the limits for
+    /// every client (so an empty list exempts the client).
+    pub fn with_client_limits(mut self, client: ClientId, limits: Vec<WindowLimit>) -> Self {
+        self.windows.remove(&client);
+        self.client_limits.insert(client, limits);
+        self
+    }
+
+    fn limits_of(&self, client: ClientId) -> &[WindowLimit] {
+        self.client_limits.get(&client).unwrap_or(&self.limits)
+    }
+
+    // The first limit of its client which `transaction` would exceed, if any.
+    fn exceeded(&self, transaction: &Transaction) -> Option<WindowLimit> {
+        let timestamp = transaction.timestamp()?;
+        let windows = self.windows.get(&transaction.client());
+        let limits = self.limits_of(transaction.client()).iter().enumerate();
+        limits
+            .filter(|(_, limit)| limit.covers(transaction.type_()))
+            .find(|&(i, limit)| {
+                let window = windows.and_then(|windows| windows.get(i));
+                let total = window.map_or(Decimal::ZERO, |w| w.total_at(limit, timestamp));
+                total + limit.weight(transaction) > limit.max
+            })
+            .map(|(_, limit)| *limit)
+    }
+}
+
+impl Validator for WindowLimits {
+    fn name(&self) -> &'static str {
+        "window_limits"
+    }
+
+    fn validate(&self, transaction: &Transaction) -> bool {
+        self.exceeded(transaction).is_none()
+    }
+
+    fn error(&self, transaction: &Transaction) -> Error {
+        match self.exceeded(transaction) {
+            Some(limit) => Error::LimitExceeded {
+                tx: transaction.tx(),
+                client: transaction.client(),
+                limit,
+            },
+            None => Error::ValidationFailed {
+                tx: transaction.tx(),
+                validator: self.name(),
+            },
+        }
+    }
+
+    fn applied(&mut self, transaction: &Transaction) {
+        let client = transaction.client();
+        let limits = self.client_limits.get(&client).unwrap_or(&self.limits);
+        if !limits.iter().any(|limit| limit.applies_to(transaction)) {
+            return;
+        }
+        let windows = self.windows.entry(client).or_default();
+        windows.resize_with(limits.len(), Window::default);
+        for (limit, window) in limits.iter().zip(windows.iter_mut()) {
+            if limit.applies_to(transaction) {
+                window.record(limit, transaction);
+            }
+        }
+    }
+
+    fn warning(&mut self, transaction: &Transaction) -> Option<Warning> {
+        let client = transaction.client();
+        let covered = self
+            .limits_of(client)
+            .iter()
+            .any(|limit| limit.covers(transaction.type_()));
+        if transaction.timestamp().is_some() || !covered {
+            return None;
+        }
+        // Logged once, since input without timestamps bypasses every limit.
+        if self.bypassed.is_empty() {
+            warn!("Velocity limits don't apply to transactions without a timestamp");
+        }
+        // Once per client, since its further transactions are bound to lack one too.
+        self.bypassed
+            .insert(client)
+            .then(|| Warning::LimitBypassed {
+                client,
+                tx: transaction.tx(),
+                type_: transaction.type_(),
+            })
+    }
+
+    fn reset(&mut self) {
+        self.windows.clear();
+        self.bypassed.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal::Decimal;
+
+    use super::*;
+    use crate::transaction::{ClientFilter, Error, TransactionEngine, Warning};
+
+    fn withdrawal(tx: u64, timestamp: u64) -> Transaction {
+        Transaction::new(Type::Withdrawal, 1, tx, Some(Decimal::ONE)).with_timestamp(timestamp)
+    }
+
+    #[test]
+    fn parses_and_displays_limits() {
+        let limit: WindowLimit = "withdrawal-amount:1000/24h".parse().unwrap();
+        assert_eq!(limit.kind, LimitKind::WithdrawalAmount);
+        assert_eq!(limit.max, Decimal::new(1000, 0));
+        assert_eq!(limit.window, 86_400);
+        assert_eq!(limit.to_string().parse::<WindowLimit>().unwrap(), limit);
+
+        assert!("deposit-count:1.5/1h".parse::<WindowLimit>().is_err());
+        assert!("deposit-amount:-1/1h".parse::<WindowLimit>().is_err());
+        assert!("deposit-count:1/month".parse::<WindowLimit>().is_err());
+        assert!("deposits:1/1h".parse::<WindowLimit>().is_err());
+    }
+
+    #[test]
+    fn rejects_withdrawals_beyond_the_count_until_the_window_slides_on() {
+        let limit: WindowLimit = "withdrawal-count:3/1h".parse().unwrap();
+        let mut engine = TransactionEngine::new().with_validator(WindowLimits::new(vec![limit]));
+        engine
+            .process_transaction(
+                Transaction::new(Type::Deposit, 1, 1, Some(Decimal::new(10, 0))).with_timestamp(0),
+            )
+            .unwrap();
+
+        for tx in 2..5 {
+            engine.process_transaction(withdrawal(tx, 1000)).unwrap();
+        }
+        assert_eq!(
+            engine.process_transaction(withdrawal(5, 4599)),
+            Err(Error::LimitExceeded {
+                tx: 5.into(),
+                client: 1.into(),
+                limit,
+            })
+        );
+        // An hour after the first three, they've left the window.
+        engine.process_transaction(withdrawal(6, 4600)).unwrap();
+
+        let account = engine.account(1.into()).unwrap();
+        assert_eq!(account.balance("").available(), Decimal::new(6, 0));
+    }
+
+    #[test]
+    fn rejects_withdrawals_beyond_the_amount() {
+        let limit: WindowLimit = "withdrawal-amount:2.5/1h".parse().unwrap();
+        let mut engine = TransactionEngine::new().with_validator(WindowLimits::new(vec![limit]));
+        engine
+            .process_transaction(
+                Transaction::new(Type::Deposit, 1, 1, Some(Decimal::new(10, 0))).with_timestamp(0),
+            )
+            .unwrap();
+
+        engine.process_transaction(withdrawal(2, 10)).unwrap();
+        engine.process_transaction(withdrawal(3, 20)).unwrap();
+        assert!(matches!(
+            engine.process_transaction(withdrawal(4, 30)),
+            Err(Error::LimitExceeded { .. })
+        ));
+        // Rejected transactions don't count, so half of the amount is still left.
+        let half = Transaction::new(Type::Withdrawal, 1, 5, Some(Decimal::new(5, 1)));
+        engine.process_transaction(half.with_timestamp(40)).unwrap();
+    }
+
+    #[test]
+    fn leaves_transactions_without_a_timestamp_out() {
+        let limit: WindowLimit = "deposit-count:1/1h".parse().unwrap();
+        let mut engine = TransactionEngine::new().with_validator(WindowLimits::new(vec![limit]));
+
+        for (client, tx) in [(1, 1), (2, 2), (1, 3), (2, 4)] {
+            let deposit = Transaction::new(Type::Deposit, client, tx, Some(Decimal::ONE));
+            engine.process_transaction(deposit).unwrap();
+        }
+        // Only the first ones of each client get a warning.
+        let bypassed: Vec<_> = engine
+            .drain_warnings()
+            .map(|warning| match warning {
+                Warning::LimitBypassed { tx, .. } => tx,
+                other => panic!("Unexpected warning: {}", other),
+            })
+            .collect();
+        assert_eq!(bypassed, [1.into(), 2.into()]);
+    }
+
+    #[test]
+    fn only_warns_about_applied_transactions_until_reset() {
+        let limit: WindowLimit = "deposit-count:1/1h".parse().unwrap();
+        let filter = ClientFilter::new().include(1.into()..=1.into());
+        let mut engine = TransactionEngine::new()
+            .with_validator(WindowLimits::new(vec![limit]))
+            .with_client_filter(filter);
+        let deposit =
+            |client: u32, tx: u64| Transaction::new(Type::Deposit, client, tx, Some(Decimal::ONE));
+
+        // Filtered out, so the deposit never gets to bypass the limit.
+        assert!(engine.process_transaction(deposit(2, 1)).is_err());
+        assert_eq!(engine.drain_warnings().count(), 0);
+        engine.process_transaction(deposit(1, 2)).unwrap();
+        assert_eq!(engine.drain_warnings().count(), 1);
+        engine.process_transaction(deposit(1, 3)).unwrap();
+        assert_eq!(engine.drain_warnings().count(), 0);
+
+        engine.reset().unwrap();
+        engine.process_transaction(deposit(1, 4)).unwrap();
+        assert_eq!(engine.drain_warnings().count(), 1);
+    }
+}
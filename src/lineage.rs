@@ -0,0 +1,279 @@
+//! The lineage of contested funds (see `TransactionEngine::with_lineage`): which deposits and
+//! withdrawals got disputed, resolved, charged back, reversed, or voided, in which order, and
+//! which funds moved between accounts via transfers. The lineage can be exported as a graph,
+//! either for Graphviz (see `write_dot`) or as JSON (see `write_json`), with a node for every
+//! account, every such transaction, and every step of its lifecycle.
+
+use std::collections::{BTreeSet, HashMap};
+use std::io::{self, Write};
+
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use crate::account::rounded;
+use crate::transaction::{ClientId, Currency, Transaction, TransactionId, Type};
+
+/// A transaction with a lifecycle, i.e. a disputed or voided deposit or withdrawal, or a
+/// transfer (whose lifecycle may well be empty).
+#[derive(Clone, Debug, PartialEq)]
+pub struct Origin {
+    pub tx: TransactionId,
+    pub type_: Type,
+    pub client: ClientId,
+    /// The receiving client, for transfers.
+    pub to: Option<ClientId>,
+    pub amount: Decimal,
+    pub currency: Currency,
+    pub timestamp: Option<u64>,
+    /// The dispute-related transactions and voids applied to the transaction, in order.
+    pub steps: Vec<Step>,
+}
+
+/// A dispute-related transaction or void applied to an `Origin`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Step {
+    pub type_: Type,
+    /// The amount of partial disputes and settlements, which are for the whole amount
+    /// otherwise.
+    pub amount: Option<Decimal>,
+    pub timestamp: Option<u64>,
+}
+
+/// The transactions with a lifecycle, in the order they first showed up.
+#[derive(Clone, Debug, Default)]
+pub struct Lineage {
+    origins: Vec<Origin>,
+    // The index of every transaction in `origins`.
+    index: HashMap<TransactionId, usize>,
+}
+
+impl Lineage {
+    /// The transactions with a lifecycle, in the order they first showed up.
+    pub fn origins(&self) -> &[Origin] {
+        &self.origins
+    }
+
+    // Records the applied `transaction`, where `original` is the transaction it refers to
+    // as of before it got applied (for dispute-related transactions and voids).
+    pub(crate) fn record(&mut self, transaction: &Transaction, original: Option<&Transaction>) {
+        match transaction.type_() {
+            Type::Transfer => {
+                self.origin(transaction);
+            }
+            Type::Dispute
+            | Type::Resolve
+            | Type::Chargeback
+            | Type::ChargebackReversal
+            | Type::Void => {
+                let i = match (self.index.get(&transaction.tx()), original) {
+                    (Some(&i), _) => i,
+                    (None, Some(original)) => self.origin(original),
+                    (None, None) => return,
+                };
+                // Dispute-related transactions only have an amount if they're partial.
+                let amount = Some(transaction.amount()).filter(|amount| !amount.is_zero());
+                self.origins[i].steps.push(Step {
+                    type_: transaction.type_(),
+                    amount,
+                    timestamp: transaction.timestamp(),
+                });
+            }
+            _ => {}
+        }
+    }
+
+    fn origin(&mut self, transaction: &Transaction) -> usize {
+        let origins = &mut self.origins;
+        *self.index.entry(transaction.tx()).or_insert_with(|| {
+            origins.push(Origin {
+                tx: transaction.tx(),
+                type_: transaction.type_(),
+                client: transaction.client(),
+                to: transaction.destination(),
+                amount: transaction.amount(),
+                currency: transaction.currency().to_owned(),
+                timestamp: transaction.timestamp(),
+                steps: Vec::new(),
+            });
+            origins.len() - 1
+        })
+    }
+
+    // The accounts the transactions of the lineage involve, ordered by client id.
+    fn clients(&self) -> BTreeSet<ClientId> {
+        let clients = self.origins.iter().map(|origin| origin.client);
+        let receivers = self.origins.iter().filter_map(|origin| origin.to);
+        clients.chain(receivers).collect()
+    }
+}
+
+/// Writes `lineage` to `w` as a Graphviz `digraph`. Accounts are boxes, and the funds flow
+/// along the edges: into the account of a deposit, out of the one of a withdrawal, and from
+/// the sender to the receiver of a transfer. The steps of every lifecycle hang off their
+/// transaction as a chain, with charged back ones in red.
+pub fn write_dot<W: Write>(mut w: W, lineage: &Lineage) -> io::Result<()> {
+    writeln!(w, "digraph lineage {{")?;
+    writeln!(w, "  rankdir=LR;")?;
+    for client in lineage.clients() {
+        writeln!(w, "  \"client {}\" [shape=box];", client)?;
+    }
+    for origin in lineage.origins() {
+        let node = format!("tx {}", origin.tx);
+        writeln!(
+            w,
+            "  \"{}\" [label=\"{} {}\\n{}{}\"];",
+            node,
+            origin.type_.name(),
+            origin.tx,
+            rounded(origin.amount),
+            currency_suffix(&origin.currency)
+        )?;
+        match (origin.type_, origin.to) {
+            (Type::Transfer, Some(to)) => {
+                writeln!(w, "  \"client {}\" -> \"{}\";", origin.client, node)?;
+                writeln!(w, "  \"{}\" -> \"client {}\";", node, to)?;
+            }
+            (Type::Withdrawal, _) => {
+                writeln!(w, "  \"client {}\" -> \"{}\";", origin.client, node)?
+            }
+            _ => writeln!(w, "  \"{}\" -> \"client {}\";", node, origin.client)?,
+        }
+
+        let mut previous = node;
+        for (i, step) in origin.steps.iter().enumerate() {
+            let id = format!("tx {}/{}", origin.tx, i + 1);
+            let amount = step
+                .amount
+                .map(|amount| format!("\\n{}", rounded(amount)))
+                .unwrap_or_default();
+            let color = match step.type_ {
+                Type::Chargeback => ", color=red",
+                _ => "",
+            };
+            writeln!(
+                w,
+                "  \"{}\" [label=\"{}{}\", shape=ellipse{}];",
+                id,
+                step.type_.name(),
+                amount,
+                color
+            )?;
+            writeln!(w, "  \"{}\" -> \"{}\" [style=dashed];", previous, id)?;
+            previous = id;
+        }
+    }
+    writeln!(w, "}}")?;
+    w.flush()
+}
+
+/// Writes `lineage` to `w` as a JSON object with the `nodes` and `edges` of the same graph as
+/// `write_dot`. Nodes have an `id` (i.e. `client:1`, `tx:7`, or `tx:7/1` for the first step
+/// of transaction 7) and a `kind` (`account`, `transaction`, or `step`), and edges go `from`
+/// one node `to` another, with a `relation` (`credits`, `debits`, `sends`, `receives`, or
+/// `step`).
+pub fn write_json<W: Write>(mut w: W, lineage: &Lineage) -> io::Result<()> {
+    let mut nodes: Vec<Node> = lineage
+        .clients()
+        .into_iter()
+        .map(|client| Node {
+            id: format!("client:{}", client),
+            kind: "account",
+            client: Some(client),
+            ..Node::default()
+        })
+        .collect();
+    let mut edges = Vec::new();
+
+    for origin in lineage.origins() {
+        let id = format!("tx:{}", origin.tx);
+        let account = |client| format!("client:{}", client);
+        match (origin.type_, origin.to) {
+            (Type::Transfer, Some(to)) => {
+                edges.push(Edge::new(account(origin.client), id.clone(), "sends"));
+                edges.push(Edge::new(id.clone(), account(to), "receives"));
+            }
+            (Type::Withdrawal, _) => {
+                edges.push(Edge::new(account(origin.client), id.clone(), "debits"))
+            }
+            _ => edges.push(Edge::new(id.clone(), account(origin.client), "credits")),
+        }
+        nodes.push(Node {
+            id: id.clone(),
+            kind: "transaction",
+            type_: Some(origin.type_.name()),
+            client: Some(origin.client),
+            to: origin.to,
+            tx: Some(origin.tx),
+            amount: Some(rounded(origin.amount)),
+            currency: &origin.currency,
+            timestamp: origin.timestamp,
+        });
+
+        let mut previous = id;
+        for (i, step) in origin.steps.iter().enumerate() {
+            let id = format!("tx:{}/{}", origin.tx, i + 1);
+            nodes.push(Node {
+                id: id.clone(),
+                kind: "step",
+                type_: Some(step.type_.name()),
+                tx: Some(origin.tx),
+                amount: step.amount.map(rounded),
+                timestamp: step.timestamp,
+                ..Node::default()
+            });
+            edges.push(Edge::new(previous, id.clone(), "step"));
+            previous = id;
+        }
+    }
+
+    serde_json::to_writer(&mut w, &Graph { nodes, edges })?;
+    w.write_all(b"\n")?;
+    w.flush()
+}
+
+#[derive(Serialize)]
+struct Graph<'a> {
+    nodes: Vec<Node<'a>>,
+    edges: Vec<Edge>,
+}
+
+#[derive(Default, Serialize)]
+struct Node<'a> {
+    id: String,
+    kind: &'static str,
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    type_: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client: Option<ClientId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    to: Option<ClientId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tx: Option<TransactionId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    amount: Option<Decimal>,
+    #[serde(skip_serializing_if = "str::is_empty")]
+    currency: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timestamp: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct Edge {
+    from: String,
+    to: String,
+    relation: &'static str,
+}
+
+impl Edge {
+    fn new(from: String, to: String, relation: &'static str) -> Self {
+        Edge { from, to, relation }
+    }
+}
+
+fn currency_suffix(currency: &str) -> String {
+    if currency.is_empty() {
+        String::new()
+    } else {
+        format!(" {}", currency)
+    }
+}
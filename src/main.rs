@@ -1,45 +1,2156 @@
-mod account;
-mod transaction;
+mod cli;
+mod explore;
+mod progress;
+mod repl;
+mod resume;
 
 use std::env;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::iter;
+use std::path::Path;
+use std::process;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, OnceLock};
+use std::time::Instant;
 
-use csv::{ReaderBuilder, Trim};
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser};
+use serde::ser::{Serialize, SerializeMap, Serializer};
+use tracing::{info_span, warn};
+#[cfg(feature = "logging")]
+use tracing_subscriber::filter::EnvFilter;
 
-use transaction::TransactionEngine;
+use csv::StringRecord;
+use te::account;
+use te::amount;
+use te::audit::{AccountStream, AuditLog, RejectionLog};
+#[cfg(feature = "signed-audit")]
+use te::audit::{AuditChain, SignedAuditLog};
+use te::clock::{Clock, SimulatedClock, SystemClock};
+use te::config::EngineConfig;
+use te::diff;
+use te::encryption::{self, StateKey};
+use te::events;
+use te::fees::FeeSchedule;
+use te::input::{self, reader_builder, ColumnOrder, Delimiter, FileOrder, Record};
+#[cfg(feature = "spill")]
+use te::ledger::SpillLedger;
+use te::limits::{self, BalanceLimits, WindowLimits};
+use te::lineage;
+use te::risk::RiskRules;
+use te::scheduler::Scheduler;
+use te::sequence::SequenceChecker;
+use te::summary::{ProcessSummary, RunReport};
+use te::tenant::MultiTenantEngine;
+use te::transaction::{
+    self, ClientFilter, Error, Outcome, Transaction, TransactionEngine, Warning,
+};
+use te::validator::{MaxAmount, MaxDailyWithdrawal, Velocity};
+use te::AccountColumn;
 
-fn main() {
-    let input_path = env::args()
-        .nth(1)
-        .expect("Please specify the input file path.");
+use crate::progress::Progress;
+use crate::resume::{Position, ResumePoint};
+
+use crate::cli::{
+    Cli, Command, ConsumeArgs, DiffArgs, EngineArgs, HistoryArgs, InputFormat, InspectArgs,
+    LineageFormat, LoggingArgs, MergeArgs, OutputFormat, ProcessArgs, RepairArgs, ResumeAfter,
+    ServeArgs, SnapshotArgs, StateArgs, StatementArgs, StatementFormat, VerifyAuditArgs,
+};
+
+// Exit code used for errors that abort the command, mostly I/O errors (i.e. an unwritable
+// output file, or a checkpoint that can't be read).
+const EXIT_FAILURE: i32 = 1;
+// Exit code used with `--strict` when processing was aborted because the engine rejected a
+// transaction.
+const EXIT_REJECTED: i32 = 2;
+// Exit code used with `--strict` when processing was aborted because of an invalid record.
+const EXIT_ABORTED: i32 = 3;
+// Exit code used when the input could not be read (i.e. because it exceeds the size limit).
+const EXIT_INPUT_ERROR: i32 = 4;
+// Exit code used by `diff` when the accounts differ, and by `repair` when there's anything
+// to repair (without `--fix`).
+const EXIT_DISCREPANCIES: i32 = 5;
+// Exit code used for invalid command line arguments.
+const EXIT_USAGE: i32 = 6;
+// Exit code used when an engine state is inconsistent (i.e. a checkpoint no sequence of
+// transactions leads to), or an invariant check failed (see `--check-invariants`).
+const EXIT_INVARIANT: i32 = 7;
+// Exit code used with `--strict` and `--check-sequence` when processing was aborted because
+// of a gap or regression in the sequence numbers of the input.
+const EXIT_SEQUENCE: i32 = 8;
+
+// Set by `--json-errors`, which reports the errors that terminate the process as JSON
+// objects on stderr (see `report_error`).
+static JSON_ERRORS: AtomicBool = AtomicBool::new(false);
+
+// Set from `--state-key-file` (or `TE_STATE_KEY`), which encrypts the checkpoints that get
+// saved, and decrypts the ones that get loaded (see `read_state` and `write_state`).
+static STATE_KEY: OnceLock<StateKey> = OnceLock::new();
+
+// Upper bound for the number of records read ahead of processing with `--http`.
+#[cfg(feature = "http")]
+const QUEUED_EVENTS: usize = 1024;
+
+// What the processing loop works through: the input records, and with `--http` also the
+// requests of the HTTP server, which are run on the engine in between records. Nearly every
+// event is a record, so they aren't boxed (which would allocate for each one).
+#[allow(clippy::large_enum_variant)]
+enum Event {
+    // A record, and the position of the input after it.
+    Record(Record, Position),
+    #[cfg(feature = "http")]
+    Request(te::async_engine::EngineRequest),
+    // The input has been read completely, while the HTTP server keeps going.
+    #[cfg(feature = "http")]
+    InputDone,
+}
+
+// Reports an error that can't be recovered from, and terminates the process with `code`.
+fn exit_with<E: fmt::Display>(code: i32, context: &str, e: E) -> ! {
+    report_error(code, Some(context), &e.to_string(), None);
+    process::exit(code);
+}
+
+// Writes an error to stderr, as text or (with `--json-errors`) as a JSON object with the kind
+// of error (see `error_kind`), the exit code, and the line of the input for errors about it.
+fn report_error(code: i32, context: Option<&str>, message: &str, line: Option<u64>) {
+    if !json_errors() {
+        match context {
+            Some(context) => eprintln!("error: {}: {}", context, message),
+            None => eprintln!("error: {}", message),
+        }
+        return;
+    }
+
+    let mut report = serde_json::json!({
+        "error": error_kind(code),
+        "exit_code": code,
+        "message": message,
+    });
+    if let Some(context) = context {
+        report["context"] = context.into();
+    }
+    if let Some(line) = line {
+        report["line"] = line.into();
+    }
+    eprintln!("{}", report);
+}
+
+// Usage errors are reported before the arguments have been parsed, so the flag gets looked up
+// by itself then.
+fn json_errors() -> bool {
+    JSON_ERRORS.load(Ordering::Relaxed) || env::args_os().any(|arg| arg == "--json-errors")
+}
+
+// The kind of error reported by `--json-errors` for an exit code.
+fn error_kind(code: i32) -> &'static str {
+    match code {
+        EXIT_REJECTED => "rejected",
+        EXIT_ABORTED => "invalid_record",
+        EXIT_INPUT_ERROR => "input",
+        EXIT_USAGE => "usage",
+        EXIT_INVARIANT => "invariant",
+        EXIT_SEQUENCE => "sequence",
+        _ => "failure",
+    }
+}
+
+fn fail<E: fmt::Display>(context: &str, e: E) -> ! {
+    exit_with(EXIT_FAILURE, context, e)
+}
+
+// Reports a failure to open or read the input, and terminates the process.
+fn input_error<E: fmt::Display>(context: &str, e: E) -> ! {
+    exit_with(EXIT_INPUT_ERROR, context, e)
+}
+
+// Reports an inconsistent engine state, and terminates the process.
+fn invariant_error<E: fmt::Display>(context: &str, e: E) -> ! {
+    exit_with(EXIT_INVARIANT, context, e)
+}
+
+// Terminates the process once the state of the engine can't be trusted anymore, because the
+// ledger failed along the way, or because the transaction broke an invariant.
+fn check_engine_state(result: &Result<(), Error>) {
+    match result {
+        Err(e @ Error::StorageFailed { .. }) => fail("Unable to process the input", e),
+        Err(e @ Error::InvariantViolated { .. }) => invariant_error("Invariant violated", e),
+        _ => {}
+    }
+}
+
+// Stops processing in strict mode, because of the record on `line`. The errors report gets
+// completed first, so it covers the offending record as well.
+fn abort_strict<W: Write, E: fmt::Display>(
+    code: i32,
+    line: u64,
+    rejection_log: Option<RejectionLog<W>>,
+    e: E,
+) -> ! {
+    if let Some(log) = rejection_log {
+        log.finish()
+            .unwrap_or_else(|e| fail("Unable to write to the errors file", e));
+    }
+    let context = format!("Aborting on line {}", line);
+    report_error(code, Some(&context), &e.to_string(), Some(line));
+    process::exit(code);
+}
+
+// Checks the sequence number of `t` on `line` (with `--check-sequence`). Anomalies only get
+// reported, since the record itself is fine, unless in strict mode, where processing stops
+// before the record (the records missing from a gap come before it).
+fn check_sequence<W: Write>(
+    checker: Option<&mut SequenceChecker>,
+    t: &Transaction,
+    line: u64,
+    summary: &mut ProcessSummary,
+    rejection_log: &mut Option<RejectionLog<W>>,
+    strict: bool,
+) {
+    let anomaly = match checker.and_then(|checker| checker.check(t)) {
+        Some(anomaly) => anomaly,
+        None => return,
+    };
+    warn!("Sequence anomaly: {}", anomaly);
+    summary.record_sequence_anomaly(&anomaly);
+    if let Some(log) = rejection_log.as_mut() {
+        log.record_sequence_anomaly(line, t, &anomaly)
+            .unwrap_or_else(|e| fail("Unable to write to the errors file", e));
+    }
+    if strict {
+        abort_strict(EXIT_SEQUENCE, line, rejection_log.take(), anomaly);
+    }
+}
+
+// Reports invalid command line arguments the same way `clap` does, but with `EXIT_USAGE`
+// (`clap` uses 2 otherwise, which already means something else with `--strict`). Help and
+// version output go to stdout as usual.
+fn usage_error(e: clap::Error) -> ! {
+    if !e.use_stderr() {
+        let _ = e.print();
+        process::exit(0);
+    }
+    if json_errors() {
+        let rendered = e.render().to_string();
+        let message = rendered.lines().next().unwrap_or_default();
+        report_error(
+            EXIT_USAGE,
+            None,
+            message.trim_start_matches("error: "),
+            None,
+        );
+    } else {
+        let _ = e.print();
+    }
+    process::exit(EXIT_USAGE);
+}
+
+// Checkpoint files with a `.toml` extension use the human-editable TOML format, while JSON
+// is used otherwise.
+fn is_toml(path: &str) -> bool {
+    path.ends_with(".toml")
+}
+
+fn load_checkpoint(path: &str) -> TransactionEngine {
+    let state = read_state(path)
+        .unwrap_or_else(|e| fail(&format!("Unable to open the checkpoint `{}`", path), e));
+    if is_toml(path) {
+        TransactionEngine::load_toml(state.as_slice())
+    } else {
+        TransactionEngine::load_checkpoint(state.as_slice())
+    }
+    .unwrap_or_else(|e| fail(&format!("Unable to load the checkpoint `{}`", path), e))
+}
+
+fn save_checkpoint(engine: &TransactionEngine, path: &str) {
+    let mut state = Vec::new();
+    if is_toml(path) {
+        engine.save_toml(&mut state)
+    } else {
+        engine.save_checkpoint(&mut state)
+    }
+    .unwrap_or_else(|e| fail(&format!("Unable to save the checkpoint `{}`", path), e));
+    write_state(path, &state)
+        .unwrap_or_else(|e| fail(&format!("Unable to save the checkpoint `{}`", path), e));
+}
+
+// Reads the saved state at `path`, decrypting it if it's encrypted. Plaintext states are
+// loaded as they are even with a key, so existing ones can be encrypted by saving them again.
+fn read_state<P: AsRef<Path>>(path: P) -> io::Result<Vec<u8>> {
+    let state = fs::read(path)?;
+    if !encryption::is_encrypted(&state) {
+        return Ok(state);
+    }
+    match STATE_KEY.get() {
+        Some(key) => encryption::decrypt(key, &state),
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the state is encrypted, and needs the key (see `--state-key-file`)",
+        )),
+    }
+}
+
+// Writes `state` to `path`, encrypted if there's a key.
+fn write_state<P: AsRef<Path>>(path: P, state: &[u8]) -> io::Result<()> {
+    let mut file = File::create(path)?;
+    match STATE_KEY.get() {
+        Some(key) => file.write_all(&encryption::encrypt(key, state)?)?,
+        None => file.write_all(state)?,
+    }
+    file.sync_all()
+}
+
+fn save_resume_point(
+    dir: &str,
+    engine: &TransactionEngine,
+    position: Position,
+    headers: &Option<Vec<String>>,
+) {
+    ResumePoint::save(dir, engine, position, headers.clone())
+        .unwrap_or_else(|e| fail(&format!("Unable to save a checkpoint in `{}`", dir), e));
+}
+
+// Writes the accounts as of `records` input records for `--emit-snapshots-every`: into a new
+// file of `--snapshot-dir` (named after the time and `records`), or over `--snapshot-file`,
+// which gets written to a temporary file first and then renamed, so readers never see it
+// truncated.
+fn emit_snapshot(options: &ProcessArgs, engine: &TransactionEngine, records: u64) {
+    let columns = options.output_columns.as_deref();
+    if let Some(dir) = &options.snapshot_dir {
+        let now = SystemClock.now().unwrap_or_default();
+        let name = format!(
+            "accounts-{}-{:010}.{}",
+            now,
+            records,
+            output_extension(options.format)
+        );
+        let path = Path::new(dir).join(name);
+        let output = create_output(Some(&path.to_string_lossy()));
+        write_accounts(engine, output, options.format, columns);
+    }
+    if let Some(path) = &options.snapshot_file {
+        let tmp = format!("{}.tmp", path);
+        write_accounts(engine, create_output(Some(&tmp)), options.format, columns);
+        fs::rename(&tmp, path)
+            .unwrap_or_else(|e| fail(&format!("Unable to replace `{}`", path), e));
+    }
+}
+
+// Opens the file at `path` for the output, or stdout when there's no path.
+fn create_output(path: Option<&str>) -> Box<dyn Write + Send> {
+    match path {
+        Some(path) => {
+            let file = File::create(path)
+                .unwrap_or_else(|e| fail(&format!("Unable to create `{}`", path), e));
+            Box::new(BufWriter::new(file))
+        }
+        None => Box::new(io::stdout()),
+    }
+}
+
+// Writes the account report, as CSV, as a JSON array with the same fields, as Parquet, or as
+// a table, with only the given columns if any (which are rejected for Parquet upfront).
+fn write_accounts(
+    engine: &TransactionEngine,
+    mut output: Box<dyn Write + Send>,
+    format: OutputFormat,
+    columns: Option<&[AccountColumn]>,
+) {
+    match (format, columns) {
+        (OutputFormat::Csv, None) => engine.write_accounts(&mut output).map_err(io::Error::from),
+        (OutputFormat::Csv, Some(columns)) => engine
+            .write_accounts_with(&mut output, columns)
+            .map_err(io::Error::from),
+        (OutputFormat::Json, None) => serde_json::to_writer(&mut output, &engine.account_records())
+            .map_err(io::Error::from)
+            .and_then(|()| writeln!(output)),
+        (OutputFormat::Json, Some(columns)) => selected_records(engine, columns)
+            .and_then(|records| serde_json::to_writer(&mut output, &records))
+            .map_err(io::Error::from)
+            .and_then(|()| writeln!(output)),
+        (OutputFormat::Parquet, _) => write_parquet(engine, &mut output),
+        (OutputFormat::Table, columns) => {
+            let columns = columns.map_or_else(|| engine.account_columns(), <[_]>::to_vec);
+            engine.write_accounts_table(&mut output, &columns)
+        }
+    }
+    .and_then(|()| output.flush())
+    .unwrap_or_else(|e| fail("Unable to write the accounts", e));
+}
+
+// Writes the funds paid out when closing accounts as CSV (where only multi-asset payouts have
+// a `currency` column), which leaves the file empty if there were none.
+fn write_payouts(engine: &mut TransactionEngine, path: &str) -> csv::Result<()> {
+    let file = File::create(path)?;
+    let mut writer = csv::WriterBuilder::new()
+        .flexible(true)
+        .from_writer(BufWriter::new(file));
+    for payout in engine.drain_payouts() {
+        writer.serialize(payout)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+// Prints `warning` (prefixed with `prefix`) with `--warnings`. Transactions bypassing the
+// velocity limits are pointed out regardless, but only once, since input without timestamps
+// bypasses them altogether.
+fn report_warning(warning: &Warning, prefix: &str, all: bool, bypass_reported: &mut bool) {
+    if all {
+        eprintln!("warning: {}{}", prefix, warning);
+    } else if matches!(warning, Warning::LimitBypassed { .. }) && !*bypass_reported {
+        *bypass_reported = true;
+        eprintln!(
+            "warning: {}{} (further ones are only reported with `--warnings`)",
+            prefix, warning
+        );
+    }
+}
+
+// Starts the errors report (`--errors`) in the file at `path`, or on stdout for `-` (where it
+// comes before the account report, which only gets written at the end).
+fn open_rejection_log(path: &str) -> RejectionLog<Box<dyn Write>> {
+    let w: Box<dyn Write> = if path == "-" {
+        Box::new(BufWriter::new(io::stdout()))
+    } else {
+        let file =
+            File::create(path).unwrap_or_else(|e| fail(&format!("Unable to create `{}`", path), e));
+        Box::new(BufWriter::new(file))
+    };
+    RejectionLog::new(w).unwrap_or_else(|e| fail("Unable to write to the errors file", e))
+}
+
+// Writes the end-of-run report as JSON to the file at `path`, or to stderr for `-`.
+fn write_report(report: &RunReport, path: &str) -> io::Result<()> {
+    if path == "-" {
+        let mut stderr = io::stderr().lock();
+        report.write_json(&mut stderr)?;
+        return writeln!(stderr);
+    }
+    let mut file = BufWriter::new(File::create(path)?);
+    report.write_json(&mut file)?;
+    writeln!(file)?;
+    file.flush()
+}
+
+// A row of the account report with only the selected columns, in order (as far as the report
+// has them), for writing it as JSON.
+struct SelectedRecord<'a> {
+    record: serde_json::Value,
+    columns: &'a [AccountColumn],
+}
+
+impl Serialize for SelectedRecord<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+        for column in self.columns {
+            if let Some(value) = self.record.get(column.name()) {
+                map.serialize_entry(column.name(), value)?;
+            }
+        }
+        map.end()
+    }
+}
+
+fn selected_records<'a>(
+    engine: &TransactionEngine,
+    columns: &'a [AccountColumn],
+) -> serde_json::Result<Vec<SelectedRecord<'a>>> {
+    engine
+        .account_records()
+        .iter()
+        .map(|record| {
+            let record = serde_json::to_value(record)?;
+            Ok(SelectedRecord { record, columns })
+        })
+        .collect()
+}
+
+// The Parquet schema is fixed, so columns can't be selected for it.
+fn check_output_columns(format: OutputFormat, columns: Option<&[AccountColumn]>) {
+    if columns.is_some() && matches!(format, OutputFormat::Parquet) {
+        usage_error(Cli::command().error(
+            ErrorKind::ArgumentConflict,
+            "`--output-columns` can't be used with Parquet",
+        ));
+    }
+}
+
+// Writes the counts of `summary` for `--dry-run`, as CSV or JSON.
+fn write_dry_run_report(
+    summary: &ProcessSummary,
+    mut output: Box<dyn Write + Send>,
+    format: OutputFormat,
+) {
+    match format {
+        OutputFormat::Csv => summary.write_csv(&mut output).map_err(io::Error::from),
+        OutputFormat::Json => summary
+            .write_json(&mut output)
+            .map_err(io::Error::from)
+            .and_then(|()| writeln!(output)),
+        OutputFormat::Parquet => unreachable!("rejected with the arguments"),
+        OutputFormat::Table => writeln!(output, "{}", summary),
+    }
+    .and_then(|()| output.flush())
+    .unwrap_or_else(|e| fail("Unable to write the dry run report", e));
+}
+
+#[cfg(feature = "arrow")]
+fn write_parquet<W: Write + Send>(engine: &TransactionEngine, output: W) -> io::Result<()> {
+    te::arrow::write_parquet(engine, output).map_err(io::Error::other)
+}
+
+#[cfg(not(feature = "arrow"))]
+fn write_parquet<W: Write + Send>(_engine: &TransactionEngine, _output: W) -> io::Result<()> {
+    Err(io::Error::other(
+        "`te` was built without the `arrow` feature",
+    ))
+}
+
+#[cfg(feature = "sqlite")]
+fn export_sqlite(engine: &TransactionEngine, path: &str) -> io::Result<()> {
+    te::sqlite::export(engine, path).map_err(io::Error::other)
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn export_sqlite(_engine: &TransactionEngine, _path: &str) -> io::Result<()> {
+    Err(io::Error::other(
+        "`te` was built without the `sqlite` feature",
+    ))
+}
+
+// The records of the input, along with the position of the input after each of them.
+struct Input<'a> {
+    records: Box<dyn Iterator<Item = (Record, Position)> + 'a>,
+    // The header row of CSV input, which has to be checkpointed along with the position.
+    headers: Option<Vec<String>>,
+}
+
+impl<'a> Input<'a> {
+    fn records(self) -> Box<dyn Iterator<Item = Record> + 'a> {
+        Box::new(self.records.map(|(record, _)| record))
+    }
+}
+
+// Sets up the CSV reader for `input`, which starts at `start`, and returns an iterator over
+// the records. Reading records one at a time (instead of via `deserialize`) keeps track of the
+// line each of them starts on, while still reusing the same record buffer throughout. When
+// resuming past the header row, the `headers` it had are used instead.
+fn csv_transactions<'a, R: Read + 'a>(
+    input: R,
+    options: &'a ProcessArgs,
+    delimiter: Delimiter,
+    start: Position,
+    headers: Option<Vec<String>>,
+) -> Input<'a> {
+    let resumed = start.offset > 0;
+    // Detecting the delimiter reads the first line ahead, which gets put back in front of the
+    // rest of the input afterwards.
+    let mut input = BufReader::new(input);
+    let mut first_line = Vec::new();
+    let delimiter = match delimiter {
+        Delimiter::Byte(delimiter) => delimiter,
+        Delimiter::Detect => match input.read_until(b'\n', &mut first_line) {
+            Ok(_) => input::detect_delimiter(&first_line),
+            Err(e) => input_error("Unable to read the input", e),
+        },
+    };
+    let input = io::Cursor::new(first_line).chain(input);
+    let mut reader = reader_builder(options.has_headers() && !resumed)
+        .delimiter(delimiter)
+        .from_reader(input);
+
+    // The header row is otherwise read lazily, and any I/O error encountered while doing so
+    // would be reported as an invalid record.
+    let headers = if resumed {
+        headers.map(StringRecord::from)
+    } else if reader.has_headers() {
+        match reader.headers() {
+            Ok(headers) => {
+                if let Err(e) = ColumnOrder::new(headers) {
+                    input_error("Invalid input header", e);
+                }
+                Some(headers.clone())
+            }
+            Err(e) => input_error("Unable to read the input", e),
+        }
+    } else {
+        None
+    };
+
+    let saved_headers = headers
+        .as_ref()
+        .map(|h| h.iter().map(String::from).collect());
+    let columns = options.column_order();
+    if let Some(threads) = options.parse_threads {
+        return Input {
+            records: pipelined_records(reader, threads, columns, headers, start),
+            headers: saved_headers,
+        };
+    }
+
+    // Positions of the reader are relative to where it started.
+    let position = move |reader: &csv::Reader<_>| Position {
+        offset: start.offset + reader.position().byte(),
+        line: start.line - 1 + reader.position().line(),
+    };
+
+    let mut record = StringRecord::new();
+    let records = iter::from_fn(move || {
+        let line = position(&reader).line;
+        match reader.read_record(&mut record) {
+            Ok(true) => {}
+            Ok(false) => return None,
+            Err(e) => return Some(((line, Err(e.into())), position(&reader))),
+        }
+
+        // Header-less input gets normalized to the canonical order first, since columns are
+        // mapped positionally.
+        let line = record
+            .position()
+            .map_or(line, |p| start.line - 1 + p.line());
+        let result = match &columns {
+            Some(order) => order.normalize(&record).deserialize(None),
+            None => record.deserialize(headers.as_ref()),
+        };
+        Some(((line, result.map_err(Into::into)), position(&reader)))
+    });
+
+    Input {
+        records: Box::new(records),
+        headers: saved_headers,
+    }
+}
+
+// Same as the records of `csv_transactions`, but deserialized on a pool of `threads` threads
+// (see `pipeline::deserialize_ordered`), with the raw records read on this one.
+#[cfg(feature = "rayon")]
+fn pipelined_records<'a, R: Read + 'a>(
+    mut reader: csv::Reader<R>,
+    threads: usize,
+    columns: Option<ColumnOrder>,
+    headers: Option<StringRecord>,
+    start: Position,
+) -> Box<dyn Iterator<Item = (Record, Position)> + 'a> {
+    use te::pipeline;
+
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .thread_name(|index| format!("parse-{}", index))
+        .build()
+        .unwrap_or_else(|e| fail("Unable to start the deserialization threads", e));
+    let deserialize = move |record: &StringRecord| match &columns {
+        Some(order) => order.normalize(record).deserialize(None),
+        None => record.deserialize(headers.as_ref()),
+    };
+
+    let position = move |reader: &csv::Reader<_>| Position {
+        offset: start.offset + reader.position().byte(),
+        line: start.line - 1 + reader.position().line(),
+    };
+    // Records can't share a buffer here, since they're deserialized later.
+    let raw = iter::from_fn(move || {
+        let line = position(&reader).line;
+        let mut record = StringRecord::new();
+        match reader.read_record(&mut record) {
+            Ok(true) => {}
+            Ok(false) => return None,
+            Err(e) => return Some((line, Err(e), position(&reader))),
+        }
+        let line = record
+            .position()
+            .map_or(line, |p| start.line - 1 + p.line());
+        Some((line, Ok(record), position(&reader)))
+    });
+
+    Box::new(pipeline::deserialize_ordered(
+        raw,
+        Arc::new(pool),
+        Arc::new(deserialize),
+    ))
+}
+
+#[cfg(not(feature = "rayon"))]
+fn pipelined_records<'a, R: Read + 'a>(
+    _reader: csv::Reader<R>,
+    _threads: usize,
+    _columns: Option<ColumnOrder>,
+    _headers: Option<StringRecord>,
+    _start: Position,
+) -> Box<dyn Iterator<Item = (Record, Position)> + 'a> {
+    fail(
+        "Unable to deserialize the input in parallel",
+        "`te` was built without the `rayon` feature",
+    );
+}
+
+// The files to read the input from (see `input::input_files`), which is stdin (`-`) unless
+// any are given.
+fn input_files(options: &ProcessArgs) -> Vec<String> {
+    if options.input_paths.is_empty() {
+        return vec!["-".to_owned()];
+    }
+    let order = if options.by_mtime {
+        FileOrder::ModificationTime
+    } else {
+        FileOrder::Name
+    };
+    input::input_files(&options.input_paths, order)
+        .unwrap_or_else(|e| input_error("Unable to find the input files", e))
+}
+
+// Opens the input files, and returns their records in the configured format as one stream.
+// A single file can start at `start` (i.e. the position of a checkpoint, along with the
+// header row of the input). The bytes read from the files are added to `counter`, if given.
+fn open_transactions(
+    options: &ProcessArgs,
+    files: Vec<String>,
+    start: Position,
+    headers: Option<Vec<String>>,
+    counter: Option<Arc<AtomicU64>>,
+) -> Input<'_> {
+    match files.as_slice() {
+        [file] => open_file(options, file, start, headers, counter),
+        _ => {
+            // Every file gets a reader of its own (so CSV files all start with a header row),
+            // which is only opened once the previous file is done.
+            let records = files.into_iter().flat_map(move |file| {
+                open_file(options, &file, Position::default(), None, counter.clone()).records
+            });
+            Input {
+                records: Box::new(records),
+                headers: None,
+            }
+        }
+    }
+}
+
+// Opens the input file at `path` (see `open_transactions`).
+fn open_file<'a>(
+    options: &'a ProcessArgs,
+    path: &str,
+    start: Position,
+    headers: Option<Vec<String>>,
+    counter: Option<Arc<AtomicU64>>,
+) -> Input<'a> {
+    let mut input = input::open_counted(Some(path), options.max_input_bytes, counter)
+        .unwrap_or_else(|e| input_error("Unable to open the input file", e));
+    resume::skip_to(&mut input, start)
+        .unwrap_or_else(|e| input_error("Unable to resume reading the input", e));
+
+    match options.input_format(path) {
+        InputFormat::Csv => {
+            let delimiter = options.delimiter(path);
+            csv_transactions(input, options, delimiter, start, headers)
+        }
+        InputFormat::JsonLines => {
+            let records = input::json_lines_with_offsets(input);
+            offset_records(records, start)
+        }
+        #[cfg(feature = "protobuf")]
+        InputFormat::Protobuf => offset_records(te::protobuf::records_with_offsets(input), start),
+    }
+}
+
+// Numbers the `records` of line-based (or message-based) input read from `start` on, along
+// with the positions to resume reading from after each of them.
+fn offset_records<'a, I>(records: I, start: Position) -> Input<'a>
+where
+    I: Iterator<Item = (Record, u64)> + 'a,
+{
+    let records = records.map(move |((line, result), offset)| {
+        let record = (start.line - 1 + line, result);
+        let position = Position {
+            offset: start.offset + offset,
+            line: record.0 + 1,
+        };
+        (record, position)
+    });
+    Input {
+        records: Box::new(records),
+        headers: None,
+    }
+}
+
+// Processes `transactions` on `threads` worker threads. Invalid records are only logged and
+// counted, same as when processing sequentially. The progress is reported as the records are
+// read.
+fn process_parallel(
+    engine: &mut TransactionEngine,
+    transactions: Box<dyn Iterator<Item = Record> + '_>,
+    threads: usize,
+    mut progress: Option<&mut Progress>,
+) -> ProcessSummary {
+    let mut invalid = 0;
+    let mut read = 0;
+    let valid = transactions
+        .inspect(|_| {
+            read += 1;
+            if let Some(progress) = progress.as_mut() {
+                progress.update(read);
+            }
+        })
+        .filter_map(|(_, result)| match result {
+            Ok(t) => Some(t),
+            Err(e) if e.is_io_error() => input_error("Unable to read the input", e),
+            Err(e) => {
+                warn!("Skipping invalid record: {}", e);
+                invalid += 1;
+                None
+            }
+        });
+
+    let mut summary = engine.process_parallel(valid, threads);
+    // The shards don't stop at the first broken invariant, so it's only reported afterwards.
+    if summary.errors.contains_key("InvariantViolated") {
+        invariant_error(
+            "Invariant violated",
+            "a transaction left the engine state inconsistent",
+        );
+    }
+    summary.records += invalid;
+    summary.invalid += invalid;
+    summary
+}
+
+// Sets up logging to stderr. The filter is taken from the `RUST_LOG` environment variable
+// (i.e. `RUST_LOG=warn`, which only shows errors by default), unless `--log-level` is given.
+#[cfg(feature = "logging")]
+fn init_logging(options: &LoggingArgs) {
+    use std::io::IsTerminal;
+
+    use crate::cli::LogFormat;
+
+    let filter = match options.log_level {
+        Some(level) => EnvFilter::default().add_directive(level.into()),
+        None => EnvFilter::from_default_env(),
+    };
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_ansi(io::stderr().is_terminal())
+        .with_writer(io::stderr);
+
+    match options.log_format {
+        Some(LogFormat::Json) => builder.json().init(),
+        Some(LogFormat::Pretty) => builder.pretty().init(),
+        None => builder.init(),
+    }
+}
+
+// Without the `logging` feature nothing gets logged, so only explicitly asking for logs is an
+// error.
+#[cfg(not(feature = "logging"))]
+fn init_logging(options: &LoggingArgs) {
+    if options.log_level.is_some() || options.log_format.is_some() {
+        fail(
+            "Unable to set up logging",
+            "`te` was built without the `logging` feature",
+        );
+    }
+}
+
+// An engine which spills its retained transactions to `dir` (see `SpillLedger`), beyond the
+// `hot` ones kept in memory.
+#[cfg(feature = "spill")]
+fn spilling_engine(dir: &str, hot: usize) -> TransactionEngine {
+    let ledger = SpillLedger::open(dir, hot)
+        .unwrap_or_else(|e| fail(&format!("Unable to create the spill files in `{}`", dir), e));
+    TransactionEngine::new_with_storage(ledger)
+        .unwrap_or_else(|e| fail(&format!("Unable to read the spill files in `{}`", dir), e))
+}
+
+#[cfg(not(feature = "spill"))]
+fn spilling_engine(dir: &str, _hot: usize) -> TransactionEngine {
+    fail(
+        &format!("Unable to create the spill files in `{}`", dir),
+        "`te` was built without the `spill` feature",
+    );
+}
+
+// Sets up the engine as configured via `options`, starting from the checkpoint to load (if
+// any).
+fn build_engine(options: &EngineArgs) -> TransactionEngine {
+    let base = options
+        .load_checkpoint
+        .as_deref()
+        .map(|path| (load_checkpoint(path), path));
+    configure_engine(options, base)
+}
+
+// Same as `build_engine`, but starting from the given engine (along with the path it was
+// loaded from) instead of the checkpoint to load.
+fn configure_engine(
+    options: &EngineArgs,
+    base: Option<(TransactionEngine, &str)>,
+) -> TransactionEngine {
+    if let Some(chars) = &options.amount_format_chars {
+        amount::set_formatting_chars(chars);
+    }
+
+    let (mut engine, source) = match base {
+        Some((engine, path)) => (engine, Some(path)),
+        None => match &options.spill_dir {
+            Some(dir) => (spilling_engine(dir, options.hot_transactions), None),
+            None => (TransactionEngine::new(), None),
+        },
+    };
+    if let Some(path) = &options.config {
+        let context = format!("Unable to load the engine configuration `{}`", path);
+        let file = File::open(path).unwrap_or_else(|e| fail(&context, e));
+        let config = EngineConfig::load(BufReader::new(file)).unwrap_or_else(|e| fail(&context, e));
+        engine = config.apply(engine);
+    }
+    let mut engine = engine
+        .with_track_all_clients(options.track_all_clients)
+        .with_disputes_disabled(options.no_disputes)
+        .with_quarantine(options.quarantine, options.quarantine_retries)
+        .with_time_ordering(options.time_ordering.into())
+        .with_admin_ops(options.allow_admin_ops)
+        .with_residual_balance_policy(options.residual_balance.into())
+        .with_wal_snapshot_interval(options.wal_snapshot_interval)
+        .with_wal_sync(options.wal_sync)
+        .with_replay_dedup(options.dedup);
+
+    // The settings a configuration file may have as well only change the engine when given,
+    // so they don't undo that.
+    if options.signed_amounts {
+        engine = engine.with_signed_amounts(true);
+    }
+    if let Some(epsilon) = options.amount_epsilon {
+        engine = engine.with_amount_epsilon(epsilon);
+    }
+    if options.allow_zero_amounts {
+        engine = engine.with_zero_amounts_allowed(true);
+    }
+    if options.account_history {
+        engine = engine.with_account_history(true);
+    }
+    if let Some(max) = options.defer_disputes {
+        engine = engine.with_deferred_disputes(max);
+    }
+    if options.no_chargeback_freeze {
+        engine = engine.with_freeze_on_chargeback(false);
+    }
+    if options.unlock_on_reversal {
+        engine = engine.with_unlock_on_reversal(true);
+    }
+    if options.withdrawal_disputes {
+        engine = engine.with_withdrawal_disputes(true);
+    }
+    if options.allow_redispute {
+        engine = engine.with_redisputes(true);
+    }
+    if let Some(policy) = options.overdraft_policy {
+        engine = engine.with_overdraft_policy(policy.into());
+    }
+    if options.with_metadata {
+        engine = engine.with_metadata_columns(true);
+    }
+    if options.with_dispute_stats {
+        engine = engine.with_dispute_columns(true);
+    }
+
+    if let Some(policy) = options.frozen_policy {
+        engine = engine.with_frozen_policy(policy);
+    }
+    if let Some(disputes) = options.frozen_disputes {
+        engine = engine.with_frozen_disputes(disputes.into());
+    }
+    if let Some(policy) = options.zero_amounts {
+        engine = engine.with_zero_amount_policy(policy.into());
+    }
+    if !options.clients.is_empty() || !options.exclude_clients.is_empty() {
+        let included = options.clients.iter().cloned();
+        let filter = included.fold(ClientFilter::new(), ClientFilter::include);
+        let excluded = options.exclude_clients.iter().cloned();
+        engine = engine.with_client_filter(excluded.fold(filter, ClientFilter::exclude));
+    }
+    if let Some(rounding) = options.amount_rounding {
+        engine = engine.with_amount_rounding(rounding.into());
+    }
+    if let Some(limit) = options.history_limit {
+        engine = engine.with_history_limit(limit);
+    }
+    if let Some((clients, transactions)) = options.capacity {
+        engine.reserve(clients, transactions);
+    }
+    if let Some(max) = options.max_disputes {
+        engine = engine.with_max_disputes(max);
+    }
+    if let Some(window) = options.dispute_window {
+        engine = engine
+            .with_dispute_window(window)
+            .with_expired_eviction(options.evict_expired);
+    }
+    if options.simulated_time_from_timestamps {
+        engine = engine.with_clock(SimulatedClock::from_timestamps());
+    }
+    if let Some(max) = options.max_amount {
+        engine = engine.with_validator(MaxAmount(max.get()));
+    }
+    if let Some(limit) = options.max_daily_withdrawal {
+        engine = engine.with_validator(MaxDailyWithdrawal::new(limit));
+    }
+    if let Some((max, window)) = options.velocity {
+        engine = engine.with_validator(Velocity::new(max, window));
+    }
+    if !options.window_limit.is_empty() {
+        engine = engine.with_validator(WindowLimits::new(options.window_limit.clone()));
+    }
+    if let Some(path) = &options.risk_rules {
+        let context = format!("Unable to load the risk rules `{}`", path);
+        let file = File::open(path).unwrap_or_else(|e| fail(&context, e));
+        let rules = RiskRules::load(BufReader::new(file)).unwrap_or_else(|e| fail(&context, e));
+        engine = engine.with_risk_rules(rules);
+    }
+    if let Some(path) = &options.fees {
+        let context = format!("Unable to load the fee schedule `{}`", path);
+        let file = File::open(path).unwrap_or_else(|e| fail(&context, e));
+        let schedule =
+            FeeSchedule::load(BufReader::new(file)).unwrap_or_else(|e| fail(&context, e));
+        engine = engine.with_fee_schedule(schedule);
+    }
+    if options.minimum_balance.is_some() || options.overdraft_limit.is_some() {
+        engine = engine.with_balance_limits(BalanceLimits {
+            minimum_balance: options.minimum_balance.unwrap_or_default(),
+            overdraft_limit: options.overdraft_limit.unwrap_or_default(),
+        });
+    }
+    if let Some(path) = &options.balance_limits {
+        let context = format!("Unable to load the balance limits `{}`", path);
+        let file = File::open(path).unwrap_or_else(|e| fail(&context, e));
+        let clients =
+            limits::load_client_limits(BufReader::new(file)).unwrap_or_else(|e| fail(&context, e));
+        for (client, limits) in clients {
+            engine = engine.with_client_balance_limits(client, limits);
+        }
+    }
+    // Builds with the `invariants` feature check them regardless.
+    if options.check_invariants {
+        engine = engine.with_invariant_checks(true);
+    }
+
+    if let Some(path) = source {
+        if !engine.is_valid() {
+            invariant_error(path, "the checkpoint contains an inconsistent engine state");
+        }
+    }
+    if let Some(path) = &options.opening_balances {
+        let context = format!("Unable to load the opening balances `{}`", path);
+        let file = File::open(path).unwrap_or_else(|e| fail(&context, e));
+        let balances = account::read_opening_balances(BufReader::new(file))
+            .unwrap_or_else(|e| fail(&context, e));
+        for opening in balances {
+            let client = opening.client;
+            engine.open_balance(opening).unwrap_or_else(|e| {
+                fail(&context, format!("client {}: {}", client, e));
+            });
+        }
+    }
+
+    // Recovering before setting up the account stream, so replayed transactions don't show up
+    // in there.
+    if let Some(path) = &options.wal {
+        engine = engine
+            .recover(path)
+            .unwrap_or_else(|e| fail("Unable to recover from the write-ahead log", e));
+        if !engine.is_valid() {
+            invariant_error(
+                path,
+                "the write-ahead log contains an inconsistent engine state",
+            );
+        }
+    }
 
+    // Seeding after recovering as well, which would discard the metadata otherwise.
+    if let Some(path) = &options.accounts {
+        let context = format!("Unable to load the account metadata `{}`", path);
+        let file = File::open(path).unwrap_or_else(|e| fail(&context, e));
+        let metadata =
+            account::read_metadata(BufReader::new(file)).unwrap_or_else(|e| fail(&context, e));
+        for (client, metadata) in metadata {
+            engine.set_account_metadata(client, metadata);
+        }
+    }
+
+    if let Some(target) = &options.stream_accounts {
+        let mut stream = AccountStream::new(target.writer());
+        engine = engine.with_transaction_callback(move |transaction, result, account| {
+            stream
+                .record(transaction, result, account)
+                .unwrap_or_else(|e| fail("Unable to write to the account stream", e))
+        });
+    }
+
+    engine
+}
+
+// Runs the gRPC server until the process gets terminated. The engine runs on a dedicated
+// thread, which every request gets forwarded to.
+#[cfg(feature = "grpc")]
+fn serve(args: ServeArgs) {
+    use std::time::Duration;
+    use te::async_engine::AsyncTransactionEngine;
+    use te::rpc::EngineService;
+
+    let address = args
+        .listen
+        .parse()
+        .unwrap_or_else(|e| fail(&format!("Invalid listen address `{}`", args.listen), e));
+    // Binding right away, same as for the gRPC address.
+    let metrics_listener = args.metrics.as_deref().map(|address| {
+        let context = format!("Unable to bind the metrics address `{}`", address);
+        let listener = std::net::TcpListener::bind(address).unwrap_or_else(|e| fail(&context, e));
+        listener
+            .set_nonblocking(true)
+            .unwrap_or_else(|e| fail(&context, e));
+        listener
+    });
+
+    let runtime = tokio::runtime::Runtime::new()
+        .unwrap_or_else(|e| fail("Unable to start the async runtime", e));
+    let options = args.engine;
+    let metrics = metrics_listener.is_some();
+    let hold_sweep_interval = args.hold_sweep_interval;
+    runtime
+        .block_on(async move {
+            let engine =
+                AsyncTransactionEngine::spawn(move || build_engine(&options).with_metrics(metrics));
+
+            if let Some(listener) = metrics_listener {
+                let listener = tokio::net::TcpListener::from_std(listener)
+                    .unwrap_or_else(|e| fail("Unable to serve the metrics", e));
+                let router = te::rest::metrics_router(engine.clone());
+                tokio::spawn(async move {
+                    axum::serve(listener, router)
+                        .await
+                        .unwrap_or_else(|e| fail("Unable to serve the metrics", e));
+                });
+            }
+
+            if hold_sweep_interval > 0 {
+                let engine = engine.clone();
+                let period = Duration::from_secs(hold_sweep_interval);
+                tokio::spawn(async move {
+                    let mut interval = tokio::time::interval(period);
+                    loop {
+                        interval.tick().await;
+                        // By the clock of the engine, if it has one which knows the time.
+                        let released = engine
+                            .with_engine(|engine| {
+                                match engine.clock_time().or_else(|| SystemClock.now()) {
+                                    Some(now) => engine.release_expired_holds(now),
+                                    None => 0,
+                                }
+                            })
+                            .await;
+                        if released > 0 {
+                            tracing::info!(released, "Released expired holds");
+                        }
+                    }
+                });
+            }
+
+            tracing::info!(%address, "Serving the gRPC API");
+            tonic::transport::Server::builder()
+                .add_service(EngineService::new(engine).into_server())
+                .serve(address)
+                .await
+        })
+        .unwrap_or_else(|e| fail("Unable to serve the gRPC API", e));
+}
+
+#[cfg(not(feature = "grpc"))]
+fn serve(_args: ServeArgs) {
+    fail(
+        "Unable to serve the gRPC API",
+        "`te` was built without the `grpc` feature",
+    );
+}
+
+// Starts serving the HTTP API on `address`, and reading the input on a separate thread.
+// Returns the events for the processing loop, which are the requests of the HTTP server
+// interleaved with the input records.
+#[cfg(feature = "http")]
+fn serve_http(
+    options: &Arc<ProcessArgs>,
+    files: Vec<String>,
+    address: &str,
+    engine: &mut TransactionEngine,
+) -> mpsc::Receiver<Event> {
+    use std::{mem, thread};
+    use te::async_engine::AsyncTransactionEngine;
+    use te::rest::AccountNotifier;
+
+    // Binding right away, so an unusable address gets reported before processing anything.
+    let context = format!("Unable to bind the HTTP address `{}`", address);
+    let listener = std::net::TcpListener::bind(address).unwrap_or_else(|e| fail(&context, e));
+    listener
+        .set_nonblocking(true)
+        .unwrap_or_else(|e| fail(&context, e));
+
+    let (handle, requests) = AsyncTransactionEngine::detached();
+    // The engine is the one the processing loop runs the requests on, so the streamed
+    // account changes include the ones of the input.
+    let notifier = AccountNotifier::new();
+    *engine = mem::take(engine).with_observer(notifier.clone());
+    let router = te::rest::router(handle).merge(te::rest::notifications_router(notifier));
+
+    let (sender, events) = mpsc::sync_channel(QUEUED_EVENTS);
+
+    thread::spawn(move || {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .build()
+            .unwrap_or_else(|e| fail("Unable to start the async runtime", e));
+        runtime
+            .block_on(async move {
+                let listener = tokio::net::TcpListener::from_std(listener)?;
+                axum::serve(listener, router).await
+            })
+            .unwrap_or_else(|e| fail("Unable to serve the HTTP API", e));
+    });
+
+    let forward = sender.clone();
+    thread::spawn(move || {
+        for request in requests {
+            if forward.send(Event::Request(request)).is_err() {
+                break;
+            }
+        }
+    });
+
+    let options = Arc::clone(options);
+    thread::spawn(move || {
+        let input = open_transactions(&options, files, Position::default(), None, None);
+        for (record, position) in input.records {
+            if sender.send(Event::Record(record, position)).is_err() {
+                return;
+            }
+        }
+        let _ = sender.send(Event::InputDone);
+    });
+
+    events
+}
+
+#[cfg(not(feature = "http"))]
+fn serve_http(
+    _options: &Arc<ProcessArgs>,
+    _files: Vec<String>,
+    _address: &str,
+    _engine: &mut TransactionEngine,
+) -> mpsc::Receiver<Event> {
+    fail(
+        "Unable to serve the HTTP API",
+        "`te` was built without the `http` feature",
+    );
+}
+
+// Reports on the checkpoint at `args.state` (or the state after processing the transactions
+// there, for `.csv` paths): the accounts go to the output, and the totals to stderr (both as
+// of the given point, if any), unless the state gets explored interactively instead.
+// Inconsistent states are reported as a failure, once the accounts are written.
+fn inspect(args: &InspectArgs) {
+    check_output_columns(args.format, args.output_columns.as_deref());
+    let engine = if args.state.ends_with(".csv") {
+        processed_engine(&args.state)
+    } else {
+        load_checkpoint(&args.state)
+    };
+    let engine = engine
+        .with_withdrawal_disputes(args.withdrawal_disputes)
+        .with_metadata_columns(args.with_metadata)
+        .with_dispute_columns(args.with_dispute_stats);
+    if args.interactive {
+        if !engine.is_valid() {
+            invariant_error(&args.state, "the engine state is inconsistent");
+        }
+        explore::run(&engine).unwrap_or_else(|e| fail("Unable to interact with the terminal", e));
+        return;
+    }
+
+    let replayed = args.as_of.map(|as_of| {
+        engine
+            .as_of(as_of)
+            .unwrap_or_else(|e| fail("Unable to replay the history", e))
+    });
+    let reported = replayed.as_ref().unwrap_or(&engine);
+    write_accounts(
+        reported,
+        create_output(args.output_path.as_deref()),
+        args.format,
+        args.output_columns.as_deref(),
+    );
+
+    let open_disputes: usize = reported.accounts().map(|(_, a)| a.open_disputes()).sum();
+    eprintln!("{}", reported.aggregate());
+    eprintln!("open disputes: {}", open_disputes);
+    for mismatch in engine.audit() {
+        eprintln!("inconsistent: {}", mismatch);
+    }
+
+    if !engine.is_valid() {
+        invariant_error(
+            &args.state,
+            "the checkpoint contains an inconsistent engine state",
+        );
+    }
+}
+
+// Processes the transactions at `path` with the default engine settings, for `inspect`.
+fn processed_engine(path: &str) -> TransactionEngine {
+    let context = format!("Unable to process `{}`", path);
+    let input = input::open(Some(path), None).unwrap_or_else(|e| fail(&context, e));
     let mut engine = TransactionEngine::new();
+    engine
+        .process_from_reader(input)
+        .unwrap_or_else(|e| fail(&context, e));
+    engine
+}
+
+// Writes the statement of a client from a checkpoint, which is rendered as a table by default.
+fn statement(args: &StatementArgs) {
+    let engine = load_checkpoint(&args.state);
+    let statement = engine.statement(args.client).unwrap_or_else(|| {
+        fail(
+            "Unable to write the statement",
+            Error::AccountNotFound {
+                client: args.client,
+            },
+        )
+    });
+
+    let output = create_output(args.output_path.as_deref());
+    match args.format {
+        StatementFormat::Csv => statement.write_csv(output).map_err(io::Error::from),
+        StatementFormat::Json => statement.write_json(output).map_err(io::Error::from),
+        StatementFormat::Table => statement.write_table(output),
+    }
+    .unwrap_or_else(|e| fail("Unable to write the statement", e));
+}
+
+// Writes the transactions retained for a client in a checkpoint as CSV, in the order they
+// were processed.
+fn history(args: &HistoryArgs) {
+    let engine = load_checkpoint(&args.state);
+    if engine.account(args.client).is_none() {
+        fail(
+            "Unable to write the history",
+            Error::AccountNotFound {
+                client: args.client,
+            },
+        );
+    }
+
+    let output = create_output(args.output_path.as_deref());
+    transaction::write_transactions(engine.transactions_for(args.client), output)
+        .unwrap_or_else(|e| fail("Unable to write the history", e));
+}
+
+// Reads the accounts to compare from `path`, which is either an account report (for `.csv`
+// paths) or a checkpoint.
+fn diffed_accounts(path: &str) -> diff::Accounts {
+    if !path.ends_with(".csv") {
+        return diff::accounts_of(&load_checkpoint(path));
+    }
+    let context = format!("Unable to read the account report `{}`", path);
+    let file = File::open(path).unwrap_or_else(|e| fail(&context, e));
+    diff::read_report(BufReader::new(file)).unwrap_or_else(|e| fail(&context, e))
+}
+
+// Writes the discrepancies between the accounts of two checkpoints or account reports, and
+// exits with `EXIT_DISCREPANCIES` if there are any.
+fn diff(args: &DiffArgs) {
+    let discrepancies = diff::compare(&diffed_accounts(&args.left), &diffed_accounts(&args.right));
+    diff::write_csv(create_output(args.output_path.as_deref()), &discrepancies)
+        .unwrap_or_else(|e| fail("Unable to write the discrepancies", e));
+
+    eprintln!("discrepancies: {}", discrepancies.len());
+    if !discrepancies.is_empty() {
+        process::exit(EXIT_DISCREPANCIES);
+    }
+}
+
+// Reports the anomalies of a checkpoint, and with `--fix`, corrects them and saves the
+// repaired state, reporting every adjustment (and emitting audit events for them, if
+// requested). Exits with `EXIT_DISCREPANCIES` if there are anomalies left unrepaired.
+fn repair(args: &RepairArgs) {
+    let mut engine = load_checkpoint(&args.state);
+    let anomalies = engine.anomalies();
+    for anomaly in anomalies.iter() {
+        eprintln!("anomaly: {}", anomaly);
+    }
+    eprintln!("anomalies: {}", anomalies.len());
+
+    let path = match (&args.output_path, args.fix) {
+        (Some(path), true) => path,
+        _ if anomalies.is_empty() => return,
+        _ => process::exit(EXIT_DISCREPANCIES),
+    };
+
+    let mut audit_log = args
+        .audit
+        .as_ref()
+        .map(|target| AuditLog::new(target.writer()));
+    for adjustment in engine.repair() {
+        eprintln!("repaired: {}", adjustment);
+        if let Some(log) = audit_log.as_mut() {
+            log.record_repair(&adjustment)
+                .unwrap_or_else(|e| fail("Unable to write to the audit stream", e));
+        }
+    }
+
+    if !engine.is_valid() {
+        invariant_error(
+            &args.state,
+            "the state is still inconsistent in ways it can't be repaired",
+        );
+    }
+    save_checkpoint(&engine, path);
+}
+
+// Combines the checkpoints into the first one, in order, and saves the result. Merging them
+// is rejected if any of them share a transaction id.
+fn merge(args: &MergeArgs) {
+    let mut states = args.states.iter();
+    let mut engine = load_checkpoint(states.next().expect("Required by the arguments"));
+    for path in states {
+        engine
+            .merge(load_checkpoint(path))
+            .unwrap_or_else(|e| input_error(&format!("Unable to merge `{}`", path), e));
+    }
+
+    if !engine.is_valid() {
+        invariant_error("Unable to merge", "the combined state is inconsistent");
+    }
+    save_checkpoint(&engine, &args.output_path);
+}
+
+// Recovers the state from the write-ahead log, snapshots it right away (so the log is empty
+// afterwards), and exports it as a checkpoint if requested.
+fn snapshot(args: &SnapshotArgs) {
+    let mut engine = build_engine(&args.engine);
+    engine
+        .compact_wal()
+        .unwrap_or_else(|e| fail("Unable to compact the write-ahead log", e));
+
+    if let Some(path) = &args.output_path {
+        save_checkpoint(&engine, path);
+    }
+}
+
+// The key for encrypting saved states, from the file given via `--state-key-file`, or else
+// from the hex digits of the `TE_STATE_KEY` environment variable.
+fn state_key(options: &StateArgs) -> Option<StateKey> {
+    if let Some(path) = &options.state_key_file {
+        let key = StateKey::load(path)
+            .unwrap_or_else(|e| fail(&format!("Unable to read the key `{}`", path), e));
+        return Some(key);
+    }
+    let hex = env::var_os("TE_STATE_KEY")?;
+    let key = StateKey::from_hex(hex.as_encoded_bytes())
+        .unwrap_or_else(|e| fail("Unable to read the key in `TE_STATE_KEY`", e));
+    Some(key)
+}
+
+// Reads the key for signing the audit log from the file at `path`, without the trailing line
+// break (if any).
+fn read_key(path: &str) -> Vec<u8> {
+    let mut key = std::fs::read(path)
+        .unwrap_or_else(|e| fail(&format!("Unable to read the key `{}`", path), e));
+    while key.last().is_some_and(u8::is_ascii_whitespace) {
+        key.pop();
+    }
+    key
+}
+
+// Verifies the chain of a signed audit log, replays its transactions (on top of the
+// checkpoint given via `--load-checkpoint`, if any), and compares the resulting accounts
+// with the given state. Every entry has to apply again, which takes the same engine options
+// as when it was written.
+#[cfg(feature = "signed-audit")]
+fn verify_audit(args: &VerifyAuditArgs) {
+    let context = format!("Unable to verify the audit log `{}`", args.log);
+    let file = File::open(&args.log).unwrap_or_else(|e| fail(&context, e));
+    let key = args.audit_key_file.as_deref().map(read_key);
+    let mut chain = AuditChain::new(BufReader::new(file), key);
+
+    let mut engine = build_engine(&args.engine);
+    for entry in chain.by_ref() {
+        let transaction = entry.unwrap_or_else(|e| fail(&context, e));
+        let tx = transaction.tx();
+        match engine.process_transaction_detailed(transaction) {
+            Outcome::Applied => {}
+            Outcome::NoOp => fail(&context, format!("transaction {} has no effect", tx)),
+            Outcome::Rejected(e) => fail(&context, format!("transaction {}: {}", tx, e)),
+        }
+        engine.drain_warnings();
+    }
+
+    let state = load_checkpoint(&args.state);
+    let discrepancies = diff::compare(&diff::accounts_of(&engine), &diff::accounts_of(&state));
+    if !discrepancies.is_empty() {
+        diff::write_csv(io::stdout(), &discrepancies)
+            .unwrap_or_else(|e| fail("Unable to write the discrepancies", e));
+        eprintln!("discrepancies: {}", discrepancies.len());
+        process::exit(EXIT_DISCREPANCIES);
+    }
+    eprintln!("verified: {} entries", chain.verified());
+}
+
+#[cfg(not(feature = "signed-audit"))]
+fn verify_audit(args: &VerifyAuditArgs) {
+    fail(
+        &format!("Unable to verify the audit log `{}`", args.log),
+        "`te` was built without the `signed-audit` feature",
+    );
+}
+
+// Stands in for the signed audit log without the `signed-audit` feature, which fails to open.
+#[cfg(not(feature = "signed-audit"))]
+struct SignedAuditLog;
+
+#[cfg(not(feature = "signed-audit"))]
+impl SignedAuditLog {
+    fn open(_path: &str, _key: Option<Vec<u8>>) -> io::Result<Self> {
+        Err(io::Error::other(
+            "`te` was built without the `signed-audit` feature",
+        ))
+    }
+
+    fn record_applied(&mut self, _transaction: &Transaction) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Consumes transactions from Kafka until consuming fails. The snapshot and the accounts get
+// written to a temporary file first and then renamed, so a crash never leaves them truncated.
+#[cfg(feature = "kafka")]
+fn consume(args: ConsumeArgs) {
+    use std::time::{Duration, Instant};
+    use te::kafka::{KafkaSource, Payload};
+
+    check_output_columns(args.format, args.output_columns.as_deref());
+    let payload = match args.payload {
+        cli::Payload::Json => Payload::Json,
+        cli::Payload::Csv => Payload::Csv,
+    };
+    let mut source = KafkaSource::connect(args.brokers.clone(), &args.topic, &args.group, payload)
+        .unwrap_or_else(|e| fail("Unable to connect to Kafka", e));
+    let mut engine = build_engine(&args.engine);
+
+    let interval = Duration::from_secs(args.snapshot_interval);
+    let mut last_flush = Instant::now();
+    let e = source.run(&mut engine, |engine| {
+        if last_flush.elapsed() < interval {
+            return Ok(());
+        }
+        if let Some(path) = &args.snapshot {
+            let tmp = format!("{}.tmp", path);
+            save_checkpoint(engine, &tmp);
+            fs::rename(&tmp, path)?;
+        }
+        if let Some(path) = &args.output_path {
+            let tmp = format!("{}.tmp", path);
+            write_accounts(
+                engine,
+                create_output(Some(&tmp)),
+                args.format,
+                args.output_columns.as_deref(),
+            );
+            fs::rename(&tmp, path)?;
+        }
+        last_flush = Instant::now();
+        Ok(())
+    });
+    fail("Unable to consume from Kafka", e);
+}
+
+#[cfg(not(feature = "kafka"))]
+fn consume(_args: ConsumeArgs) {
+    fail(
+        "Unable to consume from Kafka",
+        "`te` was built without the `kafka` feature",
+    );
+}
+
+// The file extension of account reports in `format`.
+fn output_extension(format: OutputFormat) -> &'static str {
+    match format {
+        OutputFormat::Csv => "csv",
+        OutputFormat::Json => "json",
+        OutputFormat::Parquet => "parquet",
+        OutputFormat::Table => "txt",
+    }
+}
+
+// Processes the input with an engine per tenant (see `--tenant-output`), and writes the
+// accounts of each tenant into `dir`. Only the options which apply to every tenant alike are
+// supported, the others are rejected with the arguments.
+fn process_tenants(options: Arc<ProcessArgs>, dir: &str) {
+    fs::create_dir_all(dir).unwrap_or_else(|e| fail(&format!("Unable to create `{}`", dir), e));
+
+    let engine_options = Arc::clone(&options);
+    let mut engine = MultiTenantEngine::new(move |_| build_engine(&engine_options.engine));
+    let mut rejection_log = options.errors_path.as_deref().map(open_rejection_log);
+
+    let files = input_files(&options);
+    let mut summary = ProcessSummary::default();
+    let mut progress = options.progress.then(|| Progress::new(&files));
+    let counter = progress.as_ref().map(Progress::counter);
+    let input = open_transactions(&options, files, Position::default(), None, counter);
+    let mut sequence = options.check_sequence.then(SequenceChecker::new);
+    let mut bypass_reported = false;
+
+    for (line, result) in input.records() {
+        let _span = info_span!("record", line).entered();
+        if let Err(e) = &result {
+            if e.is_io_error() {
+                input_error("Unable to read the input", e);
+            }
+        }
+        if options.max_transactions == Some(summary.records) {
+            summary.limit_reached = true;
+            break;
+        }
+
+        match result {
+            Ok(t) => {
+                check_sequence(
+                    sequence.as_mut(),
+                    &t,
+                    line,
+                    &mut summary,
+                    &mut rejection_log,
+                    options.strict,
+                );
+                let tenant = t.tenant().map(str::to_owned);
+                let audited = rejection_log.is_some().then(|| t.clone());
+                let outcome = engine.process_transaction_detailed(t);
+                summary.record_processed(&outcome);
+                let process_result = outcome.result();
+                check_engine_state(&process_result);
+
+                let tenant = tenant.as_deref().unwrap_or(te::tenant::DEFAULT_TENANT);
+                if let Some(tenant_engine) = engine.tenant_mut(tenant) {
+                    for warning in tenant_engine.drain_warnings() {
+                        let prefix = format!("[{}] ", tenant);
+                        report_warning(&warning, &prefix, options.warnings, &mut bypass_reported);
+                    }
+                }
+
+                if let (Some(log), Some(t), Err(e)) =
+                    (rejection_log.as_mut(), &audited, &process_result)
+                {
+                    log.record_rejected(line, t, e)
+                        .unwrap_or_else(|e| fail("Unable to write to the errors file", e));
+                }
+
+                if let (true, Err(e)) = (options.strict, process_result) {
+                    abort_strict(EXIT_REJECTED, line, rejection_log, e);
+                }
+            }
+            Err(e) => {
+                warn!("Skipping invalid record: {}", e);
+                summary.record_invalid();
+
+                if let Some(log) = rejection_log.as_mut() {
+                    log.record_invalid(line, &e)
+                        .unwrap_or_else(|e| fail("Unable to write to the errors file", e));
+                }
+
+                if options.strict {
+                    abort_strict(EXIT_ABORTED, line, rejection_log, e);
+                }
+            }
+        }
+
+        if let Some(progress) = progress.as_mut() {
+            progress.update(summary.records);
+        }
+    }
+
+    if let Some(progress) = progress.as_mut() {
+        progress.finish(summary.records);
+    }
+
+    if let Some(log) = rejection_log {
+        log.finish()
+            .unwrap_or_else(|e| fail("Unable to write to the errors file", e));
+    }
+
+    for (tenant, engine) in engine.tenants() {
+        if options.engine.check_invariants && !engine.is_valid() {
+            invariant_error(
+                "Invariant violated",
+                format!("the state of tenant `{}` is inconsistent", tenant),
+            );
+        }
+
+        let path = Path::new(dir).join(format!("{}.{}", tenant, output_extension(options.format)));
+        let output = create_output(Some(&path.to_string_lossy()));
+        write_accounts(
+            engine,
+            output,
+            options.format,
+            options.output_columns.as_deref(),
+        );
+    }
+
+    if options.summary {
+        eprintln!("{}", summary);
+    }
+}
+
+fn process(options: ProcessArgs) {
+    if options.streams_to_stdout() && options.output_path.is_none() {
+        usage_error(Cli::command().error(
+            ErrorKind::MissingRequiredArgument,
+            "`--output` is required when streaming events to stdout",
+        ));
+    }
+    if options.statement.is_some() && matches!(options.format, OutputFormat::Parquet) {
+        usage_error(Cli::command().error(
+            ErrorKind::ArgumentConflict,
+            "statements can't be written as Parquet",
+        ));
+    }
+    check_output_columns(options.format, options.output_columns.as_deref());
+    if let Some(dir) = &options.snapshot_dir {
+        fs::create_dir_all(dir).unwrap_or_else(|e| fail(&format!("Unable to create `{}`", dir), e));
+    }
+    if options.dry_run && matches!(options.format, OutputFormat::Parquet) {
+        usage_error(Cli::command().error(
+            ErrorKind::ArgumentConflict,
+            "dry run reports can't be written as Parquet",
+        ));
+    }
+    let options = Arc::new(options);
+    if let Some(dir) = options.tenant_output.clone() {
+        return process_tenants(options, &dir);
+    }
+    let started = Instant::now();
+
+    // Also collecting the metrics for the HTTP API, which exports them, and for the report.
+    let metrics = options.stats || options.http.is_some() || options.report.is_some();
+    // With `--resume`, the engine starts from the checkpoint in the checkpoint directory (if
+    // there is one yet), and the input from the position it was saved at.
+    let resume_point = match (&options.checkpoint_dir, options.resume) {
+        (Some(dir), true) => ResumePoint::load(dir)
+            .unwrap_or_else(|e| fail(&format!("Unable to load the checkpoint in `{}`", dir), e)),
+        _ => None,
+    };
+    let (mut engine, start, headers) = match (resume_point, &options.checkpoint_dir) {
+        (Some(point), Some(dir)) => {
+            let engine = TransactionEngine::restore(point.state).unwrap_or_else(|e| {
+                fail(&format!("Unable to load the checkpoint in `{}`", dir), e)
+            });
+            let engine = configure_engine(&options.engine, Some((engine, dir)));
+            (engine, point.position, point.headers)
+        }
+        _ => (build_engine(&options.engine), Position::default(), None),
+    };
+    engine = engine
+        .with_metrics(metrics)
+        .with_event_log(options.events_path.is_some())
+        .with_lineage(options.export_lineage.is_some());
+
+    let mut audit_log = options
+        .audit
+        .as_ref()
+        .map(|target| AuditLog::new(target.writer()));
+
+    let mut signed_audit_log = options.signed_audit.as_ref().map(|path| {
+        let key = options.audit_key_file.as_deref().map(read_key);
+        SignedAuditLog::open(path, key)
+            .unwrap_or_else(|e| fail(&format!("Unable to open the audit log `{}`", path), e))
+    });
+
+    let mut scheduler = options.rules.as_deref().map(|path| {
+        let context = format!("Unable to load the rules `{}`", path);
+        let file = File::open(path).unwrap_or_else(|e| fail(&context, e));
+        Scheduler::load(BufReader::new(file)).unwrap_or_else(|e| fail(&context, e))
+    });
+
+    let mut rejection_log = options.errors_path.as_deref().map(open_rejection_log);
 
     // Seems like `csv::Reader` already performs some internal buffering. If that's not
     // sufficient, we could open the input file ourselves and use/implement some other
     // sort of buffering logic.
-    let mut reader = ReaderBuilder::new()
-        // Required for the csv logic to ignore whitespaces; otherwise, the presence of
-        // any whitespace seems to cause errors.
-        .trim(Trim::All)
-        // Setting this so we can have rows where the amount is not explicitly specified
-        // (i.e. dispute-related transactions)
-        .flexible(true)
-        .from_path(input_path)
-        .expect("Unable to open the input file");
+    if options.repl {
+        repl::run(&mut engine).unwrap_or_else(|e| fail("Unable to interact with the terminal", e));
+        return;
+    }
+
+    let files = input_files(&options);
+    if files.len() > 1 && (options.checkpoint_every.is_some() || options.resume) {
+        usage_error(Cli::command().error(
+            ErrorKind::ArgumentConflict,
+            "checkpoints of the input position require a single input file",
+        ));
+    }
+
+    // With `--http`, the input is read on another thread, so requests still get served while
+    // waiting for more input.
+    let (http_events, files) = match options.http.as_deref() {
+        Some(address) => (
+            Some(serve_http(&options, files, address, &mut engine)),
+            Vec::new(),
+        ),
+        None => (None, files),
+    };
+
+    let accounts_before = engine.accounts().count();
+    let mut summary = ProcessSummary::default();
+    let mut progress = options.progress.then(|| Progress::new(&files));
+    let counter = progress.as_ref().map(Progress::counter);
+
+    if let Some(threads) = options.threads {
+        let input = open_transactions(&options, files, start, headers, counter);
+        summary = process_parallel(&mut engine, input.records(), threads, progress.as_mut());
+    } else {
+        let mut headers = headers;
+        let events: Box<dyn Iterator<Item = Event>> = match &http_events {
+            Some(events) => Box::new(events.iter()),
+            None => {
+                let input = open_transactions(&options, files, start, headers.take(), counter);
+                headers = input.headers;
+                Box::new(
+                    input
+                        .records
+                        .map(|(record, position)| Event::Record(record, position)),
+                )
+            }
+        };
+        let mut resume_after = options.resume_after;
+        // Whether a record with the timestamp of the resume point came by, which is as far as
+        // the input may go with nothing left to process.
+        let mut resume_time_reached = false;
+        let mut sequence = options.check_sequence.then(SequenceChecker::new);
+        let mut bypass_reported = false;
+        let mut position = start;
+        let mut checkpointed = 0;
+        let mut emitted = 0;
+
+        // This loop incrementally processes the input data, and attempts to deserialize
+        // one record at a time.
+        for event in events {
+            // Records are the only events without the `http` feature.
+            #[allow(clippy::infallible_destructuring_match)]
+            let ((line, result), next) = match event {
+                Event::Record(record, position) => (record, position),
+                #[cfg(feature = "http")]
+                Event::Request(request) => {
+                    request.run(&mut engine);
+                    continue;
+                }
+                #[cfg(feature = "http")]
+                Event::InputDone => break,
+            };
+
+            // Ties the events of every record to the input line it came from.
+            let _span = info_span!("record", line).entered();
+
+            // Unlike invalid records, failing to read the input is not something we can skip.
+            if let Err(e) = &result {
+                if e.is_io_error() {
+                    input_error("Unable to read the input", e);
+                }
+            }
+
+            // Skipping everything (including invalid records) until we get past the resume point.
+            if let Some(point) = resume_after {
+                let timestamp = result.as_ref().ok().and_then(|t| t.timestamp());
+                let skip = match point {
+                    ResumeAfter::Transaction(id) => {
+                        if matches!(&result, Ok(t) if t.tx() == id) {
+                            resume_after = None;
+                        }
+                        true
+                    }
+                    // The first record with a later timestamp is the first one to process.
+                    ResumeAfter::Time(time) if timestamp.is_some_and(|t| t > time) => {
+                        resume_after = None;
+                        false
+                    }
+                    ResumeAfter::Time(time) => {
+                        resume_time_reached |= timestamp == Some(time);
+                        true
+                    }
+                };
+                if skip {
+                    continue;
+                }
+            }
+
+            // Getting here means there's at least one more record we're not going to consider.
+            if options.max_transactions == Some(summary.records) {
+                summary.limit_reached = true;
+                break;
+            }
+
+            match result {
+                Ok(t) => {
+                    check_sequence(
+                        sequence.as_mut(),
+                        &t,
+                        line,
+                        &mut summary,
+                        &mut rejection_log,
+                        options.strict,
+                    );
+
+                    // Adjustments which fell due since the previous transaction come first.
+                    let timestamp = t.timestamp().or_else(|| engine.clock_time());
+                    if let (Some(scheduler), Some(timestamp)) = (scheduler.as_mut(), timestamp) {
+                        scheduler.advance(&mut engine, timestamp);
+                    }
 
-    // This loop incrementally processes the input data, and attempts to deserialize
-    // one record at a time.
-    for result in reader.deserialize() {
-        if let Ok(t) = result {
-            // We could examine the result below to perform additional logic for the different
-            // reasons why a transaction was not committed successfully (i.e. insufficient
-            // funds). We simply move to the next transaction for now.
-            let _process_result = engine.process_transaction(t);
-        } else {
-            // If we got here, then parsing one of the rows has failed. Let's just ignore
-            // invalid records for this simple program, and continue.
+                    // Only paying for the clone when there's a log to write it to.
+                    let audited = (audit_log.is_some()
+                        || signed_audit_log.is_some()
+                        || rejection_log.is_some())
+                    .then(|| t.clone());
+
+                    // We could examine the result below to perform additional logic for the
+                    // different reasons why a transaction was not committed successfully (i.e.
+                    // insufficient funds). We simply move to the next transaction for now.
+                    let outcome = engine.process_transaction_detailed(t);
+                    summary.record_processed(&outcome);
+                    let applied = matches!(outcome, Outcome::Applied);
+                    let process_result = outcome.result();
+                    check_engine_state(&process_result);
+
+                    // Always draining the warnings, so they don't pile up inside the engine.
+                    for warning in engine.drain_warnings() {
+                        report_warning(&warning, "", options.warnings, &mut bypass_reported);
+                    }
+
+                    if let (Some(log), Some(t)) = (audit_log.as_mut(), &audited) {
+                        log.record_processed(t, &process_result)
+                            .unwrap_or_else(|e| fail("Unable to write to the audit stream", e));
+                    }
+
+                    if let (Some(log), Some(t), true) =
+                        (signed_audit_log.as_mut(), &audited, applied)
+                    {
+                        log.record_applied(t)
+                            .unwrap_or_else(|e| fail("Unable to write to the audit log", e));
+                    }
+
+                    if let (Some(log), Some(t), Err(e)) =
+                        (rejection_log.as_mut(), &audited, &process_result)
+                    {
+                        log.record_rejected(line, t, e)
+                            .unwrap_or_else(|e| fail("Unable to write to the errors file", e));
+                    }
+
+                    if let (true, Err(e)) = (options.strict, process_result) {
+                        abort_strict(EXIT_REJECTED, line, rejection_log, e);
+                    }
+                }
+                Err(e) => {
+                    // If we got here, then parsing one of the rows has failed. Let's just ignore
+                    // invalid records for this simple program, and continue (unless running in
+                    // strict mode).
+                    warn!("Skipping invalid record: {}", e);
+                    summary.record_invalid();
+
+                    if let Some(log) = audit_log.as_mut() {
+                        log.record_invalid(&e)
+                            .unwrap_or_else(|e| fail("Unable to write to the audit stream", e));
+                    }
+
+                    if let Some(log) = rejection_log.as_mut() {
+                        log.record_invalid(line, &e)
+                            .unwrap_or_else(|e| fail("Unable to write to the errors file", e));
+                    }
+
+                    if options.strict {
+                        abort_strict(EXIT_ABORTED, line, rejection_log, e);
+                    }
+                }
+            }
+
+            position = next;
+            if let Some(progress) = progress.as_mut() {
+                progress.update(summary.records);
+            }
+            if let (Some(every), Some(dir)) = (options.checkpoint_every, &options.checkpoint_dir) {
+                if summary.records - checkpointed >= every {
+                    save_resume_point(dir, &engine, position, &headers);
+                    checkpointed = summary.records;
+                }
+            }
+            if let Some(every) = options.emit_snapshots_every {
+                if summary.records - emitted >= every {
+                    emit_snapshot(&options, &engine, summary.records);
+                    emitted = summary.records;
+                }
+            }
+        }
+
+        // Rather than skipping the whole input, i.e. when resuming with the wrong input file.
+        if let Some(point) = resume_after.filter(|_| !resume_time_reached) {
+            input_error(
+                "Unable to resume",
+                format_args!("the input ended before {}", point),
+            );
+        }
+
+        // The last checkpoint covers the whole input, so resuming once more is a no-op.
+        if let (Some(_), Some(dir)) = (options.checkpoint_every, &options.checkpoint_dir) {
+            if summary.records > checkpointed {
+                save_resume_point(dir, &engine, position, &headers);
+            }
+        }
+    }
+
+    if let Some(progress) = progress.as_mut() {
+        progress.finish(summary.records);
+    }
+
+    if let Some(log) = rejection_log {
+        log.finish()
+            .unwrap_or_else(|e| fail("Unable to write to the errors file", e));
+    }
+
+    // Also covers failures after a transaction went through (i.e. while retrying quarantined
+    // ones).
+    if let Some(kind) = engine.storage_failure() {
+        fail("Unable to process the input", Error::StorageFailed { kind });
+    }
+
+    if options.engine.check_invariants && !engine.is_valid() {
+        invariant_error(
+            "Invariant violated",
+            "the engine state is inconsistent after processing the input",
+        );
+    }
+
+    let output = create_output(options.output_path.as_deref());
+    match options.statement {
+        // The input has been processed as usual, but only the outcomes get reported.
+        _ if options.dry_run => write_dry_run_report(&summary, output, options.format),
+        Some(client) => {
+            let statement = engine.statement(client).unwrap_or_else(|| {
+                fail(
+                    "Unable to write the statement",
+                    Error::AccountNotFound { client },
+                )
+            });
+            match options.format {
+                OutputFormat::Csv => statement.write_csv(output).map_err(io::Error::from),
+                OutputFormat::Json => statement.write_json(output).map_err(io::Error::from),
+                OutputFormat::Parquet => unreachable!("rejected with the arguments"),
+                OutputFormat::Table => statement.write_table(output),
+            }
+            .unwrap_or_else(|e| fail("Unable to write the statement", e));
+        }
+        None => write_accounts(
+            &engine,
+            output,
+            options.format,
+            options.output_columns.as_deref(),
+        ),
+    }
+
+    if let Some(path) = &options.save_checkpoint {
+        save_checkpoint(&engine, path);
+    }
+
+    if let Some(path) = &options.export_sqlite {
+        export_sqlite(&engine, path)
+            .unwrap_or_else(|e| fail(&format!("Unable to export to `{}`", path), e));
+    }
+
+    if let Some(path) = &options.events_path {
+        let context = format!("Unable to write the events to `{}`", path);
+        let file = File::create(path).unwrap_or_else(|e| fail(&context, e));
+        events::write_jsonl(BufWriter::new(file), engine.events())
+            .unwrap_or_else(|e| fail(&context, e));
+    }
+
+    if let (Some(format), Some(path), Some(lineage)) = (
+        options.export_lineage,
+        &options.lineage_output,
+        engine.lineage(),
+    ) {
+        let context = format!("Unable to write the lineage to `{}`", path);
+        let file = BufWriter::new(File::create(path).unwrap_or_else(|e| fail(&context, e)));
+        match format {
+            LineageFormat::Graphviz => lineage::write_dot(file, lineage),
+            LineageFormat::Json => lineage::write_json(file, lineage),
+        }
+        .unwrap_or_else(|e| fail(&context, e));
+    }
+
+    if let Some(path) = &options.payouts_path {
+        let context = format!("Unable to write the payouts to `{}`", path);
+        write_payouts(&mut engine, path).unwrap_or_else(|e| fail(&context, e));
+    }
+
+    if options.summary {
+        eprintln!("{}", summary);
+    }
+
+    if options.aggregate {
+        eprintln!("{}", engine.aggregate());
+    }
+
+    if let Some(metrics) = engine.metrics().filter(|_| options.stats) {
+        eprintln!("{}", metrics);
+    }
+
+    if let (Some(path), Some(metrics)) = (&options.report, engine.metrics()) {
+        let aggregate = engine.aggregate();
+        let report = RunReport::new(
+            &summary,
+            metrics,
+            &aggregate,
+            accounts_before,
+            started.elapsed(),
+        );
+        write_report(&report, path)
+            .unwrap_or_else(|e| fail(&format!("Unable to write the report to `{}`", path), e));
+    }
+
+    // The HTTP server keeps serving requests against the final state, until the process gets
+    // terminated.
+    #[cfg(feature = "http")]
+    for event in http_events.iter().flatten() {
+        if let Event::Request(request) = event {
+            request.run(&mut engine);
         }
     }
+}
+
+fn main() {
+    let cli = Cli::try_parse().unwrap_or_else(|e| usage_error(e));
+    JSON_ERRORS.store(cli.logging.json_errors, Ordering::Relaxed);
+    if let Some(key) = state_key(&cli.state) {
+        let _ = STATE_KEY.set(key);
+    }
+    init_logging(&cli.logging);
 
-    engine.print_accounts()
+    // Running without a subcommand is the same as running `process`.
+    match cli
+        .command
+        .unwrap_or(Command::Process(Box::new(cli.process)))
+    {
+        Command::Process(args) => process(*args),
+        Command::Serve(args) => serve(args),
+        Command::Inspect(args) => inspect(&args),
+        Command::Statement(args) => statement(&args),
+        Command::History(args) => history(&args),
+        Command::Diff(args) => diff(&args),
+        Command::Merge(args) => merge(&args),
+        Command::Snapshot(args) => snapshot(&args),
+        Command::Consume(args) => consume(args),
+        Command::VerifyAudit(args) => verify_audit(&args),
+        Command::Repair(args) => repair(&args),
+    }
 }
@@ -0,0 +1,151 @@
+//! Counters and timings of the transactions processed by an engine, as collected once enabled
+//! via `TransactionEngine::with_metrics`. `Metrics::render_prometheus` exports them in the
+//! Prometheus text format (i.e. for a `/metrics` endpoint), while the `Display` output is a
+//! summary report meant for humans.
+
+use std::collections::BTreeMap;
+use std::fmt::{self, Write};
+use std::time::Duration;
+
+use rust_decimal::Decimal;
+
+use crate::transaction::{Currency, Error, Type};
+
+/// Keeps track of the transactions processed by the engine, broken down by type (and by
+/// reason, for rejected ones), along with the time spent processing them.
+#[derive(Clone, Debug, Default)]
+pub struct Metrics {
+    // Transactions which went through (including no-ops), keyed by type (see `Type::name`).
+    pub processed: BTreeMap<&'static str, u64>,
+    // Transactions rejected by the engine, keyed by type and by reason (see `Error::name`).
+    pub rejected: BTreeMap<(&'static str, &'static str), u64>,
+    // Time spent in the engine processing transactions, which doesn't include reading the
+    // input (or waiting for it).
+    pub processing_time: Duration,
+    // Sums of the amounts of the applied deposits and withdrawals, keyed by type and currency.
+    pub volume: BTreeMap<(&'static str, Currency), Decimal>,
+}
+
+impl Metrics {
+    pub fn record(&mut self, type_: Type, result: &Result<(), Error>, elapsed: Duration) {
+        match result {
+            Ok(()) => *self.processed.entry(type_.name()).or_insert(0) += 1,
+            Err(e) => *self.rejected.entry((type_.name(), e.name())).or_insert(0) += 1,
+        }
+        self.processing_time += elapsed;
+    }
+
+    /// Adds `amount` to the volume of applied transactions of `type_` in `currency`.
+    pub fn record_volume(&mut self, type_: Type, currency: &str, amount: Decimal) {
+        *self
+            .volume
+            .entry((type_.name(), currency.to_owned()))
+            .or_default() += amount;
+    }
+
+    /// Adds the counts and timings of `other` (i.e. the metrics of another shard of the input).
+    pub fn merge(&mut self, other: Metrics) {
+        for (type_, count) in other.processed {
+            *self.processed.entry(type_).or_insert(0) += count;
+        }
+        for (key, count) in other.rejected {
+            *self.rejected.entry(key).or_insert(0) += count;
+        }
+        self.processing_time += other.processing_time;
+        for (key, amount) in other.volume {
+            *self.volume.entry(key).or_default() += amount;
+        }
+    }
+
+    /// The number of transactions considered by the engine, whether they went through or not.
+    pub fn transactions(&self) -> u64 {
+        self.processed.values().sum::<u64>() + self.rejected.values().sum::<u64>()
+    }
+
+    /// Transactions processed per second of processing time (zero if nothing was processed).
+    pub fn throughput(&self) -> f64 {
+        let seconds = self.processing_time.as_secs_f64();
+        if seconds > 0.0 {
+            self.transactions() as f64 / seconds
+        } else {
+            0.0
+        }
+    }
+
+    /// Renders the metrics in the Prometheus text exposition format. The processing time is
+    /// exported as a summary, so the throughput can be derived from its `_sum` and `_count`.
+    pub fn render_prometheus(&self) -> String {
+        let mut out = String::new();
+        // Writing to a `String` can't fail.
+        let _ = self.write_prometheus(&mut out);
+        out
+    }
+
+    fn write_prometheus(&self, out: &mut String) -> fmt::Result {
+        writeln!(
+            out,
+            "# HELP te_transactions_processed_total Transactions which went through, by type."
+        )?;
+        writeln!(out, "# TYPE te_transactions_processed_total counter")?;
+        for (type_, count) in self.processed.iter() {
+            writeln!(
+                out,
+                "te_transactions_processed_total{{type=\"{}\"}} {}",
+                type_, count
+            )?;
+        }
+
+        writeln!(
+            out,
+            "# HELP te_transactions_rejected_total Transactions rejected by the engine, by type \
+             and error."
+        )?;
+        writeln!(out, "# TYPE te_transactions_rejected_total counter")?;
+        for ((type_, error), count) in self.rejected.iter() {
+            writeln!(
+                out,
+                "te_transactions_rejected_total{{type=\"{}\",error=\"{}\"}} {}",
+                type_, error, count
+            )?;
+        }
+
+        writeln!(
+            out,
+            "# HELP te_transaction_processing_seconds Time spent processing transactions."
+        )?;
+        writeln!(out, "# TYPE te_transaction_processing_seconds summary")?;
+        writeln!(
+            out,
+            "te_transaction_processing_seconds_sum {}",
+            self.processing_time.as_secs_f64()
+        )?;
+        writeln!(
+            out,
+            "te_transaction_processing_seconds_count {}",
+            self.transactions()
+        )
+    }
+}
+
+impl fmt::Display for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "processed: {}", self.processed.values().sum::<u64>())?;
+        for (type_, count) in self.processed.iter() {
+            writeln!(f, "  {}: {}", type_, count)?;
+        }
+        writeln!(f, "rejected: {}", self.rejected.values().sum::<u64>())?;
+        for ((type_, error), count) in self.rejected.iter() {
+            writeln!(f, "  {} {}: {}", type_, error, count)?;
+        }
+        for ((type_, currency), amount) in self.volume.iter() {
+            // Prefixing with the currency code, unless it's the implicit currency.
+            if currency.is_empty() {
+                writeln!(f, "{} volume: {}", type_, amount)?;
+            } else {
+                writeln!(f, "{} {} volume: {}", currency, type_, amount)?;
+            }
+        }
+        writeln!(f, "processing time: {:.3?}", self.processing_time)?;
+        write!(f, "throughput: {:.0} transactions/s", self.throughput())
+    }
+}
@@ -0,0 +1,99 @@
+use rust_decimal::Decimal;
+
+use crate::account::Account;
+use crate::transaction::{ClientId, Transaction, TransactionId};
+
+/// Gets notified by the engine right after it changes an account (see
+/// `TransactionEngine::with_observer`), i.e. to drive notifications, audit pipelines, or
+/// fraud models. Every callback does nothing by default, so implementations only need to
+/// override the ones they're interested in. The accounts passed along have the change applied
+/// already. Rejected transactions don't change anything, so they're not reported (the
+/// transaction callback covers those).
+pub trait EngineObserver {
+    /// A deposit was credited to the account of the client of `transaction` (with the amount
+    /// as credited, i.e. after rounding).
+    fn on_deposit(&mut self, _transaction: &Transaction, _account: &Account) {}
+
+    /// A withdrawal was debited from the account of the client of `transaction`.
+    fn on_withdrawal(&mut self, _transaction: &Transaction, _account: &Account) {}
+
+    /// The funds of `transaction` moved from the `sender` to the `receiver`.
+    fn on_transfer(&mut self, _transaction: &Transaction, _sender: &Account, _receiver: &Account) {}
+
+    /// The undisputed deposit or withdrawal `transaction` got voided, which took its funds
+    /// back out of the account of its client (or returned them, for withdrawals).
+    fn on_void(&mut self, _transaction: &Transaction, _account: &Account) {}
+
+    /// `amount` of transaction `tx` got held under dispute in the account of `client` (which
+    /// holds the funds of the disputed transaction).
+    fn on_dispute(
+        &mut self,
+        _client: ClientId,
+        _tx: TransactionId,
+        _amount: Decimal,
+        _account: &Account,
+    ) {
+    }
+
+    /// `amount` of the dispute of transaction `tx` got released again.
+    fn on_resolve(
+        &mut self,
+        _client: ClientId,
+        _tx: TransactionId,
+        _amount: Decimal,
+        _account: &Account,
+    ) {
+    }
+
+    /// `amount` of transaction `tx` got charged back, which settles its dispute. Followed by
+    /// `on_freeze` if that froze the account (unless chargebacks don't freeze accounts).
+    fn on_chargeback(
+        &mut self,
+        _client: ClientId,
+        _tx: TransactionId,
+        _amount: Decimal,
+        _account: &Account,
+    ) {
+    }
+
+    /// The chargeback of transaction `tx` got reversed, which returned `amount` to the
+    /// account of `client` (or took it back, for withdrawals). Followed by `on_unfreeze` if
+    /// that unfroze the account (see `TransactionEngine::with_unlock_on_reversal`).
+    fn on_chargeback_reversal(
+        &mut self,
+        _client: ClientId,
+        _tx: TransactionId,
+        _amount: Decimal,
+        _account: &Account,
+    ) {
+    }
+
+    /// The account of `client` got frozen.
+    fn on_freeze(&mut self, _client: ClientId, _account: &Account) {}
+
+    /// The account of `client` got unlocked via an `unlock` transaction.
+    fn on_unfreeze(&mut self, _client: ClientId, _account: &Account) {}
+
+    /// The account of `client` got closed via a `close` transaction.
+    fn on_close(&mut self, _client: ClientId, _account: &Account) {}
+
+    /// The account of `client` got reopened via a `reopen` transaction.
+    fn on_reopen(&mut self, _client: ClientId, _account: &Account) {}
+
+    /// The account of `client` got put under review via a `review` transaction.
+    fn on_review(&mut self, _client: ClientId, _account: &Account) {}
+
+    /// The review of the account of `client` ended via an `approve` transaction.
+    fn on_approve(&mut self, _client: ClientId, _account: &Account) {}
+
+    /// The available funds of `client` in `currency` were adjusted by `amount` (negative for
+    /// debits) outside of any transaction, see `TransactionEngine::adjust_available`.
+    fn on_adjustment(
+        &mut self,
+        _client: ClientId,
+        _currency: &str,
+        _amount: Decimal,
+        _account: &Account,
+    ) {
+    }
+}
@@ -0,0 +1,125 @@
+//! Parallel deserialization of CSV input, for inputs where turning the records into
+//! transactions takes longer than processing them. The raw records are still read on the
+//! calling thread (splitting them into fields is cheap), and go to a `rayon` thread pool in
+//! batches, while the engine processes the transactions of earlier batches. The
+//! transactions come out in input order, so processing them gives the same results as
+//! deserializing them one at a time.
+
+use std::collections::VecDeque;
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
+use std::vec;
+
+use csv::StringRecord;
+use rayon::ThreadPool;
+
+use crate::input::Record;
+use crate::transaction::Transaction;
+
+// The number of records deserialized as one job, which keeps the overhead of handing them
+// to the pool (and back) small in comparison.
+const BATCH_LEN: usize = 1024;
+
+/// Turns a raw record into a transaction, i.e. via `StringRecord::deserialize` with the
+/// header row of the input.
+pub type Deserialize = dyn Fn(&StringRecord) -> csv::Result<Transaction> + Send + Sync;
+
+/// A raw record as read from the input: the line it starts on, the record itself (or the
+/// error reading it), and whatever else needs to come along with the transaction (i.e. the
+/// position of the input after the record).
+pub type RawRecord<P> = (u64, csv::Result<StringRecord>, P);
+
+/// Deserializes the records of `raw` on `pool`, and yields the transactions (along with the
+/// extra value of each record) in the order of the input. Up to two batches per thread of
+/// the pool are in flight at any time, so memory use stays bounded however large the input
+/// is, but records only come out once their batch is complete (or the input ends).
+pub fn deserialize_ordered<I, P>(
+    raw: I,
+    pool: Arc<ThreadPool>,
+    deserialize: Arc<Deserialize>,
+) -> impl Iterator<Item = (Record, P)>
+where
+    I: Iterator<Item = RawRecord<P>>,
+    P: Send + 'static,
+{
+    let in_flight = 2 * pool.current_num_threads().max(1);
+    Pipeline {
+        raw,
+        pool,
+        deserialize,
+        in_flight,
+        batches: VecDeque::new(),
+        current: Vec::new().into_iter(),
+        done: false,
+    }
+}
+
+struct Pipeline<I, P> {
+    raw: I,
+    pool: Arc<ThreadPool>,
+    deserialize: Arc<Deserialize>,
+    // The maximum number of batches handed to the pool at a time.
+    in_flight: usize,
+    // The results of the batches handed to the pool, in input order.
+    batches: VecDeque<Receiver<Vec<(Record, P)>>>,
+    // What's left of the batch at the front.
+    current: vec::IntoIter<(Record, P)>,
+    // Whether `raw` has run out.
+    done: bool,
+}
+
+impl<I, P> Pipeline<I, P>
+where
+    I: Iterator<Item = RawRecord<P>>,
+    P: Send + 'static,
+{
+    // Reads the next batch of raw records, and hands it to the pool.
+    fn dispatch(&mut self) {
+        let batch: Vec<_> = self.raw.by_ref().take(BATCH_LEN).collect();
+        if batch.len() < BATCH_LEN {
+            self.done = true;
+        }
+        if batch.is_empty() {
+            return;
+        }
+
+        let (sender, results) = mpsc::sync_channel(1);
+        let deserialize = Arc::clone(&self.deserialize);
+        self.pool.spawn(move || {
+            let records = batch
+                .into_iter()
+                .map(|(line, record, extra)| {
+                    let result = record.and_then(|record| deserialize(&record));
+                    ((line, result.map_err(Into::into)), extra)
+                })
+                .collect();
+            // The receiver only goes away when the pipeline gets dropped early.
+            let _ = sender.send(records);
+        });
+        self.batches.push_back(results);
+    }
+}
+
+impl<I, P> Iterator for Pipeline<I, P>
+where
+    I: Iterator<Item = RawRecord<P>>,
+    P: Send + 'static,
+{
+    type Item = (Record, P);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(record) = self.current.next() {
+                return Some(record);
+            }
+            while !self.done && self.batches.len() < self.in_flight {
+                self.dispatch();
+            }
+            let batch = self.batches.pop_front()?;
+            let records = batch
+                .recv()
+                .expect("Deserializing a batch of records panicked");
+            self.current = records.into_iter();
+        }
+    }
+}
@@ -0,0 +1,133 @@
+use std::fs;
+use std::io::{self, IsTerminal, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// How often the progress gets reported: the line is rewritten in place on a terminal, while
+// other destinations (i.e. log files) get a line of their own every time.
+const TERMINAL_INTERVAL: Duration = Duration::from_secs(1);
+const LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+// Reports the progress of processing the input on stderr (see `--progress`): the number of
+// records, the throughput, and the estimated time left. The estimate is based on the bytes
+// read from the input files so far (as they are, i.e. before decompressing them) and their
+// total size, so there's none for stdin.
+pub struct Progress {
+    bytes: Arc<AtomicU64>,
+    total: Option<u64>,
+    started: Instant,
+    reported: Instant,
+    terminal: bool,
+}
+
+impl Progress {
+    pub fn new(files: &[String]) -> Self {
+        let total = files
+            .iter()
+            .map(|file| match file.as_str() {
+                "-" => None,
+                path => fs::metadata(path).ok().map(|m| m.len()),
+            })
+            .sum();
+        let now = Instant::now();
+        Progress {
+            bytes: Arc::new(AtomicU64::new(0)),
+            total,
+            started: now,
+            reported: now,
+            terminal: io::stderr().is_terminal(),
+        }
+    }
+
+    // The counter the input readers add the bytes they read to.
+    pub fn counter(&self) -> Arc<AtomicU64> {
+        self.bytes.clone()
+    }
+
+    // Reports the progress after `records` records, unless it has been reported recently.
+    pub fn update(&mut self, records: u64) {
+        let interval = if self.terminal {
+            TERMINAL_INTERVAL
+        } else {
+            LOG_INTERVAL
+        };
+        if self.reported.elapsed() >= interval {
+            self.reported = Instant::now();
+            self.report(records, false);
+        }
+    }
+
+    // Reports the final numbers once the input is done.
+    pub fn finish(&mut self, records: u64) {
+        self.report(records, true);
+    }
+
+    fn report(&self, records: u64, done: bool) {
+        let bytes = self.bytes.load(Ordering::Relaxed);
+        let elapsed = self.started.elapsed().as_secs_f64().max(f64::EPSILON);
+
+        let mut line = format!(
+            "progress: {} records, {}",
+            records,
+            format_bytes(bytes as f64)
+        );
+        if let Some(total) = self.total {
+            let percent = 100.0 * bytes as f64 / total.max(1) as f64;
+            line += &format!(" of {} ({:.1}%)", format_bytes(total as f64), percent);
+        }
+        line += &format!(
+            ", {:.0} records/s, {}/s",
+            records as f64 / elapsed,
+            format_bytes(bytes as f64 / elapsed)
+        );
+        if done {
+            line += &format!(", done in {}", format_duration(elapsed));
+        } else if let Some(remaining) = self.total.and_then(|total| total.checked_sub(bytes)) {
+            let rate = bytes as f64 / elapsed;
+            if rate > 0.0 {
+                line += &format!(", {} left", format_duration(remaining as f64 / rate));
+            }
+        }
+
+        // Errors writing to stderr are not worth aborting over.
+        let mut stderr = io::stderr().lock();
+        let _ = if self.terminal {
+            // Clearing the rest of the previous line, which may have been longer.
+            write!(stderr, "\r{}\x1b[K", line)
+        } else {
+            writeln!(stderr, "{}", line)
+        };
+        if done && self.terminal {
+            let _ = writeln!(stderr);
+        }
+    }
+}
+
+fn format_bytes(bytes: f64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{:.0} {}", value, UNITS[unit])
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+// Formats `seconds` as `1h02m03s`, `2m03s`, or `3s`.
+fn format_duration(seconds: f64) -> String {
+    let seconds = seconds.round() as u64;
+    let (hours, minutes, seconds) = (seconds / 3600, seconds / 60 % 60, seconds % 60);
+    if hours > 0 {
+        format!("{}h{:02}m{:02}s", hours, minutes, seconds)
+    } else if minutes > 0 {
+        format!("{}m{:02}s", minutes, seconds)
+    } else {
+        format!("{}s", seconds)
+    }
+}
@@ -0,0 +1,137 @@
+//! Binary transaction input, as a stream of length-delimited `TransactionRecord` messages
+//! (see `proto/record.proto`), for pipelines that emit protobuf rather than CSV. The message
+//! types are generated from the definitions at build time.
+
+use std::io::{self, BufRead, BufReader, Read};
+use std::iter;
+
+use prost::Message;
+use rust_decimal::Decimal;
+
+use crate::input::{Record, RecordError};
+use crate::transaction::{Transaction, Type};
+
+include!(concat!(env!("OUT_DIR"), "/te.record.rs"));
+
+// Upper bound for the size of a single message. Records are tiny, so larger sizes mean the
+// framing is off (i.e. the input isn't length-delimited), and allocating that much is
+// pointless.
+const MAX_MESSAGE_LEN: u64 = 1 << 20;
+
+/// Deserializes transactions from length-delimited `TransactionRecord` messages. Records are
+/// numbered from 1, in place of the line numbers of textual formats.
+pub fn records<R: Read>(input: R) -> impl Iterator<Item = Record> {
+    records_with_offsets(input).map(|(record, _)| record)
+}
+
+/// Same as `records`, but also yields the byte offset (relative to the start of `input`) the
+/// next message starts at along with every record, i.e. to resume reading from there later.
+pub fn records_with_offsets<R: Read>(input: R) -> impl Iterator<Item = (Record, u64)> {
+    let mut input = BufReader::new(input);
+    let mut buf = Vec::new();
+    let mut number = 0;
+    let mut offset = 0;
+    let mut failed = false;
+    iter::from_fn(move || {
+        if failed {
+            return None;
+        }
+        number += 1;
+        let len = match read_length(&mut input) {
+            Ok(Some((len, size))) => {
+                offset += size;
+                len
+            }
+            Ok(None) => return None,
+            Err(e) => {
+                failed = true;
+                return Some(((number, Err(RecordError::Io(e))), offset));
+            }
+        };
+
+        // Once the framing is off, nothing after it can be read either.
+        buf.resize(len as usize, 0);
+        if let Err(e) = input.read_exact(&mut buf) {
+            failed = true;
+            return Some(((number, Err(RecordError::Io(e))), offset));
+        }
+        offset += len;
+
+        let result = TransactionRecord::decode(buf.as_slice())
+            .map_err(|e| e.to_string())
+            .and_then(TransactionRecord::into_transaction)
+            .map_err(|reason| RecordError::Protobuf {
+                record: number,
+                reason,
+            });
+        Some(((number, result), offset))
+    })
+}
+
+// Reads the varint in front of a message: its length, and the number of bytes it took up.
+// Returns `None` at the end of the input.
+fn read_length<R: BufRead>(input: &mut R) -> io::Result<Option<(u64, u64)>> {
+    let mut len = 0;
+    for size in 1..=10 {
+        let mut byte = [0];
+        if input.read(&mut byte)? == 0 {
+            if size == 1 {
+                return Ok(None);
+            }
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        len |= u64::from(byte[0] & 0x7f) << (7 * (size - 1));
+        if byte[0] & 0x80 == 0 {
+            if len > MAX_MESSAGE_LEN {
+                let message = format!("message of {} bytes exceeds the maximum", len);
+                return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+            }
+            return Ok(Some((len, size)));
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "invalid message length",
+    ))
+}
+
+impl TransactionRecord {
+    fn into_transaction(self) -> Result<Transaction, String> {
+        let type_: Type = self
+            .r#type
+            .parse()
+            .map_err(|e| format!("invalid transaction type: {}", e))?;
+        let amount = self
+            .amount
+            .map(|amount| amount.trim().parse::<Decimal>())
+            .transpose()
+            .map_err(|e| format!("invalid amount: {}", e))?;
+
+        let mut transaction = Transaction::new(type_, self.client, self.tx, amount);
+        if !self.currency.is_empty() {
+            transaction = transaction.with_currency(self.currency);
+        }
+        if let Some(to) = self.to {
+            transaction = transaction.with_destination(to);
+        }
+        if let Some(timestamp) = self.timestamp {
+            transaction = transaction.with_timestamp(timestamp);
+        }
+        if let Some(expires) = self.expires {
+            transaction = transaction.with_expiry(expires);
+        }
+        if !self.tenant.is_empty() {
+            transaction = transaction.with_tenant(self.tenant);
+        }
+        if !self.memo.is_empty() {
+            transaction = transaction.with_memo(self.memo);
+        }
+        if !self.reference.is_empty() {
+            transaction = transaction.with_reference(self.reference);
+        }
+        if let Some(sequence) = self.seq {
+            transaction = transaction.with_sequence(sequence);
+        }
+        Ok(transaction)
+    }
+}
@@ -0,0 +1,156 @@
+//! Python bindings for replaying scenarios in notebooks, built with `--features pyo3` and
+//! packaged via maturin (see `pyproject.toml`), as the `te` module. Transactions are dicts
+//! with the same fields as the JSON Lines input, and the accounts come back as a list of
+//! dicts with the columns of the account report, so they load straight into pandas:
+//!
+//! ```python
+//! import pandas as pd
+//! import te
+//!
+//! engine = te.TransactionEngine()
+//! engine.process_csv("transactions.csv")
+//! engine.process_dict({"type": "withdrawal", "client": 1, "tx": 99, "amount": "2.5"})
+//! accounts = pd.DataFrame(engine.accounts())
+//! ```
+//!
+//! Amounts are `decimal.Decimal`s, so they're as exact as in the engine.
+
+use std::fs::File;
+use std::io::BufReader;
+
+use pyo3::create_exception;
+use pyo3::exceptions::{PyException, PyIOError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::{PyBool, PyDict, PyFloat, PyInt};
+
+use crate::account::AccountColumn;
+use crate::input::parse_json_transaction;
+use crate::transaction::TransactionEngine;
+
+// The columns of the account report which hold amounts, and turn into `decimal.Decimal`s.
+const AMOUNT_COLUMNS: [AccountColumn; 7] = [
+    AccountColumn::Available,
+    AccountColumn::Held,
+    AccountColumn::Total,
+    AccountColumn::Overdraft,
+    AccountColumn::DisputedAmount,
+    AccountColumn::ResolvedAmount,
+    AccountColumn::ChargebackAmount,
+];
+
+create_exception!(
+    te,
+    TransactionRejected,
+    PyException,
+    "Raised when the engine rejects a transaction, with the reason as the message."
+);
+
+/// The engine, as exposed to Python (under the name `TransactionEngine`). Engines stay on the
+/// thread which created them.
+#[pyclass(name = "TransactionEngine", unsendable)]
+pub struct PyEngine {
+    engine: TransactionEngine,
+}
+
+#[pymethods]
+impl PyEngine {
+    /// Creates an engine with the default configuration.
+    #[new]
+    pub fn new() -> PyEngine {
+        PyEngine {
+            engine: TransactionEngine::new(),
+        }
+    }
+
+    /// Processes the CSV file at `path` (with a header row, same as the input of the binary),
+    /// and returns the summary of the outcome as a dict. Invalid records are skipped, and
+    /// counted as `invalid`.
+    pub fn process_csv<'py>(&mut self, py: Python<'py>, path: &str) -> PyResult<Bound<'py, PyAny>> {
+        let file = File::open(path)
+            .map_err(|e| PyIOError::new_err(format!("Unable to open `{}`: {}", path, e)))?;
+        let summary = self
+            .engine
+            .process_from_reader(BufReader::new(file))
+            .map_err(|e| PyIOError::new_err(format!("Unable to read `{}`: {}", path, e)))?;
+        let summary = serde_json::to_string(&summary).expect("Summaries are always serializable");
+        from_json(py, &summary)
+    }
+
+    /// Processes a single transaction, given as a dict. Raises a `ValueError` if it's invalid,
+    /// and `TransactionRejected` with the reason if it gets rejected (which leaves the state
+    /// unchanged).
+    pub fn process_dict(&mut self, record: &Bound<'_, PyDict>) -> PyResult<()> {
+        // Going through JSON, so amounts are parsed the same way as for JSON Lines input.
+        let json = serde_json::Value::Object(
+            record
+                .iter()
+                .map(|(key, value)| Ok((key.str()?.to_string(), to_json(&value)?)))
+                .collect::<PyResult<_>>()?,
+        );
+        let transaction = parse_json_transaction(&json.to_string())
+            .map_err(|e| PyValueError::new_err(format!("Invalid transaction: {}", e)))?;
+        self.engine
+            .process_transaction(transaction)
+            .map_err(|e| TransactionRejected::new_err(format!("{}: {}", e.name(), e)))
+    }
+
+    /// The account report (see `TransactionEngine::account_records`), as a list of dicts.
+    pub fn accounts<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        let records = serde_json::to_string(&self.engine.account_records())
+            .expect("Account records are always serializable");
+        let accounts = from_json(py, &records)?;
+        let decimal = py.import("decimal")?.getattr("Decimal")?;
+        for account in accounts.try_iter()? {
+            let account = account?.cast_into::<PyDict>()?;
+            for column in AMOUNT_COLUMNS.iter() {
+                if let Some(amount) = account.get_item(column.name())? {
+                    account.set_item(column.name(), decimal.call1((amount,))?)?;
+                }
+            }
+        }
+        Ok(accounts)
+    }
+}
+
+impl Default for PyEngine {
+    fn default() -> Self {
+        PyEngine::new()
+    }
+}
+
+// Converts a value passed from Python into JSON. Floats keep the digits Python prints them
+// with, and anything which isn't a plain JSON value (i.e. a `decimal.Decimal`) is taken as
+// a string.
+fn to_json(value: &Bound<'_, PyAny>) -> PyResult<serde_json::Value> {
+    use serde_json::Value;
+
+    if value.is_none() {
+        Ok(Value::Null)
+    } else if value.is_instance_of::<PyBool>() {
+        Ok(Value::Bool(value.extract()?))
+    } else if value.is_instance_of::<PyInt>() {
+        Ok(Value::Number(value.extract::<i64>()?.into()))
+    } else if value.is_instance_of::<PyFloat>() {
+        Ok(Value::String(value.repr()?.to_string()))
+    } else {
+        Ok(Value::String(value.str()?.to_string()))
+    }
+}
+
+// Parses `json` with Python's `json` module, which keeps the order of the fields (the one of
+// the columns, for the account report).
+fn from_json<'py>(py: Python<'py>, json: &str) -> PyResult<Bound<'py, PyAny>> {
+    py.import("json")?.call_method1("loads", (json,))
+}
+
+/// The `te` Python module.
+#[pymodule]
+#[pyo3(name = "te")]
+fn te_module(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyEngine>()?;
+    m.add(
+        "TransactionRejected",
+        m.py().get_type::<TransactionRejected>(),
+    )?;
+    Ok(())
+}
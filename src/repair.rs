@@ -0,0 +1,164 @@
+//! Reconciliation of engine states with values that no sequence of transactions leads to
+//! (i.e. checkpoints edited by hand, or written by an engine with a bug), as done by the
+//! `repair` subcommand. `TransactionEngine::anomalies` reports them, and
+//! `TransactionEngine::repair` applies the corrective adjustments documented on `Correction`.
+
+use std::fmt;
+
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use crate::transaction::{ClientId, Currency, TransactionId};
+
+/// A value of the engine state which is impossible to reach by processing transactions.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "anomaly", rename_all = "snake_case")]
+pub enum Anomaly {
+    /// Held funds below zero.
+    NegativeHeld {
+        client: ClientId,
+        #[serde(skip_serializing_if = "String::is_empty")]
+        currency: Currency,
+        held: Decimal,
+    },
+    /// A frozen (locked) account with disputes that are still open, i.e. which were never
+    /// resolved or charged back, along with the disputed transactions.
+    FrozenWithOpenDisputes {
+        client: ClientId,
+        disputes: Vec<TransactionId>,
+    },
+    /// A balance whose total isn't the sum of its available and held funds (see
+    /// `TransactionEngine::audit`).
+    TotalMismatch {
+        client: ClientId,
+        #[serde(skip_serializing_if = "String::is_empty")]
+        currency: Currency,
+        available: Decimal,
+        held: Decimal,
+        total: Decimal,
+    },
+}
+
+impl fmt::Display for Anomaly {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Anomaly::NegativeHeld {
+                client,
+                currency,
+                held,
+            } => {
+                write!(f, "held funds of client {}", client)?;
+                write_currency(f, currency)?;
+                write!(f, " are negative ({})", held)
+            }
+            Anomaly::FrozenWithOpenDisputes { client, disputes } => {
+                write!(
+                    f,
+                    "account of client {} is frozen with open disputes",
+                    client
+                )?;
+                for (i, tx) in disputes.iter().enumerate() {
+                    write!(f, "{}{}", if i == 0 { " of " } else { ", " }, tx)?;
+                }
+                Ok(())
+            }
+            Anomaly::TotalMismatch {
+                client,
+                currency,
+                available,
+                held,
+                total,
+            } => {
+                write!(f, "total funds of client {}", client)?;
+                write_currency(f, currency)?;
+                write!(
+                    f,
+                    " are {}, but available ({}) and held ({}) add up to {}",
+                    total,
+                    available,
+                    held,
+                    available + held
+                )
+            }
+        }
+    }
+}
+
+/// The corrective adjustments of `TransactionEngine::repair`, which are applied in this
+/// order.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Correction {
+    /// Resolves an open dispute of a frozen account, as a `resolve` row would (releasing the
+    /// held funds to available, except for withdrawals). The account stays frozen.
+    ResolveDispute,
+    /// Resets the count of open disputes of a frozen account, when it counts disputes which
+    /// aren't part of the history. No funds move.
+    ClearDisputes,
+    /// Sets negative held funds to the amount still held by open disputes and holds, and
+    /// moves the difference out of the available funds, so the total stays the same.
+    RestoreHeld,
+    /// Sets the total to the sum of the available and held funds, which are the ones that
+    /// transactions move.
+    RecomputeTotal,
+}
+
+/// A change made to an account by `TransactionEngine::repair`, with how much the funds
+/// changed by.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Adjustment {
+    pub client: ClientId,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub currency: Currency,
+    pub correction: Correction,
+    /// The resolved transaction, for `Correction::ResolveDispute`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tx: Option<TransactionId>,
+    pub available: Decimal,
+    pub held: Decimal,
+    pub total: Decimal,
+}
+
+impl fmt::Display for Adjustment {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.correction {
+            Correction::ResolveDispute => write!(f, "resolved the dispute")?,
+            Correction::ClearDisputes => write!(f, "cleared the open disputes")?,
+            Correction::RestoreHeld => write!(f, "restored the held funds")?,
+            Correction::RecomputeTotal => write!(f, "recomputed the total funds")?,
+        }
+        if let Some(tx) = self.tx {
+            write!(f, " of transaction {}", tx)?;
+        }
+        write!(f, " for client {}", self.client)?;
+        write_currency(f, &self.currency)?;
+        write!(
+            f,
+            " (available {}, held {}, total {})",
+            Signed(self.available),
+            Signed(self.held),
+            Signed(self.total)
+        )
+    }
+}
+
+// An amount with an explicit sign, for changes of the funds.
+struct Signed(Decimal);
+
+impl fmt::Display for Signed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0.is_sign_negative() {
+            write!(f, "{}", self.0)
+        } else {
+            write!(f, "+{}", self.0)
+        }
+    }
+}
+
+fn write_currency(f: &mut fmt::Formatter, currency: &str) -> fmt::Result {
+    if currency.is_empty() {
+        Ok(())
+    } else {
+        write!(f, " in {}", currency)
+    }
+}
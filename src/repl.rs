@@ -0,0 +1,74 @@
+use std::io::{self, BufRead, Write};
+
+use te::input::reader_builder;
+use te::transaction::{self, ClientId, Error, Transaction, TransactionEngine};
+
+// Runs an interactive session on top of `engine`, which is handy for debugging and demos.
+// Every line read from stdin is either a command (`print` to dump the current account
+// data, `history <client>` to list the transactions retained for a client, `reset` to start
+// over from scratch, or `quit` to exit), or a header-less CSV row describing a transaction
+// (i.e. `deposit, 1, 1, 10.0`). The session ends on `quit` or at the end of the input.
+pub fn run(engine: &mut TransactionEngine) -> io::Result<()> {
+    let stdin = io::stdin();
+    let mut stdout = io::stdout();
+
+    prompt(&mut stdout)?;
+
+    for line in stdin.lock().lines() {
+        let line = line?;
+
+        match line.trim() {
+            "" => {}
+            "print" => engine.write_accounts(&mut stdout)?,
+            "reset" => engine.reset()?,
+            "quit" => break,
+            row if row.starts_with("history ") => match row["history ".len()..].trim().parse() {
+                Ok(client) => write_history(engine, client, &mut stdout)?,
+                Err(e) => writeln!(stdout, "invalid client id: {}", e)?,
+            },
+            row => match parse_transaction(row) {
+                Ok(t) => {
+                    if let Err(e) = engine.process_transaction(t) {
+                        writeln!(stdout, "error: {}", e)?;
+                    }
+                    for warning in engine.drain_warnings() {
+                        writeln!(stdout, "warning: {}", warning)?;
+                    }
+                }
+                Err(e) => writeln!(stdout, "invalid record: {}", e)?,
+            },
+        }
+
+        prompt(&mut stdout)?;
+    }
+
+    Ok(())
+}
+
+// Writes the transactions retained for `client` as CSV rows (with a header).
+fn write_history<W: Write>(
+    engine: &TransactionEngine,
+    client: ClientId,
+    w: &mut W,
+) -> io::Result<()> {
+    if engine.account(client).is_none() {
+        return writeln!(w, "error: {}", Error::AccountNotFound { client });
+    }
+
+    transaction::write_transactions(engine.transactions_for(client), w)?;
+    Ok(())
+}
+
+fn prompt<W: Write>(w: &mut W) -> io::Result<()> {
+    write!(w, "> ")?;
+    w.flush()
+}
+
+// Deserializes a single transaction, using the same CSV logic as for regular input files.
+fn parse_transaction(row: &str) -> csv::Result<Transaction> {
+    reader_builder(false)
+        .from_reader(row.as_bytes())
+        .deserialize()
+        .next()
+        .expect("A non-empty row always yields a record")
+}
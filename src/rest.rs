@@ -0,0 +1,299 @@
+//! HTTP/REST front-end for the engine, so dashboards can query balances (and submit
+//! transactions) while it's busy with another feed. All endpoints exchange JSON:
+//! - `POST /transactions` takes a transaction with the same fields as the JSON Lines input,
+//!   and replies with its `status` (`applied`, `rejected` along with the `error`, or
+//!   `invalid` for bodies that don't describe a transaction).
+//! - `GET /accounts` lists the rows of the account report, ordered by client id.
+//! - `GET /accounts/{client}` lists the rows for a single client.
+//!
+//! On top of that, `GET /metrics` exports the metrics of the engine in the Prometheus text
+//! format (see `TransactionEngine::with_metrics`), which is also available on its own via
+//! `metrics_router`. `notifications_router` adds `GET /accounts/stream`, which pushes the
+//! changes to accounts as server-sent events (see `AccountNotifier`).
+
+use std::convert::Infallible;
+
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::response::sse::{Event, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use rust_decimal::Decimal;
+use serde::Serialize;
+use tokio::sync::broadcast;
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::{Stream, StreamExt};
+
+use crate::account::Account;
+use crate::async_engine::AsyncTransactionEngine;
+use crate::input::parse_json_transaction;
+use crate::observer::EngineObserver;
+use crate::transaction::{ClientId, Error, Outcome, Transaction, TransactionId};
+
+// Number of notifications buffered for every subscriber. Subscribers that fall further
+// behind miss the oldest ones (and get told how many), instead of slowing down the engine.
+const BUFFERED_NOTIFICATIONS: usize = 1024;
+
+// The reply to a submitted transaction.
+#[derive(Serialize)]
+struct Submitted {
+    status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+}
+
+/// Builds the routes of the API on top of `engine`, i.e. for `axum::serve`.
+pub fn router(engine: AsyncTransactionEngine) -> Router {
+    Router::new()
+        .route("/transactions", post(submit_transaction))
+        .route("/accounts", get(list_accounts))
+        .route("/accounts/:client", get(get_account))
+        .route("/metrics", get(export_metrics))
+        .with_state(engine)
+}
+
+/// Builds a router which only serves `GET /metrics`, i.e. next to the gRPC API.
+pub fn metrics_router(engine: AsyncTransactionEngine) -> Router {
+    Router::new()
+        .route("/metrics", get(export_metrics))
+        .with_state(engine)
+}
+
+/// Builds a router which serves `GET /accounts/stream` from `notifier`, i.e. to be merged
+/// into the one of `router`. Every subscriber gets the changes from the time it subscribed.
+pub fn notifications_router(notifier: AccountNotifier) -> Router {
+    Router::new()
+        .route("/accounts/stream", get(stream_accounts))
+        .with_state(notifier)
+}
+
+/// An observer (see `TransactionEngine::with_observer`) which passes on every change to an
+/// account to the subscribers of `GET /accounts/stream`, whatever the transaction came from
+/// (i.e. the input an engine is busy with as well as `POST /transactions`). Clones share the
+/// same subscribers, so one can go to the engine and the other one to the router.
+///
+/// There are three kinds of events, all of which come with the account afterwards (as an
+/// `AccountView`): `account` after its balances or status changed, `frozen` after it got
+/// frozen (following the `account` event of the chargeback), and `unfrozen` after it got
+/// unlocked again.
+#[derive(Clone)]
+pub struct AccountNotifier {
+    notifications: broadcast::Sender<Notification>,
+}
+
+// An event for the subscribers, with the account serialized up front, since the views
+// borrow from the engine.
+#[derive(Clone)]
+struct Notification {
+    event: &'static str,
+    account: String,
+}
+
+impl AccountNotifier {
+    pub fn new() -> Self {
+        let (notifications, _) = broadcast::channel(BUFFERED_NOTIFICATIONS);
+        AccountNotifier { notifications }
+    }
+
+    fn notify(&self, event: &'static str, client: ClientId, account: &Account) {
+        // Nothing to do when nobody is subscribed.
+        if self.notifications.receiver_count() == 0 {
+            return;
+        }
+        if let Ok(account) = serde_json::to_string(&account.view(client)) {
+            let _ = self.notifications.send(Notification { event, account });
+        }
+    }
+}
+
+impl Default for AccountNotifier {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EngineObserver for AccountNotifier {
+    fn on_deposit(&mut self, transaction: &Transaction, account: &Account) {
+        self.notify("account", transaction.client(), account);
+    }
+
+    fn on_withdrawal(&mut self, transaction: &Transaction, account: &Account) {
+        self.notify("account", transaction.client(), account);
+    }
+
+    fn on_transfer(&mut self, transaction: &Transaction, sender: &Account, receiver: &Account) {
+        self.notify("account", transaction.client(), sender);
+        if let Some(to) = transaction.destination() {
+            self.notify("account", to, receiver);
+        }
+    }
+
+    fn on_void(&mut self, transaction: &Transaction, account: &Account) {
+        self.notify("account", transaction.client(), account);
+    }
+
+    fn on_dispute(&mut self, client: ClientId, _: TransactionId, _: Decimal, account: &Account) {
+        self.notify("account", client, account);
+    }
+
+    fn on_resolve(&mut self, client: ClientId, _: TransactionId, _: Decimal, account: &Account) {
+        self.notify("account", client, account);
+    }
+
+    fn on_chargeback(&mut self, client: ClientId, _: TransactionId, _: Decimal, account: &Account) {
+        self.notify("account", client, account);
+    }
+
+    fn on_chargeback_reversal(
+        &mut self,
+        client: ClientId,
+        _: TransactionId,
+        _: Decimal,
+        account: &Account,
+    ) {
+        self.notify("account", client, account);
+    }
+
+    fn on_freeze(&mut self, client: ClientId, account: &Account) {
+        self.notify("frozen", client, account);
+    }
+
+    fn on_unfreeze(&mut self, client: ClientId, account: &Account) {
+        self.notify("unfrozen", client, account);
+    }
+
+    fn on_close(&mut self, client: ClientId, account: &Account) {
+        self.notify("account", client, account);
+    }
+
+    fn on_reopen(&mut self, client: ClientId, account: &Account) {
+        self.notify("account", client, account);
+    }
+
+    fn on_review(&mut self, client: ClientId, account: &Account) {
+        self.notify("account", client, account);
+    }
+
+    fn on_approve(&mut self, client: ClientId, account: &Account) {
+        self.notify("account", client, account);
+    }
+
+    fn on_adjustment(&mut self, client: ClientId, _: &str, _: Decimal, account: &Account) {
+        self.notify("account", client, account);
+    }
+}
+
+// Rejections are reported as `422 Unprocessable Entity`, and invalid bodies as
+// `400 Bad Request`.
+async fn submit_transaction(
+    State(engine): State<AsyncTransactionEngine>,
+    body: String,
+) -> (StatusCode, Json<Submitted>) {
+    let transaction = match parse_json_transaction(&body) {
+        Ok(transaction) => transaction,
+        Err(e) => {
+            let reply = Submitted {
+                status: "invalid",
+                error: None,
+                reason: Some(e.to_string()),
+            };
+            return (StatusCode::BAD_REQUEST, Json(reply));
+        }
+    };
+
+    match engine.process_detailed(transaction).await {
+        Outcome::Rejected(e) => {
+            let reply = Submitted {
+                status: "rejected",
+                error: Some(e.name()),
+                reason: Some(e.to_string()),
+            };
+            (StatusCode::UNPROCESSABLE_ENTITY, Json(reply))
+        }
+        Outcome::Applied | Outcome::NoOp => {
+            let reply = Submitted {
+                status: "applied",
+                error: None,
+                reason: None,
+            };
+            (StatusCode::OK, Json(reply))
+        }
+    }
+}
+
+// The rows are serialized on the engine thread, since they borrow from the engine.
+async fn list_accounts(State(engine): State<AsyncTransactionEngine>) -> Response {
+    let body = engine
+        .with_engine(|engine| serde_json::to_string(&engine.account_records()))
+        .await;
+    json_response(StatusCode::OK, body)
+}
+
+async fn get_account(
+    State(engine): State<AsyncTransactionEngine>,
+    Path(client): Path<ClientId>,
+) -> Response {
+    let records = engine
+        .with_engine(move |engine| {
+            let records = engine.client_account_records(client);
+            (!records.is_empty()).then(|| serde_json::to_string(&records))
+        })
+        .await;
+
+    match records {
+        Some(body) => json_response(StatusCode::OK, body),
+        None => {
+            let reason = Error::AccountNotFound { client }.to_string();
+            (
+                StatusCode::NOT_FOUND,
+                Json(serde_json::json!({ "reason": reason })),
+            )
+                .into_response()
+        }
+    }
+}
+
+// Subscribers which fall behind get a `lagged` event with the number of notifications they
+// missed, and the stream carries on from there.
+async fn stream_accounts(
+    State(notifier): State<AccountNotifier>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let notifications = BroadcastStream::new(notifier.notifications.subscribe());
+    Sse::new(notifications.map(|notification| {
+        let event = match notification {
+            Ok(Notification { event, account }) => Event::default().event(event).data(account),
+            Err(BroadcastStreamRecvError::Lagged(missed)) => {
+                Event::default().event("lagged").data(missed.to_string())
+            }
+        };
+        Ok(event)
+    }))
+}
+
+// Engines without metrics enabled reply with `404 Not Found`.
+async fn export_metrics(State(engine): State<AsyncTransactionEngine>) -> Response {
+    let metrics = engine
+        .with_engine(|engine| engine.metrics().map(|m| m.render_prometheus()))
+        .await;
+
+    match metrics {
+        Some(body) => (
+            StatusCode::OK,
+            [("content-type", "text/plain; version=0.0.4")],
+            body,
+        )
+            .into_response(),
+        None => (StatusCode::NOT_FOUND, "Metrics are not enabled").into_response(),
+    }
+}
+
+fn json_response(status: StatusCode, body: serde_json::Result<String>) -> Response {
+    match body {
+        Ok(body) => (status, [("content-type", "application/json")], body).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()).into_response(),
+    }
+}
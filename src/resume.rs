@@ -0,0 +1,87 @@
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use te::transaction::{Snapshot, TransactionEngine};
+
+use crate::{read_state, write_state};
+
+// Name of the checkpoint within the checkpoint directory.
+const CHECKPOINT_FILE: &str = "checkpoint.json";
+
+// Where processing of the input got to: the byte offset and the line the next record starts
+// at. Offsets are in the decompressed input, for compressed files.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+pub struct Position {
+    pub offset: u64,
+    pub line: u64,
+}
+
+impl Default for Position {
+    fn default() -> Self {
+        Position { offset: 0, line: 1 }
+    }
+}
+
+// A checkpoint for resuming the processing of an input (see `--checkpoint-every`): the engine
+// state after the records up to `position`, and the header row of CSV input, which has been
+// read already by then.
+#[derive(Deserialize, Serialize)]
+pub struct ResumePoint {
+    pub position: Position,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub headers: Option<Vec<String>>,
+    pub state: Snapshot,
+}
+
+impl ResumePoint {
+    // Loads the checkpoint from `dir`, if there is one.
+    pub fn load(dir: &str) -> io::Result<Option<Self>> {
+        let state = match read_state(checkpoint_path(dir)) {
+            Ok(state) => state,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        Ok(Some(serde_json::from_slice(&state)?))
+    }
+
+    // Replaces the checkpoint in `dir` with the current state of `engine`. The checkpoint is
+    // written to a temporary file first and then renamed, so a crash while saving leaves the
+    // previous one in place. Encrypted the same way as other saved states.
+    pub fn save(
+        dir: &str,
+        engine: &TransactionEngine,
+        position: Position,
+        headers: Option<Vec<String>>,
+    ) -> io::Result<()> {
+        fs::create_dir_all(dir)?;
+        let path = checkpoint_path(dir);
+        let tmp = path.with_extension("json.tmp");
+
+        let point = ResumePoint {
+            position,
+            headers,
+            state: engine.snapshot(),
+        };
+        write_state(&tmp, &serde_json::to_vec(&point)?)?;
+        fs::rename(&tmp, &path)
+    }
+}
+
+// Skips the input up to `position`, which has to be within the input.
+pub fn skip_to<R: Read>(input: &mut R, position: Position) -> io::Result<()> {
+    let skipped = io::copy(&mut input.take(position.offset), &mut io::sink())?;
+    if skipped < position.offset {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "the input is shorter than the checkpointed position",
+        ));
+    }
+    Ok(())
+}
+
+fn checkpoint_path(dir: &str) -> PathBuf {
+    Path::new(dir).join(CHECKPOINT_FILE)
+}
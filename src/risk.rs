@@ -0,0 +1,190 @@
+//! Fraud-flagging rules, which tag accounts with risk flags for review (i.e. by a risk team)
+//! rather than rejecting any transactions, unlike validators. Every rule that flags an account
+//! adds its `score` to the risk score of the account, which is reported along with the
+//! accounts. The rules are usually loaded from a TOML file (see `RiskRules::load`), and the
+//! ones that aren't listed are disabled:
+//!
+//! ```toml
+//! [rapid_cycle]     # withdrawing most of a deposit shortly after making it
+//! window = 3600     # seconds
+//! ratio = "0.9"     # of the deposit (the default)
+//! score = 50
+//!
+//! [disputes]        # filing more than `max` disputes
+//! max = 3
+//! score = 30
+//!
+//! [large_amount]    # deposits, withdrawals, or transfers of at least `threshold`
+//! threshold = "10000"
+//! score = 20
+//! ```
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::io::{self, Read};
+
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+
+use crate::config;
+use crate::transaction::{ClientId, Transaction, Type};
+
+/// What an account has been flagged for, by the rule of the same name.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RiskFlag {
+    RapidCycle,
+    FrequentDisputes,
+    LargeAmount,
+}
+
+impl fmt::Display for RiskFlag {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            RiskFlag::RapidCycle => "rapid_cycle",
+            RiskFlag::FrequentDisputes => "frequent_disputes",
+            RiskFlag::LargeAmount => "large_amount",
+        })
+    }
+}
+
+/// Flags withdrawals (and outgoing transfers) of at least `ratio` times a deposit the client
+/// made within the last `window` seconds. Only applies to transactions with a timestamp.
+#[derive(Clone, Debug, Deserialize)]
+pub struct RapidCycleRule {
+    pub window: u64,
+    #[serde(default = "default_ratio")]
+    pub ratio: Decimal,
+    pub score: u32,
+}
+
+fn default_ratio() -> Decimal {
+    Decimal::new(9, 1)
+}
+
+/// Flags clients once they've filed more than `max` disputes.
+#[derive(Clone, Debug, Deserialize)]
+pub struct DisputesRule {
+    pub max: usize,
+    pub score: u32,
+}
+
+/// Flags deposits, withdrawals, and transfers of at least `threshold`.
+#[derive(Clone, Debug, Deserialize)]
+pub struct LargeAmountRule {
+    pub threshold: Decimal,
+    pub score: u32,
+}
+
+/// The configured rules, which are registered via `TransactionEngine::with_risk_rules`.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RiskRules {
+    pub rapid_cycle: Option<RapidCycleRule>,
+    #[serde(rename = "disputes")]
+    pub frequent_disputes: Option<DisputesRule>,
+    pub large_amount: Option<LargeAmountRule>,
+}
+
+impl RiskRules {
+    /// Reads the rules from a TOML document, with a table for every enabled rule.
+    pub fn load<R: Read>(r: R) -> io::Result<Self> {
+        config::read_toml(r)
+    }
+
+    /// The risk score of an account with the given flags, as the sum of the scores of the
+    /// rules which raised them.
+    pub fn score<I: IntoIterator<Item = RiskFlag>>(&self, flags: I) -> u32 {
+        flags
+            .into_iter()
+            .map(|flag| match flag {
+                RiskFlag::RapidCycle => self.rapid_cycle.as_ref().map_or(0, |r| r.score),
+                RiskFlag::FrequentDisputes => {
+                    self.frequent_disputes.as_ref().map_or(0, |r| r.score)
+                }
+                RiskFlag::LargeAmount => self.large_amount.as_ref().map_or(0, |r| r.score),
+            })
+            .fold(0, u32::saturating_add)
+    }
+}
+
+// Evaluates the rules on the transactions applied by the engine, keeping track of what the
+// rules that span transactions need to know about every client.
+#[derive(Clone, Debug)]
+pub(crate) struct RiskMonitor {
+    rules: RiskRules,
+    // The timestamps and amounts of the recent deposits of each client, oldest first.
+    deposits: HashMap<ClientId, VecDeque<(u64, Decimal)>>,
+    // The number of disputes filed by each client.
+    disputes: HashMap<ClientId, usize>,
+}
+
+impl RiskMonitor {
+    pub(crate) fn new(rules: RiskRules) -> Self {
+        RiskMonitor {
+            rules,
+            deposits: HashMap::new(),
+            disputes: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn rules(&self) -> &RiskRules {
+        &self.rules
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.deposits.clear();
+        self.disputes.clear();
+    }
+
+    // The flags `transaction` raises for its client, once it has been applied.
+    pub(crate) fn evaluate(&mut self, transaction: &Transaction) -> Vec<RiskFlag> {
+        let mut flags = Vec::new();
+        let client = transaction.client();
+        let amount = transaction.amount();
+        let moves_funds = matches!(
+            transaction.type_(),
+            Type::Deposit | Type::Withdrawal | Type::Transfer
+        );
+
+        if let Some(rule) = &self.rules.large_amount {
+            if moves_funds && amount >= rule.threshold {
+                flags.push(RiskFlag::LargeAmount);
+            }
+        }
+
+        if let Some(rule) = &self.rules.frequent_disputes {
+            if transaction.type_() == Type::Dispute {
+                let disputes = self.disputes.entry(client).or_insert(0);
+                *disputes += 1;
+                if *disputes > rule.max {
+                    flags.push(RiskFlag::FrequentDisputes);
+                }
+            }
+        }
+
+        if let (Some(rule), Some(timestamp)) = (&self.rules.rapid_cycle, transaction.timestamp()) {
+            // Dropping the deposits which fell out of the window first, so the list stays
+            // short for clients with many deposits.
+            let deposits = self.deposits.entry(client).or_default();
+            while deposits
+                .front()
+                .is_some_and(|&(t, _)| timestamp.saturating_sub(t) > rule.window)
+            {
+                deposits.pop_front();
+            }
+
+            match transaction.type_() {
+                Type::Deposit => deposits.push_back((timestamp, amount)),
+                Type::Withdrawal | Type::Transfer
+                    if deposits.iter().any(|&(_, d)| amount >= d * rule.ratio) =>
+                {
+                    flags.push(RiskFlag::RapidCycle);
+                }
+                _ => {}
+            }
+        }
+
+        flags
+    }
+}
@@ -0,0 +1,186 @@
+//! gRPC front-end for the engine, as defined in `proto/engine.proto`. The message types and
+//! the `engine_server` module are generated from the definitions at build time, while
+//! `EngineService` implements the service on top of an `AsyncTransactionEngine`.
+
+// The service methods have to return `tonic::Status` errors, which are fairly large.
+#![allow(clippy::result_large_err)]
+
+use std::pin::Pin;
+
+use rust_decimal::Decimal;
+use tokio::sync::broadcast;
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::{Stream, StreamExt};
+use tonic::{Request, Response, Status};
+
+use crate::account::{self, rounded};
+use crate::async_engine::AsyncTransactionEngine;
+use crate::transaction::{ClientId, Error, Outcome, Transaction, TransactionEngine, Type};
+
+tonic::include_proto!("te");
+
+use self::engine_server::{Engine, EngineServer};
+
+// Number of account updates buffered for every `StreamAccounts` caller. Callers that fall
+// further behind get their stream terminated, instead of slowing down everyone else.
+const BUFFERED_UPDATES: usize = 1024;
+
+/// Serves the gRPC API from a shared engine. All requests go through the same
+/// `AsyncTransactionEngine`, so they're processed one at a time, in the order they arrive.
+pub struct EngineService {
+    engine: AsyncTransactionEngine,
+    updates: broadcast::Sender<Account>,
+}
+
+impl EngineService {
+    pub fn new(engine: AsyncTransactionEngine) -> Self {
+        let (updates, _) = broadcast::channel(BUFFERED_UPDATES);
+        EngineService { engine, updates }
+    }
+
+    /// Wraps the service for use with `tonic::transport::Server::add_service`.
+    pub fn into_server(self) -> EngineServer<Self> {
+        EngineServer::new(self)
+    }
+}
+
+#[tonic::async_trait]
+impl Engine for EngineService {
+    async fn submit_transaction(
+        &self,
+        request: Request<TransactionRequest>,
+    ) -> Result<Response<TransactionReply>, Status> {
+        let transaction = parse_transaction(request.into_inner())?;
+
+        // Processing and looking up the affected accounts in a single job, so the streamed
+        // updates can't include the effects of later transactions.
+        let (outcome, warnings, updated) = self
+            .engine
+            .with_engine(move |engine| {
+                let clients = [Some(transaction.client()), transaction.destination()];
+                let outcome = engine.process_transaction_detailed(transaction);
+                let warnings = engine.drain_warnings().map(|w| w.to_string()).collect();
+                let updated: Vec<_> = match outcome {
+                    Outcome::Applied => clients
+                        .iter()
+                        .flatten()
+                        .filter_map(|&client| account_message(engine, client))
+                        .collect(),
+                    _ => Vec::new(),
+                };
+                (outcome, warnings, updated)
+            })
+            .await;
+
+        // Sending only fails when nobody is streaming the updates.
+        for account in updated {
+            let _ = self.updates.send(account);
+        }
+
+        let reply = match outcome {
+            Outcome::Rejected(e) => TransactionReply {
+                accepted: false,
+                error: e.name().to_owned(),
+                reason: e.to_string(),
+                warnings,
+            },
+            Outcome::Applied | Outcome::NoOp => TransactionReply {
+                accepted: true,
+                warnings,
+                ..Default::default()
+            },
+        };
+        Ok(Response::new(reply))
+    }
+
+    async fn get_account(
+        &self,
+        request: Request<GetAccountRequest>,
+    ) -> Result<Response<Account>, Status> {
+        let client = ClientId::new(request.into_inner().client);
+        self.engine
+            .with_engine(move |engine| account_message(engine, client))
+            .await
+            .map(Response::new)
+            .ok_or_else(|| Status::not_found(Error::AccountNotFound { client }.to_string()))
+    }
+
+    async fn list_accounts(
+        &self,
+        _request: Request<ListAccountsRequest>,
+    ) -> Result<Response<ListAccountsReply>, Status> {
+        let accounts = self
+            .engine
+            .with_engine(|engine| {
+                let mut clients: Vec<_> = engine.accounts().map(|(client, _)| client).collect();
+                clients.sort_unstable();
+                clients
+                    .into_iter()
+                    .filter_map(|client| account_message(engine, client))
+                    .collect()
+            })
+            .await;
+        Ok(Response::new(ListAccountsReply { accounts }))
+    }
+
+    type StreamAccountsStream = Pin<Box<dyn Stream<Item = Result<Account, Status>> + Send>>;
+
+    async fn stream_accounts(
+        &self,
+        _request: Request<StreamAccountsRequest>,
+    ) -> Result<Response<Self::StreamAccountsStream>, Status> {
+        let updates = BroadcastStream::new(self.updates.subscribe()).map(|update| {
+            update.map_err(|BroadcastStreamRecvError::Lagged(missed)| {
+                Status::resource_exhausted(format!(
+                    "Missed {} account updates by falling behind",
+                    missed
+                ))
+            })
+        });
+        Ok(Response::new(Box::pin(updates)))
+    }
+}
+
+// Builds the transaction described by `request`, same as for a row of the input.
+fn parse_transaction(request: TransactionRequest) -> Result<Transaction, Status> {
+    let type_: Type = request
+        .r#type
+        .parse()
+        .map_err(|e| Status::invalid_argument(format!("Invalid transaction type: {}", e)))?;
+    let amount = request
+        .amount
+        .map(|amount| amount.parse::<Decimal>())
+        .transpose()
+        .map_err(|e| Status::invalid_argument(format!("Invalid amount: {}", e)))?;
+
+    let mut transaction = Transaction::new(type_, request.client, request.tx, amount);
+    if !request.currency.is_empty() {
+        transaction = transaction.with_currency(request.currency);
+    }
+    if let Some(to) = request.to {
+        transaction = transaction.with_destination(to);
+    }
+    Ok(transaction)
+}
+
+// Reports the account of `client` the same way as the account report, with one balance per
+// currency, and amounts rounded to the reported precision.
+fn account_message(engine: &TransactionEngine, client: ClientId) -> Option<Account> {
+    engine.account(client).map(|account: &account::Account| {
+        let balances = account
+            .balances()
+            .map(|(currency, balance)| Balance {
+                currency: currency.to_owned(),
+                available: rounded(balance.available()).to_string(),
+                held: rounded(balance.held()).to_string(),
+                total: rounded(balance.total()).to_string(),
+            })
+            .collect();
+        Account {
+            client: client.get(),
+            balances,
+            locked: account.frozen(),
+        }
+    })
+}
@@ -0,0 +1,90 @@
+//! What-if scenarios on top of the state of an engine (see `TransactionEngine::fork`), i.e.
+//! "what if this chargeback lands?": hypothetical transactions get applied to a copy of the
+//! state, whose balances can be inspected like those of the engine itself, and which is then
+//! either discarded or committed to the engine.
+//!
+//! ```
+//! use rust_decimal::Decimal;
+//! use te::{ClientId, Transaction, TransactionEngine, Type};
+//!
+//! let mut engine = TransactionEngine::new();
+//! engine
+//!     .process_transaction(Transaction::new(Type::Deposit, 1, 1, Some(Decimal::new(25, 1))))
+//!     .unwrap();
+//!
+//! let mut fork = engine.fork();
+//! fork.process_transaction(Transaction::new(Type::Dispute, 1, 1, None)).unwrap();
+//! fork.process_transaction(Transaction::new(Type::Chargeback, 1, 1, None)).unwrap();
+//! let account = fork.account(ClientId::new(1)).unwrap();
+//! assert_eq!(account.balance("").available(), Decimal::ZERO);
+//! fork.discard();
+//!
+//! let account = engine.account(ClientId::new(1)).unwrap();
+//! assert_eq!(account.balance("").available(), Decimal::new(25, 1));
+//! ```
+
+use std::ops::Deref;
+
+use crate::transaction::{Error, Transaction, TransactionEngine};
+
+/// A scenario forked off an engine, which derefs to the engine of the scenario, so its
+/// accounts, open disputes, and so on can be read the same way as those of the engine it was
+/// forked off. The engine itself can't be used until the scenario is committed or discarded
+/// (or dropped, which is the same as discarding it).
+///
+/// The engine of the scenario starts off with a copy of the state and the configuration, but
+/// without the hooks which would reach outside of it: the transaction callback, the observer,
+/// the validators, the risk rules, the write-ahead log, the metrics, the event log, and the
+/// lineage. Its history is kept in memory, whatever the ledger of the engine is.
+pub struct Fork<'a> {
+    engine: &'a mut TransactionEngine,
+    scenario: TransactionEngine,
+    // Everything processed by the scenario, in order, for `commit`.
+    transactions: Vec<Transaction>,
+}
+
+impl<'a> Fork<'a> {
+    pub(crate) fn new(engine: &'a mut TransactionEngine, scenario: TransactionEngine) -> Self {
+        Fork {
+            engine,
+            scenario,
+            transactions: Vec::new(),
+        }
+    }
+
+    /// Applies `transaction` to the scenario only (see `TransactionEngine::process_transaction`).
+    pub fn process_transaction(&mut self, transaction: Transaction) -> Result<(), Error> {
+        self.transactions.push(transaction.clone());
+        self.scenario.process_transaction(transaction)
+    }
+
+    /// The transactions processed by the scenario so far, including the rejected ones.
+    pub fn transactions(&self) -> &[Transaction] {
+        &self.transactions
+    }
+
+    /// Drops the scenario, and leaves the engine as it was.
+    pub fn discard(self) {}
+
+    /// Processes the transactions of the scenario with the engine, in the same order, and
+    /// returns the result of each. Going through the engine (rather than taking over the state
+    /// of the scenario) means they get journaled, validated, and reported the same way as
+    /// any other transaction. The results may therefore differ from those of the scenario for
+    /// transactions which a validator or a risk rule of the engine rejects, and transactions
+    /// without a timestamp get the time of its clock (if any) as of the commit.
+    pub fn commit(self) -> Vec<Result<(), Error>> {
+        let engine = self.engine;
+        self.transactions
+            .into_iter()
+            .map(|transaction| engine.process_transaction(transaction))
+            .collect()
+    }
+}
+
+impl Deref for Fork<'_> {
+    type Target = TransactionEngine;
+
+    fn deref(&self) -> &TransactionEngine {
+        &self.scenario
+    }
+}
@@ -0,0 +1,260 @@
+//! Periodic balance adjustments (i.e. interest on available funds, or monthly account fees),
+//! which are applied between transactions as the timestamps of the input move past the end
+//! of each period. The rules are usually loaded from a TOML file (see `Scheduler::load`):
+//!
+//! ```toml
+//! [[rule]]
+//! kind = "interest"
+//! rate = "0.001"   # per period, of the available funds
+//! every = "month"
+//!
+//! [[rule]]
+//! kind = "fee"
+//! amount = "2.50"
+//! every = "30d"
+//! currency = "EUR" # the implicit currency otherwise (interest applies to all of them)
+//! ```
+
+use std::fmt;
+use std::io::{self, Read};
+use std::str::FromStr;
+
+use rust_decimal::{Decimal, RoundingStrategy};
+use serde::Deserialize;
+use tracing::{info, warn};
+
+use crate::config;
+use crate::transaction::{ClientId, Currency, TransactionEngine, MAX_AMOUNT_SCALE};
+
+const SECONDS_PER_DAY: u64 = 86_400;
+
+/// How often a rule applies. Periods are aligned to the Unix epoch (or to the start of each
+/// calendar month, in UTC), so they don't depend on when the input starts.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Period {
+    Seconds(u64),
+    Month,
+}
+
+impl Period {
+    // The number of the period `timestamp` falls into, counting from the epoch.
+    fn index(self, timestamp: u64) -> u64 {
+        match self {
+            Period::Seconds(seconds) => timestamp / seconds,
+            Period::Month => month_index(timestamp / SECONDS_PER_DAY),
+        }
+    }
+}
+
+// Months since the year 0 for a number of days since the epoch, based on the `civil_from_days`
+// algorithm by Howard Hinnant (simplified, since the days are never negative).
+fn month_index(days: u64) -> u64 {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z % 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    // Years start in March here, so January and February belong to the next one.
+    let (year, month) = if mp < 10 {
+        (yoe + era * 400, mp + 3)
+    } else {
+        (yoe + era * 400 + 1, mp - 9)
+    };
+    year * 12 + month - 1
+}
+
+/// Parses either `month`, `day`, `hour`, or a number of seconds, minutes, hours, or days
+/// (i.e. `90s`, `15m`, `12h`, or `30d`).
+impl FromStr for Period {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let seconds = match s {
+            "month" => return Ok(Period::Month),
+            "day" => SECONDS_PER_DAY,
+            "hour" => 3600,
+            _ => {
+                let split = s.len().saturating_sub(1);
+                let unit = match s.get(split..) {
+                    Some("s") => 1,
+                    Some("m") => 60,
+                    Some("h") => 3600,
+                    Some("d") => SECONDS_PER_DAY,
+                    _ => return Err(format!("invalid period `{}`", s)),
+                };
+                let count: u64 = s[..split]
+                    .parse()
+                    .map_err(|e| format!("invalid period `{}`: {}", s, e))?;
+                count.saturating_mul(unit)
+            }
+        };
+
+        if seconds == 0 {
+            return Err(format!("invalid period `{}`: must be at least a second", s));
+        }
+        Ok(Period::Seconds(seconds))
+    }
+}
+
+impl<'de> Deserialize<'de> for Period {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// What a rule does to the accounts at the end of each period.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum Adjustment {
+    /// Credits `rate` times the available funds (if positive), rounded to four decimal places
+    /// (half to even).
+    Interest { rate: Decimal },
+    /// Debits `amount` from the available funds, or as much of it as they cover.
+    Fee { amount: Decimal },
+}
+
+/// A periodic adjustment. Frozen and closed accounts are exempt.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Rule {
+    #[serde(flatten)]
+    pub adjustment: Adjustment,
+    pub every: Period,
+    /// The currency the rule applies to. Without one, interest applies to every currency,
+    /// while fees are charged in the implicit currency.
+    #[serde(default)]
+    pub currency: Option<Currency>,
+}
+
+impl Rule {
+    // Rejects rules which would take funds out of the accounts by way of interest (or credit
+    // them by way of fees).
+    fn validate(&self) -> Result<(), String> {
+        match self.adjustment {
+            Adjustment::Interest { rate } if rate.is_sign_negative() => {
+                Err(format!("invalid interest rate {}", rate))
+            }
+            Adjustment::Fee { amount } if amount <= Decimal::ZERO => {
+                Err(format!("invalid fee amount {}", amount))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // The adjustments due for every account, as the client, currency, and signed amount.
+    fn adjustments(&self, engine: &TransactionEngine) -> Vec<(ClientId, Currency, Decimal)> {
+        let mut adjustments = Vec::new();
+        let accounts = engine
+            .accounts()
+            .filter(|(_, a)| !a.frozen() && !a.closed());
+
+        for (client, account) in accounts {
+            match self.adjustment {
+                Adjustment::Interest { rate } => {
+                    for (currency, balance) in account.balances() {
+                        if matches!(&self.currency, Some(c) if c != currency) {
+                            continue;
+                        }
+                        let interest = (balance.available() * rate).round_dp_with_strategy(
+                            MAX_AMOUNT_SCALE,
+                            RoundingStrategy::MidpointNearestEven,
+                        );
+                        if interest > Decimal::ZERO {
+                            adjustments.push((client, currency.to_owned(), interest));
+                        }
+                    }
+                }
+                Adjustment::Fee { amount } => {
+                    let currency = self.currency.as_deref().unwrap_or_default();
+                    let fee = amount.min(account.balance(currency).available());
+                    if fee > Decimal::ZERO {
+                        adjustments.push((client, currency.to_owned(), -fee));
+                    }
+                }
+            }
+        }
+
+        // Applying them in the same order from run to run.
+        adjustments.sort_unstable_by(|a, b| (a.0, &a.1).cmp(&(b.0, &b.1)));
+        adjustments
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.adjustment {
+            Adjustment::Interest { rate } => write!(f, "interest at {}", rate),
+            Adjustment::Fee { amount } => write!(f, "fee of {}", amount),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Rules {
+    #[serde(default)]
+    rule: Vec<Rule>,
+}
+
+/// Applies the rules to an engine as the input moves through time. The clock starts at the
+/// first timestamp the scheduler is given, and only ever moves forward: timestamps before
+/// the latest one don't trigger any adjustments.
+pub struct Scheduler {
+    rules: Vec<Rule>,
+    now: Option<u64>,
+}
+
+impl Scheduler {
+    pub fn new(rules: Vec<Rule>) -> Self {
+        Scheduler { rules, now: None }
+    }
+
+    /// Reads the rules from a TOML document, with a `[[rule]]` table for each of them.
+    pub fn load<R: Read>(r: R) -> io::Result<Self> {
+        let rules: Rules = config::read_toml(r)?;
+        for rule in rules.rule.iter() {
+            rule.validate()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        }
+        Ok(Scheduler::new(rules.rule))
+    }
+
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
+    /// Moves the clock forward to `timestamp`, i.e. before processing a transaction from that
+    /// time, and applies every rule once for each of its periods that ended in between.
+    /// Returns the number of adjustments applied to accounts.
+    pub fn advance(&mut self, engine: &mut TransactionEngine, timestamp: u64) -> usize {
+        let last = match self.now {
+            Some(now) if now < timestamp => now,
+            Some(_) => return 0,
+            None => {
+                self.now = Some(timestamp);
+                return 0;
+            }
+        };
+        self.now = Some(timestamp);
+
+        let mut applied = 0;
+        for rule in self.rules.iter() {
+            let periods = rule.every.index(timestamp) - rule.every.index(last);
+            for _ in 0..periods {
+                for (client, currency, amount) in rule.adjustments(engine) {
+                    match engine.adjust_available(client, &currency, amount) {
+                        Ok(()) => {
+                            info!(client = client.get(), %amount, "Applied the scheduled {}", rule);
+                            applied += 1;
+                        }
+                        Err(e) => warn!(
+                            client = client.get(),
+                            "Skipping the scheduled {}: {}", rule, e
+                        ),
+                    }
+                }
+            }
+        }
+        applied
+    }
+}
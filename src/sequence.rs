@@ -0,0 +1,132 @@
+//! Detection of dropped and replayed records in feeds which number their records (the
+//! optional `seq` column), i.e. records lost between an upstream broker and the engine. The
+//! sequence numbers of every feed are expected to go up by one from record to record, and
+//! the feed of a transaction is its tenant, since every partner program sends a feed of its
+//! own (the transactions without a tenant make up the default feed). Records without a
+//! sequence number are left alone.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use serde::Serialize;
+
+use crate::tenant::TenantId;
+use crate::transaction::Transaction;
+
+/// A record whose sequence number isn't the one following the previous record of its feed.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+#[serde(tag = "anomaly", rename_all = "snake_case")]
+pub enum SequenceAnomaly {
+    /// Records went missing: the sequence number is beyond the one expected next.
+    Gap {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        feed: Option<TenantId>,
+        expected: u64,
+        found: u64,
+    },
+    /// The sequence number isn't beyond the highest one of the feed so far, i.e. a replayed
+    /// or reordered record.
+    Regression {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        feed: Option<TenantId>,
+        highest: u64,
+        found: u64,
+    },
+}
+
+impl SequenceAnomaly {
+    /// The name of the variant, i.e. for the errors report.
+    pub fn name(&self) -> &'static str {
+        match self {
+            SequenceAnomaly::Gap { .. } => "SequenceGap",
+            SequenceAnomaly::Regression { .. } => "SequenceRegression",
+        }
+    }
+
+    /// How many records went missing, which is zero for regressions.
+    pub fn missing(&self) -> u64 {
+        match self {
+            SequenceAnomaly::Gap {
+                expected, found, ..
+            } => found - expected,
+            SequenceAnomaly::Regression { .. } => 0,
+        }
+    }
+}
+
+impl fmt::Display for SequenceAnomaly {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let feed = |feed: &Option<TenantId>| match feed {
+            Some(tenant) => format!("feed `{}`", tenant),
+            None => "the default feed".to_owned(),
+        };
+        match self {
+            SequenceAnomaly::Gap {
+                feed: name,
+                expected,
+                found,
+            } => write!(
+                f,
+                "{} record(s) missing from {}: expected sequence number {}, got {}",
+                found - expected,
+                feed(name),
+                expected,
+                found
+            ),
+            SequenceAnomaly::Regression {
+                feed: name,
+                highest,
+                found,
+            } => write!(
+                f,
+                "sequence number {} of {} is not beyond {}",
+                found,
+                feed(name),
+                highest
+            ),
+        }
+    }
+}
+
+/// Keeps track of the highest sequence number of every feed, so every record can be checked
+/// against the one before it. The first record of a feed can have any sequence number.
+#[derive(Clone, Debug, Default)]
+pub struct SequenceChecker {
+    highest: HashMap<Option<TenantId>, u64>,
+}
+
+impl SequenceChecker {
+    pub fn new() -> Self {
+        SequenceChecker::default()
+    }
+
+    /// Checks the sequence number of `transaction` (if it has one) against the previous
+    /// records of its feed. Gaps move the feed on to the new sequence number, so the missing
+    /// records are only reported once, while regressions leave it where it was.
+    pub fn check(&mut self, transaction: &Transaction) -> Option<SequenceAnomaly> {
+        let found = transaction.sequence()?;
+        let feed = transaction.tenant().map(str::to_owned);
+        let highest = match self.highest.get_mut(&feed) {
+            Some(highest) => highest,
+            None => {
+                self.highest.insert(feed, found);
+                return None;
+            }
+        };
+
+        if found <= *highest {
+            return Some(SequenceAnomaly::Regression {
+                feed,
+                highest: *highest,
+                found,
+            });
+        }
+        let expected = *highest + 1;
+        *highest = found;
+        (found > expected).then_some(SequenceAnomaly::Gap {
+            feed,
+            expected,
+            found,
+        })
+    }
+}
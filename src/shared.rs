@@ -0,0 +1,138 @@
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::{Arc, PoisonError, RwLock};
+use std::thread;
+
+use tracing::info_span;
+
+use crate::transaction::{Error, Outcome, Snapshot, Transaction, TransactionEngine};
+
+// Upper bound for the number of requests waiting for a stripe, same as for the shards of
+// `TransactionEngine::process_parallel`.
+const QUEUED_REQUESTS: usize = 64;
+
+enum Request {
+    // Boxed, since transactions are much larger than the other requests.
+    Process(Box<Transaction>, SyncSender<Outcome>),
+    Snapshot(SyncSender<Snapshot>),
+}
+
+/// A `TransactionEngine` which can be shared across threads (i.e. behind an `Arc`), so
+/// several threads can process transactions at the same time, as long as they're for
+/// different clients. The clients are partitioned among a fixed number of stripes (by client
+/// id), each of which owns the accounts of its clients, and runs an engine on a thread of its
+/// own. Transactions of the same client are processed one at a time, in the order they
+/// arrive, while the ones of clients in other stripes don't wait for each other.
+///
+/// The same restrictions as for `TransactionEngine::process_parallel` apply: dispute-related
+/// transactions have to carry the client of the referenced transaction (the receiving client
+/// for transfers), duplicate transaction ids are only detected within a stripe, and with more
+/// than one stripe, transfers are rejected (`Error::InvalidTransfer`), since their parties
+/// may belong to different stripes. Warnings are discarded. The stripe threads exit once the
+/// engine has been dropped.
+pub struct SharedTransactionEngine {
+    stripes: Vec<SyncSender<Request>>,
+    // Held for reading while a transaction is processed, and for writing while the stripes
+    // are snapshotted, so snapshots never include part of what happened concurrently.
+    gate: RwLock<()>,
+}
+
+impl SharedTransactionEngine {
+    /// Starts `stripes` engine threads (at least one), each of which sets up its engine via
+    /// `make_engine`, so the engines themselves don't need to be `Send` (i.e. when they have
+    /// a transaction callback). The history limit of the engines is split among the stripes,
+    /// so they don't retain more than that in total.
+    pub fn new<F>(stripes: usize, make_engine: F) -> Self
+    where
+        F: Fn() -> TransactionEngine + Send + Sync + 'static,
+    {
+        let count = stripes.max(1);
+        let make_engine = Arc::new(make_engine);
+        let stripes = (0..count)
+            .map(|index| {
+                let (sender, requests) = mpsc::sync_channel::<Request>(QUEUED_REQUESTS);
+                let make_engine = Arc::clone(&make_engine);
+                thread::spawn(move || {
+                    let _span = info_span!("stripe", index).entered();
+                    let mut engine = make_engine().into_stripe(count);
+                    for request in requests {
+                        request.run(&mut engine);
+                    }
+                });
+                sender
+            })
+            .collect();
+
+        SharedTransactionEngine {
+            stripes,
+            gate: RwLock::new(()),
+        }
+    }
+
+    /// The number of stripes the clients are partitioned among.
+    pub fn stripes(&self) -> usize {
+        self.stripes.len()
+    }
+
+    /// Same as `TransactionEngine::process_transaction`, blocking until the transaction has
+    /// been processed by the stripe of its client.
+    pub fn process_transaction(&self, transaction: Transaction) -> Result<(), Error> {
+        self.process_transaction_detailed(transaction).result()
+    }
+
+    /// Same as `TransactionEngine::process_transaction_detailed`.
+    pub fn process_transaction_detailed(&self, transaction: Transaction) -> Outcome {
+        let _guard = self.gate.read().unwrap_or_else(PoisonError::into_inner);
+        let (reply, outcome) = mpsc::sync_channel(1);
+        let stripe = &self.stripes[transaction.client().get() as usize % self.stripes.len()];
+        send(stripe, Request::Process(Box::new(transaction), reply));
+        outcome.recv().expect("The engine thread has terminated")
+    }
+
+    /// Snapshots the state of every stripe, combined into one (with the accounts sorted by
+    /// client id). Waits for the transactions in progress, and holds back new ones until all
+    /// the stripes have been snapshotted, so the snapshot reflects the state after some
+    /// sequence of the transactions (one which is consistent with the order every thread
+    /// processed its transactions in). For output, an engine restored from the snapshot (see
+    /// `TransactionEngine::restore`) can write the accounts in any of the usual ways.
+    pub fn accounts_snapshot(&self) -> Snapshot {
+        let _guard = self.gate.write().unwrap_or_else(PoisonError::into_inner);
+        let replies: Vec<Receiver<Snapshot>> = self
+            .stripes
+            .iter()
+            .map(|stripe| {
+                let (reply, snapshot) = mpsc::sync_channel(1);
+                send(stripe, Request::Snapshot(reply));
+                snapshot
+            })
+            .collect();
+
+        Snapshot::merge(
+            replies
+                .into_iter()
+                .map(|snapshot| snapshot.recv().expect("The engine thread has terminated")),
+        )
+    }
+}
+
+fn send(stripe: &SyncSender<Request>, request: Request) {
+    // The receiver only goes away if the engine thread panicked.
+    if stripe.send(request).is_err() {
+        panic!("The engine thread has terminated");
+    }
+}
+
+impl Request {
+    // Runs on the thread of the stripe, and replies to the caller.
+    fn run(self, engine: &mut TransactionEngine) {
+        match self {
+            Request::Process(transaction, reply) => {
+                let outcome = engine.process_transaction_detailed(*transaction);
+                engine.drain_warnings();
+                let _ = reply.send(outcome);
+            }
+            Request::Snapshot(reply) => {
+                let _ = reply.send(engine.snapshot());
+            }
+        }
+    }
+}
@@ -0,0 +1,102 @@
+//! Export of the engine state to an SQLite database, for reconciliation jobs that would rather
+//! run SQL queries than parse the CSV report. The database gets two tables:
+//!
+//! - `accounts`, with the same rows as the account report (`client`, `currency`, `available`,
+//!   `held`, `total`, `locked`, `status`, and `risk_score`), where `currency` and
+//!   `risk_score` are `NULL` unless the report has them. The `status` of the account (see
+//!   `Status`) is always included.
+//! - `transactions`, with the deposits and withdrawals retained in the history (`tx`, `type`,
+//!   `client`, `amount`, `currency`, `destination`, `timestamp`, `disputed`, and `held` for
+//!   the portion under dispute), in the order they were processed.
+//!
+//! Amounts are stored as `TEXT` in their decimal notation, since SQLite has no exact decimal
+//! type (`CAST(amount AS REAL)` converts them for arithmetic where precision doesn't matter).
+
+use std::path::Path;
+
+use rusqlite::{params, Connection, Result};
+
+use crate::account::{Account, Status};
+use crate::transaction::{ClientId, TransactionEngine};
+
+const SCHEMA: &str = "
+    DROP TABLE IF EXISTS accounts;
+    DROP TABLE IF EXISTS transactions;
+    CREATE TABLE accounts (
+        client INTEGER NOT NULL,
+        currency TEXT,
+        available TEXT NOT NULL,
+        held TEXT NOT NULL,
+        total TEXT NOT NULL,
+        locked INTEGER NOT NULL,
+        status TEXT NOT NULL,
+        risk_score INTEGER,
+        UNIQUE (client, currency)
+    );
+    CREATE TABLE transactions (
+        tx INTEGER PRIMARY KEY,
+        type TEXT NOT NULL,
+        client INTEGER NOT NULL,
+        amount TEXT NOT NULL,
+        currency TEXT,
+        destination INTEGER,
+        timestamp INTEGER,
+        disputed INTEGER NOT NULL,
+        held TEXT NOT NULL
+    );
+    CREATE INDEX transactions_client ON transactions (client);
+";
+
+/// Writes the accounts and the retained transactions of `engine` to the database at `path`,
+/// which gets created if it doesn't exist. Existing `accounts` and `transactions` tables are
+/// replaced, within a single database transaction. Fails for transaction ids or timestamps
+/// beyond the range of SQLite integers (`i64::MAX`).
+pub fn export<P: AsRef<Path>>(engine: &TransactionEngine, path: P) -> Result<()> {
+    let mut connection = Connection::open(path)?;
+    let db = connection.transaction()?;
+    db.execute_batch(SCHEMA)?;
+
+    {
+        let mut insert = db.prepare(
+            "INSERT INTO accounts
+                 (client, currency, available, held, total, locked, status, risk_score)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+        )?;
+        for record in engine.account_records() {
+            let status = engine
+                .account(record.client())
+                .map_or(Status::Active, Account::status);
+            insert.execute(params![
+                record.client().get(),
+                record.currency(),
+                record.available().to_string(),
+                record.held().to_string(),
+                record.total().to_string(),
+                record.locked(),
+                status.to_string(),
+                record.risk_score(),
+            ])?;
+        }
+
+        let mut insert = db.prepare(
+            "INSERT INTO transactions
+                 (tx, type, client, amount, currency, destination, timestamp, disputed, held)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+        )?;
+        for t in engine.retained_transactions() {
+            insert.execute(params![
+                t.tx().get(),
+                t.type_().name(),
+                t.client().get(),
+                t.amount().to_string(),
+                Some(t.currency()).filter(|c| !c.is_empty()),
+                t.destination().map(ClientId::get),
+                t.timestamp(),
+                t.disputed(),
+                t.disputed_amount().to_string(),
+            ])?;
+        }
+    }
+
+    db.commit()
+}
@@ -0,0 +1,262 @@
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use crate::account::{Account, AppliedOp, Balance};
+use crate::transaction::{ClientId, Currency, Transaction, TransactionId, Type};
+
+/// A single line of a statement.
+#[derive(Serialize)]
+pub struct Entry {
+    #[serde(rename = "type")]
+    type_: Type,
+    tx: TransactionId,
+    amount: Decimal,
+    // Only present for multi-asset accounts.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    currency: Option<Currency>,
+    disputed: bool,
+    // Only present when any of the listed transactions has a memo or reference (empty for
+    // the ones without), so every CSV row has the same columns.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    memo: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reference: Option<String>,
+    // Running balance (in the currency of the entry) after applying this (and all previous)
+    // entries.
+    balance: Decimal,
+}
+
+/// The current funds of a client account in a single currency.
+#[derive(Serialize)]
+pub struct Funds {
+    available: Decimal,
+    held: Decimal,
+    total: Decimal,
+}
+
+impl From<&Balance> for Funds {
+    fn from(balance: &Balance) -> Self {
+        Funds {
+            available: balance.available(),
+            held: balance.held(),
+            total: balance.total(),
+        }
+    }
+}
+
+/// An ordered statement of the transactions retained for a client account, followed by the
+/// current account balances. Transactions are no longer retained after a dispute gets
+/// settled, so the running balance only accounts for the transactions that are listed.
+/// The funds of multi-asset accounts are listed per currency under `balances`, instead of
+/// at the top level.
+#[derive(Serialize)]
+pub struct Statement {
+    client: ClientId,
+    entries: Vec<Entry>,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    funds: Option<Funds>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    balances: BTreeMap<Currency, Funds>,
+    locked: bool,
+    open_disputes: usize,
+    // Only present when the engine records account histories.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    history: Vec<AppliedOp>,
+}
+
+impl Statement {
+    /// Expects `transactions` to be sorted in the order they were processed.
+    pub fn new(client: ClientId, account: &Account, transactions: Vec<&Transaction>) -> Self {
+        let multi_asset = account.is_multi_asset();
+        let annotated = transactions
+            .iter()
+            .any(|t| t.memo().is_some() || t.reference().is_some());
+        let annotation = |value: Option<&str>| {
+            if annotated {
+                Some(value.unwrap_or_default().to_owned())
+            } else {
+                None
+            }
+        };
+        let mut running_balances = BTreeMap::new();
+
+        let entries = transactions
+            .into_iter()
+            .map(|t| {
+                let balance = running_balances
+                    .entry(t.currency())
+                    .or_insert(Decimal::ZERO);
+
+                // Disputes merely move funds between available and held, so the running
+                // balance is not affected by them.
+                match t.type_() {
+                    Type::Withdrawal => *balance -= t.amount(),
+                    Type::Transfer if t.client() == client => *balance -= t.amount(),
+                    _ => *balance += t.amount(),
+                }
+
+                Entry {
+                    type_: t.type_(),
+                    tx: t.tx(),
+                    amount: t.amount(),
+                    currency: if multi_asset {
+                        Some(t.currency().to_owned())
+                    } else {
+                        None
+                    },
+                    disputed: t.disputed(),
+                    memo: annotation(t.memo()),
+                    reference: annotation(t.reference()),
+                    balance: *balance,
+                }
+            })
+            .collect();
+
+        let (funds, balances) = if multi_asset {
+            let balances = account
+                .balances()
+                .map(|(currency, balance)| (currency.to_owned(), balance.into()))
+                .collect();
+            (None, balances)
+        } else {
+            (Some(account.balance("").into()), BTreeMap::new())
+        };
+
+        Statement {
+            client,
+            entries,
+            funds,
+            balances,
+            locked: account.frozen(),
+            open_disputes: account.open_disputes(),
+            history: account.history().to_vec(),
+        }
+    }
+
+    /// Writes the statement entries as CSV rows to `w`.
+    pub fn write_csv<W: Write>(&self, w: W) -> csv::Result<()> {
+        let mut writer = csv::Writer::from_writer(w);
+
+        for entry in self.entries.iter() {
+            writer.serialize(entry)?;
+        }
+
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Writes the whole statement (including the account balances) as JSON to `w`.
+    pub fn write_json<W: Write>(&self, w: W) -> serde_json::Result<()> {
+        serde_json::to_writer(w, self)
+    }
+
+    /// Writes the whole statement as a human-readable table to `w` (i.e. for support staff
+    /// looking into an account): the entries with their running balances, followed by the
+    /// account balances and status.
+    pub fn write_table<W: Write>(&self, mut w: W) -> io::Result<()> {
+        let multi_asset = self.funds.is_none();
+        let annotated = self.entries.iter().any(|entry| entry.memo.is_some());
+        writeln!(w, "Statement of client {}", self.client)?;
+        writeln!(w)?;
+
+        let mut header = vec!["TYPE", "TX", "AMOUNT"];
+        if multi_asset {
+            header.push("CURRENCY");
+        }
+        header.push("DISPUTED");
+        if annotated {
+            header.extend(["MEMO", "REFERENCE"]);
+        }
+        header.push("BALANCE");
+        let mut rows = vec![header.into_iter().map(str::to_owned).collect::<Vec<_>>()];
+        for entry in self.entries.iter() {
+            let mut row = vec![
+                entry.type_.name().to_owned(),
+                entry.tx.to_string(),
+                entry.amount.to_string(),
+            ];
+            row.extend(entry.currency.clone());
+            row.push(if entry.disputed { "yes" } else { "no" }.to_owned());
+            row.extend(entry.memo.clone());
+            row.extend(entry.reference.clone());
+            row.push(entry.balance.to_string());
+            rows.push(row);
+        }
+        let mut align = vec![false, true, true];
+        if multi_asset {
+            align.push(false);
+        }
+        align.push(false);
+        if annotated {
+            align.extend([false, false]);
+        }
+        align.push(true);
+        write_aligned(&mut w, &rows, &align)?;
+        writeln!(w)?;
+
+        let mut header = vec!["AVAILABLE", "HELD", "TOTAL"];
+        if multi_asset {
+            header.insert(0, "CURRENCY");
+        }
+        let mut rows = vec![header.into_iter().map(str::to_owned).collect::<Vec<_>>()];
+        let funds = self.funds.iter().map(|funds| ("", funds));
+        let balances = self.balances.iter().map(|(c, funds)| (c.as_str(), funds));
+        for (currency, funds) in funds.chain(balances) {
+            let mut row = Vec::new();
+            if multi_asset {
+                row.push(currency.to_owned());
+            }
+            row.extend([funds.available, funds.held, funds.total].map(|a| a.to_string()));
+            rows.push(row);
+        }
+        let align = if multi_asset {
+            &[false, true, true, true][..]
+        } else {
+            &[true, true, true][..]
+        };
+        write_aligned(&mut w, &rows, align)?;
+        writeln!(w)?;
+
+        writeln!(
+            w,
+            "Locked: {}, open disputes: {}",
+            if self.locked { "yes" } else { "no" },
+            self.open_disputes
+        )?;
+        w.flush()
+    }
+}
+
+// Writes `rows` with every column padded to the same width, and right-aligned where `align`
+// says so (i.e. for amounts).
+pub(crate) fn write_aligned<W: Write>(
+    w: &mut W,
+    rows: &[Vec<String>],
+    align: &[bool],
+) -> io::Result<()> {
+    let mut widths = vec![0; align.len()];
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    for row in rows {
+        let mut line = String::new();
+        for (i, cell) in row.iter().enumerate() {
+            if i > 0 {
+                line.push_str("  ");
+            }
+            if align[i] {
+                line.push_str(&format!("{:>1$}", cell, widths[i]));
+            } else {
+                line.push_str(&format!("{:<1$}", cell, widths[i]));
+            }
+        }
+        writeln!(w, "{}", line.trim_end())?;
+    }
+    Ok(())
+}
@@ -0,0 +1,264 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::Write;
+use std::time::Duration;
+
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+use crate::metrics::Metrics;
+use crate::sequence::SequenceAnomaly;
+use crate::transaction::{Currency, Error, Outcome, Snapshot, Warning};
+
+/// Keeps track of what happened while processing the input records.
+#[derive(Debug, Default, Serialize)]
+pub struct ProcessSummary {
+    // Number of records considered for processing, including invalid ones.
+    pub records: u64,
+    // Transactions applied successfully by the engine.
+    pub applied: u64,
+    // Transactions which were valid, but didn't have any effect.
+    pub no_op: u64,
+    // Transactions rejected by the engine.
+    pub rejected: u64,
+    // Breakdown of the rejected transactions by reason (see `Error::name`).
+    pub errors: BTreeMap<&'static str, u64>,
+    // Breakdown of the `LimitExceeded` rejections by the velocity limit they exceeded (as
+    // written by `WindowLimit`'s `Display`).
+    pub limits_exceeded: BTreeMap<String, u64>,
+    // Records that could not be parsed.
+    pub invalid: u64,
+    // Gaps and regressions in the sequence numbers of the records (see `sequence`), and how
+    // many records went missing in the gaps. Only checked with `--check-sequence`.
+    pub sequence_gaps: u64,
+    pub missing_records: u64,
+    pub sequence_regressions: u64,
+    // Whether processing stopped early because of the configured maximum number of records,
+    // while there was still input left.
+    pub limit_reached: bool,
+}
+
+impl ProcessSummary {
+    pub fn record_processed(&mut self, outcome: &Outcome) {
+        self.records += 1;
+        match outcome {
+            Outcome::Applied => self.applied += 1,
+            Outcome::NoOp => self.no_op += 1,
+            Outcome::Rejected(e) => {
+                self.rejected += 1;
+                *self.errors.entry(e.name()).or_insert(0) += 1;
+                if let Error::LimitExceeded { limit, .. } = e {
+                    *self.limits_exceeded.entry(limit.to_string()).or_insert(0) += 1;
+                }
+            }
+        }
+    }
+
+    pub fn record_invalid(&mut self) {
+        self.records += 1;
+        self.invalid += 1;
+    }
+
+    /// Counts `anomaly`, on top of the outcome of its record.
+    pub fn record_sequence_anomaly(&mut self, anomaly: &SequenceAnomaly) {
+        match anomaly {
+            SequenceAnomaly::Gap { .. } => {
+                self.sequence_gaps += 1;
+                self.missing_records += anomaly.missing();
+            }
+            SequenceAnomaly::Regression { .. } => self.sequence_regressions += 1,
+        }
+    }
+
+    /// Adds the counts of `other` (i.e. the summary of another shard of the input).
+    pub fn merge(&mut self, other: ProcessSummary) {
+        self.records += other.records;
+        self.applied += other.applied;
+        self.no_op += other.no_op;
+        self.rejected += other.rejected;
+        for (error, count) in other.errors {
+            *self.errors.entry(error).or_insert(0) += count;
+        }
+        for (limit, count) in other.limits_exceeded {
+            *self.limits_exceeded.entry(limit).or_insert(0) += count;
+        }
+        self.invalid += other.invalid;
+        self.sequence_gaps += other.sequence_gaps;
+        self.missing_records += other.missing_records;
+        self.sequence_regressions += other.sequence_regressions;
+        self.limit_reached |= other.limit_reached;
+    }
+
+    /// Writes the counts as CSV to `w`, with an `outcome,reason,count` row for each outcome
+    /// (`applied`, `no_op`, `rejected`, and `invalid`), and one per reason for the rejected
+    /// transactions instead of a total.
+    pub fn write_csv<W: Write>(&self, w: W) -> csv::Result<()> {
+        let mut writer = csv::Writer::from_writer(w);
+        writer.write_record(["outcome", "reason", "count"])?;
+        writer.write_record(["applied", "", &self.applied.to_string()])?;
+        writer.write_record(["no_op", "", &self.no_op.to_string()])?;
+        for (error, count) in self.errors.iter() {
+            writer.write_record(["rejected", error, &count.to_string()])?;
+        }
+        writer.write_record(["invalid", "", &self.invalid.to_string()])?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Writes the counts as a JSON object to `w`, with the same fields as the struct.
+    pub fn write_json<W: Write>(&self, w: W) -> serde_json::Result<()> {
+        serde_json::to_writer(w, self)
+    }
+}
+
+impl fmt::Display for ProcessSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "records: {}", self.records)?;
+        writeln!(f, "applied: {}", self.applied)?;
+        writeln!(f, "no-op: {}", self.no_op)?;
+        writeln!(f, "rejected: {}", self.rejected)?;
+        for (error, count) in self.errors.iter() {
+            writeln!(f, "  {}: {}", error, count)?;
+        }
+        writeln!(f, "invalid: {}", self.invalid)?;
+        writeln!(
+            f,
+            "limits exceeded: {}",
+            self.limits_exceeded.values().sum::<u64>()
+        )?;
+        for (limit, count) in self.limits_exceeded.iter() {
+            writeln!(f, "  {}: {}", limit, count)?;
+        }
+        writeln!(
+            f,
+            "sequence gaps: {} ({} records missing)",
+            self.sequence_gaps, self.missing_records
+        )?;
+        writeln!(f, "sequence regressions: {}", self.sequence_regressions)?;
+        write!(f, "limit reached: {}", self.limit_reached)
+    }
+}
+
+/// Sums of the funds across accounts, in a single currency.
+#[derive(Debug, Default)]
+pub struct Totals {
+    pub available: Decimal,
+    pub held: Decimal,
+    pub total: Decimal,
+}
+
+/// Firm-wide totals across every account (i.e. for end-of-day reconciliation). Funds are
+/// summed separately for every currency, since amounts in different currencies don't add
+/// up; decimal sums are exact, so there's no rounding of intermediate values.
+#[derive(Debug, Default)]
+pub struct Aggregate {
+    pub accounts: usize,
+    pub frozen_accounts: usize,
+    // Keyed by currency code (the implicit currency has an empty code).
+    pub totals: BTreeMap<Currency, Totals>,
+}
+
+impl fmt::Display for Aggregate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "accounts: {}", self.accounts)?;
+        write!(f, "frozen accounts: {}", self.frozen_accounts)?;
+        for (currency, totals) in self.totals.iter() {
+            // Prefixing with the currency code, unless it's the implicit currency.
+            let prefix = if currency.is_empty() {
+                String::new()
+            } else {
+                format!("{} ", currency)
+            };
+            write!(f, "\n{}available: {}", prefix, totals.available)?;
+            write!(f, "\n{}held: {}", prefix, totals.held)?;
+            write!(f, "\n{}total: {}", prefix, totals.total)?;
+        }
+        Ok(())
+    }
+}
+
+/// What a worker engine (see `TransactionEngine::spawn_worker`) did by the time its channel
+/// was closed: the outcome of the transactions, the totals of the final state, and the state
+/// itself, which can be restored into an engine for further reporting (see
+/// `TransactionEngine::restore`).
+pub struct EngineReport {
+    pub summary: ProcessSummary,
+    pub aggregate: Aggregate,
+    pub warnings: Vec<Warning>,
+    pub snapshot: Snapshot,
+}
+
+/// The end-of-run report of the `te` binary (`--report`), which combines the processing
+/// summary with the metrics of the engine and the totals of the final state. Serializes to a
+/// JSON object with the fields of the summary, and the ones below.
+#[derive(Debug, Serialize)]
+pub struct RunReport<'a> {
+    #[serde(flatten)]
+    pub summary: &'a ProcessSummary,
+    // Transactions which went through (including no-ops), keyed by type.
+    pub accepted_by_type: BTreeMap<&'static str, u64>,
+    // Transactions rejected by the engine, keyed by type, and by reason within each type.
+    pub rejected_by_type: BTreeMap<&'static str, BTreeMap<&'static str, u64>>,
+    pub accounts: usize,
+    // Accounts which didn't exist yet before processing (i.e. in a loaded checkpoint).
+    pub accounts_created: usize,
+    pub frozen_accounts: usize,
+    // Sums of the amounts of the applied deposits and withdrawals, keyed by type, and by
+    // currency code within each type.
+    pub volume: BTreeMap<&'static str, BTreeMap<Currency, Decimal>>,
+    // Wall-clock time of the whole run (including reading the input), and the records
+    // considered per second of it.
+    pub elapsed_seconds: f64,
+    pub throughput: f64,
+}
+
+impl<'a> RunReport<'a> {
+    /// Puts the report together, given the number of accounts there were before processing,
+    /// and the time it took.
+    pub fn new(
+        summary: &'a ProcessSummary,
+        metrics: &Metrics,
+        aggregate: &Aggregate,
+        accounts_before: usize,
+        elapsed: Duration,
+    ) -> Self {
+        let mut rejected_by_type: BTreeMap<_, BTreeMap<_, _>> = BTreeMap::new();
+        for (&(type_, error), &count) in metrics.rejected.iter() {
+            rejected_by_type
+                .entry(type_)
+                .or_default()
+                .insert(error, count);
+        }
+        let mut volume: BTreeMap<_, BTreeMap<_, _>> = BTreeMap::new();
+        for ((type_, currency), amount) in metrics.volume.iter() {
+            volume
+                .entry(*type_)
+                .or_default()
+                .insert(currency.clone(), *amount);
+        }
+
+        let elapsed_seconds = elapsed.as_secs_f64();
+        let throughput = if elapsed_seconds > 0.0 {
+            summary.records as f64 / elapsed_seconds
+        } else {
+            0.0
+        };
+
+        RunReport {
+            summary,
+            accepted_by_type: metrics.processed.clone(),
+            rejected_by_type,
+            accounts: aggregate.accounts,
+            accounts_created: aggregate.accounts.saturating_sub(accounts_before),
+            frozen_accounts: aggregate.frozen_accounts,
+            volume,
+            elapsed_seconds,
+            throughput,
+        }
+    }
+
+    /// Writes the report as a pretty-printed JSON object to `w`.
+    pub fn write_json<W: Write>(&self, w: W) -> serde_json::Result<()> {
+        serde_json::to_writer_pretty(w, self)
+    }
+}
@@ -0,0 +1,105 @@
+//! Processing for several tenants (i.e. the partner programs served by one deployment) at
+//! once, each in an engine of its own, so their accounts and transactions are kept apart.
+
+use std::collections::BTreeMap;
+
+use crate::transaction::{Error, Outcome, Transaction, TransactionEngine};
+
+/// The name of a tenant, as given by the `tenant` column of the input.
+pub type TenantId = String;
+
+/// The tenant of transactions that don't specify any.
+pub const DEFAULT_TENANT: &str = "default";
+
+// Upper bound for the length of tenant names, which end up in file names.
+const MAX_TENANT_LEN: usize = 64;
+
+/// Whether `tenant` can be used as the name of a tenant: up to 64 ASCII letters, digits,
+/// dashes, and underscores. Names are case-sensitive, and restricted so that they can be
+/// used for file names, where different tenants never end up with the same file.
+pub fn is_valid_tenant(tenant: &str) -> bool {
+    !tenant.is_empty()
+        && tenant.len() <= MAX_TENANT_LEN
+        && tenant
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+/// Routes transactions by their tenant to separate engines, which get created via
+/// `make_engine` on the first transaction of each tenant. Client and transaction ids are
+/// only unique within a tenant: the same client id in two tenants stands for two accounts,
+/// and nothing a transaction of one tenant does (including disputes, transfers, and
+/// duplicate checks) affects the others. Transactions without a tenant belong to
+/// `DEFAULT_TENANT`, and the ones with an invalid tenant (see `is_valid_tenant`) are
+/// rejected (`Error::InvalidTenant`).
+pub struct MultiTenantEngine {
+    tenants: BTreeMap<TenantId, TransactionEngine>,
+    make_engine: Box<dyn FnMut(&str) -> TransactionEngine>,
+}
+
+impl MultiTenantEngine {
+    /// Sets up an engine without any tenants yet. `make_engine` gets the name of the tenant
+    /// to create the engine for.
+    pub fn new<F>(make_engine: F) -> Self
+    where
+        F: FnMut(&str) -> TransactionEngine + 'static,
+    {
+        MultiTenantEngine {
+            tenants: BTreeMap::new(),
+            make_engine: Box::new(make_engine),
+        }
+    }
+
+    /// Same as `TransactionEngine::process_transaction`, with the engine of the tenant of
+    /// `transaction`.
+    pub fn process_transaction(&mut self, transaction: Transaction) -> Result<(), Error> {
+        self.process_transaction_detailed(transaction).result()
+    }
+
+    /// Same as `TransactionEngine::process_transaction_detailed`, with the engine of the
+    /// tenant of `transaction`.
+    pub fn process_transaction_detailed(&mut self, transaction: Transaction) -> Outcome {
+        let tenant = transaction.tenant().unwrap_or(DEFAULT_TENANT);
+        if !is_valid_tenant(tenant) {
+            return Outcome::Rejected(Error::InvalidTenant {
+                tx: transaction.tx(),
+            });
+        }
+
+        if !self.tenants.contains_key(tenant) {
+            let engine = (self.make_engine)(tenant);
+            self.tenants.insert(tenant.to_owned(), engine);
+        }
+        let engine = self.tenants.get_mut(tenant).unwrap();
+        engine.process_transaction_detailed(transaction)
+    }
+
+    /// The engine of `tenant`, if it has had any transactions.
+    pub fn tenant(&self, tenant: &str) -> Option<&TransactionEngine> {
+        self.tenants.get(tenant)
+    }
+
+    /// Same as `tenant`, for changing the tenant's engine (i.e. to drain its warnings).
+    pub fn tenant_mut(&mut self, tenant: &str) -> Option<&mut TransactionEngine> {
+        self.tenants.get_mut(tenant)
+    }
+
+    /// The tenants with their engines, sorted by name.
+    pub fn tenants(&self) -> impl Iterator<Item = (&str, &TransactionEngine)> {
+        self.tenants
+            .iter()
+            .map(|(tenant, engine)| (tenant.as_str(), engine))
+    }
+
+    /// Same as `tenants`, for changing the engines.
+    pub fn tenants_mut(&mut self) -> impl Iterator<Item = (&str, &mut TransactionEngine)> {
+        self.tenants
+            .iter_mut()
+            .map(|(tenant, engine)| (tenant.as_str(), engine))
+    }
+
+    /// Hands over the engines, keyed by tenant.
+    pub fn into_tenants(self) -> BTreeMap<TenantId, TransactionEngine> {
+        self.tenants
+    }
+}
@@ -1,43 +1,685 @@
-use std::collections::HashMap;
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::iter;
+use std::mem;
+use std::ops::Deref;
+use std::panic;
+use std::path::Path;
 use std::result;
+use std::str::FromStr;
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
+use std::vec;
 
-use rust_decimal::Decimal;
-use serde::Deserialize;
+use rust_decimal::{Decimal, RoundingStrategy};
+use serde::de::{value, IntoDeserializer};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info, info_span, warn};
 
-use crate::account::Account;
+use crate::account::{
+    Account, AccountMetadata, AccountView, OpeningBalance, Status, TotalMismatch, EMPTY_ACCOUNT,
+};
+use crate::amount::{self, amounts_equal, Amount};
+use crate::clock::Clock;
+use crate::config::{self, EngineConfig};
+use crate::diff::{self, Discrepancy};
+use crate::dispute::{DisputePolicy, DisputeRecord, OverdraftPolicy, StandardDisputePolicy};
+use crate::events::{self, EventLog, EventRecord};
+use crate::fees::FeeSchedule;
+pub use crate::id::{ClientId, TransactionId};
+use crate::input::{self, reader_builder};
+use crate::invariants::{self, Before};
+use crate::ledger::{Ledger, MemoryLedger};
+use crate::limits::{BalanceLimits, WindowLimit};
+use crate::lineage::Lineage;
+use crate::metrics::Metrics;
+use crate::observer::EngineObserver;
+use crate::repair::{Adjustment, Anomaly, Correction};
+use crate::risk::{RiskFlag, RiskMonitor, RiskRules};
+use crate::scenario::Fork;
+use crate::statement::Statement;
+use crate::summary::{Aggregate, EngineReport, ProcessSummary};
+use crate::tenant::TenantId;
+use crate::validator::Validator;
+use crate::wal::Wal;
 
-type ClientId = u16;
-type TransactionId = u32;
+pub use self::policies::{
+    ClientFilter, FrozenDisputes, FrozenPolicy, NegativeAmountPolicy, ResidualBalancePolicy,
+    TimeOrdering, ZeroAmountPolicy,
+};
 
-// Represents error conditions the prevented a transaction from successfully completing (i.e.
-// a withdrawal failing because of insufficient available funds).
-#[derive(Debug)]
+mod handlers;
+mod policies;
+mod reporting;
+
+/// How many transactions get journaled in the write-ahead log between two snapshots, unless
+/// configured via `TransactionEngine::with_wal_snapshot_interval`.
+pub const DEFAULT_WAL_SNAPSHOT_INTERVAL: u64 = 100_000;
+
+/// Currency codes are taken verbatim from the input. The empty code denotes the implicit
+/// currency of inputs without a `currency` column.
+pub type Currency = String;
+
+/// The hasher of the maps keyed by client or transaction id, which get hit for every
+/// transaction: FxHash with `--features fxhash`, and the standard library's default
+/// otherwise.
+#[cfg(feature = "fxhash")]
+pub type IdHasher = fxhash::FxBuildHasher;
+#[cfg(not(feature = "fxhash"))]
+pub type IdHasher = std::collections::hash_map::RandomState;
+
+pub(crate) type IdMap<K, V> = HashMap<K, V, IdHasher>;
+
+// Input amounts may have at most this many decimal places, which is also the precision of
+// reported balances.
+pub(crate) const MAX_AMOUNT_SCALE: u32 = 4;
+
+// Transactions are handed to the shards of `process_parallel` in batches of this size, to
+// keep the synchronization overhead low. Each shard buffers a few batches at most.
+const SHARD_BATCH_SIZE: usize = 1024;
+const SHARD_QUEUED_BATCHES: usize = 16;
+
+/// Represents error conditions the prevented a transaction from successfully completing (i.e.
+/// a withdrawal failing because of insufficient available funds), together with the details
+/// needed to act on them. For dispute-related transactions, `client` refers to the owner of
+/// the referenced transaction.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
 pub enum Error {
-    AccountFrozen,
-    AccountNotFound,
-    InsufficientFunds,
-    InvalidAmount,
-    InvalidChargeback,
-    InvalidDispute,
-    InvalidResolve,
-    TransactionNotFound,
+    AccountClosed {
+        client: ClientId,
+    },
+    AccountFrozen {
+        client: ClientId,
+    },
+    AccountNotFound {
+        client: ClientId,
+    },
+    // Withdrawals are blocked until the disputes of the account have been settled.
+    AccountUnderDispute {
+        client: ClientId,
+    },
+    // Withdrawals are blocked until the review of the account is over.
+    AccountUnderReview {
+        client: ClientId,
+    },
+    // A dispute-related transaction specified a different client than the one holding the
+    // funds of the transaction it refers to.
+    ClientMismatch {
+        tx: TransactionId,
+        client: ClientId,
+        expected: ClientId,
+    },
+    ClientNotAllowed {
+        client: ClientId,
+    },
+    AdminOpsDisabled,
+    // A dispute-related transaction specified a different currency than the one it refers to.
+    CurrencyMismatch {
+        tx: TransactionId,
+    },
+    DisputesDisabled,
+    // The dispute came after the end of the dispute window of the transaction (see
+    // `TransactionEngine::with_dispute_window`), at the given timestamp.
+    DisputeWindowExpired {
+        tx: TransactionId,
+        expired: u64,
+    },
+    DuplicateTransaction {
+        tx: TransactionId,
+    },
+    // Less funds are held than what a resolve or chargeback is about to release.
+    InconsistentState {
+        client: ClientId,
+        requested: Decimal,
+        held: Decimal,
+    },
+    ExcessivePrecision {
+        amount: Decimal,
+    },
+    // The amount is above the maximum (see `validator::MaxAmount`).
+    ExcessiveAmount {
+        amount: Decimal,
+        max: Decimal,
+    },
+    InsufficientFunds {
+        client: ClientId,
+        requested: Decimal,
+        available: Decimal,
+    },
+    // Negative amounts, or chargebacks exceeding the disputed amount.
+    InvalidAmount {
+        amount: Decimal,
+    },
+    InvalidChargeback {
+        tx: TransactionId,
+    },
+    // A chargeback reversal for a transaction that is still part of the history, so it hasn't
+    // been charged back.
+    InvalidChargebackReversal {
+        tx: TransactionId,
+    },
+    InvalidDispute {
+        tx: TransactionId,
+    },
+    // A hold without an expiry.
+    InvalidHold {
+        tx: TransactionId,
+    },
+    InvalidResolve {
+        tx: TransactionId,
+    },
+    // A tenant which can't be told apart from others (see `tenant::is_valid_tenant`).
+    InvalidTenant {
+        tx: TransactionId,
+    },
+    // A transfer without a (distinct) receiving client, or one processed in parallel.
+    InvalidTransfer {
+        tx: TransactionId,
+    },
+    // A void of a transaction which isn't an undisputed deposit or withdrawal, or which has
+    // been voided already.
+    InvalidVoid {
+        tx: TransactionId,
+    },
+    // The transaction left the account of `client` in an invalid state, which only a bug in
+    // the engine or an inconsistent loaded state can cause (see
+    // `TransactionEngine::with_invariant_checks`). It has been applied regardless.
+    InvariantViolated {
+        tx: TransactionId,
+        client: ClientId,
+    },
+    // A deposit, withdrawal, transfer or hold without an amount (as opposed to one of zero).
+    MissingAmount {
+        tx: TransactionId,
+    },
+    // Closing the account would leave funds behind (see `ResidualBalancePolicy`).
+    ResidualBalance {
+        client: ClientId,
+    },
+    // Moving `amount` would take the funds of the account beyond the range (or the precision)
+    // of `Decimal`.
+    Overflow {
+        client: ClientId,
+        amount: Decimal,
+    },
+    // The timestamp of a transaction is before the one it has to follow (see `TimeOrdering`).
+    OutOfOrder {
+        tx: TransactionId,
+        timestamp: u64,
+        previous: u64,
+    },
+    // The ledger failed to read or write (see `TransactionEngine::storage_failure`), so the
+    // transaction may have been applied in part.
+    StorageFailed {
+        kind: io::ErrorKind,
+    },
+    TransactionNotFound {
+        tx: TransactionId,
+    },
+    // Beyond one of the velocity limits of the client (see `WindowLimit`).
+    LimitExceeded {
+        tx: TransactionId,
+        client: ClientId,
+        limit: WindowLimit,
+    },
+    // Rejected by one of the validators (see `Validator::name`).
+    ValidationFailed {
+        tx: TransactionId,
+        validator: &'static str,
+    },
+    // The transaction passed the checks, but couldn't be journaled to the write-ahead log (see
+    // `TransactionEngine::recover`), so it hasn't been applied either.
+    WalWriteFailed {
+        kind: io::ErrorKind,
+    },
+    ZeroAmount,
+}
+
+impl Error {
+    // Whether the error is about a transaction or account that doesn't exist (yet), which
+    // may show up later in unordered input.
+    fn is_not_found(&self) -> bool {
+        matches!(
+            self,
+            Error::TransactionNotFound { .. } | Error::AccountNotFound { .. }
+        )
+    }
+
+    /// The name of the variant, without any of the details (i.e. to group errors by kind).
+    pub fn name(&self) -> &'static str {
+        match self {
+            Error::AccountClosed { .. } => "AccountClosed",
+            Error::AccountFrozen { .. } => "AccountFrozen",
+            Error::AccountNotFound { .. } => "AccountNotFound",
+            Error::AccountUnderDispute { .. } => "AccountUnderDispute",
+            Error::AccountUnderReview { .. } => "AccountUnderReview",
+            Error::ClientMismatch { .. } => "ClientMismatch",
+            Error::ClientNotAllowed { .. } => "ClientNotAllowed",
+            Error::AdminOpsDisabled => "AdminOpsDisabled",
+            Error::CurrencyMismatch { .. } => "CurrencyMismatch",
+            Error::DisputesDisabled => "DisputesDisabled",
+            Error::DisputeWindowExpired { .. } => "DisputeWindowExpired",
+            Error::DuplicateTransaction { .. } => "DuplicateTransaction",
+            Error::InconsistentState { .. } => "InconsistentState",
+            Error::ExcessivePrecision { .. } => "ExcessivePrecision",
+            Error::ExcessiveAmount { .. } => "ExcessiveAmount",
+            Error::InsufficientFunds { .. } => "InsufficientFunds",
+            Error::InvalidAmount { .. } => "InvalidAmount",
+            Error::InvalidChargeback { .. } => "InvalidChargeback",
+            Error::InvalidChargebackReversal { .. } => "InvalidChargebackReversal",
+            Error::InvalidDispute { .. } => "InvalidDispute",
+            Error::InvalidHold { .. } => "InvalidHold",
+            Error::InvalidResolve { .. } => "InvalidResolve",
+            Error::InvalidTenant { .. } => "InvalidTenant",
+            Error::InvalidTransfer { .. } => "InvalidTransfer",
+            Error::InvalidVoid { .. } => "InvalidVoid",
+            Error::InvariantViolated { .. } => "InvariantViolated",
+            Error::LimitExceeded { .. } => "LimitExceeded",
+            Error::MissingAmount { .. } => "MissingAmount",
+            Error::ResidualBalance { .. } => "ResidualBalance",
+            Error::Overflow { .. } => "Overflow",
+            Error::OutOfOrder { .. } => "OutOfOrder",
+            Error::StorageFailed { .. } => "StorageFailed",
+            Error::TransactionNotFound { .. } => "TransactionNotFound",
+            Error::ValidationFailed { .. } => "ValidationFailed",
+            Error::WalWriteFailed { .. } => "WalWriteFailed",
+            Error::ZeroAmount => "ZeroAmount",
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::AccountClosed { client } => write!(f, "account of client {} is closed", client),
+            Error::AccountFrozen { client } => write!(f, "account of client {} is frozen", client),
+            Error::AccountNotFound { client } => write!(f, "no account for client {}", client),
+            Error::AccountUnderDispute { client } => write!(
+                f,
+                "account of client {} is under dispute, withdrawals are blocked",
+                client
+            ),
+            Error::AccountUnderReview { client } => write!(
+                f,
+                "account of client {} is under review, withdrawals are blocked",
+                client
+            ),
+            Error::ClientMismatch {
+                tx,
+                client,
+                expected,
+            } => write!(
+                f,
+                "transaction {} belongs to client {}, not client {}",
+                tx, expected, client
+            ),
+            Error::ClientNotAllowed { client } => {
+                write!(f, "client {} is not allowed by the client filter", client)
+            }
+            Error::AdminOpsDisabled => write!(f, "administrative operations are disabled"),
+            Error::CurrencyMismatch { tx } => {
+                write!(f, "currency does not match the one of transaction {}", tx)
+            }
+            Error::DisputesDisabled => write!(f, "disputes are disabled"),
+            Error::DisputeWindowExpired { tx, expired } => write!(
+                f,
+                "transaction {} can no longer be disputed, its dispute window ended at {}",
+                tx, expired
+            ),
+            Error::DuplicateTransaction { tx } => write!(f, "transaction {} already exists", tx),
+            Error::InconsistentState {
+                client,
+                requested,
+                held,
+            } => write!(
+                f,
+                "inconsistent state for client {}: releasing {} of held funds, but only {} \
+                 are held",
+                client, requested, held
+            ),
+            Error::ExcessivePrecision { amount } => write!(
+                f,
+                "amount {} has more than {} decimal places",
+                amount, MAX_AMOUNT_SCALE
+            ),
+            Error::ExcessiveAmount { amount, max } => {
+                write!(f, "amount {} exceeds the maximum of {}", amount, max)
+            }
+            Error::InsufficientFunds {
+                client,
+                requested,
+                available,
+            } => write!(
+                f,
+                "insufficient funds for client {}: requested {}, available {}",
+                client, requested, available
+            ),
+            Error::InvalidAmount { amount } => write!(f, "invalid amount {}", amount),
+            Error::InvalidChargeback { tx } => {
+                write!(
+                    f,
+                    "transaction {} is not under dispute, cannot charge back",
+                    tx
+                )
+            }
+            Error::InvalidChargebackReversal { tx } => write!(
+                f,
+                "transaction {} has not been charged back, cannot reverse the chargeback",
+                tx
+            ),
+            Error::InvalidDispute { tx } => write!(f, "transaction {} cannot be disputed", tx),
+            Error::InvalidHold { tx } => write!(f, "hold {} does not have an expiry", tx),
+            Error::InvalidResolve { tx } => {
+                write!(f, "transaction {} is not under dispute, cannot resolve", tx)
+            }
+            Error::InvalidTenant { tx } => {
+                write!(f, "transaction {} does not have a valid tenant", tx)
+            }
+            Error::InvalidTransfer { tx } => write!(f, "transfer {} cannot be processed", tx),
+            Error::InvalidVoid { tx } => write!(f, "transaction {} cannot be voided", tx),
+            Error::InvariantViolated { tx, client } => write!(
+                f,
+                "transaction {} left the account of client {} in an invalid state",
+                tx, client
+            ),
+            Error::LimitExceeded { tx, client, limit } => write!(
+                f,
+                "transaction {} exceeds the limit {} of client {}",
+                tx, limit, client
+            ),
+            Error::MissingAmount { tx } => write!(f, "transaction {} does not have an amount", tx),
+            Error::ResidualBalance { client } => {
+                write!(f, "account of client {} still holds funds", client)
+            }
+            Error::Overflow { client, amount } => write!(
+                f,
+                "crediting {} would overflow the funds of client {}",
+                amount, client
+            ),
+            Error::OutOfOrder {
+                tx,
+                timestamp,
+                previous,
+            } => write!(
+                f,
+                "transaction {} is out of order: timestamp {} is before {}",
+                tx, timestamp, previous
+            ),
+            Error::StorageFailed { kind } => write!(f, "unable to access the ledger: {}", kind),
+            Error::TransactionNotFound { tx } => write!(f, "transaction {} not found", tx),
+            Error::ValidationFailed { tx, validator } => write!(
+                f,
+                "transaction {} was rejected by the {} validator",
+                tx, validator
+            ),
+            Error::WalWriteFailed { kind } => {
+                write!(f, "unable to write to the write-ahead log: {}", kind)
+            }
+            Error::ZeroAmount => write!(f, "amount is zero"),
+        }
+    }
 }
 
+impl std::error::Error for Error {}
+
 type Result<T> = result::Result<T, Error>;
 
-#[derive(Debug, Deserialize, PartialEq)]
-// Stands for the type of transactions we need to process. Using the `rename_all` attribute to
-// seamlessly deserialize from the corresponding lowercase strings.
+/// The outcome of processing a transaction, in more detail than a plain `Result`, so genuine
+/// rejections can be told apart from transactions that went through without any effect.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Outcome {
+    Applied,
+    Rejected(Error),
+    // The transaction was valid, but didn't change any balance (i.e. a zero-amount deposit,
+    // or a dispute that got deferred).
+    NoOp,
+}
+
+impl Outcome {
+    /// Maps the outcome back onto the `Result` returned by `process_transaction`.
+    pub fn result(&self) -> Result<()> {
+        match self {
+            Outcome::Rejected(e) => Err(*e),
+            Outcome::Applied | Outcome::NoOp => Ok(()),
+        }
+    }
+}
+
+/// A point in the history of the engine, to look up balances as of then (see
+/// `TransactionEngine::balance_at`).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AsOf {
+    // Right before the transaction with this id.
+    Before(TransactionId),
+    // Before the first transaction with a later timestamp (in seconds since the Unix epoch).
+    Time(u64),
+}
+
+impl FromStr for AsOf {
+    type Err = String;
+
+    /// Parses `before:<tx>`, or a timestamp.
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        match s.strip_prefix("before:") {
+            Some(tx) => tx
+                .trim()
+                .parse()
+                .map(AsOf::Before)
+                .map_err(|e| format!("invalid transaction id `{}`: {}", tx, e)),
+            None => s
+                .trim()
+                .parse()
+                .map(AsOf::Time)
+                .map_err(|e| format!("invalid timestamp `{}`: {}", s, e)),
+        }
+    }
+}
+
+/// Advisories about processed transactions. These don't change the outcome of processing, but
+/// help explain it (i.e. to support teams looking into why a transaction was rejected).
+#[derive(Debug)]
+pub enum Warning {
+    // A withdrawal was rejected because of insufficient available funds, even though the
+    // account has enough funds in total; the remainder is held because of open disputes.
+    FundsHeld {
+        client: ClientId,
+        tx: TransactionId,
+    },
+    // A dispute referenced a transaction that hasn't been seen yet, and has been deferred
+    // until the transaction shows up.
+    DisputeDeferred {
+        client: ClientId,
+        tx: TransactionId,
+    },
+    // A dispute-related transaction referenced a transaction or account that doesn't exist
+    // (yet), and has been quarantined to be retried after the following transactions (see
+    // `TransactionEngine::with_quarantine`).
+    Quarantined {
+        client: ClientId,
+        tx: TransactionId,
+        type_: Type,
+    },
+    // A zero-amount deposit or withdrawal was skipped (see `ZeroAmountPolicy::Skip`).
+    ZeroAmountSkipped {
+        client: ClientId,
+        tx: TransactionId,
+        type_: Type,
+    },
+    // A transaction was skipped, because it had been applied already (see
+    // `TransactionEngine::with_replay_dedup`).
+    ReplaySkipped {
+        client: ClientId,
+        tx: TransactionId,
+        type_: Type,
+    },
+    // The risk rules flagged the account of a client because of a transaction (see
+    // `TransactionEngine::with_risk_rules`).
+    RiskFlagged {
+        client: ClientId,
+        tx: TransactionId,
+        flag: RiskFlag,
+    },
+    // A deposit or withdrawal went past the velocity limits of the client, since it doesn't
+    // have a timestamp to place it in their windows (see `limits::WindowLimits`).
+    // Only raised for the first such transaction of every client.
+    LimitBypassed {
+        client: ClientId,
+        tx: TransactionId,
+        type_: Type,
+    },
+    // The write-ahead log couldn't be kept up to date after a transaction, since discarding
+    // the entry of a rejected transaction or writing a scheduled snapshot failed. The outcome
+    // of the transaction stands, and so does recovery: a leftover entry gets rejected again
+    // when replayed, and the snapshot is retried after the next transaction.
+    WalMaintenanceFailed {
+        tx: TransactionId,
+        kind: io::ErrorKind,
+    },
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Warning::FundsHeld { client, tx } => write!(
+                f,
+                "withdrawal {} for client {} was rejected because the funds are held \
+                 under dispute",
+                tx, client
+            ),
+            Warning::DisputeDeferred { client, tx } => write!(
+                f,
+                "dispute of transaction {} for client {} was deferred until the transaction \
+                 shows up",
+                tx, client
+            ),
+            Warning::Quarantined { client, tx, type_ } => write!(
+                f,
+                "{} of transaction {} for client {} was quarantined until the transaction \
+                 shows up",
+                type_.name(),
+                tx,
+                client
+            ),
+            Warning::ZeroAmountSkipped { client, tx, type_ } => write!(
+                f,
+                "{} {} for client {} was skipped, since its amount is zero",
+                type_.name(),
+                tx,
+                client
+            ),
+            Warning::ReplaySkipped { client, tx, type_ } => write!(
+                f,
+                "{} {} for client {} was skipped, since it has been applied already",
+                type_.name(),
+                tx,
+                client
+            ),
+            Warning::RiskFlagged { client, tx, flag } => write!(
+                f,
+                "account of client {} was flagged for {} by transaction {}",
+                client, flag, tx
+            ),
+            Warning::LimitBypassed { client, tx, type_ } => write!(
+                f,
+                "{} {} for client {} is not subject to the velocity limits, since it has no \
+                 timestamp",
+                type_.name(),
+                tx,
+                client
+            ),
+            Warning::WalMaintenanceFailed { tx, kind } => write!(
+                f,
+                "unable to update the write-ahead log after transaction {}: {}",
+                tx, kind
+            ),
+        }
+    }
+}
+
+/// Stands for the type of transactions we need to process.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+// Using the `rename_all` attribute to seamlessly deserialize from the corresponding
+// lowercase strings.
 #[serde(rename_all = "lowercase")]
-enum Type {
+pub enum Type {
+    // Ends the review of an account (see `Review`).
+    Approve,
     Chargeback,
+    // Undoes the chargeback of the referenced transaction (i.e. after the bank reversed it).
+    #[serde(rename = "chargeback_reversal")]
+    ChargebackReversal,
+    // Administrative operations, which are only accepted when enabled (see
+    // `TransactionEngine::with_admin_ops`). These don't carry an amount, and change the
+    // status of the account (see `Status`), as do `Approve`, `Review`, and `Unlock`.
+    Close,
     Deposit,
     Dispute,
+    // Moves funds from available to held until the hold expires (see
+    // `TransactionEngine::release_expired_holds`), i.e. for card pre-authorizations.
+    Hold,
+    Reopen,
     Resolve,
+    // Puts an account under review, which blocks withdrawals.
+    Review,
+    // Moves funds from the account of `client` to the one of `to`.
+    Transfer,
+    Unlock,
+    // Cancels an undisputed deposit or withdrawal (i.e. one made in error), which can't be
+    // disputed from then on.
+    Void,
     Withdrawal,
 }
 
-#[derive(Debug, Deserialize)]
+impl Type {
+    /// The name of the type, as used in the input (i.e. `deposit`).
+    pub fn name(&self) -> &'static str {
+        match self {
+            Type::Approve => "approve",
+            Type::Chargeback => "chargeback",
+            Type::ChargebackReversal => "chargeback_reversal",
+            Type::Close => "close",
+            Type::Deposit => "deposit",
+            Type::Dispute => "dispute",
+            Type::Hold => "hold",
+            Type::Reopen => "reopen",
+            Type::Resolve => "resolve",
+            Type::Review => "review",
+            Type::Transfer => "transfer",
+            Type::Unlock => "unlock",
+            Type::Void => "void",
+            Type::Withdrawal => "withdrawal",
+        }
+    }
+}
+
+impl FromStr for Type {
+    type Err = value::Error;
+
+    // Reuses the deserialization logic, so the accepted names match the ones from the input.
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        Type::deserialize(s.into_deserializer())
+    }
+}
+
+/// The funds paid out when closing an account (see `ResidualBalancePolicy::PayOut`), one per
+/// currency with available funds.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct Payout {
+    pub client: ClientId,
+    /// The `close` transaction.
+    pub tx: TransactionId,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub currency: Currency,
+    pub amount: Decimal,
+}
+
+/// A single transaction, as read from the input. Can also be built via `Transaction::new`
+/// when embedding the engine.
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct Transaction {
     // Must match the corresponding CSV column name.
     #[serde(rename = "type")]
@@ -47,174 +689,4114 @@ pub struct Transaction {
     // Using the `#[serde(default)]` attribute tag here instead of `Option` does not
     // appear to work properly with the `csv::ReaderBuilder::flexible(true)` logic.
     // Added an `amount()` accessor method below which simplifies dealing with the
-    // `Option` wrapper based on `unwrap_or_default`.
+    // `Option` wrapper based on `unwrap_or_default`. The attribute is still required on
+    // top of `Option` when columns are mapped positionally (i.e. without a header row),
+    // since serde would otherwise expect every element to be present. Formatting characters
+    // are stripped during deserialization, if configured (see `amount::set_formatting_chars`).
+    #[serde(default, deserialize_with = "amount::deserialize_formatted")]
     amount: Option<Decimal>,
+    // Optional column, for inputs that carry funds in more than one currency. Disputes and
+    // the related operations always apply to the currency of the original transaction (and
+    // get rejected if they specify a different one).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    currency: Option<Currency>,
+    // Only used by transfers, for the client receiving the funds.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    to: Option<ClientId>,
+    // Optional column, with the time of the transaction (as seconds since the Unix epoch),
+    // which the time-dependent features go by: dispute windows, the expiry of holds, velocity
+    // limits (see `limits::WindowLimit`), and scheduled balance adjustments (see
+    // `scheduler`). Transactions without one get the time of the clock of the engine, if it
+    // has one (see `clock`). They only have to be in timestamp order if the engine checks
+    // (see `TimeOrdering`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    timestamp: Option<u64>,
+    // Only used by holds, for the time the funds get released again (as seconds since the
+    // Unix epoch).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires: Option<u64>,
+    // Optional column, for deployments which serve several partner programs. Only used to
+    // route transactions to the engine of their tenant (see `tenant::MultiTenantEngine`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tenant: Option<TenantId>,
+    // Optional columns, with a free-form note and an external reference (i.e. the id of the
+    // bank transfer behind a deposit). Neither affects processing; they're kept along with the
+    // transaction, so it can be reconciled against the records of other systems (see
+    // `TransactionEngine::find_by_reference`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    memo: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    reference: Option<String>,
+    // Optional column, with the position of the record in the feed it came from, for feeds
+    // which number their records (see `sequence::SequenceChecker`). Doesn't affect processing.
+    #[serde(rename = "seq", default, skip_serializing_if = "Option::is_none")]
+    sequence: Option<u64>,
     // Only used for internal bookkeeping.
     #[serde(skip)]
-    disputed: bool,
+    pub(crate) disputed: bool,
+    // The portion of the amount currently held under dispute, which is the whole amount
+    // unless it was disputed partially.
+    #[serde(skip)]
+    pub(crate) held: Decimal,
+    // The portion of the amount which has been disputed and resolved already, and so can't be
+    // disputed again.
+    #[serde(skip)]
+    pub(crate) settled: Decimal,
+    // The number of disputes opened against the transaction (adding to an open dispute
+    // doesn't count as a new one).
+    #[serde(skip)]
+    pub(crate) disputes: u32,
+    // Position in the order transactions were added to the history.
+    #[serde(skip)]
+    pub(crate) seq: u64,
 }
 
 impl Transaction {
-    fn amount(&self) -> Decimal {
+    /// Creates a transaction in the implicit currency. The amount is only relevant for
+    /// deposits and withdrawals (and partial disputes, resolves, and chargebacks).
+    pub fn new<C, T>(type_: Type, client: C, tx: T, amount: Option<Decimal>) -> Self
+    where
+        C: Into<ClientId>,
+        T: Into<TransactionId>,
+    {
+        Transaction {
+            type_,
+            client: client.into(),
+            tx: tx.into(),
+            amount,
+            currency: None,
+            to: None,
+            timestamp: None,
+            expires: None,
+            tenant: None,
+            memo: None,
+            reference: None,
+            sequence: None,
+            disputed: false,
+            held: Decimal::ZERO,
+            settled: Decimal::ZERO,
+            disputes: 0,
+            seq: 0,
+        }
+    }
+
+    /// Sets the currency of the transaction (for multi-asset processing).
+    pub fn with_currency(mut self, currency: Currency) -> Self {
+        self.currency = Some(currency);
+        self
+    }
+
+    /// Sets the amount, replacing the one given to `new` (if any).
+    pub fn with_amount(mut self, amount: Amount) -> Self {
+        self.amount = Some(amount.get());
+        self
+    }
+
+    /// Sets the client receiving the funds of a transfer.
+    pub fn with_destination<C: Into<ClientId>>(mut self, to: C) -> Self {
+        self.to = Some(to.into());
+        self
+    }
+
+    /// Sets the time of the transaction, in seconds since the Unix epoch.
+    pub fn with_timestamp(mut self, timestamp: u64) -> Self {
+        self.timestamp = Some(timestamp);
+        self
+    }
+
+    /// Sets the time a hold expires at, in seconds since the Unix epoch.
+    pub fn with_expiry(mut self, expires: u64) -> Self {
+        self.expires = Some(expires);
+        self
+    }
+
+    /// Sets the tenant (partner program) the transaction belongs to.
+    pub fn with_tenant(mut self, tenant: TenantId) -> Self {
+        self.tenant = Some(tenant);
+        self
+    }
+
+    /// Sets a free-form note on the transaction.
+    pub fn with_memo(mut self, memo: String) -> Self {
+        self.memo = Some(memo);
+        self
+    }
+
+    /// Sets the external reference of the transaction (i.e. the id of a bank transfer).
+    pub fn with_reference(mut self, reference: String) -> Self {
+        self.reference = Some(reference);
+        self
+    }
+
+    /// Sets the sequence number of the record in its feed.
+    pub fn with_sequence(mut self, sequence: u64) -> Self {
+        self.sequence = Some(sequence);
+        self
+    }
+
+    pub fn type_(&self) -> Type {
+        self.type_
+    }
+
+    pub fn client(&self) -> ClientId {
+        self.client
+    }
+
+    pub fn tx(&self) -> TransactionId {
+        self.tx
+    }
+
+    pub fn amount(&self) -> Decimal {
         self.amount.unwrap_or_default()
     }
-}
 
-// Implements transaction processing logic.
-pub struct TransactionEngine {
-    client_accounts: HashMap<ClientId, Account>,
-    past_transactions: HashMap<TransactionId, Transaction>,
-}
+    pub fn currency(&self) -> &str {
+        self.currency.as_deref().unwrap_or_default()
+    }
 
-impl TransactionEngine {
-    pub fn new() -> Self {
-        TransactionEngine {
-            client_accounts: HashMap::new(),
-            past_transactions: HashMap::new(),
-        }
+    pub fn disputed(&self) -> bool {
+        self.disputed
     }
 
-    // Returns a mutable handle to the account associated with `client` (and creates a new
-    // entry first if one does not already exist).
-    fn account_mut(&mut self, client: ClientId) -> &mut Account {
-        self.client_accounts.entry(client).or_default()
+    /// How many times the transaction has been disputed (partial disputes while it's under
+    /// dispute already count as part of the open dispute).
+    pub fn dispute_count(&self) -> u32 {
+        self.disputes
     }
 
-    // Given a `TransactionId`, returns a mutable handle to the associated transaction record,
-    // and another to the corresponding client account. This is useful to avoid restrictions
-    // imposed by the borrow checker when needing both mutable handles at the same time.
-    // Returns an error if no such transaction record exists.
-    fn transaction_mut(&mut self, tx: TransactionId) -> Result<(&mut Transaction, &mut Account)> {
-        let transaction = self
-            .past_transactions
-            .get_mut(&tx)
-            .ok_or(Error::TransactionNotFound)?;
+    /// The portion of the amount currently held under dispute (zero unless `disputed`).
+    pub fn disputed_amount(&self) -> Decimal {
+        self.held
+    }
 
-        let account = self
-            .client_accounts
-            .get_mut(&transaction.client)
-            .ok_or(Error::AccountNotFound)?;
+    /// The portion of the amount which can still be disputed (on top of any open dispute).
+    pub fn disputable_amount(&self) -> Decimal {
+        self.amount() - self.held - self.settled
+    }
 
-        Ok((transaction, account))
+    /// The client receiving the funds of a transfer.
+    pub fn destination(&self) -> Option<ClientId> {
+        self.to
     }
 
-    pub fn process_transaction(&mut self, transaction: Transaction) -> Result<()> {
-        match transaction.type_ {
-            Type::Deposit => self.process_deposit(transaction),
-            Type::Withdrawal => self.process_withdrawal(transaction),
-            Type::Dispute => self.process_dispute(transaction),
-            Type::Resolve => self.process_resolve(transaction),
-            Type::Chargeback => self.process_chargeback(transaction),
+    /// The time of the transaction, in seconds since the Unix epoch.
+    pub fn timestamp(&self) -> Option<u64> {
+        self.timestamp
+    }
+
+    /// The time a hold expires at, in seconds since the Unix epoch.
+    pub fn expires(&self) -> Option<u64> {
+        self.expires
+    }
+
+    /// The tenant the transaction belongs to, if any.
+    pub fn tenant(&self) -> Option<&str> {
+        self.tenant.as_deref()
+    }
+
+    /// The note on the transaction, if any.
+    pub fn memo(&self) -> Option<&str> {
+        self.memo.as_deref()
+    }
+
+    /// The external reference of the transaction, if any.
+    pub fn reference(&self) -> Option<&str> {
+        self.reference.as_deref()
+    }
+
+    /// The sequence number of the record in its feed, if any.
+    pub fn sequence(&self) -> Option<u64> {
+        self.sequence
+    }
+
+    // The portion of `amount` (out of the funds held under dispute of the transaction) which
+    // returns to the available funds of the holder when it's settled by `outcome`, a resolve
+    // or a chargeback. The funds of a disputed withdrawal have already left the account, so a
+    // chargeback credits them back, while a resolve lets the withdrawal stand. For the other
+    // types, it's the other way around: a resolve releases the funds, while a chargeback
+    // takes them away.
+    pub(crate) fn released_on(&self, outcome: Type, amount: Decimal) -> Decimal {
+        let released = match self.type_ {
+            Type::Withdrawal => outcome == Type::Chargeback,
+            _ => outcome == Type::Resolve,
+        };
+        if released {
+            amount
+        } else {
+            Decimal::ZERO
+        }
+    }
+
+    // The client whose account holds the funds of the transaction, which is the one affected
+    // by disputes: the receiving end for transfers, and `client` otherwise.
+    pub(crate) fn holder(&self) -> ClientId {
+        match (self.type_, self.to) {
+            (Type::Transfer, Some(to)) => to,
+            _ => self.client,
         }
     }
 
-    // Handles a `deposit` transaction.
-    fn process_deposit(&mut self, transaction: Transaction) -> Result<()> {
-        let amount = transaction.amount();
+    // Returns the amount of a `deposit` or `withdrawal`, after checking that there is one,
+    // and that it's a valid value. Amounts finer than `MAX_AMOUNT_SCALE` are rejected, unless
+    // a rounding strategy has been configured (trailing zeros are fine either way, as they
+    // don't increase the precision). Zero amounts (including ones that got rounded to zero)
+    // are only accepted when configured as well. The transaction keeps its original amount,
+    // so callers have to store the returned one if the transaction is retained.
+    fn validated_amount(&self, config: &Config) -> Result<Decimal> {
+        let mut amount = self.amount.ok_or(Error::MissingAmount { tx: self.tx })?;
 
         if amount.is_sign_negative() {
-            return Err(Error::InvalidAmount);
+            return Err(Error::InvalidAmount { amount });
         }
 
-        self.account_mut(transaction.client)
-            .check_frozen_mut()?
-            .increase_available(amount);
-        // Inserting after the amount has been updated successfully. Not checking the
-        // return value of `insert` because transaction ids are guaranteed to be unique.
-        self.past_transactions.insert(transaction.tx, transaction);
+        if amount.normalize().scale() > MAX_AMOUNT_SCALE {
+            match config.amount_rounding {
+                Some(strategy) => {
+                    amount = amount.round_dp_with_strategy(MAX_AMOUNT_SCALE, strategy)
+                }
+                None => return Err(Error::ExcessivePrecision { amount }),
+            }
+        }
 
-        Ok(())
+        if amount.is_zero() && config.zero_amounts != ZeroAmountPolicy::Accept {
+            return Err(Error::ZeroAmount);
+        }
+
+        Ok(amount)
+    }
+}
+
+/// A transaction as a CSV row with every column of the input, in the canonical order (see
+/// `input`). The optional columns are always present (and empty where the transaction has
+/// no value), so rows of different kinds of transactions line up under a single header.
+#[derive(Clone, Debug, Serialize)]
+pub struct TransactionRecord {
+    #[serde(rename = "type")]
+    type_: Type,
+    client: ClientId,
+    tx: TransactionId,
+    amount: Option<Decimal>,
+    currency: Option<Currency>,
+    to: Option<ClientId>,
+    timestamp: Option<u64>,
+    expires: Option<u64>,
+    tenant: Option<TenantId>,
+    memo: Option<String>,
+    reference: Option<String>,
+    seq: Option<u64>,
+}
+
+impl From<&Transaction> for TransactionRecord {
+    fn from(transaction: &Transaction) -> Self {
+        TransactionRecord {
+            type_: transaction.type_,
+            client: transaction.client,
+            tx: transaction.tx,
+            amount: transaction.amount,
+            currency: transaction.currency.clone(),
+            to: transaction.to,
+            timestamp: transaction.timestamp,
+            expires: transaction.expires,
+            tenant: transaction.tenant.clone(),
+            memo: transaction.memo.clone(),
+            reference: transaction.reference.clone(),
+            seq: transaction.sequence,
+        }
+    }
+}
+
+/// Writes `transactions` as CSV rows (see `TransactionRecord`) to `w`, after a header row,
+/// which is written even if there are no transactions.
+pub fn write_transactions<W, I>(transactions: I, w: W) -> csv::Result<()>
+where
+    W: Write,
+    I: IntoIterator<Item = Transaction>,
+{
+    let mut writer = csv::WriterBuilder::new().has_headers(false).from_writer(w);
+    writer.write_record(input::COLUMNS)?;
+    for transaction in transactions {
+        writer.serialize(TransactionRecord::from(&transaction))?;
     }
+    writer.flush()?;
+    Ok(())
+}
 
-    // Handles a `withdrawal` transaction.
-    fn process_withdrawal(&mut self, transaction: Transaction) -> Result<()> {
-        let amount = transaction.amount();
+// The engine state, as persisted by checkpoints. Generic over the field types, so we can
+// serialize straight from a borrowed engine, while deserialization produces owned values.
+// Accounts are listed together with their client ids rather than as a map, since formats
+// such as TOML only support string keys.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct Checkpoint<A, T> {
+    // The `disputed` flag is not serialized as part of a `Transaction` (since the struct
+    // doubles as the input record type), so we keep track of disputed ids separately.
+    disputed: Vec<TransactionId>,
+    // Sorted by client id.
+    accounts: Vec<AccountState<A>>,
+    // Listed in the order they were added to the history.
+    transactions: Vec<T>,
+    // Only recorded with `with_replay_dedup`, and omitted unless there are any.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    processed: Vec<Processed>,
+    // The progress of transactions which have been disputed partially, and omitted unless
+    // there are any. Disputed transactions not listed here are disputed for their whole
+    // amount.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    partial_disputes: Vec<PartialDispute>,
+    // The chargebacks which can still be reversed, as the charged back transactions with the
+    // amount that was charged back, and omitted unless there are any.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    chargebacks: Vec<Transaction>,
+    // The holds which haven't expired yet, and omitted unless there are any.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    holds: Vec<Transaction>,
+    // The voided transactions, so they can't be disputed (or their ids reused), and omitted
+    // unless there are any.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    voided: Vec<Transaction>,
+    // The ids of the transactions which left the history (i.e. once resolved or charged
+    // back), so they can't be reused, and omitted unless there are any.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    seen: Vec<TransactionId>,
+}
 
-        if amount.is_sign_negative() {
-            return Err(Error::InvalidAmount);
+// The disputed and resolved portions of a transaction (see `Transaction::disputed_amount`).
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+struct PartialDispute {
+    tx: TransactionId,
+    held: Decimal,
+    settled: Decimal,
+    // Missing from snapshots written before dispute counts, in which case transactions
+    // count as disputed once if they're under dispute.
+    #[serde(default)]
+    disputes: Option<u32>,
+}
+
+// Identifies a transaction that has been applied, so replays of it can be skipped (see
+// `TransactionEngine::with_replay_dedup`).
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+struct Processed {
+    client: ClientId,
+    tx: TransactionId,
+    #[serde(rename = "type")]
+    type_: Type,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct AccountState<A> {
+    client: ClientId,
+    #[serde(flatten)]
+    account: A,
+}
+
+type OwnedCheckpoint = Checkpoint<Account, Transaction>;
+
+/// A copy of the engine state, as returned by `TransactionEngine::snapshot`: the client
+/// accounts, together with the transactions which can still be disputed (including the
+/// ones under dispute). Serializes to the same format as checkpoints, so it can be stored
+/// in any serde format, and loaded back via `load_checkpoint` as well.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Snapshot(OwnedCheckpoint);
+
+impl Snapshot {
+    // Combines the snapshots of engines which own disjoint sets of clients (i.e. the stripes
+    // of a `SharedTransactionEngine`) into one.
+    pub(crate) fn merge<I: IntoIterator<Item = Snapshot>>(snapshots: I) -> Snapshot {
+        let mut merged = Checkpoint {
+            disputed: Vec::new(),
+            accounts: Vec::new(),
+            transactions: Vec::new(),
+            processed: Vec::new(),
+            partial_disputes: Vec::new(),
+            chargebacks: Vec::new(),
+            holds: Vec::new(),
+            voided: Vec::new(),
+            seen: Vec::new(),
+        };
+        for Snapshot(checkpoint) in snapshots {
+            merged.disputed.extend(checkpoint.disputed);
+            merged.accounts.extend(checkpoint.accounts);
+            merged.transactions.extend(checkpoint.transactions);
+            merged.processed.extend(checkpoint.processed);
+            merged.partial_disputes.extend(checkpoint.partial_disputes);
+            merged.chargebacks.extend(checkpoint.chargebacks);
+            merged.holds.extend(checkpoint.holds);
+            merged.voided.extend(checkpoint.voided);
+            merged.seen.extend(checkpoint.seen);
         }
+        merged.accounts.sort_by_key(|state| state.client);
+        // Keeping the order the transactions were added to the history in, as far as the
+        // sequence numbers of the engines go.
+        merged.transactions.sort_by_key(|t| t.seq);
+        Snapshot(merged)
+    }
+}
 
-        self.account_mut(transaction.client)
-            .check_frozen_mut()?
-            .withdraw(amount)?;
+// Invoked after each processed transaction, with the outcome and the state of the affected
+// account at that point.
+type Callback = Box<dyn FnMut(&Transaction, result::Result<(), &Error>, &Account)>;
 
-        self.past_transactions.insert(transaction.tx, transaction);
+// The number of open disputes of an account and the funds they hold, by currency.
+type OpenDisputes = HashMap<Currency, (usize, Decimal)>;
 
-        Ok(())
+// The dispute policy in effect, as a handle that doesn't borrow the engine, so the handlers
+// can consult it while updating the engine state.
+enum PolicyHandle {
+    Custom(Arc<dyn DisputePolicy>),
+    Standard(StandardDisputePolicy),
+}
+
+impl Deref for PolicyHandle {
+    type Target = dyn DisputePolicy;
+
+    fn deref(&self) -> &Self::Target {
+        match self {
+            PolicyHandle::Custom(policy) => policy.as_ref(),
+            PolicyHandle::Standard(policy) => policy,
+        }
     }
+}
 
-    // Handles a `dispute` transaction.
-    fn process_dispute(&mut self, transaction: Transaction) -> Result<()> {
-        let (t, a) = self.transaction_mut(transaction.tx)?;
+// The settings of an engine, as adjusted via the `with_*` methods. Kept separately from the
+// state, so shard engines can be set up the same way (see `process_parallel`).
+#[derive(Clone)]
+struct Config {
+    frozen_policy: FrozenPolicy,
+    overdraft_policy: OverdraftPolicy,
+    negative_amounts: NegativeAmountPolicy,
+    // Tolerance used when validations compare amounts for equality.
+    amount_epsilon: Decimal,
+    // Create an account for every client referenced by a transaction, even when the
+    // transaction gets rejected.
+    track_all_clients: bool,
+    // Deposits are not retained in the history, so disputes cannot be supported.
+    disputes_disabled: bool,
+    // How many disputes of transactions which haven't been seen yet may be held back at most
+    // (zero disables deferral).
+    max_deferred_disputes: usize,
+    // How many dispute-related transactions referencing unknown transactions or accounts may
+    // be quarantined at most (zero disables the quarantine), and after how many of the
+    // following transactions each of them is retried before it gets rejected for good.
+    max_quarantined: usize,
+    quarantine_retries: u32,
+    // Transactions for clients the filter doesn't allow are rejected (i.e. to partition
+    // clients across tenants), and their accounts aren't reported.
+    client_filter: Option<ClientFilter>,
+    // Whether chargebacks freeze the affected account.
+    freeze_on_chargeback: bool,
+    // Whether chargeback reversals unfreeze the affected account.
+    unlock_on_reversal: bool,
+    time_ordering: TimeOrdering,
+    // Number of seconds after the timestamp of a transaction during which it can be disputed.
+    dispute_window: Option<u64>,
+    // Whether transactions whose dispute window has ended get evicted from the history.
+    evict_expired: bool,
+    // Withdrawals can be disputed as well (only deposits can by default).
+    withdrawal_disputes: bool,
+    // Resolved transactions can be disputed again.
+    redisputes: bool,
+    // Upper bound for the number of disputes per transaction (which implies `redisputes`).
+    max_disputes: Option<u32>,
+    // Have every account keep track of the operations applied to it.
+    record_history: bool,
+    // What to do about deposits and withdrawals of zero (which are no-ops otherwise).
+    zero_amounts: ZeroAmountPolicy,
+    // How to round amounts with too many decimal places (which are rejected if unset).
+    amount_rounding: Option<RoundingStrategy>,
+    // Fees deducted along with every withdrawal.
+    fees: Option<FeeSchedule>,
+    // How far withdrawals and outgoing transfers can take the available funds, for every
+    // client unless overridden per client.
+    balance_limits: BalanceLimits,
+    client_balance_limits: HashMap<ClientId, BalanceLimits>,
+    // Accept the administrative transaction types (`unlock`, `close`, and `reopen`).
+    admin_ops: bool,
+    // What happens to the funds of accounts that get closed.
+    residual_balances: ResidualBalancePolicy,
+    // Replaces the standard dispute rules (as given by the settings above) when set.
+    dispute_policy: Option<Arc<dyn DisputePolicy>>,
+    // Set for the shards of `process_parallel`, where the other party of a transfer may
+    // belong to a different shard.
+    transfers_disabled: bool,
+    // Upper bound for the number of transactions retained in the history.
+    history_limit: Option<usize>,
+    // Number of write-ahead log entries after which the state gets snapshotted (zero
+    // disables snapshots), and whether to sync every entry to disk.
+    wal_snapshot_interval: u64,
+    wal_sync: bool,
+    // Skip transactions which have been applied already.
+    replay_dedup: bool,
+    // Check the affected accounts after every transaction (see `invariants`).
+    check_invariants: bool,
+    // Report the metadata of the accounts (see `AccountMetadata`).
+    metadata_columns: bool,
+    // Report the open and settled disputes of the accounts.
+    dispute_columns: bool,
+    // The time transactions without a timestamp are processed at (they have none if unset).
+    clock: Option<Arc<dyn Clock>>,
+}
 
-        // Only `deposit` transactions can be disputed with this dummy
-        // transaction engine.
-        if t.disputed || t.type_ != Type::Deposit {
-            return Err(Error::InvalidDispute);
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            frozen_policy: FrozenPolicy::default(),
+            overdraft_policy: OverdraftPolicy::default(),
+            negative_amounts: NegativeAmountPolicy::default(),
+            amount_epsilon: Decimal::ZERO,
+            track_all_clients: false,
+            disputes_disabled: false,
+            max_deferred_disputes: 0,
+            max_quarantined: 0,
+            quarantine_retries: 0,
+            client_filter: None,
+            freeze_on_chargeback: true,
+            unlock_on_reversal: false,
+            time_ordering: TimeOrdering::default(),
+            dispute_window: None,
+            evict_expired: false,
+            withdrawal_disputes: false,
+            redisputes: false,
+            max_disputes: None,
+            record_history: false,
+            zero_amounts: ZeroAmountPolicy::default(),
+            amount_rounding: None,
+            fees: None,
+            balance_limits: BalanceLimits::default(),
+            client_balance_limits: HashMap::new(),
+            admin_ops: false,
+            residual_balances: ResidualBalancePolicy::default(),
+            dispute_policy: None,
+            transfers_disabled: false,
+            history_limit: None,
+            wal_snapshot_interval: DEFAULT_WAL_SNAPSHOT_INTERVAL,
+            wal_sync: false,
+            replay_dedup: false,
+            check_invariants: cfg!(any(test, feature = "invariants")),
+            metadata_columns: false,
+            dispute_columns: false,
+            clock: None,
         }
+    }
+}
 
-        let amount = t.amount();
+// The state handed to one of the shards of `process_parallel`, and back from it (together
+// with what happened while processing the shard's transactions).
+#[derive(Default)]
+struct Shard {
+    accounts: Vec<(ClientId, Account)>,
+    transactions: Vec<Transaction>,
+    deferred_disputes: Vec<Transaction>,
+    quarantine: Vec<(Transaction, u32)>,
+    chargebacks: Vec<Transaction>,
+    holds: Vec<Transaction>,
+    voided: Vec<Transaction>,
+    seen: Vec<TransactionId>,
+    processed: Vec<Processed>,
+    metrics: Option<Metrics>,
+    events: Option<EventLog>,
+    payouts: Vec<Payout>,
+    summary: ProcessSummary,
+    next_seq: u64,
+    latest_timestamp: Option<u64>,
+}
 
-        // We assume disputes cannot take place while an account is frozen.
-        a.check_frozen_mut()?
-            .decrease_available(amount)
-            .increase_held(amount);
+impl Shard {
+    // Runs on the worker thread of the shard, until there are no more batches to process.
+    fn process(self, config: &Config, receiver: Receiver<Vec<Transaction>>) -> Shard {
+        let mut engine = TransactionEngine::new();
+        engine.config = config.clone();
+        engine.next_seq = self.next_seq;
+        engine.latest_timestamp = self.latest_timestamp;
+        engine.client_accounts.extend(self.accounts);
+        for t in self.transactions {
+            engine.store_transaction(t);
+        }
+        if engine.history_bounded() {
+            engine.rebuild_history_order();
+        }
+        for t in self.deferred_disputes {
+            engine.deferred_disputes.insert(t.tx, t);
+        }
+        engine.quarantine.extend(self.quarantine);
+        for t in self.chargebacks {
+            engine.chargebacks.insert(t.tx, t);
+        }
+        for t in self.holds {
+            engine.insert_hold(t);
+        }
+        for t in self.voided {
+            engine.voided.insert(t.tx, t);
+        }
+        engine.seen.extend(self.seen);
+        engine.processed.extend(self.processed);
+        engine.metrics = self.metrics;
+        engine.event_log = self.events;
 
-        t.disputed = true;
+        let mut summary = ProcessSummary::default();
+        for batch in receiver {
+            for t in batch {
+                let outcome = engine.process_transaction_detailed(t);
+                summary.record_processed(&outcome);
+                engine.warnings.clear();
+            }
+        }
 
-        Ok(())
+        Shard {
+            accounts: engine.client_accounts.drain().collect(),
+            transactions: engine.stored_transactions().collect(),
+            deferred_disputes: engine.deferred_disputes.drain().map(|(_, t)| t).collect(),
+            quarantine: engine.quarantine.drain(..).collect(),
+            chargebacks: engine.chargebacks.drain().map(|(_, t)| t).collect(),
+            holds: engine.holds.drain().map(|(_, t)| t).collect(),
+            voided: engine.voided.drain().map(|(_, t)| t).collect(),
+            seen: engine.seen.drain().collect(),
+            processed: engine.processed.drain().collect(),
+            metrics: engine.metrics.take(),
+            events: engine.event_log.take(),
+            payouts: engine.payouts,
+            summary,
+            next_seq: engine.next_seq,
+            latest_timestamp: engine.latest_timestamp,
+        }
+    }
+}
+
+/// Configures the behaviors that differ between deployments up front, for a
+/// `TransactionEngine` built once they're all set. Every option defaults to the behavior of
+/// `TransactionEngine::new`, and gets forwarded to the `with_*` method of the same name on
+/// the engine being built (which documents it), in the order the options were set.
+#[derive(Clone, Default)]
+pub struct TransactionEngineBuilder {
+    // The settings, as calls of the `with_*` methods of the engine.
+    steps: Vec<Arc<BuildStep>>,
+    // Applied on top of the steps once built.
+    settings: Option<EngineConfig>,
+}
+
+type BuildStep = dyn Fn(TransactionEngine) -> TransactionEngine + Send + Sync;
+
+impl TransactionEngineBuilder {
+    pub fn new() -> Self {
+        TransactionEngineBuilder::default()
     }
 
-    // Handles a `resolve` transaction. We assume `resolve` and `chargeback` operations
-    // for disputes that happened before an account got frozen can still go through.
-    // It's straightforward to change this behaviour if the assumption is wrong.
-    fn process_resolve(&mut self, transaction: Transaction) -> Result<()> {
-        let (t, a) = self.transaction_mut(transaction.tx)?;
+    pub fn with_frozen_policy(self, policy: FrozenPolicy) -> Self {
+        self.then(move |engine| engine.with_frozen_policy(policy))
+    }
 
-        if !t.disputed {
-            return Err(Error::InvalidResolve);
-        }
+    pub fn with_frozen_disputes(self, disputes: FrozenDisputes) -> Self {
+        self.then(move |engine| engine.with_frozen_disputes(disputes))
+    }
 
-        let amount = t.amount();
-        a.decrease_held(amount).increase_available(amount);
+    pub fn with_redisputes(self, enabled: bool) -> Self {
+        self.then(move |engine| engine.with_redisputes(enabled))
+    }
 
-        let id = t.tx;
-        // We assume transactions can only be disputed once. Remove the resolved transaction
-        // from the current history, so it cannot be disputed again.
-        self.past_transactions.remove(&id);
+    pub fn with_max_disputes(self, max: u32) -> Self {
+        self.then(move |engine| engine.with_max_disputes(max))
+    }
 
-        Ok(())
+    pub fn with_dispute_window(self, seconds: u64) -> Self {
+        self.then(move |engine| engine.with_dispute_window(seconds))
+    }
+
+    pub fn with_expired_eviction(self, enabled: bool) -> Self {
+        self.then(move |engine| engine.with_expired_eviction(enabled))
+    }
+
+    pub fn with_negative_amount_policy(self, policy: NegativeAmountPolicy) -> Self {
+        self.then(move |engine| engine.with_negative_amount_policy(policy))
+    }
+
+    pub fn with_zero_amount_policy(self, policy: ZeroAmountPolicy) -> Self {
+        self.then(move |engine| engine.with_zero_amount_policy(policy))
+    }
+
+    /// Also applies to the transactions the ledger contains already, for
+    /// `build_with_storage`.
+    pub fn with_history_limit(self, limit: usize) -> Self {
+        self.then(move |engine| engine.with_history_limit(limit))
+    }
+
+    pub fn with_metadata_columns(self, enabled: bool) -> Self {
+        self.then(move |engine| engine.with_metadata_columns(enabled))
+    }
+
+    pub fn with_dispute_columns(self, enabled: bool) -> Self {
+        self.then(move |engine| engine.with_dispute_columns(enabled))
+    }
+
+    pub fn with_quarantine(self, max: usize, retries: u32) -> Self {
+        self.then(move |engine| engine.with_quarantine(max, retries))
+    }
+
+    /// Every engine built gets the same clock.
+    pub fn with_clock<C: Clock + 'static>(self, clock: C) -> Self {
+        let clock = Arc::new(clock);
+        self.then(move |engine| engine.with_clock(Arc::clone(&clock)))
+    }
+
+    /// How many accounts and transactions the engine makes room for up front, as described
+    /// for `TransactionEngine::with_capacity`.
+    pub fn with_capacity(self, clients: usize, transactions: usize) -> Self {
+        self.then(move |mut engine| {
+            engine.reserve(clients, transactions);
+            engine
+        })
+    }
+
+    /// Settings from a configuration file (see `EngineConfig`), which take precedence over
+    /// the other settings of the builder.
+    pub fn with_config(mut self, config: EngineConfig) -> Self {
+        self.settings = Some(config);
+        self
+    }
+
+    /// Builds an engine without any accounts.
+    pub fn build(self) -> TransactionEngine {
+        self.configure(TransactionEngine::new())
+    }
+
+    /// Builds an engine on top of `ledger` (see `TransactionEngine::new_with_storage`).
+    pub fn build_with_storage<L: Ledger + 'static>(
+        self,
+        ledger: L,
+    ) -> io::Result<TransactionEngine> {
+        Ok(self.configure(TransactionEngine::new_with_storage(ledger)?))
     }
 
-    fn process_chargeback(&mut self, transaction: Transaction) -> Result<()> {
-        let (t, a) = self.transaction_mut(transaction.tx)?;
+    fn then<F>(mut self, step: F) -> Self
+    where
+        F: Fn(TransactionEngine) -> TransactionEngine + Send + Sync + 'static,
+    {
+        self.steps.push(Arc::new(step));
+        self
+    }
 
-        if !t.disputed {
-            return Err(Error::InvalidChargeback);
+    fn configure(&self, engine: TransactionEngine) -> TransactionEngine {
+        let engine = self.steps.iter().fold(engine, |engine, step| step(engine));
+        match &self.settings {
+            Some(settings) => settings.apply(engine),
+            None => engine,
         }
+    }
+}
 
-        a.decrease_held(t.amount()).freeze();
+/// Implements transaction processing logic.
+pub struct TransactionEngine {
+    client_accounts: IdMap<ClientId, Account>,
+    // The history of past transactions (and a copy of the accounts, for persistent ledgers).
+    ledger: Box<dyn Ledger>,
+    // The kind of the first error the ledger returned (see `storage_failure`).
+    storage_failure: Cell<Option<io::ErrorKind>>,
+    config: Config,
+    // Disputes of transactions which haven't been seen yet, keyed by the id of the disputed
+    // transaction.
+    deferred_disputes: IdMap<TransactionId, Transaction>,
+    // Dispute-related transactions referencing unknown transactions or accounts, in the order
+    // they came in, with how many times they've been retried so far.
+    quarantine: VecDeque<(Transaction, u32)>,
+    // The chargebacks which can still be reversed (see `Checkpoint::chargebacks`), keyed by
+    // the id of the charged back transaction.
+    chargebacks: IdMap<TransactionId, Transaction>,
+    // The holds which haven't expired yet, keyed by transaction id, and the earliest time one
+    // of them expires at.
+    holds: IdMap<TransactionId, Transaction>,
+    next_hold_expiry: Option<u64>,
+    // The voided transactions, keyed by transaction id.
+    voided: IdMap<TransactionId, Transaction>,
+    // The ids of every transaction added to the history, which stay here once it leaves the
+    // history (i.e. once resolved or charged back), so they can't be reused.
+    seen: HashSet<TransactionId, IdHasher>,
+    // The transactions currently under dispute, kept in step with their `disputed` flag in
+    // the ledger (see `store_transaction`), so open disputes can be looked up without going
+    // through the history.
+    open_disputes: IdMap<TransactionId, DisputeRecord>,
+    callback: Option<Callback>,
+    observer: Option<Box<dyn EngineObserver>>,
+    // Run in order before applying a transaction.
+    validators: Vec<Box<dyn Validator>>,
+    // Evaluates the risk rules on the applied transactions, once set up via
+    // `with_risk_rules`.
+    risk: Option<RiskMonitor>,
+    // Sequence number for the next transaction added to the ledger.
+    next_seq: u64,
+    // The latest timestamp of the applied transactions, which is the current time as far as
+    // dispute windows go. Only kept with `with_dispute_window`.
+    latest_timestamp: Option<u64>,
+    // Advisories which have not been handed out via `drain_warnings` yet.
+    warnings: Vec<Warning>,
+    // Funds paid out when closing accounts, which have not been handed out via
+    // `drain_payouts` yet.
+    payouts: Vec<Payout>,
+    // The ids of the retained transactions, oldest first. Only kept when the history is
+    // bounded, and may still contain ids that have been removed since (i.e. once resolved).
+    history_order: VecDeque<TransactionId>,
+    // Journal of the accepted transactions, once set up via `recover`.
+    wal: Option<Wal>,
+    // The transactions applied so far, only recorded with `with_replay_dedup`.
+    processed: HashSet<Processed, IdHasher>,
+    // Only collected once enabled via `with_metrics`.
+    metrics: Option<Metrics>,
+    // Only recorded once enabled via `with_event_log`.
+    event_log: Option<EventLog>,
+    // Only recorded once enabled via `with_lineage`.
+    lineage: Option<Lineage>,
+}
 
-        let id = t.tx;
-        self.past_transactions.remove(&id);
+impl Default for TransactionEngine {
+    fn default() -> Self {
+        TransactionEngine::new()
+    }
+}
 
-        Ok(())
+impl TransactionEngine {
+    /// Creates an engine without any accounts, using the default configuration (which can be
+    /// adjusted via the `with_*` methods).
+    pub fn new() -> Self {
+        TransactionEngine {
+            client_accounts: IdMap::default(),
+            ledger: Box::new(MemoryLedger::default()),
+            storage_failure: Cell::new(None),
+            config: Config::default(),
+            deferred_disputes: IdMap::default(),
+            quarantine: VecDeque::new(),
+            chargebacks: IdMap::default(),
+            holds: IdMap::default(),
+            next_hold_expiry: None,
+            voided: IdMap::default(),
+            seen: HashSet::default(),
+            open_disputes: IdMap::default(),
+            callback: None,
+            observer: None,
+            validators: Vec::new(),
+            risk: None,
+            next_seq: 0,
+            latest_timestamp: None,
+            warnings: Vec::new(),
+            payouts: Vec::new(),
+            history_order: VecDeque::new(),
+            wal: None,
+            processed: HashSet::default(),
+            metrics: None,
+            event_log: None,
+            lineage: None,
+        }
+    }
+
+    /// Same as `new`, but with room for `clients` accounts and `transactions` transactions in
+    /// the history up front, so the maps don't have to grow (and rehash) while processing.
+    /// These are hints, and either may be exceeded.
+    pub fn with_capacity(clients: usize, transactions: usize) -> Self {
+        let mut engine = TransactionEngine::new();
+        engine.reserve(clients, transactions);
+        engine
     }
 
-    // Simple method to print the resulting account data. Could have used the `csv` crate
-    // for output as well, but this was quicker.
-    pub fn print_accounts(&self) {
-        println!("client,available,held,total,locked");
+    /// Makes room for at least `clients` more accounts and `transactions` more transactions
+    /// in the history, same as `with_capacity` but for an existing engine (i.e. one loaded
+    /// from a checkpoint). Persistent ledgers may ignore the hint for the transactions.
+    pub fn reserve(&mut self, clients: usize, transactions: usize) {
+        self.client_accounts.reserve(clients);
+        self.ledger.reserve(transactions);
+        if self.history_bounded() {
+            self.history_order.reserve(transactions);
+        }
+    }
 
-        for (client, account) in self.client_accounts.iter() {
-            println!(
-                "{},{},{},{},{}",
-                client,
-                account.available(),
-                account.held(),
-                account.available() + account.held(),
-                account.frozen()
-            );
+    /// Returns a builder for configuring an engine up front (see `TransactionEngineBuilder`).
+    pub fn builder() -> TransactionEngineBuilder {
+        TransactionEngineBuilder::new()
+    }
+
+    /// Creates an engine on top of `ledger`, which may already contain the state of a previous
+    /// run (i.e. for an on-disk ledger that got reopened after a restart). The configuration
+    /// isn't part of the ledger, so it has to be the same as before. As with checkpoints, the
+    /// loaded state can be checked via `is_valid`. Fails if the ledger can't be read.
+    pub fn new_with_storage<L: Ledger + 'static>(ledger: L) -> io::Result<Self> {
+        let mut engine = TransactionEngine::new();
+        engine.client_accounts = ledger.accounts()?.into_iter().collect();
+
+        for t in ledger.transactions() {
+            let t = t?;
+            engine.next_seq = engine.next_seq.max(t.seq + 1);
+            if t.disputed {
+                engine.open_disputes.insert(t.tx, DisputeRecord::of(&t));
+            }
         }
+        // Open disputes are not stored along with the accounts, same as for checkpoints.
+        engine.recount_open_disputes();
+
+        engine.ledger = Box::new(ledger);
+        Ok(engine)
+    }
+
+    /// Sets which types of transactions are still allowed for frozen accounts. For disputes,
+    /// resolves, and chargebacks, this only applies to the standard dispute rules (see
+    /// `with_dispute_policy`).
+    pub fn with_frozen_policy(mut self, policy: FrozenPolicy) -> Self {
+        self.config.frozen_policy = policy;
+        self
+    }
+
+    /// Sets which parts of the dispute lifecycle still go through for frozen accounts (only
+    /// resolves by default), on top of the frozen policy set so far: `FrozenDisputes::Block`
+    /// keeps open disputes from being resolved or charged back until the account is
+    /// unlocked, and `FrozenDisputes::Settle` lets them be charged back as well. Same as for
+    /// `with_frozen_policy`, this only applies to the standard dispute rules.
+    pub fn with_frozen_disputes(mut self, disputes: FrozenDisputes) -> Self {
+        self.config.frozen_policy = self.config.frozen_policy.with_disputes(disputes);
+        self
+    }
+
+    /// Sets what happens when disputing a deposit (or an incoming transfer) would leave the
+    /// account with negative available funds. Such disputes go through by default.
+    pub fn with_overdraft_policy(mut self, policy: OverdraftPolicy) -> Self {
+        self.config.overdraft_policy = policy;
+        self
+    }
+
+    /// Enables support for feeds that use a single signed amount column to represent funds
+    /// going in or out. In this mode, a negative amount on a `deposit` or `withdrawal` row is
+    /// interpreted as a withdrawal, and a positive one as a deposit, regardless of the type
+    /// (same as `NegativeAmountPolicy::Signed`).
+    pub fn with_signed_amounts(mut self, enabled: bool) -> Self {
+        self.config.negative_amounts = if enabled {
+            NegativeAmountPolicy::Signed
+        } else {
+            NegativeAmountPolicy::Reject
+        };
+        self
+    }
+
+    /// Sets what happens to deposits and withdrawals with a negative amount, which get
+    /// rejected by default.
+    pub fn with_negative_amount_policy(mut self, policy: NegativeAmountPolicy) -> Self {
+        self.config.negative_amounts = policy;
+        self
+    }
+
+    /// Sets the tolerance used when validations compare amounts for equality (exact matches
+    /// are required by default).
+    pub fn with_amount_epsilon(mut self, epsilon: Decimal) -> Self {
+        self.config.amount_epsilon = epsilon;
+        self
+    }
+
+    /// When enabled, every client referenced by a transaction gets an account, even if all of
+    /// its transactions were rejected (so reporting can include a zero row for such clients).
+    /// Otherwise, accounts may only get created as part of processing valid transactions.
+    pub fn with_track_all_clients(mut self, enabled: bool) -> Self {
+        self.config.track_all_clients = enabled;
+        self
+    }
+
+    /// For inputs that are known not to contain any disputes. When enabled, deposits only
+    /// update balances and are not retained in the history (which saves a considerable amount
+    /// of memory for large inputs), while dispute-related transactions are rejected with
+    /// `Error::DisputesDisabled`.
+    pub fn with_disputes_disabled(mut self, disabled: bool) -> Self {
+        self.config.disputes_disabled = disabled;
+        self
+    }
+
+    /// Zero-amount deposits and withdrawals are rejected with `Error::ZeroAmount` by default,
+    /// since they don't change any balance but still end up in the transaction history
+    /// (same as `ZeroAmountPolicy::Accept` when allowed).
+    pub fn with_zero_amounts_allowed(mut self, allowed: bool) -> Self {
+        self.config.zero_amounts = if allowed {
+            ZeroAmountPolicy::Accept
+        } else {
+            ZeroAmountPolicy::Reject
+        };
+        self
+    }
+
+    /// Sets what happens to zero-amount deposits and withdrawals, which get rejected by
+    /// default.
+    pub fn with_zero_amount_policy(mut self, policy: ZeroAmountPolicy) -> Self {
+        self.config.zero_amounts = policy;
+        self
+    }
+
+    /// Amounts with more than four decimal places are rejected with `Error::ExcessivePrecision`
+    /// by default. When a rounding strategy is set, such amounts get rounded to four decimal
+    /// places instead, and processed as if the input had the rounded amount.
+    pub fn with_amount_rounding(mut self, strategy: RoundingStrategy) -> Self {
+        self.config.amount_rounding = Some(strategy);
+        self
+    }
+
+    /// Charges the fees of `schedule` on every withdrawal, deducted from the available funds
+    /// together with the withdrawn amount (so withdrawals the account can't cover the fee of
+    /// are rejected with `Error::InsufficientFunds`). With account histories (see
+    /// `with_account_history`), every fee gets an entry of its own, right after the one of
+    /// its withdrawal and with the same transaction id. Disputes of withdrawals only ever
+    /// concern the withdrawn amount, so fees aren't refunded.
+    pub fn with_fee_schedule(mut self, schedule: FeeSchedule) -> Self {
+        self.config.fees = Some(schedule);
+        self
+    }
+
+    /// Applies `limits` to the accounts of every client (unless overridden via
+    /// `with_client_balance_limits`): withdrawals and outgoing transfers can take the
+    /// available funds down to `BalanceLimits::floor` (i.e. below zero, with an overdraft
+    /// line), and are rejected with `Error::InsufficientFunds` beyond that (counting the fee
+    /// of a withdrawal as well). Accounts that go below zero this way are marked as overdrawn
+    /// (see `Account::overdrawn`), and with any overdraft line configured, the account report
+    /// gets an `overdraft` column with how much of it is in use.
+    pub fn with_balance_limits(mut self, limits: BalanceLimits) -> Self {
+        self.config.balance_limits = limits;
+        self
+    }
+
+    /// Same as `with_balance_limits`, but only for the account of `client`, which takes
+    /// precedence over the limits for every client.
+    pub fn with_client_balance_limits(mut self, client: ClientId, limits: BalanceLimits) -> Self {
+        self.config.client_balance_limits.insert(client, limits);
+        self
+    }
+
+    /// Accepts the administrative transaction types, which are rejected with
+    /// `Error::AdminOpsDisabled` by default, since they are meant for operations teams rather
+    /// than regular feeds:
+    /// - `unlock` unfreezes the account of `client` (i.e. after a chargeback has been reviewed).
+    /// - `close` closes the account, after which all of its transactions are rejected with
+    ///   `Error::AccountClosed` (including disputes of earlier transactions).
+    /// - `reopen` reverts `close`.
+    ///
+    /// Accounts are closed with the funds they hold, unless configured otherwise via
+    /// `with_residual_balance_policy`.
+    ///
+    /// The `tx` of these is only used to refer to them in logs and reports. They are not part
+    /// of the transaction history, and aren't checked for duplicates.
+    pub fn with_admin_ops(mut self, enabled: bool) -> Self {
+        self.config.admin_ops = enabled;
+        self
+    }
+
+    /// Adds the `name`, `reference`, and `tags` columns to the account report, with the
+    /// metadata attached to the accounts (see `set_account_metadata`), or empty fields for
+    /// accounts without any. They aren't reported by default.
+    pub fn with_metadata_columns(mut self, enabled: bool) -> Self {
+        self.config.metadata_columns = enabled;
+        self
+    }
+
+    /// Adds the dispute statistics of the accounts to the account report, per currency: the
+    /// number of open disputes and the funds they hold (`open_disputes` and
+    /// `disputed_amount`), and the number of resolves and chargebacks so far, with the funds
+    /// they settled (`resolved_disputes`, `resolved_amount`, `chargebacks`, and
+    /// `chargeback_amount`). Partial resolves and chargebacks count as one each. The settled
+    /// disputes are counted whether or not this is enabled, and are part of checkpoints.
+    pub fn with_dispute_columns(mut self, enabled: bool) -> Self {
+        self.config.dispute_columns = enabled;
+        self
+    }
+
+    /// What happens when closing an account that still holds funds: by default, it's closed
+    /// with the funds in place (see `ResidualBalancePolicy`).
+    pub fn with_residual_balance_policy(mut self, policy: ResidualBalancePolicy) -> Self {
+        self.config.residual_balances = policy;
+        self
+    }
+
+    /// Bounds the memory used by the transaction history, which otherwise retains every
+    /// deposit and withdrawal for as long as it can be disputed. Once more than `limit`
+    /// transactions are retained, the oldest ones get evicted (apart from those currently
+    /// under dispute), and disputes referencing them are rejected with
+    /// `Error::TransactionNotFound`. Duplicate transaction ids are only detected among the
+    /// retained transactions then. Also applies to the existing history, i.e. of a loaded
+    /// checkpoint.
+    pub fn with_history_limit(mut self, limit: usize) -> Self {
+        self.config.history_limit = Some(limit);
+        self.rebuild_history_order();
+        self
+    }
+
+    /// Collects `Metrics` about the processed transactions (counts by type and by error, and
+    /// the time spent processing them), which are available via `metrics` from then on.
+    pub fn with_metrics(mut self, enabled: bool) -> Self {
+        self.metrics = enabled.then(Metrics::default);
+        self
+    }
+
+    /// Makes replaying inputs idempotent, i.e. when re-feeding overlapping files after a
+    /// partial failure. Every applied transaction is recorded by its client, id, and type, and
+    /// transactions matching one of those (including disputes, resolves, and chargebacks) are
+    /// skipped with a `Warning::ReplaySkipped`, instead of getting applied twice or rejected.
+    /// Rejected transactions are not recorded, so their replays get processed as usual. The
+    /// recorded transactions are part of checkpoints, and take memory for every transaction
+    /// processed (unlike the history, they're never settled).
+    pub fn with_replay_dedup(mut self, enabled: bool) -> Self {
+        self.config.replay_dedup = enabled;
+        self
+    }
+
+    /// Allows out-of-order input, where a dispute may precede the transaction it refers to.
+    /// Up to `max` such disputes are held back (which bounds the extra memory used), and get
+    /// applied as soon as a matching transaction gets processed. Disputes beyond the limit are
+    /// rejected with `Error::TransactionNotFound`, same as when deferral is disabled (`0`), and
+    /// so are disputes of transactions which have been settled already.
+    pub fn with_deferred_disputes(mut self, max: usize) -> Self {
+        self.config.max_deferred_disputes = max;
+        self
+    }
+
+    /// Allows unordered input more generally than `with_deferred_disputes`: disputes,
+    /// resolves, chargebacks and chargeback reversals which get rejected with
+    /// `Error::TransactionNotFound` or `Error::AccountNotFound` are quarantined instead (with
+    /// a `Warning::Quarantined`), up to `max` at a time, and retried in the order they came in
+    /// after every transaction that gets applied. Each of them is retried `retries` times at
+    /// most (and at least once), and rejected for good (which only gets logged) once it's out
+    /// of retries, or fails for any other reason. Transactions beyond the limit are rejected
+    /// right away, same as when the quarantine is disabled (`0`). Retries go through the same
+    /// checks and validators as new transactions, and quarantined transactions only count as
+    /// processed (for `with_replay_dedup`) and get journaled (see `recover`) once they've been
+    /// applied. Deferred disputes take precedence, and neither are part of checkpoints.
+    pub fn with_quarantine(mut self, max: usize, retries: u32) -> Self {
+        self.config.max_quarantined = max;
+        self.config.quarantine_retries = retries;
+        self
+    }
+
+    /// Restricts processing to the clients allowed by `clients` (a `ClientFilter`, or a single
+    /// range of client ids). Transactions for any other client are rejected with
+    /// `Error::ClientNotAllowed`, before any state change (so no account gets created for them
+    /// either). Allowed clients get their accounts created as usual. The accounts of other
+    /// clients the engine already has (i.e. from a checkpoint) are left out of the account
+    /// report.
+    pub fn with_client_filter<F: Into<ClientFilter>>(mut self, clients: F) -> Self {
+        self.config.client_filter = Some(clients.into());
+        self
+    }
+
+    /// Chargebacks freeze the affected account by default. When disabled, a chargeback still
+    /// removes the funds held under dispute, but leaves the account unfrozen, so subsequent
+    /// transactions go through as usual (i.e. for chargebacks that are merely informational).
+    pub fn with_freeze_on_chargeback(mut self, enabled: bool) -> Self {
+        self.config.freeze_on_chargeback = enabled;
+        self
+    }
+
+    /// Chargeback reversals leave the account frozen by default, so it still needs to be
+    /// unlocked by hand. When enabled, a reversal unfreezes the account as well, unless
+    /// another chargeback of the account hasn't been reversed.
+    pub fn with_unlock_on_reversal(mut self, enabled: bool) -> Self {
+        self.config.unlock_on_reversal = enabled;
+        self
+    }
+
+    /// Sets whether transactions with timestamps out of order are rejected (they're accepted
+    /// by default). Since the latest timestamp of each client is part of its account, the
+    /// per-client order carries over checkpoints as well.
+    pub fn with_time_ordering(mut self, ordering: TimeOrdering) -> Self {
+        self.config.time_ordering = ordering;
+        self
+    }
+
+    /// Limits disputes to the `seconds` after the timestamp of the disputed transaction.
+    /// Later disputes are rejected with `Error::DisputeWindowExpired`, as of the timestamp of
+    /// the dispute (or the latest timestamp of the applied transactions, for disputes without
+    /// one). Transactions without a timestamp can be disputed at any time. The latest
+    /// timestamp isn't part of checkpoints, so it starts over with the transactions processed
+    /// after loading one.
+    pub fn with_dispute_window(mut self, seconds: u64) -> Self {
+        self.config.dispute_window = Some(seconds);
+        self.rebuild_history_order();
+        self
+    }
+
+    /// Since transactions can't be disputed anymore once their dispute window has ended (see
+    /// `with_dispute_window`), they can be evicted from the history (apart from those
+    /// currently under dispute), so it doesn't grow without bound. Eviction goes by the order
+    /// the transactions were added in, and stops at the first one which hasn't expired yet,
+    /// so it keeps up with inputs in timestamp order. Disputes referencing evicted
+    /// transactions are rejected with `Error::TransactionNotFound` rather than
+    /// `Error::DisputeWindowExpired`, and duplicate transaction ids are only detected among
+    /// the retained transactions.
+    pub fn with_expired_eviction(mut self, enabled: bool) -> Self {
+        self.config.evict_expired = enabled;
+        self.rebuild_history_order();
+        self
+    }
+
+    /// Only deposits can be disputed by default. When enabled, withdrawals can be disputed as
+    /// well: the disputed amount gets held (without reducing the available funds, since it
+    /// already left the account), a resolve releases the hold (so the withdrawal stands), and
+    /// a chargeback reverses the withdrawal by moving the held funds back to available. For
+    /// partial chargebacks, only the charged back portion is returned to the client. This only
+    /// applies to the standard dispute rules (see `with_dispute_policy`).
+    pub fn with_withdrawal_disputes(mut self, enabled: bool) -> Self {
+        self.config.withdrawal_disputes = enabled;
+        self
+    }
+
+    /// Transactions can only be disputed once by default, so resolved transactions are removed
+    /// from the history (or only their undisputed portion is retained, after a partial
+    /// dispute). When enabled, resolved transactions can be disputed again instead, and are
+    /// retained until they're charged back. This only applies to the standard dispute rules
+    /// (see `with_dispute_policy`).
+    pub fn with_redisputes(mut self, enabled: bool) -> Self {
+        self.config.redisputes = enabled;
+        self
+    }
+
+    /// Lets transactions be disputed up to `max` times, i.e. re-disputed after a resolve
+    /// until they've been disputed that often (which implies `with_redisputes`). Resolved
+    /// transactions are retained along with their dispute count until then, and removed from
+    /// the history once their last dispute has been resolved. This only applies to the
+    /// standard dispute rules (see `with_dispute_policy`).
+    pub fn with_max_disputes(mut self, max: u32) -> Self {
+        self.config.max_disputes = Some(max);
+        self
+    }
+
+    /// Replaces the standard dispute rules (see `StandardDisputePolicy`) with `policy`, which
+    /// then decides which transactions can be disputed (and whether again, after a resolve),
+    /// and which dispute-related transactions can go through for frozen accounts. The way
+    /// disputes, resolves, and chargebacks affect balances stays the same.
+    pub fn with_dispute_policy<P: DisputePolicy + 'static>(mut self, policy: P) -> Self {
+        self.config.dispute_policy = Some(Arc::new(policy));
+        self
+    }
+
+    /// Processes the transactions without a timestamp as of the time of `clock`, as if they
+    /// had that timestamp (which they're retained with), so holds expire, dispute windows
+    /// end, and scheduled adjustments apply as the clock moves on. Transactions with a
+    /// timestamp keep theirs, and get passed to `Clock::observe` first. Without a clock
+    /// (the default), time only goes by the timestamps of the transactions. Tests and replays
+    /// can inject a `clock::SimulatedClock` to control the time.
+    pub fn with_clock<C: Clock + 'static>(mut self, clock: C) -> Self {
+        self.config.clock = Some(Arc::new(clock));
+        self
+    }
+
+    /// The time of the clock set via `with_clock`, if any (and if it knows the time).
+    pub fn clock_time(&self) -> Option<u64> {
+        self.config.clock.as_ref().and_then(|clock| clock.now())
+    }
+
+    fn dispute_policy(&self) -> PolicyHandle {
+        match &self.config.dispute_policy {
+            Some(policy) => PolicyHandle::Custom(Arc::clone(policy)),
+            None => PolicyHandle::Standard(
+                StandardDisputePolicy::new(
+                    self.config.withdrawal_disputes,
+                    self.config.frozen_policy,
+                )
+                .with_redisputes(self.config.redisputes)
+                .with_max_disputes(self.config.max_disputes),
+            ),
+        }
+    }
+
+    /// Sets after how many journaled transactions the write-ahead log (see `recover`) gets
+    /// replaced by a snapshot of the engine state, which bounds the time it takes to recover.
+    /// Writing a snapshot takes time proportional to the size of the state, so this trades
+    /// throughput for recovery time. Zero disables snapshots.
+    pub fn with_wal_snapshot_interval(mut self, interval: u64) -> Self {
+        self.config.wal_snapshot_interval = interval;
+        self
+    }
+
+    /// When enabled, every entry of the write-ahead log gets synced to disk before the
+    /// transaction is applied, so that it survives power loss (and not just crashes of the
+    /// process). This is considerably slower.
+    pub fn with_wal_sync(mut self, enabled: bool) -> Self {
+        self.config.wal_sync = enabled;
+        self
+    }
+
+    /// When enabled, every account retains the ordered list of operations applied to it (see
+    /// `Account::history`), so it's possible to reconstruct how the account arrived at its
+    /// current balance. This increases memory use significantly for large inputs.
+    pub fn with_account_history(mut self, enabled: bool) -> Self {
+        self.config.record_history = enabled;
+        self
+    }
+
+    /// When enabled, every change to an account gets recorded as a sequence of events (see
+    /// `events::AccountEvent`) in a log which the accounts can be derived from, so it's
+    /// possible to audit every change, and to reconstruct the accounts as of any point (see
+    /// `events::fold`). The log starts with the events for the accounts the engine has
+    /// already, and holds every event from then on, so memory use grows with the input.
+    pub fn with_event_log(mut self, enabled: bool) -> Self {
+        self.event_log = enabled.then(EventLog::default);
+        self.start_event_log();
+        self
+    }
+
+    /// When enabled, the lifecycle of every transaction that gets disputed or voided from then
+    /// on, and every transfer, is recorded as part of the lineage of the funds (see
+    /// `lineage::Lineage`), i.e. to export it as a graph. Disputes applied once deferred or
+    /// quarantined are not recorded, and neither is anything processed via
+    /// `process_parallel`. The lineage isn't part of checkpoints.
+    pub fn with_lineage(mut self, enabled: bool) -> Self {
+        self.lineage = enabled.then(Lineage::default);
+        self
+    }
+
+    /// Registers a closure that gets invoked after each call to `process_transaction` (i.e. to
+    /// stream account updates to a downstream system as they happen). The account passed
+    /// along is the one affected by the transaction, which for dispute-related transactions
+    /// belongs to the client of the original transaction. Clients without an account (such as
+    /// when their first transaction got rejected) are reported with an empty account.
+    pub fn with_transaction_callback<F>(mut self, callback: F) -> Self
+    where
+        F: FnMut(&Transaction, result::Result<(), &Error>, &Account) + 'static,
+    {
+        self.callback = Some(Box::new(callback));
+        self
+    }
+
+    /// Checks the invariants of the accounts affected by every transaction right after it's
+    /// processed, and returns `Error::InvariantViolated` for the transaction if any of them
+    /// is broken (which can only be due to a bug in the engine, or an inconsistent state
+    /// loaded from a checkpoint), in which case the state can't be trusted anymore. The
+    /// transaction has been applied regardless. The checks are on by
+    /// default with the `invariants` feature, i.e. for fuzzing or test builds of embedders.
+    /// These cover the balances (the total is the sum of the available and held funds, held
+    /// funds are never negative, and neither are the total or available funds unless a
+    /// chargeback or the overdraft policy allows it), and that transactions which the frozen
+    /// policy blocks don't go through for frozen accounts. Accounts are checked in full by
+    /// `is_valid`.
+    pub fn with_invariant_checks(mut self, enabled: bool) -> Self {
+        self.config.check_invariants = enabled;
+        self
+    }
+
+    /// Registers an observer, which gets notified of every change to an account right after
+    /// it's applied (see `EngineObserver`). Unlike the transaction callback, this covers
+    /// the individual effects of a transaction (i.e. a chargeback freezing the account), as
+    /// well as deferred disputes and adjustments. Neither gets invoked by `process_parallel`.
+    pub fn with_observer<O: EngineObserver + 'static>(mut self, observer: O) -> Self {
+        self.observer = Some(Box::new(observer));
+        self
+    }
+
+    /// Adds a validator, which has to accept every transaction before it's applied (see
+    /// `validator`). Validators run after the ones registered before, but ahead of the checks
+    /// against the affected accounts (i.e. for sufficient funds), while rejections by the
+    /// client filter or the time ordering take precedence. Their state isn't part of
+    /// checkpoints, and they are bypassed by `process_parallel`.
+    pub fn with_validator<V: Validator + 'static>(mut self, validator: V) -> Self {
+        self.validators.push(Box::new(validator));
+        self
+    }
+
+    /// Evaluates the risk rules on every applied transaction, and tags the account of its
+    /// client with the flags they raise (see `risk`), which also get reported as warnings.
+    /// Accounts stay flagged, and the account report gets a `risk_score` column. The state of
+    /// the rules isn't part of checkpoints (unlike the flags), and they are bypassed by
+    /// `process_parallel`.
+    pub fn with_risk_rules(mut self, rules: RiskRules) -> Self {
+        self.risk = Some(RiskMonitor::new(rules));
+        self
+    }
+
+    /// The risk score of the account of `client`, when risk rules are configured.
+    pub fn risk_score(&self, client: ClientId) -> Option<u32> {
+        let risk = self.risk.as_ref()?;
+        let account = self.client_accounts.get(&client)?;
+        Some(risk.rules().score(account.risk_flags()))
+    }
+
+    // Hands the account of `client` to the observer (if there is one).
+    fn notify<F: FnOnce(&mut dyn EngineObserver, &Account)>(&mut self, client: ClientId, f: F) {
+        if let Some(observer) = self.observer.as_mut() {
+            let account = self.client_accounts.get(&client).unwrap_or(&EMPTY_ACCOUNT);
+            f(observer.as_mut(), account);
+        }
+    }
+
+    /// Writes the current state of the engine to `w` (as JSON), so processing can be resumed
+    /// later via `load_checkpoint`. Transactions that have been removed from the history (i.e.
+    /// already resolved or charged back) are not part of the checkpoint, and stay gone after
+    /// a restore.
+    pub fn save_checkpoint<W: Write>(&self, w: W) -> io::Result<()> {
+        serde_json::to_writer(w, &self.checkpoint())?;
+        Ok(())
+    }
+
+    /// Creates an engine based on a checkpoint previously written by `save_checkpoint`. The
+    /// loaded state should be checked via `is_valid` once the engine has been configured
+    /// (since settings such as the amount epsilon affect validation).
+    pub fn load_checkpoint<R: Read>(r: R) -> io::Result<Self> {
+        TransactionEngine::from_checkpoint(serde_json::from_reader(r)?)
+    }
+
+    /// Same as `save_checkpoint`, but using the human-editable TOML format, so operators can
+    /// manually correct the state before loading it back via `load_toml`.
+    pub fn save_toml<W: Write>(&self, mut w: W) -> io::Result<()> {
+        let toml = config::to_toml(&self.checkpoint())?;
+        w.write_all(toml.as_bytes())
+    }
+
+    /// Same as `load_checkpoint`, but for state written by `save_toml` (and possibly edited
+    /// afterwards). The same validation advice applies.
+    pub fn load_toml<R: Read>(r: R) -> io::Result<Self> {
+        TransactionEngine::from_checkpoint(config::read_toml(r)?)
+    }
+
+    /// Returns a copy of the current state of the engine, i.e. to process the next input (such
+    /// as the following day's file) on top of it via `restore`. Same as for checkpoints,
+    /// settled transactions, deferred disputes and quarantined transactions are not part of
+    /// the snapshot.
+    pub fn snapshot(&self) -> Snapshot {
+        let checkpoint = self.checkpoint();
+        let accounts = checkpoint
+            .accounts
+            .into_iter()
+            .map(|state| AccountState {
+                client: state.client,
+                account: state.account.clone(),
+            })
+            .collect();
+
+        Snapshot(Checkpoint {
+            disputed: checkpoint.disputed,
+            accounts,
+            transactions: checkpoint.transactions,
+            processed: checkpoint.processed,
+            partial_disputes: checkpoint.partial_disputes,
+            chargebacks: checkpoint.chargebacks,
+            holds: checkpoint.holds,
+            voided: checkpoint.voided,
+            seen: checkpoint.seen,
+        })
+    }
+
+    /// Creates an engine based on a snapshot previously returned by `snapshot` (or
+    /// deserialized from a stored one). The same validation advice as for `load_checkpoint`
+    /// applies.
+    pub fn restore(snapshot: Snapshot) -> io::Result<Self> {
+        TransactionEngine::from_checkpoint(snapshot.0)
+    }
+
+    /// Starts a what-if scenario on top of the current state, i.e. to see what the balances
+    /// would be if a chargeback landed, without changing them (see `scenario::Fork`). The
+    /// transactions processed by the scenario only get applied to this engine once it's
+    /// committed.
+    pub fn fork(&mut self) -> Fork<'_> {
+        let scenario = self.forked();
+        Fork::new(self, scenario)
+    }
+
+    // An engine with the same configuration and state, but with its history in memory, and
+    // none of the hooks which reach outside of it.
+    fn forked(&self) -> TransactionEngine {
+        let mut ledger = MemoryLedger::default();
+        ledger.reserve(self.history_order.len());
+        ledger.extend(self.stored_transactions());
+        // Without an event log to take them, the accounts don't record their events.
+        let mut client_accounts = self.client_accounts.clone();
+        for account in client_accounts.values_mut() {
+            account.set_recording(false);
+        }
+        TransactionEngine {
+            client_accounts,
+            ledger: Box::new(ledger),
+            config: self.config.clone(),
+            deferred_disputes: self.deferred_disputes.clone(),
+            quarantine: self.quarantine.clone(),
+            chargebacks: self.chargebacks.clone(),
+            holds: self.holds.clone(),
+            next_hold_expiry: self.next_hold_expiry,
+            voided: self.voided.clone(),
+            seen: self.seen.clone(),
+            open_disputes: self.open_disputes.clone(),
+            next_seq: self.next_seq,
+            latest_timestamp: self.latest_timestamp,
+            history_order: self.history_order.clone(),
+            processed: self.processed.clone(),
+            ..TransactionEngine::new()
+        }
+    }
+
+    /// Combines the state of `other` into this engine, i.e. of engines which processed the
+    /// files of different regions, or partitions of the same input. Accounts of clients which
+    /// only one of them knows are taken over as they are, while the funds of the accounts
+    /// both have are added up per currency (along with their open disputes), and the account
+    /// keeps the more restrictive status. The transactions retained by `other`, and its
+    /// chargebacks, holds, voided transactions, deferred disputes and quarantined
+    /// transactions, are added on top of the ones of this engine, after them in the history
+    /// (and the quarantine). The settings of this engine stay as
+    /// they are.
+    ///
+    /// Transaction ids have to be distinct across the two engines. If a transaction id is
+    /// known to both (as far as they retain their transactions, same as for
+    /// `Error::DuplicateTransaction` while processing), the merge is rejected with that
+    /// error for the lowest such id, and nothing changes.
+    pub fn merge(&mut self, mut other: TransactionEngine) -> Result<()> {
+        let mut incoming = other
+            .ledger
+            .transactions()
+            .collect::<io::Result<Vec<_>>>()
+            .map_err(|e| other.storage_failed(e))?;
+        let known = |tx: &TransactionId| {
+            self.is_stored(*tx)
+                || self.seen.contains(tx)
+                || self.chargebacks.contains_key(tx)
+                || self.holds.contains_key(tx)
+                || self.voided.contains_key(tx)
+                || self.deferred_disputes.contains_key(tx)
+        };
+        let conflict = incoming
+            .iter()
+            .map(|t| t.tx)
+            .chain(other.chargebacks.keys().copied())
+            .chain(other.holds.keys().copied())
+            .chain(other.voided.keys().copied())
+            .chain(other.seen.iter().copied())
+            .chain(other.deferred_disputes.keys().copied())
+            .filter(known)
+            .min();
+        if let Some(tx) = conflict {
+            return Err(Error::DuplicateTransaction { tx });
+        }
+
+        let mut clients: Vec<_> = other.client_accounts.keys().copied().collect();
+        clients.sort_unstable();
+        for (client, account) in other.client_accounts.drain() {
+            self.account_mut(client).absorb(account);
+            self.store_account(client);
+        }
+
+        // Renumbered to come after the transactions of this engine, in the same order.
+        incoming.sort_unstable_by_key(|t| t.seq);
+        for mut t in incoming {
+            t.seq = self.next_seq;
+            self.next_seq += 1;
+            self.store_transaction(t);
+        }
+        self.recount_open_disputes();
+        self.chargebacks.extend(other.chargebacks.drain());
+        for (_, t) in other.holds.drain() {
+            self.insert_hold(t);
+        }
+        self.voided.extend(other.voided.drain());
+        self.seen.extend(other.seen.drain());
+        self.deferred_disputes
+            .extend(other.deferred_disputes.drain());
+        self.quarantine.append(&mut other.quarantine);
+        self.processed.extend(other.processed.drain());
+        self.latest_timestamp = self.latest_timestamp.max(other.latest_timestamp);
+        self.warnings.append(&mut other.warnings);
+        self.payouts.append(&mut other.payouts);
+        if let (Some(metrics), Some(other_metrics)) = (&mut self.metrics, other.metrics) {
+            metrics.merge(other_metrics);
+        }
+        // The merged accounts are recorded as changes of their own.
+        self.flush_events((None, None), &clients);
+        if self.history_bounded() {
+            self.rebuild_history_order();
+        }
+
+        Ok(())
+    }
+
+    // Adjusts the settings for one of `stripes` engines which split the clients among them
+    // (see `SharedTransactionEngine`), the same way as for the shards of `process_parallel`.
+    pub(crate) fn into_stripe(mut self, stripes: usize) -> Self {
+        if stripes > 1 {
+            self.config.transfers_disabled = true;
+            self.config.history_limit = self.config.history_limit.map(|n| n.div_ceil(stripes));
+            if self.config.history_limit.is_some() {
+                self.evict_history();
+            }
+        }
+        self
+    }
+
+    /// Sets up a write-ahead log at `wal_path`, where every accepted transaction gets
+    /// journaled before it's applied, so the state can be rebuilt after a crash by calling
+    /// `recover` again with the same path. To keep recovery short, the state is snapshotted
+    /// every so often (see `with_wal_snapshot_interval`), and the log starts over.
+    ///
+    /// When there's already a snapshot, it replaces the current state (i.e. from a loaded
+    /// checkpoint), and the transactions journaled since are replayed on top. These are
+    /// replayed with the current settings (and run through the transaction callback), so this
+    /// has to be called once the engine has been configured the same way as before. Neither
+    /// deferred disputes nor accounts which only got created for rejected transactions (see
+    /// `with_track_all_clients`) are recovered, and `process_parallel` bypasses the log.
+    /// Recovery fails with `io::ErrorKind::InvalidData` if a journaled transaction gets
+    /// rejected on replay (i.e. because of different settings).
+    pub fn recover<P: AsRef<Path>>(mut self, wal_path: P) -> io::Result<Self> {
+        let mut wal = Wal::open(wal_path.as_ref(), self.config.wal_sync)?;
+
+        if let Some(checkpoint) = wal.load_snapshot()? {
+            self.reset()?;
+            self.load_state(checkpoint)?;
+            self.start_event_log();
+        }
+        // The warnings about the replayed transactions have been raised when they were first
+        // processed, so only the ones from before are kept.
+        let warnings = self.warnings.len();
+        wal.replay(|transaction| {
+            // Rejections have been discarded from the log, so one on replay means that the
+            // engine isn't configured the way it was when the transaction got journaled.
+            let tx = transaction.tx;
+            self.process_transaction(transaction).map_err(|e| {
+                let message = format!(
+                    "Unable to replay transaction {} of the write-ahead log: {}",
+                    tx, e
+                );
+                io::Error::new(io::ErrorKind::InvalidData, message)
+            })
+        })?;
+        self.warnings.truncate(warnings);
+
+        self.wal = Some(wal);
+        Ok(self)
+    }
+
+    /// Snapshots the state right away, and empties the write-ahead log set up via `recover`
+    /// (if any), which keeps the next recovery short regardless of the snapshot interval.
+    pub fn compact_wal(&mut self) -> io::Result<()> {
+        if let Some(mut wal) = self.wal.take() {
+            let result = wal.write_snapshot(&self.checkpoint());
+            self.wal = Some(wal);
+            result?;
+        }
+        Ok(())
+    }
+
+    // Appends `transaction` to the write-ahead log (if any), ahead of applying it.
+    fn journal(&mut self, transaction: &Transaction) -> Result<()> {
+        match &mut self.wal {
+            Some(wal) => wal
+                .append(transaction)
+                .map_err(|e| Error::WalWriteFailed { kind: e.kind() }),
+            None => Ok(()),
+        }
+    }
+
+    // Keeps or discards the journal entry of the transaction `tx` that has just been
+    // processed, and snapshots the state when due. Failures don't affect the outcome of the
+    // transaction, so they're only reported (see `Warning::WalMaintenanceFailed`).
+    fn settle_wal_entry(&mut self, tx: TransactionId, accepted: bool) {
+        if let Some(mut wal) = self.wal.take() {
+            let result = if !accepted {
+                wal.discard_last()
+            } else if wal.snapshot_due(self.config.wal_snapshot_interval) {
+                wal.write_snapshot(&self.checkpoint())
+            } else {
+                Ok(())
+            };
+            self.wal = Some(wal);
+            if let Err(e) = result {
+                warn!(tx = tx.get(), "Unable to update the write-ahead log: {}", e);
+                self.warnings
+                    .push(Warning::WalMaintenanceFailed { tx, kind: e.kind() });
+            }
+        }
+    }
+
+    fn checkpoint(&self) -> Checkpoint<&Account, Transaction> {
+        let mut accounts: Vec<_> = self
+            .client_accounts
+            .iter()
+            .map(|(&client, account)| AccountState { client, account })
+            .collect();
+        accounts.sort_unstable_by_key(|a| a.client);
+
+        let transactions = self.retained_transactions();
+
+        let disputed = transactions
+            .iter()
+            .filter(|t| t.disputed)
+            .map(|t| t.tx)
+            .collect();
+
+        let partial_disputes = transactions
+            .iter()
+            .filter(|t| {
+                !t.settled.is_zero()
+                    || (t.disputed && t.held != t.amount())
+                    || t.disputes > u32::from(t.disputed)
+            })
+            .map(|t| PartialDispute {
+                tx: t.tx,
+                held: t.held,
+                settled: t.settled,
+                disputes: Some(t.disputes),
+            })
+            .collect();
+
+        let mut processed: Vec<_> = self.processed.iter().copied().collect();
+        processed.sort_unstable_by_key(|p| (p.client, p.tx, p.type_ as u8));
+
+        let mut chargebacks: Vec<_> = self.chargebacks.values().cloned().collect();
+        chargebacks.sort_unstable_by_key(|t| t.tx);
+
+        let mut holds: Vec<_> = self.holds.values().cloned().collect();
+        holds.sort_unstable_by_key(|t| t.tx);
+
+        let mut voided: Vec<_> = self.voided.values().cloned().collect();
+        voided.sort_unstable_by_key(|t| t.tx);
+
+        // The ids of the retained transactions get recorded again when they're loaded.
+        let retained: HashSet<_> = transactions.iter().map(|t| t.tx).collect();
+        let mut seen: Vec<_> = self
+            .seen
+            .iter()
+            .copied()
+            .filter(|tx| !retained.contains(tx))
+            .collect();
+        seen.sort_unstable();
+
+        Checkpoint {
+            disputed,
+            accounts,
+            transactions,
+            processed,
+            partial_disputes,
+            chargebacks,
+            holds,
+            voided,
+            seen,
+        }
+    }
+
+    fn from_checkpoint(checkpoint: OwnedCheckpoint) -> io::Result<Self> {
+        let mut engine = TransactionEngine::new();
+        engine.load_state(checkpoint)?;
+        Ok(engine)
+    }
+
+    // Adds the accounts and transactions of `checkpoint` to the (empty) engine state.
+    fn load_state(&mut self, checkpoint: OwnedCheckpoint) -> io::Result<()> {
+        for state in checkpoint.accounts {
+            self.client_accounts.insert(state.client, state.account);
+        }
+
+        for transaction in checkpoint.transactions {
+            self.record_transaction(transaction);
+        }
+
+        for id in checkpoint.disputed {
+            let mut t = self.ledger.transaction(id)?.ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Disputed transaction {} is missing from the checkpoint", id),
+                )
+            })?;
+            t.disputed = true;
+            t.held = t.amount();
+            t.disputes = 1;
+            self.store_transaction(t);
+        }
+
+        for partial in checkpoint.partial_disputes {
+            let mut t = self.ledger.transaction(partial.tx)?.ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "Partially disputed transaction {} is missing from the checkpoint",
+                        partial.tx
+                    ),
+                )
+            })?;
+            t.held = partial.held;
+            t.settled = partial.settled;
+            if let Some(disputes) = partial.disputes {
+                t.disputes = disputes;
+            }
+            self.store_transaction(t);
+        }
+
+        for t in checkpoint.chargebacks {
+            self.chargebacks.insert(t.tx, t);
+        }
+        for t in checkpoint.holds {
+            self.insert_hold(t);
+        }
+        for t in checkpoint.voided {
+            self.voided.insert(t.tx, t);
+        }
+        self.seen.extend(checkpoint.seen);
+        self.processed.extend(checkpoint.processed);
+        // Unknown clients are caught by `is_valid` later on.
+        self.recount_open_disputes();
+        Ok(())
+    }
+
+    // Sets the number of open disputes of every account to the one of its transactions under
+    // dispute, for accounts which come from elsewhere (i.e. checkpoints, where the disputed
+    // transactions may be listed more than once, or another engine), so there's always an
+    // open dispute to settle once a disputed transaction gets resolved or charged back.
+    fn recount_open_disputes(&mut self) {
+        for account in self.client_accounts.values_mut() {
+            account.clear_open_disputes();
+        }
+        for dispute in self.open_disputes.values() {
+            if let Some(account) = self.client_accounts.get_mut(&dispute.client) {
+                account.open_dispute();
+            }
+        }
+    }
+
+    /// Discards all accounts and transactions, so the engine can be reused for an independent
+    /// batch of input. The configuration is retained, and so is the capacity allocated for
+    /// the accounts and the (in-memory) transaction history. Persistent ledgers get cleared as
+    /// well, and so does the write-ahead log (if any), which fails if the empty state can't be
+    /// snapshotted.
+    pub fn reset(&mut self) -> io::Result<()> {
+        self.client_accounts.clear();
+        self.ledger.clear()?;
+        self.storage_failure.set(None);
+        self.deferred_disputes.clear();
+        self.quarantine.clear();
+        self.chargebacks.clear();
+        self.holds.clear();
+        self.next_hold_expiry = None;
+        self.voided.clear();
+        self.seen.clear();
+        self.open_disputes.clear();
+        self.warnings.clear();
+        self.payouts.clear();
+        self.latest_timestamp = None;
+        self.history_order.clear();
+        self.processed.clear();
+        for validator in self.validators.iter_mut() {
+            validator.reset();
+        }
+        if let Some(risk) = &mut self.risk {
+            risk.clear();
+        }
+        if let Some(log) = &mut self.event_log {
+            *log = EventLog::default();
+        }
+        if let Some(lineage) = &mut self.lineage {
+            *lineage = Lineage::default();
+        }
+        self.next_seq = 0;
+
+        // The journaled transactions must not be replayed on top of the new batch.
+        self.compact_wal()
+    }
+
+    /// Checks the engine state for inconsistencies that cannot arise from processing any
+    /// sequence of transactions (i.e. as a result of loading corrupted data). On top of
+    /// every account passing `Account::is_valid`, the rules are:
+    /// - Every retained transaction belongs to a known client account.
+    /// - Only transactions which are disputable according to the dispute policy can be under
+    ///   dispute (by default, deposits, and withdrawals when enabled via
+    ///   `with_withdrawal_disputes`).
+    /// - The disputed and resolved portions of a transaction are never negative, and add up to
+    ///   no more than its amount (with nothing held unless it's under dispute).
+    /// - The funds held by an account in each currency add up to the disputed amounts of its
+    ///   transactions in that currency.
+    /// - Every chargeback which can still be reversed belongs to a known client account, and
+    ///   has a positive amount.
+    pub fn is_valid(&self) -> bool {
+        let transactions: Vec<_> = self.stored_transactions().collect();
+        let policy = self.dispute_policy();
+        let mut disputed_amounts = HashMap::new();
+
+        for t in transactions.iter() {
+            if !self.client_accounts.contains_key(&t.client)
+                || !self.client_accounts.contains_key(&t.holder())
+            {
+                return false;
+            }
+
+            if t.held.is_sign_negative()
+                || t.settled.is_sign_negative()
+                || t.disputable_amount().is_sign_negative()
+                || (!t.disputed && !t.held.is_zero())
+            {
+                return false;
+            }
+
+            if t.disputed {
+                if !policy.is_disputable(t) {
+                    return false;
+                }
+                *disputed_amounts
+                    .entry((t.holder(), t.currency()))
+                    .or_insert(Decimal::ZERO) += t.held;
+            }
+        }
+
+        // Holds count towards the held funds, same as disputes, and so do the held funds of
+        // opening balances.
+        for t in self.holds.values() {
+            if !self.client_accounts.contains_key(&t.client) || t.amount().is_sign_negative() {
+                return false;
+            }
+            *disputed_amounts
+                .entry((t.client, t.currency()))
+                .or_insert(Decimal::ZERO) += t.amount();
+        }
+        for (&client, account) in self.client_accounts.iter() {
+            for (currency, held) in account.carried_held_all() {
+                *disputed_amounts
+                    .entry((client, currency))
+                    .or_insert(Decimal::ZERO) += held;
+            }
+        }
+
+        let chargebacks_known = self.chargebacks.values().all(|t| {
+            self.client_accounts.contains_key(&t.client)
+                && self.client_accounts.contains_key(&t.holder())
+                && t.amount() > Decimal::ZERO
+        });
+        if !chargebacks_known {
+            return false;
+        }
+
+        // Checking both ways round, so amounts disputed in a currency the account holds no
+        // balance for are caught as well.
+        let disputes_held = disputed_amounts
+            .iter()
+            .all(|(&(client, currency), &disputed)| {
+                let held = self.client_accounts[&client].balance(currency).held();
+                amounts_equal(held, disputed, self.config.amount_epsilon)
+            });
+
+        disputes_held
+            && self.client_accounts.iter().all(|(client, account)| {
+                account.is_valid()
+                    && account.balances().all(|(currency, balance)| {
+                        let disputed = disputed_amounts
+                            .get(&(*client, currency))
+                            .copied()
+                            .unwrap_or_default();
+                        amounts_equal(balance.held(), disputed, self.config.amount_epsilon)
+                    })
+            })
+    }
+
+    /// Returns the account of `client`, if there is one.
+    pub fn account(&self, client: ClientId) -> Option<&Account> {
+        self.client_accounts.get(&client)
+    }
+
+    /// Iterates over all client accounts, in no particular order.
+    pub fn accounts(&self) -> impl Iterator<Item = (ClientId, &Account)> {
+        self.client_accounts
+            .iter()
+            .map(|(client, account)| (*client, account))
+    }
+
+    /// The views of all client accounts (see `Account::view`), ordered by client id, i.e. to
+    /// serialize the results as a whole.
+    pub fn account_views(&self) -> Vec<AccountView<'_>> {
+        let mut views: Vec<_> = self
+            .accounts()
+            .map(|(client, account)| account.view(client))
+            .collect();
+        views.sort_unstable_by_key(|view| view.client);
+        views
+    }
+
+    /// The number of client accounts.
+    pub fn len(&self) -> usize {
+        self.client_accounts.len()
+    }
+
+    /// Whether there are no client accounts (yet).
+    pub fn is_empty(&self) -> bool {
+        self.client_accounts.is_empty()
+    }
+
+    /// Reports every balance whose stored total isn't the sum of its available and held funds
+    /// (ordered by client and currency), i.e. to catch engine bugs, or corrupted state after
+    /// loading a checkpoint. Such accounts also fail `is_valid`.
+    pub fn audit(&self) -> Vec<TotalMismatch> {
+        let mut mismatches: Vec<_> = self
+            .client_accounts
+            .iter()
+            .flat_map(|(&client, account)| {
+                account
+                    .balances()
+                    .filter(|(_, balance)| !balance.is_consistent())
+                    .map(move |(currency, balance)| TotalMismatch {
+                        client,
+                        currency: currency.to_owned(),
+                        available: balance.available(),
+                        held: balance.held(),
+                        total: balance.total(),
+                    })
+            })
+            .collect();
+        mismatches.sort_unstable_by(|a, b| (a.client, &a.currency).cmp(&(b.client, &b.currency)));
+        mismatches
+    }
+
+    /// Scans the accounts for values which no sequence of transactions leads to (see
+    /// `repair::Anomaly`), ordered by client, which `repair` can correct.
+    pub fn anomalies(&self) -> Vec<Anomaly> {
+        let mut clients: Vec<_> = self.client_accounts.keys().copied().collect();
+        clients.sort_unstable();
+
+        let mut anomalies = Vec::new();
+        for client in clients {
+            let account = &self.client_accounts[&client];
+            if account.frozen() && account.open_disputes() > 0 {
+                anomalies.push(Anomaly::FrozenWithOpenDisputes {
+                    client,
+                    disputes: self.open_disputes_of(client),
+                });
+            }
+
+            let mut balances: Vec<_> = account.balances().collect();
+            balances.sort_unstable_by_key(|&(currency, _)| currency);
+            for (currency, balance) in balances {
+                if balance.held().is_sign_negative() {
+                    anomalies.push(Anomaly::NegativeHeld {
+                        client,
+                        currency: currency.to_owned(),
+                        held: balance.held(),
+                    });
+                }
+                if !balance.is_consistent() {
+                    anomalies.push(Anomaly::TotalMismatch {
+                        client,
+                        currency: currency.to_owned(),
+                        available: balance.available(),
+                        held: balance.held(),
+                        total: balance.total(),
+                    });
+                }
+            }
+        }
+        anomalies
+    }
+
+    /// Corrects the anomalies reported by `anomalies`, via the adjustments documented on
+    /// `repair::Correction` (in that order), and returns every change made to an account.
+    /// This bypasses the usual checks (i.e. resolves go through regardless of the frozen
+    /// policy), and nothing gets journaled, so it's only meant for repairing a loaded state
+    /// before saving it again.
+    pub fn repair(&mut self) -> Vec<Adjustment> {
+        let mut adjustments = Vec::new();
+        let mut clients: Vec<_> = self.client_accounts.keys().copied().collect();
+        clients.sort_unstable();
+
+        for &client in clients.iter() {
+            let account = &self.client_accounts[&client];
+            if !account.frozen() || account.open_disputes() == 0 {
+                continue;
+            }
+            for tx in self.open_disputes_of(client) {
+                if let Some(adjustment) = self.force_resolve(tx) {
+                    adjustments.push(adjustment);
+                }
+            }
+
+            // Whatever is still counted doesn't refer to any retained transaction.
+            let account = self.client_accounts.get_mut(&client).unwrap();
+            if account.open_disputes() > 0 {
+                while account.open_disputes() > 0 {
+                    account.settle_dispute();
+                }
+                adjustments.push(Adjustment {
+                    client,
+                    currency: Currency::new(),
+                    correction: Correction::ClearDisputes,
+                    tx: None,
+                    available: Decimal::ZERO,
+                    held: Decimal::ZERO,
+                    total: Decimal::ZERO,
+                });
+            }
+            self.store_account(client);
+        }
+
+        let expected = self.expected_held();
+        for &client in clients.iter() {
+            let account = self.client_accounts.get_mut(&client).unwrap();
+            let currencies: Vec<Currency> = account
+                .balances()
+                .filter(|(_, balance)| balance.held().is_sign_negative())
+                .map(|(currency, _)| currency.to_owned())
+                .collect();
+            for currency in currencies {
+                let key = (client, currency.clone());
+                let held = expected.get(&key).copied().unwrap_or_default();
+                let difference = held - account.balance(&currency).held();
+                account.balance_mut(&currency).hold(difference);
+                adjustments.push(Adjustment {
+                    client,
+                    currency,
+                    correction: Correction::RestoreHeld,
+                    tx: None,
+                    available: -difference,
+                    held: difference,
+                    total: Decimal::ZERO,
+                });
+            }
+
+            for (currency, change) in account.recompute_totals() {
+                adjustments.push(Adjustment {
+                    client,
+                    currency,
+                    correction: Correction::RecomputeTotal,
+                    tx: None,
+                    available: Decimal::ZERO,
+                    held: Decimal::ZERO,
+                    total: change,
+                });
+            }
+            self.store_account(client);
+        }
+
+        adjustments.sort_by_key(|a| a.client);
+        adjustments
+    }
+
+    // The ids of the open disputes of the funds of `client`, in order.
+    fn open_disputes_of(&self, client: ClientId) -> Vec<TransactionId> {
+        let mut disputes: Vec<_> = self
+            .open_disputes
+            .values()
+            .filter(|d| d.client == client)
+            .map(|d| d.tx)
+            .collect();
+        disputes.sort_unstable();
+        disputes
+    }
+
+    // The funds each balance should be holding, per the open disputes and holds.
+    fn expected_held(&self) -> HashMap<(ClientId, Currency), Decimal> {
+        let mut expected = HashMap::new();
+        for dispute in self.open_disputes.values() {
+            *expected
+                .entry((dispute.client, dispute.currency.clone()))
+                .or_insert(Decimal::ZERO) += dispute.amount;
+        }
+        for hold in self.holds.values() {
+            *expected
+                .entry((hold.client, hold.currency().to_owned()))
+                .or_insert(Decimal::ZERO) += hold.amount();
+        }
+        for (&client, account) in self.client_accounts.iter() {
+            for (currency, held) in account.carried_held_all() {
+                *expected
+                    .entry((client, currency.to_owned()))
+                    .or_insert(Decimal::ZERO) += held;
+            }
+        }
+        expected
+    }
+
+    // Resolves the open dispute of `tx` in full, same as `process_resolve` but without any of
+    // its checks, for `repair`.
+    fn force_resolve(&mut self, tx: TransactionId) -> Option<Adjustment> {
+        let mut t = self.stored_transaction(tx)?;
+        let client = t.holder();
+        let redispute = self.dispute_policy().allows_redispute(&t);
+        let record_history = self.config.record_history;
+        let account = self.client_accounts.get_mut(&client)?;
+
+        let amount = t.held;
+        let returned = t.released_on(Type::Resolve, amount);
+        account
+            .balance_mut(t.currency())
+            .settle_held(amount, returned);
+        if account.open_disputes() > 0 {
+            account.settle_dispute();
+        }
+        account.record_resolved(t.currency(), amount);
+        if record_history {
+            account.record_op(Type::Resolve, tx, amount, t.currency());
+        }
+
+        t.held = Decimal::ZERO;
+        t.disputed = false;
+        if !redispute {
+            t.settled += amount;
+        }
+        let adjustment = Adjustment {
+            client,
+            currency: t.currency().to_owned(),
+            correction: Correction::ResolveDispute,
+            tx: Some(tx),
+            available: returned,
+            held: -amount,
+            total: returned - amount,
+        };
+        if redispute || !t.disputable_amount().is_zero() {
+            self.store_transaction(t);
+        } else {
+            self.remove_transaction(tx);
+        }
+        Some(adjustment)
+    }
+
+    /// Computes the firm-wide totals across every account, in a single pass.
+    pub fn aggregate(&self) -> Aggregate {
+        let mut aggregate = Aggregate::default();
+
+        for account in self.client_accounts.values() {
+            aggregate.accounts += 1;
+            if account.frozen() {
+                aggregate.frozen_accounts += 1;
+            }
+
+            for (currency, balance) in account.balances() {
+                let totals = aggregate.totals.entry(currency.to_owned()).or_default();
+                totals.available += balance.available();
+                totals.held += balance.held();
+                totals.total += balance.total();
+            }
+        }
+
+        aggregate
+    }
+
+    /// Compares the accounts with those of `other` (see `diff`), i.e. the state of an engine
+    /// processing the same input with different settings, or from a different version.
+    pub fn diff(&self, other: &TransactionEngine) -> Vec<Discrepancy> {
+        diff::compare(&diff::accounts_of(self), &diff::accounts_of(other))
+    }
+
+    /// Returns the statement of the transactions currently retained for `client`, in the order
+    /// they were processed, or `None` if there's no such client account.
+    pub fn statement(&self, client: ClientId) -> Option<Statement> {
+        let account = self.client_accounts.get(&client)?;
+        let transactions: Vec<_> = self.transactions_for(client).collect();
+
+        Some(Statement::new(
+            client,
+            account,
+            transactions.iter().collect(),
+        ))
+    }
+
+    /// Iterates over the deposits and withdrawals currently retained for `client`, in the
+    /// order they were processed. Transactions are handed out as copies (see `Ledger`), and
+    /// those whose dispute has been settled are no longer retained.
+    pub fn transactions_for(&self, client: ClientId) -> impl Iterator<Item = Transaction> {
+        let mut transactions: Vec<_> = self
+            .stored_transactions()
+            .filter(|t| t.client == client || t.holder() == client)
+            .collect();
+        transactions.sort_unstable_by_key(|t| t.seq);
+        transactions.into_iter()
+    }
+
+    /// The transaction `tx` (as a copy), unless it's no longer retained (see
+    /// `transactions_for`).
+    pub fn transaction(&self, tx: TransactionId) -> Option<Transaction> {
+        self.stored_transaction(tx)
+    }
+
+    /// The retained transactions (see `transactions_for`) with the external reference
+    /// `reference`, in the order they were processed, i.e. to match them up with the entries
+    /// of a bank statement. References aren't required to be unique, and this scans the
+    /// whole history.
+    pub fn find_by_reference(&self, reference: &str) -> Vec<Transaction> {
+        let mut transactions: Vec<_> = self
+            .stored_transactions()
+            .filter(|t| t.reference() == Some(reference))
+            .collect();
+        transactions.sort_unstable_by_key(|t| t.seq);
+        transactions
+    }
+
+    // The transactions retained in the history, in the order they were added.
+    pub(crate) fn retained_transactions(&self) -> Vec<Transaction> {
+        let mut transactions: Vec<_> = self.stored_transactions().collect();
+        transactions.sort_unstable_by_key(|t| t.seq);
+        transactions
+    }
+
+    /// Lists the disputes which are still open, ordered by client and transaction id. These
+    /// come from an index kept alongside the history, so this doesn't scan the history.
+    pub fn open_disputes(&self) -> Vec<&DisputeRecord> {
+        let mut disputes: Vec<_> = self.open_disputes.values().collect();
+        disputes.sort_unstable_by_key(|d| (d.client, d.tx));
+        disputes
+    }
+
+    /// The funds of `client` held under open disputes, which is what the client stands to
+    /// lose to chargebacks. Amounts in different currencies are added up as they are, so this
+    /// is only meaningful per currency for multi-asset inputs (see `open_disputes`).
+    pub fn disputed_amount(&self, client: ClientId) -> Decimal {
+        self.open_disputes
+            .values()
+            .filter(|d| d.client == client)
+            .map(|d| d.amount)
+            .sum()
+    }
+
+    /// The events recorded so far (see `with_event_log`), in order.
+    pub fn events(&self) -> &[EventRecord] {
+        self.event_log.as_ref().map_or(&[], EventLog::records)
+    }
+
+    /// The lineage recorded so far, if enabled via `with_lineage`.
+    pub fn lineage(&self) -> Option<&Lineage> {
+        self.lineage.as_ref()
+    }
+
+    /// The events recorded so far for the account of `client`, in order.
+    pub fn events_for(&self, client: ClientId) -> Vec<&EventRecord> {
+        self.events()
+            .iter()
+            .filter(|record| record.client == client)
+            .collect()
+    }
+
+    /// Replaces the accounts with the ones derived from the event log (see `with_event_log`),
+    /// which should give the same accounts unless the log is missing changes. The open
+    /// disputes are counted from the history, while account histories start over. Does
+    /// nothing unless the event log is enabled.
+    pub fn rebuild_from_events(&mut self) {
+        let accounts = match &self.event_log {
+            Some(log) => events::fold(log.records()),
+            None => return,
+        };
+
+        self.client_accounts.clear();
+        self.client_accounts.extend(accounts);
+        for account in self.client_accounts.values_mut() {
+            account.set_recording(true);
+        }
+        self.recount_open_disputes();
+        let clients: Vec<_> = self.client_accounts.keys().copied().collect();
+        for client in clients {
+            self.store_account(client);
+        }
+    }
+
+    /// The account of `client` as it was at `as_of`, with its balance in every currency. With
+    /// the event log enabled (see `with_event_log`), the account is derived from the events
+    /// up to that point, which is exact. Otherwise, the account comes from replaying the
+    /// retained history up to that point, as described for `as_of`, which leaves out fees,
+    /// holds, and disputes. Fails if there's no such transaction as given by `AsOf::Before`,
+    /// if the client had no account yet, or if the replay fails.
+    pub fn balance_at(&self, client: ClientId, as_of: AsOf) -> Result<Account> {
+        let records = match &self.event_log {
+            Some(log) => log.records(),
+            None => {
+                return self
+                    .as_of(as_of)?
+                    .client_accounts
+                    .remove(&client)
+                    .ok_or(Error::AccountNotFound { client })
+            }
+        };
+
+        let end = match as_of {
+            AsOf::Before(tx) => records
+                .iter()
+                .position(|r| r.tx == Some(tx))
+                .ok_or(Error::TransactionNotFound { tx })?,
+            AsOf::Time(time) => records
+                .iter()
+                .position(|r| r.timestamp.is_some_and(|t| t > time))
+                .unwrap_or(records.len()),
+        };
+        events::fold(records[..end].iter().filter(|r| r.client == client))
+            .remove(&client)
+            .ok_or(Error::AccountNotFound { client })
+    }
+
+    /// Returns an engine (with the same configuration) with the state as of `as_of`, from
+    /// replaying the deposits, withdrawals, and transfers of the retained history up to that
+    /// point. Settled and charged back transactions are gone from the history (unless the
+    /// history keeps them for redisputes), so they're missing from the replay, as are the
+    /// disputes and the changes to the status of accounts: the funds of the replayed
+    /// transactions are all available, and the accounts are active. Neither fees nor holds
+    /// and voids are replayed, so withdrawals only take their amounts, and no funds are held.
+    /// Fails if there's no such retained transaction as given by `AsOf::Before`, if the funds
+    /// of an account exceed the range of `Decimal` along the way, or if the available funds
+    /// (down to the balance limits of the client) don't cover a replayed withdrawal or
+    /// transfer, i.e. because the deposit which covered it has been charged back since.
+    pub fn as_of(&self, as_of: AsOf) -> Result<TransactionEngine> {
+        let mut transactions = self.retained_transactions();
+        let end = match as_of {
+            AsOf::Before(tx) => transactions
+                .iter()
+                .position(|t| t.tx == tx)
+                .ok_or(Error::TransactionNotFound { tx })?,
+            AsOf::Time(time) => transactions
+                .iter()
+                .position(|t| t.timestamp.is_some_and(|ts| ts > time))
+                .unwrap_or(transactions.len()),
+        };
+        transactions.truncate(end);
+
+        let mut engine = TransactionEngine::new();
+        engine.config = self.config.clone();
+        for t in transactions {
+            let (amount, currency) = (t.amount(), t.currency());
+            match (t.type_, t.to) {
+                (Type::Deposit, _) => {
+                    engine
+                        .account_mut(t.client)
+                        .balance_mut(currency)
+                        .try_increase_available(t.client, amount)?;
+                }
+                (Type::Withdrawal, _) => {
+                    let floor = self.balance_limits(t.client).floor();
+                    engine
+                        .account_mut(t.client)
+                        .balance_mut(currency)
+                        .try_decrease_available_to(t.client, amount, floor)?;
+                }
+                (Type::Transfer, Some(to)) => {
+                    let floor = self.balance_limits(t.client).floor();
+                    engine
+                        .account_mut(t.client)
+                        .balance_mut(currency)
+                        .try_decrease_available_to(t.client, amount, floor)?;
+                    engine
+                        .account_mut(to)
+                        .balance_mut(currency)
+                        .try_increase_available(to, amount)?;
+                }
+                _ => {}
+            }
+            engine.record_transaction(Transaction {
+                disputed: false,
+                held: Decimal::ZERO,
+                settled: Decimal::ZERO,
+                disputes: 0,
+                ..t
+            });
+        }
+        Ok(engine)
+    }
+
+    // Moves the events recorded by the accounts of `clients` into the event log (see
+    // `with_event_log`), as part of processing the transaction with the given id and
+    // timestamp (if any).
+    fn flush_events(&mut self, cause: (Option<TransactionId>, Option<u64>), clients: &[ClientId]) {
+        if let Some(log) = &mut self.event_log {
+            for client in clients {
+                if let Some(account) = self.client_accounts.get_mut(client) {
+                    log.record(*client, cause, account.take_events());
+                }
+            }
+        }
+    }
+
+    // Starts the event log (if enabled) over with the events which open the accounts as they
+    // are, ordered by client, and has the accounts record their events from then on.
+    fn start_event_log(&mut self) {
+        let enabled = self.event_log.is_some();
+        for account in self.client_accounts.values_mut() {
+            account.set_recording(enabled);
+        }
+        if let Some(log) = &mut self.event_log {
+            *log = EventLog::default();
+            let mut clients: Vec<_> = self.client_accounts.keys().copied().collect();
+            clients.sort_unstable();
+            for client in clients {
+                let events = self.client_accounts[&client].opening_events();
+                log.record(client, (None, None), events);
+            }
+        }
+    }
+
+    // The clients whose accounts processing `transaction` may change: the parties of the
+    // transaction, or those of the transaction it refers to.
+    fn affected_clients(&self, transaction: &Transaction) -> Vec<ClientId> {
+        let reference = match transaction.type_ {
+            Type::Dispute | Type::Resolve | Type::Chargeback => {
+                self.stored_transaction(transaction.tx)
+            }
+            Type::ChargebackReversal => self.chargebacks.get(&transaction.tx).cloned(),
+            _ => None,
+        };
+        let mut clients = vec![transaction.client];
+        clients.extend(transaction.to);
+        if let Some(t) = reference {
+            clients.push(t.client);
+            clients.extend(t.to);
+        }
+        clients.sort_unstable();
+        clients.dedup();
+        clients
+    }
+
+    /// The metrics collected so far, if enabled via `with_metrics`.
+    pub fn metrics(&self) -> Option<&Metrics> {
+        self.metrics.as_ref()
+    }
+
+    /// Returns the advisories emitted while processing transactions since the last call.
+    pub fn drain_warnings(&mut self) -> vec::Drain<'_, Warning> {
+        self.warnings.drain(..)
+    }
+
+    /// Hands out the funds paid out when closing accounts (see `ResidualBalancePolicy`) since
+    /// the last call, in the order they were paid out. Unlike warnings, these are kept until
+    /// they're drained, regardless of how the transactions were processed.
+    pub fn drain_payouts(&mut self) -> vec::Drain<'_, Payout> {
+        self.payouts.drain(..)
+    }
+
+    // Adds `transaction` to the history of past transactions. Deposits and withdrawals check
+    // for duplicate ids beforehand, so this never replaces an existing record.
+    fn record_transaction(&mut self, mut transaction: Transaction) {
+        transaction.seq = self.next_seq;
+        self.next_seq += 1;
+        self.seen.insert(transaction.tx);
+
+        if self.history_bounded() {
+            self.history_order.push_back(transaction.tx);
+            self.store_transaction(transaction);
+            self.evict_history();
+        } else {
+            self.store_transaction(transaction);
+        }
+    }
+
+    // Writes `transaction` to the ledger, and keeps the index of open disputes in step.
+    fn store_transaction(&mut self, transaction: Transaction) {
+        if transaction.disputed {
+            self.open_disputes
+                .insert(transaction.tx, DisputeRecord::of(&transaction));
+        } else {
+            self.open_disputes.remove(&transaction.tx);
+        }
+        if let Err(e) = self.ledger.insert_transaction(transaction) {
+            self.storage_failed(e);
+        }
+    }
+
+    fn remove_transaction(&mut self, tx: TransactionId) {
+        self.open_disputes.remove(&tx);
+        if let Err(e) = self.ledger.remove_transaction(tx) {
+            self.storage_failed(e);
+        }
+    }
+
+    // Transaction `tx` from the ledger, or `None` if it can't be read (see `storage_failed`).
+    fn stored_transaction(&self, tx: TransactionId) -> Option<Transaction> {
+        self.ledger.transaction(tx).unwrap_or_else(|e| {
+            self.storage_failed(e);
+            None
+        })
+    }
+
+    fn is_stored(&self, tx: TransactionId) -> bool {
+        self.ledger.contains_transaction(tx).unwrap_or_else(|e| {
+            self.storage_failed(e);
+            false
+        })
+    }
+
+    // Whether `tx` is the id of a transaction that has been applied, whether or not it's still
+    // retained (unlike for `is_stored`).
+    fn is_known(&self, tx: TransactionId) -> bool {
+        self.seen.contains(&tx)
+            || self.chargebacks.contains_key(&tx)
+            || self.voided.contains_key(&tx)
+            || self.is_stored(tx)
+    }
+
+    // The transactions in the ledger, without the ones which can't be read.
+    fn stored_transactions(&self) -> impl Iterator<Item = Transaction> + '_ {
+        self.ledger
+            .transactions()
+            .filter_map(move |t| t.map_err(|e| self.storage_failed(e)).ok())
+    }
+
+    // Records the first failure of the ledger, after which every transaction gets rejected
+    // (see `storage_failure`), and returns the error to report for the current one.
+    fn storage_failed(&self, e: io::Error) -> Error {
+        warn!("Unable to access the ledger: {}", e);
+        let kind = self.storage_failure.get().unwrap_or(e.kind());
+        self.storage_failure.set(Some(kind));
+        Error::StorageFailed { kind }
+    }
+
+    /// The kind of error the ledger failed with, if it ever did. The state of the engine is
+    /// no longer in step with the ledger after that (i.e. a transaction may have been applied
+    /// to the account but not recorded in the history), so every further transaction is
+    /// rejected with `Error::StorageFailed`, until the engine gets `reset`.
+    pub fn storage_failure(&self) -> Option<io::ErrorKind> {
+        self.storage_failure.get()
+    }
+
+    // Lines up the retained transactions in the order they were added to the history (i.e.
+    // after loading existing state), and applies the history limit to them.
+    fn rebuild_history_order(&mut self) {
+        let mut retained: Vec<_> = self.stored_transactions().map(|t| (t.seq, t.tx)).collect();
+        retained.sort_unstable();
+        self.history_order = retained.into_iter().map(|(_, tx)| tx).collect();
+        self.evict_history();
+    }
+
+    // Whether the order of the retained transactions is kept track of, so the oldest ones can
+    // be evicted (see `evict_history`).
+    fn history_bounded(&self) -> bool {
+        self.config.history_limit.is_some() || self.evicts_expired()
+    }
+
+    fn evicts_expired(&self) -> bool {
+        self.config.evict_expired && self.config.dispute_window.is_some()
+    }
+
+    // Removes the oldest transactions from the history until it's within the history limit,
+    // as well as the ones whose dispute window has ended (as far as they are in timestamp
+    // order, since this stops at the first one that hasn't). Transactions under dispute are
+    // retained regardless (and moved to the back), but every entry is looked at once at most,
+    // so these cannot stall the eviction.
+    fn evict_history(&mut self) {
+        let limit = self.config.history_limit.unwrap_or(usize::MAX);
+
+        let mut remaining = self.history_order.len();
+        while let (Some(&tx), true) = (self.history_order.front(), remaining > 0) {
+            if self.history_order.len() <= limit && !self.dispute_window_ended(tx) {
+                break;
+            }
+            remaining -= 1;
+            self.history_order.pop_front();
+            if self.open_disputes.contains_key(&tx) {
+                self.history_order.push_back(tx);
+            } else if self.is_stored(tx) {
+                // Evicted ids are forgotten as well, to keep the memory use bounded, while
+                // the ones of settled transactions (which have left the history already) are
+                // kept.
+                self.seen.remove(&tx);
+                self.remove_transaction(tx);
+            }
+        }
+    }
+
+    // Whether transaction `tx` is to be evicted since its dispute window ended before the
+    // latest timestamp, or the transaction is gone already. Transactions without a timestamp
+    // never expire.
+    fn dispute_window_ended(&self, tx: TransactionId) -> bool {
+        let (window, now) = match (self.config.dispute_window, self.latest_timestamp) {
+            (Some(window), Some(now)) if self.config.evict_expired => (window, now),
+            _ => return false,
+        };
+        self.stored_transaction(tx).is_none_or(|t| {
+            t.timestamp
+                .is_some_and(|timestamp| timestamp.saturating_add(window) < now)
+        })
+    }
+
+    // Writes the account of `client` through to the ledger (if there is one).
+    fn store_account(&mut self, client: ClientId) {
+        if let Some(account) = self.client_accounts.get(&client) {
+            if let Err(e) = self.ledger.store_account(client, account) {
+                self.storage_failed(e);
+            }
+        }
+    }
+
+    // Returns a mutable handle to the account associated with `client` (and creates a new
+    // entry first if one does not already exist).
+    fn account_mut(&mut self, client: ClientId) -> &mut Account {
+        let recording = self.event_log.is_some();
+        self.client_accounts.entry(client).or_insert_with(|| {
+            let mut account = Account::default();
+            account.set_recording(recording);
+            account
+        })
+    }
+
+    // Rejects transactions which predate the latest one of the same client, when checking the
+    // order per client.
+    fn check_client_order(&self, transaction: &Transaction) -> Result<()> {
+        if self.config.time_ordering != TimeOrdering::PerClient {
+            return Ok(());
+        }
+        let latest = self
+            .client_accounts
+            .get(&transaction.client)
+            .and_then(Account::latest_timestamp);
+        match (transaction.timestamp, latest) {
+            (Some(timestamp), Some(previous)) if timestamp < previous => Err(Error::OutOfOrder {
+                tx: transaction.tx,
+                timestamp,
+                previous,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Processes all transactions from `reader`, which is expected to contain CSV data with a
+    /// header row (same reader configuration as for the binary's input). Invalid records are
+    /// skipped, while failing to read the input aborts processing. Warnings are discarded.
+    pub fn process_from_reader<R: Read>(&mut self, reader: R) -> csv::Result<ProcessSummary> {
+        let _span = info_span!("process_from_reader").entered();
+        let mut reader = reader_builder(true).from_reader(reader);
+        let mut summary = ProcessSummary::default();
+
+        // The `deserialize` iterator would swallow I/O errors for the header row otherwise.
+        reader.byte_headers()?;
+
+        for result in reader.deserialize::<Transaction>() {
+            match result {
+                Ok(transaction) => {
+                    let outcome = self.process_transaction_detailed(transaction);
+                    summary.record_processed(&outcome);
+                    self.warnings.clear();
+                }
+                Err(e) if e.is_io_error() => return Err(e),
+                Err(e) => {
+                    warn!("Skipping invalid record: {}", e);
+                    summary.record_invalid()
+                }
+            }
+        }
+
+        Ok(summary)
+    }
+
+    /// Processes `transactions` in order, and returns the id of each of them together with
+    /// the result of `process_transaction`, so callers get the outcome of every transaction
+    /// without a loop of their own. Warnings are discarded.
+    pub fn process_all<I>(&mut self, transactions: I) -> Vec<(TransactionId, Result<()>)>
+    where
+        I: IntoIterator<Item = Transaction>,
+    {
+        transactions
+            .into_iter()
+            .map(|transaction| {
+                let tx = transaction.tx;
+                let result = self.process_transaction(transaction);
+                self.warnings.clear();
+                (tx, result)
+            })
+            .collect()
+    }
+
+    /// Starts an engine on a thread of its own, which processes the transactions sent to the
+    /// returned channel in order. The channel holds up to `capacity` transactions, so
+    /// producers block while the engine is that far behind (zero makes every send wait for
+    /// the engine). The engine is set up on the thread via `make_engine`, so it doesn't need
+    /// to be `Send` (i.e. when it has a transaction callback). Once every sender has been
+    /// dropped, the thread finishes with a report of the outcome, which the returned handle
+    /// yields when joined.
+    pub fn spawn_worker<F>(
+        capacity: usize,
+        make_engine: F,
+    ) -> (
+        mpsc::SyncSender<Transaction>,
+        thread::JoinHandle<EngineReport>,
+    )
+    where
+        F: FnOnce() -> TransactionEngine + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::sync_channel(capacity);
+        let handle = thread::spawn(move || {
+            let _span = info_span!("worker").entered();
+            let mut engine = make_engine();
+            let mut summary = ProcessSummary::default();
+            let mut warnings = Vec::new();
+
+            for transaction in receiver {
+                summary.record_processed(&engine.process_transaction_detailed(transaction));
+                warnings.extend(engine.drain_warnings());
+            }
+
+            EngineReport {
+                summary,
+                aggregate: engine.aggregate(),
+                warnings,
+                snapshot: engine.snapshot(),
+            }
+        });
+        (sender, handle)
+    }
+
+    /// Same as `process_all`, but stops at the first rejected transaction (i.e. for strict
+    /// processing), and returns its id together with the reason. The transactions before it
+    /// stay applied, and the ones after it are not consumed (so processing can carry on from
+    /// an iterator passed by reference). Returns the number of transactions processed
+    /// otherwise.
+    pub fn try_process_all<I>(
+        &mut self,
+        transactions: I,
+    ) -> result::Result<usize, (TransactionId, Error)>
+    where
+        I: IntoIterator<Item = Transaction>,
+    {
+        let mut processed = 0;
+        for transaction in transactions {
+            let tx = transaction.tx;
+            let result = self.process_transaction(transaction);
+            self.warnings.clear();
+            result.map_err(|e| (tx, e))?;
+            processed += 1;
+        }
+        Ok(processed)
+    }
+
+    /// Processes `transactions` on `shards` worker threads, each of which owns the accounts of
+    /// a subset of the clients (partitioned by client id). Since every transaction affects a
+    /// single client account, this speeds up large inputs considerably. The transactions of
+    /// each client are still processed in order, while there's no ordering across clients.
+    /// The resulting state gets merged back into the engine once all transactions have been
+    /// processed.
+    ///
+    /// Dispute-related transactions are routed based on their `client` field, so they need to
+    /// carry the client of the referenced transaction (same as otherwise), or get rejected
+    /// with `Error::TransactionNotFound`. Likewise, duplicate transaction ids are only
+    /// detected within a shard. Neither the transaction callback, the validators, nor the
+    /// risk rules are invoked, and warnings are discarded.
+    pub fn process_parallel<I>(&mut self, transactions: I, shards: usize) -> ProcessSummary
+    where
+        I: IntoIterator<Item = Transaction>,
+    {
+        let shards = shards.max(1);
+        let shard_of = |client: ClientId| client.get() as usize % shards;
+        let _span = info_span!("process_parallel", shards).entered();
+
+        // Handing the current state over to the shards, partitioned the same way as the input.
+        let mut states: Vec<Shard> = (0..shards)
+            .map(|_| Shard {
+                next_seq: self.next_seq,
+                latest_timestamp: self.latest_timestamp,
+                metrics: self.metrics.as_ref().map(|_| Metrics::default()),
+                ..Shard::default()
+            })
+            .collect();
+        for (client, account) in self.client_accounts.drain() {
+            states[shard_of(client)].accounts.push((client, account));
+        }
+        // The shards record the events for their own clients, starting from their accounts.
+        if self.event_log.is_some() {
+            for state in &mut states {
+                let clients = state.accounts.iter().map(|(client, _)| *client);
+                state.events = Some(EventLog::on_top_of(clients));
+            }
+        }
+        let mut known = Vec::new();
+        for t in self.stored_transactions() {
+            known.push(t.tx);
+            states[shard_of(t.holder())].transactions.push(t);
+        }
+        for (_, t) in self.deferred_disputes.drain() {
+            states[shard_of(t.client)].deferred_disputes.push(t);
+        }
+        for (t, retries) in self.quarantine.drain(..) {
+            states[shard_of(t.client)].quarantine.push((t, retries));
+        }
+        for (_, t) in self.chargebacks.drain() {
+            states[shard_of(t.holder())].chargebacks.push(t);
+        }
+        self.next_hold_expiry = None;
+        for (_, t) in self.holds.drain() {
+            states[shard_of(t.client)].holds.push(t);
+        }
+        for (_, t) in self.voided.drain() {
+            states[shard_of(t.client)].voided.push(t);
+        }
+        // Every shard checks for reused ids against all of them, since ids aren't partitioned
+        // by client.
+        let seen: Vec<_> = self.seen.drain().collect();
+        for state in &mut states {
+            state.seen.clone_from(&seen);
+        }
+        for key in self.processed.drain() {
+            states[shard_of(key.client)].processed.push(key);
+        }
+
+        // The history limit is split among the shards, so they don't retain more than that
+        // in total.
+        let config = &Config {
+            transfers_disabled: true,
+            history_limit: self.config.history_limit.map(|n| n.div_ceil(shards)),
+            ..self.config.clone()
+        };
+        let results: Vec<Shard> = thread::scope(|scope| {
+            let mut senders = Vec::with_capacity(shards);
+            let mut workers = Vec::with_capacity(shards);
+            for (index, state) in states.into_iter().enumerate() {
+                let (sender, receiver) = mpsc::sync_channel(SHARD_QUEUED_BATCHES);
+                senders.push(sender);
+                // Created here, so the span of the worker is nested in the current one.
+                let span = info_span!("shard", index);
+                workers.push(scope.spawn(move || {
+                    let _span = span.entered();
+                    state.process(config, receiver)
+                }));
+            }
+
+            let mut batches: Vec<Vec<Transaction>> = (0..shards)
+                .map(|_| Vec::with_capacity(SHARD_BATCH_SIZE))
+                .collect();
+            for t in transactions {
+                let shard = shard_of(t.client);
+                batches[shard].push(t);
+                if batches[shard].len() == SHARD_BATCH_SIZE {
+                    let batch =
+                        mem::replace(&mut batches[shard], Vec::with_capacity(SHARD_BATCH_SIZE));
+                    // Sending only fails if the worker panicked, which gets propagated
+                    // when joining it below.
+                    let _ = senders[shard].send(batch);
+                }
+            }
+            for (sender, batch) in senders.into_iter().zip(batches) {
+                let _ = sender.send(batch);
+            }
+
+            workers
+                .into_iter()
+                .map(|w| w.join().unwrap_or_else(|e| panic::resume_unwind(e)))
+                .collect()
+        });
+
+        let mut summary = ProcessSummary::default();
+        let mut retained = HashSet::new();
+        for shard in results {
+            summary.merge(shard.summary);
+            self.next_seq = self.next_seq.max(shard.next_seq);
+            self.latest_timestamp = self.latest_timestamp.max(shard.latest_timestamp);
+            for (client, account) in shard.accounts {
+                self.client_accounts.insert(client, account);
+                self.store_account(client);
+            }
+            for t in shard.transactions {
+                retained.insert(t.tx);
+                self.store_transaction(t);
+            }
+            for t in shard.deferred_disputes {
+                self.deferred_disputes.insert(t.tx, t);
+            }
+            self.quarantine.extend(shard.quarantine);
+            for t in shard.chargebacks {
+                self.chargebacks.insert(t.tx, t);
+            }
+            for t in shard.holds {
+                self.insert_hold(t);
+            }
+            for t in shard.voided {
+                self.voided.insert(t.tx, t);
+            }
+            self.seen.extend(shard.seen);
+            self.processed.extend(shard.processed);
+            self.payouts.extend(shard.payouts);
+            if let (Some(metrics), Some(shard_metrics)) = (&mut self.metrics, shard.metrics) {
+                metrics.merge(shard_metrics);
+            }
+            if let (Some(log), Some(shard_log)) = (&mut self.event_log, shard.events) {
+                log.merge(shard_log);
+            }
+        }
+        // Transactions which were settled (or evicted) by one of the shards.
+        for tx in known {
+            if !retained.contains(&tx) {
+                self.remove_transaction(tx);
+            }
+        }
+        if self.history_bounded() {
+            self.rebuild_history_order();
+        }
+
+        summary
+    }
+
+    /// Same as `process_transaction`, but reports transactions which didn't have any effect
+    /// as `Outcome::NoOp`.
+    pub fn process_transaction_detailed(&mut self, transaction: Transaction) -> Outcome {
+        let warnings = self.warnings.len();
+        let no_op = self.is_replay(&transaction)
+            || match transaction.type_ {
+                Type::Deposit | Type::Withdrawal | Type::Transfer | Type::Hold => {
+                    transaction.amount().is_zero()
+                }
+                Type::Dispute => {
+                    self.config.max_deferred_disputes > 0 && !self.is_known(transaction.tx)
+                }
+                Type::Resolve | Type::Chargeback | Type::ChargebackReversal | Type::Void => false,
+                Type::Unlock | Type::Close | Type::Reopen | Type::Review | Type::Approve => self
+                    .client_accounts
+                    .get(&transaction.client)
+                    .is_some_and(|account| match transaction.type_ {
+                        Type::Unlock => !account.frozen(),
+                        Type::Close => account.closed(),
+                        Type::Reopen => !account.closed(),
+                        Type::Review => account.status() == Status::UnderReview,
+                        _ => account.status() != Status::UnderReview,
+                    }),
+            };
+
+        let result = self.process_transaction(transaction);
+        // Quarantined transactions haven't had any effect (yet) either.
+        let quarantined = || {
+            self.warnings[warnings..]
+                .iter()
+                .any(|w| matches!(w, Warning::Quarantined { .. }))
+        };
+        match result {
+            Ok(()) if no_op || quarantined() => Outcome::NoOp,
+            Ok(()) => Outcome::Applied,
+            Err(e) => Outcome::Rejected(e),
+        }
+    }
+
+    /// Applies `transaction` to the affected account, or returns the reason why it has been
+    /// rejected (in which case the engine state stays the same, apart from the account that
+    /// `with_track_all_clients` may create).
+    pub fn process_transaction(&mut self, mut transaction: Transaction) -> Result<()> {
+        if let Some(clock) = &self.config.clock {
+            match transaction.timestamp {
+                Some(timestamp) => clock.observe(timestamp),
+                None => transaction.timestamp = clock.now(),
+            }
+        }
+        if let Some(timestamp) = transaction.timestamp {
+            self.release_expired_holds(timestamp);
+        }
+
+        if self.metrics.is_none() {
+            self.apply_transaction(transaction, None)
+        } else {
+            let type_ = transaction.type_;
+            // Recorded under the type the amount is processed as (see `processed_key`).
+            let volume = matches!(type_, Type::Deposit | Type::Withdrawal).then(|| {
+                let key = self.processed_key(&transaction);
+                (
+                    key.type_,
+                    transaction.currency().to_owned(),
+                    transaction.amount().abs(),
+                )
+            });
+            let started = Instant::now();
+            let result = self.apply_transaction(transaction, None);
+            if let Some(metrics) = &mut self.metrics {
+                metrics.record(type_, &result, started.elapsed());
+                if let (Some((type_, currency, amount)), Ok(())) = (volume, &result) {
+                    metrics.record_volume(type_, &currency, amount);
+                }
+            }
+            result
+        }
+    }
+
+    /// Credits `amount` to the available funds of `client` in `currency`, or debits it for
+    /// negative amounts, outside of any transaction (i.e. for interest or fees applied by a
+    /// `scheduler::Scheduler`). Frozen and closed accounts are left alone, and debits beyond
+    /// the available funds are rejected, same as for withdrawals. Adjustments are not part
+    /// of the write-ahead log or the account history, and don't invoke the transaction
+    /// callback.
+    pub fn adjust_available(
+        &mut self,
+        client: ClientId,
+        currency: &str,
+        amount: Decimal,
+    ) -> Result<()> {
+        let a = self
+            .client_accounts
+            .get_mut(&client)
+            .ok_or(Error::AccountNotFound { client })?
+            .check_frozen_mut(client, false)?;
+
+        let mut balance = a.balance_mut(currency);
+        if amount.is_sign_negative() {
+            balance.try_decrease_available(client, -amount)?;
+        } else {
+            balance.try_increase_available(client, amount)?;
+        }
+        self.store_account(client);
+        self.flush_events((None, None), &[client]);
+        self.notify(client, |o, a| o.on_adjustment(client, currency, amount, a));
+
+        Ok(())
+    }
+
+    // Does the actual work of `process_transaction`, which only adds the metrics on top.
+    // Quarantined transactions are retried through here as well, along with how many times
+    // they've been retried before (`None` for new transactions).
+    fn apply_transaction(
+        &mut self,
+        mut transaction: Transaction,
+        retries: Option<u32>,
+    ) -> Result<()> {
+        let processed = self.processed_key(&transaction);
+        if self.config.negative_amounts == NegativeAmountPolicy::Signed
+            && matches!(transaction.type_, Type::Deposit | Type::Withdrawal)
+            && transaction.amount.is_some()
+        {
+            let amount = transaction.amount();
+            transaction.type_ = if amount.is_sign_negative() {
+                Type::Withdrawal
+            } else {
+                Type::Deposit
+            };
+            transaction.amount = Some(amount.abs());
+        }
+
+        let (type_, client, tx) = (transaction.type_, transaction.client, transaction.tx);
+        // Events emitted while processing (i.e. rejections) get the transaction as context.
+        let _span =
+            info_span!("transaction", r#type = ?type_, client = client.get(), tx = tx.get())
+                .entered();
+
+        // Checked on the key from before normalizing the amount above.
+        if self.config.replay_dedup && self.processed.contains(&processed) {
+            info!("Skipping transaction which has been applied already");
+            self.warnings
+                .push(Warning::ReplaySkipped { client, tx, type_ });
+            return Ok(());
+        }
+
+        if self.config.zero_amounts == ZeroAmountPolicy::Skip
+            && matches!(type_, Type::Deposit | Type::Withdrawal)
+            && transaction.amount.is_some_and(|amount| amount.is_zero())
+        {
+            warn!("Skipping zero-amount transaction");
+            self.warnings
+                .push(Warning::ZeroAmountSkipped { client, tx, type_ });
+            return Ok(());
+        }
+
+        // Only making a copy when there's someone to hand it to afterwards. Dispute-related
+        // transactions affect the owner of the original transaction, which has to be looked
+        // up beforehand, since settled disputes are removed from the history.
+        let callback_args = self.callback.is_some().then(|| {
+            let owner = match type_ {
+                Type::Dispute | Type::Resolve | Type::Chargeback => {
+                    self.stored_transaction(tx).map_or(client, |t| t.holder())
+                }
+                Type::ChargebackReversal => {
+                    self.chargebacks.get(&tx).map_or(client, |t| t.holder())
+                }
+                _ => client,
+            };
+            (transaction.clone(), owner)
+        });
+
+        // Along with the transaction a dispute-related transaction or void refers to, as of
+        // before it gets settled (and removed from the history).
+        let lineage_args = match type_ {
+            _ if self.lineage.is_none() => None,
+            Type::Dispute | Type::Resolve | Type::Chargeback | Type::Void => {
+                Some((transaction.clone(), self.stored_transaction(tx)))
+            }
+            Type::ChargebackReversal => {
+                Some((transaction.clone(), self.chargebacks.get(&tx).cloned()))
+            }
+            Type::Transfer => Some((transaction.clone(), None)),
+            _ => None,
+        };
+
+        let before = self
+            .config
+            .check_invariants
+            .then(|| self.affected_accounts(&transaction));
+        let affected = self
+            .event_log
+            .is_some()
+            .then(|| self.affected_clients(&transaction));
+
+        let ordered = self.check_client_order(&transaction);
+        let validated = self.validate(&transaction);
+        let applied =
+            (!self.validators.is_empty() || self.risk.is_some()).then(|| transaction.clone());
+        let quarantinable = (self.config.max_quarantined > 0
+            && matches!(
+                type_,
+                Type::Dispute | Type::Resolve | Type::Chargeback | Type::ChargebackReversal
+            ))
+        .then(|| transaction.clone());
+        let timestamp = transaction.timestamp;
+        let checked = match type_ {
+            _ if self.storage_failure.get().is_some() => Err(Error::StorageFailed {
+                kind: self.storage_failure.get().unwrap_or(io::ErrorKind::Other),
+            }),
+            _ if !self.client_allowed(client) => Err(Error::ClientNotAllowed { client }),
+            _ if ordered.is_err() => ordered,
+            _ if validated.is_err() => validated,
+            Type::Dispute | Type::Resolve | Type::Chargeback | Type::ChargebackReversal
+                if self.config.disputes_disabled =>
+            {
+                Err(Error::DisputesDisabled)
+            }
+            Type::Unlock | Type::Close | Type::Reopen | Type::Review | Type::Approve
+                if !self.config.admin_ops =>
+            {
+                Err(Error::AdminOpsDisabled)
+            }
+            _ => Ok(()),
+        };
+        // Only transactions which passed the checks get journaled, ahead of processing, since
+        // the engine cannot recover from failing to journal them halfway through. The ones the
+        // handlers reject get discarded again.
+        let checked = checked.and_then(|()| self.journal(&transaction));
+        let journaled = checked.is_ok() && self.wal.is_some();
+        let result = match type_ {
+            _ if checked.is_err() => checked,
+            Type::Unlock | Type::Close | Type::Reopen | Type::Review | Type::Approve => {
+                self.process_admin(transaction)
+            }
+            Type::Transfer => self.process_transfer(transaction),
+            Type::Deposit => self.process_deposit(transaction),
+            Type::Withdrawal => self.process_withdrawal(transaction),
+            Type::Hold => self.process_hold(transaction),
+            Type::Void => self.process_void(transaction),
+            Type::Dispute => self.process_dispute(transaction),
+            Type::Resolve => self.process_resolve(transaction),
+            Type::Chargeback => self.process_chargeback(transaction),
+            Type::ChargebackReversal => self.process_chargeback_reversal(transaction),
+        };
+        // Whatever the handler made of it, the transaction may only have been applied in part
+        // if the ledger failed along the way, so its journal entry gets discarded.
+        let result = match self.storage_failure.get() {
+            Some(kind) if checked.is_ok() => Err(Error::StorageFailed { kind }),
+            _ => result,
+        };
+        // Retried transactions go back to the quarantine until they run out of retries.
+        let requeued = match (&result, retries) {
+            (Err(e), _) if !e.is_not_found() => None,
+            (Err(_), None) if self.quarantine.len() < self.config.max_quarantined => Some(0),
+            (Err(_), Some(retries)) if retries + 1 < self.config.quarantine_retries => {
+                Some(retries + 1)
+            }
+            _ => None,
+        };
+        let (result, quarantined) = match (requeued, quarantinable) {
+            (Some(retries), Some(t)) => {
+                if retries == 0 {
+                    self.warnings
+                        .push(Warning::Quarantined { client, tx, type_ });
+                }
+                self.quarantine.push_back((t, retries));
+                (Ok(()), true)
+            }
+            _ => (result, false),
+        };
+        // Quarantined transactions haven't been applied (yet), so they don't leave any trace
+        // until they are.
+        let applied_ok = result.is_ok() && !quarantined;
+
+        // Logging here covers every error returned by the individual handlers.
+        if let Err(e) = &result {
+            // The transaction fields are repeated here, since the span is only enabled at the
+            // `info` level.
+            warn!(
+                r#type = ?type_,
+                client = client.get(),
+                tx = tx.get(),
+                error = e.name(),
+                "Rejected transaction: {}",
+                e
+            );
+
+            // Accounts don't get created for transactions which couldn't be journaled, since
+            // they wouldn't be recovered.
+            if self.config.track_all_clients
+                && !matches!(
+                    e,
+                    Error::ClientNotAllowed { .. }
+                        | Error::StorageFailed { .. }
+                        | Error::WalWriteFailed { .. }
+                )
+            {
+                self.account_mut(client);
+                self.store_account(client);
+            }
+        }
+
+        if applied_ok && self.config.replay_dedup {
+            self.processed.insert(processed);
+        }
+
+        if let (true, Some(timestamp)) = (applied_ok, timestamp) {
+            self.record_client_timestamp(client, timestamp);
+            if self.config.dispute_window.is_some() {
+                self.latest_timestamp = self.latest_timestamp.max(Some(timestamp));
+            }
+        }
+
+        if let (true, Some(transaction)) = (applied_ok, &applied) {
+            for validator in self.validators.iter_mut() {
+                if let Some(warning) = validator.warning(transaction) {
+                    self.warnings.push(warning);
+                }
+                validator.applied(transaction);
+            }
+            self.flag_risks(transaction);
+        }
+
+        if let (Some(lineage), Some((transaction, original))) = (&mut self.lineage, lineage_args) {
+            if applied_ok {
+                lineage.record(&transaction, original.as_ref());
+            }
+        }
+
+        if applied_ok && matches!(type_, Type::Deposit | Type::Withdrawal | Type::Transfer) {
+            self.apply_deferred_dispute(tx);
+        }
+        // Ahead of the retried transactions, which record their own events.
+        if let Some(clients) = affected {
+            self.flush_events((Some(tx), timestamp), &clients);
+        }
+        // Retried transactions don't trigger another round of retries, since the round they're
+        // part of is still going.
+        if applied_ok && retries.is_none() {
+            self.retry_quarantined();
+        }
+
+        // Settled only after the deferred dispute and the retried quarantined transactions
+        // have been applied as well, so a snapshot taken here includes their effects.
+        if journaled {
+            self.settle_wal_entry(tx, applied_ok);
+        }
+
+        let result = match before {
+            Some(before) => self
+                .check_invariants(type_, tx, &before, result.is_ok())
+                .and(result),
+            None => result,
+        };
+
+        // Quarantined transactions get the callback once they're applied or rejected for good.
+        if let (Some(callback), Some((transaction, owner)), false) =
+            (&mut self.callback, callback_args, quarantined)
+        {
+            let account = self.client_accounts.get(&owner).unwrap_or(&EMPTY_ACCOUNT);
+            callback(&transaction, result.as_ref().map(|_| ()), account);
+        }
+
+        result
+    }
+
+    // The accounts `transaction` may change, along with what the invariant checks need to know
+    // about them beforehand.
+    fn affected_accounts(&self, transaction: &Transaction) -> Vec<Before> {
+        let before = |client| Before {
+            client,
+            frozen: self
+                .client_accounts
+                .get(&client)
+                .is_some_and(Account::frozen),
+        };
+
+        match transaction.type_ {
+            Type::Dispute | Type::Resolve | Type::Chargeback => {
+                let t = match self.stored_transaction(transaction.tx) {
+                    Some(t) => t,
+                    None => return Vec::new(),
+                };
+                let mut affected = vec![before(t.holder())];
+                // The sender of a charged back transfer gets the funds back, regardless of the
+                // frozen policy.
+                if t.client != t.holder() {
+                    affected.push(Before {
+                        client: t.client,
+                        frozen: false,
+                    });
+                }
+                affected
+            }
+            // Chargeback reversals go through for frozen accounts.
+            Type::ChargebackReversal => match self.chargebacks.get(&transaction.tx) {
+                Some(t) => {
+                    let mut affected = vec![Before {
+                        client: t.holder(),
+                        frozen: false,
+                    }];
+                    if t.client != t.holder() {
+                        affected.push(Before {
+                            client: t.client,
+                            frozen: false,
+                        });
+                    }
+                    affected
+                }
+                None => Vec::new(),
+            },
+            Type::Transfer => {
+                let mut affected = vec![before(transaction.client)];
+                affected.extend(transaction.to.map(before));
+                affected
+            }
+            _ => vec![before(transaction.client)],
+        }
+    }
+
+    // Returns an error if the transaction left any of the affected accounts in an invalid
+    // state.
+    fn check_invariants(
+        &self,
+        type_: Type,
+        tx: TransactionId,
+        before: &[Before],
+        applied: bool,
+    ) -> Result<()> {
+        let allowed_when_frozen = match type_ {
+            Type::Dispute | Type::Resolve | Type::Chargeback => {
+                self.dispute_policy().allowed_when_frozen(type_)
+            }
+            _ => self.config.frozen_policy.allows(type_),
+        };
+        let rules = invariants::Rules {
+            overdraft_allowed: self.config.overdraft_policy != OverdraftPolicy::Reject,
+            allowed_when_frozen,
+        };
+
+        for before in before {
+            if let Some(account) = self.client_accounts.get(&before.client) {
+                if let Err(violation) = invariants::check(before, account, applied, &rules) {
+                    error!(
+                        r#type = ?type_,
+                        tx = tx.get(),
+                        "Invariant violated: {}",
+                        violation
+                    );
+                    return Err(Error::InvariantViolated {
+                        tx,
+                        client: before.client,
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // The key `transaction` gets recorded under once applied, which has the type it's processed
+    // as (see `with_signed_amounts`).
+    fn processed_key(&self, transaction: &Transaction) -> Processed {
+        let type_ = match transaction.type_ {
+            Type::Deposit | Type::Withdrawal
+                if self.config.negative_amounts == NegativeAmountPolicy::Signed =>
+            {
+                if transaction.amount().is_sign_negative() {
+                    Type::Withdrawal
+                } else {
+                    Type::Deposit
+                }
+            }
+            type_ => type_,
+        };
+        Processed {
+            client: transaction.client,
+            tx: transaction.tx,
+            type_,
+        }
+    }
+
+    // Whether `transaction` has been applied already, and gets skipped with
+    // `with_replay_dedup`.
+    fn is_replay(&self, transaction: &Transaction) -> bool {
+        self.config.replay_dedup && self.processed.contains(&self.processed_key(transaction))
+    }
+
+    // Tags the account of the client of `transaction` (which has been applied) with the flags
+    // the risk rules raise for it.
+    fn flag_risks(&mut self, transaction: &Transaction) {
+        let flags = match &mut self.risk {
+            Some(risk) => risk.evaluate(transaction),
+            None => return,
+        };
+
+        let (client, tx) = (transaction.client, transaction.tx);
+        for flag in flags {
+            let flagged = match self.client_accounts.get_mut(&client) {
+                Some(account) => account.flag(flag),
+                None => false,
+            };
+            if flagged {
+                info!(client = client.get(), %flag, "Flagged account");
+                self.warnings
+                    .push(Warning::RiskFlagged { client, tx, flag });
+                self.store_account(client);
+            }
+        }
+    }
+
+    // Runs the validators, in the order they were registered.
+    fn validate(&self, transaction: &Transaction) -> Result<()> {
+        match self.validators.iter().find(|v| !v.validate(transaction)) {
+            Some(validator) => Err(validator.error(transaction)),
+            None => Ok(()),
+        }
+    }
+
+    // Keeps track of the latest timestamp of `client`, when checking the order per client.
+    fn record_client_timestamp(&mut self, client: ClientId, timestamp: u64) {
+        if self.config.time_ordering != TimeOrdering::PerClient {
+            return;
+        }
+        // Dispute-related transactions don't necessarily carry the client of an account.
+        if let Some(account) = self.client_accounts.get_mut(&client) {
+            account.record_timestamp(timestamp);
+            self.store_account(client);
+        }
+    }
+
+    fn balance_limits(&self, client: ClientId) -> BalanceLimits {
+        self.config
+            .client_balance_limits
+            .get(&client)
+            .copied()
+            .unwrap_or(self.config.balance_limits)
+    }
+
+    // Whether any of the accounts has an overdraft line, which the account report shows the
+    // usage of.
+    fn overdrafts_enabled(&self) -> bool {
+        iter::once(&self.config.balance_limits)
+            .chain(self.config.client_balance_limits.values())
+            .any(|limits| limits.overdraft_limit > Decimal::ZERO)
+    }
+
+    fn client_allowed(&self, client: ClientId) -> bool {
+        self.config
+            .client_filter
+            .as_ref()
+            .is_none_or(|clients| clients.allows(client))
+    }
+
+    /// Attaches descriptive details to the account of `client` (i.e. from a seed file read via
+    /// `account::read_metadata`), creating the account if there's none yet, so seeded
+    /// accounts are part of the report even without any transactions. Replaces any metadata
+    /// attached before, and doesn't change the funds or status of the account.
+    pub fn set_account_metadata(&mut self, client: ClientId, metadata: AccountMetadata) {
+        self.account_mut(client).set_metadata(metadata);
+        self.store_account(client);
+    }
+
+    /// Starts the account of `opening.client` off with the given balance (i.e. the closing
+    /// balance of an earlier run, as read via `account::read_opening_balances`), creating the
+    /// account if there's none yet, and freezes it if it's locked. The funds are added to the
+    /// ones the account has already. Held funds stay held, since the disputes they're held
+    /// for are unknown to the engine (checkpoints carry those over instead). Fails for
+    /// negative held funds (`Error::InvalidAmount`), and if the funds would exceed the range
+    /// of `Decimal` (`Error::Overflow`), in which case the account stays the same. Opening
+    /// balances are not part of the write-ahead log or the account history, and don't invoke
+    /// the transaction callback.
+    pub fn open_balance(&mut self, opening: OpeningBalance) -> Result<()> {
+        let OpeningBalance {
+            client,
+            currency,
+            available,
+            held,
+            locked,
+        } = opening;
+        if held.is_sign_negative() {
+            return Err(Error::InvalidAmount { amount: held });
+        }
+
+        let account = self.account_mut(client);
+        let mut balance = account.balance(&currency).clone();
+        balance
+            .try_increase_available(client, available)?
+            .try_increase_held(client, held)?;
+        let overdrawn = balance.available().is_sign_negative();
+        account
+            .balance_mut(&currency)
+            .adjust_available(available)
+            .increase_held(held);
+        if !held.is_zero() {
+            account.carry_held(&currency, held);
+        }
+        if overdrawn {
+            account.mark_overdrawn();
+        }
+        if locked {
+            account.freeze();
+        }
+
+        self.store_account(client);
+        self.flush_events((None, None), &[client]);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::str::FromStr;
+
+    use csv::{ReaderBuilder, Trim};
+
+    use super::*;
+
+    // Parses `csv` (without the header row) into transactions.
+    fn transactions(csv: &str) -> Vec<Transaction> {
+        let input = format!("type,client,tx,amount\n{}", csv);
+        ReaderBuilder::new()
+            .trim(Trim::All)
+            .flexible(true)
+            .from_reader(input.as_bytes())
+            .deserialize()
+            .map(|t| t.expect("Invalid test input"))
+            .collect()
+    }
+
+    // Processes `csv` with `engine`, returning the result of every transaction.
+    fn process(engine: &mut TransactionEngine, csv: &str) -> Vec<Result<()>> {
+        transactions(csv)
+            .into_iter()
+            .map(|t| engine.process_transaction(t))
+            .collect()
+    }
+
+    fn client(id: u32) -> ClientId {
+        ClientId::from(id)
+    }
+
+    fn tx(id: u64) -> TransactionId {
+        TransactionId::from(id)
+    }
+
+    fn amount(amount: &str) -> Decimal {
+        Decimal::from_str(amount).expect("Invalid amount")
+    }
+
+    #[test]
+    fn totals_add_up_the_deposits() {
+        let mut engine = TransactionEngine::new();
+        let results = process(
+            &mut engine,
+            "deposit,1,1,10.5\ndeposit,1,2,4.25\ndeposit,1,3,0.0001\ndispute,1,2,",
+        );
+        assert!(results.iter().all(|r| r.is_ok()));
+
+        let account = engine.client_accounts[&client(1)].balance("");
+        let deposits = amount("10.5") + amount("4.25") + amount("0.0001");
+        assert_eq!(account.held(), amount("4.25"));
+        assert_eq!(account.total(), account.available() + account.held());
+        assert_eq!(account.total(), deposits);
+    }
+
+    #[test]
+    fn withdrawals_rejected_only_because_of_held_funds_are_pointed_out() {
+        let mut engine = TransactionEngine::new();
+        process(&mut engine, "deposit,1,1,10\ndeposit,1,2,5\ndispute,1,2,");
+        assert_eq!(engine.drain_warnings().count(), 0);
+
+        // The account holds 15 in total, 5 of which are held.
+        let results = process(&mut engine, "withdrawal,1,3,12");
+        assert!(matches!(
+            results[..],
+            [Err(Error::InsufficientFunds { .. })]
+        ));
+        let warnings: Vec<Warning> = engine.drain_warnings().collect();
+        assert!(matches!(
+            warnings[..],
+            [Warning::FundsHeld { client, tx }] if client == ClientId::from(1) && tx == 3.into()
+        ));
+
+        // Not even the held funds would cover this one.
+        let results = process(&mut engine, "withdrawal,1,4,20");
+        assert!(matches!(
+            results[..],
+            [Err(Error::InsufficientFunds { .. })]
+        ));
+        assert_eq!(engine.drain_warnings().count(), 0);
+    }
+
+    // The account report of `engine`, sorted by line.
+    fn accounts(engine: &TransactionEngine) -> Vec<String> {
+        let mut output = Vec::new();
+        engine
+            .write_accounts(&mut output)
+            .expect("Unable to write the accounts");
+        let mut lines: Vec<String> = String::from_utf8(output)
+            .expect("Account reports are UTF-8")
+            .lines()
+            .map(String::from)
+            .collect();
+        lines.sort();
+        lines
+    }
+
+    #[cfg(feature = "toml")]
+    #[test]
+    fn toml_checkpoints_round_trip() {
+        let mut engine = TransactionEngine::new();
+        process(
+            &mut engine,
+            "deposit,1,1,10\ndeposit,2,2,5.5\ndispute,2,2,\nwithdrawal,1,3,2.25",
+        );
+        let mut toml = Vec::new();
+        engine
+            .save_toml(&mut toml)
+            .expect("Unable to save the state");
+
+        let loaded = TransactionEngine::load_toml(&toml[..]).expect("Unable to load the state");
+        assert!(loaded.is_valid());
+        assert_eq!(accounts(&loaded), accounts(&engine));
+        assert_eq!(
+            loaded.client_accounts[&client(2)].balance("").held(),
+            amount("5.5")
+        );
+        assert!(loaded
+            .ledger
+            .transaction(tx(2))
+            .unwrap()
+            .unwrap()
+            .disputed());
+
+        // Saving the loaded state again yields the same file.
+        let mut again = Vec::new();
+        loaded
+            .save_toml(&mut again)
+            .expect("Unable to save the state");
+        assert_eq!(
+            String::from_utf8(again).unwrap(),
+            String::from_utf8(toml).unwrap()
+        );
+    }
+
+    #[cfg(feature = "toml")]
+    #[test]
+    fn edited_toml_checkpoints_are_validated() {
+        let mut engine = TransactionEngine::new();
+        process(&mut engine, "deposit,1,1,10");
+        let mut toml = Vec::new();
+        engine
+            .save_toml(&mut toml)
+            .expect("Unable to save the state");
+        let toml = String::from_utf8(toml).unwrap();
+        let load = |toml: &str| TransactionEngine::load_toml(toml.as_bytes());
+
+        // Negative held funds, even with the total left as it was.
+        assert!(toml.contains("available = \"10\"\nheld = \"0\""));
+        let edited = toml.replace(
+            "available = \"10\"\nheld = \"0\"",
+            "available = \"11\"\nheld = \"-1\"",
+        );
+        assert!(!load(&edited).expect("Unable to load").is_valid());
+
+        // A total which doesn't match the available and held funds.
+        assert!(toml.contains(r#"total = "10""#));
+        let edited = toml.replace(r#"total = "10""#, r#"total = "12""#);
+        assert!(!load(&edited).expect("Unable to load").is_valid());
+
+        // Edits which don't parse are rejected when loading.
+        let edited = toml.replace(r#"available = "10""#, r#"available = "ten""#);
+        assert!(load(&edited).is_err());
+
+        // Consistent corrections are fine.
+        let edited = toml
+            .replace(r#"available = "10""#, r#"available = "12""#)
+            .replace(r#"total = "10""#, r#"total = "12""#);
+        let loaded = load(&edited).expect("Unable to load");
+        assert!(loaded.is_valid());
+        assert_eq!(
+            loaded.client_accounts[&client(1)].balance("").total(),
+            amount("12")
+        );
+    }
+
+    #[test]
+    fn disabled_disputes_are_rejected_and_deposits_not_retained() {
+        let mut engine = TransactionEngine::new().with_disputes_disabled(true);
+        let results = process(
+            &mut engine,
+            "deposit,1,1,10\nwithdrawal,1,2,3\ndispute,1,1,\nresolve,1,1,\nchargeback,1,1,",
+        );
+        assert!(matches!(
+            results[..],
+            [
+                Ok(()),
+                Ok(()),
+                Err(Error::DisputesDisabled),
+                Err(Error::DisputesDisabled),
+                Err(Error::DisputesDisabled)
+            ]
+        ));
+        assert!(!engine.ledger.contains_transaction(tx(1)).unwrap());
+        let balance = engine.client_accounts[&client(1)].balance("");
+        assert_eq!(
+            (balance.available(), balance.held()),
+            (amount("7"), amount("0"))
+        );
+    }
+
+    #[test]
+    fn callbacks_see_every_outcome_with_the_affected_account() {
+        let calls = Rc::new(RefCell::new(Vec::new()));
+        let recorded = Rc::clone(&calls);
+        let mut engine = TransactionEngine::new().with_transaction_callback(
+            move |transaction, result, account| {
+                recorded.borrow_mut().push((
+                    transaction.tx(),
+                    result.map_err(|e| *e),
+                    account.balance("").available(),
+                ))
+            },
+        );
+        process(
+            &mut engine,
+            "deposit,1,1,10\nwithdrawal,1,2,15\ndispute,1,1,\nresolve,1,1,\n\
+             withdrawal,3,3,1\ndeposit,1,4,-1",
+        );
+
+        assert_eq!(
+            calls.borrow()[..],
+            [
+                (tx(1), Ok(()), amount("10")),
+                (
+                    tx(2),
+                    Err(Error::InsufficientFunds {
+                        client: client(1),
+                        requested: amount("15"),
+                        available: amount("10")
+                    }),
+                    amount("10")
+                ),
+                (tx(1), Ok(()), amount("0")),
+                (tx(1), Ok(()), amount("10")),
+                (
+                    tx(3),
+                    Err(Error::InsufficientFunds {
+                        client: client(3),
+                        requested: amount("1"),
+                        available: amount("0")
+                    }),
+                    amount("0")
+                ),
+                (
+                    tx(4),
+                    Err(Error::InvalidAmount {
+                        amount: amount("-1")
+                    }),
+                    amount("10")
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn zero_amounts_follow_the_zero_amount_policy() {
+        let mut engine = TransactionEngine::new();
+        let results = process(&mut engine, "deposit,1,1,0\nwithdrawal,1,2,0.0000");
+        assert!(matches!(
+            results[..],
+            [Err(Error::ZeroAmount), Err(Error::ZeroAmount)]
+        ));
+        assert!(engine.ledger.transactions().next().is_none());
+
+        let mut engine = TransactionEngine::new().with_zero_amounts_allowed(true);
+        let results = process(&mut engine, "deposit,1,1,0\nwithdrawal,1,2,0.0000");
+        assert!(matches!(results[..], [Ok(()), Ok(())]));
+        assert!(engine.ledger.contains_transaction(tx(1)).unwrap());
+        assert_eq!(
+            engine.client_accounts[&client(1)].balance("").total(),
+            amount("0")
+        );
+
+        // Skipped ones are neither rejected nor retained, but pointed out.
+        let mut engine = TransactionEngine::new().with_zero_amount_policy(ZeroAmountPolicy::Skip);
+        let results = process(&mut engine, "deposit,1,1,0\ndeposit,1,2,5");
+        assert!(matches!(results[..], [Ok(()), Ok(())]));
+        assert!(!engine.ledger.contains_transaction(tx(1)).unwrap());
+        let warnings: Vec<Warning> = engine.drain_warnings().collect();
+        assert!(matches!(
+            warnings[..],
+            [Warning::ZeroAmountSkipped {
+                type_: Type::Deposit,
+                ..
+            }]
+        ));
+    }
+
+    #[test]
+    fn reset_engines_only_reflect_the_following_batch() {
+        // The withdrawal leaves a warning behind, as the funds are held.
+        let first = "deposit,1,1,10\ndeposit,2,2,5\ndispute,2,2,\nwithdrawal,2,3,3";
+        let second = "deposit,3,4,7\nchargeback,2,2,\nwithdrawal,1,5,1";
+
+        let mut engine = TransactionEngine::new();
+        process(&mut engine, first);
+        engine.reset().unwrap();
+        let results = process(&mut engine, second);
+
+        // The accounts and the disputed deposit of the first batch are gone.
+        assert!(matches!(
+            results[..],
+            [
+                Ok(()),
+                Err(Error::TransactionNotFound { .. }),
+                Err(Error::InsufficientFunds { .. })
+            ]
+        ));
+        assert_eq!(engine.drain_warnings().count(), 0);
+        let mut fresh = TransactionEngine::new();
+        process(&mut fresh, second);
+        assert_eq!(accounts(&engine), accounts(&fresh));
+    }
+
+    #[test]
+    fn account_histories_record_every_balance_step() {
+        let input = "deposit,1,1,10\ndeposit,1,2,5\nwithdrawal,1,3,4\nwithdrawal,1,4,40\n\
+                     dispute,1,2,";
+        let mut engine = TransactionEngine::new().with_account_history(true);
+        process(&mut engine, input);
+
+        // The rejected withdrawal isn't part of the history.
+        let history: Vec<String> = engine.client_accounts[&client(1)]
+            .history()
+            .iter()
+            .map(|op| serde_json::to_string(op).unwrap())
+            .collect();
+        assert_eq!(
+            history,
+            [
+                r#"{"type":"deposit","tx":1,"amount":"10","available":"10","held":"0"}"#,
+                r#"{"type":"deposit","tx":2,"amount":"5","available":"15","held":"0"}"#,
+                r#"{"type":"withdrawal","tx":3,"amount":"4","available":"11","held":"0"}"#,
+                r#"{"type":"dispute","tx":2,"amount":"5","available":"6","held":"5"}"#,
+            ]
+        );
+
+        let mut engine = TransactionEngine::new();
+        process(&mut engine, input);
+        assert!(engine.client_accounts[&client(1)].history().is_empty());
+    }
+
+    #[test]
+    fn open_disputes_count_unsettled_disputes() {
+        let mut engine = TransactionEngine::new();
+        process(
+            &mut engine,
+            "deposit,1,1,10\ndeposit,1,2,5\ndispute,1,1,\ndispute,1,2,",
+        );
+        assert_eq!(engine.client_accounts[&client(1)].open_disputes(), 2);
+
+        // The resolved transaction is removed from the history, which doesn't affect the
+        // count.
+        process(&mut engine, "resolve,1,1,");
+        assert_eq!(engine.client_accounts[&client(1)].open_disputes(), 1);
+
+        process(&mut engine, "chargeback,1,2,");
+        assert_eq!(engine.client_accounts[&client(1)].open_disputes(), 0);
+    }
+
+    #[test]
+    fn deferred_disputes_apply_once_their_transaction_arrives() {
+        let mut engine = TransactionEngine::new().with_deferred_disputes(8);
+        let results = process(&mut engine, "dispute,1,1,");
+        assert!(matches!(results[..], [Ok(())]));
+        // Held back until the deposit arrives.
+        assert!(!engine.client_accounts.contains_key(&client(1)));
+
+        process(&mut engine, "deposit,1,1,10");
+        let account = &engine.client_accounts[&client(1)];
+        assert_eq!(account.balance("").available(), amount("0"));
+        assert_eq!(account.balance("").held(), amount("10"));
+        assert_eq!(account.open_disputes(), 1);
+
+        // Without deferral, the dispute is rejected right away.
+        let mut engine = TransactionEngine::new();
+        let results = process(&mut engine, "dispute,1,1,\ndeposit,1,1,10");
+        assert!(matches!(
+            results[..],
+            [Err(Error::TransactionNotFound { .. }), Ok(())]
+        ));
+        assert_eq!(
+            engine.client_accounts[&client(1)].balance("").held(),
+            amount("0")
+        );
+    }
+
+    #[test]
+    fn detailed_outcomes_tell_no_ops_from_rejections() {
+        let mut engine = TransactionEngine::new()
+            .with_zero_amounts_allowed(true)
+            .with_deferred_disputes(1);
+        let outcomes: Vec<Outcome> = transactions(
+            "deposit,1,1,10\ndeposit,1,2,0\nwithdrawal,1,3,20\ndispute,1,9,\ndispute,1,8,\n\
+             dispute,1,1,\nresolve,1,1,\nchargeback,1,1,",
+        )
+        .into_iter()
+        .map(|t| engine.process_transaction_detailed(t))
+        .collect();
+
+        // Only a single dispute of an unknown transaction can be deferred, and the resolve
+        // settled the dispute before the chargeback.
+        assert_eq!(
+            outcomes,
+            [
+                Outcome::Applied,
+                Outcome::NoOp,
+                Outcome::Rejected(Error::InsufficientFunds {
+                    client: client(1),
+                    requested: amount("20"),
+                    available: amount("10")
+                }),
+                Outcome::NoOp,
+                Outcome::Rejected(Error::TransactionNotFound { tx: tx(8) }),
+                Outcome::Applied,
+                Outcome::Applied,
+                Outcome::Rejected(Error::TransactionNotFound { tx: tx(1) }),
+            ]
+        );
+        let results: Vec<Result<()>> = outcomes.iter().map(Outcome::result).collect();
+        assert!(matches!(
+            results[..],
+            [
+                Ok(()),
+                Ok(()),
+                Err(Error::InsufficientFunds { .. }),
+                Ok(()),
+                ..
+            ]
+        ));
+    }
+
+    #[test]
+    fn builders_forward_their_options_to_the_engine() {
+        let engine = TransactionEngine::builder()
+            .with_redisputes(true)
+            .with_max_disputes(2)
+            .with_dispute_window(60)
+            .with_expired_eviction(true)
+            .with_negative_amount_policy(NegativeAmountPolicy::Signed)
+            .with_history_limit(3)
+            .with_metadata_columns(true)
+            .with_dispute_columns(true)
+            .with_quarantine(4, 2)
+            .build();
+
+        let config = &engine.config;
+        assert!(config.redisputes && config.evict_expired);
+        assert_eq!(config.max_disputes, Some(2));
+        assert_eq!(config.dispute_window, Some(60));
+        assert_eq!(config.negative_amounts, NegativeAmountPolicy::Signed);
+        assert_eq!(config.history_limit, Some(3));
+        assert!(config.metadata_columns && config.dispute_columns);
+        assert_eq!((config.max_quarantined, config.quarantine_retries), (4, 2));
+
+        // Without any options, it's the same as a new engine.
+        let config = TransactionEngine::builder().build().config;
+        assert!(!config.redisputes && config.history_limit.is_none());
+        assert_eq!(config.zero_amounts, ZeroAmountPolicy::default());
+    }
+
+    #[test]
+    fn engines_built_with_options_behave_accordingly() {
+        let builder = TransactionEngine::builder()
+            .with_zero_amount_policy(ZeroAmountPolicy::Accept)
+            .with_max_disputes(2);
+        let mut engine = builder.clone().build();
+        let results = process(
+            &mut engine,
+            "deposit,1,1,0\ndeposit,1,2,10\ndispute,1,2,\nresolve,1,2,\n\
+             dispute,1,2,\nresolve,1,2,\ndispute,1,2,",
+        );
+        assert!(results[..6].iter().all(Result::is_ok), "{:?}", results);
+        // Once it has been disputed twice, it leaves the history with its second resolve.
+        assert!(matches!(results[6], Err(Error::TransactionNotFound { .. })));
+
+        // Options added to a copy of the builder don't affect the original.
+        let mut engine = builder.clone().with_history_limit(1).build();
+        let results = process(&mut engine, "deposit,1,1,10\ndeposit,1,2,5\ndispute,1,1,");
+        assert!(matches!(results[2], Err(Error::TransactionNotFound { .. })));
+        let mut engine = builder.build();
+        let results = process(&mut engine, "deposit,1,1,10\ndeposit,1,2,5\ndispute,1,1,");
+        assert!(results.iter().all(Result::is_ok), "{:?}", results);
+    }
+
+    #[test]
+    fn config_settings_take_precedence_over_the_builder_options() {
+        let mut config = EngineConfig::default();
+        config.amounts.zero = Some(ZeroAmountPolicy::Reject);
+        let mut engine = TransactionEngine::builder()
+            .with_config(config)
+            .with_zero_amount_policy(ZeroAmountPolicy::Accept)
+            .build();
+        let results = process(&mut engine, "deposit,1,1,0");
+        assert!(matches!(results[0], Err(Error::ZeroAmount)));
     }
 }
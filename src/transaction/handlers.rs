@@ -0,0 +1,828 @@
+//! The handlers which apply the individual types of transactions to the accounts, once
+//! `TransactionEngine::apply_transaction` has checked them against the configuration.
+
+use rust_decimal::Decimal;
+use tracing::{info, info_span, warn};
+
+use super::{Error, Payout, Result, TimeOrdering, Transaction, TransactionEngine, Type, Warning};
+use crate::account::{Account, Status};
+use crate::dispute::OverdraftPolicy;
+use crate::id::TransactionId;
+
+impl TransactionEngine {
+    // Given a dispute-related transaction, returns a copy of the transaction record it refers
+    // to, and a mutable handle to the corresponding client account. Changes to the record
+    // have to be written back to the ledger afterwards. Returns an error if no such record
+    // exists, or if `reference` specifies a different client or currency than the record.
+    fn transaction_mut(&mut self, reference: &Transaction) -> Result<(Transaction, &mut Account)> {
+        let tx = reference.tx;
+        let transaction = self
+            .ledger
+            .transaction(tx)
+            .map_err(|e| self.storage_failed(e))?
+            .ok_or(Error::TransactionNotFound { tx })?;
+
+        Self::check_reference(reference, &transaction)?;
+        self.check_lifecycle_order(reference, &transaction)?;
+
+        let account =
+            self.client_accounts
+                .get_mut(&transaction.holder())
+                .ok_or(Error::AccountNotFound {
+                    client: transaction.holder(),
+                })?;
+
+        Ok((transaction, account))
+    }
+
+    // Rejects dispute-related transactions which specify a different client than the holder of
+    // the funds of the transaction they refer to (the receiving end, for transfers), or a
+    // different currency.
+    fn check_reference(reference: &Transaction, original: &Transaction) -> Result<()> {
+        let tx = reference.tx;
+        if reference.client != original.holder() {
+            return Err(Error::ClientMismatch {
+                tx,
+                client: reference.client,
+                expected: original.holder(),
+            });
+        }
+        if matches!(&reference.currency, Some(c) if c != original.currency()) {
+            return Err(Error::CurrencyMismatch { tx });
+        }
+        Ok(())
+    }
+
+    // Rejects dispute-related transactions which predate the transaction they refer to (unless
+    // the order is unchecked).
+    fn check_lifecycle_order(&self, reference: &Transaction, original: &Transaction) -> Result<()> {
+        if self.config.time_ordering == TimeOrdering::Unchecked {
+            return Ok(());
+        }
+        match (reference.timestamp, original.timestamp) {
+            (Some(timestamp), Some(previous)) if timestamp < previous => Err(Error::OutOfOrder {
+                tx: reference.tx,
+                timestamp,
+                previous,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    // Applies the deferred dispute of transaction `tx` (if any), now that it's been processed.
+    // The dispute can still fail at this point (i.e. because the transaction turned out to
+    // be a withdrawal), which only gets logged, since the dispute row is long gone.
+    pub(super) fn apply_deferred_dispute(&mut self, tx: TransactionId) {
+        if let Some(dispute) = self.deferred_disputes.remove(&tx) {
+            let _span = info_span!(
+                "deferred_dispute",
+                client = dispute.client.get(),
+                tx = tx.get()
+            )
+            .entered();
+            if let Err(e) = self.process_dispute(dispute) {
+                warn!(
+                    tx = tx.get(),
+                    error = e.name(),
+                    "Rejected deferred dispute: {}",
+                    e
+                );
+            }
+        }
+    }
+
+    // Retries the quarantined transactions in the order they came in, now that another
+    // transaction has been applied. They go through the same checks as any other transaction
+    // (and get journaled once they do). The ones referencing transactions or accounts which
+    // still don't exist stay in the quarantine until they run out of retries, and rejections
+    // only get logged, same as for deferred disputes.
+    pub(super) fn retry_quarantined(&mut self) {
+        for _ in 0..self.quarantine.len() {
+            let (t, retries) = match self.quarantine.pop_front() {
+                Some(entry) => entry,
+                None => break,
+            };
+            let (type_, client, tx) = (t.type_, t.client, t.tx);
+            let _span =
+                info_span!("quarantined", r#type = ?type_, client = client.get(), tx = tx.get())
+                    .entered();
+            let queued = self.quarantine.len();
+            if self.apply_transaction(t, Some(retries)).is_ok() && self.quarantine.len() == queued {
+                info!("Applied quarantined transaction");
+            }
+        }
+    }
+
+    // Transaction ids are supposed to be unique, but nothing stops a buggy (or malicious)
+    // feed from reusing one, which would replace the record of the original transaction (or
+    // take the place of one that has been settled already).
+    fn check_duplicate(&self, tx: TransactionId) -> Result<()> {
+        if self.is_known(tx) || self.holds.contains_key(&tx) {
+            return Err(Error::DuplicateTransaction { tx });
+        }
+        Ok(())
+    }
+
+    // Handles the administrative transaction types, which only change the status of the
+    // (existing) account. Frozen accounts can't be closed or put under review, since they'd
+    // end up active once reopened or approved, and closed accounts have to be reopened first.
+    // Transactions for accounts which have the status already are no-ops. Whether accounts
+    // with funds can be closed is up to the residual balance policy.
+    pub(super) fn process_admin(&mut self, transaction: Transaction) -> Result<()> {
+        let client = transaction.client;
+        let policy = self.config.residual_balances;
+        let record_history = self.config.record_history;
+        let account = self
+            .client_accounts
+            .get_mut(&client)
+            .ok_or(Error::AccountNotFound { client })?;
+
+        match (transaction.type_, account.status()) {
+            (Type::Close | Type::Review, Status::Frozen) => {
+                return Err(Error::AccountFrozen { client })
+            }
+            (Type::Review, Status::Closed) => return Err(Error::AccountClosed { client }),
+            _ => {}
+        }
+
+        if transaction.type_ == Type::Close {
+            let payouts = account.residual_payouts(client, policy)?;
+            for (currency, amount) in payouts {
+                account.balance_mut(&currency).decrease_available(amount);
+                if record_history {
+                    account.record_op(Type::Close, transaction.tx, amount, &currency);
+                }
+                self.payouts.push(Payout {
+                    client,
+                    tx: transaction.tx,
+                    currency,
+                    amount,
+                });
+            }
+        }
+
+        match transaction.type_ {
+            Type::Unlock => account.unfreeze(),
+            Type::Close => account.close(),
+            Type::Review => account.review(),
+            Type::Approve => account.approve(),
+            _ => account.reopen(),
+        };
+        self.store_account(client);
+        self.notify(client, |o, a| match transaction.type_ {
+            Type::Unlock => o.on_unfreeze(client, a),
+            Type::Close => o.on_close(client, a),
+            Type::Review => o.on_review(client, a),
+            Type::Approve => o.on_approve(client, a),
+            _ => o.on_reopen(client, a),
+        });
+
+        Ok(())
+    }
+
+    // Handles a `deposit` transaction.
+    pub(super) fn process_deposit(&mut self, mut transaction: Transaction) -> Result<()> {
+        let amount = transaction.validated_amount(&self.config)?;
+        transaction.amount = Some(amount);
+        self.check_duplicate(transaction.tx)?;
+
+        let allow_frozen = self.config.frozen_policy.deposit;
+        let record_history = self.config.record_history;
+        let account = self
+            .account_mut(transaction.client)
+            .check_frozen_mut(transaction.client, allow_frozen)?;
+        account
+            .balance_mut(transaction.currency())
+            .try_increase_available(transaction.client, amount)?;
+
+        if record_history {
+            account.record_op(
+                Type::Deposit,
+                transaction.tx,
+                amount,
+                transaction.currency(),
+            );
+        }
+        let client = transaction.client;
+        self.notify(client, |o, a| o.on_deposit(&transaction, a));
+        // Inserting after the amount has been updated successfully. There's no need to keep
+        // deposits around when they cannot be disputed.
+        if !self.config.disputes_disabled {
+            self.record_transaction(transaction);
+        }
+        self.store_account(client);
+
+        Ok(())
+    }
+
+    // Handles a `withdrawal` transaction.
+    pub(super) fn process_withdrawal(&mut self, mut transaction: Transaction) -> Result<()> {
+        let amount = transaction.validated_amount(&self.config)?;
+        transaction.amount = Some(amount);
+        self.check_duplicate(transaction.tx)?;
+
+        let allow_frozen = self.config.frozen_policy.withdrawal;
+        let record_history = self.config.record_history;
+        let fees = self.config.fees;
+        let floor = self.balance_limits(transaction.client).floor();
+        let account = self
+            .account_mut(transaction.client)
+            .check_frozen_mut(transaction.client, allow_frozen)?
+            .check_withdrawable_mut(transaction.client)?;
+
+        let mut balance = account.balance_mut(transaction.currency());
+
+        // The fee is deducted along with the amount, so either both go through or neither.
+        let fee = fees.map_or(Decimal::ZERO, |fees| fees.fee(amount));
+        let debit = amount.checked_add(fee).ok_or(Error::Overflow {
+            client: transaction.client,
+            amount,
+        })?;
+
+        // A withdrawal cannot take place if the amount is greater than the currently
+        // available funds (beyond the balance limits).
+        if let Err(e) = balance.try_decrease_available_to(transaction.client, debit, floor) {
+            // Let's point out when the withdrawal would have gone through if it weren't
+            // for the funds held under dispute.
+            if balance.total() - floor >= debit {
+                self.warnings.push(Warning::FundsHeld {
+                    client: transaction.client,
+                    tx: transaction.tx,
+                });
+            }
+            return Err(e);
+        }
+        if balance.available().is_sign_negative() {
+            account.mark_overdrawn();
+        }
+
+        if record_history {
+            account.record_op(
+                Type::Withdrawal,
+                transaction.tx,
+                amount,
+                transaction.currency(),
+            );
+            if !fee.is_zero() {
+                account.record_fee(transaction.tx, fee, transaction.currency());
+            }
+        }
+
+        let client = transaction.client;
+        self.notify(client, |o, a| o.on_withdrawal(&transaction, a));
+        self.record_transaction(transaction);
+        self.store_account(client);
+
+        Ok(())
+    }
+
+    // Handles a `hold` transaction, which moves the amount from the available to the held funds
+    // of the account until the hold expires. Holds are kept apart from the history, since
+    // they can't be disputed.
+    pub(super) fn process_hold(&mut self, mut transaction: Transaction) -> Result<()> {
+        let amount = transaction.validated_amount(&self.config)?;
+        transaction.amount = Some(amount);
+        self.check_duplicate(transaction.tx)?;
+        if transaction.expires.is_none() {
+            return Err(Error::InvalidHold { tx: transaction.tx });
+        }
+
+        let allow_frozen = self.config.frozen_policy.hold;
+        let record_history = self.config.record_history;
+        let floor = self.balance_limits(transaction.client).floor();
+        let client = transaction.client;
+        let account = self
+            .account_mut(client)
+            .check_frozen_mut(client, allow_frozen)?
+            .check_withdrawable_mut(client)?;
+
+        account
+            .balance_mut(transaction.currency())
+            .try_hold_to(client, amount, floor)?;
+        if account
+            .balance(transaction.currency())
+            .available()
+            .is_sign_negative()
+        {
+            account.mark_overdrawn();
+        }
+
+        if record_history {
+            account.record_op(Type::Hold, transaction.tx, amount, transaction.currency());
+        }
+
+        self.insert_hold(transaction);
+        self.store_account(client);
+
+        Ok(())
+    }
+
+    // Handles a `void` transaction, which reverses the deposit or withdrawal it refers to, as
+    // long as it has never been disputed: the funds of a deposit are taken back out of the
+    // available funds (which needs them to still be there), while the ones of a withdrawal are
+    // returned (without any fee it was charged). The original moves from the history to the
+    // voided transactions, so it can't be disputed, or voided again.
+    pub(super) fn process_void(&mut self, transaction: Transaction) -> Result<()> {
+        let tx = transaction.tx;
+        if self.voided.contains_key(&tx) || self.chargebacks.contains_key(&tx) {
+            return Err(Error::InvalidVoid { tx });
+        }
+        let record_history = self.config.record_history;
+        let (t, a) = self.transaction_mut(&transaction)?;
+
+        if !matches!(t.type_, Type::Deposit | Type::Withdrawal) || t.disputed || t.disputes > 0 {
+            return Err(Error::InvalidVoid { tx });
+        }
+
+        // Voids go through for frozen accounts, but not for closed ones.
+        let client = t.client;
+        let amount = t.amount();
+        let mut balance = a.check_frozen_mut(client, true)?.balance_mut(t.currency());
+        if t.type_ == Type::Deposit {
+            balance.try_decrease_available(client, amount)?;
+        } else {
+            balance.try_increase_available(client, amount)?;
+        }
+
+        if record_history {
+            a.record_op(Type::Void, tx, amount, t.currency());
+        }
+
+        self.remove_transaction(tx);
+        self.store_account(client);
+        self.notify(client, |o, a| o.on_void(&t, a));
+        self.voided.insert(tx, t);
+
+        Ok(())
+    }
+
+    // Keeps track of `hold` until it expires.
+    pub(super) fn insert_hold(&mut self, hold: Transaction) {
+        if let Some(expires) = hold.expires {
+            self.next_hold_expiry = Some(self.next_hold_expiry.map_or(expires, |e| e.min(expires)));
+        }
+        self.holds.insert(hold.tx, hold);
+    }
+
+    /// Releases the funds of every hold which expires at `now` (in seconds since the Unix
+    /// epoch) or before, back to the available funds of the account. This happens on its own
+    /// before processing a transaction with a timestamp, so this only needs to be called when
+    /// time moves on without transactions (i.e. on a timer, or at the end of each batch).
+    /// Holds of frozen accounts are released as well. Returns how many holds were released.
+    pub fn release_expired_holds(&mut self, now: u64) -> usize {
+        if self.next_hold_expiry.is_none_or(|expires| expires > now) {
+            return 0;
+        }
+
+        let mut expired: Vec<_> = self
+            .holds
+            .values()
+            .filter(|t| t.expires.is_some_and(|expires| expires <= now))
+            .map(|t| (t.expires, t.tx))
+            .collect();
+        expired.sort_unstable();
+
+        let record_history = self.config.record_history;
+        let mut clients = Vec::new();
+        for (_, tx) in expired.iter() {
+            let hold = self.holds.remove(tx).unwrap();
+            let (client, amount) = (hold.client, hold.amount());
+            // Unknown clients are caught by `is_valid`, i.e. for a hand-edited checkpoint.
+            if let Some(account) = self.client_accounts.get_mut(&client) {
+                account.balance_mut(hold.currency()).release(amount);
+                if record_history {
+                    account.record_op(Type::Hold, hold.tx, -amount, hold.currency());
+                }
+                self.store_account(client);
+                clients.push(client);
+            }
+        }
+        self.next_hold_expiry = self.holds.values().filter_map(|t| t.expires).min();
+
+        clients.sort_unstable();
+        clients.dedup();
+        self.flush_events((None, None), &clients);
+        expired.len()
+    }
+
+    // Handles a `transfer` transaction, which withdraws the amount from the account of
+    // `client`, and deposits it into the account of `to`. Both parties are checked before
+    // either balance changes, so the transfer goes through as a whole or not at all.
+    pub(super) fn process_transfer(&mut self, mut transaction: Transaction) -> Result<()> {
+        let (from, tx) = (transaction.client, transaction.tx);
+        let to = match transaction.to {
+            Some(to) if to != from && !self.config.transfers_disabled => to,
+            _ => return Err(Error::InvalidTransfer { tx }),
+        };
+        if !self.client_allowed(to) {
+            return Err(Error::ClientNotAllowed { client: to });
+        }
+
+        let amount = transaction.validated_amount(&self.config)?;
+        transaction.amount = Some(amount);
+        self.check_duplicate(tx)?;
+
+        let allow_frozen = self.config.frozen_policy.transfer;
+        let record_history = self.config.record_history;
+
+        // The receiving account only gets created once the transfer goes through.
+        if let Some(account) = self.client_accounts.get_mut(&to) {
+            account.check_frozen_mut(to, allow_frozen)?;
+            // Checked before debiting the sender, who would lose the funds otherwise.
+            account
+                .balance(transaction.currency())
+                .clone()
+                .try_increase_available(to, amount)?;
+        }
+
+        let floor = self.balance_limits(from).floor();
+        let sender = self
+            .account_mut(from)
+            .check_frozen_mut(from, allow_frozen)?
+            .check_withdrawable_mut(from)?;
+        let mut balance = sender.balance_mut(transaction.currency());
+        if let Err(e) = balance.try_decrease_available_to(from, amount, floor) {
+            // Same as for withdrawals.
+            if balance.total() - floor >= amount {
+                self.warnings.push(Warning::FundsHeld { client: from, tx });
+            }
+            return Err(e);
+        }
+        if balance.available().is_sign_negative() {
+            sender.mark_overdrawn();
+        }
+        if record_history {
+            sender.record_op(Type::Transfer, tx, amount, transaction.currency());
+        }
+
+        let receiver = self.account_mut(to);
+        receiver
+            .balance_mut(transaction.currency())
+            .increase_available(amount);
+        if record_history {
+            receiver.record_op(Type::Transfer, tx, amount, transaction.currency());
+        }
+
+        if let Some(observer) = self.observer.as_mut() {
+            let accounts = &self.client_accounts;
+            observer.on_transfer(&transaction, &accounts[&from], &accounts[&to]);
+        }
+        self.record_transaction(transaction);
+        self.store_account(from);
+        self.store_account(to);
+
+        Ok(())
+    }
+
+    // Handles a `dispute` transaction. The whole (remaining) amount of the transaction is
+    // disputed, unless the dispute row specifies a lesser amount; only that portion is held
+    // then, and the rest can still be disputed later on. Further partial disputes can be
+    // raised while one is open already, which adds to the disputed portion.
+    pub(super) fn process_dispute(&mut self, transaction: Transaction) -> Result<()> {
+        let policy = self.dispute_policy();
+        let allow_frozen = policy.allowed_when_frozen(Type::Dispute);
+        let record_history = self.config.record_history;
+        let overdraft_policy = self.config.overdraft_policy;
+        let partial = match transaction.amount {
+            Some(_) => Some(transaction.validated_amount(&self.config)?),
+            None => None,
+        };
+
+        // Checked ahead of deferring disputes, since voided transactions are never coming.
+        if self.voided.contains_key(&transaction.tx) {
+            return Err(Error::InvalidDispute { tx: transaction.tx });
+        }
+
+        // Only transactions which have never been seen can still come, while disputes of ones
+        // which have been settled already are rejected below.
+        if self.config.max_deferred_disputes > 0 && !self.is_known(transaction.tx) {
+            let tx = transaction.tx;
+            if self.deferred_disputes.contains_key(&tx) {
+                return Err(Error::InvalidDispute { tx });
+            }
+            if self.deferred_disputes.len() < self.config.max_deferred_disputes {
+                self.warnings.push(Warning::DisputeDeferred {
+                    client: transaction.client,
+                    tx,
+                });
+                self.deferred_disputes.insert(tx, transaction);
+                return Ok(());
+            }
+        }
+
+        // Disputes without a timestamp are checked against the latest one.
+        let now = transaction.timestamp.or(self.latest_timestamp);
+        let window = self.config.dispute_window;
+
+        let (mut t, a) = self.transaction_mut(&transaction)?;
+
+        if let (Some(window), Some(now), Some(timestamp)) = (window, now, t.timestamp) {
+            let expired = timestamp.saturating_add(window);
+            if now > expired {
+                return Err(Error::DisputeWindowExpired { tx: t.tx, expired });
+            }
+        }
+
+        // Only `deposit` and `transfer` transactions can be disputed with the standard policy,
+        // unless withdrawal disputes have been enabled. Disputing an already disputed
+        // transaction again requires an amount, to tell it apart from a duplicate dispute.
+        if (t.disputed && partial.is_none()) || !policy.is_disputable(&t) {
+            return Err(Error::InvalidDispute { tx: t.tx });
+        }
+
+        let disputable = t.disputable_amount();
+        let amount = partial.unwrap_or(disputable);
+
+        if amount > disputable {
+            return Err(Error::InvalidAmount { amount });
+        }
+
+        let client = t.holder();
+        let opened = !t.disputed;
+
+        let a = a.check_frozen_mut(client, allow_frozen)?;
+        let mut balance = a.balance_mut(t.currency());
+        // The funds of a disputed withdrawal have already left the account, so they are held
+        // on top of the available funds. Disputing a deposit may leave the available funds
+        // negative (when some of it has been withdrawn already), which is up to the overdraft
+        // policy. Transfers are disputed like deposits into the receiving account.
+        let mut overdrawn = false;
+        if t.type_ == Type::Withdrawal {
+            balance.try_increase_held(client, amount)?;
+        } else {
+            if overdraft_policy == OverdraftPolicy::Reject {
+                balance.try_hold_to(client, amount, Decimal::ZERO)?;
+            } else {
+                overdrawn = balance.available() < amount;
+                balance.hold(amount);
+            }
+        }
+        if overdrawn && overdraft_policy == OverdraftPolicy::Restrict {
+            a.mark_under_dispute();
+        }
+        // Partial disputes of the same transaction count as a single open dispute.
+        if opened {
+            a.open_dispute();
+        }
+
+        if record_history {
+            a.record_op(Type::Dispute, t.tx, amount, t.currency());
+        }
+
+        if opened {
+            t.disputes += 1;
+        }
+        t.disputed = true;
+        t.held += amount;
+        let tx = t.tx;
+        self.store_transaction(t);
+        self.store_account(client);
+        self.notify(client, |o, a| o.on_dispute(client, tx, amount, a));
+
+        Ok(())
+    }
+
+    // Handles a `resolve` transaction. Whether `resolve` and `chargeback` operations for
+    // disputes that happened before an account got frozen can still go through depends on
+    // the dispute policy (only resolves are allowed by default). The disputed amount is
+    // resolved in full, unless the resolve row specifies a lesser amount; the rest of it
+    // stays under dispute then.
+    pub(super) fn process_resolve(&mut self, transaction: Transaction) -> Result<()> {
+        let policy = self.dispute_policy();
+        let allow_frozen = policy.allowed_when_frozen(Type::Resolve);
+        let record_history = self.config.record_history;
+        let partial = match transaction.amount {
+            Some(_) => Some(transaction.validated_amount(&self.config)?),
+            None => None,
+        };
+        let (mut t, a) = self.transaction_mut(&transaction)?;
+
+        if !t.disputed {
+            return Err(Error::InvalidResolve { tx: t.tx });
+        }
+
+        let amount = partial.unwrap_or(t.held);
+
+        if amount > t.held {
+            return Err(Error::InvalidAmount { amount });
+        }
+
+        let client = t.holder();
+        let a = a.check_frozen_mut(client, allow_frozen)?;
+        // A resolved withdrawal stands, so its funds don't return to the account.
+        a.balance_mut(t.currency()).try_settle_held(
+            client,
+            amount,
+            t.released_on(Type::Resolve, amount),
+        )?;
+        t.held -= amount;
+        let settled = t.held.is_zero();
+        if settled {
+            a.settle_dispute();
+        }
+
+        a.record_resolved(t.currency(), amount);
+        if record_history {
+            a.record_op(Type::Resolve, t.tx, amount, t.currency());
+        }
+
+        // Transactions can only be disputed once by default, so the resolved portion cannot
+        // be disputed again. Once nothing is left to dispute, the transaction is removed from
+        // the current history.
+        let redispute = policy.allows_redispute(&t);
+        if !redispute {
+            t.settled += amount;
+        }
+        t.disputed = !settled;
+        let tx = t.tx;
+        if t.disputed || redispute || !t.disputable_amount().is_zero() {
+            self.store_transaction(t);
+        } else {
+            self.remove_transaction(tx);
+        }
+        self.store_account(client);
+        self.notify(client, |o, a| o.on_resolve(client, tx, amount, a));
+
+        Ok(())
+    }
+
+    // Handles a `chargeback` transaction. The disputed amount is charged back in full, unless
+    // the chargeback row specifies a lesser amount; only that portion is charged back then,
+    // while the remainder is returned from held to available funds. Portions of the
+    // transaction which haven't been disputed are not affected. Charging back a
+    // withdrawal returns the charged back portion to available funds instead, while the
+    // remainder of the withdrawal stands. Charging back a transfer returns the charged back
+    // portion to the sender.
+    pub(super) fn process_chargeback(&mut self, transaction: Transaction) -> Result<()> {
+        let allow_frozen = self.dispute_policy().allowed_when_frozen(Type::Chargeback);
+        let freeze = self.config.freeze_on_chargeback;
+        let record_history = self.config.record_history;
+        let partial = match transaction.amount {
+            Some(_) => Some(transaction.validated_amount(&self.config)?),
+            None => None,
+        };
+        let (t, _) = self.transaction_mut(&transaction)?;
+
+        if !t.disputed {
+            return Err(Error::InvalidChargeback { tx: t.tx });
+        }
+
+        let disputed = t.held;
+        let amount = partial.unwrap_or(disputed);
+
+        if amount > disputed {
+            return Err(Error::InvalidAmount { amount });
+        }
+
+        // The portion which isn't charged back is resolved instead.
+        let returned = t.released_on(Type::Chargeback, amount)
+            + t.released_on(Type::Resolve, disputed - amount);
+
+        // Checked before any funds move, so the chargeback doesn't go through halfway.
+        if t.type_ == Type::Transfer {
+            if let Some(sender) = self.client_accounts.get(&t.client) {
+                sender
+                    .balance(t.currency())
+                    .clone()
+                    .try_increase_available(t.client, amount)?;
+            }
+        }
+
+        let client = t.holder();
+        let a = self
+            .account_mut(client)
+            .check_frozen_mut(client, allow_frozen)?;
+        a.balance_mut(t.currency())
+            .try_settle_held(client, disputed, returned)?;
+        a.settle_dispute().mark_charged_back();
+
+        // Freezes the whole account, not just the balance in the disputed currency.
+        let frozen = freeze && !a.frozen();
+        if frozen {
+            a.freeze();
+        }
+
+        a.record_chargeback(t.currency(), amount);
+        if record_history {
+            a.record_op(Type::Chargeback, t.tx, amount, t.currency());
+        }
+
+        if t.type_ == Type::Transfer {
+            let sender = self.account_mut(t.client);
+            sender.balance_mut(t.currency()).increase_available(amount);
+            self.store_account(t.client);
+        }
+
+        let tx = t.tx;
+        self.remove_transaction(tx);
+        self.chargebacks.insert(
+            tx,
+            Transaction {
+                amount: Some(amount),
+                disputed: false,
+                held: Decimal::ZERO,
+                settled: Decimal::ZERO,
+                disputes: 0,
+                ..t
+            },
+        );
+        self.store_account(client);
+        self.notify(client, |o, a| {
+            o.on_chargeback(client, tx, amount, a);
+            if frozen {
+                o.on_freeze(client, a);
+            }
+        });
+
+        Ok(())
+    }
+
+    // Handles a `chargeback_reversal` transaction, which undoes the chargeback of the referenced
+    // transaction: the charged back amount is credited to the holder of the funds again (or
+    // debited, for withdrawals, whose chargebacks credited it), and taken back from the sender
+    // of a transfer. Frozen accounts don't block the reversal, and get unfrozen when enabled
+    // via `with_unlock_on_reversal`, unless another chargeback of the account still stands.
+    // The transaction doesn't return to the history, so it cannot be disputed again.
+    pub(super) fn process_chargeback_reversal(&mut self, transaction: Transaction) -> Result<()> {
+        let tx = transaction.tx;
+        let t = match self.chargebacks.get(&tx) {
+            Some(t) => t.clone(),
+            None if self.is_stored(tx) => return Err(Error::InvalidChargebackReversal { tx }),
+            None => return Err(Error::TransactionNotFound { tx }),
+        };
+
+        Self::check_reference(&transaction, &t)?;
+        self.check_lifecycle_order(&transaction, &t)?;
+        // The sender of a transfer may belong to a different shard.
+        if t.type_ == Type::Transfer && self.config.transfers_disabled {
+            return Err(Error::InvalidTransfer { tx });
+        }
+
+        let (client, sender) = (t.holder(), t.client);
+        // Checking both parties upfront, so nothing changes when either is rejected.
+        for party in [client, sender] {
+            self.client_accounts
+                .get_mut(&party)
+                .ok_or(Error::AccountNotFound { client: party })?
+                .check_frozen_mut(party, true)?;
+        }
+
+        let amount = t.amount();
+        let currency = t.currency();
+        match t.type_ {
+            Type::Withdrawal => {
+                self.account_mut(client)
+                    .balance_mut(currency)
+                    .try_decrease_available(client, amount)?;
+            }
+            Type::Transfer => {
+                // Checked before debiting the sender, same as for transfers.
+                self.account_mut(client)
+                    .balance(currency)
+                    .clone()
+                    .try_increase_available(client, amount)?;
+                let a = self.account_mut(sender);
+                a.balance_mut(currency)
+                    .try_decrease_available(sender, amount)?;
+                self.account_mut(client)
+                    .balance_mut(currency)
+                    .increase_available(amount);
+            }
+            _ => {
+                self.account_mut(client)
+                    .balance_mut(currency)
+                    .try_increase_available(client, amount)?;
+            }
+        }
+        self.chargebacks.remove(&tx);
+
+        let unlock = self.config.unlock_on_reversal
+            && !self
+                .chargebacks
+                .values()
+                .any(|other| other.holder() == client);
+        let record_history = self.config.record_history;
+        let a = self.account_mut(client);
+        let unfrozen = unlock && a.frozen();
+        if unfrozen {
+            a.unfreeze();
+        }
+
+        if record_history {
+            a.record_op(Type::ChargebackReversal, tx, amount, currency);
+        }
+
+        if sender != client {
+            self.store_account(sender);
+        }
+        self.store_account(client);
+        self.notify(client, |o, a| {
+            o.on_chargeback_reversal(client, tx, amount, a);
+            if unfrozen {
+                o.on_unfreeze(client, a);
+            }
+        });
+
+        Ok(())
+    }
+}
@@ -0,0 +1,234 @@
+//! The policies which decide what the engine does with transactions that it can't process
+//! the usual way: ones for frozen accounts, or with unusual amounts, clients, or timestamps,
+//! and what happens to the funds of accounts being closed.
+
+use std::ops::RangeInclusive;
+
+use serde::Deserialize;
+
+use super::Type;
+use crate::id::ClientId;
+
+/// Determines which types of transactions can still go through once an account is frozen.
+/// By default, inbound deposits still get credited and previously opened disputes can be
+/// resolved, while withdrawals, new disputes, and chargebacks are blocked.
+#[derive(Clone, Copy, Debug)]
+pub struct FrozenPolicy {
+    pub(super) deposit: bool,
+    pub(super) withdrawal: bool,
+    pub(super) dispute: bool,
+    pub(super) resolve: bool,
+    pub(super) chargeback: bool,
+    // Applies to both parties of a transfer.
+    pub(super) transfer: bool,
+    pub(super) hold: bool,
+}
+
+impl FrozenPolicy {
+    /// A policy which blocks all types of transactions for frozen accounts.
+    pub fn block_all() -> Self {
+        FrozenPolicy {
+            deposit: false,
+            withdrawal: false,
+            dispute: false,
+            resolve: false,
+            chargeback: false,
+            transfer: false,
+            hold: false,
+        }
+    }
+
+    /// Whether transactions of the given type are allowed for frozen accounts.
+    pub fn allows(&self, type_: Type) -> bool {
+        match type_ {
+            Type::Deposit => self.deposit,
+            Type::Withdrawal => self.withdrawal,
+            Type::Dispute => self.dispute,
+            Type::Resolve => self.resolve,
+            Type::Chargeback => self.chargeback,
+            Type::Transfer => self.transfer,
+            Type::Hold => self.hold,
+            // Chargeback reversals mostly apply to the accounts frozen by the chargeback, and
+            // voids only undo what went through before.
+            Type::ChargebackReversal
+            | Type::Void
+            | Type::Approve
+            | Type::Close
+            | Type::Reopen
+            | Type::Review
+            | Type::Unlock => true,
+        }
+    }
+
+    /// Allows (or blocks) transactions of the given type for frozen accounts.
+    pub fn allow(mut self, type_: Type, allowed: bool) -> Self {
+        match type_ {
+            Type::Deposit => self.deposit = allowed,
+            Type::Withdrawal => self.withdrawal = allowed,
+            Type::Dispute => self.dispute = allowed,
+            Type::Resolve => self.resolve = allowed,
+            Type::Chargeback => self.chargeback = allowed,
+            Type::Transfer => self.transfer = allowed,
+            Type::Hold => self.hold = allowed,
+            // Administrative operations (and chargeback reversals and voids) are never blocked
+            // by the frozen status.
+            Type::ChargebackReversal
+            | Type::Void
+            | Type::Approve
+            | Type::Close
+            | Type::Reopen
+            | Type::Review
+            | Type::Unlock => {}
+        }
+        self
+    }
+}
+
+/// Which parts of the dispute lifecycle still go through for frozen accounts, as a shorthand
+/// for the `dispute`, `resolve`, and `chargeback` types of a `FrozenPolicy` (see
+/// `FrozenPolicy::with_disputes`). New disputes are blocked either way, unless allowed via
+/// `FrozenPolicy::allow` afterwards.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum FrozenDisputes {
+    /// Disputes opened before the account got frozen stay open until it's unlocked.
+    Block,
+    /// Disputes opened before the account got frozen can still be resolved, but not charged
+    /// back.
+    #[default]
+    ResolveOnly,
+    /// Disputes opened before the account got frozen can still be resolved or charged back.
+    Settle,
+}
+
+impl FrozenPolicy {
+    /// Allows (or blocks) the dispute-related types for frozen accounts as given by
+    /// `disputes`, leaving the other types as they are.
+    pub fn with_disputes(self, disputes: FrozenDisputes) -> Self {
+        let (resolve, chargeback) = match disputes {
+            FrozenDisputes::Block => (false, false),
+            FrozenDisputes::ResolveOnly => (true, false),
+            FrozenDisputes::Settle => (true, true),
+        };
+        self.allow(Type::Dispute, false)
+            .allow(Type::Resolve, resolve)
+            .allow(Type::Chargeback, chargeback)
+    }
+}
+
+impl Default for FrozenPolicy {
+    fn default() -> Self {
+        FrozenPolicy::block_all()
+            .allow(Type::Deposit, true)
+            .allow(Type::Resolve, true)
+    }
+}
+
+/// Whether the engine checks the order of the transaction timestamps, i.e. to audit upstream
+/// feeds. Transactions without a timestamp are never checked.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum TimeOrdering {
+    /// Timestamps can come in any order.
+    #[default]
+    Unchecked,
+    /// Disputes, resolves, chargebacks, and chargeback reversals are rejected with
+    /// `Error::OutOfOrder` if their timestamp is before the one of the referenced transaction
+    /// (i.e. a dispute from before the deposit it refers to).
+    PerTransaction,
+    /// On top of the checks of `PerTransaction`, transactions are rejected if their timestamp
+    /// is before the one of the latest transaction that went through for the same client
+    /// (the `client` of the row).
+    PerClient,
+}
+
+/// The clients the engine processes transactions for (see
+/// `TransactionEngine::with_client_filter`): those in any of the included ranges (or every
+/// client, if there are none), except for those in any of the excluded ranges.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ClientFilter {
+    included: Vec<RangeInclusive<ClientId>>,
+    excluded: Vec<RangeInclusive<ClientId>>,
+}
+
+impl ClientFilter {
+    /// A filter which allows every client, until ranges get included or excluded.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allows the clients in `clients` (along with the ones of the other included ranges).
+    pub fn include(mut self, clients: RangeInclusive<ClientId>) -> Self {
+        self.included.push(clients);
+        self
+    }
+
+    /// Disallows the clients in `clients`, even if they're in an included range.
+    pub fn exclude(mut self, clients: RangeInclusive<ClientId>) -> Self {
+        self.excluded.push(clients);
+        self
+    }
+
+    pub fn allows(&self, client: ClientId) -> bool {
+        let included = self.included.is_empty()
+            || self
+                .included
+                .iter()
+                .any(|clients| clients.contains(&client));
+        included
+            && !self
+                .excluded
+                .iter()
+                .any(|clients| clients.contains(&client))
+    }
+}
+
+impl From<RangeInclusive<ClientId>> for ClientFilter {
+    fn from(clients: RangeInclusive<ClientId>) -> Self {
+        ClientFilter::new().include(clients)
+    }
+}
+
+/// What happens to deposits and withdrawals with a negative amount.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum NegativeAmountPolicy {
+    /// They're rejected with `Error::InvalidAmount`.
+    #[default]
+    Reject,
+    /// The sign decides the type instead, for feeds with a single signed amount column: a
+    /// negative amount is a withdrawal (of the absolute amount), and a positive one a deposit,
+    /// regardless of the type of the row.
+    Signed,
+}
+
+/// What happens to deposits and withdrawals with an amount of zero, which don't change any
+/// balance. Ones without an amount at all are always rejected with `Error::MissingAmount`.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ZeroAmountPolicy {
+    /// They're rejected with `Error::ZeroAmount`.
+    #[default]
+    Reject,
+    /// They're processed as no-ops, and end up in the transaction history like any other.
+    Accept,
+    /// They're logged (with a `Warning::ZeroAmountSkipped`) and skipped, without being
+    /// rejected or added to the history. Amounts which only round to zero (see
+    /// `TransactionEngine::with_amount_rounding`) are still rejected.
+    Skip,
+}
+
+/// What happens when an account gets closed (see `TransactionEngine::with_admin_ops`) while it
+/// still holds funds.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ResidualBalancePolicy {
+    /// The account is closed with its funds left in place, and they're reported as before.
+    #[default]
+    Retain,
+    /// Closing is rejected with `Error::ResidualBalance` unless all of the balances are zero.
+    Reject,
+    /// The available funds are paid out, i.e. withdrawn in full and reported as a `Payout`
+    /// (see `TransactionEngine::drain_payouts`). Accounts with held funds or a negative
+    /// balance can't be settled like that, so closing them is rejected with
+    /// `Error::ResidualBalance`.
+    PayOut,
+}
@@ -0,0 +1,261 @@
+//! The account report, as CSV or a table, and the records it consists of.
+
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+use super::{OpenDisputes, TransactionEngine};
+use crate::account::{Account, AccountColumn, AccountRecord};
+use crate::id::ClientId;
+use crate::statement;
+
+// The columns `with_dispute_columns` adds to the account report, in order.
+const DISPUTE_COLUMNS: [AccountColumn; 6] = [
+    AccountColumn::OpenDisputes,
+    AccountColumn::DisputedAmount,
+    AccountColumn::ResolvedDisputes,
+    AccountColumn::ResolvedAmount,
+    AccountColumn::Chargebacks,
+    AccountColumn::ChargebackAmount,
+];
+
+impl TransactionEngine {
+    /// Writes the resulting account data as CSV rows to `w`, sorted by client id (so the
+    /// output is the same from run to run). The `currency` column is only present in
+    /// multi-asset mode (i.e. when any funds are held in an explicit currency), in which case
+    /// there's one row per client and currency.
+    pub fn write_accounts<W: Write>(&self, w: W) -> csv::Result<()> {
+        let mut writer = csv::Writer::from_writer(w);
+
+        // The header is normally derived from the first record, so it has to be written
+        // explicitly when there are no accounts at all.
+        let records = self.account_records();
+        if records.is_empty() {
+            let mut header = vec!["client", "available", "held", "total", "locked"];
+            if self.config.admin_ops {
+                header.push("status");
+            }
+            if self.risk.is_some() {
+                header.push("risk_score");
+            }
+            if self.overdrafts_enabled() {
+                header.push("overdraft");
+            }
+            if self.config.metadata_columns {
+                header.extend(["name", "reference", "tags"]);
+            }
+            if self.config.dispute_columns {
+                header.extend(DISPUTE_COLUMNS.iter().map(|column| column.name()));
+            }
+            writer.write_record(header)?;
+        }
+
+        for record in records {
+            writer.serialize(record)?;
+        }
+
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// The columns `write_accounts` writes: `currency` in multi-asset mode, `status` with the
+    /// administrative transaction types, `risk_score` with risk rules, `overdraft` with
+    /// overdraft lines, `name`, `reference`, and `tags` with metadata columns, and the dispute
+    /// statistics with dispute columns, besides the columns that are always there.
+    pub fn account_columns(&self) -> Vec<AccountColumn> {
+        let mut columns = vec![AccountColumn::Client];
+        if self.is_multi_asset() {
+            columns.push(AccountColumn::Currency);
+        }
+        columns.extend([
+            AccountColumn::Available,
+            AccountColumn::Held,
+            AccountColumn::Total,
+            AccountColumn::Locked,
+        ]);
+        if self.config.admin_ops {
+            columns.push(AccountColumn::Status);
+        }
+        if self.risk.is_some() {
+            columns.push(AccountColumn::RiskScore);
+        }
+        if self.overdrafts_enabled() {
+            columns.push(AccountColumn::Overdraft);
+        }
+        if self.config.metadata_columns {
+            columns.extend([
+                AccountColumn::Name,
+                AccountColumn::Reference,
+                AccountColumn::Tags,
+            ]);
+        }
+        if self.config.dispute_columns {
+            columns.extend(DISPUTE_COLUMNS);
+        }
+        columns
+    }
+
+    /// Same as `write_accounts`, but with only the given `columns`, in the given order.
+    /// Columns the report doesn't have (i.e. `currency` outside of multi-asset mode) are left
+    /// empty.
+    pub fn write_accounts_with<W: Write>(
+        &self,
+        w: W,
+        columns: &[AccountColumn],
+    ) -> csv::Result<()> {
+        let mut writer = csv::Writer::from_writer(w);
+        writer.write_record(columns.iter().map(|column| column.name()))?;
+        for record in self.account_records() {
+            writer.write_record(
+                columns
+                    .iter()
+                    .map(|&column| record.field(column).unwrap_or_default()),
+            )?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Writes the account report as a table with aligned columns (i.e. for reading it on a
+    /// terminal), with the given `columns`, in the given order.
+    pub fn write_accounts_table<W: Write>(
+        &self,
+        mut w: W,
+        columns: &[AccountColumn],
+    ) -> io::Result<()> {
+        let header = columns.iter().map(|column| column.name().to_uppercase());
+        let mut rows = vec![header.collect::<Vec<_>>()];
+        for record in self.account_records() {
+            rows.push(
+                columns
+                    .iter()
+                    .map(|&column| record.field(column).unwrap_or_default())
+                    .collect(),
+            );
+        }
+        // Amounts and scores are right-aligned.
+        let align: Vec<_> = columns
+            .iter()
+            .map(|column| {
+                matches!(
+                    column,
+                    AccountColumn::Available
+                        | AccountColumn::Held
+                        | AccountColumn::Total
+                        | AccountColumn::RiskScore
+                        | AccountColumn::Overdraft
+                        | AccountColumn::OpenDisputes
+                        | AccountColumn::DisputedAmount
+                        | AccountColumn::ResolvedDisputes
+                        | AccountColumn::ResolvedAmount
+                        | AccountColumn::Chargebacks
+                        | AccountColumn::ChargebackAmount
+                )
+            })
+            .collect();
+        statement::write_aligned(&mut w, &rows, &align)?;
+        w.flush()
+    }
+
+    /// The rows of the account report (one per client and currency, ordered by client id), as
+    /// written by `write_accounts`. Accounts of clients outside of the client filter (see
+    /// `with_client_filter`) are left out.
+    pub fn account_records(&self) -> Vec<AccountRecord<'_>> {
+        let multi_asset = self.is_multi_asset();
+        let mut accounts: Vec<_> = self
+            .client_accounts
+            .iter()
+            .filter(|(&client, _)| self.client_allowed(client))
+            .collect();
+        accounts.sort_unstable_by_key(|(&client, _)| client);
+
+        let mut disputes = self.open_dispute_totals();
+        accounts
+            .into_iter()
+            .flat_map(|(&client, account)| {
+                let open = disputes
+                    .as_mut()
+                    .map(|d| d.remove(&client).unwrap_or_default());
+                self.records_of(client, account, multi_asset, open)
+            })
+            .collect()
+    }
+
+    /// Same as `account_records`, but only for the account of `client` (so there are no rows
+    /// for clients without an account).
+    pub fn client_account_records(&self, client: ClientId) -> Vec<AccountRecord<'_>> {
+        let multi_asset = self.is_multi_asset();
+        let open = self
+            .open_dispute_totals()
+            .map(|mut d| d.remove(&client).unwrap_or_default());
+        self.client_accounts
+            .get(&client)
+            .into_iter()
+            .flat_map(|account| self.records_of(client, account, multi_asset, open.clone()))
+            .collect()
+    }
+
+    // The `currency` column is only reported if any of the accounts holds funds in an
+    // explicit currency, so the rows have the same shape throughout.
+    fn is_multi_asset(&self) -> bool {
+        self.client_accounts
+            .iter()
+            .any(|(&client, account)| self.client_allowed(client) && account.is_multi_asset())
+    }
+
+    // The number of open disputes and the funds they hold, by client and currency, for the
+    // dispute columns (if enabled).
+    fn open_dispute_totals(&self) -> Option<HashMap<ClientId, OpenDisputes>> {
+        if !self.config.dispute_columns {
+            return None;
+        }
+        let mut totals: HashMap<ClientId, OpenDisputes> = HashMap::new();
+        for dispute in self.open_disputes.values() {
+            let (count, amount) = totals
+                .entry(dispute.client)
+                .or_default()
+                .entry(dispute.currency.clone())
+                .or_default();
+            *count += 1;
+            *amount += dispute.amount;
+        }
+        Some(totals)
+    }
+
+    fn records_of<'a>(
+        &self,
+        client: ClientId,
+        account: &'a Account,
+        multi_asset: bool,
+        open: Option<OpenDisputes>,
+    ) -> impl Iterator<Item = AccountRecord<'a>> {
+        let score = self
+            .risk
+            .as_ref()
+            .map(|risk| risk.rules().score(account.risk_flags()));
+        // Accounts can't be closed or put under review without the administrative types.
+        let status = Some(account.status()).filter(|_| self.config.admin_ops);
+        let overdrafts = self.overdrafts_enabled();
+        let metadata = self.config.metadata_columns;
+        account.balances().map(move |(code, balance)| {
+            let currency = if multi_asset { Some(code) } else { None };
+            let mut record = AccountRecord::new(client, currency, balance, account);
+            if let Some(status) = status {
+                record = record.with_status(status);
+            }
+            if let Some(score) = score {
+                record = record.with_risk_score(score);
+            }
+            if overdrafts {
+                record = record.with_overdraft();
+            }
+            if metadata {
+                record = record.with_metadata(account.metadata());
+            }
+            if let Some(open) = &open {
+                let (count, amount) = open.get(code).copied().unwrap_or_default();
+                record = record.with_dispute_stats(count, amount, &account.dispute_stats(code));
+            }
+            record
+        })
+    }
+}
@@ -0,0 +1,201 @@
+//! Business rules which transactions have to pass before they're applied, on top of the ones
+//! built into the engine (i.e. limits imposed by risk teams). Validators are registered via
+//! `TransactionEngine::with_validator`, and run in the order they were registered, so the
+//! first one that rejects a transaction decides the error it's rejected with (see
+//! `Validator::error`). The velocity limits of `limits::WindowLimits` are validators as well.
+
+use std::collections::{HashMap, VecDeque};
+
+use rust_decimal::Decimal;
+
+use crate::amount::Amount;
+use crate::transaction::{ClientId, Error, Transaction, Type, Warning};
+
+const SECONDS_PER_DAY: u64 = 86_400;
+
+/// A rule for the transactions the engine processes. Validators only see transactions which
+/// passed the checks that don't depend on the account (i.e. the client filter), and get told
+/// about the ones which went through, to keep track of limits that span transactions.
+pub trait Validator {
+    /// The name the validator is referred to by in `Error::ValidationFailed`.
+    fn name(&self) -> &'static str;
+
+    /// Whether `transaction` may be applied.
+    fn validate(&self, transaction: &Transaction) -> bool;
+
+    /// The error `transaction` gets rejected with once `validate` refused it, which is
+    /// `Error::ValidationFailed` (naming the validator) unless the validator has a more
+    /// specific one.
+    fn error(&self, transaction: &Transaction) -> Error {
+        Error::ValidationFailed {
+            tx: transaction.tx(),
+            validator: self.name(),
+        }
+    }
+
+    /// Invoked once `transaction` has been applied.
+    fn applied(&mut self, _transaction: &Transaction) {}
+
+    /// Invoked once `transaction` has been applied (ahead of `applied`), for validators which
+    /// point out something about it, i.e. that it escaped their rule. The warning is handed
+    /// out via `TransactionEngine::drain_warnings`.
+    fn warning(&mut self, _transaction: &Transaction) -> Option<Warning> {
+        None
+    }
+
+    /// Invoked by `TransactionEngine::reset`, to forget about the transactions seen so far.
+    fn reset(&mut self) {}
+}
+
+// Transactions which move funds into or out of an account, as opposed to disputes and the
+// administrative operations (which don't have an amount of their own).
+fn moves_funds(transaction: &Transaction) -> bool {
+    matches!(
+        transaction.type_(),
+        Type::Deposit | Type::Withdrawal | Type::Transfer
+    )
+}
+
+/// Rejects deposits, withdrawals, and transfers of more than the given amount, with
+/// `Error::ExcessiveAmount`. Guards against absurd values (i.e. a deposit of 10^28) as well,
+/// which would take the funds of an account beyond what a `Decimal` can represent; credits
+/// that would are rejected with `Error::Overflow` regardless.
+#[derive(Clone, Copy, Debug)]
+pub struct MaxAmount(pub Decimal);
+
+impl Validator for MaxAmount {
+    fn name(&self) -> &'static str {
+        "max_amount"
+    }
+
+    fn validate(&self, transaction: &Transaction) -> bool {
+        !moves_funds(transaction) || transaction.amount() <= self.0
+    }
+
+    fn error(&self, transaction: &Transaction) -> Error {
+        Error::ExcessiveAmount {
+            amount: transaction.amount(),
+            max: self.0,
+        }
+    }
+}
+
+/// Rejects withdrawals (and outgoing transfers) which would take the funds a client moved out
+/// of their account within a day beyond the limit. Days are calendar days in UTC, based on
+/// the timestamp of the transactions, while transactions without one count towards the day
+/// of the latest timestamp of the client.
+#[derive(Clone, Debug)]
+pub struct MaxDailyWithdrawal {
+    limit: Decimal,
+    // The current day of each client, and how much they withdrew that day.
+    withdrawn: HashMap<ClientId, (Option<u64>, Decimal)>,
+}
+
+impl MaxDailyWithdrawal {
+    pub fn new(limit: Amount) -> Self {
+        MaxDailyWithdrawal {
+            limit: limit.get(),
+            withdrawn: HashMap::new(),
+        }
+    }
+
+    // How much the client of `transaction` withdrew on the day of the transaction so far.
+    fn withdrawn_on(&self, transaction: &Transaction) -> (Option<u64>, Decimal) {
+        let day = transaction.timestamp().map(|t| t / SECONDS_PER_DAY);
+        match self.withdrawn.get(&transaction.client()) {
+            Some(&(current, withdrawn)) if day.is_none() || day == current => (current, withdrawn),
+            _ => (day, Decimal::ZERO),
+        }
+    }
+}
+
+impl Validator for MaxDailyWithdrawal {
+    fn name(&self) -> &'static str {
+        "max_daily_withdrawal"
+    }
+
+    fn validate(&self, transaction: &Transaction) -> bool {
+        if !matches!(transaction.type_(), Type::Withdrawal | Type::Transfer) {
+            return true;
+        }
+        let (_, withdrawn) = self.withdrawn_on(transaction);
+        withdrawn + transaction.amount() <= self.limit
+    }
+
+    fn applied(&mut self, transaction: &Transaction) {
+        if matches!(transaction.type_(), Type::Withdrawal | Type::Transfer) {
+            let (day, withdrawn) = self.withdrawn_on(transaction);
+            self.withdrawn.insert(
+                transaction.client(),
+                (day, withdrawn + transaction.amount()),
+            );
+        }
+    }
+
+    fn reset(&mut self) {
+        self.withdrawn.clear();
+    }
+}
+
+/// Rejects deposits, withdrawals, and transfers once a client has had `max` of them within
+/// the last `window` seconds (i.e. to catch scripted abuse). Only applies to transactions
+/// with a timestamp, since there's no notion of time otherwise.
+#[derive(Clone, Debug)]
+pub struct Velocity {
+    max: usize,
+    window: u64,
+    // The timestamps of the recent transactions of each client, oldest first.
+    recent: HashMap<ClientId, VecDeque<u64>>,
+}
+
+impl Velocity {
+    pub fn new(max: usize, window: u64) -> Self {
+        Velocity {
+            max,
+            window,
+            recent: HashMap::new(),
+        }
+    }
+}
+
+impl Validator for Velocity {
+    fn name(&self) -> &'static str {
+        "velocity"
+    }
+
+    fn validate(&self, transaction: &Transaction) -> bool {
+        let timestamp = match transaction.timestamp() {
+            Some(timestamp) if moves_funds(transaction) => timestamp,
+            _ => return true,
+        };
+        let recent = self.recent.get(&transaction.client()).map_or(0, |recent| {
+            recent
+                .iter()
+                .filter(|&&t| timestamp.saturating_sub(t) < self.window)
+                .count()
+        });
+        recent < self.max
+    }
+
+    fn applied(&mut self, transaction: &Transaction) {
+        let timestamp = match transaction.timestamp() {
+            Some(timestamp) if moves_funds(transaction) => timestamp,
+            _ => return,
+        };
+        let window = self.window;
+        let recent = self.recent.entry(transaction.client()).or_default();
+        // Dropping the timestamps which fell out of the window of the latest transaction, so
+        // only transactions that arrive out of order may see fewer than there were.
+        while recent
+            .front()
+            .is_some_and(|&t| timestamp.saturating_sub(t) >= window)
+        {
+            recent.pop_front();
+        }
+        recent.push_back(timestamp);
+    }
+
+    fn reset(&mut self) {
+        self.recent.clear();
+    }
+}
@@ -0,0 +1,197 @@
+//! The write-ahead log behind `TransactionEngine::recover`. Every accepted transaction is
+//! appended to the log as a JSON object on its own line (along with a sequence number) before
+//! it's applied, and the engine state is written to a snapshot next to the log every so often
+//! (see `snapshot_path`), after which the log starts over. Recovering means loading the last
+//! snapshot, and replaying the entries which aren't part of it yet.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::transaction::Transaction;
+
+/// Where the snapshots for the log at `wal_path` are kept, which is the same path with a
+/// `.snapshot` suffix.
+pub fn snapshot_path(wal_path: &Path) -> PathBuf {
+    let mut path = wal_path.as_os_str().to_owned();
+    path.push(".snapshot");
+    PathBuf::from(path)
+}
+
+#[derive(Serialize)]
+struct Entry<'a> {
+    seq: u64,
+    transaction: &'a Transaction,
+}
+
+#[derive(Deserialize)]
+struct OwnedEntry {
+    seq: u64,
+    transaction: Transaction,
+}
+
+// The sequence number of the first entry that's not part of the snapshot is stored along with
+// the state, so entries that are still in the log (i.e. because of a crash right after
+// writing the snapshot) don't get replayed twice.
+#[derive(Deserialize, Serialize)]
+struct SnapshotFile<S> {
+    next_seq: u64,
+    state: S,
+}
+
+pub(crate) struct Wal {
+    file: File,
+    path: PathBuf,
+    // The length of the log up to the end of the last entry, and up to the end of the one
+    // before (so the last entry can be discarded again).
+    len: u64,
+    previous_len: u64,
+    // Keeps counting across snapshots.
+    next_seq: u64,
+    // Number of entries written since the last snapshot.
+    pending: u64,
+    // Sync every entry to disk, instead of only handing it to the OS (which survives crashes
+    // of the process, but not of the machine).
+    sync: bool,
+}
+
+impl Wal {
+    /// Opens the log at `path`, which gets created if it doesn't exist yet.
+    pub fn open(path: &Path, sync: bool) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+        let len = file.metadata()?.len();
+        Ok(Wal {
+            file,
+            path: path.to_owned(),
+            len,
+            previous_len: len,
+            next_seq: 0,
+            pending: 0,
+            sync,
+        })
+    }
+
+    /// Returns the state of the last snapshot, if there's been one.
+    pub fn load_snapshot<S: DeserializeOwned>(&mut self) -> io::Result<Option<S>> {
+        let file = match File::open(snapshot_path(&self.path)) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let snapshot: SnapshotFile<S> = serde_json::from_reader(BufReader::new(file))?;
+        self.next_seq = snapshot.next_seq;
+        Ok(Some(snapshot.state))
+    }
+
+    /// Hands every entry written since the last snapshot to `apply`, in order, stopping at the
+    /// first error. A partially written last entry (i.e. because of a crash halfway through)
+    /// is cut off.
+    pub fn replay<F>(&mut self, mut apply: F) -> io::Result<()>
+    where
+        F: FnMut(Transaction) -> io::Result<()>,
+    {
+        let mut reader = BufReader::new(File::open(&self.path)?);
+        let mut line = String::new();
+        let mut offset = 0;
+
+        loop {
+            line.clear();
+            let read = reader.read_line(&mut line)? as u64;
+            if read == 0 {
+                break;
+            }
+
+            let complete = line.ends_with('\n');
+            let entry = match serde_json::from_str::<OwnedEntry>(&line) {
+                Ok(entry) if complete => entry,
+                // Only the last entry can be incomplete.
+                _ if offset + read == self.len => {
+                    self.file.set_len(offset)?;
+                    self.len = offset;
+                    break;
+                }
+                Ok(_) => {
+                    let message = "Incomplete entry in the write-ahead log";
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, message));
+                }
+                Err(e) => return Err(e.into()),
+            };
+            offset += read;
+
+            if entry.seq >= self.next_seq {
+                self.next_seq = entry.seq + 1;
+                self.pending += 1;
+                apply(entry.transaction)?;
+            }
+        }
+
+        self.previous_len = self.len;
+        Ok(())
+    }
+
+    pub fn append(&mut self, transaction: &Transaction) -> io::Result<()> {
+        let entry = Entry {
+            seq: self.next_seq,
+            transaction,
+        };
+        let mut line = serde_json::to_vec(&entry)?;
+        line.push(b'\n');
+
+        // Written in one go, since the file isn't buffered.
+        self.file.write_all(&line)?;
+        if self.sync {
+            self.file.sync_data()?;
+        }
+
+        self.previous_len = self.len;
+        self.len += line.len() as u64;
+        self.next_seq += 1;
+        self.pending += 1;
+        Ok(())
+    }
+
+    /// Removes the entry written last (i.e. for a transaction that got rejected).
+    pub fn discard_last(&mut self) -> io::Result<()> {
+        self.file.set_len(self.previous_len)?;
+        self.len = self.previous_len;
+        self.next_seq -= 1;
+        self.pending -= 1;
+        Ok(())
+    }
+
+    /// Whether at least `interval` entries have been written since the last snapshot (a zero
+    /// `interval` disables snapshots).
+    pub fn snapshot_due(&self, interval: u64) -> bool {
+        interval > 0 && self.pending >= interval
+    }
+
+    /// Replaces the snapshot with `state`, and empties the log. The snapshot is written to a
+    /// temporary file first, so there's always a complete one on disk.
+    pub fn write_snapshot<S: Serialize>(&mut self, state: &S) -> io::Result<()> {
+        let path = snapshot_path(&self.path);
+        let mut temporary = path.clone().into_os_string();
+        temporary.push(".tmp");
+
+        let mut writer = BufWriter::new(File::create(&temporary)?);
+        let snapshot = SnapshotFile {
+            next_seq: self.next_seq,
+            state,
+        };
+        serde_json::to_writer(&mut writer, &snapshot)?;
+        writer.into_inner()?.sync_all()?;
+        fs::rename(&temporary, &path)?;
+
+        self.file.set_len(0)?;
+        self.len = 0;
+        self.previous_len = 0;
+        self.pending = 0;
+        Ok(())
+    }
+}
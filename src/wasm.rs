@@ -0,0 +1,58 @@
+//! JavaScript bindings for running the engine in the browser (i.e. to simulate settlement in
+//! a web UI), built with `--features wasm` for the `wasm32-unknown-unknown` target and
+//! packaged via `wasm-bindgen` (or `wasm-pack`). Transactions are plain objects with the same
+//! fields as the JSON Lines input:
+//!
+//! ```js
+//! const engine = new TransactionEngine();
+//! engine.processTransaction({ type: "deposit", client: 1, tx: 1, amount: "2.5" });
+//! const accounts = JSON.parse(engine.accountsJson());
+//! ```
+
+use wasm_bindgen::prelude::*;
+
+use crate::input::parse_json_transaction;
+use crate::transaction::TransactionEngine;
+
+/// The engine, as exposed to JavaScript (under the name `TransactionEngine`).
+#[wasm_bindgen(js_name = TransactionEngine)]
+pub struct WasmEngine {
+    engine: TransactionEngine,
+}
+
+#[wasm_bindgen(js_class = TransactionEngine)]
+impl WasmEngine {
+    /// Creates an engine with the default configuration.
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> WasmEngine {
+        WasmEngine {
+            engine: TransactionEngine::new(),
+        }
+    }
+
+    /// Processes a single transaction, and throws an `Error` with the reason if it's invalid
+    /// or gets rejected (which leaves the state unchanged).
+    #[wasm_bindgen(js_name = processTransaction)]
+    pub fn process_transaction(&mut self, transaction: &JsValue) -> Result<(), JsError> {
+        // Going through JSON, so amounts are parsed the same way as for JSON Lines input.
+        let json = js_sys::JSON::stringify(transaction)
+            .map_err(|_| JsError::new("Invalid transaction: not serializable as JSON"))?;
+        let transaction = parse_json_transaction(&String::from(json))
+            .map_err(|e| JsError::new(&format!("Invalid transaction: {}", e)))?;
+        self.engine.process_transaction(transaction)?;
+        Ok(())
+    }
+
+    /// The account report (see `TransactionEngine::account_records`), as a JSON array.
+    #[wasm_bindgen(js_name = accountsJson)]
+    pub fn accounts_json(&self) -> String {
+        serde_json::to_string(&self.engine.account_records())
+            .expect("Account records are always serializable")
+    }
+}
+
+impl Default for WasmEngine {
+    fn default() -> Self {
+        WasmEngine::new()
+    }
+}
@@ -0,0 +1,1019 @@
+//! Tests of the `te` binary, run against files in a scratch directory.
+
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Output, Stdio};
+
+use tempfile::TempDir;
+
+// A scratch directory for the files of a test, which `te` runs in.
+struct Scratch(TempDir);
+
+impl Scratch {
+    fn new() -> Self {
+        Scratch(tempfile::tempdir().expect("Unable to create a scratch directory"))
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.0.path().join(name)
+    }
+
+    fn write(&self, name: &str, contents: &str) -> PathBuf {
+        let path = self.path(name);
+        fs::write(&path, contents).expect("Unable to write a scratch file");
+        path
+    }
+
+    fn read(&self, name: &str) -> String {
+        fs::read_to_string(self.path(name)).expect("Unable to read a scratch file")
+    }
+
+    fn te(&self, args: &[&str]) -> Output {
+        self.command(args)
+            .env_remove("RUST_LOG")
+            .output()
+            .expect("Unable to run `te`")
+    }
+
+    // Runs `te` with logging enabled at the `warn` level.
+    #[cfg(feature = "logging")]
+    fn te_logged(&self, args: &[&str]) -> Output {
+        self.command(args)
+            .env("RUST_LOG", "warn")
+            .output()
+            .expect("Unable to run `te`")
+    }
+
+    // Runs `te`, passing `input` on stdin.
+    fn te_stdin(&self, args: &[&str], input: &str) -> Output {
+        let mut child = self
+            .command(args)
+            .env_remove("RUST_LOG")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .expect("Unable to run `te`");
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(input.as_bytes())
+            .expect("Unable to write to stdin");
+        child.wait_with_output().expect("Unable to run `te`")
+    }
+
+    // A `te` command run in the scratch directory, with saved states left unencrypted.
+    fn command(&self, args: &[&str]) -> Command {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_te"));
+        command
+            .args(args)
+            .current_dir(self.0.path())
+            .env_remove("TE_STATE_KEY");
+        command
+    }
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+// The account report on the stdout of `output`, sorted by line, as the order of the
+// accounts isn't specified.
+fn accounts(output: &Output) -> Vec<String> {
+    let mut lines: Vec<String> = stdout(output).lines().map(String::from).collect();
+    lines.sort();
+    lines
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+// The `status` of every event of an audit stream.
+fn statuses(audit: &str) -> Vec<&str> {
+    audit
+        .lines()
+        .filter_map(|line| line.strip_prefix(r#"{"status":""#))
+        .map(|rest| rest.split('"').next().unwrap())
+        .collect()
+}
+
+#[test]
+fn audit_stream_is_written_alongside_the_accounts() {
+    let dir = Scratch::new();
+    dir.write(
+        "input.csv",
+        "type,client,tx,amount\ndeposit,1,1,10\nwithdrawal,1,2,15\ndeposit,x,3,1\n",
+    );
+    let expected_audit = [
+        r#"{"status":"applied","type":"deposit","client":1,"tx":1,"amount":"10"}"#,
+        r#"{"status":"rejected","type":"withdrawal","client":1,"tx":2,"amount":"15","error":"InsufficientFunds"}"#,
+    ];
+    let expected_accounts = "client,available,held,total,locked\n1,10,0,10,false\n";
+
+    let output = dir.te(&["input.csv", "--output", "accounts.csv", "--audit", "stdout"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let audit = stdout(&output);
+    let lines: Vec<&str> = audit.lines().collect();
+    assert_eq!(lines.len(), 3);
+    assert_eq!(lines[..2], expected_audit);
+    assert!(lines[2].starts_with(r#"{"status":"invalid","line":4,"error":"#));
+    assert_eq!(dir.read("accounts.csv"), expected_accounts);
+
+    // With the audit stream on stderr, the accounts stay on stdout.
+    let output = dir.te(&["input.csv", "--audit", "stderr"]);
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), expected_accounts);
+    assert_eq!(
+        stderr(&output).lines().take(2).collect::<Vec<_>>(),
+        expected_audit
+    );
+}
+
+#[test]
+fn resuming_from_a_checkpoint_matches_an_uninterrupted_run() {
+    let dir = Scratch::new();
+    let header = "type,client,tx,amount\n";
+    let first = "deposit,1,1,10\ndeposit,2,2,5\ndeposit,1,3,2.5\ndispute,1,1,\n\
+                 withdrawal,2,4,1\ndispute,2,2,\n";
+    let second = "resolve,1,1,\nwithdrawal,1,5,3\nchargeback,2,2,\ndeposit,3,6,7\n\
+                  dispute,1,3,\n";
+    dir.write("first.csv", &format!("{}{}", header, first));
+    dir.write("second.csv", &format!("{}{}", header, second));
+    dir.write("all.csv", &format!("{}{}{}", header, first, second));
+
+    let uninterrupted = dir.te(&["all.csv"]);
+    assert!(uninterrupted.status.success());
+
+    let output = dir.te(&["first.csv", "--save-checkpoint", "state.json"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let resumed = dir.te(&["second.csv", "--load-checkpoint", "state.json"]);
+    assert!(resumed.status.success(), "{}", stderr(&resumed));
+
+    assert_eq!(accounts(&resumed), accounts(&uninterrupted));
+}
+
+#[test]
+fn inconsistent_checkpoints_are_rejected() {
+    let dir = Scratch::new();
+    dir.write("input.csv", "type,client,tx,amount\ndeposit,1,1,10\n");
+    let output = dir.te(&["input.csv", "--save-checkpoint", "state.json"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let state = dir.read("state.json");
+    assert!(dir
+        .te(&["input.csv", "--load-checkpoint", "state.json"])
+        .status
+        .success());
+
+    // Negative held funds, even with the total left as it was.
+    let edited = state.replace(
+        r#""available":"10","held":"0""#,
+        r#""available":"11","held":"-1""#,
+    );
+    assert_ne!(edited, state);
+    dir.write("state.json", &edited);
+    let output = dir.te(&["input.csv", "--load-checkpoint", "state.json"]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("inconsistent engine state"));
+
+    // Held funds without any disputed transaction.
+    let edited = state.replace(
+        r#""available":"10","held":"0""#,
+        r#""available":"5","held":"5""#,
+    );
+    dir.write("state.json", &edited);
+    let output = dir.te(&["input.csv", "--load-checkpoint", "state.json"]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("inconsistent engine state"));
+}
+
+#[test]
+fn frozen_accounts_take_deposits_but_reject_withdrawals_by_default() {
+    let dir = Scratch::new();
+    dir.write(
+        "input.csv",
+        "type,client,tx,amount\ndeposit,1,1,10\ndeposit,1,2,5\ndispute,1,2,\n\
+         chargeback,1,2,\ndeposit,1,3,3\nwithdrawal,1,4,1\n",
+    );
+    let deposit = r#""type":"deposit","client":1,"tx":3,"amount":"3""#;
+    let withdrawal = r#""type":"withdrawal","client":1,"tx":4,"amount":"1""#;
+
+    let output = dir.te(&["input.csv", "--audit", "stderr"]);
+    assert!(output.status.success());
+    let audit = stderr(&output);
+    assert!(audit.contains(&format!(r#"{{"status":"applied",{}}}"#, deposit)));
+    assert!(audit.contains(&format!(
+        r#"{{"status":"rejected",{},"error":"AccountFrozen"}}"#,
+        withdrawal
+    )));
+    assert_eq!(
+        stdout(&output),
+        "client,available,held,total,locked\n1,13,0,13,true\n"
+    );
+
+    // Blocking everything rejects the deposit as well.
+    let output = dir.te(&["input.csv", "--audit", "stderr", "--allow-frozen", ""]);
+    assert!(output.status.success());
+    let audit = stderr(&output);
+    assert!(audit.contains(&format!(
+        r#"{{"status":"rejected",{},"error":"AccountFrozen"}}"#,
+        deposit
+    )));
+    assert_eq!(
+        stdout(&output),
+        "client,available,held,total,locked\n1,10,0,10,true\n"
+    );
+}
+
+#[test]
+fn amounts_with_more_than_four_decimal_places_are_rejected() {
+    let dir = Scratch::new();
+    dir.write("input.csv", "type,client,tx,amount\ndeposit,1,1,1.00001\n");
+    let output = dir.te(&["input.csv", "--audit", "stderr"]);
+    assert!(stderr(&output).contains(r#""error":"ExcessivePrecision""#));
+    // The rejected deposit doesn't open an account.
+    assert_eq!(stdout(&output), "client,available,held,total,locked\n");
+
+    // Trailing zeros don't count towards the precision.
+    dir.write(
+        "input.csv",
+        "type,client,tx,amount\ndeposit,1,1,1.00001\ndeposit,1,2,1.0001\n\
+         deposit,1,3,1.000100\nwithdrawal,1,4,0.00001\n",
+    );
+    let output = dir.te(&["input.csv", "--audit", "stderr"]);
+    let audit = stderr(&output);
+    assert_eq!(
+        statuses(&audit),
+        ["rejected", "applied", "applied", "rejected"]
+    );
+    assert!(audit
+        .lines()
+        .last()
+        .unwrap()
+        .contains(r#""error":"ExcessivePrecision""#));
+    assert_eq!(
+        stdout(&output),
+        "client,available,held,total,locked\n1,2.0002,0,2.0002,false\n"
+    );
+}
+
+#[test]
+fn signed_amounts_decide_between_deposits_and_withdrawals() {
+    let dir = Scratch::new();
+    // Whatever the type says, a positive amount is a deposit and a negative one a
+    // withdrawal: 10 + 2 - 3 - 1.5.
+    dir.write(
+        "input.csv",
+        "type,client,tx,amount\ndeposit,1,1,10\ndeposit,1,2,-3\nwithdrawal,1,3,2\n\
+         withdrawal,1,4,-1.5\nwithdrawal,1,5,-100\n",
+    );
+    let output = dir.te(&["input.csv", "--signed-amounts", "--audit", "stderr"]);
+    assert!(output.status.success());
+    let audit = stderr(&output);
+    assert_eq!(
+        statuses(&audit),
+        ["applied", "applied", "applied", "applied", "rejected"]
+    );
+    assert!(audit
+        .lines()
+        .last()
+        .unwrap()
+        .contains(r#""error":"InsufficientFunds""#));
+    assert_eq!(
+        stdout(&output),
+        "client,available,held,total,locked\n1,7.5,0,7.5,false\n"
+    );
+
+    // Without the option, negative amounts are invalid.
+    let output = dir.te(&["input.csv", "--audit", "stderr"]);
+    assert_eq!(
+        statuses(&stderr(&output)),
+        ["applied", "rejected", "applied", "rejected", "rejected"]
+    );
+}
+
+#[test]
+fn statements_list_the_transactions_with_running_balances() {
+    let dir = Scratch::new();
+    dir.write(
+        "input.csv",
+        "type,client,tx,amount\ndeposit,1,1,10\ndeposit,2,2,4\nwithdrawal,1,3,3\n\
+         deposit,1,4,2\ndispute,1,1,\n",
+    );
+    let output = dir.te(&["input.csv", "--statement", "1"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        stdout(&output),
+        "type,tx,amount,disputed,balance\ndeposit,1,10,true,10\nwithdrawal,3,3,false,7\n\
+         deposit,4,2,false,9\n"
+    );
+
+    let output = dir.te(&[
+        "input.csv",
+        "--statement",
+        "1",
+        "--statement-format",
+        "json",
+    ]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(
+        stdout(&output).contains(r#"],"available":"-1","held":"10","total":"9","locked":false"#)
+    );
+}
+
+#[test]
+fn history_lists_the_retained_transactions_with_every_column() {
+    let dir = Scratch::new();
+    dir.write(
+        "input.csv",
+        "type,client,tx,amount,currency,timestamp\ndeposit,1,1,10,,100\ndeposit,1,2,5,USD,\n\
+         deposit,2,3,4,,\n",
+    );
+    let output = dir.te(&["input.csv", "--save-checkpoint", "state.json"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+
+    let output = dir.te(&["history", "1", "state.json"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        stdout(&output),
+        "type,client,tx,amount,currency,to,timestamp,expires,tenant,memo,reference,seq\n\
+         deposit,1,1,10,,,100,,,,,\ndeposit,1,2,5,USD,,,,,,,\n"
+    );
+
+    let output = dir.te(&["history", "3", "state.json"]);
+    assert!(!output.status.success());
+}
+
+#[test]
+fn interactive_reference_queries_keep_the_columns_aligned() {
+    let dir = Scratch::new();
+    dir.write(
+        "input.csv",
+        "type,client,tx,amount,memo,reference\ndeposit,1,1,10,,abc\ndeposit,1,2,5,rent,abc\n",
+    );
+    let output = dir.te(&["input.csv", "--save-checkpoint", "state.json"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+
+    let output = dir.te_stdin(
+        &["inspect", "state.json", "--interactive"],
+        "reference abc\nquit\n",
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stdout(&output).contains(
+        "type,client,tx,amount,currency,to,timestamp,expires,tenant,memo,reference,seq\n\
+         deposit,1,1,10,,,,,,,abc,\ndeposit,1,2,5,,,,,,rent,abc,\n"
+    ));
+}
+
+#[test]
+fn exit_codes() {
+    let dir = Scratch::new();
+    dir.write("valid.csv", "type,client,tx,amount\ndeposit,1,1,10\n");
+    dir.write(
+        "rejected.csv",
+        "type,client,tx,amount\ndeposit,1,1,10\nwithdrawal,1,2,20\n",
+    );
+    dir.write(
+        "invalid.csv",
+        "type,client,tx,amount\ndeposit,1,1,10\nrefund,1,2,5\n",
+    );
+    dir.write(
+        "gap.csv",
+        "type,client,tx,amount,seq\ndeposit,1,1,10,1\ndeposit,1,2,10,3\n",
+    );
+    let code = |args: &[&str]| dir.te(args).status.code();
+
+    assert_eq!(code(&["valid.csv"]), Some(0));
+    // Without `--strict`, rejected transactions and invalid records don't fail the run.
+    assert_eq!(code(&["rejected.csv"]), Some(0));
+    assert_eq!(code(&["invalid.csv"]), Some(0));
+
+    assert_eq!(code(&["valid.csv", "--strict"]), Some(0));
+    assert_eq!(code(&["rejected.csv", "--strict"]), Some(2));
+    assert_eq!(code(&["invalid.csv", "--strict"]), Some(3));
+    assert_eq!(code(&["missing.csv"]), Some(4));
+
+    assert_eq!(
+        code(&["valid.csv", "--save-checkpoint", "valid.json"]),
+        Some(0)
+    );
+    assert_eq!(
+        code(&["rejected.csv", "--save-checkpoint", "rejected.json"]),
+        Some(0)
+    );
+    assert_eq!(code(&["diff", "valid.json", "valid.json"]), Some(0));
+    // The rejected withdrawal left the account as it was.
+    assert_eq!(code(&["diff", "valid.json", "rejected.json"]), Some(0));
+    dir.write(
+        "other.csv",
+        "type,client,tx,amount\ndeposit,1,1,10\ndeposit,2,2,1\n",
+    );
+    assert_eq!(
+        code(&["other.csv", "--save-checkpoint", "other.json"]),
+        Some(0)
+    );
+    assert_eq!(code(&["diff", "valid.json", "other.json"]), Some(5));
+    assert_eq!(code(&["repair", "valid.json"]), Some(0));
+    let state = dir.read("valid.json");
+    assert!(state.contains(r#""total":"10""#));
+    dir.write(
+        "edited.json",
+        &state.replace(r#""total":"10""#, r#""total":"11""#),
+    );
+    assert_eq!(code(&["repair", "edited.json"]), Some(5));
+
+    assert_eq!(
+        code(&["valid.csv", "--save-checkpoint", "missing/state.json"]),
+        Some(1)
+    );
+    assert_eq!(code(&["valid.csv", "--no-such-flag"]), Some(6));
+    let help = dir.te(&["--help"]);
+    assert_eq!(help.status.code(), Some(0));
+    assert!(stdout(&help).contains("Exit status:"));
+
+    assert_eq!(
+        code(&["valid.csv", "--load-checkpoint", "edited.json"]),
+        Some(7)
+    );
+
+    assert_eq!(code(&["gap.csv", "--check-sequence"]), Some(0));
+    assert_eq!(code(&["gap.csv", "--check-sequence", "--strict"]), Some(8));
+}
+
+#[test]
+fn held_funds_are_compared_to_disputes_within_the_epsilon() {
+    let dir = Scratch::new();
+    dir.write(
+        "input.csv",
+        "type,client,tx,amount\ndeposit,1,1,50.0\ndispute,1,1,\n",
+    );
+    dir.write("empty.csv", "type,client,tx,amount\n");
+    let output = dir.te(&["input.csv", "--save-checkpoint", "state.json"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let state = dir.read("state.json");
+    let saved = r#""available":"0.0","held":"50.0","total":"50.0""#;
+    assert!(state.contains(saved));
+    // Loads the checkpoint with `held` (and the total) edited.
+    let load = |held: &str, args: &[&str]| {
+        let edited = format!(r#""available":"0.0","held":"{0}","total":"{0}""#, held);
+        dir.write("edited.json", &state.replace(saved, &edited));
+        let mut all = vec!["empty.csv", "--load-checkpoint", "edited.json"];
+        all.extend_from_slice(args);
+        dir.te(&all).status.success()
+    };
+
+    // Differences in scale alone are no mismatch.
+    assert!(load("50.0000", &[]));
+    assert!(!load("50.001", &[]));
+    assert!(load("50.001", &["--amount-epsilon", "0.001"]));
+    assert!(!load("50.002", &["--amount-epsilon", "0.001"]));
+}
+
+#[test]
+fn header_less_input_matches_input_with_a_header() {
+    let dir = Scratch::new();
+    let rows = "deposit,1,1,10\ndeposit,2,2,5\nwithdrawal,1,3,4\ndispute,2,2\n";
+    dir.write("header.csv", &format!("type,client,tx,amount\n{}", rows));
+    dir.write("no-header.csv", rows);
+
+    let with_header = dir.te(&["header.csv"]);
+    let without_header = dir.te(&["no-header.csv", "--no-headers"]);
+    assert!(
+        without_header.status.success(),
+        "{}",
+        stderr(&without_header)
+    );
+    assert_eq!(accounts(&without_header), accounts(&with_header));
+    assert_eq!(accounts(&with_header).len(), 3);
+}
+
+#[test]
+fn only_records_after_the_resume_point_are_applied() {
+    let dir = Scratch::new();
+    let header = "type,client,tx,amount\n";
+    let done = "deposit,1,1,10\ndeposit,2,2,5\nwithdrawal,1,3,4\n";
+    let rest = "deposit,1,4,1\ndispute,2,2,\n";
+    dir.write("done.csv", &format!("{}{}", header, done));
+    dir.write("all.csv", &format!("{}{}{}", header, done, rest));
+
+    let output = dir.te(&["done.csv", "--save-checkpoint", "state.json"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let resumed = dir.te(&[
+        "all.csv",
+        "--load-checkpoint",
+        "state.json",
+        "--resume-after",
+        "3",
+        "--audit",
+        "stderr",
+    ]);
+    assert!(resumed.status.success(), "{}", stderr(&resumed));
+    // Only the two records after the resume point show up in the audit stream.
+    let audit = stderr(&resumed);
+    assert_eq!(statuses(&audit), ["applied", "applied"]);
+    assert!(audit.lines().next().unwrap().contains(r#""tx":4"#));
+    assert_eq!(
+        accounts(&resumed),
+        [
+            "1,7,0,7,false",
+            "2,0,5,5,false",
+            "client,available,held,total,locked"
+        ]
+    );
+}
+
+#[test]
+fn records_up_to_the_resume_timestamp_are_skipped() {
+    let dir = Scratch::new();
+    dir.write(
+        "input.csv",
+        "type,client,tx,amount,timestamp\ndeposit,1,1,10,100\ndeposit,1,2,5,200\n\
+         withdrawal,1,3,4,\ndeposit,1,4,1,300\nwithdrawal,1,5,0.5,\n",
+    );
+    let output = dir.te(&["input.csv", "--resume-after", "time:200"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    // Processing picks up at the first record after the timestamp, which leaves the
+    // withdrawal without a timestamp in between behind as well.
+    assert_eq!(
+        stdout(&output),
+        "client,available,held,total,locked\n1,0.5,0,0.5,false\n"
+    );
+
+    // Getting to the timestamp without anything after it leaves nothing to process.
+    let output = dir.te(&["input.csv", "--resume-after", "time:300"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+}
+
+#[test]
+fn resume_points_missing_from_the_input_are_an_error() {
+    let dir = Scratch::new();
+    dir.write(
+        "input.csv",
+        "type,client,tx,amount,timestamp\ndeposit,1,1,10,100\ndeposit,1,2,5,200\n",
+    );
+    for point in ["3", "time:250"] {
+        let output = dir.te(&["input.csv", "--resume-after", point]);
+        assert_eq!(output.status.code(), Some(4), "{}", stderr(&output));
+        assert!(stdout(&output).is_empty());
+        assert!(stderr(&output).contains("the input ended before"));
+    }
+}
+
+#[test]
+fn accounts_are_written_in_client_order() {
+    let dir = Scratch::new();
+    dir.write(
+        "input.csv",
+        "type,client,tx,amount\ndeposit,3,1,1\ndeposit,10,2,2\ndeposit,1,3,3\n\
+         deposit,2,4,4\ndeposit,30,5,5\n",
+    );
+    let expected = "client,available,held,total,locked\n1,3,0,3,false\n2,4,0,4,false\n\
+                    3,1,0,1,false\n10,2,0,2,false\n30,5,0,5,false\n";
+    // Numerically, and the same way on every run (and with every number of threads).
+    for args in [&["input.csv"][..], &["input.csv", "--threads", "3"]] {
+        let output = dir.te(args);
+        assert!(output.status.success(), "{}", stderr(&output));
+        assert_eq!(stdout(&output), expected);
+    }
+}
+
+#[test]
+fn inputs_over_the_size_cap_are_rejected() {
+    let dir = Scratch::new();
+    let input = "type,client,tx,amount\ndeposit,1,1,10\ndeposit,1,2,5\n";
+    dir.write("input.csv", input);
+    let size = input.len().to_string();
+
+    let output = dir.te(&["input.csv", "--max-input-bytes", &size]);
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+
+    let limit = (input.len() - 1).to_string();
+    let output = dir.te(&["input.csv", "--max-input-bytes", &limit]);
+    assert_eq!(output.status.code(), Some(4));
+    assert!(stdout(&output).is_empty());
+    assert!(stderr(&output).contains(&format!("exceeds the maximum size of {} bytes", limit)));
+}
+
+#[cfg(feature = "logging")]
+#[test]
+fn overdrafts_log_a_warning() {
+    let dir = Scratch::new();
+    dir.write(
+        "input.csv",
+        "type,client,tx,amount\ndeposit,1,1,10\nwithdrawal,1,2,5\n",
+    );
+    let output = dir.te_logged(&["input.csv"]);
+    assert!(output.status.success());
+    assert!(stderr(&output).is_empty(), "{}", stderr(&output));
+
+    dir.write(
+        "input.csv",
+        "type,client,tx,amount\ndeposit,1,1,10\nwithdrawal,1,2,5\nwithdrawal,1,3,20\n",
+    );
+    let output = dir.te_logged(&["input.csv"]);
+    let logs = stderr(&output);
+    assert_eq!(logs.lines().count(), 1, "{}", logs);
+    assert!(logs.contains("WARN"), "{}", logs);
+    assert!(logs.contains("Rejected transaction"), "{}", logs);
+    assert!(logs.contains("client=1"), "{}", logs);
+    assert!(logs.contains("tx=3"), "{}", logs);
+    assert!(logs.contains("error=\"InsufficientFunds\""), "{}", logs);
+
+    // Logging stays off unless enabled.
+    assert!(stderr(&dir.te(&["input.csv"])).is_empty());
+}
+
+#[test]
+fn max_transactions_stops_after_the_limit() {
+    let dir = Scratch::new();
+    // The rejected withdrawal counts towards the limit as well.
+    let mut input = String::from("type,client,tx,amount\ndeposit,1,1,5\nwithdrawal,2,2,1\n");
+    for tx in 3..=10 {
+        input.push_str(&format!("deposit,{},{},1\n", tx, tx));
+    }
+    dir.write("input.csv", &input);
+
+    let output = dir.te(&["input.csv", "--max-transactions", "3", "--summary"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        accounts(&output),
+        [
+            "1,5,0,5,false",
+            "2,0,0,0,false",
+            "3,1,0,1,false",
+            "client,available,held,total,locked"
+        ]
+    );
+    let summary = stderr(&output);
+    assert!(summary.contains("records: 3\n"), "{}", summary);
+    assert!(summary.contains("applied: 2\n"), "{}", summary);
+    assert!(summary.contains("rejected: 1\n"), "{}", summary);
+    assert!(summary.contains("limit reached: true"), "{}", summary);
+
+    // Input that fits into the limit doesn't reach it.
+    let output = dir.te(&["input.csv", "--max-transactions", "10", "--summary"]);
+    assert!(stderr(&output).contains("limit reached: false"));
+}
+
+#[test]
+fn clients_seen_only_in_rejected_rows_can_get_an_account() {
+    let dir = Scratch::new();
+    // The deposit of client 2 is invalid, as its amount is negative.
+    dir.write(
+        "input.csv",
+        "type,client,tx,amount\ndeposit,1,1,10\ndeposit,2,2,-5\n",
+    );
+
+    let output = dir.te(&["input.csv"]);
+    assert_eq!(
+        accounts(&output),
+        ["1,10,0,10,false", "client,available,held,total,locked"]
+    );
+
+    let output = dir.te(&["input.csv", "--track-all-clients"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        accounts(&output),
+        [
+            "1,10,0,10,false",
+            "2,0,0,0,false",
+            "client,available,held,total,locked"
+        ]
+    );
+}
+
+#[test]
+fn ids_beyond_the_original_ranges_are_accepted() {
+    let dir = Scratch::new();
+    dir.write(
+        "input.csv",
+        "type,client,tx,amount\ndeposit,70000,5000000000,10\n\
+         deposit,70000,5000000001,5\nwithdrawal,70000,5000000002,4\n\
+         dispute,70000,5000000001,\n",
+    );
+    let output = dir.te(&["input.csv", "--strict"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        stdout(&output),
+        "client,available,held,total,locked\n70000,6,5,11,false\n"
+    );
+}
+
+#[test]
+fn summaries_break_down_the_rejections_by_reason() {
+    let dir = Scratch::new();
+    dir.write(
+        "input.csv",
+        "type,client,tx,amount\ndeposit,1,1,10\nwithdrawal,1,2,20\nwithdrawal,2,3,1\n\
+         dispute,1,9,\ndeposit,1,4,1.00001\nwithdrawal,1,5,30\nrefund,1,6,1\n",
+    );
+    let output = dir.te(&["input.csv", "--summary"]);
+    assert!(output.status.success());
+    let summary = stderr(&output);
+    assert!(
+        summary.contains(
+            "rejected: 5\n  ExcessivePrecision: 1\n  InsufficientFunds: 3\n  \
+             TransactionNotFound: 1\ninvalid: 1\n"
+        ),
+        "{}",
+        summary
+    );
+}
+
+#[test]
+fn max_amount_rejects_larger_transactions() {
+    let dir = Scratch::new();
+    dir.write(
+        "input.csv",
+        "type,client,tx,amount\ndeposit,1,1,100\ndeposit,1,2,100.0001\nwithdrawal,1,3,150\n",
+    );
+    let output = dir.te(&["input.csv", "--max-amount", "100", "--summary"]);
+    assert!(output.status.success());
+    let summary = stderr(&output);
+    assert!(
+        summary.contains("rejected: 2\n  ExcessiveAmount: 2\n"),
+        "{}",
+        summary
+    );
+    assert_eq!(
+        stdout(&output),
+        "client,available,held,total,locked\n1,100,0,100,false\n"
+    );
+
+    // The limit is an amount like any other, so it can't be negative.
+    let output = dir.te(&["input.csv", "--max-amount", "-1"]);
+    assert!(!output.status.success());
+}
+
+#[test]
+fn currencies_of_a_client_are_tracked_independently() {
+    let dir = Scratch::new();
+    // The EUR withdrawal fails, even though the client has enough USD.
+    dir.write(
+        "input.csv",
+        "type,client,tx,amount,currency\ndeposit,1,1,10,USD\ndeposit,1,2,5,EUR\n\
+         withdrawal,1,3,4,USD\nwithdrawal,1,4,6,EUR\ndispute,1,2,,\n",
+    );
+    let output = dir.te(&["input.csv", "--audit", "stderr"]);
+    assert!(output.status.success());
+    assert_eq!(
+        statuses(&stderr(&output)),
+        ["applied", "applied", "applied", "rejected", "applied"]
+    );
+    // The dispute holds the EUR deposit only.
+    assert_eq!(
+        accounts(&output),
+        [
+            "1,EUR,0,5,5,false",
+            "1,USD,6,0,6,false",
+            "client,currency,available,held,total,locked"
+        ]
+    );
+}
+
+#[test]
+fn reordered_columns_match_the_canonical_order() {
+    let dir = Scratch::new();
+    dir.write(
+        "canonical.csv",
+        "type,client,tx,amount\ndeposit,1,1,10\ndeposit,2,2,5\nwithdrawal,1,3,4\ndispute,2,2,\n",
+    );
+    dir.write(
+        "named.csv",
+        "amount,tx,type,client\n10,1,deposit,1\n5,2,deposit,2\n4,3,withdrawal,1\n,2,dispute,2\n",
+    );
+    dir.write(
+        "positional.csv",
+        "1,deposit,1,10\n2,deposit,2,5\n1,withdrawal,3,4\n2,dispute,2\n",
+    );
+    let expected = accounts(&dir.te(&["canonical.csv"]));
+    assert_eq!(expected.len(), 3);
+
+    let named = dir.te(&["named.csv"]);
+    assert!(named.status.success(), "{}", stderr(&named));
+    assert_eq!(accounts(&named), expected);
+    let positional = dir.te(&["positional.csv", "--columns", "client,type,tx,amount"]);
+    assert!(positional.status.success(), "{}", stderr(&positional));
+    assert_eq!(accounts(&positional), expected);
+
+    // Headers without one of the required columns are rejected.
+    dir.write("missing.csv", "type,tx,amount\ndeposit,1,10\n");
+    assert_eq!(dir.te(&["missing.csv"]).status.code(), Some(4));
+}
+
+#[test]
+fn short_rows_and_extra_trailing_fields_are_accepted() {
+    let dir = Scratch::new();
+    dir.write(
+        "exact.csv",
+        "type,client,tx,amount\ndeposit,1,1,10\ndeposit,1,2,5\ndispute,1,2,\n",
+    );
+    // A dispute without the amount field, and stray trailing fields.
+    dir.write(
+        "ragged.csv",
+        "type,client,tx,amount\ndeposit,1,1,10,\ndeposit,1,2,5,x,y\ndispute,1,2\n",
+    );
+    dir.write(
+        "ragged-no-headers.csv",
+        "deposit,1,1,10,,extra\ndeposit,1,2,5,,\ndispute,1,2\n",
+    );
+    let expected = dir.te(&["exact.csv"]);
+    assert_eq!(
+        stdout(&expected),
+        "client,available,held,total,locked\n1,10,5,15,false\n"
+    );
+
+    let output = dir.te(&["ragged.csv", "--strict"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), stdout(&expected));
+    let output = dir.te(&["ragged-no-headers.csv", "--no-headers", "--strict"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), stdout(&expected));
+
+    // Without a header row, trailing fields aren't taken for the optional columns either.
+    dir.write("currency-no-headers.csv", "deposit,1,1,10,x\n");
+    let output = dir.te(&["currency-no-headers.csv", "--no-headers", "--strict"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(
+        stdout(&output),
+        "client,available,held,total,locked\n1,10,0,10,false\n"
+    );
+
+    // Rows need the `type`, `client`, and `tx` fields though.
+    dir.write(
+        "short.csv",
+        "type,client,tx,amount\ndeposit,1,1,10\ndeposit,1\n",
+    );
+    assert_eq!(dir.te(&["short.csv", "--strict"]).status.code(), Some(3));
+}
+
+#[test]
+fn partial_chargebacks_release_the_rest() {
+    let dir = Scratch::new();
+    let disputed = "type,client,tx,amount\ndeposit,1,1,100\ndeposit,1,2,20\ndispute,1,1,\n";
+    dir.write("partial.csv", &format!("{}chargeback,1,1,40\n", disputed));
+    dir.write("full.csv", &format!("{}chargeback,1,1,\n", disputed));
+    dir.write(
+        "excessive.csv",
+        &format!("{}chargeback,1,1,101\n", disputed),
+    );
+
+    let output = dir.te(&["partial.csv"]);
+    assert_eq!(
+        stdout(&output),
+        "client,available,held,total,locked\n1,80,0,80,true\n"
+    );
+    // Without an amount, the whole disputed amount is charged back.
+    let output = dir.te(&["full.csv"]);
+    assert_eq!(
+        stdout(&output),
+        "client,available,held,total,locked\n1,20,0,20,true\n"
+    );
+    let output = dir.te(&["excessive.csv", "--audit", "stderr"]);
+    assert!(stderr(&output).contains(r#""error":"InvalidAmount""#));
+    assert_eq!(
+        stdout(&output),
+        "client,available,held,total,locked\n1,20,100,120,false\n"
+    );
+}
+
+#[test]
+fn formatting_characters_are_stripped_from_amounts() {
+    let dir = Scratch::new();
+    dir.write(
+        "input.csv",
+        "type,client,tx,amount\ndeposit,1,1,\"$1,234.56\"\nwithdrawal,1,2,$34.5\n\
+         deposit,1,3,12 EUR\n",
+    );
+
+    // The last amount still isn't a valid number once stripped.
+    let output = dir.te(&[
+        "input.csv",
+        "--amount-format-chars",
+        "$,",
+        "--audit",
+        "stderr",
+    ]);
+    let audit = stderr(&output);
+    assert_eq!(statuses(&audit), ["applied", "applied", "invalid"]);
+    assert_eq!(
+        stdout(&output),
+        "client,available,held,total,locked\n1,1200.06,0,1200.06,false\n"
+    );
+
+    // Nothing is stripped by default.
+    let output = dir.te(&["input.csv", "--audit", "stderr"]);
+    assert_eq!(
+        statuses(&stderr(&output)),
+        ["invalid", "invalid", "invalid"]
+    );
+}
+
+#[test]
+fn stdin_and_file_input_agree() {
+    let dir = Scratch::new();
+    let input = "type,client,tx,amount\ndeposit,1,1,10\ndeposit,2,2,5\ndispute,1,1,\n\
+                 withdrawal,2,3,1.5\n";
+    dir.write("input.csv", input);
+    let from_file = dir.te(&["input.csv"]);
+    assert!(from_file.status.success(), "{}", stderr(&from_file));
+    assert_eq!(
+        accounts(&from_file),
+        [
+            "1,0,10,10,false",
+            "2,3.5,0,3.5,false",
+            "client,available,held,total,locked"
+        ]
+    );
+
+    // Without a path, and with `-`.
+    for args in [&[][..], &["-"][..]] {
+        let from_stdin = dir.te_stdin(args, input);
+        assert!(from_stdin.status.success(), "{}", stderr(&from_stdin));
+        assert_eq!(accounts(&from_stdin), accounts(&from_file));
+    }
+}
+
+#[test]
+fn jsonl_input_matches_csv_input() {
+    let dir = Scratch::new();
+    dir.write(
+        "input.csv",
+        "type,client,tx,amount\ndeposit,1,1,10\ndeposit,2,2,5.25\ndispute,1,1,\n\
+         withdrawal,2,3,1.5\nresolve,1,1,\nwithdrawal,1,4,2\n",
+    );
+    dir.write(
+        "input.jsonl",
+        r#"{"type":"deposit","client":1,"tx":1,"amount":"10"}
+{"type":"deposit","client":2,"tx":2,"amount":5.25}
+{"type":"dispute","client":1,"tx":1}
+{"type":"withdrawal","client":2,"tx":3,"amount":"1.5"}
+{"type":"resolve","client":1,"tx":1,"amount":null}
+{"type":"withdrawal","client":1,"tx":4,"amount":"2"}
+"#,
+    );
+
+    let csv = dir.te(&["input.csv"]);
+    assert!(csv.status.success(), "{}", stderr(&csv));
+    let jsonl = dir.te(&["input.jsonl", "--input-format", "jsonl"]);
+    assert!(jsonl.status.success(), "{}", stderr(&jsonl));
+    assert_eq!(accounts(&jsonl), accounts(&csv));
+    assert_eq!(
+        accounts(&csv),
+        [
+            "1,8,0,8,false",
+            "2,3.75,0,3.75,false",
+            "client,available,held,total,locked"
+        ]
+    );
+}
+
+#[cfg(feature = "signed-audit")]
+#[test]
+fn verify_audit_catches_a_modified_entry() {
+    let dir = Scratch::new();
+    dir.write(
+        "input.csv",
+        "type,client,tx,amount\ndeposit,1,1,10\ndeposit,2,2,5\nwithdrawal,1,3,4\n",
+    );
+    dir.write("key", "secret\n");
+    let args = [
+        "input.csv",
+        "--signed-audit",
+        "audit.jsonl",
+        "--audit-key-file",
+        "key",
+        "--save-checkpoint",
+        "state.json",
+    ];
+    assert!(dir.te(&args).status.success());
+
+    let verify = [
+        "verify-audit",
+        "audit.jsonl",
+        "--state",
+        "state.json",
+        "--audit-key-file",
+        "key",
+    ];
+    let output = dir.te(&verify);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("verified: 3 entries"));
+
+    let log = dir.read("audit.jsonl");
+    dir.write(
+        "audit.jsonl",
+        &log.replace(r#""amount":"4""#, r#""amount":"3""#),
+    );
+    let output = dir.te(&verify);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("entry 2 of the audit log doesn't match its hash"));
+
+    // Without the key, the chain doesn't verify from the first entry on.
+    dir.write("audit.jsonl", &log);
+    let output = dir.te(&verify[..4]);
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("entry 0"));
+}
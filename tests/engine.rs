@@ -0,0 +1,993 @@
+//! Tests of the engine through the public API of the library.
+
+use std::fs;
+use std::io;
+use std::str::FromStr;
+
+use rust_decimal::Decimal;
+use te::clock::SimulatedClock;
+use te::fees::FeeSchedule;
+#[cfg(feature = "spill")]
+use te::ledger::SpillLedger;
+use te::ledger::{Ledger, MemoryLedger};
+use te::limits::{BalanceLimits, LimitKind, WindowLimit, WindowLimits};
+use te::summary::ProcessSummary;
+use te::transaction::{
+    AsOf, ClientId, FrozenDisputes, TransactionEngine, TransactionId, Warning, ZeroAmountPolicy,
+};
+use te::{Error, OverdraftPolicy, Status, Transaction, Type};
+
+// Processes `csv` (without the header row) with `engine`.
+fn process(engine: &mut TransactionEngine, csv: &str) -> ProcessSummary {
+    let input = format!("type,client,tx,amount\n{}", csv);
+    engine
+        .process_from_reader(input.as_bytes())
+        .expect("Unable to process the input")
+}
+
+// Parses `amount`, for transactions.
+fn amount(amount: &str) -> Option<Decimal> {
+    Some(Decimal::from_str(amount).expect("Invalid amount"))
+}
+
+// The account report of `engine`, as CSV (sorted by line, since the order of the accounts
+// isn't specified).
+fn accounts(engine: &TransactionEngine) -> Vec<String> {
+    let mut output = Vec::new();
+    engine
+        .write_accounts(&mut output)
+        .expect("Unable to write the accounts");
+    let mut lines: Vec<String> = String::from_utf8(output)
+        .expect("Account reports are UTF-8")
+        .lines()
+        .map(String::from)
+        .collect();
+    lines.sort();
+    lines
+}
+
+#[test]
+fn client_filters_reject_other_clients() {
+    let mut engine = TransactionEngine::new()
+        .with_client_filter(ClientId::from(10)..=ClientId::from(19))
+        .with_track_all_clients(true);
+    let summary = process(
+        &mut engine,
+        "deposit,10,1,5\ndeposit,9,2,5\ndeposit,19,3,7\ndeposit,20,4,1\n\
+         withdrawal,10,5,2\nwithdrawal,20,6,1\n",
+    );
+
+    assert_eq!((summary.applied, summary.rejected), (3, 3));
+    assert_eq!(summary.errors.get("ClientNotAllowed"), Some(&3));
+    // Not even tracking all clients opens accounts for the others.
+    assert_eq!(
+        accounts(&engine),
+        [
+            "10,3,0,3,false",
+            "19,7,0,7,false",
+            "client,available,held,total,locked"
+        ]
+    );
+}
+
+#[test]
+fn aggregates_sum_every_account_including_frozen_ones() {
+    let mut engine = TransactionEngine::new();
+    process(
+        &mut engine,
+        "deposit,1,1,10\ndeposit,1,2,2.5\ndispute,1,2,\n\
+         deposit,2,3,4\nwithdrawal,2,4,1\n\
+         deposit,3,5,7\ndispute,3,5,\nchargeback,3,5,\ndeposit,3,6,1\n",
+    );
+
+    let aggregate = engine.aggregate();
+    assert_eq!((aggregate.accounts, aggregate.frozen_accounts), (3, 1));
+    assert_eq!(aggregate.totals.len(), 1);
+    let totals = &aggregate.totals[""];
+    assert_eq!(totals.available, "14".parse().unwrap());
+    assert_eq!(totals.held, "2.5".parse().unwrap());
+    assert_eq!(totals.total, "16.5".parse().unwrap());
+}
+
+#[test]
+fn chargebacks_freeze_the_account_unless_disabled() {
+    let input = "deposit,1,1,10\ndeposit,1,2,5\ndispute,1,1,\nchargeback,1,1,\n\
+                 withdrawal,1,3,1\n";
+    for &freeze in &[true, false] {
+        let mut engine = TransactionEngine::new().with_freeze_on_chargeback(freeze);
+        let summary = process(&mut engine, input);
+        assert!(engine.is_valid());
+
+        // The withdrawal following the chargeback only goes through on an unfrozen account.
+        if freeze {
+            assert_eq!(summary.errors.get("AccountFrozen"), Some(&1));
+            assert_eq!(accounts(&engine)[0], "1,5,0,5,true");
+        } else {
+            assert_eq!(summary.rejected, 0);
+            assert_eq!(accounts(&engine)[0], "1,4,0,4,false");
+        }
+    }
+}
+
+// A transfer of `value` from `from` to `to`.
+fn transfer(from: u32, to: u32, tx: u64, value: &str) -> Transaction {
+    Transaction::new(Type::Transfer, from, tx, amount(value)).with_destination(to)
+}
+
+#[test]
+fn transfers_move_funds_between_accounts() {
+    let mut engine = TransactionEngine::new();
+    process(&mut engine, "deposit,1,1,10\n");
+    engine.process_transaction(transfer(1, 2, 2, "4")).unwrap();
+    assert_eq!(accounts(&engine)[..2], ["1,6,0,6,false", "2,4,0,4,false"]);
+
+    // Transfers go through as a whole or not at all.
+    let result = engine.process_transaction(transfer(1, 2, 3, "7"));
+    assert!(
+        matches!(result, Err(Error::InsufficientFunds { .. })),
+        "{:?}",
+        result
+    );
+    let result = engine.process_transaction(transfer(1, 1, 4, "1"));
+    assert!(
+        matches!(result, Err(Error::InvalidTransfer { .. })),
+        "{:?}",
+        result
+    );
+    assert_eq!(accounts(&engine)[..2], ["1,6,0,6,false", "2,4,0,4,false"]);
+    assert!(engine.is_valid());
+}
+
+#[test]
+fn charged_back_transfers_return_the_funds_to_the_sender() {
+    let mut engine = TransactionEngine::new();
+    process(&mut engine, "deposit,1,1,10\n");
+    engine.process_transaction(transfer(1, 2, 2, "4")).unwrap();
+
+    // Disputes of a transfer refer to the receiving end, which holds the funds.
+    let summary = process(&mut engine, "dispute,1,2,\ndispute,2,2,\n");
+    assert_eq!(summary.errors.get("ClientMismatch"), Some(&1));
+    assert_eq!(accounts(&engine)[1], "2,0,4,4,false");
+
+    process(&mut engine, "chargeback,2,2,\n");
+    assert_eq!(accounts(&engine)[..2], ["1,10,0,10,false", "2,0,0,0,true"]);
+    assert!(engine.is_valid());
+}
+
+#[test]
+fn transfer_chargebacks_need_room_for_the_funds_of_the_sender() {
+    let mut engine = TransactionEngine::new();
+    let deposit = Transaction::new(Type::Deposit, 1u32, 1u64, Some(Decimal::MAX));
+    engine.process_transaction(deposit).unwrap();
+    engine.process_transaction(transfer(1, 2, 2, "1")).unwrap();
+    process(&mut engine, "deposit,1,3,1\ndispute,2,2,\n");
+
+    // Crediting the sender would overflow, so the chargeback doesn't go through at all.
+    let summary = process(&mut engine, "chargeback,2,2,\n");
+    assert_eq!(summary.errors.get("Overflow"), Some(&1));
+    assert_eq!(
+        accounts(&engine)[..2],
+        [
+            format!("1,{},0,{},false", Decimal::MAX, Decimal::MAX),
+            "2,0,1,1,false".to_owned()
+        ]
+    );
+
+    // Once the dispute has been resolved instead, the receiver keeps the funds.
+    process(&mut engine, "resolve,2,2,\n");
+    assert_eq!(accounts(&engine)[1], "2,1,0,1,false");
+    assert!(engine.is_valid());
+}
+
+#[test]
+fn expired_holds_return_the_funds_to_available() {
+    let mut engine = TransactionEngine::new();
+    process(&mut engine, "deposit,1,1,10\n");
+    let hold = Transaction::new(Type::Hold, 1u32, 2u64, amount("4")).with_expiry(100);
+    engine.process_transaction(hold).unwrap();
+    assert_eq!(accounts(&engine)[0], "1,6,4,10,false");
+
+    assert_eq!(engine.release_expired_holds(99), 0);
+    assert_eq!(accounts(&engine)[0], "1,6,4,10,false");
+    assert_eq!(engine.release_expired_holds(100), 1);
+    assert_eq!(accounts(&engine)[0], "1,10,0,10,false");
+
+    // Transactions with a timestamp release the holds which expired before them on their own.
+    let hold = Transaction::new(Type::Hold, 1u32, 3u64, amount("3")).with_expiry(150);
+    engine.process_transaction(hold).unwrap();
+    let deposit = Transaction::new(Type::Deposit, 1u32, 4u64, amount("1")).with_timestamp(150);
+    engine.process_transaction(deposit).unwrap();
+    assert_eq!(accounts(&engine)[0], "1,11,0,11,false");
+    assert!(engine.is_valid());
+}
+
+#[test]
+fn simulated_clocks_decide_when_holds_expire() {
+    let clock = SimulatedClock::new(100);
+    let mut engine = TransactionEngine::new().with_clock(clock.clone());
+    process(&mut engine, "deposit,1,1,10\n");
+    let hold = Transaction::new(Type::Hold, 1u32, 2u64, amount("4")).with_expiry(150);
+    engine.process_transaction(hold).unwrap();
+
+    // Transactions without a timestamp are processed as of the time of the clock.
+    clock.set(149);
+    process(&mut engine, "deposit,1,3,1\n");
+    assert_eq!(accounts(&engine)[0], "1,7,4,11,false");
+    clock.set(150);
+    process(&mut engine, "deposit,1,4,1\n");
+    assert_eq!(accounts(&engine)[0], "1,12,0,12,false");
+    assert!(engine.is_valid());
+}
+
+#[test]
+fn charged_back_withdrawals_return_the_funds() {
+    let mut engine = TransactionEngine::new().with_withdrawal_disputes(true);
+    process(
+        &mut engine,
+        "deposit,1,1,10\nwithdrawal,1,2,4\ndispute,1,2,\n",
+    );
+    // The withdrawn funds are held on top of the available ones.
+    assert_eq!(accounts(&engine)[0], "1,6,4,10,false");
+
+    let summary = process(&mut engine, "chargeback,1,2,\n");
+    assert_eq!(summary.rejected, 0);
+    assert_eq!(accounts(&engine)[0], "1,10,0,10,true");
+    assert!(engine.is_valid());
+}
+
+#[test]
+fn resolved_withdrawals_stand() {
+    let mut engine = TransactionEngine::new().with_withdrawal_disputes(true);
+    process(
+        &mut engine,
+        "deposit,1,1,10\nwithdrawal,1,2,4\ndispute,1,2,\n",
+    );
+    assert_eq!(accounts(&engine)[0], "1,6,4,10,false");
+
+    let summary = process(&mut engine, "resolve,1,2,\n");
+    assert_eq!(summary.rejected, 0);
+    assert_eq!(accounts(&engine)[0], "1,6,0,6,false");
+    assert!(engine.is_valid());
+}
+
+#[test]
+fn ids_of_resolved_transactions_cannot_be_reused() {
+    let mut engine = TransactionEngine::new();
+    let summary = process(
+        &mut engine,
+        "deposit,1,1,10\ndispute,1,1,\nresolve,1,1,\ndeposit,1,1,5\n",
+    );
+    assert_eq!(summary.errors.get("DuplicateTransaction"), Some(&1));
+    assert_eq!(accounts(&engine)[0], "1,10,0,10,false");
+
+    // Not even once the engine has been restored from a snapshot.
+    let mut restored = TransactionEngine::restore(engine.snapshot()).unwrap();
+    let summary = process(&mut restored, "withdrawal,1,1,5\n");
+    assert_eq!(summary.errors.get("DuplicateTransaction"), Some(&1));
+    assert_eq!(accounts(&restored)[0], "1,10,0,10,false");
+}
+
+#[test]
+fn ids_of_charged_back_transactions_cannot_be_reused() {
+    let mut engine = TransactionEngine::new();
+    let summary = process(
+        &mut engine,
+        "deposit,1,1,10\ndispute,1,1,\nchargeback,1,1,\ndeposit,2,1,5\n",
+    );
+    assert_eq!(summary.errors.get("DuplicateTransaction"), Some(&1));
+    assert_eq!(
+        accounts(&engine)[..2],
+        ["1,0,0,0,true", "client,available,held,total,locked"]
+    );
+}
+
+#[test]
+fn disputes_of_settled_transactions_are_rejected_rather_than_deferred() {
+    let mut engine = TransactionEngine::new().with_deferred_disputes(10);
+    process(
+        &mut engine,
+        "deposit,1,1,10\ndispute,1,1,\nresolve,1,1,\n\
+         deposit,1,2,5\ndispute,1,2,\nchargeback,1,2,\n",
+    );
+
+    let summary = process(&mut engine, "dispute,1,1,\ndispute,1,2,\ndispute,1,3,\n");
+    assert_eq!(summary.errors.get("TransactionNotFound"), Some(&2));
+    // Only the dispute of the transaction which hasn't come yet is held back.
+    assert_eq!(summary.no_op, 1);
+    assert_eq!(accounts(&engine)[0], "1,10,0,10,true");
+}
+
+#[test]
+fn partial_disputes_hold_only_the_disputed_amount() {
+    let mut engine = TransactionEngine::new();
+    process(&mut engine, "deposit,1,1,10\ndispute,1,1,4\n");
+    assert_eq!(accounts(&engine)[0], "1,6,4,10,false");
+
+    // Resolving part of it leaves the rest under dispute, and more can still be disputed.
+    let summary = process(&mut engine, "resolve,1,1,1\ndispute,1,1,2\n");
+    assert_eq!(summary.rejected, 0);
+    assert_eq!(accounts(&engine)[0], "1,5,5,10,false");
+    process(&mut engine, "resolve,1,1,\n");
+    assert_eq!(accounts(&engine)[0], "1,10,0,10,false");
+    assert!(engine.is_valid());
+}
+
+#[test]
+fn disputes_beyond_the_deposit_are_rejected() {
+    let mut engine = TransactionEngine::new();
+    let summary = process(
+        &mut engine,
+        "deposit,1,1,10\ndispute,1,1,11\ndispute,1,1,6\ndispute,1,1,5\nresolve,1,1,7\n",
+    );
+    // Only 4 of the deposit are left to dispute once 6 are, and only 6 can be resolved.
+    assert_eq!(summary.errors.get("InvalidAmount"), Some(&3));
+    assert_eq!(accounts(&engine)[0], "1,4,6,10,false");
+    assert!(engine.is_valid());
+}
+
+#[test]
+fn chargeback_reversals_restore_the_funds_and_unlock_the_account() {
+    let mut engine = TransactionEngine::new().with_unlock_on_reversal(true);
+    process(
+        &mut engine,
+        "deposit,1,1,10\ndeposit,1,2,5\ndispute,1,1,\nchargeback,1,1,\n",
+    );
+    assert_eq!(accounts(&engine)[0], "1,5,0,5,true");
+
+    let summary = process(&mut engine, "chargeback_reversal,1,1,\n");
+    assert_eq!(summary.rejected, 0);
+    assert_eq!(accounts(&engine)[0], "1,15,0,15,false");
+
+    // The reversal is final, so it can't happen twice.
+    let summary = process(&mut engine, "chargeback_reversal,1,1,\n");
+    assert_eq!(summary.rejected, 1);
+    assert_eq!(accounts(&engine)[0], "1,15,0,15,false");
+    assert!(engine.is_valid());
+}
+
+#[test]
+fn only_charged_back_transactions_can_be_reversed() {
+    let mut engine = TransactionEngine::new();
+    let summary = process(
+        &mut engine,
+        "deposit,1,1,10\ndispute,1,1,\nchargeback_reversal,1,1,\nchargeback_reversal,1,9,\n",
+    );
+    assert_eq!(summary.errors.get("InvalidChargebackReversal"), Some(&1));
+    assert_eq!(summary.errors.get("TransactionNotFound"), Some(&1));
+    assert_eq!(accounts(&engine)[0], "1,0,10,10,false");
+}
+
+#[test]
+fn resolved_transactions_can_be_disputed_again_up_to_the_limit() {
+    let mut engine = TransactionEngine::new().with_max_disputes(2);
+    let summary = process(
+        &mut engine,
+        "deposit,1,1,10\ndispute,1,1,\nresolve,1,1,\ndispute,1,1,\n",
+    );
+    assert_eq!(summary.rejected, 0);
+    assert_eq!(accounts(&engine)[0], "1,0,10,10,false");
+
+    // The second resolve settles it for good, so there's nothing left to dispute.
+    let summary = process(&mut engine, "resolve,1,1,\ndispute,1,1,\n");
+    assert_eq!(summary.errors.get("TransactionNotFound"), Some(&1));
+    assert_eq!(accounts(&engine)[0], "1,10,0,10,false");
+
+    // Without re-disputes, the first resolve does.
+    let mut engine = TransactionEngine::new();
+    let summary = process(
+        &mut engine,
+        "deposit,1,1,10\ndispute,1,1,\nresolve,1,1,\ndispute,1,1,\n",
+    );
+    assert_eq!(summary.rejected, 1);
+    assert_eq!(accounts(&engine)[0], "1,10,0,10,false");
+}
+
+#[test]
+fn overdrafts_of_disputes_follow_the_overdraft_policy() {
+    let input = "deposit,1,1,10\nwithdrawal,1,2,8\ndispute,1,1,\n";
+
+    // By default, the dispute takes the available funds below zero.
+    let mut engine = TransactionEngine::new();
+    let summary = process(&mut engine, input);
+    assert_eq!(summary.rejected, 0);
+    assert_eq!(accounts(&engine)[0], "1,-8,10,2,false");
+
+    let mut engine = TransactionEngine::new().with_overdraft_policy(OverdraftPolicy::Reject);
+    let summary = process(&mut engine, input);
+    assert_eq!(summary.errors.get("InsufficientFunds"), Some(&1));
+    assert_eq!(accounts(&engine)[0], "1,2,0,2,false");
+
+    // Restricted accounts can't withdraw until the dispute has been settled.
+    let mut engine = TransactionEngine::new().with_overdraft_policy(OverdraftPolicy::Restrict);
+    let summary = process(&mut engine, input);
+    assert_eq!(summary.rejected, 0);
+    let summary = process(&mut engine, "deposit,1,3,20\nwithdrawal,1,4,1\n");
+    assert_eq!(summary.errors.get("AccountUnderDispute"), Some(&1));
+    let summary = process(&mut engine, "resolve,1,1,\nwithdrawal,1,5,1\n");
+    assert_eq!(summary.rejected, 0);
+    assert_eq!(accounts(&engine)[0], "1,21,0,21,false");
+    assert!(engine.is_valid());
+}
+
+#[test]
+fn overflowing_debits_are_rejected_without_touching_the_balances() {
+    let mut engine = TransactionEngine::new().with_balance_limits(BalanceLimits {
+        overdraft_limit: Decimal::MAX,
+        ..BalanceLimits::default()
+    });
+    let withdrawal =
+        |tx: u64, value: Decimal| Transaction::new(Type::Withdrawal, 1u32, tx, Some(value));
+    assert!(engine
+        .process_transaction(withdrawal(1, Decimal::MAX))
+        .is_ok());
+    let result = engine.process_transaction(withdrawal(2, Decimal::ONE));
+    assert!(matches!(result, Err(Error::Overflow { .. })));
+    let expected = format!(
+        "1,{},0,{},false,{}",
+        Decimal::MIN,
+        Decimal::MIN,
+        Decimal::MAX
+    );
+    assert_eq!(accounts(&engine)[0], expected);
+
+    // The fee is added to the amount before the debit, which can overflow as well.
+    let mut engine = TransactionEngine::new().with_fee_schedule(FeeSchedule {
+        flat: Decimal::ONE,
+        ..FeeSchedule::default()
+    });
+    process(&mut engine, "deposit,1,1,10\n");
+    let result = engine.process_transaction(withdrawal(2, Decimal::MAX));
+    assert!(matches!(result, Err(Error::Overflow { .. })));
+    assert_eq!(accounts(&engine)[0], "1,10,0,10,false");
+}
+
+#[test]
+fn replayed_transactions_are_skipped_with_replay_dedup() {
+    let input = "deposit,1,1,10\nwithdrawal,1,2,3\ndispute,1,1,\n";
+    let mut engine = TransactionEngine::new().with_replay_dedup(true);
+    let summary = process(&mut engine, input);
+    assert_eq!(summary.applied, 3);
+
+    // Re-feeding the same input (after a partial failure) applies nothing twice, including the
+    // dispute, which would otherwise be rejected as a duplicate.
+    let summary = process(&mut engine, &format!("{}resolve,1,1,\n", input));
+    assert_eq!(summary.no_op, 3);
+    assert_eq!(summary.applied, 1);
+    assert_eq!(summary.rejected, 0);
+    assert_eq!(accounts(&engine)[0], "1,7,0,7,false");
+
+    // Without it, the replay gets rejected.
+    let mut engine = TransactionEngine::new();
+    process(&mut engine, input);
+    let summary = process(&mut engine, input);
+    assert_eq!(summary.rejected, 3);
+}
+
+#[test]
+fn account_statuses_decide_the_transactions_accepted() {
+    let mut engine = TransactionEngine::new().with_admin_ops(true);
+    let status = |engine: &TransactionEngine| engine.account(ClientId::from(1)).map(|a| a.status());
+    process(&mut engine, "deposit,1,1,10\n");
+    assert_eq!(status(&engine), Some(Status::Active));
+
+    // Accounts under review accept deposits, but not withdrawals.
+    let summary = process(
+        &mut engine,
+        "review,1,2,\ndeposit,1,3,5\nwithdrawal,1,4,1\n",
+    );
+    assert_eq!(status(&engine), Some(Status::UnderReview));
+    assert_eq!(summary.applied, 2);
+    assert_eq!(summary.errors.get("AccountUnderReview"), Some(&1));
+    let summary = process(&mut engine, "approve,1,5,\nwithdrawal,1,6,1\n");
+    assert_eq!(status(&engine), Some(Status::Active));
+    assert_eq!(summary.applied, 2);
+
+    // Closed accounts accept nothing until they're reopened.
+    let summary = process(&mut engine, "close,1,7,\ndeposit,1,8,5\nwithdrawal,1,9,1\n");
+    assert_eq!(status(&engine), Some(Status::Closed));
+    assert_eq!(summary.errors.get("AccountClosed"), Some(&2));
+    let summary = process(&mut engine, "reopen,1,10,\ndeposit,1,11,5\n");
+    assert_eq!(status(&engine), Some(Status::Active));
+    assert_eq!(summary.applied, 2);
+    assert_eq!(accounts(&engine)[0], "1,19,0,19,false,active");
+
+    // Chargebacks freeze accounts, until they're unlocked.
+    process(&mut engine, "dispute,1,11,\nchargeback,1,11,\n");
+    assert_eq!(status(&engine), Some(Status::Frozen));
+    process(&mut engine, "unlock,1,12,\n");
+    assert_eq!(status(&engine), Some(Status::Active));
+}
+
+#[test]
+fn open_disputes_of_frozen_accounts_follow_the_frozen_disputes_preset() {
+    let input = "deposit,1,1,10\ndeposit,1,2,5\ndeposit,1,3,4\n\
+                 dispute,1,1,\ndispute,1,2,\ndispute,1,3,\nchargeback,1,1,\n";
+    let settle = "resolve,1,2,\nchargeback,1,3,\n";
+
+    let mut engine = TransactionEngine::new().with_frozen_disputes(FrozenDisputes::Block);
+    process(&mut engine, input);
+    let summary = process(&mut engine, settle);
+    assert_eq!(summary.errors.get("AccountFrozen"), Some(&2));
+    assert_eq!(accounts(&engine)[0], "1,0,9,9,true");
+
+    // Resolves go through by default, but chargebacks don't.
+    let mut engine = TransactionEngine::new();
+    process(&mut engine, input);
+    let summary = process(&mut engine, settle);
+    assert_eq!(summary.applied, 1);
+    assert_eq!(summary.errors.get("AccountFrozen"), Some(&1));
+    assert_eq!(accounts(&engine)[0], "1,5,4,9,true");
+
+    let mut engine = TransactionEngine::new().with_frozen_disputes(FrozenDisputes::Settle);
+    process(&mut engine, input);
+    let summary = process(&mut engine, settle);
+    assert_eq!(summary.applied, 2);
+    assert_eq!(accounts(&engine)[0], "1,5,0,5,true");
+}
+
+#[test]
+fn open_disputes_are_indexed_until_settled() {
+    let mut engine = TransactionEngine::new();
+    process(
+        &mut engine,
+        "deposit,2,1,10\ndeposit,1,2,5\ndeposit,1,3,4\n\
+         dispute,1,3,\ndispute,2,1,\ndispute,1,2,2\n",
+    );
+    let open = |engine: &TransactionEngine| {
+        engine
+            .open_disputes()
+            .iter()
+            .map(|d| (u32::from(d.client), u64::from(d.tx), d.amount))
+            .collect::<Vec<_>>()
+    };
+    let expected = vec![
+        (1, 2, Decimal::from(2)),
+        (1, 3, Decimal::from(4)),
+        (2, 1, Decimal::from(10)),
+    ];
+    assert_eq!(open(&engine), expected);
+    assert_eq!(engine.disputed_amount(ClientId::from(1)), Decimal::from(6));
+
+    process(&mut engine, "resolve,1,3,\nchargeback,2,1,\n");
+    assert_eq!(open(&engine), expected[..1]);
+    assert_eq!(engine.disputed_amount(ClientId::from(1)), Decimal::from(2));
+    assert_eq!(engine.disputed_amount(ClientId::from(2)), Decimal::ZERO);
+}
+
+#[test]
+fn lineages_record_the_lifecycle_of_contested_funds() {
+    let mut engine = TransactionEngine::new().with_lineage(true);
+    process(
+        &mut engine,
+        "deposit,1,1,10\ndeposit,1,2,5\ndeposit,1,3,4\n\
+         dispute,1,1,\nresolve,1,1,\n",
+    );
+    engine.process_transaction(transfer(1, 2, 4, "3")).unwrap();
+    process(&mut engine, "dispute,1,2,2\nchargeback,1,2,\n");
+
+    // Only the contested transactions and transfers show up, in the order they first did.
+    let lineage = engine.lineage().expect("Lineage not recorded");
+    let origins: Vec<_> = lineage
+        .origins()
+        .iter()
+        .map(|o| {
+            let steps: Vec<_> = o.steps.iter().map(|s| (s.type_, s.amount)).collect();
+            (u64::from(o.tx), o.to.map(u32::from), steps)
+        })
+        .collect();
+    assert_eq!(
+        origins,
+        [
+            (1, None, vec![(Type::Dispute, None), (Type::Resolve, None)]),
+            (4, Some(2), vec![]),
+            (
+                2,
+                None,
+                vec![(Type::Dispute, amount("2")), (Type::Chargeback, None)]
+            ),
+        ]
+    );
+
+    // Without it, nothing is recorded.
+    let mut engine = TransactionEngine::new();
+    process(&mut engine, "deposit,1,1,10\ndispute,1,1,\n");
+    assert!(engine.lineage().is_none());
+}
+
+#[test]
+fn disputes_naming_another_client_are_rejected() {
+    let mut engine = TransactionEngine::new();
+    process(&mut engine, "deposit,1,1,10\ndeposit,2,2,5\n");
+    let summary = process(&mut engine, "dispute,2,1,\n");
+    assert_eq!(summary.errors.get("ClientMismatch"), Some(&1));
+    assert_eq!(accounts(&engine)[..2], ["1,10,0,10,false", "2,5,0,5,false"]);
+
+    // Same for settling disputes, which stay open.
+    let summary = process(&mut engine, "dispute,1,1,\nresolve,2,1,\nchargeback,2,1,\n");
+    assert_eq!(summary.applied, 1);
+    assert_eq!(summary.errors.get("ClientMismatch"), Some(&2));
+    assert_eq!(accounts(&engine)[..2], ["1,0,10,10,false", "2,5,0,5,false"]);
+
+    // The funds of transfers are held by the receiving client, who's the one to dispute them.
+    engine.process_transaction(transfer(2, 3, 3, "5")).unwrap();
+    let result = engine.process_transaction(Transaction::new(Type::Dispute, 2u32, 3u64, None));
+    assert!(matches!(
+        result,
+        Err(Error::ClientMismatch { client, expected, .. })
+            if client == ClientId::from(2) && expected == ClientId::from(3)
+    ));
+    let summary = process(&mut engine, "dispute,3,3,\n");
+    assert_eq!(summary.applied, 1);
+    assert!(engine.is_valid());
+}
+
+#[test]
+fn voids_restore_the_balances() {
+    let mut engine = TransactionEngine::new();
+    let summary = process(
+        &mut engine,
+        "deposit,1,1,10\ndeposit,1,2,5\nwithdrawal,1,3,4\nvoid,1,1,\nvoid,1,3,\n",
+    );
+    assert_eq!(summary.rejected, 0);
+    // The voided deposit is taken back out, and the voided withdrawal returned.
+    assert_eq!(accounts(&engine)[0], "1,5,0,5,false");
+    assert!(engine.is_valid());
+
+    // Voids need the funds of the deposit to still be there.
+    let summary = process(&mut engine, "deposit,2,4,3\nwithdrawal,2,5,2\nvoid,2,4,\n");
+    assert_eq!(summary.errors.get("InsufficientFunds"), Some(&1));
+    assert_eq!(accounts(&engine)[1], "2,1,0,1,false");
+}
+
+#[test]
+fn disputed_transactions_cannot_be_voided() {
+    let mut engine = TransactionEngine::new();
+    let summary = process(
+        &mut engine,
+        "deposit,1,1,10\ndeposit,1,2,5\ndispute,1,1,\nvoid,1,1,\n",
+    );
+    assert_eq!(summary.errors.get("InvalidVoid"), Some(&1));
+    assert_eq!(accounts(&engine)[0], "1,5,10,15,false");
+
+    // Not even once the dispute has been resolved.
+    let summary = process(&mut engine, "dispute,1,2,\nresolve,1,2,\nvoid,1,2,\n");
+    assert_eq!(summary.rejected, 1);
+    assert_eq!(accounts(&engine)[0], "1,5,10,15,false");
+    assert!(engine.is_valid());
+}
+
+#[test]
+fn voided_transactions_cannot_be_disputed() {
+    let mut engine = TransactionEngine::new();
+    let summary = process(
+        &mut engine,
+        "deposit,1,1,10\ndeposit,1,2,5\nvoid,1,1,\ndispute,1,1,\nvoid,1,1,\n",
+    );
+    assert_eq!(summary.errors.get("InvalidDispute"), Some(&1));
+    assert_eq!(summary.errors.get("InvalidVoid"), Some(&1));
+    assert_eq!(accounts(&engine)[0], "1,5,0,5,false");
+    assert!(engine.is_valid());
+}
+
+#[test]
+fn only_accepted_transactions_are_journaled() {
+    let dir = tempfile::tempdir().unwrap();
+    let wal = dir.path().join("engine.wal");
+    let mut engine = TransactionEngine::new()
+        .with_client_filter(1u32.into()..=2u32.into())
+        .recover(&wal)
+        .unwrap();
+    process(
+        &mut engine,
+        "deposit,1,1,10\nwithdrawal,1,2,20\ndeposit,3,3,5\ndispute,1,9,\nwithdrawal,1,4,1\n",
+    );
+
+    let journal = fs::read_to_string(&wal).unwrap();
+    let entries: Vec<_> = journal.lines().collect();
+    assert_eq!(entries.len(), 2, "{}", journal);
+    assert!(entries[0].contains(r#""tx":1"#));
+    assert!(entries[1].contains(r#""tx":4"#));
+
+    drop(engine);
+    let recovered = TransactionEngine::new()
+        .with_client_filter(1u32.into()..=2u32.into())
+        .recover(&wal)
+        .unwrap();
+    assert_eq!(
+        recovered
+            .account(1u32.into())
+            .unwrap()
+            .balance("")
+            .available(),
+        amount("9").unwrap()
+    );
+}
+
+#[test]
+fn rejections_on_replay_fail_the_recovery() {
+    let dir = tempfile::tempdir().unwrap();
+    let wal = dir.path().join("engine.wal");
+    let clients = |last: u32| ClientId::from(1)..=ClientId::from(last);
+    let mut engine = TransactionEngine::new()
+        .with_client_filter(clients(2))
+        .recover(&wal)
+        .unwrap();
+    process(&mut engine, "deposit,1,1,10\ndeposit,2,2,5\n");
+    drop(engine);
+
+    // Client 2 is no longer allowed, so its deposit can't be replayed.
+    let error = TransactionEngine::new()
+        .with_client_filter(clients(1))
+        .recover(&wal)
+        .err()
+        .expect("Replaying a rejected transaction succeeded");
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    assert!(error.to_string().contains("transaction 2"), "{}", error);
+}
+
+#[test]
+fn warnings_from_before_the_recovery_are_kept() {
+    let dir = tempfile::tempdir().unwrap();
+    let wal = dir.path().join("engine.wal");
+    // Untimed deposits bypass the limit, with a warning which mustn't be raised on replay.
+    let limit = WindowLimit {
+        kind: LimitKind::DepositCount,
+        max: Decimal::ONE,
+        window: 60,
+    };
+    let mut engine = TransactionEngine::new()
+        .with_validator(WindowLimits::new(vec![limit]))
+        .recover(&wal)
+        .unwrap();
+    let deposit = Transaction::new(Type::Deposit, 1u32, 1u64, amount("10"));
+    engine.process_transaction(deposit).unwrap();
+    assert_eq!(engine.drain_warnings().count(), 1);
+    drop(engine);
+
+    let mut engine = TransactionEngine::new()
+        .with_validator(WindowLimits::new(vec![limit]))
+        .with_zero_amount_policy(ZeroAmountPolicy::Skip);
+    let skipped = Transaction::new(Type::Deposit, 2u32, 3u64, amount("0"));
+    engine.process_transaction(skipped).unwrap();
+
+    let mut engine = engine.recover(&wal).unwrap();
+    let warnings: Vec<Warning> = engine.drain_warnings().collect();
+    assert!(
+        matches!(warnings[..], [Warning::ZeroAmountSkipped { tx, .. }] if tx == TransactionId::from(3)),
+        "{:?}",
+        warnings
+    );
+}
+
+#[test]
+fn failed_wal_snapshots_are_reported_and_retried() {
+    let dir = tempfile::tempdir().unwrap();
+    let wal = dir.path().join("engine.wal");
+    let mut engine = TransactionEngine::new()
+        .with_wal_snapshot_interval(1)
+        .recover(&wal)
+        .unwrap();
+
+    // The snapshot is written to a temporary file first, which can't be created over a
+    // directory.
+    let temporary = dir.path().join("engine.wal.snapshot.tmp");
+    fs::create_dir(&temporary).unwrap();
+    let deposit = Transaction::new(Type::Deposit, 1u32, 1u64, amount("10"));
+    engine.process_transaction(deposit).unwrap();
+    let warnings: Vec<_> = engine.drain_warnings().collect();
+    assert!(
+        matches!(warnings[..], [Warning::WalMaintenanceFailed { .. }]),
+        "{:?}",
+        warnings
+    );
+    assert!(!fs::read_to_string(&wal).unwrap().is_empty());
+
+    fs::remove_dir(&temporary).unwrap();
+    let deposit = Transaction::new(Type::Deposit, 1u32, 2u64, amount("5"));
+    engine.process_transaction(deposit).unwrap();
+    assert_eq!(engine.drain_warnings().count(), 0);
+    assert!(fs::read_to_string(&wal).unwrap().is_empty());
+
+    drop(engine);
+    let recovered = TransactionEngine::new().recover(&wal).unwrap();
+    assert_eq!(
+        recovered.account(1u32.into()).unwrap().balance("").total(),
+        amount("15").unwrap()
+    );
+}
+
+#[cfg(feature = "spill")]
+#[test]
+fn spilled_transactions_match_the_in_memory_history() {
+    let csv = "deposit,1,1,10\ndeposit,2,2,20\ndeposit,1,3,5\nwithdrawal,2,4,3\n\
+               dispute,1,1,\ndispute,2,2,\nresolve,1,1,\nchargeback,2,2,\ndispute,1,3,\n";
+    let dir = tempfile::tempdir().unwrap();
+    let mut spilled =
+        TransactionEngine::new_with_storage(SpillLedger::open(dir.path(), 1).unwrap()).unwrap();
+    process(&mut spilled, csv);
+    let mut engine = TransactionEngine::new();
+    process(&mut engine, csv);
+
+    assert_eq!(accounts(&spilled), accounts(&engine));
+    assert!(spilled.is_valid());
+}
+
+// Fails every write once `writes` have gone through.
+struct FailingLedger {
+    ledger: MemoryLedger,
+    writes: usize,
+}
+
+impl FailingLedger {
+    fn write(&mut self) -> io::Result<()> {
+        match self.writes.checked_sub(1) {
+            Some(writes) => {
+                self.writes = writes;
+                Ok(())
+            }
+            None => Err(io::Error::other("disk full")),
+        }
+    }
+}
+
+impl Ledger for FailingLedger {
+    fn transaction(&self, tx: TransactionId) -> io::Result<Option<Transaction>> {
+        self.ledger.transaction(tx)
+    }
+
+    fn insert_transaction(&mut self, transaction: Transaction) -> io::Result<()> {
+        self.write()?;
+        self.ledger.insert_transaction(transaction)
+    }
+
+    fn remove_transaction(&mut self, tx: TransactionId) -> io::Result<()> {
+        self.write()?;
+        self.ledger.remove_transaction(tx)
+    }
+
+    fn transactions(&self) -> Box<dyn Iterator<Item = io::Result<Transaction>> + '_> {
+        self.ledger.transactions()
+    }
+
+    fn clear(&mut self) -> io::Result<()> {
+        self.ledger.clear()
+    }
+}
+
+#[test]
+fn ledger_failures_reject_every_further_transaction() {
+    let ledger = FailingLedger {
+        ledger: MemoryLedger::default(),
+        writes: 1,
+    };
+    let mut engine = TransactionEngine::new_with_storage(ledger).unwrap();
+    let deposit = |tx: u64| Transaction::new(Type::Deposit, 1u32, tx, amount("10"));
+    engine.process_transaction(deposit(1)).unwrap();
+    assert_eq!(engine.storage_failure(), None);
+
+    let failed = Error::StorageFailed {
+        kind: io::ErrorKind::Other,
+    };
+    assert_eq!(engine.process_transaction(deposit(2)), Err(failed));
+    assert_eq!(engine.storage_failure(), Some(io::ErrorKind::Other));
+    let withdrawal = Transaction::new(Type::Withdrawal, 1u32, 3u64, amount("1"));
+    assert_eq!(engine.process_transaction(withdrawal), Err(failed));
+
+    engine.reset().unwrap();
+    assert_eq!(engine.storage_failure(), None);
+}
+
+#[cfg(feature = "toml")]
+#[test]
+fn loaded_open_disputes_are_recounted() {
+    let mut engine = TransactionEngine::new();
+    process(&mut engine, "deposit,1,1,10\ndeposit,1,2,5\ndispute,1,1,\n");
+    let mut toml = Vec::new();
+    engine
+        .save_toml(&mut toml)
+        .expect("Unable to save the state");
+    let toml = String::from_utf8(toml).unwrap();
+
+    // Listing a disputed transaction twice doesn't make for two open disputes.
+    assert!(toml.contains("disputed = [1]"));
+    let edited = toml.replace("disputed = [1]", "disputed = [1, 1]");
+    let mut loaded = TransactionEngine::load_toml(edited.as_bytes()).expect("Unable to load");
+    assert_eq!(loaded.account(1u32.into()).unwrap().open_disputes(), 1);
+
+    process(&mut loaded, "resolve,1,1,\n");
+    assert_eq!(loaded.account(1u32.into()).unwrap().open_disputes(), 0);
+    assert!(loaded.is_valid());
+}
+
+#[test]
+fn quarantined_transactions_only_count_once_applied() {
+    let dir = tempfile::tempdir().unwrap();
+    let wal = dir.path().join("engine.wal");
+    let mut engine = TransactionEngine::new()
+        .with_quarantine(10, 1)
+        .with_replay_dedup(true)
+        .recover(&wal)
+        .unwrap();
+
+    // Not journaled while quarantined, and rejected for good after a single retry.
+    process(&mut engine, "dispute,1,1,\n");
+    assert!(fs::read_to_string(&wal).unwrap().is_empty());
+    process(&mut engine, "deposit,1,2,5\n");
+    assert_eq!(fs::read_to_string(&wal).unwrap().lines().count(), 1);
+
+    // So the same dispute isn't skipped as a replay once its transaction arrives.
+    process(&mut engine, "deposit,1,1,10\ndispute,1,1,\n");
+    let account = engine.account(1u32.into()).unwrap();
+    assert_eq!(account.balance("").held(), amount("10").unwrap());
+    assert_eq!(fs::read_to_string(&wal).unwrap().lines().count(), 3);
+}
+
+#[test]
+fn quarantined_transactions_are_journaled_once_applied() {
+    let dir = tempfile::tempdir().unwrap();
+    let wal = dir.path().join("engine.wal");
+    let mut engine = TransactionEngine::new()
+        .with_quarantine(10, 5)
+        .recover(&wal)
+        .unwrap();
+    process(&mut engine, "dispute,1,1,\ndeposit,1,1,10\n");
+    let journal = fs::read_to_string(&wal).unwrap();
+    let entries: Vec<_> = journal.lines().collect();
+    assert_eq!(entries.len(), 2);
+    assert!(entries[0].contains("deposit") && entries[1].contains("dispute"));
+
+    drop(engine);
+    let recovered = TransactionEngine::new()
+        .with_quarantine(10, 5)
+        .recover(&wal)
+        .unwrap();
+    let account = recovered.account(1u32.into()).unwrap();
+    assert_eq!(account.balance("").held(), amount("10").unwrap());
+}
+
+#[test]
+fn accounts_rebuilt_from_the_event_log_match() {
+    let mut engine = TransactionEngine::new()
+        .with_quarantine(10, 5)
+        .with_event_log(true);
+    process(
+        &mut engine,
+        "deposit,1,1,10\nwithdrawal,1,2,3\ndispute,2,3,\ndeposit,2,3,4\n\
+         dispute,1,2,\nresolve,1,2,\ndispute,1,1,\nchargeback,1,1,\n",
+    );
+    let processed = accounts(&engine);
+
+    // The quarantined dispute is recorded as part of its own transaction.
+    let held = engine
+        .events_for(2u32.into())
+        .into_iter()
+        .find(|r| matches!(r.event, te::events::AccountEvent::FundsHeld { .. }))
+        .unwrap();
+    assert_eq!(held.tx, Some(TransactionId::from(3)));
+
+    engine.rebuild_from_events();
+    assert_eq!(accounts(&engine), processed);
+}
+
+#[test]
+fn replayed_withdrawals_need_the_funds_to_cover_them() {
+    let mut engine = TransactionEngine::new();
+    process(
+        &mut engine,
+        "deposit,1,1,10\ndeposit,1,2,5\nwithdrawal,1,3,12\ndeposit,2,4,1\n\
+         dispute,1,2,\nchargeback,1,2,\n",
+    );
+
+    // The deposit which covered the withdrawal has been charged back, so it isn't replayed.
+    let before = engine.as_of(AsOf::Before(TransactionId::from(3))).unwrap();
+    assert_eq!(
+        accounts(&before),
+        ["1,10,0,10,false", "client,available,held,total,locked"]
+    );
+    let result = engine.balance_at(ClientId::from(1), AsOf::Before(TransactionId::from(4)));
+    assert!(
+        matches!(result, Err(Error::InsufficientFunds { .. })),
+        "{:?}",
+        result
+    );
+}
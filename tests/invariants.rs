@@ -0,0 +1,124 @@
+//! Property tests of the engine invariants, over random sequences of transactions (the
+//! counterpart of the `fuzz/` target, which runs on stable).
+
+use proptest::prelude::*;
+use proptest::sample::select;
+use rust_decimal::Decimal;
+use te::{Error, OverdraftPolicy, Transaction, TransactionEngine, Type};
+
+// Small ranges of clients and transaction ids, so the dispute-related transactions and
+// transfers mostly refer to existing ones.
+const CLIENTS: u32 = 8;
+const TRANSACTIONS: u64 = 32;
+
+const TYPES: [Type; 14] = [
+    Type::Approve,
+    Type::Chargeback,
+    Type::ChargebackReversal,
+    Type::Close,
+    Type::Deposit,
+    Type::Dispute,
+    Type::Hold,
+    Type::Reopen,
+    Type::Resolve,
+    Type::Review,
+    Type::Transfer,
+    Type::Unlock,
+    Type::Void,
+    Type::Withdrawal,
+];
+
+// Mostly small amounts with up to six decimal places (more than amounts may have), which may
+// be negative, and the occasional extreme one.
+fn amount() -> impl Strategy<Value = Decimal> {
+    prop_oneof![
+        20 => (any::<i32>(), 0..7u32).prop_map(|(mantissa, scale)| {
+            Decimal::new(i64::from(mantissa), scale)
+        }),
+        1 => Just(Decimal::MAX),
+        1 => Just(Decimal::MIN),
+    ]
+}
+
+// Transactions of any type, with an amount for the types that need one (and sometimes for
+// the others), in one of two currencies.
+fn transaction() -> impl Strategy<Value = Transaction> {
+    (
+        select(&TYPES[..]),
+        0..CLIENTS,
+        0..TRANSACTIONS,
+        proptest::option::of(amount()),
+        amount(),
+        any::<bool>(),
+        proptest::option::of(0..CLIENTS),
+        proptest::option::of(0..256u64),
+        proptest::option::of(0..256u64),
+    )
+        .prop_map(
+            |(type_, client, tx, amount, required, eur, to, timestamp, expires)| {
+                let amount = match type_ {
+                    Type::Deposit | Type::Withdrawal | Type::Transfer | Type::Hold => {
+                        Some(amount.unwrap_or(required))
+                    }
+                    _ => amount,
+                };
+                let mut transaction = Transaction::new(type_, client, tx, amount);
+                if eur {
+                    transaction = transaction.with_currency("EUR".to_owned());
+                }
+                if let Some(to) = to.or_else(|| (type_ == Type::Transfer).then_some(client)) {
+                    transaction = transaction.with_destination(to);
+                }
+                if let Some(timestamp) = timestamp {
+                    transaction = transaction.with_timestamp(timestamp);
+                }
+                if let Some(expires) = expires.or_else(|| (type_ == Type::Hold).then_some(0)) {
+                    transaction = transaction.with_expiry(expires);
+                }
+                transaction
+            },
+        )
+}
+
+// An engine with invariant checks, and the behaviors enabled by the bits of `flags` (same as
+// for the fuzz target).
+fn engine(flags: u8) -> TransactionEngine {
+    let bit = |n: u8| flags & (1 << n) != 0;
+    let overdraft_policy = match flags >> 6 {
+        0 => OverdraftPolicy::Allow,
+        1 => OverdraftPolicy::Reject,
+        _ => OverdraftPolicy::Restrict,
+    };
+
+    TransactionEngine::new()
+        .with_invariant_checks(true)
+        .with_withdrawal_disputes(bit(0))
+        .with_redisputes(bit(1))
+        .with_admin_ops(bit(2))
+        .with_zero_amounts_allowed(bit(3))
+        .with_signed_amounts(bit(4))
+        .with_unlock_on_reversal(bit(5))
+        .with_overdraft_policy(overdraft_policy)
+}
+
+proptest! {
+    #[test]
+    fn random_sequences_keep_the_engine_consistent(
+        flags in any::<u8>(),
+        transactions in proptest::collection::vec(transaction(), 0..64),
+    ) {
+        let mut engine = engine(flags);
+        for transaction in transactions {
+            // Rejections are fine, as long as none of them is for a broken invariant.
+            let result = engine.process_transaction(transaction);
+            prop_assert!(
+                !matches!(result, Err(Error::InvariantViolated { .. })),
+                "{:?}",
+                result
+            );
+            engine.drain_warnings();
+        }
+        prop_assert!(engine.is_valid());
+        prop_assert!(engine.audit().is_empty());
+    }
+}